@@ -1,3 +1,4 @@
+use crate::error;
 use actix_web::error::ParseError;
 use actix_web::http::header;
 use actix_web::http::header::{HeaderName, HeaderValue, InvalidHeaderValue, TryIntoHeaderValue};
@@ -11,19 +12,67 @@ use sha2::{Digest, Sha384};
 use std::collections::HashSet;
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter, Write};
-use std::io::ErrorKind;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use tonic::metadata::{MetadataMap, MetadataValue};
 use tracing::{error, instrument};
 use uuid::Uuid;
 
+pub mod password;
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
     sub: Uuid,
     company: String,
     iss: String,
+    #[serde(default)] // tokens without an audience fail validation
+    aud: String,
+    #[serde(default)] // tokens issued before iat was added are treated as issued at the epoch
+    iat: u64,
+    exp: u64,
+    #[serde(default)]
+    nbf: u64,
+    #[serde(default = "Scope::all")] // tokens issued before scopes were added keep full access
+    scopes: Vec<Scope>,
+    // namespace ids the token is limited to, the token can use every namespace of the tenant when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespaces: Option<Vec<Uuid>>,
+    // set on service tokens, names the service acting on behalf of the tenant in sub
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    act: Option<Actor>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Actor {
+    sub: String,
+}
+
+// What a token is allowed to do. Admin implies every other scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
 }
 
+impl Scope {
+    pub fn all() -> Vec<Scope> {
+        vec![Scope::Read, Scope::Write, Scope::Admin]
+    }
+}
+
+pub const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+pub const DEFAULT_CLOCK_SKEW: Duration = Duration::from_secs(60);
+pub const DEFAULT_ISSUER: &str = "kvstore";
+pub const DEFAULT_AUDIENCE: &str = "kvstore";
+pub const DEFAULT_SERVICE_AUDIENCE: &str = "kvstore-storage";
+pub const DEFAULT_SERVICE_TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+pub const GATEWAY_SERVICE_NAME: &str = "kvstore-gateway";
+// the service token a call carries for the tenant it reads from, when that isn't the caller
+pub const SOURCE_AUTHORIZATION_METADATA: &str = "source-authorization";
+
 #[derive(Clone)]
 pub struct Token(Arc<str>);
 
@@ -42,18 +91,74 @@ impl AsRef<str> for Token {
     }
 }
 
+fn sha384(value: &[u8]) -> String {
+    let mut hasher = Sha384::new();
+    hasher.update(value);
+    general_purpose::STANDARD_NO_PAD.encode(hasher.finalize())
+}
+
 // Implement Display so that we can hash the token and we don't accidentally store it in logs
 impl Display for Token {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        let mut hasher = Sha384::new();
         f.write_str("sha384::")?;
-        hasher.update(self.0.as_bytes());
-        let result = hasher.finalize();
-        f.write_str(general_purpose::STANDARD_NO_PAD.encode(result).as_str())?;
+        f.write_str(sha384(self.0.as_bytes()).as_str())?;
         Ok(())
     }
 }
 
+const API_KEY_PREFIX: &str = "kv_";
+
+// A long lived credential handed out to a tenant. Only the hash of the key is ever persisted.
+#[derive(Clone)]
+pub struct ApiKey(Arc<str>);
+
+impl ApiKey {
+    pub fn generate() -> ApiKey {
+        // two v4 uuids give 244 random bits
+        ApiKey(
+            format!(
+                "{}{}{}",
+                API_KEY_PREFIX,
+                Uuid::new_v4().simple(),
+                Uuid::new_v4().simple()
+            )
+            .into(),
+        )
+    }
+
+    pub fn hash(&self) -> String {
+        sha384(self.0.as_bytes())
+    }
+}
+
+impl From<&str> for ApiKey {
+    fn from(value: &str) -> Self {
+        ApiKey(value.into())
+    }
+}
+
+impl AsRef<str> for ApiKey {
+    fn as_ref(&self) -> &str {
+        self.0.as_ref()
+    }
+}
+
+// Serialized in full so the key can be returned once when it is created
+impl Serialize for ApiKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_ref())
+    }
+}
+
+impl Debug for ApiKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "sha384::{}", self.hash())
+    }
+}
+
 impl Debug for Token {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         Display::fmt(self, f)
@@ -73,46 +178,277 @@ impl Identity {
     pub fn token(&self) -> Token {
         self.token.clone()
     }
+
+    // Seconds since the unix epoch when the token was issued
+    pub fn issued_at(&self) -> u64 {
+        self.claims.iat
+    }
+
+    // Seconds since the unix epoch when the token stops being valid
+    pub fn expires_at(&self) -> u64 {
+        self.claims.exp
+    }
+
+    pub fn scopes(&self) -> &[Scope] {
+        self.claims.scopes.as_slice()
+    }
+
+    pub fn namespaces(&self) -> Option<&[Uuid]> {
+        self.claims.namespaces.as_deref()
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.claims
+            .scopes
+            .iter()
+            .any(|granted| *granted == scope || *granted == Scope::Admin)
+    }
+
+    pub fn can_access_namespace(&self, namespace_id: Uuid) -> bool {
+        match &self.claims.namespaces {
+            Some(namespaces) => namespaces.contains(&namespace_id),
+            None => true,
+        }
+    }
+
+    // Whether this is a service token minted for an internal hop rather than a token handed to a tenant
+    pub fn is_service(&self) -> bool {
+        self.claims.act.is_some()
+    }
+
+    // The service acting on behalf of the tenant, if this is a service token
+    pub fn actor(&self) -> Option<&str> {
+        self.claims.act.as_ref().map(|act| act.sub.as_str())
+    }
+
+    // Whether the token allows an operation needing the scope on the namespace
+    pub fn allows(&self, scope: Scope, namespace_id: Uuid) -> bool {
+        self.has_scope(scope) && self.can_access_namespace(namespace_id)
+    }
 }
 
 pub trait JwtIssuer {
-    fn new_identity(&self, tenant_id: Uuid) -> errors::Result<Identity>;
+    // Issues a token with every scope for every namespace of the tenant
+    fn new_identity(&self, tenant_id: Uuid) -> errors::Result<Identity> {
+        self.new_scoped_identity(tenant_id, Scope::all(), None)
+    }
+
+    fn new_scoped_identity(
+        &self,
+        tenant_id: Uuid,
+        scopes: Vec<Scope>,
+        namespaces: Option<Vec<Uuid>>,
+    ) -> errors::Result<Identity>;
+}
+
+// The signing algorithms supported for tokens. The key files are PEM encoded, and EC and Ed25519
+// private keys must be in PKCS#8 format. Hs256 uses a shared secret as both the private and public
+// key, anyone who can validate tokens can also issue them, so it's only meant for single box deployments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyAlgorithm {
+    #[default]
+    Rs256,
+    Es256,
+    EdDsa,
+    Hs256,
+}
+
+// HS256 secrets shorter than the hash output make brute forcing the secret feasible
+const MIN_SECRET_LEN: usize = 32;
+
+impl KeyAlgorithm {
+    fn algorithm(&self) -> Algorithm {
+        match self {
+            KeyAlgorithm::Rs256 => Algorithm::RS256,
+            KeyAlgorithm::Es256 => Algorithm::ES256,
+            KeyAlgorithm::EdDsa => Algorithm::EdDSA,
+            KeyAlgorithm::Hs256 => Algorithm::HS256,
+        }
+    }
+
+    fn encoding_key(&self, private_key: &[u8]) -> errors::Result<EncodingKey> {
+        match self {
+            KeyAlgorithm::Rs256 => EncodingKey::from_rsa_pem(private_key),
+            KeyAlgorithm::Es256 => EncodingKey::from_ec_pem(private_key),
+            KeyAlgorithm::EdDsa => EncodingKey::from_ed_pem(private_key),
+            KeyAlgorithm::Hs256 => {
+                check_secret_len(private_key)?;
+                Ok(EncodingKey::from_secret(private_key))
+            }
+        }
+    }
+
+    fn decoding_key(&self, public_key: &[u8]) -> errors::Result<DecodingKey> {
+        match self {
+            KeyAlgorithm::Rs256 => DecodingKey::from_rsa_pem(public_key),
+            KeyAlgorithm::Es256 => DecodingKey::from_ec_pem(public_key),
+            KeyAlgorithm::EdDsa => DecodingKey::from_ed_pem(public_key),
+            KeyAlgorithm::Hs256 => {
+                check_secret_len(public_key)?;
+                Ok(DecodingKey::from_secret(public_key))
+            }
+        }
+    }
+}
+
+fn check_secret_len(secret: &[u8]) -> errors::Result<()> {
+    if secret.len() < MIN_SECRET_LEN {
+        error!(min_len = MIN_SECRET_LEN, "jwt secret is too short");
+        return Err(errors::ErrorKind::InvalidKeyFormat.into());
+    }
+    Ok(())
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "RS256" => Ok(KeyAlgorithm::Rs256),
+            "ES256" => Ok(KeyAlgorithm::Es256),
+            "EDDSA" | "ED25519" => Ok(KeyAlgorithm::EdDsa),
+            "HS256" => Ok(KeyAlgorithm::Hs256),
+            _ => Err(format!("unsupported key algorithm: {}", s)),
+        }
+    }
+}
+
+impl Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.algorithm(), f)
+    }
 }
 
 #[derive(Clone)]
-pub struct RsaJwtIssuer {
+pub struct KeyJwtIssuer {
+    algorithm: KeyAlgorithm,
     private_key: EncodingKey,
+    lifetime: Duration,
+    issuer: String,
+    audience: String,
 }
 
-impl RsaJwtIssuer {
-    pub fn new(rsa_private_key: &[u8]) -> errors::Result<RsaJwtIssuer> {
+impl KeyJwtIssuer {
+    pub fn new(algorithm: KeyAlgorithm, private_key: &[u8]) -> errors::Result<KeyJwtIssuer> {
         // replace with our own error type
-        let private_key = EncodingKey::from_rsa_pem(rsa_private_key)?;
+        let private_key = algorithm.encoding_key(private_key)?;
+
+        Ok(KeyJwtIssuer {
+            algorithm,
+            private_key,
+            lifetime: DEFAULT_TOKEN_LIFETIME,
+            issuer: DEFAULT_ISSUER.to_owned(),
+            audience: DEFAULT_AUDIENCE.to_owned(),
+        })
+    }
+
+    // Sets how long newly issued tokens are valid for
+    pub fn with_lifetime(mut self, lifetime: Duration) -> KeyJwtIssuer {
+        self.lifetime = lifetime;
+        self
+    }
+
+    // Sets the iss claim of newly issued tokens
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> KeyJwtIssuer {
+        self.issuer = issuer.into();
+        self
+    }
+
+    // Sets the aud claim of newly issued tokens, typically the name of the cluster the token is for
+    pub fn with_audience(mut self, audience: impl Into<String>) -> KeyJwtIssuer {
+        self.audience = audience.into();
+        self
+    }
+
+    // Issues a service token that lets the named service act as the tenant behind the identity. The
+    // scopes and namespace restrictions are carried over so the callee enforces the same permissions.
+    #[instrument(skip(identity))]
+    pub fn new_service_identity(
+        &self,
+        service: &str,
+        identity: &Identity,
+    ) -> errors::Result<Identity> {
+        let now = jsonwebtoken::get_current_timestamp();
+        let claims = Claims {
+            sub: identity.tenant_id(),
+            company: identity.claims.company.clone(),
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            iat: now,
+            exp: now + self.lifetime.as_secs(),
+            nbf: now,
+            scopes: identity.claims.scopes.clone(),
+            namespaces: identity.claims.namespaces.clone(),
+            act: Some(Actor {
+                sub: service.to_owned(),
+            }),
+        };
+        self.encode(claims)
+    }
 
-        Ok(RsaJwtIssuer { private_key })
+    // Issues an admin token for an operator tool, e.g. kvadmin, that acts on storage nodes as a whole
+    // rather than for a tenant
+    pub fn new_operator_identity(&self, service: &str) -> errors::Result<Identity> {
+        let now = jsonwebtoken::get_current_timestamp();
+        let claims = Claims {
+            sub: Uuid::nil(),
+            company: String::new(),
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            iat: now,
+            exp: now + self.lifetime.as_secs(),
+            nbf: now,
+            scopes: vec![Scope::Admin],
+            namespaces: None,
+            act: Some(Actor {
+                sub: service.to_owned(),
+            }),
+        };
+        self.encode(claims)
+    }
+
+    fn encode(&self, claims: Claims) -> errors::Result<Identity> {
+        let token = encode(
+            &Header::new(self.algorithm.algorithm()),
+            &claims,
+            &self.private_key,
+        )?;
+
+        Ok(Identity {
+            token: Token(token.into()),
+            claims,
+        })
     }
 }
 
-impl JwtIssuer for RsaJwtIssuer {
+impl JwtIssuer for KeyJwtIssuer {
     #[instrument]
-    fn new_identity(&self, tenant_id: Uuid) -> errors::Result<Identity> {
+    fn new_scoped_identity(
+        &self,
+        tenant_id: Uuid,
+        scopes: Vec<Scope>,
+        namespaces: Option<Vec<Uuid>>,
+    ) -> errors::Result<Identity> {
+        let now = jsonwebtoken::get_current_timestamp();
         let claims = Claims {
             sub: tenant_id,
             company: "my own".to_owned(),
-            iss: "kvstore".to_owned(),
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            iat: now,
+            exp: now + self.lifetime.as_secs(),
+            nbf: now,
+            scopes,
+            namespaces,
+            act: None,
         };
-        let token = encode(&Header::new(Algorithm::RS256), &claims, &self.private_key)?;
-
-        return Ok(Identity {
-            token: Token(token.into()),
-            claims,
-        });
+        self.encode(claims)
     }
 }
 
-impl fmt::Debug for RsaJwtIssuer {
+impl fmt::Debug for KeyJwtIssuer {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.write_str("rsa jwt issuer")
+        write!(f, "{} jwt issuer", self.algorithm)
     }
 }
 
@@ -121,32 +457,66 @@ pub trait JwtValidator {
 }
 
 #[derive(Clone)]
-pub struct RsaJwtValidator {
+pub struct KeyJwtValidator {
+    algorithm: KeyAlgorithm,
     public_key: DecodingKey,
+    clock_skew: Duration,
+    issuer: String,
+    audience: String,
 }
 
-impl fmt::Debug for RsaJwtValidator {
+impl fmt::Debug for KeyJwtValidator {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.write_str("rsa jwt validator")
+        write!(f, "{} jwt validator", self.algorithm)
     }
 }
 
-impl RsaJwtValidator {
-    pub fn new(rsa_public_key: &[u8]) -> errors::Result<RsaJwtValidator> {
+impl KeyJwtValidator {
+    pub fn new(algorithm: KeyAlgorithm, public_key: &[u8]) -> errors::Result<KeyJwtValidator> {
         // replace with our own error type
-        let public_key = DecodingKey::from_rsa_pem(rsa_public_key)?;
+        let public_key = algorithm.decoding_key(public_key)?;
+
+        Ok(KeyJwtValidator {
+            algorithm,
+            public_key,
+            clock_skew: DEFAULT_CLOCK_SKEW,
+            issuer: DEFAULT_ISSUER.to_owned(),
+            audience: DEFAULT_AUDIENCE.to_owned(),
+        })
+    }
 
-        Ok(RsaJwtValidator { public_key })
+    // Sets how far the clocks of the issuer and validator may drift apart when checking exp and nbf
+    pub fn with_clock_skew(mut self, clock_skew: Duration) -> KeyJwtValidator {
+        self.clock_skew = clock_skew;
+        self
+    }
+
+    // Sets the only iss claim that is accepted
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> KeyJwtValidator {
+        self.issuer = issuer.into();
+        self
+    }
+
+    // Sets the only aud claim that is accepted
+    pub fn with_audience(mut self, audience: impl Into<String>) -> KeyJwtValidator {
+        self.audience = audience.into();
+        self
     }
 }
 
-impl JwtValidator for RsaJwtValidator {
+impl JwtValidator for KeyJwtValidator {
     #[instrument(skip(token_str))]
     fn parse(&self, token_str: impl Into<String>) -> errors::Result<Identity> {
         let token_str = token_str.into();
-        let mut validation = Validation::new(Algorithm::RS256);
-        validation.validate_exp = false; // TODO for production remove this
-        validation.required_spec_claims = HashSet::new();
+        // only the configured algorithm is accepted so a token can't pick a weaker one in its header
+        let mut validation = Validation::new(self.algorithm.algorithm());
+        validation.validate_nbf = true;
+        validation.leeway = self.clock_skew.as_secs();
+        // tokens minted for another environment carry a different iss or aud and are rejected
+        validation.set_issuer(&[self.issuer.as_str()]);
+        validation.set_audience(&[self.audience.as_str()]);
+        validation.required_spec_claims =
+            HashSet::from(["exp".to_string(), "iss".to_string(), "aud".to_string()]);
 
         let token = decode::<Claims>(&token_str, &self.public_key, &validation)?;
 
@@ -173,23 +543,22 @@ impl AsRef<str> for AuthHeader {
     }
 }
 
-impl TryFrom<&MetadataMap> for AuthHeader {
-    type Error = ErrorKind;
-
-    fn try_from(value: &MetadataMap) -> Result<Self, Self::Error> {
+impl AuthHeader {
+    // The bearer token in the named metadata entry
+    pub fn from_metadata(value: &MetadataMap, name: &str) -> Result<Self, error::Error> {
         value
-            .get("Authorization")
-            .ok_or(ErrorKind::NotFound)
+            .get(name)
+            .ok_or(error::Error::MissingAuthorization)
             .and_then(|header| {
                 header.to_str().map_err(|err| {
                     error!(err = err.to_string(), "failed to get auth header");
-                    ErrorKind::NotFound
+                    error::Error::MissingAuthorization
                 })
             })
             .and_then(|auth| {
                 auth.split_ascii_whitespace()
                     .nth(1)
-                    .ok_or(ErrorKind::NotFound)
+                    .ok_or(error::Error::MissingAuthorization)
             })
             .map(|token| AuthHeader {
                 bearer: token.to_string(),
@@ -197,6 +566,14 @@ impl TryFrom<&MetadataMap> for AuthHeader {
     }
 }
 
+impl TryFrom<&MetadataMap> for AuthHeader {
+    type Error = error::Error;
+
+    fn try_from(value: &MetadataMap) -> Result<Self, Self::Error> {
+        AuthHeader::from_metadata(value, "Authorization")
+    }
+}
+
 impl From<AuthHeader> for MetadataMap {
     fn from(header: AuthHeader) -> Self {
         let mut map = MetadataMap::new();
@@ -217,6 +594,14 @@ impl From<AuthHeader> for MetadataMap {
     }
 }
 
+impl From<Token> for AuthHeader {
+    fn from(token: Token) -> Self {
+        AuthHeader {
+            bearer: token.as_ref().to_string(),
+        }
+    }
+}
+
 impl Debug for AuthHeader {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.write_str("auth header")
@@ -262,3 +647,49 @@ impl header::Header for AuthHeader {
         }
     }
 }
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+pub struct ApiKeyHeader(ApiKey);
+
+impl ApiKeyHeader {
+    pub fn api_key(&self) -> &ApiKey {
+        &self.0
+    }
+}
+
+impl Debug for ApiKeyHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("api key header")
+    }
+}
+
+impl TryIntoHeaderValue for ApiKeyHeader {
+    type Error = InvalidHeaderValue;
+
+    fn try_into_value(self) -> Result<HeaderValue, Self::Error> {
+        HeaderValue::try_from(self.0.as_ref())
+    }
+}
+
+impl header::Header for ApiKeyHeader {
+    fn name() -> HeaderName {
+        HeaderName::from_static(API_KEY_HEADER)
+    }
+
+    fn parse<M: HttpMessage>(msg: &M) -> Result<Self, ParseError> {
+        let Some(value) = msg.headers().get(API_KEY_HEADER) else {
+            return Err(ParseError::Header);
+        };
+        match value.to_str() {
+            Ok(api_key) if api_key.starts_with(API_KEY_PREFIX) => {
+                Ok(ApiKeyHeader(ApiKey::from(api_key)))
+            }
+            Ok(_) => Err(ParseError::Header),
+            Err(err) => {
+                error!(err = err.to_string(), "failed to get api key header");
+                Err(ParseError::Header)
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use tracing::instrument;
+
+pub use argon2::password_hash::{Error, Result};
+
+// The OWASP recommended minimum for argon2id
+pub const DEFAULT_MEMORY_KIB: u32 = 19 * 1024;
+pub const DEFAULT_ITERATIONS: u32 = 2;
+pub const DEFAULT_PARALLELISM: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasswordParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordParams {
+    fn default() -> Self {
+        PasswordParams {
+            memory_kib: DEFAULT_MEMORY_KIB,
+            iterations: DEFAULT_ITERATIONS,
+            parallelism: DEFAULT_PARALLELISM,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    // the password matched but the hash was made with other parameters and should be replaced
+    ValidNeedsRehash,
+}
+
+impl Verification {
+    pub fn is_valid(&self) -> bool {
+        *self != Verification::Invalid
+    }
+}
+
+// Hashes and verifies passwords with argon2id. Hashes are stored in the PHC string format which
+// records the parameters used, so raising the parameters only affects new hashes until a password
+// is verified and rehashed.
+#[derive(Debug, Clone)]
+pub struct Passwords {
+    params: Params,
+}
+
+impl Passwords {
+    pub fn new(params: PasswordParams) -> Result<Passwords> {
+        let params = Params::new(
+            params.memory_kib,
+            params.iterations,
+            params.parallelism,
+            None,
+        )?;
+        Ok(Passwords { params })
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    #[instrument(skip_all)]
+    pub fn hash(&self, password: &str) -> Result<String> {
+        let salt = SaltString::generate(&mut OsRng);
+        Ok(self
+            .argon2()
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string())
+    }
+
+    #[instrument(skip_all)]
+    pub fn verify(&self, password: &str, hash: &str) -> Result<Verification> {
+        let hash = PasswordHash::new(hash)?;
+        // the parameters are read from the stored hash, not from self
+        match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) if self.needs_rehash(&hash) => Ok(Verification::ValidNeedsRehash),
+            Ok(()) => Ok(Verification::Valid),
+            Err(Error::Password) => Ok(Verification::Invalid),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn needs_rehash(&self, hash: &PasswordHash) -> bool {
+        let Ok(params) = Params::try_from(hash) else {
+            return true;
+        };
+        hash.algorithm != Algorithm::Argon2id.ident()
+            || hash.version != Some(Version::V0x13.into())
+            || params.m_cost() != self.params.m_cost()
+            || params.t_cost() != self.params.t_cost()
+            || params.p_cost() != self.params.p_cost()
+    }
+}
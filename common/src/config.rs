@@ -0,0 +1,397 @@
+use derive_more::{Display, Error};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+
+#[derive(Error, Display, Debug)]
+pub enum Error {
+    #[display(fmt = "failed to read config file {}: {}", path, source)]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[display(fmt = "failed to parse config file {}: {}", path, reason)]
+    Parse {
+        path: String,
+        #[error(not(source))]
+        reason: String,
+    },
+
+    #[display(fmt = "invalid command line argument {}, expected --name value", _0)]
+    Argument(#[error(not(source))] String),
+
+    #[display(
+        fmt = "invalid value {:?} for {} from {}: {}",
+        value,
+        key,
+        layer,
+        reason
+    )]
+    Invalid {
+        #[error(not(source))]
+        key: String,
+        #[error(not(source))]
+        value: String,
+        #[error(not(source))]
+        layer: Layer,
+        #[error(not(source))]
+        reason: String,
+    },
+
+    #[display(fmt = "{} must be set, e.g. with {}", key, hint)]
+    Missing {
+        #[error(not(source))]
+        key: String,
+        #[error(not(source))]
+        hint: String,
+    },
+
+    #[display(fmt = "unknown settings: {}", _0)]
+    Unknown(#[error(not(source))] String),
+}
+
+// Where a setting's value came from, reported in errors so a bad value can be found quickly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Layer {
+    File(String),
+    Env(String),
+    Flag(String),
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layer::File(path) => write!(f, "config file {}", path),
+            Layer::Env(name) => write!(f, "environment variable {}", name),
+            Layer::Flag(name) => write!(f, "flag --{}", name),
+        }
+    }
+}
+
+// Settings layered from the built in defaults, a toml or yaml file, environment variables, and
+// command line flags, each overriding the ones before it. A setting named token_lifetime_secs is
+// read from token_lifetime_secs in the file (or lifetime_secs in a [token] table), from
+// <PREFIX>_TOKEN_LIFETIME_SECS in the environment, and from --token-lifetime-secs on the command line.
+#[derive(Debug)]
+pub struct Config {
+    prefix: String,
+    path: Option<String>,
+    file: HashMap<String, String>,
+    flags: HashMap<String, String>,
+    read: Mutex<HashSet<String>>,
+}
+
+impl Config {
+    // Loads the file given with --config or <PREFIX>_CONFIG, if any, and the process's flags
+    pub fn load(prefix: &str) -> Result<Config, Error> {
+        Config::from_args(prefix, env::args().skip(1))
+    }
+
+    pub fn from_args(
+        prefix: &str,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Config, Error> {
+        let flags = parse_flags(args)?;
+        let path = flags
+            .get("config")
+            .cloned()
+            .or_else(|| env::var(format!("{}_CONFIG", prefix)).ok());
+        let file = match &path {
+            Some(path) => read_file(path)?,
+            None => HashMap::new(),
+        };
+        Ok(Config {
+            prefix: prefix.to_string(),
+            path,
+            file,
+            flags,
+            read: Mutex::new(HashSet::from(["config".to_string()])),
+        })
+    }
+
+    fn env_name(&self, key: &str) -> String {
+        format!("{}_{}", self.prefix, key.to_uppercase())
+    }
+
+    // The value and the layer it came from, the last layer that sets the key wins
+    fn raw(&self, key: &str) -> Option<(String, Layer)> {
+        if let Ok(mut read) = self.read.lock() {
+            read.insert(key.to_string());
+        }
+        if let Some(value) = self.flags.get(key) {
+            return Some((value.clone(), Layer::Flag(key.replace('_', "-"))));
+        }
+        let name = self.env_name(key);
+        if let Ok(value) = env::var(&name) {
+            return Some((value, Layer::Env(name)));
+        }
+        self.file.get(key).map(|value| {
+            (
+                value.clone(),
+                Layer::File(self.path.clone().unwrap_or_default()),
+            )
+        })
+    }
+
+    pub fn get<T>(&self, key: &str) -> Result<Option<T>, Error>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let Some((value, layer)) = self.raw(key) else {
+            return Ok(None);
+        };
+        value
+            .parse()
+            .map(Some)
+            .map_err(|err: T::Err| Error::Invalid {
+                key: key.to_string(),
+                value,
+                layer,
+                reason: err.to_string(),
+            })
+    }
+
+    pub fn get_or<T>(&self, key: &str, default: T) -> Result<T, Error>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    // Durations are whole seconds or milliseconds, the unit is part of the setting's name
+    pub fn secs_or(&self, key: &str, default: Duration) -> Result<Duration, Error> {
+        Ok(Duration::from_secs(self.get_or(key, default.as_secs())?))
+    }
+
+    pub fn millis_or(&self, key: &str, default: Duration) -> Result<Duration, Error> {
+        Ok(Duration::from_millis(
+            self.get_or(key, default.as_millis() as u64)?,
+        ))
+    }
+
+    pub fn require<T>(&self, key: &str) -> Result<T, Error>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.get(key)?.ok_or_else(|| Error::Missing {
+            key: key.to_string(),
+            hint: self.env_name(key),
+        })
+    }
+
+    // A setting is invalid given the others, e.g. a certificate without its key
+    pub fn invalid(&self, key: &str, reason: impl Into<String>) -> Error {
+        let (value, layer) = self
+            .raw(key)
+            .unwrap_or_else(|| (String::new(), Layer::Env(self.env_name(key))));
+        Error::Invalid {
+            key: key.to_string(),
+            value,
+            layer,
+            reason: reason.into(),
+        }
+    }
+
+    // Called once every setting has been read, a setting in the file or on the command line that
+    // nothing read is most likely a typo
+    pub fn check_unknown(&self) -> Result<(), Error> {
+        let read = self
+            .read
+            .lock()
+            .map(|read| read.clone())
+            .unwrap_or_default();
+        let mut unknown: Vec<String> = self
+            .file
+            .keys()
+            .chain(self.flags.keys())
+            .filter(|key| !read.contains(*key))
+            .cloned()
+            .collect();
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        unknown.sort();
+        unknown.dedup();
+        Err(Error::Unknown(unknown.join(", ")))
+    }
+}
+
+// Flags are --name value or --name=value, a flag without a value is a boolean set to true
+fn parse_flags(args: impl IntoIterator<Item = String>) -> Result<HashMap<String, String>, Error> {
+    let mut flags = HashMap::new();
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let Some(name) = arg.strip_prefix("--").filter(|name| !name.is_empty()) else {
+            return Err(Error::Argument(arg));
+        };
+        let (name, value) = match name.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => match args.next_if(|next| !next.starts_with("--")) {
+                Some(value) => (name.to_string(), value),
+                None => (name.to_string(), "true".to_string()),
+            },
+        };
+        flags.insert(name.replace('-', "_"), value);
+    }
+    Ok(flags)
+}
+
+fn read_file(path: &str) -> Result<HashMap<String, String>, Error> {
+    let contents = std::fs::read_to_string(path).map_err(|source| Error::Read {
+        path: path.to_string(),
+        source,
+    })?;
+    let parse_error = |reason: String| Error::Parse {
+        path: path.to_string(),
+        reason,
+    };
+    let value: serde_json::Value = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => {
+            serde_yaml::from_str(&contents).map_err(|err| parse_error(err.to_string()))?
+        }
+        _ => toml::from_str(&contents).map_err(|err| parse_error(err.to_string()))?,
+    };
+    let mut settings = HashMap::new();
+    flatten("", &value, &mut settings);
+    Ok(settings)
+}
+
+// Nested tables become settings joined with _, lists become comma separated values
+fn flatten(key: &str, value: &serde_json::Value, settings: &mut HashMap<String, String>) {
+    match value {
+        serde_json::Value::Object(table) => {
+            for (name, value) in table {
+                let name = name.replace('-', "_");
+                let key = match key {
+                    "" => name,
+                    key => format!("{}_{}", key, name),
+                };
+                flatten(&key, value, settings);
+            }
+        }
+        serde_json::Value::Array(items) => {
+            let items: Vec<String> = items
+                .iter()
+                .map(|item| match item {
+                    serde_json::Value::String(item) => item.clone(),
+                    item => item.to_string(),
+                })
+                .collect();
+            settings.insert(key.to_string(), items.join(","));
+        }
+        serde_json::Value::String(value) => {
+            settings.insert(key.to_string(), value.clone());
+        }
+        serde_json::Value::Null => {}
+        value => {
+            settings.insert(key.to_string(), value.to_string());
+        }
+    }
+}
+
+// The settings that differ between two loads of the config, split into the ones that were applied and
+// the ones that only take effect after a restart
+#[derive(Debug, Default)]
+pub struct Changes {
+    applied: Vec<String>,
+    restart: Vec<String>,
+}
+
+impl Changes {
+    // Records a runtime tunable setting that changed, returning whether it did
+    pub fn apply<T: PartialEq + fmt::Debug>(&mut self, key: &str, old: &T, new: &T) -> bool {
+        if old == new {
+            return false;
+        }
+        self.applied
+            .push(format!("{}: {:?} -> {:?}", key, old, new));
+        true
+    }
+
+    // Records a setting that can't change at runtime, the values aren't included since they may be secrets
+    pub fn restart<T: PartialEq>(&mut self, key: &str, old: &T, new: &T) {
+        if old != new {
+            self.restart.push(key.to_string());
+        }
+    }
+
+    pub fn note(&mut self, change: impl Into<String>) {
+        self.applied.push(change.into());
+    }
+
+    pub fn applied(&self) -> &[String] {
+        &self.applied
+    }
+
+    pub fn requires_restart(&self) -> &[String] {
+        &self.restart
+    }
+}
+
+// Calls reload every time the process gets a SIGHUP, the conventional signal to reread configuration
+pub async fn reload_on_hangup<F, Fut>(mut reload: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            error!(
+                err = err.to_string(),
+                "failed to listen for SIGHUP, config reload is disabled"
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("got SIGHUP, reloading configuration");
+        reload().await;
+    }
+}
+
+// Calls on_change whenever one of the files changes, checking every interval. files is asked for the
+// paths on every check so the set can change, e.g. after reloading a config file that names them.
+// Modification times and sizes are polled rather than watched with inotify so files replaced by
+// swapping a symlink, like kubernetes secret volumes, are noticed too.
+pub async fn watch_files<P, F, Fut>(files: P, interval: Duration, mut on_change: F)
+where
+    P: Fn() -> Vec<PathBuf>,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let fingerprint = || {
+        files()
+            .into_iter()
+            .map(|path| {
+                let modified = std::fs::metadata(&path)
+                    .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+                    .ok();
+                (path, modified)
+            })
+            .collect::<Vec<(PathBuf, Option<(SystemTime, u64)>)>>()
+    };
+    let mut last = fingerprint();
+    loop {
+        tokio::time::sleep(interval).await;
+        let current = fingerprint();
+        if current != last {
+            info!("watched files changed");
+            on_change().await;
+            // compared against what was seen before the change was handled, so a file that was
+            // still being written is picked up again once it's done
+            last = current;
+        }
+    }
+}
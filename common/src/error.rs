@@ -0,0 +1,41 @@
+use actix_web::http::StatusCode;
+use std::io;
+use thiserror::Error;
+use tonic::{Code, Status};
+
+// Errors from the helpers shared by the gateway and storage nodes
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("authorization header is missing or malformed")]
+    MissingAuthorization,
+
+    #[error("failed to read {path}")]
+    Read {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl Error {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::MissingAuthorization => StatusCode::UNAUTHORIZED,
+            Error::Read { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> Code {
+        match self {
+            Error::MissingAuthorization => Code::Unauthenticated,
+            Error::Read { .. } => Code::Internal,
+        }
+    }
+}
+
+// Only the message is sent, sources can hold paths and other details callers shouldn't see
+impl From<Error> for Status {
+    fn from(err: Error) -> Self {
+        Status::new(err.code(), err.to_string())
+    }
+}
@@ -1,37 +1,245 @@
+use crate::metrics::metrics;
 use actix_web::http::StatusCode;
-use actix_web::{get, web::Data, App, HttpResponse, HttpResponseBuilder, HttpServer};
+use actix_web::{routes, web::Data, App, HttpResponse, HttpResponseBuilder, HttpServer};
+use futures::future::BoxFuture;
 use serde::Serialize;
+use std::future::Future;
 use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::error;
 use tracing_actix_web::TracingLogger;
 
-type HealthCheck = fn() -> Result<String, String>;
+// The result of probing a single downstream dependency
+#[derive(Serialize, Debug, Clone)]
+pub struct DependencyStatus {
+    pub name: String,
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl DependencyStatus {
+    pub fn healthy(name: impl Into<String>) -> DependencyStatus {
+        DependencyStatus {
+            name: name.into(),
+            healthy: true,
+            detail: None,
+        }
+    }
+
+    pub fn unhealthy(name: impl Into<String>, detail: impl Into<String>) -> DependencyStatus {
+        DependencyStatus {
+            name: name.into(),
+            healthy: false,
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+pub const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Probes something a service depends on. A check can report several dependencies, e.g. one per
+// storage node, a check that reports none is healthy.
+#[tonic::async_trait]
+pub trait HealthCheck: Send + Sync {
+    async fn check(&self) -> Vec<DependencyStatus>;
+}
+
+// Async closures are checks too, e.g. move || { let pool = pool.clone(); async move { ... } }
+#[tonic::async_trait]
+impl<F, Fut> HealthCheck for F
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = Vec<DependencyStatus>> + Send,
+{
+    async fn check(&self) -> Vec<DependencyStatus> {
+        self().await
+    }
+}
+
+// A check built at runtime, e.g. from a list of dependencies that aren't known until startup
+pub type BoxedCheck = Box<dyn Fn() -> BoxFuture<'static, Vec<DependencyStatus>> + Send + Sync>;
+
+// Liveness checks failing means the process should be restarted, readiness checks failing means it
+// should only be taken out of rotation until they pass again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    Liveness,
+    Readiness,
+}
+
+#[derive(Clone)]
+struct NamedCheck {
+    name: String,
+    probe: Probe,
+    // a check that doesn't finish in time is reported as failed
+    timeout: Duration,
+    check: Arc<dyn HealthCheck>,
+}
+
+// The named checks behind /healthz and /readyz
+#[derive(Clone, Default)]
+pub struct HealthChecks {
+    checks: Vec<NamedCheck>,
+}
 
 #[derive(Serialize, Debug)]
-struct HealthCheckResponse {
-    message: String,
+pub struct CheckResult {
+    pub name: String,
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<DependencyStatus>,
 }
 
-#[get("/health")]
-async fn check(health_check_fn: Data<HealthCheck>) -> HttpResponse {
-    match health_check_fn.into_inner()() {
-        Ok(success) => {
-            HttpResponseBuilder::new(StatusCode::OK).json(HealthCheckResponse { message: success })
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub checks: Vec<CheckResult>,
+}
+
+impl HealthChecks {
+    pub fn with_liveness(self, name: impl Into<String>, check: impl HealthCheck + 'static) -> Self {
+        self.with_check(name, Probe::Liveness, check)
+    }
+
+    pub fn with_readiness(
+        self,
+        name: impl Into<String>,
+        check: impl HealthCheck + 'static,
+    ) -> Self {
+        self.with_check(name, Probe::Readiness, check)
+    }
+
+    fn with_check(
+        mut self,
+        name: impl Into<String>,
+        probe: Probe,
+        check: impl HealthCheck + 'static,
+    ) -> Self {
+        self.checks.push(NamedCheck {
+            name: name.into(),
+            probe,
+            timeout: DEFAULT_CHECK_TIMEOUT,
+            check: Arc::new(check),
+        });
+        self
+    }
+
+    // Sets the timeout of the checks added with the given name
+    pub fn with_timeout(mut self, name: &str, timeout: Duration) -> Self {
+        for check in self.checks.iter_mut().filter(|check| check.name == name) {
+            check.timeout = timeout;
+        }
+        self
+    }
+
+    // Readiness runs the liveness checks too, a process that isn't live can't take traffic
+    pub async fn run(&self, probe: Probe) -> HealthReport {
+        let checks = self
+            .checks
+            .iter()
+            .filter(|check| probe == Probe::Readiness || check.probe == Probe::Liveness)
+            .map(|check| async move {
+                let dependencies =
+                    match tokio::time::timeout(check.timeout, check.check.check()).await {
+                        Ok(dependencies) => dependencies,
+                        Err(_) => vec![DependencyStatus::unhealthy(
+                            check.name.clone(),
+                            format!("timed out after {}ms", check.timeout.as_millis()),
+                        )],
+                    };
+                CheckResult {
+                    name: check.name.clone(),
+                    healthy: dependencies.iter().all(|dependency| dependency.healthy),
+                    dependencies,
+                }
+            });
+        let checks = futures::future::join_all(checks).await;
+        HealthReport {
+            healthy: checks.iter().all(|check| check.healthy),
+            checks,
         }
-        Err(err) => {
-            HttpResponseBuilder::new(StatusCode::OK).json(HealthCheckResponse { message: err })
+    }
+}
+
+// 200 when every check passed and 503 otherwise, with the per check results either way
+fn respond(probe: Probe, report: HealthReport) -> HttpResponse {
+    if report.healthy {
+        return HttpResponseBuilder::new(StatusCode::OK).json(report);
+    }
+    for check in report.checks.iter().filter(|check| !check.healthy) {
+        for dependency in check
+            .dependencies
+            .iter()
+            .filter(|dependency| !dependency.healthy)
+        {
+            error!(
+                probe = ?probe,
+                check = check.name,
+                dependency = dependency.name,
+                detail = dependency.detail,
+                "health check failed"
+            );
         }
     }
+    HttpResponseBuilder::new(StatusCode::SERVICE_UNAVAILABLE).json(report)
 }
 
-pub async fn healthcheck_endpoint(port: u16, healthcheck_fn: HealthCheck) -> io::Result<()> {
+// /health and /ready are what the probes were served at before, kept for deployments still
+// probing them
+#[routes]
+#[get("/healthz")]
+#[get("/health")]
+async fn healthz(checks: Data<HealthChecks>) -> HttpResponse {
+    respond(Probe::Liveness, checks.run(Probe::Liveness).await)
+}
+
+#[routes]
+#[get("/readyz")]
+#[get("/ready")]
+async fn readyz(checks: Data<HealthChecks>) -> HttpResponse {
+    respond(Probe::Readiness, checks.run(Probe::Readiness).await)
+}
+
+// Serves /healthz, /readyz, their /health and /ready aliases, and /metrics
+pub async fn healthcheck_endpoint(
+    bind_address: &str,
+    port: u16,
+    checks: HealthChecks,
+) -> io::Result<()> {
+    let checks = Data::new(checks);
     HttpServer::new(move || {
         App::new()
-            .app_data(Data::new(healthcheck_fn))
+            .app_data(checks.clone())
             .wrap(TracingLogger::default())
-            .service(check)
+            .service(healthz)
+            .service(readyz)
+            .service(metrics)
     })
-    .bind(("0.0.0.0", port))
-    .unwrap()
+    .bind((bind_address, port))?
     .run()
     .await
 }
+
+// Runs healthcheck_endpoint on its own thread and actix runtime, for binaries that run on a plain
+// tokio runtime
+pub fn spawn_healthcheck_endpoint(
+    bind_address: String,
+    port: u16,
+    checks: HealthChecks,
+) -> io::Result<thread::JoinHandle<()>> {
+    thread::Builder::new()
+        .name("healthcheck".to_string())
+        .spawn(move || {
+            let endpoint = healthcheck_endpoint(&bind_address, port, checks);
+            if let Err(err) = actix_web::rt::System::new().block_on(endpoint) {
+                error!(
+                    err = err.to_string(),
+                    port = port,
+                    "healthcheck endpoint failed"
+                );
+            }
+        })
+}
@@ -0,0 +1,119 @@
+use crate::storage;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_RESERVED_PREFIXES: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Charset {
+    // any bytes, keys that aren't utf-8 can only be reached over grpc
+    #[default]
+    Any,
+    Utf8,
+    // ascii letters, digits, and - . _ ~ /, which never need escaping in a url path
+    UrlSafe,
+}
+
+impl Charset {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Charset::Any => "any",
+            Charset::Utf8 => "utf8",
+            Charset::UrlSafe => "url_safe",
+        }
+    }
+
+    pub fn parse(charset: &str) -> Option<Charset> {
+        match charset {
+            "" | "any" => Some(Charset::Any),
+            "utf8" => Some(Charset::Utf8),
+            "url_safe" => Some(Charset::UrlSafe),
+            _ => None,
+        }
+    }
+
+    fn allows(&self, key: &[u8]) -> bool {
+        match self {
+            Charset::Any => true,
+            Charset::Utf8 => std::str::from_utf8(key).is_ok(),
+            Charset::UrlSafe => key
+                .iter()
+                .all(|byte| byte.is_ascii_alphanumeric() || b"-._~/".contains(byte)),
+        }
+    }
+}
+
+// The rules a namespace's keys follow, e.g.
+// {"max_length": 256, "charset": "url_safe", "reserved_prefixes": ["_internal/"]}
+// The gateway and the storage nodes both check a put's key against it, so a key written over grpc
+// can be read over http when the charset is utf8 or url_safe. Keys are stored as given, a policy
+// rejects keys rather than rewriting them, and keys written before the policy was set are kept.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyPolicy {
+    // in bytes, unlimited when unset
+    pub max_length: Option<u32>,
+    #[serde(default)]
+    pub charset: Charset,
+    // keys starting with any of them can't be written
+    #[serde(default)]
+    pub reserved_prefixes: Vec<String>,
+}
+
+impl KeyPolicy {
+    // A policy every key follows
+    pub fn is_default(&self) -> bool {
+        *self == KeyPolicy::default()
+    }
+
+    // A max length of 0 would reject every key, and an empty prefix would reserve every key
+    pub fn is_valid(&self) -> bool {
+        self.max_length.is_none_or(|max_length| max_length > 0)
+            && self.reserved_prefixes.len() <= MAX_RESERVED_PREFIXES
+            && self
+                .reserved_prefixes
+                .iter()
+                .all(|prefix| !prefix.is_empty())
+    }
+
+    // Why the key breaks the policy, None when it follows it
+    pub fn violation(&self, key: &[u8]) -> Option<String> {
+        if let Some(max_length) = self.max_length {
+            if key.len() > max_length as usize {
+                return Some(format!("the key is longer than {} bytes", max_length));
+            }
+        }
+        if !self.charset.allows(key) {
+            return Some(format!(
+                "the key has characters outside the {} charset",
+                self.charset.as_str()
+            ));
+        }
+        self.reserved_prefixes
+            .iter()
+            .find(|prefix| key.starts_with(prefix.as_bytes()))
+            .map(|prefix| format!("keys starting with {} are reserved", prefix))
+    }
+}
+
+impl From<&KeyPolicy> for storage::KeyPolicy {
+    fn from(value: &KeyPolicy) -> Self {
+        storage::KeyPolicy {
+            max_length: value.max_length,
+            charset: value.charset.as_str().to_string(),
+            reserved_prefixes: value.reserved_prefixes.clone(),
+        }
+    }
+}
+
+// Fails with the charset when it isn't one of the known ones
+impl TryFrom<&storage::KeyPolicy> for KeyPolicy {
+    type Error = String;
+
+    fn try_from(value: &storage::KeyPolicy) -> Result<Self, Self::Error> {
+        Ok(KeyPolicy {
+            max_length: value.max_length,
+            charset: Charset::parse(&value.charset).ok_or_else(|| value.charset.clone())?,
+            reserved_prefixes: value.reserved_prefixes.clone(),
+        })
+    }
+}
@@ -1,8 +1,13 @@
-use std::fs::File;
-use std::io::Read;
-
 pub mod auth;
+pub mod config;
+pub mod error;
 pub mod healthcheck;
+pub mod key_policy;
+pub mod logging;
+pub mod metrics;
+pub mod panics;
+pub mod trace_context;
+pub mod version;
 pub mod crc64hasher;
 
 pub mod storage {
@@ -13,9 +18,9 @@ pub mod admin {
     tonic::include_proto!("admin");
 }
 
-pub fn read_file_bytes(path: &str) -> std::io::Result<Vec<u8>> {
-    let mut file = File::open(path)?;
-    let mut buffer = vec![];
-    file.read_to_end(&mut buffer)?;
-    Ok(buffer)
+pub fn read_file_bytes(path: &str) -> Result<Vec<u8>, error::Error> {
+    std::fs::read(path).map_err(|source| error::Error::Read {
+        path: path.to_string(),
+        source,
+    })
 }
@@ -0,0 +1,221 @@
+use opentelemetry::trace::TraceError;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self, Tracer};
+use opentelemetry_sdk::{runtime, Resource};
+use serde::Serialize;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, Subscriber};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::filter::ParseError;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid log filter")]
+    InvalidFilter(#[from] ParseError),
+
+    #[error("failed to change the log filter")]
+    Reload(#[from] reload::Error),
+}
+
+// Changes the filter of the global subscriber installed by init. The filter is the configured level
+// plus, while an override is active, extra directives such as storage::partition=debug.
+#[derive(Clone)]
+pub struct LogLevel {
+    handle: reload::Handle<EnvFilter, Registry>,
+    state: Arc<Mutex<FilterState>>,
+}
+
+struct FilterState {
+    level: LevelFilter,
+    directives: Option<String>,
+    expires_at: Option<SystemTime>,
+    // bumped on every override so an expiring override doesn't clear the one that replaced it
+    generation: u64,
+}
+
+// The filter in effect, what the log filter endpoints return
+#[derive(Serialize, Debug)]
+pub struct FilterStatus {
+    pub level: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directives: Option<String>,
+    // seconds since the epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+impl LogLevel {
+    pub fn current(&self) -> FilterStatus {
+        let state = self.state.lock().unwrap();
+        FilterStatus {
+            level: state.level.to_string(),
+            directives: state.directives.clone(),
+            expires_at: state.expires_at.map(|expires_at| {
+                expires_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+        }
+    }
+
+    // Sets the configured level, an active override stays on top of it
+    pub fn set(&self, level: LevelFilter) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.level = level;
+        self.apply(&state)
+    }
+
+    // Adds directives, e.g. kvstore::connections=debug, on top of the configured level until they're
+    // cleared or, when given a duration, until it passes. Must be called from within a tokio runtime.
+    pub fn override_with(&self, directives: &str, duration: Option<Duration>) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        filter(state.level, Some(directives))?;
+        state.directives = Some(directives.to_string());
+        state.expires_at = duration.map(|duration| SystemTime::now() + duration);
+        state.generation += 1;
+        self.apply(&state)?;
+        info!(directives = directives, duration = ?duration, "overriding the log filter");
+
+        if let Some(duration) = duration {
+            let generation = state.generation;
+            let log_level = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(duration).await;
+                if let Err(err) = log_level.clear_generation(Some(generation)) {
+                    error!(
+                        err = err.to_string(),
+                        "failed to clear the log filter override"
+                    );
+                }
+            });
+        }
+        Ok(())
+    }
+
+    // Goes back to the configured level
+    pub fn clear(&self) -> Result<(), Error> {
+        self.clear_generation(None)
+    }
+
+    fn clear_generation(&self, generation: Option<u64>) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.directives.is_none()
+            || generation.is_some_and(|generation| generation != state.generation)
+        {
+            return Ok(());
+        }
+        state.directives = None;
+        state.expires_at = None;
+        self.apply(&state)?;
+        info!("cleared the log filter override");
+        Ok(())
+    }
+
+    fn apply(&self, state: &FilterState) -> Result<(), Error> {
+        Ok(self
+            .handle
+            .reload(filter(state.level, state.directives.as_deref())?)?)
+    }
+}
+
+fn filter(level: LevelFilter, directives: Option<&str>) -> Result<EnvFilter, ParseError> {
+    match directives {
+        Some(directives) => EnvFilter::builder().parse(format!("{},{}", level, directives)),
+        None => EnvFilter::builder().parse(level.to_string()),
+    }
+}
+
+// Installs the global subscriber, json lines in release builds and human readable lines in debug
+// builds. The level starts at info so config errors are logged, and is set from the config once loaded.
+// Spans are also exported over otlp when an endpoint is configured, see otlp_layer. Must be called
+// from within a tokio runtime.
+pub fn init(service: &str) -> LogLevel {
+    let (otlp, otlp_error) = match otlp_layer(service) {
+        Ok(otlp) => (otlp, None),
+        Err(err) => (None, Some(err)),
+    };
+    let exporting = otlp.is_some();
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(LevelFilter::INFO.to_string()));
+    let format = fmt::layer()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_target(true)
+        .with_thread_names(true)
+        .with_file(true);
+    // boxed since the json and human readable formats are different types
+    let format = match cfg!(debug_assertions) {
+        true => format.boxed(),
+        false => format.json().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(otlp)
+        .with(format)
+        .init();
+
+    if exporting {
+        info!("exporting spans over otlp");
+    }
+    if let Some(err) = otlp_error {
+        error!(err = err.to_string(), "failed to start the otlp exporter");
+    }
+    LogLevel {
+        handle,
+        state: Arc::new(Mutex::new(FilterState {
+            level: LevelFilter::INFO,
+            directives: None,
+            expires_at: None,
+            generation: 0,
+        })),
+    }
+}
+
+// Flushes spans that haven't been exported yet, called before the process exits. Shutting down blocks
+// until the exporter's task, which runs on this runtime, has flushed, so it's done on another thread.
+pub async fn shutdown() {
+    if let Err(err) =
+        tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await
+    {
+        error!(err = err.to_string(), "failed to flush spans");
+    }
+}
+
+// Exporting is opt in with the standard OTEL_EXPORTER_OTLP_ENDPOINT (or
+// OTEL_EXPORTER_OTLP_TRACES_ENDPOINT) environment variable, e.g. http://localhost:4317 for a local
+// Jaeger or Tempo. Sampling is set with OTEL_TRACES_SAMPLER and OTEL_TRACES_SAMPLER_ARG, e.g.
+// parentbased_traceidratio and 0.1 to keep a tenth of traces, and OTEL_SERVICE_NAME overrides the
+// service name.
+fn otlp_layer<S>(service: &str) -> Result<Option<OpenTelemetryLayer<S, Tracer>>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let configured = [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ]
+    .iter()
+    .any(|name| env::var(name).is_ok_and(|endpoint| !endpoint.is_empty()));
+    if !configured {
+        return Ok(None);
+    }
+    let service = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| service.to_string());
+    let resource =
+        Resource::default().merge(&Resource::new([KeyValue::new("service.name", service)]));
+    // incoming http requests join the caller's trace when they carry a w3c traceparent header
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(runtime::Tokio)?;
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
@@ -0,0 +1,118 @@
+use actix_web::http::StatusCode;
+use actix_web::{get, HttpResponse, HttpResponseBuilder};
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::time::{Duration, Instant};
+use tracing::error;
+
+// The registry every metric in the process is registered with and /metrics serves
+pub fn registry() -> &'static Registry {
+    prometheus::default_registry()
+}
+
+// Registers a metric, returning it so a metric can be built and registered in one expression. A metric whose name is
+// already taken still works, it just isn't exported.
+pub fn register<C: Collector + Clone + 'static>(collector: C) -> C {
+    if let Err(err) = registry().register(Box::new(collector.clone())) {
+        error!(err = err.to_string(), "failed to register metric");
+    }
+    collector
+}
+
+// Rate, errors, and duration of the requests a service handles, labeled by operation. A prefix of
+// kvstore_http registers kvstore_http_requests_total, kvstore_http_errors_total, and
+// kvstore_http_request_duration_seconds.
+#[derive(Debug, Clone)]
+pub struct RequestMetrics {
+    requests: IntCounterVec,
+    errors: IntCounterVec,
+    duration: HistogramVec,
+}
+
+impl RequestMetrics {
+    pub fn new(prefix: &str, description: &str) -> RequestMetrics {
+        RequestMetrics {
+            requests: register(
+                IntCounterVec::new(
+                    Opts::new(
+                        format!("{}_requests_total", prefix),
+                        format!("Handled {}", description),
+                    ),
+                    &["operation"],
+                )
+                .unwrap(),
+            ),
+            errors: register(
+                IntCounterVec::new(
+                    Opts::new(
+                        format!("{}_errors_total", prefix),
+                        format!("Failed {} by error code", description),
+                    ),
+                    &["operation", "code"],
+                )
+                .unwrap(),
+            ),
+            duration: register(
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        format!("{}_request_duration_seconds", prefix),
+                        format!("Latency of {}", description),
+                    ),
+                    &["operation"],
+                )
+                .unwrap(),
+            ),
+        }
+    }
+
+    pub fn start(&self, operation: impl Into<String>) -> RequestTimer {
+        RequestTimer {
+            metrics: self.clone(),
+            operation: operation.into(),
+            start: Instant::now(),
+        }
+    }
+
+    // error is the failed request's code, e.g. an http status or grpc code
+    pub fn observe(&self, operation: &str, error: Option<&str>, elapsed: Duration) {
+        self.requests.with_label_values(&[operation]).inc();
+        if let Some(code) = error {
+            self.errors.with_label_values(&[operation, code]).inc();
+        }
+        self.duration
+            .with_label_values(&[operation])
+            .observe(elapsed.as_secs_f64());
+    }
+}
+
+// A request being timed, nothing is recorded unless it's finished
+pub struct RequestTimer {
+    metrics: RequestMetrics,
+    operation: String,
+    start: Instant,
+}
+
+impl RequestTimer {
+    pub fn finish(self, error: Option<&str>) {
+        self.metrics
+            .observe(&self.operation, error, self.start.elapsed());
+    }
+}
+
+// Every registered metric in the text exposition format
+#[get("/metrics")]
+pub async fn metrics() -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&registry().gather(), &mut buffer) {
+        Ok(()) => HttpResponseBuilder::new(StatusCode::OK)
+            .content_type(encoder.format_type())
+            .body(buffer),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to encode metrics");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use crate::metrics::register;
+use prometheus::{IntCounter, Opts};
+use std::backtrace::Backtrace;
+use std::panic::{self, PanicHookInfo};
+use std::sync::OnceLock;
+use std::thread;
+use tracing::error;
+
+// Replaces the default panic hook, which writes unstructured text to stderr, with one that logs the
+// panic through tracing and counts it in {service}_panics_total. A panic still only unwinds the
+// thread or task it happened on.
+pub fn install_hook(service: &str) {
+    let panics = panic_counter(service);
+    panic::set_hook(Box::new(move |info| {
+        panics.inc();
+        log_panic(info);
+    }));
+}
+
+// Like install_hook, but any panic is fatal: last_gasp runs, e.g. to flush data to disk, and then the
+// process aborts. For services that can't trust their state once something has panicked.
+pub fn install_fatal_hook(service: &str, last_gasp: impl Fn() + Send + Sync + 'static) {
+    let panics = panic_counter(service);
+    panic::set_hook(Box::new(move |info| {
+        panics.inc();
+        log_panic(info);
+        last_gasp();
+        error!("aborting after panic");
+        std::process::abort();
+    }));
+}
+
+// shared so the hook can be replaced, e.g. with a fatal one once there's data to flush
+fn panic_counter(service: &str) -> IntCounter {
+    static PANICS: OnceLock<IntCounter> = OnceLock::new();
+    PANICS
+        .get_or_init(|| {
+            register(
+                IntCounter::with_opts(Opts::new(
+                    format!("{}_panics_total", service),
+                    "Panics since the process started",
+                ))
+                .unwrap(),
+            )
+        })
+        .clone()
+}
+
+fn log_panic(info: &PanicHookInfo) {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload");
+    let location = info.location().map(|location| {
+        format!(
+            "{}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        )
+    });
+    error!(
+        panic.message = message,
+        panic.location = location,
+        panic.thread = thread::current().name(),
+        panic.backtrace = Backtrace::force_capture().to_string(),
+        "panicked"
+    );
+}
@@ -0,0 +1,49 @@
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use tonic::codegen::http::{HeaderMap, Request};
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tracing::{info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+// W3C trace context, the traceparent and tracestate headers, carried from the gateway to the
+// storage nodes in grpc metadata so a request's spans on both end up in one trace. A span only has
+// a context to carry when spans are exported, see logging::init.
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+// Adds the span's trace context to an outgoing rpc
+pub fn inject(span: &Span, metadata: &mut MetadataMap) {
+    TraceContextPropagator::new().inject_context(&span.context(), &mut MetadataInjector(metadata));
+}
+
+// The span an incoming rpc is served in, a child of the caller's span when the rpc carries a trace
+// context. Used as the server's trace_fn, since interceptors run before the rpc's span is entered.
+pub fn rpc_span(request: &Request<()>) -> Span {
+    let span = info_span!("rpc", method = request.uri().path());
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(request.headers()));
+    span.set_parent(parent);
+    span
+}
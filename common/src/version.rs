@@ -0,0 +1,61 @@
+use serde::Serialize;
+
+pub use git_version::git_version;
+
+// The version of the storage grpc protocol, bumped whenever a change breaks gateways or storage
+// nodes running the previous version
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// What a binary was built from, see build_info!
+#[derive(Serialize, Debug, Clone)]
+pub struct BuildInfo {
+    pub service: &'static str,
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    // seconds since the epoch
+    pub build_timestamp: u64,
+    pub profile: &'static str,
+    pub features: Vec<&'static str>,
+    pub protocol_version: u32,
+}
+
+impl BuildInfo {
+    pub fn new(
+        service: &'static str,
+        version: &'static str,
+        git_hash: &'static str,
+        build_timestamp: &'static str,
+        profile: &'static str,
+        features: &'static str,
+    ) -> BuildInfo {
+        BuildInfo {
+            service,
+            version,
+            git_hash,
+            build_timestamp: build_timestamp.parse().unwrap_or_default(),
+            profile,
+            features: features
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .collect(),
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+// The BuildInfo of the calling binary. Expands in the binary's crate so the version, git hash, and
+// features are its own, which needs a build script setting BUILD_TIMESTAMP, BUILD_PROFILE, and
+// BUILD_FEATURES, like the gateway's and the storage node's.
+#[macro_export]
+macro_rules! build_info {
+    ($service:expr) => {
+        $crate::version::BuildInfo::new(
+            $service,
+            env!("CARGO_PKG_VERSION"),
+            $crate::version::git_version!(),
+            env!("BUILD_TIMESTAMP"),
+            env!("BUILD_PROFILE"),
+            env!("BUILD_FEATURES"),
+        )
+    };
+}
@@ -0,0 +1,98 @@
+use common::storage::node_admin_client::NodeAdminClient;
+use thiserror::Error;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Request, Status};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{node}: {}", .status.message())]
+    Status {
+        node: String,
+        // boxed since a status is much larger than the other errors
+        #[source]
+        status: Box<Status>,
+    },
+
+    #[error("invalid node {0}")]
+    InvalidNode(String, #[source] tonic::transport::Error),
+
+    #[error("failed to issue an admin token")]
+    Token(#[from] jsonwebtoken::errors::Error),
+
+    #[error(transparent)]
+    Common(#[from] common::error::Error),
+
+    #[error("invalid json")]
+    Json(#[from] serde_json::Error),
+
+    #[error("no credentials, pass --token, --jwt-secret, or --jwt-private-key")]
+    NoCredentials,
+
+    #[error("partition {0} isn't on any of the nodes")]
+    PartitionNotFound(String),
+
+    #[error("{0}")]
+    Usage(&'static str),
+}
+
+impl Error {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Error::Status { status, .. } if status.code() == Code::NotFound)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Sends the admin token with every call
+#[derive(Clone)]
+pub struct BearerToken(MetadataValue<Ascii>);
+
+impl Interceptor for BearerToken {
+    fn call(&mut self, mut request: Request<()>) -> std::result::Result<Request<()>, Status> {
+        request
+            .metadata_mut()
+            .insert("authorization", self.0.clone());
+        Ok(request)
+    }
+}
+
+pub type AdminClient = NodeAdminClient<InterceptedService<Channel, BearerToken>>;
+
+// The admin service of one storage node
+pub struct Node {
+    pub endpoint: String,
+    client: AdminClient,
+}
+
+impl Node {
+    pub fn connect(endpoint: &str, token: &str) -> Result<Node> {
+        let channel = Endpoint::from_shared(endpoint.to_string())
+            .map_err(|err| Error::InvalidNode(endpoint.to_string(), err))?
+            .connect_lazy();
+        let token = format!("Bearer {}", token)
+            .parse()
+            .map_err(|_| Error::Usage("the token isn't a valid header value"))?;
+        Ok(Node {
+            endpoint: endpoint.to_string(),
+            client: NodeAdminClient::with_interceptor(channel, BearerToken(token)),
+        })
+    }
+
+    // Calls the node, errors are tagged with the node they came from
+    pub async fn call<T, F, Fut>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(AdminClient) -> Fut,
+        Fut: std::future::Future<Output = std::result::Result<tonic::Response<T>, Status>>,
+    {
+        f(self.client.clone())
+            .await
+            .map(|response| response.into_inner())
+            .map_err(|status| Error::Status {
+                node: self.endpoint.clone(),
+                status: Box::new(status),
+            })
+    }
+}
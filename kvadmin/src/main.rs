@@ -0,0 +1,684 @@
+use clap::{Args, Parser, Subcommand};
+use client::{Error, Node, Result};
+use common::auth::{KeyAlgorithm, KeyJwtIssuer, DEFAULT_ISSUER, DEFAULT_SERVICE_AUDIENCE};
+use common::storage::{
+    AddPartitionRequest, BackupRequest, BackupResponse, DumpPartitionRequest, DumpedKey,
+    FollowPartitionRequest, ListOrphanedPartitionsResponse, ListPartitionsRequest, PartitionInfo,
+    PartitionRequest, ReplicationStatus, SetCompactionsPausedRequest, SetLogFilterRequest,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+
+mod client;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "kvadmin",
+    version,
+    about = "Operator tool for kvstore storage nodes"
+)]
+struct Cli {
+    /// Storage nodes to manage
+    #[arg(
+        long = "node",
+        global = true,
+        env = "KVADMIN_NODES",
+        value_delimiter = ',',
+        default_value = "http://[::1]:50051"
+    )]
+    nodes: Vec<String>,
+
+    #[command(flatten)]
+    credentials: Credentials,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+// An admin token can be given as is, or issued with the key the gateway signs service tokens with
+#[derive(Args, Debug)]
+struct Credentials {
+    #[arg(long, global = true, env = "KVADMIN_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "KVADMIN_JWT_SECRET",
+        hide_env_values = true
+    )]
+    jwt_secret: Option<String>,
+
+    #[arg(long, global = true, env = "KVADMIN_JWT_PRIVATE_KEY")]
+    jwt_private_key: Option<String>,
+
+    #[arg(long, global = true, env = "KVADMIN_JWT_ALGORITHM")]
+    jwt_algorithm: Option<KeyAlgorithm>,
+
+    #[arg(long, global = true, env = "KVADMIN_JWT_ISSUER", default_value = DEFAULT_ISSUER)]
+    jwt_issuer: String,
+
+    #[arg(long, global = true, env = "KVADMIN_JWT_AUDIENCE", default_value = DEFAULT_SERVICE_AUDIENCE)]
+    jwt_audience: String,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the partitions on each node with their estimated size
+    Partitions {
+        #[arg(long)]
+        tenant: Option<String>,
+        #[arg(long)]
+        namespace: Option<String>,
+    },
+    /// Total keys and bytes per namespace across the nodes
+    Stats {
+        #[arg(long)]
+        tenant: Option<String>,
+    },
+    /// Print which node holds each partition of each namespace
+    PartitionMap,
+    /// Compact a partition on whichever node holds it
+    Compact { partition: String },
+    /// Add a partition to a namespace, keys already written to the namespace are not moved
+    AddPartition {
+        #[arg(long)]
+        tenant: String,
+        #[arg(long)]
+        namespace: String,
+        /// Generated when not given
+        #[arg(long)]
+        id: Option<String>,
+    },
+    /// Stop routing keys to a partition, its files are left on the node's disk
+    RemovePartition { partition: String },
+    /// List partition directories the nodes don't use, and partitions whose directory is missing
+    Orphans,
+    /// Print raw keys and their decoded metadata from a partition, values only with --values
+    Dump {
+        partition: String,
+        #[arg(long)]
+        start_at: Option<String>,
+        #[arg(long)]
+        end_before: Option<String>,
+        #[arg(long, default_value_t = 50)]
+        limit: u32,
+        /// Only every nth key in the range
+        #[arg(long, default_value_t = 1)]
+        every: u32,
+        #[arg(long)]
+        values: bool,
+    },
+    /// Back up the partitions of a namespace, of a tenant, or everything, on each node
+    Backup {
+        #[arg(long)]
+        tenant: Option<String>,
+        #[arg(long, requires = "tenant")]
+        namespace: Option<String>,
+        /// Upload to this object store url, e.g. s3://bucket/exports, instead of the nodes'
+        /// configured backup_upload_url
+        #[arg(long)]
+        upload_to: Option<String>,
+    },
+    /// Print the build each node is running
+    Version,
+    #[command(subcommand, name = "log-filter")]
+    LogFilter(LogFilterCommand),
+    /// Show, pause, or resume the scheduled compactions of each node
+    #[command(subcommand)]
+    Compactions(CompactionsCommand),
+    /// Show the nodes' replication logs and followers' lag, or change which partition leads
+    #[command(subcommand)]
+    Replication(ReplicationCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum ReplicationCommand {
+    Status,
+    /// Make the --node's partition follow the same partition on the leader and refuse writes
+    Follow {
+        partition: String,
+        /// The leader node's admin endpoint, e.g. http://storage-1:50051
+        #[arg(long)]
+        leader: String,
+    },
+    /// Make the --node's follower partition take writes, stop or demote the old leader first
+    Promote {
+        partition: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CompactionsCommand {
+    Status,
+    /// Stop scheduled compactions from starting, the one running is finished
+    Pause,
+    Resume,
+}
+
+#[derive(Subcommand, Debug)]
+enum LogFilterCommand {
+    Get,
+    /// Add tracing directives on top of the configured level, e.g. storage::partition=debug
+    Set {
+        directives: String,
+        #[arg(long)]
+        duration_secs: Option<u64>,
+    },
+    Clear,
+}
+
+#[derive(Serialize, Debug)]
+struct PartitionRow {
+    node: String,
+    tenant_id: String,
+    namespace_id: String,
+    partition_id: String,
+    key_count: u64,
+    total_bytes: u64,
+}
+
+// keys and values are printed with non printable bytes escaped
+#[derive(Serialize, Debug)]
+struct DumpedKeyRow {
+    key: String,
+    raw_metadata: String,
+    crc: Option<u32>,
+    version: Option<u32>,
+    value_len: Option<u64>,
+    crc_matches: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+impl From<DumpedKey> for DumpedKeyRow {
+    fn from(key: DumpedKey) -> Self {
+        DumpedKeyRow {
+            key: key.key.escape_ascii().to_string(),
+            raw_metadata: key.raw_metadata.escape_ascii().to_string(),
+            crc: key.crc,
+            version: key.version,
+            value_len: key.value_len,
+            crc_matches: key.crc_matches,
+            value: key.value.map(|value| value.escape_ascii().to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct DumpRow {
+    node: String,
+    keys: Vec<DumpedKeyRow>,
+    // pass as --start-at for the next page
+    next: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct BackupRow {
+    backup_id: String,
+    partitions: u32,
+    total_bytes: u64,
+    uploaded_to: Option<String>,
+}
+
+impl From<BackupResponse> for BackupRow {
+    fn from(backup: BackupResponse) -> Self {
+        BackupRow {
+            backup_id: backup.backup_id,
+            partitions: backup.partitions,
+            total_bytes: backup.total_bytes,
+            uploaded_to: backup.uploaded_to,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct OrphanRow {
+    partition_id: String,
+    bytes: u64,
+    first_seen: String,
+    quarantined: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct OrphansRow {
+    orphaned: Vec<OrphanRow>,
+    missing: Vec<String>,
+}
+
+impl From<ListOrphanedPartitionsResponse> for OrphansRow {
+    fn from(response: ListOrphanedPartitionsResponse) -> Self {
+        OrphansRow {
+            orphaned: response
+                .orphaned
+                .into_iter()
+                .map(|orphan| OrphanRow {
+                    partition_id: orphan.partition_id,
+                    bytes: orphan.bytes,
+                    first_seen: orphan
+                        .first_seen
+                        .map(|first_seen| first_seen.to_string())
+                        .unwrap_or_default(),
+                    quarantined: orphan.quarantined,
+                })
+                .collect(),
+            missing: response.missing,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ReplicationRow {
+    partition_id: String,
+    leader: Option<String>,
+    next_sequence: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    leader_next_sequence: Option<u64>,
+    lag_entries: u64,
+    lag_seconds: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+    bootstrapping: bool,
+}
+
+impl From<ReplicationStatus> for ReplicationRow {
+    fn from(status: ReplicationStatus) -> Self {
+        ReplicationRow {
+            partition_id: status.partition_id,
+            leader: status.leader,
+            next_sequence: status.next_sequence,
+            leader_next_sequence: status.leader_next_sequence,
+            lag_entries: status.lag_entries,
+            lag_seconds: status.lag_seconds,
+            last_error: status.last_error,
+            bootstrapping: status.bootstrapping,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+struct NamespaceStats {
+    partitions: u32,
+    key_count: u64,
+    total_bytes: u64,
+}
+
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn token(credentials: &Credentials) -> Result<String> {
+    if let Some(token) = &credentials.token {
+        return Ok(token.clone());
+    }
+    let (algorithm, key) = match (&credentials.jwt_secret, &credentials.jwt_private_key) {
+        (Some(secret), _) => (KeyAlgorithm::Hs256, secret.clone().into_bytes()),
+        (None, Some(path)) => (
+            credentials.jwt_algorithm.unwrap_or_default(),
+            common::read_file_bytes(path)?,
+        ),
+        (None, None) => return Err(Error::NoCredentials),
+    };
+    let identity = KeyJwtIssuer::new(algorithm, &key)?
+        .with_issuer(credentials.jwt_issuer.clone())
+        .with_audience(credentials.jwt_audience.clone())
+        .new_operator_identity("kvadmin")?;
+    Ok(identity.token().to_string())
+}
+
+async fn list_partitions(
+    nodes: &[Node],
+    tenant: Option<String>,
+    namespace: Option<String>,
+) -> Result<Vec<PartitionRow>> {
+    let mut rows = Vec::new();
+    for node in nodes {
+        let request = ListPartitionsRequest {
+            tenant_id: tenant.clone(),
+            namespace_id: namespace.clone(),
+        };
+        let response = node
+            .call(|mut client| async move { client.list_partitions(request).await })
+            .await?;
+        rows.extend(
+            response
+                .partitions
+                .into_iter()
+                .map(|partition| row(&node.endpoint, partition)),
+        );
+    }
+    Ok(rows)
+}
+
+fn row(node: &str, partition: PartitionInfo) -> PartitionRow {
+    PartitionRow {
+        node: node.to_string(),
+        tenant_id: partition.tenant_id,
+        namespace_id: partition.namespace_id,
+        partition_id: partition.partition_id,
+        key_count: partition.key_count,
+        total_bytes: partition.total_bytes,
+    }
+}
+
+// Runs a partition rpc on every node until one has the partition, returning the node that did
+async fn on_partition_node<'a, T, R, F, Fut>(
+    nodes: &'a [Node],
+    partition: &str,
+    request: R,
+    f: F,
+) -> Result<(&'a Node, T)>
+where
+    R: Clone,
+    F: Fn(client::AdminClient, R) -> Fut,
+    Fut: std::future::Future<Output = std::result::Result<tonic::Response<T>, tonic::Status>>,
+{
+    for node in nodes {
+        let request = request.clone();
+        match node.call(|client| f(client, request)).await {
+            Ok(response) => return Ok((node, response)),
+            Err(err) if err.is_not_found() => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Err(Error::PartitionNotFound(partition.to_string()))
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let token = token(&cli.credentials)?;
+    let nodes = cli
+        .nodes
+        .iter()
+        .map(|endpoint| Node::connect(endpoint, &token))
+        .collect::<Result<Vec<Node>>>()?;
+
+    match cli.command {
+        Command::Partitions { tenant, namespace } => {
+            print_json(&list_partitions(&nodes, tenant, namespace).await?)
+        }
+        Command::Stats { tenant } => {
+            let mut stats: BTreeMap<String, BTreeMap<String, NamespaceStats>> = BTreeMap::new();
+            for row in list_partitions(&nodes, tenant, None).await? {
+                let namespace = stats
+                    .entry(row.tenant_id)
+                    .or_default()
+                    .entry(row.namespace_id)
+                    .or_default();
+                namespace.partitions += 1;
+                namespace.key_count += row.key_count;
+                namespace.total_bytes += row.total_bytes;
+            }
+            print_json(&stats)
+        }
+        Command::PartitionMap => {
+            // tenant, then namespace, then the partitions in the order each node routes keys to them
+            let mut map: BTreeMap<String, BTreeMap<String, Vec<(String, String)>>> =
+                BTreeMap::new();
+            for row in list_partitions(&nodes, None, None).await? {
+                map.entry(row.tenant_id)
+                    .or_default()
+                    .entry(row.namespace_id)
+                    .or_default()
+                    .push((row.partition_id, row.node));
+            }
+            print_json(&map)
+        }
+        Command::Compact { partition } => {
+            let request = PartitionRequest {
+                partition_id: partition.clone(),
+            };
+            let (node, ()) = on_partition_node(
+                &nodes,
+                &partition,
+                request,
+                |mut client, request| async move { client.compact_partition(request).await },
+            )
+            .await?;
+            eprintln!("done on {}", node.endpoint);
+            Ok(())
+        }
+        Command::AddPartition {
+            tenant,
+            namespace,
+            id,
+        } => {
+            let [node] = nodes.as_slice() else {
+                return Err(Error::Usage("pass the one --node to add the partition to"));
+            };
+            let request = AddPartitionRequest {
+                tenant_id: tenant,
+                namespace_id: namespace,
+                partition_id: id,
+            };
+            let partition = node
+                .call(|mut client| async move { client.add_partition(request).await })
+                .await?;
+            print_json(&row(&node.endpoint, partition))
+        }
+        Command::RemovePartition { partition } => {
+            let request = PartitionRequest {
+                partition_id: partition.clone(),
+            };
+            let (node, ()) = on_partition_node(
+                &nodes,
+                &partition,
+                request,
+                |mut client, request| async move { client.remove_partition(request).await },
+            )
+            .await?;
+            eprintln!("done on {}", node.endpoint);
+            Ok(())
+        }
+        Command::Dump {
+            partition,
+            start_at,
+            end_before,
+            limit,
+            every,
+            values,
+        } => {
+            let request = DumpPartitionRequest {
+                partition_id: partition.clone(),
+                start_at: start_at.map(String::into_bytes),
+                end_before: end_before.map(String::into_bytes),
+                limit,
+                sample_every: every,
+                include_values: values,
+            };
+            let (node, dump) = on_partition_node(
+                &nodes,
+                &partition,
+                request,
+                |mut client, request| async move { client.dump_partition(request).await },
+            )
+            .await?;
+            print_json(&DumpRow {
+                node: node.endpoint.clone(),
+                keys: dump.keys.into_iter().map(DumpedKeyRow::from).collect(),
+                next: dump.next.map(|next| next.escape_ascii().to_string()),
+            })
+        }
+        Command::Backup {
+            tenant,
+            namespace,
+            upload_to,
+        } => {
+            let mut backups = BTreeMap::new();
+            for node in &nodes {
+                let request = BackupRequest {
+                    tenant_id: tenant.clone(),
+                    namespace_id: namespace.clone(),
+                    upload_to: upload_to.clone(),
+                };
+                match node
+                    .call(|mut client| async move { client.backup(request).await })
+                    .await
+                {
+                    Ok(backup) => {
+                        backups.insert(node.endpoint.clone(), BackupRow::from(backup));
+                    }
+                    // the namespace or tenant just has no partitions on this node
+                    Err(err) if err.is_not_found() => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+            print_json(&backups)
+        }
+        Command::Orphans => {
+            let mut orphans = BTreeMap::new();
+            for node in &nodes {
+                let response = node
+                    .call(|mut client| async move { client.list_orphaned_partitions(()).await })
+                    .await?;
+                orphans.insert(node.endpoint.clone(), OrphansRow::from(response));
+            }
+            print_json(&orphans)
+        }
+        Command::Version => {
+            for node in &nodes {
+                let version = node
+                    .call(|mut client| async move { client.version(()).await })
+                    .await?;
+                println!(
+                    "{}: {} {} ({}), protocol {}",
+                    node.endpoint,
+                    version.service,
+                    version.version,
+                    version.git_hash,
+                    version.protocol_version
+                );
+            }
+            Ok(())
+        }
+        Command::Compactions(command) => {
+            for node in &nodes {
+                let status = match command {
+                    CompactionsCommand::Status => {
+                        node.call(
+                            |mut client| async move { client.get_compaction_status(()).await },
+                        )
+                        .await?
+                    }
+                    CompactionsCommand::Pause | CompactionsCommand::Resume => {
+                        let request = SetCompactionsPausedRequest {
+                            paused: matches!(command, CompactionsCommand::Pause),
+                        };
+                        node.call(|mut client| async move {
+                            client.set_compactions_paused(request).await
+                        })
+                        .await?
+                    }
+                };
+                println!(
+                    "{}: {}{}{}{}",
+                    node.endpoint,
+                    status
+                        .schedule
+                        .as_deref()
+                        .unwrap_or("no compaction windows"),
+                    if status.in_window {
+                        ", in a window"
+                    } else {
+                        ""
+                    },
+                    if status.paused { ", paused" } else { "" },
+                    status
+                        .compacting
+                        .map(|partition| format!(", compacting {}", partition))
+                        .unwrap_or_default()
+                );
+            }
+            Ok(())
+        }
+        Command::Replication(ReplicationCommand::Status) => {
+            let mut statuses = BTreeMap::new();
+            for node in &nodes {
+                let response = node
+                    .call(|mut client| async move { client.get_replication_status(()).await })
+                    .await?;
+                let rows: Vec<ReplicationRow> = response
+                    .partitions
+                    .into_iter()
+                    .map(ReplicationRow::from)
+                    .collect();
+                statuses.insert(node.endpoint.clone(), rows);
+            }
+            print_json(&statuses)
+        }
+        // the partition is on the leader's node too, so the node to change has to be given
+        Command::Replication(command) => {
+            let [node] = nodes.as_slice() else {
+                return Err(Error::Usage("pass the one --node to change"));
+            };
+            let status = match command {
+                ReplicationCommand::Follow { partition, leader } => {
+                    let request = FollowPartitionRequest {
+                        partition_id: partition,
+                        leader,
+                    };
+                    node.call(|mut client| async move { client.follow_partition(request).await })
+                        .await?
+                }
+                ReplicationCommand::Promote { partition } => {
+                    let request = PartitionRequest {
+                        partition_id: partition,
+                    };
+                    node.call(|mut client| async move { client.promote_partition(request).await })
+                        .await?
+                }
+                ReplicationCommand::Status => unreachable!("handled above"),
+            };
+            print_json(&ReplicationRow::from(status))
+        }
+        Command::LogFilter(command) => {
+            for node in &nodes {
+                let filter = match &command {
+                    LogFilterCommand::Get => {
+                        node.call(|mut client| async move { client.get_log_filter(()).await })
+                            .await?
+                    }
+                    LogFilterCommand::Set {
+                        directives,
+                        duration_secs,
+                    } => {
+                        let request = SetLogFilterRequest {
+                            directives: directives.clone(),
+                            duration_secs: *duration_secs,
+                        };
+                        node.call(|mut client| async move { client.set_log_filter(request).await })
+                            .await?
+                    }
+                    // an empty filter clears the override
+                    LogFilterCommand::Clear => {
+                        let request = SetLogFilterRequest::default();
+                        node.call(|mut client| async move { client.set_log_filter(request).await })
+                            .await?
+                    }
+                };
+                println!(
+                    "{}: {}{}",
+                    node.endpoint,
+                    filter.level,
+                    filter
+                        .directives
+                        .map(|directives| format!(",{}", directives))
+                        .unwrap_or_default()
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("kvadmin: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,522 @@
+use base64::{engine::general_purpose, Engine as _};
+use derive_more::{Display, Error};
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::{Method, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader};
+use std::time::Duration;
+
+// A scan streams the whole namespace, the gateway gives up on one after an hour by default
+const SCAN_TIMEOUT: Duration = Duration::from_secs(3600);
+
+#[derive(Error, Display, Debug)]
+pub enum Error {
+    #[display(fmt = "request failed: {}", _0)]
+    Http(reqwest::Error),
+
+    #[display(fmt = "{}: {}", status, body)]
+    Status {
+        status: StatusCode,
+        #[error(not(source))]
+        body: String,
+    },
+
+    #[display(fmt = "{}", _0)]
+    Io(std::io::Error),
+
+    #[display(fmt = "invalid json: {}", _0)]
+    Json(serde_json::Error),
+
+    #[display(fmt = "invalid endpoint: {}", _0)]
+    InvalidEndpoint(#[error(not(source))] String),
+
+    #[display(fmt = "not logged in, run kvctl login or pass --token")]
+    NotLoggedIn,
+
+    #[display(fmt = "line {}: {}", line, reason)]
+    InvalidRecord {
+        line: usize,
+        #[error(not(source))]
+        reason: String,
+    },
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Json(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub enum Credential {
+    Token(String),
+    ApiKey(String),
+}
+
+#[derive(Serialize, Debug)]
+pub struct TokenRequest<'a> {
+    pub name: &'a str,
+    pub password: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespaces: Option<Vec<String>>,
+}
+
+// Settings that aren't given are left to the gateway's defaults
+#[derive(Serialize, Debug)]
+pub struct NamespaceSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_ttl_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub durability: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_ttl_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenResponse {
+    pub token: String,
+    pub expires_at: u64,
+    pub scopes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespaces: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyPage {
+    pub keys: Vec<KeyInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyInfo {
+    pub name: String,
+    pub version: u32,
+    pub crc: u32,
+    // only when the keys were listed with their values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+// Thin wrapper over the gateway's http api
+pub struct Client {
+    endpoint: String,
+    credential: Option<Credential>,
+    http: reqwest::blocking::Client,
+}
+
+impl Client {
+    pub fn new(endpoint: &str, credential: Option<Credential>) -> Client {
+        Client {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            credential,
+            http: reqwest::blocking::Client::new(),
+        }
+    }
+
+    // Keys and names are percent encoded so keys containing a / still address a single key
+    fn url(&self, segments: &[&str]) -> Result<Url> {
+        let mut url = Url::parse(&self.endpoint)
+            .map_err(|_| Error::InvalidEndpoint(self.endpoint.clone()))?;
+        url.path_segments_mut()
+            .map_err(|_| Error::InvalidEndpoint(self.endpoint.clone()))?
+            .pop_if_empty()
+            .extend(segments);
+        Ok(url)
+    }
+
+    fn request(&self, method: Method, segments: &[&str]) -> Result<RequestBuilder> {
+        let request = self.http.request(method, self.url(segments)?);
+        match &self.credential {
+            Some(Credential::Token(token)) => Ok(request.bearer_auth(token)),
+            Some(Credential::ApiKey(key)) => Ok(request.header("x-api-key", key)),
+            None => Err(Error::NotLoggedIn),
+        }
+    }
+
+    // Turns any non 2xx response into an error carrying the response body
+    fn send(request: RequestBuilder) -> Result<Response> {
+        let response = request.send()?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let status = response.status();
+        Err(Error::Status {
+            status,
+            body: response.text().unwrap_or_default(),
+        })
+    }
+
+    fn json<T: DeserializeOwned>(request: RequestBuilder) -> Result<T> {
+        Ok(Client::send(request)?.json()?)
+    }
+
+    pub fn login(&self, request: &TokenRequest) -> Result<TokenResponse> {
+        Client::json(self.http.post(self.url(&["tokens"])?).json(request))
+    }
+
+    // raw skips the namespace's read transform
+    // The gateway's default consistency applies when it isn't given
+    pub fn get(
+        &self,
+        namespace: &str,
+        key: &str,
+        raw: bool,
+        consistency: Option<&str>,
+    ) -> Result<Vec<u8>> {
+        let mut request = self
+            .request(Method::GET, &["namespaces", namespace, "keys", key])?
+            .query(&[("raw", raw)]);
+        if let Some(consistency) = consistency {
+            request = request.query(&[("consistency", consistency)]);
+        }
+        Ok(Client::send(request)?.bytes()?.to_vec())
+    }
+
+    // Without a ttl the key gets the namespace's default ttl, if it has one
+    pub fn put(
+        &self,
+        namespace: &str,
+        key: &str,
+        value: &str,
+        ttl_secs: Option<u64>,
+        if_absent: bool,
+    ) -> Result<serde_json::Value> {
+        Client::json(
+            self.request(Method::PUT, &["namespaces", namespace, "keys", key])?
+                .json(&serde_json::json!({
+                    "value": value,
+                    "ttl_secs": ttl_secs,
+                    "if_absent": if_absent,
+                })),
+        )
+    }
+
+    // Sends the value base64 encoded so values that aren't utf-8 are stored as is
+    pub fn put_bytes(&self, namespace: &str, key: &str, value: &[u8]) -> Result<serde_json::Value> {
+        Client::json(
+            self.request(Method::PUT, &["namespaces", namespace, "keys", key])?
+                .json(&serde_json::json!({
+                    "value": general_purpose::STANDARD.encode(value),
+                    "encoding": "base64",
+                })),
+        )
+    }
+
+    // A destination credential copies the key into that tenant's namespace
+    pub fn copy(
+        &self,
+        namespace: &str,
+        key: &str,
+        to_namespace: &str,
+        to_key: Option<&str>,
+        expected_version: Option<u32>,
+        destination_auth: Option<&str>,
+    ) -> Result<serde_json::Value> {
+        let mut request = self
+            .request(
+                Method::POST,
+                &["namespaces", namespace, "keys", key, "copy"],
+            )?
+            .json(&serde_json::json!({
+                "namespace": to_namespace,
+                "key": to_key,
+                "expected_version": expected_version,
+            }));
+        if let Some(destination_auth) = destination_auth {
+            request = request.header("x-destination-authorization", destination_auth);
+        }
+        Client::json(request)
+    }
+
+    // With an expected version the delete is sent with If-Match, it fails if the key has moved on
+    pub fn delete(&self, namespace: &str, key: &str, expected_version: Option<u32>) -> Result<()> {
+        let mut request = self.request(Method::DELETE, &["namespaces", namespace, "keys", key])?;
+        if let Some(expected) = expected_version {
+            request = request.header(reqwest::header::IF_MATCH, format!("\"{}\"", expected));
+        }
+        Client::send(request).map(|_| ())
+    }
+
+    // Without a ttl the key takes the namespace's default ttl, or stops expiring if there's none
+    pub fn touch(
+        &self,
+        namespace: &str,
+        key: &str,
+        ttl_secs: Option<u64>,
+    ) -> Result<serde_json::Value> {
+        Client::json(
+            self.request(
+                Method::POST,
+                &["namespaces", namespace, "keys", key, "touch"],
+            )?
+            .json(&serde_json::json!({ "ttl_secs": ttl_secs })),
+        )
+    }
+
+    // Without a confirmation the gateway only counts the keys and returns the confirmation to use
+    pub fn delete_prefix(
+        &self,
+        namespace: &str,
+        prefix: &str,
+        confirm: Option<&str>,
+    ) -> Result<serde_json::Value> {
+        let mut query = vec![("prefix", prefix)];
+        if let Some(confirm) = confirm {
+            query.push(("confirm", confirm));
+        }
+        Client::json(
+            self.request(Method::DELETE, &["namespaces", namespace, "keys"])?
+                .query(&query),
+        )
+    }
+
+    pub fn list_keys(
+        &self,
+        namespace: &str,
+        limit: Option<u32>,
+        start_after: Option<&str>,
+        snapshot: Option<&str>,
+        include_values: bool,
+        max_value_size: Option<u32>,
+    ) -> Result<KeyPage> {
+        let mut request = self.request(Method::GET, &["namespaces", namespace, "keys"])?;
+        if let Some(limit) = limit {
+            request = request.query(&[("limit", limit)]);
+        }
+        if let Some(start_after) = start_after {
+            request = request.query(&[("start_after", start_after)]);
+        }
+        if let Some(snapshot) = snapshot {
+            request = request.query(&[("snapshot", snapshot)]);
+        }
+        if include_values {
+            request = request.query(&[("include_values", true)]);
+        }
+        if let Some(max_value_size) = max_value_size {
+            request = request.query(&[("max_value_size", max_value_size)]);
+        }
+        Client::json(request)
+    }
+
+    pub fn sample_keys(&self, namespace: &str, count: Option<u32>) -> Result<KeyPage> {
+        let mut request = self.request(Method::GET, &["namespaces", namespace, "sample"])?;
+        if let Some(count) = count {
+            request = request.query(&[("count", count)]);
+        }
+        Client::json(request)
+    }
+
+    // Every key of the namespace with its value as json lines, read as the lines are. raw skips
+    // the namespace's read transform.
+    pub fn scan(&self, namespace: &str, raw: bool) -> Result<impl BufRead> {
+        let response = Client::send(
+            self.request(Method::GET, &["namespaces", namespace, "scan"])?
+                .query(&[("raw", raw)])
+                .timeout(SCAN_TIMEOUT),
+        )?;
+        Ok(BufReader::new(response))
+    }
+
+    pub fn list_namespaces(&self) -> Result<serde_json::Value> {
+        Client::json(self.request(Method::GET, &["namespaces"])?)
+    }
+
+    pub fn create_namespace(
+        &self,
+        name: &str,
+        max_keys: Option<u64>,
+        max_bytes: Option<u64>,
+        size_hint_bytes: Option<u64>,
+        settings: &NamespaceSettings,
+    ) -> Result<serde_json::Value> {
+        let mut body = serde_json::to_value(settings)?;
+        body["name"] = serde_json::json!(name);
+        body["quota"] = serde_json::json!({ "max_keys": max_keys, "max_bytes": max_bytes });
+        body["size_hint_bytes"] = serde_json::json!(size_hint_bytes);
+        Client::json(self.request(Method::POST, &["namespaces"])?.json(&body))
+    }
+
+    pub fn clone_namespace(&self, name: &str, new_name: &str) -> Result<serde_json::Value> {
+        Client::json(
+            self.request(Method::POST, &["namespaces", name, "clone"])?
+                .json(&serde_json::json!({ "name": new_name })),
+        )
+    }
+
+    pub fn delete_namespace(&self, name: &str) -> Result<()> {
+        Client::send(self.request(Method::DELETE, &["namespaces", name])?).map(|_| ())
+    }
+
+    pub fn namespace_stats(&self, name: &str) -> Result<serde_json::Value> {
+        Client::json(self.request(Method::GET, &["namespaces", name, "stats"])?)
+    }
+
+    pub fn count_keys(
+        &self,
+        name: &str,
+        prefix: Option<&str>,
+        exact: bool,
+    ) -> Result<serde_json::Value> {
+        let mut request = self
+            .request(Method::GET, &["namespaces", name, "count"])?
+            .query(&[("exact", exact)]);
+        if let Some(prefix) = prefix {
+            request = request.query(&[("prefix", prefix)]);
+        }
+        Client::json(request)
+    }
+
+    pub fn namespace_quota(&self, name: &str) -> Result<serde_json::Value> {
+        Client::json(self.request(Method::GET, &["namespaces", name, "quota"])?)
+    }
+
+    pub fn set_namespace_quota(
+        &self,
+        name: &str,
+        max_keys: Option<u64>,
+        max_bytes: Option<u64>,
+    ) -> Result<serde_json::Value> {
+        Client::json(
+            self.request(Method::PUT, &["namespaces", name, "quota"])?
+                .json(&serde_json::json!({ "max_keys": max_keys, "max_bytes": max_bytes })),
+        )
+    }
+
+    // Sends the wasm modules base64 encoded, a module that isn't given is removed
+    pub fn set_namespace_transforms(
+        &self,
+        name: &str,
+        read: Option<&[u8]>,
+        write: Option<&[u8]>,
+    ) -> Result<()> {
+        let encode =
+            |module: Option<&[u8]>| module.map(|module| general_purpose::STANDARD.encode(module));
+        Client::send(
+            self.request(Method::PUT, &["namespaces", name, "transforms"])?
+                .json(&serde_json::json!({ "read": encode(read), "write": encode(write) })),
+        )
+        .map(|_| ())
+    }
+
+    pub fn namespace_settings(&self, name: &str) -> Result<serde_json::Value> {
+        Client::json(self.request(Method::GET, &["namespaces", name, "settings"])?)
+    }
+
+    pub fn set_namespace_settings(
+        &self,
+        name: &str,
+        settings: &NamespaceSettings,
+    ) -> Result<serde_json::Value> {
+        Client::json(
+            self.request(Method::PUT, &["namespaces", name, "settings"])?
+                .json(settings),
+        )
+    }
+
+    pub fn namespace_schema(&self, name: &str) -> Result<serde_json::Value> {
+        Client::json(self.request(Method::GET, &["namespaces", name, "schema"])?)
+    }
+
+    pub fn set_namespace_schema(
+        &self,
+        name: &str,
+        schema: &serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        Client::json(
+            self.request(Method::PUT, &["namespaces", name, "schema"])?
+                .json(schema),
+        )
+    }
+
+    pub fn remove_namespace_schema(&self, name: &str) -> Result<()> {
+        Client::send(self.request(Method::DELETE, &["namespaces", name, "schema"])?).map(|_| ())
+    }
+
+    pub fn namespace_key_policy(&self, name: &str) -> Result<serde_json::Value> {
+        Client::json(self.request(Method::GET, &["namespaces", name, "key-policy"])?)
+    }
+
+    pub fn set_namespace_key_policy(
+        &self,
+        name: &str,
+        max_length: Option<u32>,
+        charset: Option<&str>,
+        reserved_prefixes: &[String],
+    ) -> Result<serde_json::Value> {
+        let mut body = serde_json::json!({
+            "max_length": max_length,
+            "reserved_prefixes": reserved_prefixes,
+        });
+        if let Some(charset) = charset {
+            body["charset"] = serde_json::json!(charset);
+        }
+        Client::json(
+            self.request(Method::PUT, &["namespaces", name, "key-policy"])?
+                .json(&body),
+        )
+    }
+
+    pub fn create_api_key(&self, name: &str) -> Result<serde_json::Value> {
+        Client::json(
+            self.request(Method::POST, &["api-keys"])?
+                .json(&serde_json::json!({ "name": name })),
+        )
+    }
+
+    pub fn list_api_keys(&self) -> Result<serde_json::Value> {
+        Client::json(self.request(Method::GET, &["api-keys"])?)
+    }
+
+    pub fn revoke_api_key(&self, id: &str) -> Result<()> {
+        Client::send(self.request(Method::DELETE, &["api-keys", id])?).map(|_| ())
+    }
+
+    pub fn list_webhooks(&self, namespace: &str) -> Result<serde_json::Value> {
+        Client::json(self.request(Method::GET, &["namespaces", namespace, "webhooks"])?)
+    }
+
+    // The response includes the secret events are signed with, it's only returned here
+    pub fn add_webhook(&self, namespace: &str, url: &str) -> Result<serde_json::Value> {
+        Client::json(
+            self.request(Method::POST, &["namespaces", namespace, "webhooks"])?
+                .json(&serde_json::json!({ "url": url })),
+        )
+    }
+
+    pub fn remove_webhook(&self, namespace: &str, id: &str) -> Result<()> {
+        Client::send(self.request(Method::DELETE, &["namespaces", namespace, "webhooks", id])?)
+            .map(|_| ())
+    }
+
+    pub fn webhook_dead_letters(&self, namespace: &str) -> Result<serde_json::Value> {
+        Client::json(self.request(
+            Method::GET,
+            &["namespaces", namespace, "webhooks", "dead-letters"],
+        )?)
+    }
+}
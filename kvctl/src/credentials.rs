@@ -0,0 +1,59 @@
+use crate::client::{Result, TokenResponse};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The token from the last login, kept in ~/.kvctl/credentials.json
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Credentials {
+    pub endpoint: String,
+    #[serde(flatten)]
+    pub token: TokenResponse,
+}
+
+fn path() -> PathBuf {
+    let home = std::env::var_os("HOME").unwrap_or_else(|| ".".into());
+    PathBuf::from(home).join(".kvctl").join("credentials.json")
+}
+
+impl Credentials {
+    pub fn load() -> Result<Option<Credentials>> {
+        match fs::read(path()) {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // The file holds a bearer token so it's only readable by the user
+    pub fn save(&self) -> Result<()> {
+        let path = path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn remove() -> Result<()> {
+        match fs::remove_file(path()) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or_default();
+        self.token.expires_at <= now
+    }
+}
@@ -0,0 +1,688 @@
+use base64::{engine::general_purpose, Engine as _};
+use clap::{Args, Parser, Subcommand};
+use client::{Client, Credential, Error, KeyInfo, NamespaceSettings, Result, TokenRequest};
+use crc32fast::Hasher;
+use credentials::Credentials;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+mod client;
+mod credentials;
+
+// page size used when walking every key of a namespace
+const EXPORT_PAGE_SIZE: u32 = 500;
+
+#[derive(Parser, Debug)]
+#[command(name = "kvctl", version, about = "Command line client for kvstore")]
+struct Cli {
+    // defaults to the endpoint of the last login, then http://localhost:8080
+    #[arg(long, global = true, env = "KVCTL_ENDPOINT")]
+    endpoint: Option<String>,
+
+    // used instead of the token saved by login
+    #[arg(long, global = true, env = "KVCTL_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    #[arg(long, global = true, env = "KVCTL_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Get a token and save it for later commands
+    Login(Login),
+    /// Forget the saved token
+    Logout,
+    /// Print the saved token
+    Token,
+    #[command(subcommand, name = "api-key")]
+    ApiKey(ApiKeyCommand),
+    #[command(subcommand)]
+    Namespace(NamespaceCommand),
+    #[command(subcommand)]
+    Webhook(WebhookCommand),
+    /// Print a key's value
+    Get {
+        namespace: String,
+        key: String,
+        /// Print the value as stored, without the namespace's read transform
+        #[arg(long)]
+        raw: bool,
+        /// strong, or eventual to allow a read that may miss the latest write
+        #[arg(long)]
+        consistency: Option<String>,
+    },
+    /// Set a key, the value is read from stdin when not given
+    Put {
+        namespace: String,
+        key: String,
+        value: Option<String>,
+        #[arg(long, conflicts_with = "value")]
+        file: Option<PathBuf>,
+        /// Expire the key this long after it's written, instead of after the namespace's default
+        #[arg(long)]
+        ttl_secs: Option<u64>,
+        /// Only put the value if the key doesn't exist yet
+        #[arg(long)]
+        if_absent: bool,
+    },
+    /// Copy a key to another namespace, or another key, on the storage nodes
+    Copy {
+        namespace: String,
+        key: String,
+        to_namespace: String,
+        /// The key to copy to, the same key when unset
+        #[arg(long)]
+        to_key: Option<String>,
+        /// Only copy if the key copied to is at this version, 0 for a key that doesn't exist
+        #[arg(long)]
+        expected_version: Option<u32>,
+        /// A token or api key of the tenant to copy to, when it isn't yours
+        #[arg(long)]
+        destination_auth: Option<String>,
+    },
+    /// Delete a key
+    Delete {
+        namespace: String,
+        key: String,
+        /// Only delete the key if it's at this version
+        #[arg(long)]
+        expected_version: Option<u32>,
+    },
+    /// Change when a key expires without rewriting its value
+    Touch {
+        namespace: String,
+        key: String,
+        /// Seconds from now, the namespace's default ttl when unset
+        #[arg(long)]
+        ttl_secs: Option<u64>,
+    },
+    /// Delete every key with a prefix, without --yes only the number of keys is shown
+    DeletePrefix {
+        namespace: String,
+        prefix: String,
+        #[arg(long)]
+        yes: bool,
+    },
+    /// List the keys of a namespace
+    Keys {
+        namespace: String,
+        #[arg(long)]
+        limit: Option<u32>,
+        #[arg(long)]
+        start_after: Option<String>,
+        /// The snapshot a previous page returned, so the page is listed as of the same time
+        #[arg(long, requires = "start_after", conflicts_with = "all")]
+        snapshot: Option<String>,
+        /// Follow the pages until every key is listed
+        #[arg(long, conflicts_with = "limit")]
+        all: bool,
+        /// List each key's value along with it, values too large to be listed are left out
+        #[arg(long)]
+        values: bool,
+        /// The largest value in bytes to list
+        #[arg(long, requires = "values")]
+        max_value_size: Option<u32>,
+    },
+    /// Show keys picked at random from a namespace
+    Sample {
+        namespace: String,
+        #[arg(long)]
+        count: Option<u32>,
+    },
+    /// Write every key of a namespace, as of one point in time, as json lines of {"key", "value",
+    /// "version", "crc"}, with values base64 encoded
+    Export {
+        namespace: String,
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Put every key from an export into a namespace, the versions are assigned by the namespace
+    /// being imported into
+    Import {
+        namespace: String,
+        #[arg(long, short)]
+        input: Option<PathBuf>,
+    },
+}
+
+#[derive(Args, Debug)]
+struct Login {
+    #[arg(long)]
+    tenant: String,
+    // prompted for when not given
+    #[arg(long, env = "KVCTL_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+    #[arg(long, value_delimiter = ',')]
+    scopes: Option<Vec<String>>,
+    #[arg(long, value_delimiter = ',')]
+    namespaces: Option<Vec<String>>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ApiKeyCommand {
+    Create { name: String },
+    List,
+    Revoke { id: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum WebhookCommand {
+    List {
+        namespace: String,
+    },
+    /// Notify the url of the namespace's puts and deletes, prints the secret events are signed with
+    Add {
+        namespace: String,
+        url: String,
+    },
+    Remove {
+        namespace: String,
+        id: String,
+    },
+    /// Show the namespace's most recent events that couldn't be delivered
+    DeadLetters {
+        namespace: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum NamespaceCommand {
+    List,
+    Create {
+        name: String,
+        #[arg(long)]
+        max_keys: Option<u64>,
+        #[arg(long)]
+        max_bytes: Option<u64>,
+        /// Expected size of the namespace, the storage nodes size its partitions from it
+        #[arg(long)]
+        size_hint_bytes: Option<u64>,
+        #[command(flatten)]
+        settings: Settings,
+    },
+    /// Copy the namespace's keys into a new namespace, with its quota, settings, and key policy
+    Clone {
+        name: String,
+        new_name: String,
+    },
+    Delete {
+        name: String,
+    },
+    Stats {
+        name: String,
+    },
+    /// Count the namespace's keys, estimated unless --exact or a prefix is given
+    Count {
+        name: String,
+        #[arg(long)]
+        prefix: Option<String>,
+        /// Read every key rather than use the storage nodes' estimate
+        #[arg(long)]
+        exact: bool,
+    },
+    /// Show the namespace's quota, missing limits are unlimited
+    Quota {
+        name: String,
+    },
+    /// Replace the namespace's quota, limits that aren't given are removed
+    SetQuota {
+        name: String,
+        #[arg(long)]
+        max_keys: Option<u64>,
+        #[arg(long)]
+        max_bytes: Option<u64>,
+    },
+    /// Replace the namespace's wasm transforms, a module that isn't given is removed
+    SetTransforms {
+        name: String,
+        /// Module transforming values as they're read
+        #[arg(long)]
+        read: Option<PathBuf>,
+        /// Module validating values before they're written
+        #[arg(long)]
+        write: Option<PathBuf>,
+    },
+    /// Show the namespace's description, default ttl, compression, and durability
+    Settings {
+        name: String,
+    },
+    /// Replace the namespace's settings, settings that aren't given go back to their defaults
+    SetSettings {
+        name: String,
+        #[command(flatten)]
+        settings: Settings,
+    },
+    /// Show the JSON Schema the namespace's values are validated against
+    Schema {
+        name: String,
+    },
+    /// Validate the namespace's puts against the JSON Schema in the file
+    SetSchema {
+        name: String,
+        file: PathBuf,
+    },
+    /// Stop validating the namespace's values
+    RemoveSchema {
+        name: String,
+    },
+    /// Show the rules the namespace's keys follow
+    KeyPolicy {
+        name: String,
+    },
+    /// Replace the namespace's key policy, rules that aren't given are removed
+    SetKeyPolicy {
+        name: String,
+        /// Longest key in bytes
+        #[arg(long)]
+        max_length: Option<u32>,
+        /// any, utf8, or url_safe
+        #[arg(long)]
+        charset: Option<String>,
+        /// Keys starting with it can't be written, can be repeated
+        #[arg(long = "reserved-prefix")]
+        reserved_prefixes: Vec<String>,
+    },
+}
+
+#[derive(Args, Debug)]
+struct Settings {
+    #[arg(long)]
+    description: Option<String>,
+    /// Expiry applied to keys written without their own ttl
+    #[arg(long)]
+    default_ttl_secs: Option<u64>,
+    /// none, snappy, lz4, or zstd
+    #[arg(long)]
+    compression: Option<String>,
+    /// buffered, or sync to sync every write to disk before it's acknowledged
+    #[arg(long)]
+    durability: Option<String>,
+    /// Cache gets in the gateway for this long, up to 60 seconds
+    #[arg(long)]
+    cache_ttl_secs: Option<u64>,
+}
+
+impl From<Settings> for NamespaceSettings {
+    fn from(settings: Settings) -> Self {
+        NamespaceSettings {
+            description: settings.description,
+            default_ttl_secs: settings.default_ttl_secs,
+            compression: settings.compression,
+            durability: settings.durability,
+            cache_ttl_secs: settings.cache_ttl_secs,
+        }
+    }
+}
+
+// A line of an export. The version and crc are the key's metadata where it was exported from,
+// the crc is checked on import so a damaged file is caught before it's written.
+#[derive(Serialize, Deserialize, Debug)]
+struct Record {
+    key: String,
+    // base64 of the value's bytes
+    value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crc: Option<u32>,
+}
+
+impl Record {
+    fn decode(&self, line: usize) -> Result<Vec<u8>> {
+        let invalid = |reason: String| Error::InvalidRecord { line, reason };
+        let value = general_purpose::STANDARD
+            .decode(&self.value)
+            .map_err(|err| invalid(format!("invalid base64 value: {}", err)))?;
+        if let Some(crc) = self.crc {
+            let mut hasher = Hasher::new();
+            hasher.update(self.key.as_bytes());
+            hasher.update(&value);
+            if hasher.finalize() != crc {
+                return Err(invalid(format!("crc mismatch for key {}", self.key)));
+            }
+        }
+        Ok(value)
+    }
+}
+
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn read_value(value: Option<String>, file: Option<PathBuf>) -> Result<String> {
+    if let Some(value) = value {
+        return Ok(value);
+    }
+    let mut value = String::new();
+    match file {
+        Some(file) => File::open(file)?.read_to_string(&mut value)?,
+        None => io::stdin().read_to_string(&mut value)?,
+    };
+    Ok(value)
+}
+
+// Calls f with every page of keys starting after start_after
+fn for_each_page(
+    client: &Client,
+    namespace: &str,
+    limit: u32,
+    start_after: Option<String>,
+    include_values: bool,
+    max_value_size: Option<u32>,
+    mut f: impl FnMut(Vec<KeyInfo>) -> Result<()>,
+) -> Result<()> {
+    let mut start_after = start_after;
+    // every page after the first is listed from the first page's snapshot
+    let mut snapshot = None;
+    loop {
+        let page = client.list_keys(
+            namespace,
+            Some(limit),
+            start_after.as_deref(),
+            snapshot.as_deref(),
+            include_values,
+            max_value_size,
+        )?;
+        f(page.keys)?;
+        match page.next {
+            Some(next) => {
+                start_after = Some(next);
+                snapshot = page.snapshot;
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+fn login(endpoint: &str, login: Login) -> Result<()> {
+    let password = match login.password {
+        Some(password) => password,
+        None => rpassword::prompt_password("password: ")?,
+    };
+    let token = Client::new(endpoint, None).login(&TokenRequest {
+        name: &login.tenant,
+        password: &password,
+        scopes: login.scopes,
+        namespaces: login.namespaces,
+    })?;
+    Credentials {
+        endpoint: endpoint.to_string(),
+        token,
+    }
+    .save()?;
+    eprintln!("logged in as {}", login.tenant);
+    Ok(())
+}
+
+fn export(client: &Client, namespace: &str, output: Option<PathBuf>) -> Result<()> {
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    let mut exported = 0;
+    // exported values are imported as written, so they skip the read transform
+    for line in client.scan(namespace, true)?.lines() {
+        let record: Record = serde_json::from_str(&line?)?;
+        serde_json::to_writer(&mut writer, &record)?;
+        writer.write_all(b"\n")?;
+        exported += 1;
+    }
+    writer.flush()?;
+    eprintln!("exported {} keys", exported);
+    Ok(())
+}
+
+// Stops at the first record that fails, the keys before it stay imported
+fn import(client: &Client, namespace: &str, input: Option<PathBuf>) -> Result<()> {
+    let reader: Box<dyn BufRead> = match input {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+    let mut imported = 0;
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line).map_err(|err| Error::InvalidRecord {
+            line: number + 1,
+            reason: err.to_string(),
+        })?;
+        client.put_bytes(namespace, &record.key, &record.decode(number + 1)?)?;
+        imported += 1;
+    }
+    eprintln!("imported {} keys", imported);
+    Ok(())
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let saved = Credentials::load()?;
+    let endpoint = cli
+        .endpoint
+        .or_else(|| saved.as_ref().map(|saved| saved.endpoint.clone()))
+        .unwrap_or_else(|| "http://localhost:8080".to_string());
+
+    let credential = match (cli.token, cli.api_key, &saved) {
+        (Some(token), _, _) => Some(Credential::Token(token)),
+        (None, Some(api_key), _) => Some(Credential::ApiKey(api_key)),
+        (None, None, Some(saved)) if !saved.is_expired() => {
+            Some(Credential::Token(saved.token.token.clone()))
+        }
+        _ => None,
+    };
+    let client = Client::new(&endpoint, credential);
+
+    match cli.command {
+        Command::Login(args) => login(&endpoint, args),
+        Command::Logout => Credentials::remove(),
+        Command::Token => match saved {
+            Some(saved) if !saved.is_expired() => print_json(&saved.token),
+            _ => Err(Error::NotLoggedIn),
+        },
+        Command::ApiKey(ApiKeyCommand::Create { name }) => {
+            print_json(&client.create_api_key(&name)?)
+        }
+        Command::ApiKey(ApiKeyCommand::List) => print_json(&client.list_api_keys()?),
+        Command::ApiKey(ApiKeyCommand::Revoke { id }) => client.revoke_api_key(&id),
+        Command::Webhook(WebhookCommand::List { namespace }) => {
+            print_json(&client.list_webhooks(&namespace)?)
+        }
+        Command::Webhook(WebhookCommand::Add { namespace, url }) => {
+            print_json(&client.add_webhook(&namespace, &url)?)
+        }
+        Command::Webhook(WebhookCommand::Remove { namespace, id }) => {
+            client.remove_webhook(&namespace, &id)
+        }
+        Command::Webhook(WebhookCommand::DeadLetters { namespace }) => {
+            print_json(&client.webhook_dead_letters(&namespace)?)
+        }
+        Command::Namespace(NamespaceCommand::List) => print_json(&client.list_namespaces()?),
+        Command::Namespace(NamespaceCommand::Create {
+            name,
+            max_keys,
+            max_bytes,
+            size_hint_bytes,
+            settings,
+        }) => print_json(&client.create_namespace(
+            &name,
+            max_keys,
+            max_bytes,
+            size_hint_bytes,
+            &settings.into(),
+        )?),
+        Command::Namespace(NamespaceCommand::Clone { name, new_name }) => {
+            print_json(&client.clone_namespace(&name, &new_name)?)
+        }
+        Command::Namespace(NamespaceCommand::Delete { name }) => client.delete_namespace(&name),
+        Command::Namespace(NamespaceCommand::Stats { name }) => {
+            print_json(&client.namespace_stats(&name)?)
+        }
+        Command::Namespace(NamespaceCommand::Count {
+            name,
+            prefix,
+            exact,
+        }) => print_json(&client.count_keys(&name, prefix.as_deref(), exact)?),
+        Command::Namespace(NamespaceCommand::Quota { name }) => {
+            print_json(&client.namespace_quota(&name)?)
+        }
+        Command::Namespace(NamespaceCommand::SetQuota {
+            name,
+            max_keys,
+            max_bytes,
+        }) => print_json(&client.set_namespace_quota(&name, max_keys, max_bytes)?),
+        Command::Namespace(NamespaceCommand::SetTransforms { name, read, write }) => {
+            let read = read.map(std::fs::read).transpose()?;
+            let write = write.map(std::fs::read).transpose()?;
+            client.set_namespace_transforms(&name, read.as_deref(), write.as_deref())
+        }
+        Command::Namespace(NamespaceCommand::Settings { name }) => {
+            print_json(&client.namespace_settings(&name)?)
+        }
+        Command::Namespace(NamespaceCommand::SetSettings { name, settings }) => {
+            print_json(&client.set_namespace_settings(&name, &settings.into())?)
+        }
+        Command::Namespace(NamespaceCommand::Schema { name }) => {
+            print_json(&client.namespace_schema(&name)?)
+        }
+        Command::Namespace(NamespaceCommand::SetSchema { name, file }) => {
+            let schema = serde_json::from_slice(&std::fs::read(file)?)?;
+            print_json(&client.set_namespace_schema(&name, &schema)?)
+        }
+        Command::Namespace(NamespaceCommand::RemoveSchema { name }) => {
+            client.remove_namespace_schema(&name)
+        }
+        Command::Namespace(NamespaceCommand::KeyPolicy { name }) => {
+            print_json(&client.namespace_key_policy(&name)?)
+        }
+        Command::Namespace(NamespaceCommand::SetKeyPolicy {
+            name,
+            max_length,
+            charset,
+            reserved_prefixes,
+        }) => print_json(&client.set_namespace_key_policy(
+            &name,
+            max_length,
+            charset.as_deref(),
+            &reserved_prefixes,
+        )?),
+        Command::Get {
+            namespace,
+            key,
+            raw,
+            consistency,
+        } => {
+            let value = client.get(&namespace, &key, raw, consistency.as_deref())?;
+            io::stdout().write_all(&value)?;
+            Ok(())
+        }
+        Command::Put {
+            namespace,
+            key,
+            value,
+            file,
+            ttl_secs,
+            if_absent,
+        } => print_json(&client.put(
+            &namespace,
+            &key,
+            &read_value(value, file)?,
+            ttl_secs,
+            if_absent,
+        )?),
+        Command::Copy {
+            namespace,
+            key,
+            to_namespace,
+            to_key,
+            expected_version,
+            destination_auth,
+        } => print_json(&client.copy(
+            &namespace,
+            &key,
+            &to_namespace,
+            to_key.as_deref(),
+            expected_version,
+            destination_auth.as_deref(),
+        )?),
+        Command::Delete {
+            namespace,
+            key,
+            expected_version,
+        } => client.delete(&namespace, &key, expected_version),
+        Command::Touch {
+            namespace,
+            key,
+            ttl_secs,
+        } => print_json(&client.touch(&namespace, &key, ttl_secs)?),
+        Command::DeletePrefix {
+            namespace,
+            prefix,
+            yes,
+        } => {
+            // the first call only counts the keys and hands back the confirmation for the delete
+            let preview = client.delete_prefix(&namespace, &prefix, None)?;
+            match (yes, preview["confirmation"].as_str()) {
+                (true, Some(confirmation)) => {
+                    print_json(&client.delete_prefix(&namespace, &prefix, Some(confirmation))?)
+                }
+                _ => print_json(&preview),
+            }
+        }
+        Command::Keys {
+            namespace,
+            limit,
+            start_after,
+            snapshot,
+            all,
+            values,
+            max_value_size,
+        } => {
+            if !all {
+                return print_json(&client.list_keys(
+                    &namespace,
+                    limit,
+                    start_after.as_deref(),
+                    snapshot.as_deref(),
+                    values,
+                    max_value_size,
+                )?);
+            }
+            // one key per line so large namespaces don't have to be held in memory
+            for_each_page(
+                &client,
+                &namespace,
+                EXPORT_PAGE_SIZE,
+                start_after,
+                values,
+                max_value_size,
+                |keys| {
+                    for key in keys {
+                        println!("{}", serde_json::to_string(&key)?);
+                    }
+                    Ok(())
+                },
+            )
+        }
+        Command::Sample { namespace, count } => print_json(&client.sample_keys(&namespace, count)?),
+        Command::Export { namespace, output } => export(&client, &namespace, output),
+        Command::Import { namespace, input } => import(&client, &namespace, input),
+    }
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("kvctl: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
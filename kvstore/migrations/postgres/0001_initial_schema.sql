@@ -0,0 +1,76 @@
+-- The same schema as migrations/sqlite, in postgres types
+create table tenants (
+    id bigint generated by default as identity primary key,
+    uuid varchar(36),
+    name varchar(255),
+    password_hash varchar(255),
+    disabled boolean not null default false,
+    tokens_valid_after bigint not null default 0,
+    unique(name),
+    unique(uuid)
+);
+
+create table namespaces (
+    id bigint generated by default as identity primary key,
+    uuid varchar(36),
+    name varchar(255),
+    tenant_id bigint,
+    unique(tenant_id, name),
+    foreign key(tenant_id) references tenants(id)
+);
+
+create table storage_targets (
+    id bigint generated by default as identity primary key,
+    namespace_id bigint,
+    endpoint varchar(255)
+);
+
+create table client_certificates (
+    id bigint generated by default as identity primary key,
+    uuid varchar(36),
+    tenant_id bigint,
+    fingerprint varchar(64),
+    san varchar(255),
+    unique(uuid),
+    unique(fingerprint),
+    unique(san),
+    foreign key(tenant_id) references tenants(id)
+);
+
+create table api_keys (
+    id bigint generated by default as identity primary key,
+    uuid varchar(36),
+    tenant_id bigint,
+    name varchar(255),
+    key_hash varchar(64),
+    created_at bigint,
+    revoked boolean not null default false,
+    unique(uuid),
+    unique(key_hash),
+    foreign key(tenant_id) references tenants(id)
+);
+
+create table audit_log (
+    id bigint generated by default as identity primary key,
+    timestamp bigint,
+    event varchar(64),
+    tenant varchar(255),
+    source varchar(64),
+    outcome varchar(16),
+    detail varchar(255)
+);
+
+create index audit_log_tenant on audit_log (tenant, timestamp);
+
+-- the audit log is append only
+create function audit_log_append_only() returns trigger as $$
+begin
+    raise exception 'audit log is append only';
+end;
+$$ language plpgsql;
+
+create trigger audit_log_no_update before update on audit_log
+    for each row execute function audit_log_append_only();
+
+create trigger audit_log_no_delete before delete on audit_log
+    for each row execute function audit_log_append_only();
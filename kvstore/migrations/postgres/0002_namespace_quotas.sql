@@ -0,0 +1,4 @@
+-- Namespace quotas, null is unlimited. The storage nodes enforce them, these are what's sent to
+-- the nodes when a namespace is created or its quota changes.
+alter table namespaces add column max_keys bigint;
+alter table namespaces add column max_bytes bigint;
@@ -0,0 +1,6 @@
+-- Tenants are active, suspended, or deleted. A deleted tenant can't be used and is purged, data on
+-- the storage nodes included, once purge_after passes. Replaces the disabled flag.
+alter table tenants add column status varchar(16) not null default 'active';
+alter table tenants add column purge_after bigint;
+update tenants set status = 'suspended' where disabled;
+alter table tenants drop column disabled;
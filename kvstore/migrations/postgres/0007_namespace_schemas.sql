@@ -0,0 +1,2 @@
+-- The JSON Schema a namespace's values are validated against, null when values aren't validated
+alter table namespaces add column json_schema text;
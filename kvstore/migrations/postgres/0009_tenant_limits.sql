@@ -0,0 +1,14 @@
+-- Limits on a tenant's usage across its namespaces, null is unlimited, see usage
+alter table tenants add column max_namespaces bigint;
+alter table tenants add column max_keys bigint;
+alter table tenants add column max_bytes bigint;
+
+-- A tenant's keys and bytes across its namespaces, refreshed from the storage nodes' stats and
+-- counted up by the gateway's puts in between
+create table tenant_usage (
+    tenant_id bigint primary key,
+    key_count bigint not null default 0,
+    total_bytes bigint not null default 0,
+    updated_at bigint not null default 0,
+    foreign key(tenant_id) references tenants(id)
+);
@@ -0,0 +1,3 @@
+-- How long the storage nodes keep a namespace's deleted and replaced values, null keeps nothing
+alter table namespaces add column retain_deleted_secs bigint;
+alter table namespaces add column retain_versions_secs bigint;
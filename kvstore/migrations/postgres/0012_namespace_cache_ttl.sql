@@ -0,0 +1,2 @@
+-- How long the gateways cache gets of a namespace's keys, null doesn't cache them
+alter table namespaces add column cache_ttl_secs bigint;
@@ -0,0 +1,3 @@
+-- The storage node a namespace's keys are stored on, picked when it's created. Namespaces created
+-- before this are left null and stay on the first storage node, where their keys were written.
+alter table namespaces add column storage_node varchar(255);
@@ -0,0 +1,77 @@
+-- The tables the gateway created on startup before it ran migrations. They're created only if
+-- missing so databases from those builds are taken over as they are.
+create table if not exists tenants (
+    id integer primary key autoincrement,
+    uuid varchar(36),
+    name varchar(255),
+    password_hash varchar(255),
+    unique(name),
+    unique(uuid)
+);
+
+-- Those builds' tenants table predates these columns, they're added the same way to a new one
+alter table tenants add column disabled boolean not null default 0;
+alter table tenants add column tokens_valid_after integer not null default 0;
+
+create table if not exists namespaces (
+    id integer primary key autoincrement,
+    uuid varchar(36),
+    name varchar(255),
+    tenant_id integer,
+    unique(tenant_id, name),
+    foreign key(tenant_id) references tenants(id)
+);
+
+create table if not exists storage_targets (
+    id integer primary key autoincrement,
+    namespace_id integer,
+    endpoint varchar(255)
+);
+
+create table if not exists client_certificates (
+    id integer primary key autoincrement,
+    uuid varchar(36),
+    tenant_id integer,
+    fingerprint varchar(64),
+    san varchar(255),
+    unique(uuid),
+    unique(fingerprint),
+    unique(san),
+    foreign key(tenant_id) references tenants(id)
+);
+
+create table if not exists api_keys (
+    id integer primary key autoincrement,
+    uuid varchar(36),
+    tenant_id integer,
+    name varchar(255),
+    key_hash varchar(64),
+    created_at integer,
+    revoked boolean not null default 0,
+    unique(uuid),
+    unique(key_hash),
+    foreign key(tenant_id) references tenants(id)
+);
+
+create table if not exists audit_log (
+    id integer primary key autoincrement,
+    timestamp integer,
+    event varchar(64),
+    tenant varchar(255),
+    source varchar(64),
+    outcome varchar(16),
+    detail varchar(255)
+);
+
+create index if not exists audit_log_tenant on audit_log (tenant, timestamp);
+
+-- the audit log is append only
+create trigger if not exists audit_log_no_update before update on audit_log
+begin
+    select raise(abort, 'audit log is append only');
+end;
+
+create trigger if not exists audit_log_no_delete before delete on audit_log
+begin
+    select raise(abort, 'audit log is append only');
+end;
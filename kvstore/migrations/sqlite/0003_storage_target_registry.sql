@@ -0,0 +1,8 @@
+-- Storage nodes registered at runtime through the admin api, routed to alongside the configured
+-- ones while their status is active. Capacity is an operator annotation, null if unknown.
+alter table storage_targets add column uuid varchar(36);
+alter table storage_targets add column status varchar(16) not null default 'active';
+alter table storage_targets add column capacity_bytes integer;
+alter table storage_targets add column created_at integer not null default 0;
+create unique index storage_targets_uuid on storage_targets (uuid);
+create unique index storage_targets_endpoint on storage_targets (endpoint);
@@ -0,0 +1,7 @@
+-- Descriptive metadata and settings that make a namespace more than a name. Namespaces created
+-- before this are given a creation time of 0.
+alter table namespaces add column created_at integer not null default 0;
+alter table namespaces add column description varchar(1024);
+alter table namespaces add column default_ttl_secs integer;
+alter table namespaces add column compression varchar(16) not null default 'snappy';
+alter table namespaces add column durability varchar(16) not null default 'buffered';
@@ -0,0 +1,24 @@
+-- Urls notified of a namespace's key changes, and the events that couldn't be delivered to them.
+-- The secret signs each event, so it's stored as is rather than hashed.
+create table webhooks (
+    id integer primary key autoincrement,
+    uuid varchar(36),
+    namespace_id integer,
+    url varchar(2048),
+    secret varchar(255),
+    created_at integer not null default 0,
+    unique(uuid),
+    foreign key(namespace_id) references namespaces(id)
+);
+
+create table webhook_dead_letters (
+    id integer primary key autoincrement,
+    webhook_id integer,
+    event text,
+    error varchar(1024),
+    attempts integer,
+    failed_at integer,
+    foreign key(webhook_id) references webhooks(id)
+);
+
+create index webhook_dead_letters_webhook on webhook_dead_letters (webhook_id);
@@ -0,0 +1,2 @@
+-- The rules a namespace's keys follow as json, see common::key_policy, null allows every key
+alter table namespaces add column key_policy text;
@@ -0,0 +1,14 @@
+-- Limits on a tenant's usage across its namespaces, null is unlimited, see usage
+alter table tenants add column max_namespaces integer;
+alter table tenants add column max_keys integer;
+alter table tenants add column max_bytes integer;
+
+-- A tenant's keys and bytes across its namespaces, refreshed from the storage nodes' stats and
+-- counted up by the gateway's puts in between
+create table tenant_usage (
+    tenant_id integer primary key,
+    key_count integer not null default 0,
+    total_bytes integer not null default 0,
+    updated_at integer not null default 0,
+    foreign key(tenant_id) references tenants(id)
+);
@@ -0,0 +1,14 @@
+-- A tenant's requests and bytes through the gateways per hour, for charging tenants back, see
+-- accounting. The hour is the unix timestamp it starts at, storage_bytes is measured once an hour
+-- and null until it has been.
+create table tenant_usage_hourly (
+    tenant_id integer not null,
+    hour integer not null,
+    requests integer not null default 0,
+    bytes_read integer not null default 0,
+    bytes_written integer not null default 0,
+    storage_bytes integer,
+    primary key(tenant_id, hour),
+    foreign key(tenant_id) references tenants(id)
+);
+create index tenant_usage_hourly_hour on tenant_usage_hourly(hour);
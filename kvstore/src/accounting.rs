@@ -0,0 +1,356 @@
+use crate::db::{optional, DbPool};
+use crate::{usage, AppData};
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header;
+use actix_web::web::{Bytes, Data};
+use actix_web::HttpMessage;
+use serde::{Deserialize, Serialize};
+use sqlx::any::AnyRow;
+use sqlx::{query, Result, Row};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::{Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+use uuid::Uuid;
+
+// how often the counted requests and bytes are written to the database, they're lost if the
+// gateway dies in between
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+const HOUR_SECS: i64 = 60 * 60;
+
+// the range a query covers when it doesn't say
+const DEFAULT_QUERY_RANGE_SECS: i64 = 24 * HOUR_SECS;
+
+// Marks a request as made by the tenant, set once the tenant is authenticated so the response can
+// be counted towards its usage
+#[derive(Debug, Clone, Copy)]
+pub struct Metered(pub Uuid);
+
+// Requests and bytes through the gateway. Bytes written are the request bodies, bytes read the
+// response bodies.
+#[derive(Debug, Clone, Copy, Default)]
+struct Traffic {
+    requests: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, other: Traffic) {
+        self.requests += other.requests;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+    }
+}
+
+// A tenant's usage in the hour starting at hour, a unix timestamp. storage_bytes is what the
+// tenant's namespaces held when measured during the hour, null when they weren't.
+#[derive(Serialize, Debug, Clone)]
+pub struct HourlyUsage {
+    pub tenant: String,
+    pub hour: i64,
+    pub requests: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub storage_bytes: Option<u64>,
+}
+
+impl From<AnyRow> for HourlyUsage {
+    fn from(row: AnyRow) -> Self {
+        HourlyUsage {
+            tenant: row.get(0),
+            hour: row.get(1),
+            requests: row.get::<i64, usize>(2) as u64,
+            bytes_read: row.get::<i64, usize>(3) as u64,
+            bytes_written: row.get::<i64, usize>(4) as u64,
+            storage_bytes: optional::<i64>(&row, 5).map(|bytes| bytes as u64),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct UsageQuery {
+    pub tenant: Option<String>,
+    // unix timestamps, hours starting at or after from and before to, the last day when not set
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+// Counts each tenant's requests and bytes per hour, for charging tenants back for what they use.
+// Gateways count what passes through them and add it to the tenant's hour in the database every
+// FLUSH_INTERVAL, so the hours are totals across gateways. Storage is measured once an hour from the
+// storage nodes' stats.
+pub struct Accounting {
+    db_pool: DbPool,
+    // not yet flushed, by tenant and hour
+    pending: Mutex<HashMap<(Uuid, i64), Traffic>>,
+}
+
+impl Accounting {
+    pub fn new(db_pool: DbPool) -> Accounting {
+        Accounting {
+            db_pool,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Counts a request by the tenant, and the bytes it read and wrote
+    pub fn record_request(&self, tenant_id: Uuid, bytes_read: u64, bytes_written: u64) {
+        self.record(
+            tenant_id,
+            Traffic {
+                requests: 1,
+                bytes_read,
+                bytes_written,
+            },
+        );
+    }
+
+    // Counts bytes the tenant read, as part of a request already counted
+    pub fn record_read(&self, tenant_id: Uuid, bytes_read: u64) {
+        self.record(
+            tenant_id,
+            Traffic {
+                bytes_read,
+                ..Traffic::default()
+            },
+        );
+    }
+
+    fn record(&self, tenant_id: Uuid, traffic: Traffic) {
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry((tenant_id, hour(now())))
+            .or_default()
+            .add(traffic);
+    }
+
+    // Adds the counted traffic to the database. Traffic that couldn't be written is kept for the
+    // next flush.
+    pub async fn flush(&self) {
+        let pending =
+            std::mem::take(&mut *self.pending.lock().unwrap_or_else(PoisonError::into_inner));
+        for ((tenant_id, hour), traffic) in pending {
+            if let Err(err) = self.add(tenant_id, hour, traffic).await {
+                error!(
+                    tenant_id = tenant_id.to_string(),
+                    err = err.to_string(),
+                    "failed to write the tenant's usage"
+                );
+                self.pending
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .entry((tenant_id, hour))
+                    .or_default()
+                    .add(traffic);
+            }
+        }
+    }
+
+    async fn add(&self, tenant_id: Uuid, hour: i64, traffic: Traffic) -> Result<()> {
+        query("insert into tenant_usage_hourly (tenant_id, hour, requests, bytes_read, bytes_written) select id, $2, $3, $4, $5 from tenants where uuid = $1 on conflict (tenant_id, hour) do update set requests = tenant_usage_hourly.requests + excluded.requests, bytes_read = tenant_usage_hourly.bytes_read + excluded.bytes_read, bytes_written = tenant_usage_hourly.bytes_written + excluded.bytes_written")
+            .bind(tenant_id.to_string())
+            .bind(hour)
+            .bind(traffic.requests as i64)
+            .bind(traffic.bytes_read as i64)
+            .bind(traffic.bytes_written as i64)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_storage(&self, tenant_id: Uuid, hour: i64, storage_bytes: u64) -> Result<()> {
+        query("insert into tenant_usage_hourly (tenant_id, hour, storage_bytes) select id, $2, $3 from tenants where uuid = $1 on conflict (tenant_id, hour) do update set storage_bytes = excluded.storage_bytes")
+            .bind(tenant_id.to_string())
+            .bind(hour)
+            .bind(storage_bytes as i64)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    // The hours in the query's range, by tenant then hour
+    pub async fn hourly(&self, filter: &UsageQuery) -> Result<Vec<HourlyUsage>> {
+        let to = filter.to.unwrap_or_else(now);
+        let from = filter.from.unwrap_or(to - DEFAULT_QUERY_RANGE_SECS);
+        query("select t.name, u.hour, u.requests, u.bytes_read, u.bytes_written, u.storage_bytes from tenant_usage_hourly as u join tenants as t on t.id = u.tenant_id where ($1 = '' or t.name = $1) and u.hour >= $2 and u.hour < $3 order by t.name, u.hour")
+            .bind(filter.tenant.as_deref().unwrap_or_default())
+            .bind(from)
+            .bind(to)
+            .map(|row: AnyRow| row.into())
+            .fetch_all(&self.db_pool)
+            .await
+    }
+}
+
+// The hours as csv, with a header row
+pub fn to_csv(usage: &[HourlyUsage]) -> String {
+    let mut csv = String::from("tenant,hour,requests,bytes_read,bytes_written,storage_bytes\n");
+    for row in usage {
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{},{}",
+            csv_field(&row.tenant),
+            row.hour,
+            row.requests,
+            row.bytes_read,
+            row.bytes_written,
+            row.storage_bytes
+                .map(|bytes| bytes.to_string())
+                .unwrap_or_default()
+        );
+    }
+    csv
+}
+
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+// Counts the response towards the tenant that made the request, when it was authenticated. The
+// request's bytes are its declared length, the response's are counted as they're sent.
+pub fn meter<B: MessageBody + 'static>(response: ServiceResponse<B>) -> ServiceResponse<BoxBody> {
+    let response = response.map_into_boxed_body();
+    let Some(Metered(tenant_id)) = response.request().extensions().get::<Metered>().copied() else {
+        return response;
+    };
+    let Some(app_data) = response.request().app_data::<Data<AppData>>().cloned() else {
+        return response;
+    };
+    let bytes_written = response
+        .request()
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok())
+        .unwrap_or(0);
+    app_data
+        .accounting
+        .record_request(tenant_id, 0, bytes_written);
+    response.map_body(|_, body| {
+        BoxBody::new(MeteredBody {
+            body,
+            app_data,
+            tenant_id,
+            sent: 0,
+        })
+    })
+}
+
+// A response body that counts its bytes towards the tenant once it's done with, whether or not it
+// was sent in full
+struct MeteredBody {
+    body: BoxBody,
+    app_data: Data<AppData>,
+    tenant_id: Uuid,
+    sent: u64,
+}
+
+impl MessageBody for MeteredBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Bytes, Self::Error>>> {
+        let polled = Pin::new(&mut self.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &polled {
+            self.sent += chunk.len() as u64;
+        }
+        polled
+    }
+}
+
+impl Drop for MeteredBody {
+    fn drop(&mut self) {
+        if self.sent > 0 {
+            self.app_data
+                .accounting
+                .record_read(self.tenant_id, self.sent);
+        }
+    }
+}
+
+// Flushes the counted traffic every FLUSH_INTERVAL and measures every tenant's storage once an
+// hour. A tenant whose storage couldn't be measured is tried again on the next flush within the
+// hour.
+pub async fn run(app_data: Data<AppData>) {
+    let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+    // the hour being measured and the tenants measured in it
+    let mut measured: (i64, HashSet<Uuid>) = (0, HashSet::new());
+    loop {
+        ticker.tick().await;
+        app_data.accounting.flush().await;
+
+        let current = hour(now());
+        if measured.0 != current {
+            measured = (current, HashSet::new());
+        }
+        let tenants = match app_data.tenants.list().await {
+            Ok(tenants) => tenants,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to list tenants to measure");
+                continue;
+            }
+        };
+        for tenant in tenants {
+            if measured.1.contains(&tenant.uuid) {
+                continue;
+            }
+            let result = match usage::measure(&app_data, tenant.uuid).await {
+                Ok((_, total_bytes)) => app_data
+                    .accounting
+                    .set_storage(tenant.uuid, current, total_bytes)
+                    .await
+                    .map_err(|err| err.to_string()),
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(()) => {
+                    info!(tenant = tenant.name.as_ref(), "measured tenant storage");
+                    measured.1.insert(tenant.uuid);
+                }
+                Err(err) => error!(
+                    tenant = tenant.name.as_ref(),
+                    err = err,
+                    "failed to measure tenant storage"
+                ),
+            }
+        }
+    }
+}
+
+// The start of the hour the unix timestamp is in
+fn hour(timestamp: i64) -> i64 {
+    timestamp - timestamp.rem_euclid(HOUR_SECS)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
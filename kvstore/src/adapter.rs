@@ -0,0 +1,437 @@
+use crate::auth::AuthenticatedTenant;
+use crate::consistency::Consistency;
+use crate::error::KVErrors;
+use crate::namespace::Namespace;
+use crate::response_cache::CachedGet;
+use crate::retry::Rpc;
+use crate::usage::LimitExceeded;
+use crate::webhook::{Event, EventKind};
+use crate::{schema, service_metadata, AppData};
+use common::auth::Scope;
+use common::storage::{self, DeleteKeyRequest, GetRequest, GetResponse, PutRequest, TouchRequest};
+use crc32fast::Hasher;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+use tonic::{Code, Extensions};
+use tracing::{error, info};
+
+// Why a protocol adapter's command failed. The adapters, see resp and memcached, make the same
+// checks and storage calls as the http handlers and turn these into their protocol's errors.
+#[derive(Error, Debug)]
+pub enum AdapterError {
+    #[error("no such namespace")]
+    NoNamespace,
+
+    #[error("the token does not allow the command on the namespace")]
+    Forbidden,
+
+    #[error("{0}")]
+    InvalidKey(String),
+
+    #[error("the value does not match the namespace's schema")]
+    SchemaViolation,
+
+    #[error("the tenant is at its {} of {}", .0.limit, .0.max)]
+    LimitExceeded(LimitExceeded),
+
+    #[error("invalid expire time")]
+    InvalidTtl,
+
+    #[error("not found")]
+    NotFound,
+
+    // a put only if absent found the key
+    #[error("the key exists")]
+    Exists,
+
+    // the key isn't at the expected version
+    #[error("the key has changed")]
+    VersionMismatch,
+
+    #[error(transparent)]
+    Request(#[from] KVErrors),
+}
+
+impl From<tonic::Status> for AdapterError {
+    fn from(status: tonic::Status) -> Self {
+        match status.code() {
+            Code::NotFound => AdapterError::NotFound,
+            Code::AlreadyExists => AdapterError::Exists,
+            Code::FailedPrecondition => AdapterError::VersionMismatch,
+            _ => AdapterError::Request(status.into()),
+        }
+    }
+}
+
+// A put as the adapters make it, with the same options as one over http
+#[derive(Debug, Default)]
+pub struct Put {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    // the namespace's default ttl applies when not set
+    pub ttl_secs: Option<u64>,
+    pub expected_version: Option<u32>,
+    pub if_absent: bool,
+}
+
+// The storage request for a get of the key, shared with the http handlers. A node routes a key to
+// one of the namespace's partitions by hashing it, so the partition is left for the node to pick.
+pub fn get_request(
+    namespace: &Namespace,
+    key: Vec<u8>,
+    version: Option<u32>,
+    raw: bool,
+    consistency: Consistency,
+) -> GetRequest {
+    GetRequest {
+        namespace_id: namespace.id.to_string(),
+        partition_id: String::new(),
+        key,
+        version,
+        raw,
+        consistency: storage::Consistency::from(consistency).into(),
+    }
+}
+
+// The storage request for the put, shared with the http handlers like get_request. The ttl is the
+// one the key is written with, after the namespace's default applied.
+pub fn put_request(namespace: &Namespace, put: Put, crc: u32) -> PutRequest {
+    PutRequest {
+        namespace_id: namespace.id.to_string(),
+        partition_id: String::new(),
+        key: put.key,
+        value: put.value,
+        crc: Some(crc),
+        expected_version: put.expected_version,
+        ttl_secs: put.ttl_secs,
+        if_absent: put.if_absent,
+    }
+}
+
+// The tenant's namespace, when the caller can use it for the scope
+pub async fn namespace(
+    app_data: &AppData,
+    identity: &AuthenticatedTenant,
+    name: &str,
+    scope: Scope,
+) -> Result<Namespace, AdapterError> {
+    let namespace = app_data
+        .namespaces
+        .get(identity.tenant_id(), name)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to get namespace");
+            AdapterError::NoNamespace
+        })?;
+    if !identity.allows(scope, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Err(AdapterError::Forbidden);
+    }
+    Ok(namespace)
+}
+
+// None when the key doesn't exist, or has expired
+pub async fn get(
+    app_data: &AppData,
+    identity: &AuthenticatedTenant,
+    namespace: &Namespace,
+    key: &[u8],
+) -> Result<Option<GetResponse>, AdapterError> {
+    app_data
+        .accounting
+        .record_request(identity.tenant_id(), 0, 0);
+    let cached = CachedGet {
+        tenant_id: identity.tenant_id(),
+        namespace_id: namespace.id,
+        key: key.to_vec(),
+        version: None,
+        raw: false,
+    };
+    let cache_ttl = namespace.settings.cache_ttl_secs.map(Duration::from_secs);
+    if cache_ttl.is_some() && app_data.default_consistency == Consistency::Eventual {
+        if let Some(response) = app_data.response_cache.get(&cached).await {
+            app_data
+                .accounting
+                .record_read(identity.tenant_id(), response.value.len() as u64);
+            return Ok(Some(GetResponse::clone(&response)));
+        }
+    }
+
+    let metadata = service_metadata(app_data, identity)?;
+    let request = get_request(
+        namespace,
+        key.to_vec(),
+        None,
+        false,
+        app_data.default_consistency,
+    );
+    match app_data
+        .connection_manager
+        .call_idempotent(Rpc::Get, namespace.storage_node.as_deref(), |mut client| {
+            let request = tonic::Request::from_parts(
+                metadata.clone(),
+                Extensions::default(),
+                request.clone(),
+            );
+            async move { client.get(request).await }
+        })
+        .await
+    {
+        Ok(response) => {
+            let response = response.into_inner();
+            app_data
+                .accounting
+                .record_read(identity.tenant_id(), response.value.len() as u64);
+            if let Some(ttl) = cache_ttl {
+                app_data
+                    .response_cache
+                    .insert(cached, Arc::new(response.clone()), ttl)
+                    .await;
+            }
+            Ok(Some(response))
+        }
+        Err(err) if err.code() == Code::NotFound => Ok(None),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get key");
+            Err(err.into())
+        }
+    }
+}
+
+// Checked like a put over http: the key policy, the schema, the tenant's limits and the namespace's
+// default ttl all apply. Returns the key's new version.
+pub async fn put(
+    app_data: &AppData,
+    identity: &AuthenticatedTenant,
+    namespace: &Namespace,
+    put: Put,
+) -> Result<u32, AdapterError> {
+    let tenant_id = identity.tenant_id();
+    let id = String::from_utf8_lossy(&put.key).into_owned();
+    app_data
+        .accounting
+        .record_request(tenant_id, 0, put.value.len() as u64);
+
+    if let Some(reason) = namespace.key_policy.violation(&put.key) {
+        info!(key = id, "key breaks the namespace's key policy");
+        return Err(AdapterError::InvalidKey(reason));
+    }
+
+    let schema = app_data
+        .schemas
+        .validator(namespace.id)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to get namespace schema");
+            KVErrors::from(err)
+        })?;
+    if let Some(schema) = schema {
+        schema::validate(&schema, &put.value).map_err(|_| {
+            info!(key = id, "value does not match the namespace's schema");
+            AdapterError::SchemaViolation
+        })?;
+    }
+
+    // a put expecting a version past 0 overwrites a key, so it doesn't add to the tenant's keys
+    let overwrite = put.expected_version.is_some_and(|expected| expected > 0);
+    let value_len = put.value.len() as u64;
+    match app_data
+        .usage
+        .check_put(tenant_id, overwrite, value_len)
+        .await
+    {
+        Ok(None) => {}
+        Ok(Some(exceeded)) => {
+            info!(limit = exceeded.limit, "tenant is at its limit");
+            return Err(AdapterError::LimitExceeded(exceeded));
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to check the tenant's limits");
+            return Err(KVErrors::from(err).into());
+        }
+    }
+
+    // a ttl of 0 would expire the key as it's written, and expiries are stored as signed seconds
+    let ttl_secs = put.ttl_secs.or(namespace.settings.default_ttl_secs);
+    if ttl_secs.is_some_and(|ttl| ttl == 0 || ttl > i64::MAX as u64) {
+        return Err(AdapterError::InvalidTtl);
+    }
+
+    let mut hasher = Hasher::new();
+    hasher.update(&put.key);
+    hasher.update(&put.value);
+    let crc = hasher.finalize();
+
+    let key = put.key.clone();
+    let request = tonic::Request::from_parts(
+        service_metadata(app_data, identity)?,
+        Extensions::default(),
+        put_request(namespace, Put { ttl_secs, ..put }, crc),
+    );
+    let written = app_data
+        .connection_manager
+        .call(
+            Rpc::Put,
+            namespace.storage_node.as_deref(),
+            |mut client| async move { client.put(request).await },
+        )
+        .await;
+    // a put that failed, e.g. timed out, may still have been written
+    app_data
+        .response_cache
+        .invalidate_key(tenant_id, namespace.id, &key)
+        .await;
+    let version = match written {
+        Ok(response) => response.into_inner().version,
+        Err(err) => {
+            info!(err = err.to_string(), "failed to put value");
+            return Err(err.into());
+        }
+    };
+
+    // the put went through, so failing to count it is only logged
+    if let Err(err) = app_data
+        .usage
+        .record_put(tenant_id, version == 1, value_len)
+        .await
+    {
+        error!(err = err.to_string(), "failed to record the tenant's usage");
+    }
+
+    app_data.webhooks.notify(Event::new(
+        EventKind::Put,
+        namespace.id,
+        &namespace.name,
+        &id,
+        Some(version),
+    ));
+    Ok(version)
+}
+
+pub async fn delete(
+    app_data: &AppData,
+    identity: &AuthenticatedTenant,
+    namespace: &Namespace,
+    key: &[u8],
+    expected_version: Option<u32>,
+) -> Result<(), AdapterError> {
+    app_data
+        .accounting
+        .record_request(identity.tenant_id(), 0, 0);
+    let id = String::from_utf8_lossy(key).into_owned();
+    let request = tonic::Request::from_parts(
+        service_metadata(app_data, identity)?,
+        Extensions::default(),
+        DeleteKeyRequest {
+            namespace_id: namespace.id.to_string(),
+            key: key.to_vec(),
+            expected_version,
+            forget: false,
+        },
+    );
+    let deleted = app_data
+        .connection_manager
+        .call(
+            Rpc::Delete,
+            namespace.storage_node.as_deref(),
+            |mut client| async move { client.delete(request).await },
+        )
+        .await;
+    app_data
+        .response_cache
+        .invalidate_key(identity.tenant_id(), namespace.id, key)
+        .await;
+    match deleted {
+        Ok(_) => {
+            app_data.webhooks.notify(Event::new(
+                EventKind::Delete,
+                namespace.id,
+                &namespace.name,
+                &id,
+                None,
+            ));
+            Ok(())
+        }
+        Err(err) => {
+            info!(err = err.to_string(), "failed to delete key");
+            Err(err.into())
+        }
+    }
+}
+
+// Moves the key's expiry without rewriting its value, it no longer expires when ttl_secs isn't set
+pub async fn touch(
+    app_data: &AppData,
+    identity: &AuthenticatedTenant,
+    namespace: &Namespace,
+    key: &[u8],
+    ttl_secs: Option<u64>,
+) -> Result<(), AdapterError> {
+    app_data
+        .accounting
+        .record_request(identity.tenant_id(), 0, 0);
+    if ttl_secs.is_some_and(|ttl| ttl == 0 || ttl > i64::MAX as u64) {
+        return Err(AdapterError::InvalidTtl);
+    }
+    let metadata = service_metadata(app_data, identity)?;
+    let request = TouchRequest {
+        namespace_id: namespace.id.to_string(),
+        key: key.to_vec(),
+        ttl_secs,
+    };
+    let touched = app_data
+        .connection_manager
+        .call_idempotent(
+            Rpc::Touch,
+            namespace.storage_node.as_deref(),
+            |mut client| {
+                let request = tonic::Request::from_parts(
+                    metadata.clone(),
+                    Extensions::default(),
+                    request.clone(),
+                );
+                async move { client.touch(request).await }
+            },
+        )
+        .await;
+    app_data
+        .response_cache
+        .invalidate_key(identity.tenant_id(), namespace.id, key)
+        .await;
+    match touched {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            info!(err = err.to_string(), "failed to touch key");
+            Err(err.into())
+        }
+    }
+}
+
+// A line of a line based protocol without its \r\n, None once the client has closed the connection
+pub async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_len: u64,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(max_len)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "line too long or unterminated",
+        ));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
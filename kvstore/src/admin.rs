@@ -0,0 +1,1017 @@
+use crate::accounting::{self, ExportFormat, HourlyUsage, UsageQuery};
+use crate::audit::{AuditEvent, AuditQuery, Outcome};
+use crate::client_cert::CertificateMapping;
+use crate::namespace::Namespace;
+use crate::purge::{self, NodeFailure, PurgeError};
+use crate::retry::Rpc;
+use crate::storage_target::StorageTarget;
+use crate::tenant::Tenant;
+use crate::usage::{TenantLimits, TenantUsage};
+use crate::{AppData, KVErrors};
+use actix_web::dev::{Payload, Service};
+use actix_web::http::header::Header;
+use actix_web::http::StatusCode;
+use actix_web::web::Data;
+use actix_web::{
+    delete, get, post, put, web, App, FromRequest, HttpRequest, HttpResponse, HttpResponseBuilder,
+    HttpServer, Responder,
+};
+use common::auth::{AuthHeader, JwtIssuer, Scope};
+use common::logging::{self, LogLevel};
+use common::storage::ListNamespacesRequest;
+use futures::future::{ready, Ready};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io;
+use std::time::Duration;
+use tonic::metadata::MetadataMap;
+use tonic::Extensions;
+use tracing::{error, info, warn};
+use tracing_actix_web::TracingLogger;
+use tracing_attributes::instrument;
+use uuid::Uuid;
+
+// Shared secret that admin callers present as a bearer token
+pub struct AdminToken(String);
+
+impl AdminToken {
+    pub fn new(token: impl Into<String>) -> AdminToken {
+        AdminToken(token.into())
+    }
+
+    // compares in constant time so the token can't be guessed byte by byte from response timings
+    fn matches(&self, auth_header: &AuthHeader) -> bool {
+        let expected = self.0.as_bytes();
+        let actual = auth_header.as_ref().as_bytes();
+        expected.len() == actual.len()
+            && expected
+                .iter()
+                .zip(actual)
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+// Rejects the request with a 401 unless its bearer token is the admin token, every admin handler
+// takes one
+#[derive(Debug)]
+struct AuthenticatedAdmin;
+
+impl FromRequest for AuthenticatedAdmin {
+    type Error = KVErrors;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let admin_token = req.app_data::<Data<AdminToken>>();
+        let authenticated = match (admin_token, AuthHeader::parse(req)) {
+            (Some(admin_token), Ok(auth_header)) => admin_token.matches(&auth_header),
+            _ => false,
+        };
+        if !authenticated {
+            error!("invalid admin token");
+            return ready(Err(KVErrors::Unauthorized));
+        }
+        ready(Ok(AuthenticatedAdmin))
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateTenantRequest {
+    name: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct SetPasswordRequest {
+    password: String,
+}
+
+#[derive(Serialize, Debug)]
+struct TenantsResponse {
+    tenants: Vec<Tenant>,
+}
+
+#[derive(Serialize, Debug)]
+struct TenantNamespace {
+    tenant: String,
+    #[serde(flatten)]
+    namespace: Namespace,
+}
+
+#[derive(Serialize, Debug)]
+struct AllNamespacesResponse {
+    namespaces: Vec<TenantNamespace>,
+}
+
+#[instrument(skip(app_data))]
+#[get("/admin/tenants")]
+async fn list_tenants(_admin: AuthenticatedAdmin, app_data: Data<AppData>) -> impl Responder {
+    match app_data.tenants.list().await {
+        Ok(tenants) => HttpResponseBuilder::new(StatusCode::OK).json(TenantsResponse { tenants }),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to list tenants");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[instrument(skip(app_data, data))]
+#[post("/admin/tenants")]
+async fn create_tenant(
+    _admin: AuthenticatedAdmin,
+    data: web::Json<CreateTenantRequest>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    let CreateTenantRequest { name, password } = data.into_inner();
+    if name.is_empty() || password.is_empty() {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+    }
+
+    info!(tenant = name, "creating tenant");
+
+    let Some(password_hash) = hash_password(&app_data, password).await else {
+        return HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish();
+    };
+
+    match app_data.tenants.create(&name, &password_hash).await {
+        Ok(tenant) => {
+            let mut response = HttpResponseBuilder::new(StatusCode::CREATED).json(&tenant);
+            response.extensions_mut().insert(AuditedTenant(tenant.uuid));
+            response
+        }
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            HttpResponseBuilder::new(StatusCode::CONFLICT).finish()
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to create tenant");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[instrument(skip(app_data, data))]
+#[put("/admin/tenants/{name}/password")]
+async fn set_password(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<String>,
+    data: web::Json<SetPasswordRequest>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    let name = path.into_inner();
+    let password = data.into_inner().password;
+    if password.is_empty() {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+    }
+
+    info!(tenant = name, "setting tenant password");
+
+    let Some(password_hash) = hash_password(&app_data, password).await else {
+        return HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish();
+    };
+
+    match app_data
+        .tenants
+        .set_password_hash(&name, &password_hash)
+        .await
+    {
+        Ok(true) => HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish(),
+        Ok(false) => HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish(),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to set password");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+// Hashing is cpu bound so it runs on the blocking thread pool
+async fn hash_password(app_data: &AppData, password: String) -> Option<String> {
+    let passwords = app_data.passwords.clone();
+    match web::block(move || passwords.hash(&password)).await {
+        Ok(Ok(hash)) => Some(hash),
+        Ok(Err(err)) => {
+            error!(err = err.to_string(), "failed to hash password");
+            None
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to run password hashing");
+            None
+        }
+    }
+}
+
+#[instrument(skip(app_data))]
+#[post("/admin/tenants/{name}/disable")]
+async fn disable_tenant(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    set_suspended(path.into_inner(), true, app_data).await
+}
+
+#[instrument(skip(app_data))]
+#[post("/admin/tenants/{name}/enable")]
+async fn enable_tenant(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    set_suspended(path.into_inner(), false, app_data).await
+}
+
+// A suspended tenant's requests are rejected with a 403 until it's resumed, its data is kept
+async fn set_suspended(name: String, suspended: bool, app_data: Data<AppData>) -> impl Responder {
+    info!(tenant = name, suspended = suspended, "updating tenant");
+
+    match app_data.tenants.set_suspended(&name, suspended).await {
+        Ok(true) => HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish(),
+        // a deleted tenant has to be restored first
+        Ok(false) => match app_data.tenants.get(&name).await {
+            Ok(_) => HttpResponseBuilder::new(StatusCode::CONFLICT).finish(),
+            Err(_) => HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish(),
+        },
+        Err(err) => {
+            error!(err = err.to_string(), "failed to update tenant");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct DeleteTenantQuery {
+    // skips the grace period
+    #[serde(default)]
+    purge: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct DeleteTenantScheduled {
+    purge_after: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct DeleteTenantResponse {
+    partitions_deleted: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct DeleteTenantFailed {
+    failed: Vec<NodeFailure>,
+}
+
+// Offboards a tenant. It's marked deleted right away, which rejects its requests and revokes its
+// tokens, and it's purged once the deletion grace period passes, see purge::purge. With ?purge=true
+// it's purged now, if a node can't be reached the tenant is left deleted and the purge can be
+// retried.
+#[instrument(skip(app_data))]
+#[delete("/admin/tenants/{name}")]
+async fn delete_tenant(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<String>,
+    query: web::Query<DeleteTenantQuery>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    let name = path.into_inner();
+
+    info!(tenant = name, purge = query.purge, "deleting tenant");
+
+    let purge_after = match app_data.tenants.schedule_deletion(&name).await {
+        Ok(Some(purge_after)) => purge_after,
+        Ok(None) => return HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish(),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to delete tenant");
+            return HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish();
+        }
+    };
+    if !query.purge {
+        return HttpResponseBuilder::new(StatusCode::ACCEPTED)
+            .json(DeleteTenantScheduled { purge_after });
+    }
+
+    let tenant = match app_data.tenants.get(&name).await {
+        Ok(tenant) => tenant,
+        Err(sqlx::Error::RowNotFound) => {
+            return HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get tenant");
+            return HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish();
+        }
+    };
+
+    match purge::purge(&app_data, &tenant).await {
+        Ok(partitions_deleted) => HttpResponseBuilder::new(StatusCode::OK)
+            .json(DeleteTenantResponse { partitions_deleted }),
+        Err(PurgeError::Nodes(failed)) => {
+            HttpResponseBuilder::new(StatusCode::BAD_GATEWAY).json(DeleteTenantFailed { failed })
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to purge tenant");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+// Cancels a pending deletion, the tenant is active again but its old tokens stay revoked
+#[instrument(skip(app_data))]
+#[post("/admin/tenants/{name}/restore")]
+async fn restore_tenant(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    let name = path.into_inner();
+
+    info!(tenant = name, "restoring tenant");
+
+    match app_data.tenants.restore(&name).await {
+        Ok(true) => HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish(),
+        // only deleted tenants can be restored
+        Ok(false) => match app_data.tenants.get(&name).await {
+            Ok(_) => HttpResponseBuilder::new(StatusCode::CONFLICT).finish(),
+            Err(_) => HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish(),
+        },
+        Err(err) => {
+            error!(err = err.to_string(), "failed to restore tenant");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[instrument(skip(app_data))]
+#[post("/admin/tenants/{name}/revoke-tokens")]
+async fn revoke_tokens(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    let name = path.into_inner();
+
+    info!(tenant = name, "revoking tenant tokens");
+
+    match app_data.tenants.revoke_tokens(&name).await {
+        Ok(true) => HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish(),
+        Ok(false) => HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish(),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to revoke tokens");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+// The tenant's uuid, a 404 when there's no tenant with the name
+async fn tenant_id(app_data: &AppData, name: &str) -> Result<Uuid, HttpResponse> {
+    match app_data.tenants.get(name).await {
+        Ok(tenant) => Ok(tenant.uuid),
+        Err(sqlx::Error::RowNotFound) => {
+            Err(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish())
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get tenant");
+            Err(HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish())
+        }
+    }
+}
+
+#[instrument(skip(app_data))]
+#[get("/admin/tenants/{name}/limits")]
+async fn get_limits(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    let tenant_id = match tenant_id(&app_data, &path.into_inner()).await {
+        Ok(tenant_id) => tenant_id,
+        Err(response) => return response,
+    };
+
+    match app_data.usage.limits(tenant_id).await {
+        Ok(limits) => HttpResponseBuilder::new(StatusCode::OK).json(limits),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get tenant limits");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+// Replaces the tenant's limits, e.g. {"max_namespaces": 10, "max_bytes": 1073741824}, limits left
+// out are unlimited. Lowering a limit below the tenant's usage doesn't remove anything, it only
+// stops the tenant growing further.
+#[instrument(skip(app_data))]
+#[put("/admin/tenants/{name}/limits")]
+async fn set_limits(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<String>,
+    data: web::Json<TenantLimits>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    if !data.is_valid() {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+    }
+
+    let name = path.into_inner();
+
+    info!(tenant = name, "setting tenant limits");
+
+    match app_data.usage.set_limits(&name, &data).await {
+        Ok(true) => HttpResponseBuilder::new(StatusCode::OK).json(data.into_inner()),
+        Ok(false) => HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish(),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to set tenant limits");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct TenantUsageResponse {
+    limits: TenantLimits,
+    usage: TenantUsage,
+}
+
+// Keys and bytes are only measured for tenants with a key or byte limit, see usage::refresh
+#[instrument(skip(app_data))]
+#[get("/admin/tenants/{name}/usage")]
+async fn get_usage(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    let tenant_id = match tenant_id(&app_data, &path.into_inner()).await {
+        Ok(tenant_id) => tenant_id,
+        Err(response) => return response,
+    };
+
+    let limits = app_data.usage.limits(tenant_id).await;
+    let usage = app_data.usage.usage(tenant_id).await;
+    match limits.and_then(|limits| usage.map(|usage| (limits, usage))) {
+        Ok((limits, usage)) => {
+            HttpResponseBuilder::new(StatusCode::OK).json(TenantUsageResponse { limits, usage })
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get tenant usage");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct HourlyUsageResponse {
+    usage: Vec<HourlyUsage>,
+}
+
+// Every tenant's usage by the hour, for charging tenants back, e.g.
+// /admin/usage/hourly?tenant=dev&from=1700000000&to=1700086400&format=csv. The hours counted
+// within the last minute may not have been written yet, see accounting.
+#[instrument(skip(app_data))]
+#[get("/admin/usage/hourly")]
+async fn get_hourly_usage(
+    _admin: AuthenticatedAdmin,
+    filter: web::Query<UsageQuery>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    match app_data.accounting.hourly(&filter).await {
+        Ok(usage) => match filter.format {
+            ExportFormat::Json => {
+                HttpResponseBuilder::new(StatusCode::OK).json(HourlyUsageResponse { usage })
+            }
+            ExportFormat::Csv => HttpResponseBuilder::new(StatusCode::OK)
+                .content_type("text/csv")
+                .insert_header(("Content-Disposition", "attachment; filename=\"usage.csv\""))
+                .body(accounting::to_csv(&usage)),
+        },
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get hourly usage");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct CertificatesResponse {
+    certificates: Vec<CertificateMapping>,
+}
+
+// Maps a client certificate to the tenant by fingerprint or by subject alternative name, e.g.
+// {"san": "uri:spiffe://cluster/reporting"}
+#[instrument(skip(app_data))]
+#[post("/admin/tenants/{name}/certificates")]
+async fn add_certificate(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<String>,
+    data: web::Json<CertificateMapping>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    // exactly one of fingerprint and san identifies the certificate
+    if data.fingerprint.is_some() == data.san.is_some() {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+    }
+
+    let name = path.into_inner();
+
+    info!(tenant = name, "mapping client certificate to tenant");
+
+    match app_data
+        .client_certs
+        .create(&name, data.fingerprint.as_deref(), data.san.as_deref())
+        .await
+    {
+        Ok(Some(mapping)) => HttpResponseBuilder::new(StatusCode::CREATED).json(mapping),
+        Ok(None) => HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish(),
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            HttpResponseBuilder::new(StatusCode::CONFLICT).finish()
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to map client certificate");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[instrument(skip(app_data))]
+#[get("/admin/tenants/{name}/certificates")]
+async fn list_certificates(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    match app_data.client_certs.list(&path.into_inner()).await {
+        Ok(certificates) => {
+            HttpResponseBuilder::new(StatusCode::OK).json(CertificatesResponse { certificates })
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to list client certificates");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[instrument(skip(app_data))]
+#[delete("/admin/tenants/{name}/certificates/{id}")]
+async fn remove_certificate(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<(String, Uuid)>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    let (name, id) = path.into_inner();
+
+    info!(tenant = name, "removing client certificate mapping");
+
+    match app_data.client_certs.delete(&name, id).await {
+        Ok(true) => HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish(),
+        Ok(false) => HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish(),
+        Err(err) => {
+            error!(
+                err = err.to_string(),
+                "failed to remove client certificate mapping"
+            );
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[instrument(skip(app_data))]
+#[get("/admin/namespaces")]
+async fn list_all_namespaces(
+    _admin: AuthenticatedAdmin,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    match app_data.namespaces.list_all().await {
+        Ok(namespaces) => HttpResponseBuilder::new(StatusCode::OK).json(AllNamespacesResponse {
+            namespaces: namespaces
+                .into_iter()
+                .map(|(tenant, namespace)| TenantNamespace { tenant, namespace })
+                .collect(),
+        }),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to list namespaces");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct OrphanedNamespace {
+    node: String,
+    namespace_id: String,
+    partitions: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct ReconcileResponse {
+    // partitions on a storage node for a namespace the database doesn't have
+    orphaned: Vec<OrphanedNamespace>,
+    // namespaces in the database no storage node has partitions for
+    unhosted: Vec<String>,
+    // nodes that couldn't be asked, a namespace only they host is reported as unhosted
+    failed: Vec<NodeFailure>,
+}
+
+// Compares the tenant's namespaces in the database with the ones the storage nodes host
+#[instrument(skip(app_data))]
+#[get("/admin/tenants/{name}/reconcile")]
+async fn reconcile_namespaces(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    let name = path.into_inner();
+
+    info!(
+        tenant = name,
+        "reconciling namespaces with the storage nodes"
+    );
+
+    let tenant_id = match tenant_id(&app_data, &name).await {
+        Ok(tenant_id) => tenant_id,
+        Err(response) => return response,
+    };
+    let namespaces = match app_data.namespaces.list(tenant_id).await {
+        Ok(namespaces) => namespaces,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to list namespaces");
+            return HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish();
+        }
+    };
+
+    // the nodes list the namespaces of the token's tenant
+    let metadata: MetadataMap = match app_data
+        .jwts
+        .new_scoped_identity(tenant_id, vec![Scope::Read], None)
+        .and_then(|identity| app_data.jwts.new_service_identity(&identity))
+    {
+        Ok(identity) => AuthHeader::from(identity.token()).into(),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to issue service token");
+            return HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish();
+        }
+    };
+    let results = app_data
+        .connection_manager
+        .call_all(Rpc::ListNamespaces, |mut client| {
+            let request = tonic::Request::from_parts(
+                metadata.clone(),
+                Extensions::default(),
+                ListNamespacesRequest {},
+            );
+            async move { client.list_namespaces(request).await }
+        })
+        .await;
+
+    let mut hosted = HashSet::new();
+    let mut response = ReconcileResponse {
+        orphaned: vec![],
+        unhosted: vec![],
+        failed: vec![],
+    };
+    for (node, result) in results {
+        let node_namespaces = match result {
+            Ok(node_namespaces) => node_namespaces.into_inner().namespaces,
+            Err(status) => {
+                response.failed.push(NodeFailure {
+                    node,
+                    error: status.message().to_string(),
+                });
+                continue;
+            }
+        };
+        for namespace in node_namespaces {
+            let known = namespaces
+                .iter()
+                .any(|known| known.id.to_string() == namespace.namespace_id);
+            if known {
+                hosted.insert(namespace.namespace_id);
+            } else {
+                response.orphaned.push(OrphanedNamespace {
+                    node: node.clone(),
+                    namespace_id: namespace.namespace_id,
+                    partitions: namespace.partitions,
+                });
+            }
+        }
+    }
+    response.unhosted = namespaces
+        .into_iter()
+        .filter(|namespace| !hosted.contains(&namespace.id.to_string()))
+        .map(|namespace| namespace.name)
+        .collect();
+
+    HttpResponseBuilder::new(StatusCode::OK).json(response)
+}
+
+// The tenant an admin call is audited with when its path doesn't name one
+struct AuditedTenant(Uuid);
+
+#[derive(Serialize, Debug)]
+struct AuditEventsResponse {
+    events: Vec<AuditEvent>,
+}
+
+// Filters are passed as query parameters, e.g. /admin/audit?tenant=dev&event=login_failed&since=1700000000
+#[instrument(skip(app_data))]
+#[get("/admin/audit")]
+async fn list_audit_events(
+    _admin: AuthenticatedAdmin,
+    filter: web::Query<AuditQuery>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    // events are stored with the tenant's uuid, a name is looked up
+    let mut filter = filter.into_inner();
+    if let Some(name) = filter.tenant.as_deref() {
+        if Uuid::parse_str(name).is_err() {
+            match tenant_id(&app_data, name).await {
+                Ok(tenant_id) => filter.tenant = Some(tenant_id.to_string()),
+                Err(response) => return response,
+            }
+        }
+    }
+
+    match app_data.audit.list(&filter).await {
+        Ok(events) => HttpResponseBuilder::new(StatusCode::OK).json(AuditEventsResponse { events }),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to list audit events");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct StorageTargetState {
+    #[serde(flatten)]
+    target: StorageTarget,
+    // the gateway's circuit state for the node, null when requests aren't routed to it
+    circuit: Option<&'static str>,
+}
+
+#[derive(Serialize, Debug)]
+struct StorageTargetsResponse {
+    targets: Vec<StorageTargetState>,
+}
+
+// Applies a registration change to this gateway's routing, other gateways pick it up on their next
+// discovery refresh
+async fn reroute(app_data: &AppData) {
+    match app_data.storage_targets.routed_endpoints().await {
+        Ok(registered) => app_data
+            .connection_manager
+            .set_registered_endpoints(&registered),
+        Err(err) => error!(
+            err = err.to_string(),
+            "failed to load registered storage targets"
+        ),
+    }
+}
+
+#[instrument(skip(app_data))]
+#[get("/admin/storage-targets")]
+async fn list_storage_targets(
+    _admin: AuthenticatedAdmin,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    match app_data.storage_targets.list().await {
+        Ok(targets) => HttpResponseBuilder::new(StatusCode::OK).json(StorageTargetsResponse {
+            targets: targets
+                .into_iter()
+                .map(|target| StorageTargetState {
+                    circuit: app_data.connection_manager.circuit_state(&target.endpoint),
+                    target,
+                })
+                .collect(),
+        }),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to list storage targets");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+// Registers a storage node, it's routed to right away while its status is active, e.g.
+// {"endpoint": "http://storage-3:50051", "status": "active", "capacity_bytes": 107374182400}
+#[instrument(skip(app_data))]
+#[post("/admin/storage-targets")]
+async fn register_storage_target(
+    _admin: AuthenticatedAdmin,
+    data: web::Json<StorageTarget>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    if !data.is_valid() {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+    }
+
+    info!(endpoint = data.endpoint, "registering storage target");
+
+    match app_data.storage_targets.create(&data).await {
+        Ok(target) => {
+            reroute(&app_data).await;
+            HttpResponseBuilder::new(StatusCode::CREATED).json(target)
+        }
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            HttpResponseBuilder::new(StatusCode::CONFLICT).finish()
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to register storage target");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+// Replaces a target's endpoint, status, and capacity, e.g. {"endpoint": "http://storage-3:50051",
+// "status": "draining"} to stop routing to it
+#[instrument(skip(app_data))]
+#[put("/admin/storage-targets/{id}")]
+async fn update_storage_target(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<Uuid>,
+    data: web::Json<StorageTarget>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    if !data.is_valid() {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+    }
+
+    let id = path.into_inner();
+
+    info!(id = id.to_string(), "updating storage target");
+
+    match app_data.storage_targets.update(id, &data).await {
+        Ok(Some(target)) => {
+            reroute(&app_data).await;
+            HttpResponseBuilder::new(StatusCode::OK).json(target)
+        }
+        Ok(None) => HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish(),
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            HttpResponseBuilder::new(StatusCode::CONFLICT).finish()
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to update storage target");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+// Stops routing to the node, its data isn't touched
+#[instrument(skip(app_data))]
+#[delete("/admin/storage-targets/{id}")]
+async fn remove_storage_target(
+    _admin: AuthenticatedAdmin,
+    path: web::Path<Uuid>,
+    app_data: Data<AppData>,
+) -> impl Responder {
+    let id = path.into_inner();
+
+    info!(id = id.to_string(), "removing storage target");
+
+    match app_data.storage_targets.delete(id).await {
+        Ok(true) => {
+            reroute(&app_data).await;
+            HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish()
+        }
+        Ok(false) => HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish(),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to remove storage target");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LogFilterRequest {
+    // tracing directives added on top of the configured level, e.g. kvstore::connections=debug
+    directives: String,
+    // the override is removed after this long, it stays until deleted when not given
+    duration_secs: Option<u64>,
+}
+
+#[instrument(skip(log_level))]
+#[get("/admin/log-filter")]
+async fn get_log_filter(_admin: AuthenticatedAdmin, log_level: Data<LogLevel>) -> impl Responder {
+    HttpResponseBuilder::new(StatusCode::OK).json(log_level.current())
+}
+
+// Raises logging for some modules without a restart, e.g.
+// {"directives": "kvstore::connections=debug", "duration_secs": 600}
+#[instrument(skip(log_level, data))]
+#[put("/admin/log-filter")]
+async fn set_log_filter(
+    _admin: AuthenticatedAdmin,
+    data: web::Json<LogFilterRequest>,
+    log_level: Data<LogLevel>,
+) -> impl Responder {
+    let duration = data.duration_secs.map(Duration::from_secs);
+    match log_level.override_with(&data.directives, duration) {
+        Ok(()) => HttpResponseBuilder::new(StatusCode::OK).json(log_level.current()),
+        Err(logging::Error::InvalidFilter(err)) => {
+            HttpResponseBuilder::new(StatusCode::BAD_REQUEST).body(err.to_string())
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to set log filter");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+#[instrument(skip(log_level))]
+#[delete("/admin/log-filter")]
+async fn clear_log_filter(_admin: AuthenticatedAdmin, log_level: Data<LogLevel>) -> impl Responder {
+    match log_level.clear() {
+        Ok(()) => HttpResponseBuilder::new(StatusCode::OK).json(log_level.current()),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to clear log filter");
+            HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+// Runs the admin api on its own port so it can be firewalled separately from tenant traffic.
+// The admin api is disabled when no admin token is configured.
+pub async fn admin_endpoint(
+    bind_address: &str,
+    port: u16,
+    app_data: Data<AppData>,
+    admin_token: Option<AdminToken>,
+    log_level: LogLevel,
+) -> io::Result<()> {
+    let Some(admin_token) = admin_token else {
+        warn!("no admin token configured, admin api is disabled");
+        return Ok(());
+    };
+    let admin_token = Data::new(admin_token);
+    let log_level = Data::new(log_level);
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(app_data.clone())
+            .app_data(admin_token.clone())
+            .app_data(log_level.clone())
+            .wrap_fn(|req, srv| {
+                // every admin call is audited, including ones rejected for a bad admin token
+                let app_data = req.app_data::<Data<AppData>>().cloned();
+                let source = req.peer_addr().map(|addr| addr.ip());
+                let call = format!("{} {}", req.method(), req.path());
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    if let Some(app_data) = app_data {
+                        let outcome = if response.status() == StatusCode::UNAUTHORIZED {
+                            Outcome::Denied
+                        } else if response.status().is_success() {
+                            Outcome::Success
+                        } else {
+                            Outcome::Failure
+                        };
+                        // calls name the tenant, the event has its uuid, a tenant that was just
+                        // created is passed along by the handler
+                        let created = response
+                            .response()
+                            .extensions()
+                            .get::<AuditedTenant>()
+                            .map(|AuditedTenant(tenant_id)| *tenant_id);
+                        let tenant = match (created, response.request().match_info().get("name")) {
+                            (Some(tenant_id), _) => Some(tenant_id),
+                            (None, Some(name)) => app_data
+                                .tenants
+                                .get(name)
+                                .await
+                                .ok()
+                                .map(|tenant| tenant.uuid),
+                            (None, None) => None,
+                        };
+                        app_data
+                            .audit
+                            .record("admin_call", tenant, source, outcome, Some(&call))
+                            .await;
+                    }
+                    Ok(response)
+                }
+            })
+            .wrap(TracingLogger::default())
+            .service(list_tenants)
+            .service(create_tenant)
+            .service(set_password)
+            .service(disable_tenant)
+            .service(enable_tenant)
+            .service(revoke_tokens)
+            .service(get_limits)
+            .service(set_limits)
+            .service(get_usage)
+            .service(get_hourly_usage)
+            .service(delete_tenant)
+            .service(restore_tenant)
+            .service(list_all_namespaces)
+            .service(reconcile_namespaces)
+            .service(add_certificate)
+            .service(list_certificates)
+            .service(remove_certificate)
+            .service(list_audit_events)
+            .service(list_storage_targets)
+            .service(register_storage_target)
+            .service(update_storage_target)
+            .service(remove_storage_target)
+            .service(get_log_filter)
+            .service(set_log_filter)
+            .service(clear_log_filter)
+    })
+    .bind((bind_address, port))?
+    .run()
+    .await
+}
@@ -0,0 +1,91 @@
+use crate::db::DbPool;
+use common::auth::ApiKey;
+use serde::Serialize;
+use sqlx::any::AnyRow;
+use sqlx::{query, Result, Row};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing_attributes::instrument;
+use uuid::Uuid;
+
+#[derive(Serialize, Debug)]
+pub struct ApiKeyInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: i64,
+}
+
+impl From<AnyRow> for ApiKeyInfo {
+    fn from(row: AnyRow) -> Self {
+        ApiKeyInfo {
+            id: Uuid::parse_str(row.get(0)).unwrap(),
+            name: row.get(1),
+            created_at: row.get(2),
+        }
+    }
+}
+
+pub struct ApiKeyRepo {
+    db_pool: DbPool,
+}
+
+impl ApiKeyRepo {
+    pub fn new(db_pool: DbPool) -> ApiKeyRepo {
+        ApiKeyRepo { db_pool }
+    }
+
+    // Creates a new key for the tenant. The key itself is only returned here, only its hash is stored.
+    #[instrument(skip(self))]
+    pub async fn create(&self, tenant_id: Uuid, name: &str) -> Result<(ApiKeyInfo, ApiKey)> {
+        let api_key = ApiKey::generate();
+        let info = ApiKeyInfo {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs() as i64)
+                .unwrap_or(0),
+        };
+
+        let result = query("insert into api_keys (uuid, tenant_id, name, key_hash, created_at) select $1, id, $2, $3, $4 from tenants where uuid = $5")
+            .bind(info.id.to_string())
+            .bind(&info.name)
+            .bind(api_key.hash())
+            .bind(info.created_at)
+            .bind(tenant_id.to_string())
+            .execute(&self.db_pool)
+            .await?;
+        // nothing is inserted for a tenant that doesn't exist
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        Ok((info, api_key))
+    }
+
+    pub async fn list(&self, tenant_id: Uuid) -> Result<Vec<ApiKeyInfo>> {
+        query("select k.uuid, k.name, k.created_at from api_keys as k inner join tenants on k.tenant_id = tenants.id where tenants.uuid = $1 and k.revoked = false order by k.created_at")
+            .bind(tenant_id.to_string())
+            .map(|row: AnyRow| row.into())
+            .fetch_all(&self.db_pool)
+            .await
+    }
+
+    // Returns false if the tenant has no active key with the given id
+    pub async fn revoke(&self, tenant_id: Uuid, id: Uuid) -> Result<bool> {
+        let result = query("update api_keys set revoked = true where uuid = $1 and revoked = false and tenant_id = (select id from tenants where uuid = $2)")
+            .bind(id.to_string())
+            .bind(tenant_id.to_string())
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Looks up the tenant that owns an unrevoked key
+    pub async fn tenant_for_key(&self, api_key: &ApiKey) -> Result<Option<Uuid>> {
+        query("select tenants.uuid from api_keys as k inner join tenants on k.tenant_id = tenants.id where k.key_hash = $1 and k.revoked = false")
+            .bind(api_key.hash())
+            .map(|row: AnyRow| Uuid::parse_str(row.get(0)).unwrap())
+            .fetch_optional(&self.db_pool)
+            .await
+    }
+}
@@ -0,0 +1,142 @@
+use crate::db::{optional, DbPool};
+use serde::{Deserialize, Serialize};
+use sqlx::any::AnyRow;
+use sqlx::{query, Result, Row};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+pub const DEFAULT_QUERY_LIMIT: u32 = 100;
+pub const MAX_QUERY_LIMIT: u32 = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    // the caller's credentials were rejected
+    Denied,
+    Failure,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Denied => "denied",
+            Outcome::Failure => "failure",
+        }
+    }
+
+    fn parse(outcome: &str) -> Outcome {
+        match outcome {
+            "success" => Outcome::Success,
+            "denied" => Outcome::Denied,
+            _ => Outcome::Failure,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub timestamp: i64,
+    pub event: String,
+    // the uuid of the tenant the event is about
+    pub tenant: Option<String>,
+    pub source: Option<String>,
+    pub outcome: Outcome,
+    pub detail: Option<String>,
+}
+
+impl From<AnyRow> for AuditEvent {
+    fn from(row: AnyRow) -> Self {
+        AuditEvent {
+            id: row.get(0),
+            timestamp: row.get(1),
+            event: row.get(2),
+            tenant: optional(&row, 3),
+            source: optional(&row, 4),
+            outcome: Outcome::parse(row.get(5)),
+            detail: optional(&row, 6),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct AuditQuery {
+    // a tenant's name or uuid, events are stored with the uuid
+    pub tenant: Option<String>,
+    pub event: Option<String>,
+    // only events at or after this unix timestamp
+    pub since: Option<i64>,
+    pub limit: Option<u32>,
+}
+
+// Append only record of authentication events. Every event is written to the audit log stream and
+// stored in the audit_log table, which has triggers rejecting updates and deletes.
+pub struct AuditLog {
+    db_pool: DbPool,
+}
+
+impl AuditLog {
+    pub fn new(db_pool: DbPool) -> AuditLog {
+        AuditLog { db_pool }
+    }
+
+    // A failure to store the event is logged but never fails the request being audited
+    pub async fn record(
+        &self,
+        event: &str,
+        tenant: Option<Uuid>,
+        source: Option<IpAddr>,
+        outcome: Outcome,
+        detail: Option<&str>,
+    ) {
+        let tenant = tenant.map(|tenant| tenant.to_string());
+        let source = source.map(|source| source.to_string());
+        warn!(
+            target: "audit",
+            event = event,
+            tenant = tenant,
+            source = source,
+            outcome = outcome.as_str(),
+            detail = detail,
+        );
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0);
+        // missing values are bound as empty strings and stored as null, see db::DbPool
+        if let Err(err) = query("insert into audit_log (timestamp, event, tenant, source, outcome, detail) values ($1, $2, nullif($3, ''), nullif($4, ''), $5, nullif($6, ''))")
+            .bind(timestamp)
+            .bind(event)
+            .bind(tenant.as_deref().unwrap_or_default())
+            .bind(source.as_deref().unwrap_or_default())
+            .bind(outcome.as_str())
+            .bind(detail.unwrap_or_default())
+            .execute(&self.db_pool)
+            .await
+        {
+            error!(err = err.to_string(), event = event, "failed to store audit event");
+        }
+    }
+
+    // Returns the newest matching events first
+    pub async fn list(&self, filter: &AuditQuery) -> Result<Vec<AuditEvent>> {
+        let limit = filter
+            .limit
+            .unwrap_or(DEFAULT_QUERY_LIMIT)
+            .min(MAX_QUERY_LIMIT);
+        // a missing filter is bound as an empty string, or the earliest time, and matches everything
+        query("select id, timestamp, event, tenant, source, outcome, detail from audit_log where ($1 = '' or tenant = $1) and ($2 = '' or event = $2) and timestamp >= $3 order by id desc limit $4")
+            .bind(filter.tenant.as_deref().unwrap_or_default())
+            .bind(filter.event.as_deref().unwrap_or_default())
+            .bind(filter.since.unwrap_or(i64::MIN))
+            .bind(limit as i64)
+            .map(|row: AnyRow| row.into())
+            .fetch_all(&self.db_pool)
+            .await
+    }
+}
@@ -1,18 +1,78 @@
-use common::auth::{Identity, JwtIssuer, JwtValidator, RsaJwtIssuer, RsaJwtValidator};
+use crate::accounting::Metered;
+use crate::audit::Outcome;
+use crate::oidc::TenantClaim;
+use crate::tls::ClientCertificate;
+use crate::{AppData, KVErrors};
+use actix_web::dev::Payload;
+use actix_web::http::header::Header;
+use actix_web::web::Data;
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use common::auth::{
+    ApiKey, ApiKeyHeader, AuthHeader, Identity, JwtIssuer, JwtValidator, KeyAlgorithm,
+    KeyJwtIssuer, KeyJwtValidator, Scope, DEFAULT_SERVICE_AUDIENCE, DEFAULT_SERVICE_TOKEN_LIFETIME,
+    GATEWAY_SERVICE_NAME,
+};
+use futures::future::LocalBoxFuture;
 use jsonwebtoken::errors::Result;
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::time::Duration;
+use tracing::error;
 use uuid::Uuid;
 
 #[derive(Clone, Debug)]
 pub(crate) struct JwtIssuerVerifier {
-    verifier: RsaJwtValidator,
-    issuer: RsaJwtIssuer,
+    verifier: KeyJwtValidator,
+    issuer: KeyJwtIssuer,
+    // mints the tokens sent to storage nodes, they have their own audience so user tokens aren't valid there
+    service_issuer: KeyJwtIssuer,
 }
 
 impl JwtIssuerVerifier {
-    pub fn new(private_key: &[u8], public_key_path: &[u8]) -> Result<JwtIssuerVerifier> {
-        let issuer = RsaJwtIssuer::new(private_key)?;
-        let verifier = RsaJwtValidator::new(public_key_path)?;
-        Ok(JwtIssuerVerifier { verifier, issuer })
+    pub fn new(
+        algorithm: KeyAlgorithm,
+        private_key: &[u8],
+        public_key_path: &[u8],
+        token_lifetime: Duration,
+        clock_skew: Duration,
+        issuer_name: &str,
+        audience: &str,
+    ) -> Result<JwtIssuerVerifier> {
+        let issuer = KeyJwtIssuer::new(algorithm, private_key)?
+            .with_lifetime(token_lifetime)
+            .with_issuer(issuer_name)
+            .with_audience(audience);
+        let service_issuer = KeyJwtIssuer::new(algorithm, private_key)?
+            .with_lifetime(DEFAULT_SERVICE_TOKEN_LIFETIME)
+            .with_issuer(issuer_name)
+            .with_audience(DEFAULT_SERVICE_AUDIENCE);
+        let verifier = KeyJwtValidator::new(algorithm, public_key_path)?
+            .with_clock_skew(clock_skew)
+            .with_issuer(issuer_name)
+            .with_audience(audience);
+        Ok(JwtIssuerVerifier {
+            verifier,
+            issuer,
+            service_issuer,
+        })
+    }
+
+    // Sets the audience of service tokens, must match the audience the storage nodes expect
+    pub fn with_service_audience(mut self, audience: impl Into<String>) -> JwtIssuerVerifier {
+        self.service_issuer = self.service_issuer.with_audience(audience);
+        self
+    }
+
+    // Mints a short lived token for calling storage nodes on behalf of the tenant behind the identity
+    pub fn new_service_identity(&self, identity: &Identity) -> Result<Identity> {
+        self.service_issuer
+            .new_service_identity(GATEWAY_SERVICE_NAME, identity)
+    }
+
+    // Mints a short lived admin token for storage node admin rpcs that aren't on behalf of a tenant
+    pub fn new_operator_identity(&self) -> Result<Identity> {
+        self.service_issuer
+            .new_operator_identity(GATEWAY_SERVICE_NAME)
     }
 }
 
@@ -23,7 +83,205 @@ impl JwtValidator for JwtIssuerVerifier {
 }
 
 impl JwtIssuer for JwtIssuerVerifier {
-    fn new_identity(&self, tenant_id: Uuid) -> Result<Identity> {
-        self.issuer.new_identity(tenant_id)
+    fn new_scoped_identity(
+        &self,
+        tenant_id: Uuid,
+        scopes: Vec<Scope>,
+        namespaces: Option<Vec<Uuid>>,
+    ) -> Result<Identity> {
+        self.issuer
+            .new_scoped_identity(tenant_id, scopes, namespaces)
+    }
+}
+
+// How the caller proved who they are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Credential {
+    Token,
+    Oidc,
+    ApiKey,
+    ClientCertificate,
+}
+
+impl Credential {
+    // Short lived tokens, either ours or from a federated identity provider
+    pub fn is_bearer(&self) -> bool {
+        matches!(self, Credential::Token | Credential::Oidc)
+    }
+}
+
+// The tenant behind a request. Extracting it verifies the bearer token, api key, or client certificate
+// and checks the tenant is still active, rejecting the request with a 401 or 403 otherwise.
+pub(crate) struct AuthenticatedTenant {
+    identity: Identity,
+    credential: Credential,
+}
+
+impl AuthenticatedTenant {
+    pub fn credential(&self) -> Credential {
+        self.credential
+    }
+}
+
+impl Deref for AuthenticatedTenant {
+    type Target = Identity;
+
+    fn deref(&self) -> &Self::Target {
+        &self.identity
+    }
+}
+
+impl FromRequest for AuthenticatedTenant {
+    type Error = KVErrors;
+    type Future = LocalBoxFuture<'static, std::result::Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let app_data = req.app_data::<Data<AppData>>().cloned();
+        let auth_header = AuthHeader::parse(req).ok();
+        let api_key = ApiKeyHeader::parse(req).ok();
+        let client_cert = req.conn_data::<ClientCertificate>().cloned();
+        let source = req.peer_addr().map(|addr| addr.ip());
+        let req = req.clone();
+
+        Box::pin(async move {
+            let Some(app_data) = app_data else {
+                error!("app data is not configured");
+                return Err(KVErrors::InternalServerError);
+            };
+
+            let found = authenticate(
+                &app_data,
+                auth_header.as_ref().map(AsRef::as_ref),
+                api_key.as_ref().map(ApiKeyHeader::api_key),
+                client_cert,
+            )
+            .await?;
+            let tenant = verify(&app_data, found, source).await?;
+            // the response is counted towards the tenant's usage, see accounting::meter
+            req.extensions_mut().insert(Metered(tenant.tenant_id()));
+            Ok(tenant)
+        })
+    }
+}
+
+impl AuthenticatedTenant {
+    // For callers that aren't on http, e.g. the resp listener, the secret is either a bearer token
+    // or an api key
+    pub async fn from_secret(
+        app_data: &AppData,
+        secret: &str,
+        source: Option<IpAddr>,
+    ) -> std::result::Result<AuthenticatedTenant, KVErrors> {
+        let found = match authenticate(app_data, Some(secret), None, None).await? {
+            Some(found) => Some(found),
+            None => {
+                let api_key = ApiKey::from(secret);
+                authenticate(app_data, None, Some(&api_key), None).await?
+            }
+        };
+        verify(app_data, found, source).await
     }
 }
+
+// Rejects callers whose credentials didn't check out, and tenants that are suspended or deleted
+async fn verify(
+    app_data: &AppData,
+    found: Option<(Identity, Credential)>,
+    source: Option<IpAddr>,
+) -> std::result::Result<AuthenticatedTenant, KVErrors> {
+    let Some((identity, credential)) = found else {
+        error!("failed to verify auth data");
+        app_data
+            .audit
+            .record("authentication_failed", None, source, Outcome::Denied, None)
+            .await;
+        return Err(KVErrors::Unauthorized);
+    };
+
+    let active = app_data
+        .tenants
+        .is_active(identity.tenant_id(), identity.issued_at())
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to check tenant status");
+            KVErrors::InternalServerError
+        })?;
+    if !active {
+        error!("tenant is suspended or deleted, or the token was revoked");
+        app_data
+            .audit
+            .record(
+                "authentication_failed",
+                Some(identity.tenant_id()),
+                source,
+                Outcome::Denied,
+                Some("tenant inactive or token revoked"),
+            )
+            .await;
+        return Err(KVErrors::Forbidden);
+    }
+
+    Ok(AuthenticatedTenant {
+        identity,
+        credential,
+    })
+}
+
+// Resolves the caller from a bearer token, an api key, or a client certificate presented on the https
+// listener. Bearer tokens are either ours or issued by the configured oidc provider. Federated tokens,
+// api keys, and certificates are exchanged for a freshly minted token that only lives for the
+// request, so it isn't audited like a token issued at login. A credential that doesn't check out is
+// audited by verify.
+async fn authenticate(
+    app_data: &AppData,
+    bearer: Option<&str>,
+    api_key: Option<&ApiKey>,
+    client_cert: Option<ClientCertificate>,
+) -> std::result::Result<Option<(Identity, Credential)>, KVErrors> {
+    let (tenant_id, credential) = if let Some(bearer) = bearer {
+        if let Ok(identity) = app_data.jwts.parse(bearer) {
+            return Ok(Some((identity, Credential::Token)));
+        }
+        // not one of ours, it may have been issued by a federated identity provider
+        let Some(oidc) = &app_data.oidc else {
+            return Ok(None);
+        };
+        let tenant_id = match oidc.validate(bearer).await {
+            Some(TenantClaim::Uuid(tenant_id)) => Ok(Some(tenant_id)),
+            Some(TenantClaim::Name(name)) => match app_data.tenants.get(name).await {
+                Ok(tenant) => Ok(Some(tenant.uuid)),
+                Err(sqlx::Error::RowNotFound) => Ok(None),
+                Err(err) => Err(err),
+            },
+            None => return Ok(None),
+        };
+        (tenant_id, Credential::Oidc)
+    } else if let Some(api_key) = api_key {
+        (
+            app_data.api_keys.tenant_for_key(api_key).await,
+            Credential::ApiKey,
+        )
+    } else if let Some(client_cert) = client_cert {
+        (
+            app_data.client_certs.tenant_for_cert(&client_cert).await,
+            Credential::ClientCertificate,
+        )
+    } else {
+        return Ok(None);
+    };
+
+    let tenant_id = match tenant_id {
+        Ok(Some(tenant_id)) => tenant_id,
+        Ok(None) => return Ok(None),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to look up credentials");
+            return Err(KVErrors::InternalServerError);
+        }
+    };
+
+    let identity = app_data.jwts.new_identity(tenant_id).map_err(|err| {
+        error!(err = err.to_string(), "failed to issue token");
+        KVErrors::InternalServerError
+    })?;
+    Ok(Some((identity, credential)))
+}
@@ -0,0 +1,34 @@
+use crate::error::KVErrors;
+use actix_web::dev::ServiceRequest;
+use actix_web::error::JsonPayloadError;
+use actix_web::http::header;
+use actix_web::web::JsonConfig;
+
+// Large enough for a namespace's transforms, the largest body the gateway accepts
+pub const DEFAULT_MAX_REQUEST_BODY: usize = 4 * 1024 * 1024;
+
+// Bodies larger than max_request_body_bytes are turned away with a 413 before they're read. A
+// request that declares its length is rejected before the gateway reads any of the body, one sent
+// chunked is rejected as soon as it grows past the limit. The storage nodes limit values on their
+// own, this keeps the gateway from buffering bodies that can't be stored anyway.
+pub fn exceeds(request: &ServiceRequest, limit: usize) -> bool {
+    request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok())
+        .is_some_and(|length| length > limit as u64)
+}
+
+// Json bodies stop being read once they reach the limit
+pub fn json_config(limit: usize) -> JsonConfig {
+    JsonConfig::default()
+        .limit(limit)
+        .error_handler(|err, _| match err {
+            JsonPayloadError::Overflow { limit }
+            | JsonPayloadError::OverflowKnownLength { limit, .. } => {
+                KVErrors::PayloadTooLarge(limit).into()
+            }
+            err => err.into(),
+        })
+}
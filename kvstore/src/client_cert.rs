@@ -0,0 +1,105 @@
+use crate::db::{optional, DbPool};
+use crate::tls::ClientCertificate;
+use serde::{Deserialize, Serialize};
+use sqlx::any::AnyRow;
+use sqlx::{query, Result, Row};
+use uuid::Uuid;
+
+// A certificate is mapped to a tenant either by its exact fingerprint or by one of its subject
+// alternative names, the latter survives certificate rotation
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CertificateMapping {
+    #[serde(skip_deserializing)]
+    pub id: Uuid,
+    pub fingerprint: Option<String>,
+    pub san: Option<String>,
+}
+
+impl From<AnyRow> for CertificateMapping {
+    fn from(row: AnyRow) -> Self {
+        CertificateMapping {
+            id: Uuid::parse_str(row.get(0)).unwrap(),
+            fingerprint: optional(&row, 1),
+            san: optional(&row, 2),
+        }
+    }
+}
+
+pub struct ClientCertRepo {
+    db_pool: DbPool,
+}
+
+impl ClientCertRepo {
+    pub fn new(db_pool: DbPool) -> ClientCertRepo {
+        ClientCertRepo { db_pool }
+    }
+
+    // Returns None if there is no tenant with the given name
+    pub async fn create(
+        &self,
+        tenant: &str,
+        fingerprint: Option<&str>,
+        san: Option<&str>,
+    ) -> Result<Option<CertificateMapping>> {
+        let id = Uuid::new_v4();
+        let fingerprint = fingerprint.map(|fingerprint| fingerprint.to_ascii_lowercase());
+        // either match may be missing, it's bound as an empty string and stored as null
+        let result = query("insert into client_certificates (uuid, tenant_id, fingerprint, san) select $1, id, nullif($2, ''), nullif($3, '') from tenants where name = $4")
+            .bind(id.to_string())
+            .bind(fingerprint.as_deref().unwrap_or_default())
+            .bind(san.unwrap_or_default())
+            .bind(tenant)
+            .execute(&self.db_pool)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        Ok(Some(CertificateMapping {
+            id,
+            fingerprint,
+            san: san.map(String::from),
+        }))
+    }
+
+    pub async fn list(&self, tenant: &str) -> Result<Vec<CertificateMapping>> {
+        query("select c.uuid, c.fingerprint, c.san from client_certificates as c inner join tenants on c.tenant_id = tenants.id where tenants.name = $1")
+            .bind(tenant)
+            .map(|row: AnyRow| row.into())
+            .fetch_all(&self.db_pool)
+            .await
+    }
+
+    // Returns false if the tenant has no mapping with the given id
+    pub async fn delete(&self, tenant: &str, id: Uuid) -> Result<bool> {
+        let result = query("delete from client_certificates where uuid = $1 and tenant_id = (select id from tenants where name = $2)")
+            .bind(id.to_string())
+            .bind(tenant)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Looks up the tenant a certificate is mapped to, an exact fingerprint match wins over a san match
+    pub async fn tenant_for_cert(&self, cert: &ClientCertificate) -> Result<Option<Uuid>> {
+        let tenant = query("select tenants.uuid from client_certificates as c inner join tenants on c.tenant_id = tenants.id where c.fingerprint = $1")
+            .bind(cert.fingerprint())
+            .map(|row: AnyRow| Uuid::parse_str(row.get(0)).unwrap())
+            .fetch_optional(&self.db_pool)
+            .await?;
+        if tenant.is_some() {
+            return Ok(tenant);
+        }
+
+        for san in cert.sans() {
+            let tenant = query("select tenants.uuid from client_certificates as c inner join tenants on c.tenant_id = tenants.id where c.san = $1")
+                .bind(san)
+                .map(|row: AnyRow| Uuid::parse_str(row.get(0)).unwrap())
+                .fetch_optional(&self.db_pool)
+                .await?;
+            if tenant.is_some() {
+                return Ok(tenant);
+            }
+        }
+        Ok(None)
+    }
+}
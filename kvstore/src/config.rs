@@ -0,0 +1,405 @@
+use crate::consistency::Consistency;
+use crate::db::{self, JournalMode};
+use crate::retry::{self, RetryPolicy, Rpc};
+use crate::throttle::{self, ThrottleLimits};
+use crate::{
+    body_limit, connections, discovery, namespace, oidc, replica, response_cache, tenant, usage,
+};
+use common::auth::password::{self, PasswordParams};
+use common::auth::{self, KeyAlgorithm};
+use common::config::{Config, Error};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+
+pub const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0";
+pub const DEFAULT_HTTP_PORT: u16 = 8080;
+pub const DEFAULT_TLS_PORT: u16 = 8443;
+pub const DEFAULT_HEALTH_PORT: u16 = 8081;
+pub const DEFAULT_ADMIN_PORT: u16 = 8082;
+pub const DEFAULT_DATABASE_URL: &str = "sqlite://data.db";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    // client certificates are only requested when a ca is configured
+    pub client_ca: Option<String>,
+}
+
+// The tenant and namespace a memcached listener's clients use. Memcached's text protocol has no way
+// to authenticate, so every connection to the listener acts with the api key and its keys are the
+// namespace's keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemcachedConfig {
+    pub port: u16,
+    pub api_key: String,
+    pub namespace: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JwtConfig {
+    pub algorithm: KeyAlgorithm,
+    // only used with HS256, the other algorithms read the key pair from the key files
+    pub secret: Option<String>,
+    pub private_key: String,
+    pub public_key: String,
+    pub issuer: String,
+    pub audience: String,
+    pub service_audience: String,
+    pub token_lifetime: Duration,
+    pub clock_skew: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub audience: String,
+    pub tenant_claim: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseConfig {
+    // sqlite:// for a single gateway, postgres:// to share the metadata between gateways
+    pub url: String,
+    pub max_connections: u32,
+    // connections kept open while idle
+    pub min_connections: u32,
+    // sqlite only, applied to every connection as it's opened
+    pub journal_mode: JournalMode,
+    pub busy_timeout: Duration,
+    pub foreign_keys: bool,
+    // sqlite only, where a copy of the database is kept, see replica
+    pub replica_path: Option<String>,
+    pub replica_interval: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageConfig {
+    // comma separated http:// endpoints, dns:// names, and srv:// records
+    pub nodes: String,
+    pub tls_config: Option<String>,
+    // set when the nodes serve admin rpcs on a separate admin_listen_address
+    pub admin_port: Option<u16>,
+    pub discovery_interval: Duration,
+    pub health_interval: Duration,
+    pub breaker_failure_threshold: u32,
+    pub breaker_open_duration: Duration,
+    pub retry_budget_ratio: f64,
+    pub timeouts: HashMap<Rpc, Duration>,
+    pub retry_policies: HashMap<Rpc, RetryPolicy>,
+}
+
+// Everything the gateway can be configured with, see common::config for where settings are read from
+#[derive(Debug, Clone, PartialEq)]
+pub struct GatewayConfig {
+    pub bind_address: String,
+    pub http_port: u16,
+    pub health_port: u16,
+    pub admin_port: u16,
+    // the https listener is only started when a certificate and key are configured
+    pub tls: Option<TlsConfig>,
+    pub tls_port: u16,
+    // the redis protocol listener is only started when a port is configured, see resp
+    pub resp_port: Option<u16>,
+    // the memcached protocol listener is only started when a port is configured, see memcached
+    pub memcached: Option<MemcachedConfig>,
+    pub database: DatabaseConfig,
+    pub admin_token: Option<String>,
+    // the dev tenant is only created with a password, see db::migrate
+    pub dev_tenant_password: Option<String>,
+    pub jwt: JwtConfig,
+    pub password: PasswordParams,
+    pub log_level: LevelFilter,
+    pub login: ThrottleLimits,
+    pub oidc: Option<OidcConfig>,
+    pub storage: StorageConfig,
+    // how long a deleted tenant can be restored before it's purged
+    pub deletion_grace: Duration,
+    // namespace lookups cached per gateway, a capacity of 0 turns caching off
+    pub namespace_cache_capacity: u64,
+    pub namespace_cache_ttl: Duration,
+    // bytes of get responses cached per gateway for namespaces with a cache ttl, 0 turns caching
+    // off, see response_cache
+    pub response_cache_capacity: u64,
+    // how fresh gets are that don't choose, see consistency
+    pub default_consistency: Consistency,
+    // requests with larger bodies are rejected with a 413, see body_limit
+    pub max_request_body: usize,
+    // a tenant using this share of its max_bytes is warned about in the audit log, see usage
+    pub storage_warning_percent: u8,
+}
+
+impl GatewayConfig {
+    pub fn load(config: &Config) -> Result<GatewayConfig, Error> {
+        let gateway = GatewayConfig {
+            bind_address: config.get_or("bind_address", DEFAULT_BIND_ADDRESS.to_string())?,
+            http_port: config.get_or("http_port", DEFAULT_HTTP_PORT)?,
+            health_port: config.get_or("health_port", DEFAULT_HEALTH_PORT)?,
+            admin_port: config.get_or("admin_port", DEFAULT_ADMIN_PORT)?,
+            tls: tls(config)?,
+            tls_port: config.get_or("tls_port", DEFAULT_TLS_PORT)?,
+            resp_port: config.get("resp_port")?,
+            memcached: memcached(config)?,
+            database: database(config)?,
+            admin_token: config.get("admin_token")?,
+            dev_tenant_password: config.get("dev_tenant_password")?,
+            jwt: jwt(config)?,
+            password: PasswordParams {
+                memory_kib: config.get_or("password_memory_kib", password::DEFAULT_MEMORY_KIB)?,
+                iterations: config.get_or("password_iterations", password::DEFAULT_ITERATIONS)?,
+                parallelism: config
+                    .get_or("password_parallelism", password::DEFAULT_PARALLELISM)?,
+            },
+            log_level: config.get_or("log_level", LevelFilter::INFO)?,
+            login: ThrottleLimits {
+                base_delay: config.millis_or("login_backoff_ms", throttle::DEFAULT_BASE_DELAY)?,
+                max_delay: config.secs_or("login_max_backoff_secs", throttle::DEFAULT_MAX_DELAY)?,
+                lockout_threshold: config.get_or(
+                    "login_lockout_threshold",
+                    throttle::DEFAULT_LOCKOUT_THRESHOLD,
+                )?,
+                lockout_duration: config
+                    .secs_or("login_lockout_secs", throttle::DEFAULT_LOCKOUT_DURATION)?,
+            },
+            oidc: oidc(config)?,
+            storage: storage(config)?,
+            deletion_grace: config
+                .secs_or("tenant_deletion_grace_secs", tenant::DEFAULT_DELETION_GRACE)?,
+            namespace_cache_capacity: config.get_or(
+                "namespace_cache_capacity",
+                namespace::DEFAULT_CACHE_CAPACITY,
+            )?,
+            namespace_cache_ttl: config
+                .secs_or("namespace_cache_ttl_secs", namespace::DEFAULT_CACHE_TTL)?,
+            response_cache_capacity: config
+                .get_or("response_cache_mb", response_cache::DEFAULT_CAPACITY_MB)?
+                * 1024
+                * 1024,
+            default_consistency: config.get_or("default_consistency", Consistency::default())?,
+            max_request_body: config.get_or(
+                "max_request_body_bytes",
+                body_limit::DEFAULT_MAX_REQUEST_BODY,
+            )?,
+            storage_warning_percent: config.get_or(
+                "storage_warning_percent",
+                usage::DEFAULT_STORAGE_WARNING_PERCENT,
+            )?,
+        };
+        gateway.validate(config)?;
+        config.check_unknown()?;
+        Ok(gateway)
+    }
+
+    fn validate(&self, config: &Config) -> Result<(), Error> {
+        let mut ports = vec![
+            ("http_port", self.http_port),
+            ("health_port", self.health_port),
+            ("admin_port", self.admin_port),
+        ];
+        if self.tls.is_some() {
+            ports.push(("tls_port", self.tls_port));
+        }
+        if let Some(resp_port) = self.resp_port {
+            ports.push(("resp_port", resp_port));
+        }
+        if let Some(memcached) = &self.memcached {
+            ports.push(("memcached_port", memcached.port));
+        }
+        for (i, (key, port)) in ports.iter().enumerate() {
+            if *port == 0 {
+                return Err(config.invalid(key, "port must not be 0"));
+            }
+            if let Some((other, _)) = ports[..i].iter().find(|(_, other)| other == port) {
+                return Err(config.invalid(key, format!("port is already used by {}", other)));
+            }
+        }
+        if self.database.max_connections == 0 {
+            return Err(config.invalid("database_max_connections", "must be at least 1"));
+        }
+        if self.database.min_connections > self.database.max_connections {
+            return Err(config.invalid(
+                "database_min_connections",
+                "must not be more than database_max_connections",
+            ));
+        }
+        if self.database.replica_path.is_some() {
+            if db::is_postgres(&self.database.url) {
+                return Err(config.invalid(
+                    "sqlite_replica_path",
+                    "postgres is replicated by the database server, not the gateway",
+                ));
+            }
+            if replica::database_path(&self.database.url).is_none() {
+                return Err(config.invalid(
+                    "sqlite_replica_path",
+                    "an in memory database can't be replicated",
+                ));
+            }
+            if self.database.replica_interval.is_zero() {
+                return Err(config.invalid("sqlite_replica_interval_secs", "must not be 0"));
+            }
+        }
+        if self.max_request_body == 0 {
+            return Err(config.invalid("max_request_body_bytes", "must not be 0"));
+        }
+        if !(1..=100).contains(&self.storage_warning_percent) {
+            return Err(config.invalid("storage_warning_percent", "must be between 1 and 100"));
+        }
+        if self.storage.nodes.trim().is_empty() {
+            return Err(config.invalid("storage_nodes", "at least one storage node is required"));
+        }
+        if self.storage.retry_budget_ratio < 0.0 {
+            return Err(config.invalid("retry_budget_ratio", "must not be negative"));
+        }
+        Ok(())
+    }
+}
+
+fn tls(config: &Config) -> Result<Option<TlsConfig>, Error> {
+    let cert: Option<String> = config.get("tls_cert")?;
+    let key: Option<String> = config.get("tls_key")?;
+    let client_ca = config.get("tls_client_ca")?;
+    match (cert, key) {
+        (Some(cert), Some(key)) => Ok(Some(TlsConfig {
+            cert,
+            key,
+            client_ca,
+        })),
+        (Some(_), None) => Err(config.invalid("tls_cert", "tls_key must also be set")),
+        (None, Some(_)) => Err(config.invalid("tls_key", "tls_cert must also be set")),
+        (None, None) => Ok(None),
+    }
+}
+
+fn memcached(config: &Config) -> Result<Option<MemcachedConfig>, Error> {
+    let api_key: Option<String> = config.get("memcached_api_key")?;
+    let namespace: Option<String> = config.get("memcached_namespace")?;
+    let Some(port) = config.get("memcached_port")? else {
+        return match (api_key, namespace) {
+            (None, None) => Ok(None),
+            (Some(_), _) => Err(config.invalid("memcached_api_key", "memcached_port must be set")),
+            (_, Some(_)) => {
+                Err(config.invalid("memcached_namespace", "memcached_port must be set"))
+            }
+        };
+    };
+    let Some(api_key) = api_key else {
+        return Err(config.invalid("memcached_port", "memcached_api_key must also be set"));
+    };
+    let Some(namespace) = namespace else {
+        return Err(config.invalid("memcached_port", "memcached_namespace must also be set"));
+    };
+    Ok(Some(MemcachedConfig {
+        port,
+        api_key,
+        namespace,
+    }))
+}
+
+fn database(config: &Config) -> Result<DatabaseConfig, Error> {
+    Ok(DatabaseConfig {
+        url: config.get_or("database_url", DEFAULT_DATABASE_URL.to_string())?,
+        max_connections: config.get_or("database_max_connections", db::DEFAULT_MAX_CONNECTIONS)?,
+        min_connections: config.get_or("database_min_connections", db::DEFAULT_MIN_CONNECTIONS)?,
+        // wal lets readers carry on while a write is in progress
+        journal_mode: config.get_or("sqlite_journal_mode", JournalMode::Wal)?,
+        // how long a write waits for another connection's write to finish before failing as busy
+        busy_timeout: config.millis_or("sqlite_busy_timeout_ms", db::DEFAULT_BUSY_TIMEOUT)?,
+        foreign_keys: config.get_or("sqlite_foreign_keys", true)?,
+        replica_path: config.get("sqlite_replica_path")?,
+        replica_interval: config
+            .secs_or("sqlite_replica_interval_secs", replica::DEFAULT_INTERVAL)?,
+    })
+}
+
+fn jwt(config: &Config) -> Result<JwtConfig, Error> {
+    let algorithm = config.get_or("jwt_algorithm", KeyAlgorithm::default())?;
+    let secret = match algorithm {
+        KeyAlgorithm::Hs256 => Some(config.require("jwt_secret")?),
+        _ => None,
+    };
+    Ok(JwtConfig {
+        algorithm,
+        secret,
+        private_key: config.get_or("jwt_private_key", "key.pem".to_string())?,
+        public_key: config.get_or("jwt_public_key", "key.pub".to_string())?,
+        issuer: config.get_or("jwt_issuer", auth::DEFAULT_ISSUER.to_string())?,
+        audience: config.get_or("jwt_audience", auth::DEFAULT_AUDIENCE.to_string())?,
+        service_audience: config.get_or(
+            "service_audience",
+            auth::DEFAULT_SERVICE_AUDIENCE.to_string(),
+        )?,
+        token_lifetime: config.secs_or("token_lifetime_secs", auth::DEFAULT_TOKEN_LIFETIME)?,
+        clock_skew: config.secs_or("clock_skew_secs", auth::DEFAULT_CLOCK_SKEW)?,
+    })
+}
+
+// tokens from an external identity provider are accepted when an oidc issuer is configured
+fn oidc(config: &Config) -> Result<Option<OidcConfig>, Error> {
+    let Some(issuer) = config.get("oidc_issuer")? else {
+        return Ok(None);
+    };
+    Ok(Some(OidcConfig {
+        issuer,
+        audience: config.require("oidc_audience")?,
+        tenant_claim: config.get_or("oidc_tenant_claim", oidc::DEFAULT_TENANT_CLAIM.to_string())?,
+    }))
+}
+
+fn storage(config: &Config) -> Result<StorageConfig, Error> {
+    // timeouts are configured per rpc, e.g. timeout_list_keys_ms = 30000
+    let mut timeouts = HashMap::new();
+    for rpc in Rpc::all() {
+        let key = format!("timeout_{}_ms", rpc.env_name().to_lowercase());
+        timeouts.insert(rpc, config.millis_or(&key, rpc.default_timeout())?);
+    }
+
+    // retries are configured per rpc, e.g. retry_get_attempts = 1 disables retrying gets
+    let mut retry_policies = HashMap::new();
+    for rpc in Rpc::idempotent() {
+        let name = rpc.env_name().to_lowercase();
+        let attempts_key = format!("retry_{}_attempts", name);
+        let policy = RetryPolicy {
+            max_attempts: config.get_or(&attempts_key, retry::DEFAULT_MAX_ATTEMPTS)?,
+            base_delay: config.millis_or(
+                &format!("retry_{}_base_delay_ms", name),
+                retry::DEFAULT_BASE_DELAY,
+            )?,
+            max_delay: config.millis_or(
+                &format!("retry_{}_max_delay_ms", name),
+                retry::DEFAULT_MAX_DELAY,
+            )?,
+        };
+        if policy.max_attempts == 0 {
+            return Err(config.invalid(&attempts_key, "must be at least 1"));
+        }
+        retry_policies.insert(rpc, policy);
+    }
+
+    Ok(StorageConfig {
+        nodes: config.get_or(
+            "storage_nodes",
+            discovery::DEFAULT_STORAGE_NODES.to_string(),
+        )?,
+        tls_config: config.get("storage_tls_config")?,
+        admin_port: config.get("storage_admin_port")?,
+        discovery_interval: config.secs_or(
+            "storage_discovery_secs",
+            discovery::DEFAULT_REFRESH_INTERVAL,
+        )?,
+        health_interval: config.secs_or(
+            "storage_health_interval_secs",
+            connections::DEFAULT_MONITOR_INTERVAL,
+        )?,
+        breaker_failure_threshold: config.get_or(
+            "breaker_failure_threshold",
+            connections::DEFAULT_FAILURE_THRESHOLD,
+        )?,
+        breaker_open_duration: config
+            .secs_or("breaker_open_secs", connections::DEFAULT_OPEN_DURATION)?,
+        retry_budget_ratio: config.get_or("retry_budget_ratio", retry::DEFAULT_BUDGET_RATIO)?,
+        timeouts,
+        retry_policies,
+    })
+}
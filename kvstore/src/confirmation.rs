@@ -0,0 +1,79 @@
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+// how long a bulk delete's preview can be confirmed for
+pub const CONFIRMATION_TTL: Duration = Duration::from_secs(5 * 60);
+
+// Confirmations of bulk deletes, handed out by a dry run and required by the delete. A token is
+// "<expiry>.<signature>", the signature is an hmac-sha256 over the expiry, the tenant, the namespace
+// and the prefix, so only a gateway that previewed that delete could have issued it.
+pub struct DeleteConfirmations {
+    key: Vec<u8>,
+    ttl: Duration,
+}
+
+impl DeleteConfirmations {
+    // The key is derived from the token signing key, so every gateway of a deployment accepts the
+    // confirmations the others issue
+    pub fn new(signing_key: &[u8], ttl: Duration) -> Self {
+        let key = Hmac::<Sha256>::new_from_slice(signing_key)
+            .unwrap()
+            .chain_update(b"kvstore bulk delete confirmation")
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        DeleteConfirmations { key, ttl }
+    }
+
+    pub fn issue(&self, tenant_id: Uuid, namespace: &str, prefix: &str) -> String {
+        let expires_at = now() + self.ttl.as_secs();
+        let signature = self
+            .mac(expires_at, tenant_id, namespace, prefix)
+            .finalize()
+            .into_bytes();
+        format!(
+            "{}.{}",
+            expires_at,
+            general_purpose::URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    // Whether the token was issued for this delete and hasn't expired
+    pub fn verify(&self, token: &str, tenant_id: Uuid, namespace: &str, prefix: &str) -> bool {
+        let Some((expires_at, signature)) = token.split_once('.') else {
+            return false;
+        };
+        let Ok(expires_at) = expires_at.parse::<u64>() else {
+            return false;
+        };
+        let Ok(signature) = general_purpose::URL_SAFE_NO_PAD.decode(signature) else {
+            return false;
+        };
+        expires_at >= now()
+            && self
+                .mac(expires_at, tenant_id, namespace, prefix)
+                .verify_slice(&signature)
+                .is_ok()
+    }
+
+    fn mac(&self, expires_at: u64, tenant_id: Uuid, namespace: &str, prefix: &str) -> Hmac<Sha256> {
+        // the namespace is length prefixed so it can't run into the prefix
+        Hmac::<Sha256>::new_from_slice(&self.key)
+            .unwrap()
+            .chain_update(expires_at.to_be_bytes())
+            .chain_update(tenant_id.as_bytes())
+            .chain_update((namespace.len() as u64).to_be_bytes())
+            .chain_update(namespace.as_bytes())
+            .chain_update(prefix.as_bytes())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
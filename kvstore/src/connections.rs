@@ -1,17 +1,881 @@
+use crate::retry::{is_retryable, RetryBudget, RetryPolicy, Rpc};
+use crate::tls::{self, ChannelTls};
+use common::healthcheck::DependencyStatus;
+use common::metrics::register;
+use common::storage::node_admin_client::NodeAdminClient;
 use common::storage::storage_client::StorageClient;
-use tonic::transport::Channel;
+use common::trace_context;
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts};
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tonic::codegen::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Channel, Uri};
+use tonic::{Code, Request, Status};
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::HealthCheckRequest;
+use tracing::{error, info, warn, Span};
 
-#[derive(Debug, Default)]
+const STORAGE_SERVICE_NAME: &str = "storage.Storage";
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+pub const DEFAULT_OPEN_DURATION: Duration = Duration::from_secs(30);
+pub const DEFAULT_MONITOR_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+    Closed,
+    // requests fail fast until the deadline passes
+    Open { until: Instant },
+    // a single trial request is let through, its outcome closes or reopens the circuit
+    HalfOpen { since: Instant },
+}
+
+impl BreakerState {
+    fn name(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open { .. } => "open",
+            BreakerState::HalfOpen { .. } => "half_open",
+        }
+    }
+
+    // Exported as the circuit state gauge
+    fn value(&self) -> i64 {
+        match self {
+            BreakerState::Closed => 0,
+            BreakerState::HalfOpen { .. } => 1,
+            BreakerState::Open { .. } => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: BreakerState,
+    consecutive_failures: u32,
+    requests: u64,
+    failures: u64,
+}
+
+// Per storage node metrics, labeled by endpoint
+#[derive(Debug, Clone)]
+struct ConnectionMetrics {
+    in_flight: IntGaugeVec,
+    latency: HistogramVec,
+    errors: IntCounterVec,
+    circuit_state: IntGaugeVec,
+    circuit_transitions: IntCounterVec,
+    reconnects: IntCounterVec,
+}
+
+impl ConnectionMetrics {
+    fn new() -> ConnectionMetrics {
+        ConnectionMetrics {
+            in_flight: register(
+                IntGaugeVec::new(
+                    Opts::new(
+                        "kvstore_storage_requests_in_flight",
+                        "Storage rpcs waiting for a response",
+                    ),
+                    &["endpoint"],
+                )
+                .unwrap(),
+            ),
+            latency: register(
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "kvstore_storage_request_duration_seconds",
+                        "Storage rpc latency",
+                    ),
+                    &["endpoint"],
+                )
+                .unwrap(),
+            ),
+            errors: register(
+                IntCounterVec::new(
+                    Opts::new(
+                        "kvstore_storage_errors_total",
+                        "Failed storage rpcs by grpc status code",
+                    ),
+                    &["endpoint", "code"],
+                )
+                .unwrap(),
+            ),
+            circuit_state: register(
+                IntGaugeVec::new(
+                    Opts::new(
+                        "kvstore_storage_circuit_state",
+                        "Circuit breaker state, 0 closed, 1 half open, 2 open",
+                    ),
+                    &["endpoint"],
+                )
+                .unwrap(),
+            ),
+            circuit_transitions: register(
+                IntCounterVec::new(
+                    Opts::new(
+                        "kvstore_storage_circuit_transitions_total",
+                        "Circuit breaker state changes by the state entered",
+                    ),
+                    &["endpoint", "state"],
+                )
+                .unwrap(),
+            ),
+            reconnects: register(
+                IntCounterVec::new(
+                    Opts::new(
+                        "kvstore_storage_reconnects_total",
+                        "Storage channels replaced with a fresh channel",
+                    ),
+                    &["endpoint"],
+                )
+                .unwrap(),
+            ),
+        }
+    }
+
+    // Labeled series of a node that went away would otherwise keep reporting their last value
+    fn remove(&self, endpoint: &str) {
+        let _ = self.in_flight.remove_label_values(&[endpoint]);
+        let _ = self.circuit_state.remove_label_values(&[endpoint]);
+    }
+}
+
+// Decrements the in flight gauge when the request finishes or is cancelled
+struct InFlight(IntGauge);
+
+impl InFlight {
+    fn start(gauge: IntGauge) -> InFlight {
+        gauge.inc();
+        InFlight(gauge)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+// Sets the rpc's timeout as the grpc-timeout header so the storage node gives up on the request
+// at the same time the gateway does, and the current span's trace context so the node's spans join
+// the request's trace
+#[derive(Debug, Clone, Copy)]
+pub struct RpcHeaders(Duration);
+
+impl Interceptor for RpcHeaders {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        request.set_timeout(self.0);
+        trace_context::inject(&Span::current(), request.metadata_mut());
+        Ok(request)
+    }
+}
+
+pub type Storage = StorageClient<InterceptedService<Channel, RpcHeaders>>;
+pub type Admin = NodeAdminClient<InterceptedService<Channel, RpcHeaders>>;
+
+#[derive(Debug, Clone)]
+struct Clients {
+    channel: Channel,
+    health: HealthClient<Channel>,
+    // the same channel unless the nodes serve admin rpcs on their own port
+    admin: Channel,
+}
+
+impl Clients {
+    // The channel connects lazily and resolves the endpoint's host name when it does, so a new
+    // channel picks up a node that came back on a different address
+    fn connect(
+        endpoint: &str,
+        tls: Option<&ChannelTls>,
+        admin_port: Option<u16>,
+    ) -> Option<Clients> {
+        let channel = connect_channel(endpoint, endpoint, tls)?;
+        let admin = match admin_port {
+            Some(port) => connect_channel(endpoint, &with_port(endpoint, port)?, tls)?,
+            None => channel.clone(),
+        };
+        Some(Clients {
+            health: HealthClient::new(channel.clone()),
+            channel,
+            admin,
+        })
+    }
+}
+
+// Connects to uri with the tls settings of the storage node's endpoint
+fn connect_channel(endpoint: &str, uri: &str, tls: Option<&ChannelTls>) -> Option<Channel> {
+    let channel = match Channel::from_shared(uri.to_string()) {
+        Ok(channel) => channel,
+        Err(err) => {
+            error!(
+                endpoint = uri,
+                err = err.to_string(),
+                "invalid storage endpoint"
+            );
+            return None;
+        }
+    };
+    let channel = match tls.and_then(|tls| tls.client_config(endpoint)) {
+        Some(tls_config) => match channel.tls_config(tls_config) {
+            Ok(channel) => channel,
+            Err(err) => {
+                error!(
+                    endpoint = uri,
+                    err = err.to_string(),
+                    "invalid storage channel tls config"
+                );
+                return None;
+            }
+        },
+        None => channel,
+    };
+    Some(channel.connect_lazy())
+}
+
+// The endpoint with its port replaced, e.g. for a node's admin listener
+fn with_port(endpoint: &str, port: u16) -> Option<String> {
+    let uri: Uri = match endpoint.parse() {
+        Ok(uri) => uri,
+        Err(err) => {
+            error!(
+                endpoint = endpoint,
+                err = err.to_string(),
+                "invalid storage endpoint"
+            );
+            return None;
+        }
+    };
+    Some(format!(
+        "{}://{}:{}",
+        uri.scheme_str().unwrap_or("http"),
+        uri.host()?,
+        port
+    ))
+}
+
+#[derive(Debug)]
+struct Connection {
+    endpoint: String,
+    clients: RwLock<Clients>,
+    breaker: Mutex<Breaker>,
+    metrics: ConnectionMetrics,
+    tls: Option<Arc<ChannelTls>>,
+    admin_port: Option<u16>,
+}
+
+impl Connection {
+    fn new(
+        endpoint: &str,
+        metrics: ConnectionMetrics,
+        tls: Option<Arc<ChannelTls>>,
+        admin_port: Option<u16>,
+    ) -> Option<Connection> {
+        metrics
+            .circuit_state
+            .with_label_values(&[endpoint])
+            .set(BreakerState::Closed.value());
+        Some(Connection {
+            metrics,
+            endpoint: endpoint.to_string(),
+            clients: RwLock::new(Clients::connect(endpoint, tls.as_deref(), admin_port)?),
+            tls,
+            admin_port,
+            breaker: Mutex::new(Breaker {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                requests: 0,
+                failures: 0,
+            }),
+        })
+    }
+
+    fn clients(&self) -> Option<Clients> {
+        self.clients.read().map(|clients| clients.clone()).ok()
+    }
+
+    // Drops the old channel, which may be stuck on an address the node no longer has
+    fn reconnect(&self) {
+        let Some(clients) = Clients::connect(&self.endpoint, self.tls.as_deref(), self.admin_port)
+        else {
+            return;
+        };
+        if let Ok(mut current) = self.clients.write() {
+            info!(endpoint = self.endpoint, "reconnecting to storage node");
+            self.metrics
+                .reconnects
+                .with_label_values(&[&self.endpoint])
+                .inc();
+            *current = clients;
+        }
+    }
+
+    fn transition(&self, breaker: &mut Breaker, state: BreakerState) {
+        breaker.state = state;
+        self.metrics
+            .circuit_state
+            .with_label_values(&[&self.endpoint])
+            .set(state.value());
+        self.metrics
+            .circuit_transitions
+            .with_label_values(&[&self.endpoint, state.name()])
+            .inc();
+    }
+
+    fn allow(&self, open_duration: Duration) -> bool {
+        let Ok(mut breaker) = self.breaker.lock() else {
+            return true;
+        };
+        let now = Instant::now();
+        match breaker.state {
+            BreakerState::Closed => true,
+            BreakerState::Open { until } if now >= until => {
+                info!(
+                    endpoint = self.endpoint,
+                    "circuit half open, sending trial request"
+                );
+                self.transition(&mut breaker, BreakerState::HalfOpen { since: now });
+                // the trial goes over a fresh channel
+                self.reconnect();
+                true
+            }
+            BreakerState::Open { .. } => false,
+            // the trial request may have been cancelled without reporting back, so allow another one eventually
+            BreakerState::HalfOpen { since } if now >= since + open_duration => {
+                breaker.state = BreakerState::HalfOpen { since: now };
+                true
+            }
+            BreakerState::HalfOpen { .. } => false,
+        }
+    }
+
+    fn record(&self, success: bool, failure_threshold: u32, open_duration: Duration) {
+        let Ok(mut breaker) = self.breaker.lock() else {
+            return;
+        };
+        breaker.requests += 1;
+        if success {
+            if breaker.state != BreakerState::Closed {
+                info!(endpoint = self.endpoint, "circuit closed");
+                self.transition(&mut breaker, BreakerState::Closed);
+            }
+            breaker.consecutive_failures = 0;
+            return;
+        }
+
+        breaker.failures += 1;
+        breaker.consecutive_failures += 1;
+        let trip = match breaker.state {
+            BreakerState::Closed => breaker.consecutive_failures >= failure_threshold,
+            BreakerState::HalfOpen { .. } => true,
+            BreakerState::Open { .. } => false,
+        };
+        if trip {
+            warn!(
+                endpoint = self.endpoint,
+                consecutive_failures = breaker.consecutive_failures,
+                error_rate = breaker.failures as f64 / breaker.requests as f64,
+                "circuit open, storage node keeps failing"
+            );
+            self.transition(
+                &mut breaker,
+                BreakerState::Open {
+                    until: Instant::now() + open_duration,
+                },
+            );
+        }
+    }
+}
+
+// Only errors that say the node itself is in trouble count against its circuit, a missing key or a
+// rejected token says nothing about the node's health
+fn is_node_failure(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::DeadlineExceeded | Code::Unknown | Code::Internal
+    )
+}
+
+// Holds the clients for the storage nodes. Every node has a circuit breaker, once a node fails
+// failure_threshold requests in a row it is skipped for open_duration before a trial request is
+// let through again, and requests for the namespaces on it fail fast. The set of nodes can change
+// at runtime as nodes are discovered or go away.
+#[derive(Debug)]
 pub struct ConnectionManager {
-    connections: Vec<StorageClient<Channel>>,
+    connections: RwLock<Vec<Arc<Connection>>>,
+    // nodes found through discovery and nodes registered through the admin api, requests are
+    // routed to both
+    discovered: RwLock<Vec<String>>,
+    registered: RwLock<Vec<String>>,
+    failure_threshold: u32,
+    open_duration: Duration,
+    // timeouts and retry policies can be changed at runtime
+    retry_policies: RwLock<HashMap<Rpc, RetryPolicy>>,
+    timeouts: RwLock<HashMap<Rpc, Duration>>,
+    retry_budget: RetryBudget,
+    metrics: ConnectionMetrics,
+    tls: Option<Arc<ChannelTls>>,
+    // storage nodes' admin listener port, when they don't serve admin rpcs on the storage port
+    admin_port: Option<u16>,
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        ConnectionManager {
+            connections: RwLock::new(Vec::new()),
+            discovered: RwLock::new(Vec::new()),
+            registered: RwLock::new(Vec::new()),
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            open_duration: DEFAULT_OPEN_DURATION,
+            retry_policies: RwLock::new(
+                Rpc::idempotent()
+                    .into_iter()
+                    .map(|rpc| (rpc, RetryPolicy::default()))
+                    .collect(),
+            ),
+            timeouts: RwLock::new(
+                Rpc::all()
+                    .into_iter()
+                    .map(|rpc| (rpc, rpc.default_timeout()))
+                    .collect(),
+            ),
+            retry_budget: RetryBudget::default(),
+            metrics: ConnectionMetrics::new(),
+            tls: None,
+            admin_port: None,
+        }
+    }
 }
 
 impl ConnectionManager {
-    pub fn get_conn(&self, index: usize) -> Option<&StorageClient<Channel>> {
-        self.connections.get(index)
+    pub fn with_circuit_breaker(
+        mut self,
+        failure_threshold: u32,
+        open_duration: Duration,
+    ) -> ConnectionManager {
+        self.failure_threshold = failure_threshold.max(1);
+        self.open_duration = open_duration;
+        self
+    }
+
+    pub fn with_retry_policy(self, rpc: Rpc, policy: RetryPolicy) -> ConnectionManager {
+        self.set_retry_policy(rpc, policy);
+        self
+    }
+
+    pub fn set_retry_policy(&self, rpc: Rpc, policy: RetryPolicy) {
+        if let Ok(mut policies) = self.retry_policies.write() {
+            policies.insert(rpc, policy);
+        }
+    }
+
+    // A storage rpc that takes longer than its timeout fails with DeadlineExceeded
+    pub fn with_timeout(self, rpc: Rpc, timeout: Duration) -> ConnectionManager {
+        self.set_timeout(rpc, timeout);
+        self
+    }
+
+    pub fn set_timeout(&self, rpc: Rpc, timeout: Duration) {
+        if let Ok(mut timeouts) = self.timeouts.write() {
+            timeouts.insert(rpc, timeout);
+        }
+    }
+
+    // Channels to https endpoints use the given tls settings, must be set before nodes are added
+    pub fn with_tls(mut self, tls: ChannelTls) -> ConnectionManager {
+        self.tls = Some(Arc::new(tls));
+        self
+    }
+
+    // Reloads the channel tls settings when their file or a certificate they name changes, and
+    // reconnects https channels so new connections present the new certificates. Requests in flight
+    // finish on the channels they started on.
+    pub async fn watch_tls(&self) {
+        let Some(channel_tls) = &self.tls else {
+            return;
+        };
+        common::config::watch_files(
+            || channel_tls.files(),
+            tls::WATCH_INTERVAL,
+            || {
+                match channel_tls.reload() {
+                    Ok(()) => {
+                        info!("reloaded storage channel tls config");
+                        for conn in self.connections() {
+                            if conn.endpoint.starts_with("https://") {
+                                conn.reconnect();
+                            }
+                        }
+                    }
+                    Err(err) => error!(
+                        err = err.to_string(),
+                        "failed to reload storage channel tls config, keeping the current one"
+                    ),
+                }
+                async {}
+            },
+        )
+        .await
+    }
+
+    pub fn with_admin_port(mut self, port: u16) -> ConnectionManager {
+        self.admin_port = Some(port);
+        self
+    }
+
+    pub fn with_retry_budget(mut self, budget: RetryBudget) -> ConnectionManager {
+        self.retry_budget = budget;
+        self
     }
 
-    pub fn new_conn(&mut self, client: StorageClient<Channel>) {
-        self.connections.push(client)
+    fn connections(&self) -> Vec<Arc<Connection>> {
+        self.connections
+            .read()
+            .map(|connections| connections.clone())
+            .unwrap_or_default()
+    }
+
+    // The nodes last found through discovery
+    pub fn discovered_endpoints(&self) -> Vec<String> {
+        self.discovered
+            .read()
+            .map(|discovered| discovered.clone())
+            .unwrap_or_default()
+    }
+
+    // Replaces the discovered storage nodes
+    pub fn set_endpoints(&self, endpoints: &[String]) {
+        if let Ok(mut discovered) = self.discovered.write() {
+            *discovered = endpoints.to_vec();
+        }
+        self.route();
+    }
+
+    // Replaces the storage nodes registered through the admin api
+    pub fn set_registered_endpoints(&self, endpoints: &[String]) {
+        if let Ok(mut registered) = self.registered.write() {
+            *registered = endpoints.to_vec();
+        }
+        self.route();
+    }
+
+    // The circuit state of the node, None if requests aren't routed to it
+    pub fn circuit_state(&self, endpoint: &str) -> Option<&'static str> {
+        self.connections()
+            .iter()
+            .find(|conn| conn.endpoint == endpoint)
+            .and_then(|conn| conn.breaker.lock().ok().map(|breaker| breaker.state.name()))
+    }
+
+    // Routes to the discovered and registered nodes, nodes that are still present keep their
+    // channel, circuit state and place in the order, and new nodes are added after them. Namespaces
+    // created before nodes were recorded are on the first node, so it mustn't change with the order
+    // discovery finds nodes in. Channels connect lazily so an unreachable node doesn't hold up the
+    // update.
+    fn route(&self) {
+        let mut endpoints = self.discovered_endpoints();
+        if let Ok(registered) = self.registered.read() {
+            for endpoint in registered.iter() {
+                if !endpoints.contains(endpoint) {
+                    endpoints.push(endpoint.clone());
+                }
+            }
+        }
+        let Ok(mut connections) = self.connections.write() else {
+            return;
+        };
+        let mut updated: Vec<Arc<Connection>> = connections
+            .iter()
+            .filter(|conn| endpoints.contains(&conn.endpoint))
+            .cloned()
+            .collect();
+        for endpoint in &endpoints {
+            if updated.iter().any(|conn| &conn.endpoint == endpoint) {
+                continue;
+            }
+            if let Some(conn) = Connection::new(
+                endpoint,
+                self.metrics.clone(),
+                self.tls.clone(),
+                self.admin_port,
+            ) {
+                info!(endpoint = endpoint, "adding storage node");
+                updated.push(Arc::new(conn));
+            }
+        }
+        for conn in connections.iter() {
+            if !endpoints.contains(&conn.endpoint) {
+                info!(endpoint = conn.endpoint, "removing storage node");
+                self.metrics.remove(&conn.endpoint);
+            }
+        }
+        *connections = updated;
+    }
+
+    // A node for a new namespace, namespaces are spread over the nodes requests are routed to at
+    // random. The namespace records its node, so nodes coming and going later don't move it.
+    pub fn place(&self) -> Option<String> {
+        self.connections()
+            .choose(&mut rand::thread_rng())
+            .map(|conn| conn.endpoint.clone())
+    }
+
+    // The node a namespace's keys are on, the first node for namespaces created before nodes were
+    // recorded
+    fn owner(&self, node: Option<&str>) -> Option<Arc<Connection>> {
+        let connections = self.connections();
+        match node {
+            Some(node) => connections.into_iter().find(|conn| conn.endpoint == node),
+            None => connections.into_iter().next(),
+        }
+    }
+
+    // The endpoint of the node a namespace's keys are on, None when it isn't routed to
+    pub fn endpoint(&self, node: Option<&str>) -> Option<String> {
+        self.owner(node).map(|conn| conn.endpoint.clone())
+    }
+
+    // Sends the request to the storage node the namespace is on, failing fast with Unavailable when
+    // its circuit is open. It's never sent to another node, which doesn't have its keys.
+    pub async fn call<T, F, Fut>(
+        &self,
+        rpc: Rpc,
+        node: Option<&str>,
+        request: F,
+    ) -> Result<T, Status>
+    where
+        F: FnOnce(Storage) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let Some(conn) = self.owner(node) else {
+            return Err(Status::unavailable(
+                "the namespace's storage node isn't routed to",
+            ));
+        };
+        if !conn.allow(self.open_duration) {
+            return Err(Status::unavailable(
+                "the namespace's storage node is failing, its circuit is open",
+            ));
+        }
+        let Some(clients) = conn.clients() else {
+            return Err(Status::unavailable(
+                "storage node connection is unavailable",
+            ));
+        };
+        self.send(rpc, &conn, clients, request).await
+    }
+
+    async fn send<T, F, Fut>(
+        &self,
+        rpc: Rpc,
+        conn: &Connection,
+        clients: Clients,
+        request: F,
+    ) -> Result<T, Status>
+    where
+        F: FnOnce(Storage) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let timeout = self
+            .timeouts
+            .read()
+            .ok()
+            .and_then(|timeouts| timeouts.get(&rpc).copied())
+            .unwrap_or_else(|| rpc.default_timeout());
+        let in_flight =
+            InFlight::start(self.metrics.in_flight.with_label_values(&[&conn.endpoint]));
+        let start = Instant::now();
+        // the client shares the connection's channel, a hung node is given up on after the timeout
+        // rather than holding the caller forever
+        let client = StorageClient::with_interceptor(clients.channel, RpcHeaders(timeout));
+        let result = match tokio::time::timeout(timeout, request(client)).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!(
+                    rpc = ?rpc,
+                    endpoint = conn.endpoint,
+                    timeout_ms = timeout.as_millis() as u64,
+                    "storage request timed out"
+                );
+                Err(Status::deadline_exceeded("storage request timed out"))
+            }
+        };
+        drop(in_flight);
+        self.metrics
+            .latency
+            .with_label_values(&[&conn.endpoint])
+            .observe(start.elapsed().as_secs_f64());
+
+        let success = match &result {
+            Ok(_) => true,
+            Err(status) => {
+                self.metrics
+                    .errors
+                    .with_label_values(&[&conn.endpoint, &format!("{:?}", status.code())])
+                    .inc();
+                !is_node_failure(status)
+            }
+        };
+        conn.record(success, self.failure_threshold, self.open_duration);
+        result
+    }
+
+    // Like call, but for idempotent rpcs that are retried with backoff when the node couldn't be
+    // reached. The request is rebuilt for every attempt since tonic requests can't be cloned.
+    pub async fn call_idempotent<T, F, Fut>(
+        &self,
+        rpc: Rpc,
+        node: Option<&str>,
+        request: F,
+    ) -> Result<T, Status>
+    where
+        F: Fn(Storage) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let policy = self
+            .retry_policies
+            .read()
+            .ok()
+            .and_then(|policies| policies.get(&rpc).copied())
+            .unwrap_or_default();
+        self.retry_budget.deposit();
+
+        let mut attempt = 1;
+        loop {
+            let result = self.call(rpc, node, &request).await;
+            match &result {
+                Err(status) if is_retryable(status) && attempt < policy.max_attempts => {
+                    if !self.retry_budget.withdraw() {
+                        warn!(rpc = ?rpc, "retry budget exhausted, not retrying");
+                        return result;
+                    }
+                    let delay = policy.delay(attempt);
+                    info!(
+                        rpc = ?rpc,
+                        attempt = attempt,
+                        delay_ms = delay.as_millis() as u64,
+                        err = status.message(),
+                        "retrying storage request"
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    // Health checks the storage nodes every interval so a dead node's circuit trips, and a recovered
+    // node's circuit closes, even when no requests are being sent to it
+    pub async fn monitor(&self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            self.check().await;
+        }
+    }
+
+    // Sends the request to every storage node, open circuits included, for changes like a
+    // namespace's quota that every node has to see. Returns each node's result by endpoint.
+    pub async fn call_all<T, F, Fut>(
+        &self,
+        rpc: Rpc,
+        request: F,
+    ) -> Vec<(String, Result<T, Status>)>
+    where
+        F: Fn(Storage) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let mut results = Vec::new();
+        for conn in self.connections() {
+            let result = match conn.clients() {
+                Some(clients) => self.send(rpc, &conn, clients, &request).await,
+                None => Err(Status::unavailable(
+                    "storage node connection is unavailable",
+                )),
+            };
+            results.push((conn.endpoint.clone(), result));
+        }
+        results
+    }
+
+    // Sends an admin rpc to every storage node, open circuits included since an admin change has to
+    // reach every node. Returns each node's result by endpoint.
+    pub async fn call_admin_all<T, F, Fut>(
+        &self,
+        timeout: Duration,
+        request: F,
+    ) -> Vec<(String, Result<T, Status>)>
+    where
+        F: Fn(Admin) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let mut results = Vec::new();
+        for conn in self.connections() {
+            let result = match conn.clients() {
+                Some(clients) => {
+                    let client =
+                        NodeAdminClient::with_interceptor(clients.admin, RpcHeaders(timeout));
+                    match tokio::time::timeout(timeout, request(client)).await {
+                        Ok(result) => result,
+                        Err(_) => Err(Status::deadline_exceeded("storage request timed out")),
+                    }
+                }
+                None => Err(Status::unavailable(
+                    "storage node connection is unavailable",
+                )),
+            };
+            results.push((conn.endpoint.clone(), result));
+        }
+        results
+    }
+
+    // Asks every storage node whether its storage service is serving using the standard grpc health
+    // protocol. The check doubles as the trial request for nodes whose circuit is ready to half open.
+    pub async fn check(&self) -> Vec<DependencyStatus> {
+        let connections = self.connections();
+        if connections.is_empty() {
+            return vec![DependencyStatus::unhealthy(
+                "storage",
+                "no storage nodes discovered",
+            )];
+        }
+        let mut statuses = Vec::with_capacity(connections.len());
+        for conn in connections.iter() {
+            let name = format!("storage {}", conn.endpoint);
+            let Some(clients) = conn
+                .allow(self.open_duration)
+                .then(|| conn.clients())
+                .flatten()
+            else {
+                statuses.push(DependencyStatus::unhealthy(name, "circuit open"));
+                continue;
+            };
+
+            let mut health = clients.health;
+            let request = HealthCheckRequest {
+                service: STORAGE_SERVICE_NAME.to_string(),
+            };
+
+            let status =
+                match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, health.check(request)).await {
+                    Ok(Ok(response)) => match response.into_inner().status() {
+                        ServingStatus::Serving => DependencyStatus::healthy(name),
+                        status => DependencyStatus::unhealthy(name, status.as_str_name()),
+                    },
+                    Ok(Err(err)) => DependencyStatus::unhealthy(name, err.message()),
+                    Err(_) => DependencyStatus::unhealthy(name, "health check timed out"),
+                };
+            // a node failing its health check counts against its circuit like a failed request
+            conn.record(status.healthy, self.failure_threshold, self.open_duration);
+            statuses.push(status);
+        }
+        statuses
     }
 }
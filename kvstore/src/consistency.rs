@@ -0,0 +1,57 @@
+use actix_web::HttpRequest;
+use common::storage;
+use serde::Deserialize;
+use std::str::FromStr;
+
+// a get's consistency is read from this header when it has no consistency query parameter
+pub const CONSISTENCY_HEADER: &str = "x-consistency";
+
+// How fresh a get has to be, chosen per request and the gateway's default_consistency otherwise.
+// Writes go to the first storage node whose circuit allows them, so a strong read is sent where
+// the key's last write went.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Consistency {
+    // sent where writes go, it isn't served from the gateway's response cache
+    Strong,
+    // may be served from the response cache
+    #[default]
+    Eventual,
+}
+
+impl FromStr for Consistency {
+    type Err = String;
+
+    fn from_str(consistency: &str) -> Result<Self, Self::Err> {
+        match consistency.to_ascii_lowercase().as_str() {
+            "strong" => Ok(Consistency::Strong),
+            "eventual" => Ok(Consistency::Eventual),
+            _ => Err("expected strong or eventual".to_string()),
+        }
+    }
+}
+
+impl From<Consistency> for storage::Consistency {
+    fn from(value: Consistency) -> Self {
+        match value {
+            Consistency::Strong => storage::Consistency::Strong,
+            Consistency::Eventual => storage::Consistency::Eventual,
+        }
+    }
+}
+
+// The request's consistency, the query parameter's over the header's. None when the header isn't
+// one the gateway knows.
+pub fn requested(
+    req: &HttpRequest,
+    query: Option<Consistency>,
+    default: Consistency,
+) -> Option<Consistency> {
+    if let Some(consistency) = query {
+        return Some(consistency);
+    }
+    match req.headers().get(CONSISTENCY_HEADER) {
+        Some(header) => header.to_str().ok()?.parse().ok(),
+        None => Some(default),
+    }
+}
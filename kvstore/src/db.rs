@@ -0,0 +1,191 @@
+use crate::config::DatabaseConfig;
+use crate::error::Error;
+use futures::TryStreamExt;
+use sqlx::any::{install_default_drivers, AnyPoolOptions, AnyRow};
+use sqlx::migrate::{MigrateDatabase, Migrator};
+use sqlx::{query, Any, Decode, Executor, Pool, Row, Type, TypeInfo, ValueRef};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{error, info};
+use uuid::Uuid;
+
+// The gateway's metadata lives in sqlite for a single gateway, or in postgres so several gateway
+// replicas can share it, picked by the scheme of database_url, see replica for keeping either when
+// a host is lost. Queries are written to run on both, with $1 style placeholders and no sqlite only
+// syntax. The any driver binds a null as an integer, and postgres keeps the parameter types of a
+// statement's first call, so optional strings are bound as empty strings, and optional numbers as
+// -1, and turned back into nulls with nullif.
+pub type DbPool = Pool<Any>;
+
+// Each database has its own migrations, sqlx records the ones applied in _sqlx_migrations so each
+// runs once per database
+static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+static POSTGRES_MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+// A nullable column. The any driver never reports a value as null, so decoding one as an Option
+// fails, its type is checked instead.
+pub fn optional<'r, T>(row: &'r AnyRow, index: usize) -> Option<T>
+where
+    T: Decode<'r, Any> + Type<Any>,
+{
+    match row.try_get_raw(index).unwrap().type_info().name() {
+        "NULL" => None,
+        _ => Some(row.get(index)),
+    }
+}
+
+pub const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+pub const DEFAULT_MIN_CONNECTIONS: u32 = 0;
+pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Sqlite's journal modes, see https://www.sqlite.org/pragma.html#pragma_journal_mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JournalMode::Delete => "delete",
+            JournalMode::Truncate => "truncate",
+            JournalMode::Persist => "persist",
+            JournalMode::Memory => "memory",
+            JournalMode::Wal => "wal",
+            JournalMode::Off => "off",
+        }
+    }
+}
+
+impl FromStr for JournalMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.to_ascii_lowercase().as_str() {
+            "delete" => Ok(JournalMode::Delete),
+            "truncate" => Ok(JournalMode::Truncate),
+            "persist" => Ok(JournalMode::Persist),
+            "memory" => Ok(JournalMode::Memory),
+            "wal" => Ok(JournalMode::Wal),
+            "off" => Ok(JournalMode::Off),
+            _ => Err("expected delete, truncate, persist, memory, wal, or off".to_string()),
+        }
+    }
+}
+
+// The busy timeout comes first so switching the journal mode waits out other connections
+fn sqlite_pragmas(config: &DatabaseConfig) -> Vec<String> {
+    vec![
+        format!("pragma busy_timeout = {}", config.busy_timeout.as_millis()),
+        format!("pragma journal_mode = {}", config.journal_mode.as_str()),
+        format!(
+            "pragma foreign_keys = {}",
+            if config.foreign_keys { "on" } else { "off" }
+        ),
+    ]
+}
+
+pub fn is_postgres(url: &str) -> bool {
+    url.starts_with("postgres://") || url.starts_with("postgresql://")
+}
+
+// A sqlite database is created when it doesn't exist yet, a postgres one has to be created up front
+pub async fn connect(config: &DatabaseConfig) -> Result<DbPool, Error> {
+    install_default_drivers();
+    let url = config.url.as_str();
+    let database_error = |source| Error::Database {
+        url: url.to_string(),
+        source,
+    };
+    if !is_postgres(url) && !Any::database_exists(url).await.unwrap_or(false) {
+        info!(url = url, "creating database");
+        Any::create_database(url).await.map_err(|err| {
+            error!(err = err.to_string(), "failed to create db");
+            database_error(err)
+        })?;
+    }
+
+    let pragmas = match is_postgres(url) {
+        true => Vec::new(),
+        false => sqlite_pragmas(config),
+    };
+    AnyPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .after_connect(move |conn, _| {
+            let pragmas = pragmas.clone();
+            Box::pin(async move {
+                for pragma in pragmas {
+                    conn.execute(pragma.as_str()).await?;
+                }
+                Ok(())
+            })
+        })
+        .connect(url)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to connect to db");
+            database_error(err)
+        })
+}
+
+// Brings the schema up to date and creates the dev tenant on a new database. No tenant is left
+// without a password, so the dev tenant is only created, or given a password when an older build
+// created it without one, when there's a dev password.
+pub async fn migrate(
+    pool: &DbPool,
+    url: &str,
+    dev_password_hash: Option<&str>,
+) -> Result<(), Error> {
+    let migrator = match is_postgres(url) {
+        true => &POSTGRES_MIGRATOR,
+        false => &SQLITE_MIGRATOR,
+    };
+    migrator.run(pool).await.map_err(|err| {
+        error!(err = err.to_string(), "failed to migrate the database");
+        Error::Migrate(err)
+    })?;
+    match dev_password_hash {
+        Some(password_hash) => seed_dev_tenant(pool, password_hash).await.map_err(|err| {
+            error!(err = err.to_string(), "failed to create the dev tenant");
+            Error::Seed(err)
+        })?,
+        None => info!("no dev_tenant_password is set, not creating the dev tenant"),
+    }
+    info!(
+        version = migrator.iter().map(|migration| migration.version).max(),
+        "database is up to date"
+    );
+    Ok(())
+}
+
+// Creates the dev tenant and namespace on a new database
+async fn seed_dev_tenant(pool: &DbPool, password_hash: &str) -> Result<(), sqlx::Error> {
+    query("update tenants set password_hash = $1 where name = 'dev' and password_hash is null")
+        .bind(password_hash)
+        .execute(pool)
+        .await?;
+    let Some::<i64>(user_id) = query(
+        "insert into tenants (name, uuid, password_hash) values ('dev', $1, $2) on conflict do nothing returning id",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(password_hash)
+    .map(|row: AnyRow| row.get(0))
+    .fetch(pool)
+    .try_next()
+    .await?
+    else {
+        return Ok(());
+    };
+    query("insert into namespaces (name, uuid, tenant_id) values ('dev', $1, $2) on conflict do nothing")
+        .bind(Uuid::new_v4().to_string())
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
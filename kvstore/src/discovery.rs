@@ -0,0 +1,166 @@
+use crate::AppData;
+use actix_web::web::Data;
+use derive_more::{Display, Error};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{error, info};
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::TokioAsyncResolver;
+
+pub const DEFAULT_STORAGE_NODES: &str = "http://[::1]:50051";
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Error, Display, Debug)]
+pub enum Error {
+    #[display(fmt = "invalid storage node source: {}", _0)]
+    InvalidSource(#[error(not(source))] String),
+
+    #[display(fmt = "failed to resolve storage nodes: {}", _0)]
+    Resolve(ResolveError),
+}
+
+impl From<ResolveError> for Error {
+    fn from(err: ResolveError) -> Self {
+        Error::Resolve(err)
+    }
+}
+
+// Where storage nodes are found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    // a single node, e.g. http://storage-0:50051
+    Static(String),
+    // every address a name resolves to, e.g. dns://storage.default.svc.cluster.local:50051 for a
+    // kubernetes headless service
+    Dns { host: String, port: u16 },
+    // every target of an srv record, e.g. srv://_grpc._tcp.storage.example.com
+    Srv(String),
+}
+
+impl FromStr for Source {
+    type Err = Error;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        if let Some(name) = source.strip_prefix("srv://") {
+            return Ok(Source::Srv(name.to_string()));
+        }
+        if let Some(address) = source.strip_prefix("dns://") {
+            let (host, port) = address
+                .rsplit_once(':')
+                .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+                .ok_or_else(|| Error::InvalidSource(source.to_string()))?;
+            return Ok(Source::Dns {
+                host: host.to_string(),
+                port,
+            });
+        }
+        if source.starts_with("http://") || source.starts_with("https://") {
+            return Ok(Source::Static(source.to_string()));
+        }
+        Err(Error::InvalidSource(source.to_string()))
+    }
+}
+
+// Resolves the configured sources into the endpoints of the storage nodes
+pub struct Discovery {
+    sources: Vec<Source>,
+    resolver: TokioAsyncResolver,
+    scheme: &'static str,
+}
+
+impl Discovery {
+    // Takes a comma separated list of sources, e.g. http://storage-0:50051,srv://_grpc._tcp.storage
+    pub fn new(sources: &str) -> Result<Discovery, Error> {
+        let sources = sources
+            .split(',')
+            .map(str::trim)
+            .filter(|source| !source.is_empty())
+            .map(Source::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+        Ok(Discovery {
+            sources,
+            resolver,
+            scheme: "http",
+        })
+    }
+
+    // Nodes found through dns or srv records are connected to over https rather than http
+    pub fn with_tls(mut self) -> Discovery {
+        self.scheme = "https";
+        self
+    }
+
+    // The nodes in the order of their sources, so static nodes keep their configured order. The
+    // records of a dns or srv source come back in any order and are sorted among themselves.
+    pub async fn resolve(&self) -> Result<Vec<String>, Error> {
+        let mut endpoints = Vec::new();
+        for source in self.sources.iter() {
+            let mut found = Vec::new();
+            match source {
+                Source::Static(endpoint) => found.push(endpoint.clone()),
+                Source::Dns { host, port } => {
+                    for ip in self.resolver.lookup_ip(host.as_str()).await?.iter() {
+                        let endpoint = match ip {
+                            IpAddr::V4(ip) => format!("{}://{}:{}", self.scheme, ip, port),
+                            IpAddr::V6(ip) => format!("{}://[{}]:{}", self.scheme, ip, port),
+                        };
+                        found.push(endpoint);
+                    }
+                    found.sort();
+                }
+                Source::Srv(name) => {
+                    for srv in self.resolver.srv_lookup(name.as_str()).await?.iter() {
+                        let target = srv.target().to_utf8();
+                        found.push(format!(
+                            "{}://{}:{}",
+                            self.scheme,
+                            target.trim_end_matches('.'),
+                            srv.port()
+                        ));
+                    }
+                    found.sort();
+                }
+            }
+            for endpoint in found {
+                if !endpoints.contains(&endpoint) {
+                    endpoints.push(endpoint);
+                }
+            }
+        }
+        Ok(endpoints)
+    }
+
+    // Re-resolves the sources every interval and updates the connection manager. A failed lookup
+    // keeps the current nodes rather than dropping them all. Nodes registered through another
+    // gateway's admin api are picked up here too.
+    pub async fn refresh(self, app_data: Data<AppData>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match self.resolve().await {
+                Ok(endpoints) if endpoints.is_empty() => {
+                    error!("storage node discovery found no nodes, keeping the current nodes")
+                }
+                Ok(endpoints) => {
+                    if endpoints != app_data.connection_manager.discovered_endpoints() {
+                        info!(nodes = endpoints.len(), "storage nodes changed");
+                        app_data.connection_manager.set_endpoints(&endpoints);
+                    }
+                }
+                Err(err) => error!(err = err.to_string(), "failed to discover storage nodes"),
+            }
+            match app_data.storage_targets.routed_endpoints().await {
+                Ok(registered) => app_data
+                    .connection_manager
+                    .set_registered_endpoints(&registered),
+                Err(err) => error!(
+                    err = err.to_string(),
+                    "failed to load registered storage targets"
+                ),
+            }
+        }
+    }
+}
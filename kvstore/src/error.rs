@@ -0,0 +1,327 @@
+use crate::schema::SchemaViolation;
+use crate::usage::LimitExceeded;
+use crate::{discovery, oidc};
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use common::auth::password;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use thiserror::Error;
+use tonic_types::StatusExt;
+
+// Errors that stop the gateway from starting, each keeps the error that caused it
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid configuration")]
+    Config(#[from] common::config::Error),
+
+    #[error(transparent)]
+    Common(#[from] common::error::Error),
+
+    #[error("failed to parse the jwt keys")]
+    Keys(#[source] jsonwebtoken::errors::Error),
+
+    #[error("invalid password hashing parameters")]
+    Password(#[source] password::Error),
+
+    #[error("failed to discover the oidc provider")]
+    Oidc(#[from] oidc::Error),
+
+    #[error("invalid storage node configuration")]
+    Discovery(#[from] discovery::Error),
+
+    #[error("failed to open database {url}")]
+    Database {
+        url: String,
+        #[source]
+        source: sqlx::Error,
+    },
+
+    #[error("failed to migrate the database")]
+    Migrate(#[source] sqlx::migrate::MigrateError),
+
+    #[error("failed to create the dev tenant")]
+    Seed(#[source] sqlx::Error),
+
+    #[error("failed to hash the dev tenant's password")]
+    DevPassword(#[source] password::Error),
+
+    #[error("failed to load the registered storage targets")]
+    StorageTargets(#[source] sqlx::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+// Errors returned by request handlers. Callers only see the message, the source is kept for logging.
+// Statuses are boxed so the handlers' results stay small.
+#[derive(Error, Debug)]
+pub enum KVErrors {
+    #[error("unauthorized")]
+    Unauthorized,
+
+    #[error("forbidden")]
+    Forbidden,
+
+    #[error("not found")]
+    NotFound(#[source] Box<tonic::Status>),
+
+    #[error("bad request")]
+    BadRequest(#[source] Box<tonic::Status>),
+
+    #[error("conflict")]
+    Conflict(#[source] Box<tonic::Status>),
+
+    #[error("precondition failed")]
+    PreconditionFailed(#[source] Box<tonic::Status>),
+
+    #[error("checksum mismatch")]
+    ChecksumMismatch(#[source] Box<tonic::Status>),
+
+    #[error("quota exceeded")]
+    QuotaExceeded(#[source] Box<tonic::Status>),
+
+    #[error("{0}")]
+    InvalidKey(String),
+
+    #[error("the request body is larger than the limit of {0} bytes")]
+    PayloadTooLarge(usize),
+
+    #[error("the value does not match the namespace's schema")]
+    SchemaViolation(Vec<SchemaViolation>),
+
+    #[error("the tenant is at its {} limit of {}", .0.limit, .0.max)]
+    TenantLimitExceeded(LimitExceeded),
+
+    #[error("downstream service unavailable")]
+    ServiceUnavailable(#[source] Box<tonic::Status>),
+
+    #[error("downstream service timed out")]
+    GatewayTimeout(#[source] Box<tonic::Status>),
+
+    #[error("internal server error")]
+    InternalServerError,
+
+    #[error("internal server error")]
+    Storage(#[source] Box<tonic::Status>),
+
+    #[error("internal server error")]
+    Database(#[from] sqlx::Error),
+}
+
+// A storage node that can't be reached, or no node being available at all, is reported as 503, one
+// that didn't answer in time as 504, a key it doesn't have as 404, a put made against a version the
+// key is no longer at as 409, and a value whose crc doesn't match as 422
+impl From<tonic::Status> for KVErrors {
+    fn from(status: tonic::Status) -> Self {
+        let status = Box::new(status);
+        match status.code() {
+            tonic::Code::Unavailable => KVErrors::ServiceUnavailable(status),
+            tonic::Code::DeadlineExceeded => KVErrors::GatewayTimeout(status),
+            tonic::Code::NotFound => KVErrors::NotFound(status),
+            tonic::Code::InvalidArgument => KVErrors::BadRequest(status),
+            tonic::Code::FailedPrecondition | tonic::Code::AlreadyExists => {
+                KVErrors::Conflict(status)
+            }
+            tonic::Code::DataLoss => KVErrors::ChecksumMismatch(status),
+            tonic::Code::ResourceExhausted => KVErrors::QuotaExceeded(status),
+            _ => KVErrors::Storage(status),
+        }
+    }
+}
+
+// An RFC 7807 problem, with the google.rpc details of the storage error it came from when the
+// storage node sent any
+#[derive(Serialize, Debug)]
+struct Problem {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: String,
+    status: u16,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domain: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    violations: Vec<Violation>,
+}
+
+#[derive(Serialize, Debug)]
+struct Violation {
+    kind: &'static str,
+    subject: String,
+    description: String,
+}
+
+impl Problem {
+    fn new(status: StatusCode, detail: String) -> Problem {
+        Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or_default().to_string(),
+            status: status.as_u16(),
+            detail,
+            reason: None,
+            domain: None,
+            metadata: HashMap::new(),
+            violations: vec![],
+        }
+    }
+
+    fn with_details(mut self, status: &tonic::Status) -> Problem {
+        let details = status.get_error_details();
+        if let Some(info) = details.error_info() {
+            self.reason = Some(info.reason.clone());
+            self.domain = Some(info.domain.clone());
+            self.metadata = info.metadata.clone();
+        }
+        if let Some(bad_request) = details.bad_request() {
+            self.violations
+                .extend(
+                    bad_request
+                        .field_violations
+                        .iter()
+                        .map(|violation| Violation {
+                            kind: "field",
+                            subject: violation.field.clone(),
+                            description: violation.description.clone(),
+                        }),
+                );
+        }
+        if let Some(precondition) = details.precondition_failure() {
+            self.violations
+                .extend(precondition.violations.iter().map(|violation| Violation {
+                    kind: "precondition",
+                    subject: violation.subject.clone(),
+                    description: violation.description.clone(),
+                }));
+        }
+        if let Some(quota) = details.quota_failure() {
+            self.violations
+                .extend(quota.violations.iter().map(|violation| Violation {
+                    kind: "quota",
+                    subject: violation.subject.clone(),
+                    description: violation.description.clone(),
+                }));
+        }
+        self
+    }
+}
+
+impl KVErrors {
+    // The storage status behind the error, only errors clients can act on carry their details
+    fn client_status(&self) -> Option<&tonic::Status> {
+        match self {
+            KVErrors::NotFound(status)
+            | KVErrors::BadRequest(status)
+            | KVErrors::Conflict(status)
+            | KVErrors::PreconditionFailed(status)
+            | KVErrors::ChecksumMismatch(status)
+            | KVErrors::QuotaExceeded(status) => Some(status),
+            _ => None,
+        }
+    }
+}
+
+impl ResponseError for KVErrors {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            KVErrors::Unauthorized => StatusCode::UNAUTHORIZED,
+            KVErrors::Forbidden => StatusCode::FORBIDDEN,
+            KVErrors::NotFound(_) => StatusCode::NOT_FOUND,
+            KVErrors::BadRequest(_) | KVErrors::InvalidKey(_) => StatusCode::BAD_REQUEST,
+            KVErrors::Conflict(_) => StatusCode::CONFLICT,
+            KVErrors::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            KVErrors::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            KVErrors::ChecksumMismatch(_) | KVErrors::SchemaViolation(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            KVErrors::TenantLimitExceeded(exceeded) if exceeded.is_storage() => {
+                StatusCode::INSUFFICIENT_STORAGE
+            }
+            KVErrors::QuotaExceeded(_) | KVErrors::TenantLimitExceeded(_) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            KVErrors::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            KVErrors::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            KVErrors::InternalServerError | KVErrors::Storage(_) | KVErrors::Database(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let KVErrors::Unauthorized = self {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        let mut problem = Problem::new(self.status_code(), self.to_string());
+        if let Some(status) = self.client_status() {
+            problem = Problem {
+                detail: status.message().to_string(),
+                ..problem
+            }
+            .with_details(status);
+            // e.g. a storage node that stalled a write says when it can be retried
+            let retry_delay = status
+                .get_error_details()
+                .retry_info()
+                .and_then(|retry_info| retry_info.retry_delay);
+            if let Some(retry_delay) = retry_delay {
+                response.insert_header((
+                    header::RETRY_AFTER,
+                    retry_delay.as_secs().max(1).to_string(),
+                ));
+            }
+        }
+        // reported like the storage nodes report a key that breaks the namespace's key policy
+        if let KVErrors::InvalidKey(reason) = self {
+            problem.reason = Some("INVALID_KEY".to_string());
+            problem.violations.push(Violation {
+                kind: "field",
+                subject: "key".to_string(),
+                description: reason.clone(),
+            });
+        }
+        if let KVErrors::PayloadTooLarge(limit) = self {
+            problem
+                .metadata
+                .insert("limit_bytes".to_string(), limit.to_string());
+        }
+        // each violation's subject is the JSON pointer of the part of the value that doesn't match
+        if let KVErrors::SchemaViolation(violations) = self {
+            problem.reason = Some("SCHEMA_VIOLATION".to_string());
+            problem
+                .violations
+                .extend(violations.iter().map(|violation| Violation {
+                    kind: "schema",
+                    subject: violation.pointer.clone(),
+                    description: violation.message.clone(),
+                }));
+        }
+        // reported like a namespace quota the storage nodes enforce, the subject is the limit. A
+        // tenant out of storage is a 507 with the reason a storage node's resource exhausted
+        // status would have
+        if let KVErrors::TenantLimitExceeded(exceeded) = self {
+            problem.reason = Some(
+                match exceeded.is_storage() {
+                    true => "RESOURCE_EXHAUSTED",
+                    false => "TENANT_LIMIT_EXCEEDED",
+                }
+                .to_string(),
+            );
+            problem.violations.push(Violation {
+                kind: "quota",
+                subject: exceeded.limit.to_string(),
+                description: self.to_string(),
+            });
+        }
+        response
+            .content_type("application/problem+json")
+            .json(problem)
+    }
+}
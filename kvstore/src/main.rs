@@ -1,166 +1,543 @@
+use crate::accounting::Accounting;
+use crate::admin::AdminToken;
+use crate::auth::AuthenticatedTenant;
+use crate::config::GatewayConfig;
+use crate::confirmation::{DeleteConfirmations, CONFIRMATION_TTL};
 use crate::connections::ConnectionManager;
+use crate::consistency::Consistency;
+use crate::db::DbPool;
+use crate::discovery::Discovery;
+use actix_web::dev::Service;
+use actix_web::http::header;
+use base64::{engine::general_purpose, Engine as _};
 use actix_web::http::StatusCode;
 use actix_web::web::Data;
 use actix_web::{
-    body::BoxBody, error, get, http::header::ContentType, middleware, post, put, web, App,
+    body::BoxBody, delete, get, http::header::ContentType, middleware, post, put, web, App,
     HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder,
 };
-use common::auth::{JwtIssuer, JwtValidator};
-use common::storage::{storage_client::StorageClient, GetRequest, KeyMetadata, PutRequest};
+use api_key::{ApiKeyInfo, ApiKeyRepo};
+use audit::{AuditLog, Outcome};
+use client_cert::ClientCertRepo;
+use common::auth::{
+    ApiKey, AuthHeader, Identity, JwtIssuer, Scope, SOURCE_AUTHORIZATION_METADATA,
+};
+use common::auth::password::{Passwords, Verification};
+use common::healthcheck::{DependencyStatus, HealthChecks};
+use common::key_policy::KeyPolicy;
+use common::version::BuildInfo;
+use common::metrics::RequestMetrics;
+use common::storage::{
+    CloneNamespaceRequest, CopyKeyRequest, CountKeysRequest, CreateNamespaceRequest,
+    DeleteKeyRequest, DeleteNamespaceRequest, DeleteRangeRequest, GetResponse, KeyMetadata,
+    ListVersionsRequest, NamespaceStatsRequest, SampleKeysRequest, ScanRecord, ScanRequest,
+    SetNamespaceKeyPolicyRequest, SetNamespaceQuotaRequest, SetNamespaceRetentionRequest,
+    SetNamespaceTransformsRequest, TouchRequest, TransactWriteOp, TransactWriteRequest,
+};
 use const_format::formatcp;
+use error::{Error, KVErrors};
 use crc32fast::Hasher;
-use derive_more::{Display, Error};
-use futures::{try_join, TryStreamExt};
+use futures::{try_join, StreamExt};
 use git_version::git_version;
-use namespace::{Namespace, NamespaceRepo};
+use namespace::{Namespace, NamespaceRepo, NamespaceSettings, Quota, Retention};
+use oidc::OidcValidator;
+use response_cache::{CachedGet, ResponseCache};
+use retry::{RetryBudget, Rpc};
+use schema::Schemas;
+use storage_target::StorageTargetRepo;
 use serde::{Deserialize, Serialize};
-use sqlx::sqlite::{Sqlite, SqlitePoolOptions, SqliteRow};
-use sqlx::{migrate::MigrateDatabase, query, Pool, Row};
-use std::io::{Error, ErrorKind};
-use tenant::TenantRepo;
-use tonic::transport::Channel;
+use sqlx::query;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tenant::{TenantRepo, TenantStatus};
+use throttle::LoginThrottle;
+use usage::UsageRepo;
+use tonic::metadata::MetadataMap;
 use tonic::Extensions;
-use tracing::{error, info, Instrument, Level, span};
+use tracing::{error, info, span, warn, Instrument, Level};
 use tracing_actix_web::TracingLogger;
 use tracing_attributes::instrument;
-use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::fmt::FormatFields;
 use uuid::Uuid;
+use webhook::{Event, EventKind, Webhooks};
 
+mod accounting;
+mod adapter;
+mod admin;
+mod api_key;
+mod audit;
 mod auth;
+mod body_limit;
+mod client_cert;
+mod config;
+mod confirmation;
 mod connections;
+mod consistency;
+mod db;
+mod discovery;
+mod error;
+mod memcached;
 mod namespace;
+mod oidc;
+mod purge;
+mod reload;
+mod replica;
+mod resp;
+mod response_cache;
+mod retry;
+mod schema;
+mod storage_target;
 mod tenant;
+mod throttle;
+mod tls;
+mod usage;
+mod webhook;
 
 const GIT_VERSION: &str = git_version!();
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const USER_AGENT: &str = formatcp!("kvstore/{} - {}", VERSION, GIT_VERSION);
 
+// page size of key listings when the caller doesn't ask for one, and the most storage returns
+const DEFAULT_LIST_LIMIT: u32 = 50;
+const MAX_LIST_LIMIT: u32 = 1000;
+
+const DATABASE_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+// storage nodes are probed one after another, each with its own 2 second timeout
+const STORAGE_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[actix_web::main]
 async fn main() -> Result<(), Error> {
-    if cfg!(debug_assertions) {
-        tracing_subscriber::fmt()
-            .with_max_level(Level::INFO)
-            .with_span_events(FmtSpan::CLOSE)
-            .with_target(true)
-            .with_thread_names(true)
-            .with_file(true)
-            .init();
-    } else {
-        tracing_subscriber::fmt()
-            .json()
-            .with_max_level(Level::INFO)
-            .with_span_events(FmtSpan::CLOSE)
-            .with_target(true)
-            .with_thread_names(true)
-            .with_file(true)
-            .init();
-    }
-
-    let private_key = common::read_file_bytes("key.pem")?;
-    let public_key = common::read_file_bytes("key.pub")?;
-    let jwts = auth::JwtIssuerVerifier::new(private_key.as_slice(), public_key.as_slice())
-        .map_err(|err| {
-            error! {err = err.to_string(), "failed to parse key"};
-            ErrorKind::InvalidData
-        })?;
-
-    let pool = create_db_pool("sqlite://data.db").await?;
+    let log_level = common::logging::init("kvstore");
+    common::panics::install_hook("kvstore");
+
+    // settings come from defaults, the file given with --config or KVSTORE_CONFIG, KVSTORE_*
+    // environment variables, and --flags, in that order
+    let config = common::config::Config::load("KVSTORE")
+        .and_then(|config| GatewayConfig::load(&config))
+        .inspect_err(|err| error!(err = err.to_string(), "invalid configuration"))?;
+    if let Err(err) = log_level.set(config.log_level) {
+        error!(err = err.to_string(), "failed to set the log level");
+    }
 
-    info!("creating sqlite tables");
-    create_tables(&pool).await.unwrap();
-    info!("ran create tables");
+    let (private_key, public_key) = match &config.jwt.secret {
+        Some(secret) => (secret.clone().into_bytes(), secret.clone().into_bytes()),
+        None => (
+            common::read_file_bytes(&config.jwt.private_key)?,
+            common::read_file_bytes(&config.jwt.public_key)?,
+        ),
+    };
+    let confirmations = DeleteConfirmations::new(&private_key, CONFIRMATION_TTL);
+    let jwts = auth::JwtIssuerVerifier::new(
+        config.jwt.algorithm,
+        private_key.as_slice(),
+        public_key.as_slice(),
+        config.jwt.token_lifetime,
+        config.jwt.clock_skew,
+        &config.jwt.issuer,
+        &config.jwt.audience,
+    )
+    .map_err(|err| {
+        error! {err = err.to_string(), "failed to parse key"};
+        Error::Keys(err)
+    })?
+    .with_service_audience(config.jwt.service_audience.clone());
+
+    let passwords = Passwords::new(config.password).map_err(|err| {
+        error!(err = err.to_string(), "invalid password hashing parameters");
+        Error::Password(err)
+    })?;
+
+    let login_throttle = LoginThrottle::default().with_limits(config.login);
+
+    // tokens from an external identity provider are accepted when an oidc issuer is configured
+    let oidc = match &config.oidc {
+        Some(oidc) => {
+            let validator = OidcValidator::discover(
+                oidc.issuer.clone(),
+                oidc.audience.clone(),
+                oidc.tenant_claim.clone(),
+            )
+            .await
+            .inspect_err(|err| error!(err = err.to_string(), "failed to discover oidc provider"))?;
+            Some(validator)
+        }
+        None => None,
+    };
 
-    let channel = Channel::from_static("http://[::1]:50051").connect_lazy();
+    // a gateway replacing a lost host starts from the replica of the sqlite database
+    let replica_path = config.database.replica_path.as_ref().map(PathBuf::from);
+    if let (Some(replica), Some(database)) =
+        (&replica_path, replica::database_path(&config.database.url))
+    {
+        let restored = replica::restore(&database, replica).inspect_err(|err| {
+            error!(
+                err = err.to_string(),
+                "failed to restore the database from its replica"
+            )
+        })?;
+        if restored {
+            info!(
+                replica = replica.to_str(),
+                "restored the database from its replica"
+            );
+        }
+    }
+    let pool = db::connect(&config.database).await?;
+    info!("migrating the database");
+    let dev_password_hash = match &config.dev_tenant_password {
+        Some(password) => Some(passwords.hash(password).map_err(Error::DevPassword)?),
+        None => None,
+    };
+    db::migrate(&pool, &config.database.url, dev_password_hash.as_deref()).await?;
+    if let Some(replica) = replica_path {
+        actix_web::rt::spawn(replica::replicate(
+            pool.clone(),
+            replica,
+            config.database.replica_interval,
+        ));
+    }
 
-    let client = StorageClient::new(channel);
+    // storage channels to https endpoints are configured with a json file, see tls::ChannelTls
+    let channel_tls = match &config.storage.tls_config {
+        Some(path) => Some(tls::ChannelTls::load(path)?),
+        None => None,
+    };
 
-    let mut connection_manager = connections::ConnectionManager::default();
-    connection_manager.new_conn(client);
+    // storage nodes are a comma separated list of http:// endpoints, dns:// names, and srv:// records
+    let discovery = Discovery::new(&config.storage.nodes)
+        .inspect_err(|err| error!(err = err.to_string(), "invalid storage node configuration"))?;
+    let discovery = match channel_tls {
+        Some(_) => discovery.with_tls(),
+        None => discovery,
+    };
+    let storage_endpoints = discovery.resolve().await.unwrap_or_else(|err| {
+        error!(err = err.to_string(), "failed to discover storage nodes");
+        Vec::new()
+    });
 
+    let mut connection_manager = connections::ConnectionManager::default()
+        .with_circuit_breaker(
+            config.storage.breaker_failure_threshold,
+            config.storage.breaker_open_duration,
+        )
+        .with_retry_budget(RetryBudget::new(config.storage.retry_budget_ratio));
+    for (rpc, timeout) in config.storage.timeouts.iter() {
+        connection_manager = connection_manager.with_timeout(*rpc, *timeout);
+    }
+    for (rpc, policy) in config.storage.retry_policies.iter() {
+        connection_manager = connection_manager.with_retry_policy(*rpc, *policy);
+    }
+    if let Some(channel_tls) = channel_tls {
+        connection_manager = connection_manager.with_tls(channel_tls);
+    }
+    if let Some(port) = config.storage.admin_port {
+        connection_manager = connection_manager.with_admin_port(port);
+    }
+    connection_manager.set_endpoints(&storage_endpoints);
+
+    // nodes registered through the admin api are routed to alongside the configured ones
+    let storage_targets = StorageTargetRepo::new(pool.clone());
+    let registered = storage_targets.routed_endpoints().await.map_err(|err| {
+        error!(
+            err = err.to_string(),
+            "failed to load registered storage targets"
+        );
+        Error::StorageTargets(err)
+    })?;
+    connection_manager.set_registered_endpoints(&registered);
+
+    let (webhooks, webhook_events) = Webhooks::new(pool.clone());
     let app_data = web::Data::new(AppData {
-        namespaces: NamespaceRepo::new(pool.clone()),
+        accounting: Accounting::new(pool.clone()),
+        api_keys: ApiKeyRepo::new(pool.clone()),
+        audit: AuditLog::new(pool.clone()),
+        client_certs: ClientCertRepo::new(pool.clone()),
+        confirmations,
+        namespaces: NamespaceRepo::new(pool.clone())
+            .with_cache(config.namespace_cache_capacity, config.namespace_cache_ttl),
         jwts,
+        login_throttle,
+        oidc,
+        max_request_body: config.max_request_body,
+        default_consistency: config.default_consistency,
+        passwords,
+        response_cache: ResponseCache::new(config.response_cache_capacity),
+        schemas: Schemas::new(
+            pool.clone(),
+            config.namespace_cache_capacity,
+            config.namespace_cache_ttl,
+        ),
         connection_manager,
-        tenants: TenantRepo::new(pool.clone()),
+        storage_targets,
+        tenants: TenantRepo::new(pool.clone()).with_deletion_grace(config.deletion_grace),
+        usage: UsageRepo::new(pool.clone()),
+        webhooks,
     });
 
-    let healthcheck = common::healthcheck::healthcheck_endpoint(8081, || Ok("healthy".to_string()));
+    actix_web::rt::spawn(discovery.refresh(app_data.clone(), config.storage.discovery_interval));
+    actix_web::rt::spawn(purge::purge_deleted(app_data.clone()));
+    actix_web::rt::spawn(usage::refresh(
+        app_data.clone(),
+        usage::REFRESH_INTERVAL,
+        config.storage_warning_percent,
+    ));
+    actix_web::rt::spawn(webhook::deliver(app_data.clone(), webhook_events));
+    actix_web::rt::spawn(accounting::run(app_data.clone()));
+
+    let monitored = app_data.clone();
+    let monitor_interval = config.storage.health_interval;
+    actix_web::rt::spawn(
+        async move { monitored.connection_manager.monitor(monitor_interval).await },
+    );
+
+    let watched = app_data.clone();
+    actix_web::rt::spawn(async move { watched.connection_manager.watch_tls().await });
+
+    let health_pool = pool.clone();
+    let health_data = app_data.clone();
+    let health_checks = HealthChecks::default()
+        .with_readiness("database", move || check_database(health_pool.clone()))
+        .with_timeout("database", DATABASE_CHECK_TIMEOUT)
+        .with_readiness("storage", move || {
+            let app_data = health_data.clone();
+            async move { app_data.connection_manager.check().await }
+        })
+        .with_timeout("storage", STORAGE_CHECK_TIMEOUT);
+    let healthcheck = common::healthcheck::healthcheck_endpoint(
+        &config.bind_address,
+        config.health_port,
+        health_checks,
+    );
+
+    let admin = admin::admin_endpoint(
+        &config.bind_address,
+        config.admin_port,
+        app_data.clone(),
+        config.admin_token.clone().map(AdminToken::new),
+        log_level.clone(),
+    );
+
+    // the redis and memcached protocol listeners are only started when their ports are configured
+    if let Some(resp_port) = config.resp_port {
+        resp::resp_endpoint(&config.bind_address, resp_port, app_data.clone()).await?;
+    }
+    if let Some(memcached) = config.memcached.clone() {
+        memcached::memcached_endpoint(&config.bind_address, memcached, app_data.clone()).await?;
+    }
+
+    // the https listener is only started when a certificate and key are configured
+    let (server_cert, tls_config) = match &config.tls {
+        Some(tls) => {
+            let server_cert = Arc::new(tls::ServerCert::load(&tls.cert, &tls.key)?);
+            let tls_config = tls::server_config(server_cert.clone(), tls.client_ca.clone())?;
+            // renewed certificates are picked up as soon as the files change
+            actix_web::rt::spawn(server_cert.clone().watch(tls.cert.clone(), tls.key.clone()));
+            (Some(server_cert), Some(tls_config))
+        }
+        None => (None, None),
+    };
+
+    // log level, login throttling, storage timeouts and retries, and the https certificate are
+    // reapplied on SIGHUP, see reload::Reloader
+    actix_web::rt::spawn(
+        reload::Reloader::new(app_data.clone(), config.clone(), log_level, server_cert).watch(),
+    );
 
+    // what was counted since the last flush is written out once the server stops
+    let accounted = app_data.clone();
+    let http_metrics = RequestMetrics::new("kvstore_http", "http requests");
+    let build_info = Data::new(common::build_info!("kvstore"));
+    let max_request_body = config.max_request_body;
     let server = HttpServer::new(move || {
+        let http_metrics = http_metrics.clone();
         App::new()
             .app_data(app_data.clone())
+            .app_data(build_info.clone())
+            .app_data(body_limit::json_config(max_request_body))
+            .wrap_fn(move |req, srv| {
+                let response =
+                    (!body_limit::exceeds(&req, max_request_body)).then(|| srv.call(req));
+                async move {
+                    match response {
+                        Some(response) => response.await,
+                        None => Err(KVErrors::PayloadTooLarge(max_request_body).into()),
+                    }
+                }
+            })
+            .wrap_fn(|req, srv| {
+                let response = srv.call(req);
+                async move { Ok(accounting::meter(response.await?)) }
+            })
+            .wrap_fn(move |req, srv| {
+                // labeled by route rather than path so keys don't end up in label values
+                let route = req
+                    .match_pattern()
+                    .unwrap_or_else(|| "unmatched".to_string());
+                let timer = http_metrics.start(format!("{} {}", req.method(), route));
+                let response = srv.call(req);
+                async move {
+                    let response = response.await;
+                    let status = match &response {
+                        Ok(response) => response.status(),
+                        Err(err) => err.as_response_error().status_code(),
+                    };
+                    let code = status.as_u16().to_string();
+                    timer.finish(status.is_server_error().then_some(code.as_str()));
+                    response
+                }
+            })
             .wrap(TracingLogger::default())
             .wrap(middleware::DefaultHeaders::new().add(("User-Agent", USER_AGENT)))
             .service(put)
+            .service(copy_key)
+            .service(transact)
             .service(gen_token)
             .service(list_namespaces)
+            .service(create_namespace)
+            .service(clone_namespace)
+            .service(delete_namespace)
             .service(get)
+            .service(delete_key)
+            .service(touch)
             .service(list_keys)
+            .service(scan)
+            .service(sample_keys)
+            .service(delete_keys)
+            .service(namespace_stats)
+            .service(count_keys)
+            .service(get_namespace_quota)
+            .service(set_namespace_quota)
+            .service(set_namespace_transforms)
+            .service(get_namespace_key_policy)
+            .service(set_namespace_key_policy)
+            .service(get_namespace_retention)
+            .service(set_namespace_retention)
+            .service(list_versions)
+            .service(list_webhooks)
+            .service(create_webhook)
+            .service(delete_webhook)
+            .service(webhook_dead_letters)
+            .service(get_namespace_settings)
+            .service(set_namespace_settings)
+            .service(get_namespace_schema)
+            .service(set_namespace_schema)
+            .service(delete_namespace_schema)
+            .service(create_api_key)
+            .service(list_api_keys)
+            .service(revoke_api_key)
+            .service(version)
     })
-    .bind(("0.0.0.0", 8080))
-    .unwrap()
-    .run();
-
-    try_join!(healthcheck, server).map(|(_, _)| ())
-}
+    .on_connect(tls::on_connect)
+    .bind((config.bind_address.as_str(), config.http_port))?;
 
-async fn create_db_pool(path: &str) -> Result<Pool<Sqlite>, ErrorKind> {
-    if !Sqlite::database_exists(path).await.unwrap_or(false) {
-        info!(path = path, "creating database");
-        match Sqlite::create_database(path).await {
-            Ok(_) => info!("created db successfully"),
-            Err(err) => {
-                error!(err = err.to_string(), "failed to create db");
-                return Err(ErrorKind::NotFound);
-            }
+    let server = match tls_config {
+        Some(tls_config) => {
+            server.bind_rustls_021((config.bind_address.as_str(), config.tls_port), tls_config)?
         }
+        None => server,
     }
+    .run();
 
-    let pool = SqlitePoolOptions::new()
-        .connect(path)
-        .await
-        .map_err(|err| {
-            error! {err = err.to_string(), "failed to connect to db"};
-            ErrorKind::NotFound
-        })?;
-    Ok(pool)
-}
-
-async fn create_tables(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
-    query("create table if not exists namespaces (id integer primary key autoincrement, uuid varchar(36), name varchar(255), tenant_id integer, unique(tenant_id, name), foreign key(tenant_id) references tenants(id))").execute(pool).await?;
-    query("create table if not exists storage_targets (id integer primary key autoincrement, namespace_id integer, endpoint varchar(255))").execute(pool).await?;
-    query("create table if not exists tenants(id integer primary key autoincrement, uuid varchar(36), name varchar(255), password_hash varchar(255), unique(name), unique(uuid))").execute(pool).await?;
-    let Some::<u32>(user_id) =
-        query("insert or ignore into tenants (name, uuid) values ('dev', ?) returning id")
-            .bind(Uuid::new_v4().to_string())
-            .map(|row: SqliteRow| row.get(0))
-            .fetch(pool)
-            .try_next()
-            .await?
-    else {
-        return Ok(());
-    };
-    query("insert or ignore into namespaces (name, uuid, tenant_id) values('dev', ?, ?)")
-        .bind(Uuid::new_v4().to_string())
-        .bind(user_id)
-        .execute(pool)
-        .await?;
-    Ok(())
+    let result = try_join!(healthcheck, admin, server).map(|(_, _, _)| ());
+    accounted.accounting.flush().await;
+    common::logging::shutdown().await;
+    Ok(result?)
 }
 
 struct AppData {
+    accounting: Accounting,
+    api_keys: ApiKeyRepo,
+    audit: AuditLog,
+    client_certs: ClientCertRepo,
+    // bulk deletes have to present the confirmation their dry run issued
+    confirmations: DeleteConfirmations,
     connection_manager: ConnectionManager,
     jwts: auth::JwtIssuerVerifier,
+    login_throttle: LoginThrottle,
     namespaces: NamespaceRepo,
+    oidc: Option<OidcValidator>,
+    // see body_limit, bodies read without an extractor are checked against it as they're read
+    max_request_body: usize,
+    // gets that don't choose their consistency have this one
+    default_consistency: Consistency,
+    passwords: Passwords,
+    // gets of namespaces with a cache ttl, writes through this gateway invalidate them
+    response_cache: ResponseCache,
+    schemas: Schemas,
+    storage_targets: StorageTargetRepo,
     tenants: TenantRepo,
+    usage: UsageRepo,
+    webhooks: Webhooks,
+}
+
+// Storage nodes only accept service tokens, the tenant's own token is never forwarded to them
+fn service_metadata(app_data: &AppData, identity: &Identity) -> Result<MetadataMap, KVErrors> {
+    app_data
+        .jwts
+        .new_service_identity(identity)
+        .map(|service| AuthHeader::from(service.token()).into())
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to issue service token");
+            KVErrors::InternalServerError
+        })
+}
+
+// What's deployed, so operators can tell which build and protocol version a gateway is running
+#[get("/version")]
+async fn version(build_info: Data<BuildInfo>) -> impl Responder {
+    HttpResponse::Ok().json(build_info.as_ref())
+}
+
+// The metadata database answers queries
+async fn check_database(pool: DbPool) -> Vec<DependencyStatus> {
+    vec![match query("select 1").execute(&pool).await {
+        Ok(_) => DependencyStatus::healthy("database"),
+        Err(err) => DependencyStatus::unhealthy("database", err.to_string()),
+    }]
 }
 
 #[derive(Deserialize, Debug)]
 struct PutValue {
     value: String,
     crc: Option<u32>,
+    // "base64" for binary values, otherwise the value is stored as given
+    encoding: Option<String>,
+    // only put if the key is at this version, 0 for a key that must not exist yet
+    expected_version: Option<u32>,
+    // the key expires this long after it's written, the namespace's default_ttl_secs when unset
+    ttl_secs: Option<u64>,
+    // only put if the key doesn't exist, a 409 with the existing key's metadata otherwise. It can't
+    // be combined with expected_version.
+    #[serde(default)]
+    if_absent: bool,
+}
+
+// One write of a transaction, a put unless delete is set
+#[derive(Deserialize, Debug)]
+struct TransactOp {
+    key: String,
+    #[serde(default)]
+    value: String,
+    crc: Option<u32>,
+    encoding: Option<String>,
+    expected_version: Option<u32>,
+    ttl_secs: Option<u64>,
+    #[serde(default)]
+    delete: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct Transact {
+    ops: Vec<TransactOp>,
+}
+
+#[derive(Serialize)]
+struct TransactResp {
+    // each op's new version in order, 0 for a delete
+    versions: Vec<u32>,
 }
 
 #[derive(Serialize)]
@@ -183,74 +560,239 @@ impl Responder for PutResp {
     }
 }
 
-#[derive(Error, Display, Debug)]
-enum KVErrors {
-    #[display(fmt = "downstream service unavailable")]
-    ServiceUnavailable,
-
-    #[display(fmt = "internal server error")]
-    InternalServerError,
-}
-
-impl error::ResponseError for KVErrors {
-    fn status_code(&self) -> StatusCode {
-        match *self {
-            KVErrors::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
-            KVErrors::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
-        }
-    }
-
-    fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::plaintext())
-            .body(self.to_string())
-    }
-}
-
 #[derive(Serialize, Debug)]
 struct GenTokenResponse {
     token: common::auth::Token,
+    expires_at: u64,
+    scopes: Vec<Scope>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespaces: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize)]
 struct GenTokenRequest {
     name: String,
+    password: Option<String>,
+    // the token gets every scope when none are requested
+    scopes: Option<Vec<Scope>>,
+    // names of the namespaces the token is limited to, the token can use every namespace when unset
+    namespaces: Option<Vec<String>>,
+}
+
+impl std::fmt::Debug for GenTokenRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GenTokenRequest")
+            .field("name", &self.name)
+            .field("scopes", &self.scopes)
+            .field("namespaces", &self.namespaces)
+            .finish_non_exhaustive()
+    }
 }
 
-#[instrument(skip(app_data))]
+#[instrument(skip(app_data, req))]
 #[post("/tokens")]
 async fn gen_token(
+    req: HttpRequest,
     app_data: Data<AppData>,
     data: web::Json<GenTokenRequest>,
 ) -> Result<impl Responder, Box<dyn std::error::Error>> {
+    let source = req
+        .peer_addr()
+        .map_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED), |addr| addr.ip());
+
+    if let Some(retry_after) = app_data.login_throttle.check(&data.name, source) {
+        let tenant_id = app_data.tenants.get(&data.name).await.ok();
+        app_data
+            .audit
+            .record(
+                "login_throttled",
+                tenant_id.map(|tenant| tenant.uuid),
+                Some(source),
+                Outcome::Denied,
+                None,
+            )
+            .await;
+        return Ok(HttpResponseBuilder::new(StatusCode::TOO_MANY_REQUESTS)
+            .insert_header((header::RETRY_AFTER, retry_after.as_secs().max(1)))
+            .finish());
+    }
+
+    // an unknown tenant, a wrong password and an inactive tenant all get the same 401, and the
+    // password is checked before the tenant's status, so a response doesn't tell which names exist
+    // or what state they're in
     let tenant = match app_data.tenants.get(&data.name).await {
         Ok(tenant) => tenant,
         Err(err) => {
             error!(err = err.to_string(), "failed to get tenant information");
+            login_failed(&app_data, &data.name, None, source, "unknown tenant").await;
+            return Ok(HttpResponseBuilder::new(StatusCode::UNAUTHORIZED).finish());
+        }
+    };
+    if !verify_password(&app_data, &data.name, data.password.as_deref()).await? {
+        error!("invalid password");
+        login_failed(
+            &app_data,
+            &data.name,
+            Some(tenant.uuid),
+            source,
+            "invalid password",
+        )
+        .await;
+        return Ok(HttpResponseBuilder::new(StatusCode::UNAUTHORIZED).finish());
+    }
+    if tenant.status != TenantStatus::Active {
+        error!(
+            status = tenant.status.as_str(),
+            "refusing to issue token for inactive tenant"
+        );
+        app_data
+            .audit
+            .record(
+                "login_failed",
+                Some(tenant.uuid),
+                Some(source),
+                Outcome::Denied,
+                Some(&format!("tenant {}", tenant.status.as_str())),
+            )
+            .await;
+        return Ok(HttpResponseBuilder::new(StatusCode::UNAUTHORIZED).finish());
+    }
+    app_data.login_throttle.record_success(&data.name, source);
+    let scopes = match &data.scopes {
+        Some(scopes) if scopes.is_empty() => {
+            error!("refusing to issue token without scopes");
             return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
         }
+        Some(scopes) => scopes.clone(),
+        None => Scope::all(),
+    };
+    let namespaces = match &data.namespaces {
+        Some(names) => {
+            let mut namespaces = Vec::with_capacity(names.len());
+            for name in names {
+                match app_data.namespaces.get(tenant.uuid, name).await {
+                    Ok(namespace) => namespaces.push(namespace.id),
+                    Err(err) => {
+                        error!(err = err.to_string(), "unknown namespace in token request");
+                        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+                    }
+                }
+            }
+            Some(namespaces)
+        }
+        None => None,
     };
-    let token = app_data.jwts.new_identity(tenant.uuid)?;
+    let token = app_data
+        .jwts
+        .new_scoped_identity(tenant.uuid, scopes, namespaces)?;
+    app_data
+        .audit
+        .record(
+            "token_issued",
+            Some(tenant.uuid),
+            Some(source),
+            Outcome::Success,
+            Some("password"),
+        )
+        .await;
     Ok(
         HttpResponseBuilder::new(StatusCode::OK).json(GenTokenResponse {
             token: token.token(),
+            expires_at: token.expires_at(),
+            scopes: token.scopes().to_vec(),
+            namespaces: data.into_inner().namespaces,
         }),
     )
 }
 
-#[instrument(skip(auth_data, app_data, path))]
+// The throttle counts failures by the name logged in with, the audit log records the tenant's uuid
+async fn login_failed(
+    app_data: &AppData,
+    name: &str,
+    tenant_id: Option<Uuid>,
+    source: IpAddr,
+    reason: &str,
+) {
+    app_data
+        .audit
+        .record(
+            "login_failed",
+            tenant_id,
+            Some(source),
+            Outcome::Denied,
+            Some(reason),
+        )
+        .await;
+    app_data.login_throttle.record_failure(name, source);
+}
+
+// Hashing is cpu bound so it runs on the blocking thread pool. A hash made with outdated
+// parameters is replaced on a successful login.
+async fn verify_password(
+    app_data: &AppData,
+    name: &str,
+    password: Option<&str>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    // a tenant without a password can't log in until one is set through the admin api
+    let Some(hash) = app_data.tenants.password_hash(name).await? else {
+        warn!(tenant = name, "tenant has no password set");
+        return Ok(false);
+    };
+    let Some(password) = password else {
+        return Ok(false);
+    };
+
+    let passwords = app_data.passwords.clone();
+    let password = password.to_owned();
+    let (verification, new_hash) = web::block(move || {
+        passwords
+            .verify(&password, &hash)
+            .and_then(|verification| match verification {
+                Verification::ValidNeedsRehash => passwords
+                    .hash(&password)
+                    .map(|new_hash| (verification, Some(new_hash))),
+                _ => Ok((verification, None)),
+            })
+    })
+    .await??;
+
+    if let Some(new_hash) = new_hash {
+        info!(tenant = name, "upgrading password hash parameters");
+        if let Err(err) = app_data.tenants.set_password_hash(name, &new_hash).await {
+            error!(err = err.to_string(), "failed to upgrade password hash");
+        }
+    }
+    Ok(verification.is_valid())
+}
+
+#[derive(Deserialize, Debug)]
+struct GetQuery {
+    // return the value as stored, without the namespace's read transform
+    #[serde(default)]
+    raw: bool,
+    // a version other than the current one is read from the versions the namespace retains, and
+    // can be read after the key was deleted
+    version: Option<u32>,
+    // strong or eventual, the X-Consistency header's or the gateway's default when not set
+    consistency: Option<Consistency>,
+}
+
+#[instrument(skip(identity, app_data, path, req))]
 #[get("/namespaces/{namespace}/keys/{id}")]
 async fn get(
+    req: HttpRequest,
     path: web::Path<(String, String)>,
+    query: web::Query<GetQuery>,
     app_data: Data<AppData>,
-    auth_data: web::Header<common::auth::AuthHeader>,
+    identity: AuthenticatedTenant,
 ) -> Result<impl Responder, KVErrors> {
     let (namespace, id) = path.into_inner();
-    let Ok(identity) = app_data.jwts.parse(auth_data.as_ref()) else {
-        error!("failed to verify auth data");
-        return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+    let Some(consistency) =
+        consistency::requested(&req, query.consistency, app_data.default_consistency)
+    else {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
     };
-    let metadata = auth_data.into_inner().into();
+    let metadata = service_metadata(&app_data, &identity)?;
 
     let tenant_id = identity.tenant_id();
 
@@ -264,50 +806,90 @@ async fn get(
         }
     };
 
-    let mut client = app_data.connection_manager.get_conn(0).unwrap().clone(); // this clone is needed because the client needs a mutable reference, the tonic docs claim this is a cheap clone
+    if !identity.allows(Scope::Read, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
 
-    let request = tonic::Request::from_parts(
-        metadata,
-        Extensions::default(),
-        GetRequest {
-            key: id.into_bytes(),
-            namespace_id: namespace.id.to_string(),
-            version: None,
-        },
+    let cached = CachedGet {
+        tenant_id,
+        namespace_id: namespace.id,
+        key: id.into_bytes(),
+        version: query.version,
+        raw: query.raw,
+    };
+    let cache_ttl = namespace.settings.cache_ttl_secs.map(Duration::from_secs);
+    // a strong read still refreshes the cached response
+    if cache_ttl.is_some() && consistency == Consistency::Eventual {
+        if let Some(response) = app_data.response_cache.get(&cached).await {
+            return Ok(get_response(&response));
+        }
+    }
+
+    let request = adapter::get_request(
+        &namespace,
+        cached.key.clone(),
+        query.version,
+        query.raw,
+        consistency,
     );
 
-    match client.get(request).await {
+    match app_data
+        .connection_manager
+        .call_idempotent(Rpc::Get, namespace.storage_node.as_deref(), |mut client| {
+            let request = tonic::Request::from_parts(
+                metadata.clone(),
+                Extensions::default(),
+                request.clone(),
+            );
+            async move { client.get(request).await }
+        })
+        .await
+    {
         Ok(response) => {
-            let response = response.get_ref();
-
-            let response_metadata = response.metadata.as_ref().unwrap();
-            Ok(HttpResponseBuilder::new(StatusCode::OK)
-                .append_header(("version", response_metadata.version.to_string()))
-                .append_header(("crc", response_metadata.crc.to_string()))
-                .content_type("plain/text")
-                .body(response.value.clone()))
+            let response = Arc::new(response.into_inner());
+            if let Some(ttl) = cache_ttl {
+                app_data
+                    .response_cache
+                    .insert(cached, response.clone(), ttl)
+                    .await;
+            }
+            Ok(get_response(&response))
         }
         Err(err) => {
             error!(err = err.to_string(), "failed to get key");
-            Err(KVErrors::InternalServerError)
+            Err(err.into())
         }
     }
 }
 
-#[instrument(skip(app_data, auth_data, path))]
+fn get_response(response: &GetResponse) -> HttpResponse {
+    let response_metadata = response.metadata.as_ref().unwrap();
+    let mut builder = HttpResponseBuilder::new(StatusCode::OK);
+    // the etag is the version, so a delete can be made conditional on it with If-Match
+    builder
+        .append_header(("version", response_metadata.version.to_string()))
+        .append_header((header::ETAG, format!("\"{}\"", response_metadata.version)))
+        .append_header(("crc", response_metadata.crc.to_string()));
+    // unix seconds, only sent for a key that expires
+    if let Some(expires_at) = &response_metadata.expires_at {
+        builder.append_header(("expires-at", expires_at.seconds.to_string()));
+    }
+    builder
+        .content_type("plain/text")
+        .body(response.value.clone())
+}
+
+#[instrument(skip(app_data, identity, path))]
 #[put("/namespaces/{namespace}/keys/{id}")]
 async fn put(
     path: web::Path<(String, String)>,
     data: web::Json<PutValue>,
     app_data: web::Data<AppData>,
-    auth_data: web::Header<common::auth::AuthHeader>,
+    identity: AuthenticatedTenant,
 ) -> Result<impl Responder, KVErrors> {
     let (namespace, id) = path.into_inner();
-    let Ok(identity) = app_data.jwts.parse(auth_data.as_ref()) else {
-        error!("failed to verify auth data");
-        return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
-    };
-    let metadata = auth_data.into_inner().into();
+    let metadata = service_metadata(&app_data, &identity)?;
 
     let tenant_id = identity.tenant_id();
 
@@ -319,43 +901,128 @@ async fn put(
         }
     };
 
-    let mut client = app_data.connection_manager.get_conn(0).unwrap().clone(); // this clone is needed because the client needs a mutable reference, the tonic docs claim this is a cheap clone
+    if !identity.allows(Scope::Write, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
 
-    let mut hasher = Hasher::new();
-    hasher.update(id.as_bytes());
-    hasher.update(data.value.as_bytes());
-    let crc = hasher.finalize();
+    if let Some(reason) = namespace.key_policy.violation(id.as_bytes()) {
+        info!(key = id, "key breaks the namespace's key policy");
+        return Err(KVErrors::InvalidKey(reason));
+    }
 
-    info!(key = id, "putting new key");
+    if data.if_absent && data.expected_version.is_some() {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    }
 
-    match data.crc {
-        Some(crc) => {
-            if crc != crc {
-                return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
-            }
+    let Some(value) = decode_value(&data.value, data.encoding.as_deref()) else {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    };
+
+    let schema = app_data
+        .schemas
+        .validator(namespace.id)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to get namespace schema");
+            KVErrors::from(err)
+        })?;
+    if let Some(schema) = schema {
+        schema::validate(&schema, &value).map_err(|violations| {
+            info!(key = id, "value does not match the namespace's schema");
+            KVErrors::SchemaViolation(violations)
+        })?;
+    }
+
+    // a put expecting a version past 0 overwrites a key, so it doesn't add to the tenant's keys
+    let overwrite = data.expected_version.is_some_and(|expected| expected > 0);
+    match app_data
+        .usage
+        .check_put(tenant_id, overwrite, value.len() as u64)
+        .await
+    {
+        Ok(None) => {}
+        Ok(Some(exceeded)) => {
+            info!(limit = exceeded.limit, "tenant is at its limit");
+            return Err(KVErrors::TenantLimitExceeded(exceeded));
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to check the tenant's limits");
+            return Err(err.into());
         }
-        None => {}
     }
 
+    // a ttl of 0 would expire the key as it's written, and expiries are stored as signed seconds
+    let ttl_secs = data.ttl_secs.or(namespace.settings.default_ttl_secs);
+    if ttl_secs.is_some_and(|ttl| ttl == 0 || ttl > i64::MAX as u64) {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    }
+
+    // the storage node checks the caller's crc, or the one computed here when there isn't one
+    let crc = data.crc.unwrap_or_else(|| {
+        let mut hasher = Hasher::new();
+        hasher.update(id.as_bytes());
+        hasher.update(&value);
+        hasher.finalize()
+    });
+
+    info!(key = id, "putting new key");
+
+    let value_len = value.len() as u64;
     let request = tonic::Request::from_parts(
         metadata,
         Extensions::default(),
-        PutRequest {
-            namespace_id: namespace.id.to_string(),
-            key: id.into_bytes(),
-            crc: Some(crc),
-            value: data.value.clone().into_bytes(),
-        },
+        adapter::put_request(
+            &namespace,
+            adapter::Put {
+                key: id.clone().into_bytes(),
+                value,
+                ttl_secs,
+                expected_version: data.expected_version,
+                if_absent: data.if_absent,
+            },
+            crc,
+        ),
     );
 
-    let put_response = match client.put(request).await {
+    let written = app_data
+        .connection_manager
+        .call(
+            Rpc::Put,
+            namespace.storage_node.as_deref(),
+            |mut client| async move { client.put(request).await },
+        )
+        .await;
+    // a put that failed, e.g. timed out, may still have been written
+    app_data
+        .response_cache
+        .invalidate_key(tenant_id, namespace.id, id.as_bytes())
+        .await;
+    let put_response = match written {
         Ok(response) => response.into_inner(),
         Err(err) => {
             error!(err = err.to_string(), "failed to put value");
-            return Err(KVErrors::InternalServerError);
+            return Err(err.into());
         }
     };
 
+    // the put went through, so failing to count it is only logged
+    if let Err(err) = app_data
+        .usage
+        .record_put(tenant_id, put_response.version == 1, value_len)
+        .await
+    {
+        error!(err = err.to_string(), "failed to record the tenant's usage");
+    }
+
+    app_data.webhooks.notify(Event::new(
+        EventKind::Put,
+        namespace.id,
+        &namespace.name,
+        &id,
+        Some(put_response.version),
+    ));
+
     Ok(HttpResponseBuilder::new(StatusCode::OK).json(PutResp {
         version: put_response.version,
         crc: put_response.crc,
@@ -365,130 +1032,2485 @@ async fn put(
     }))
 }
 
-#[derive(Deserialize, Clone, Debug)]
-struct CreateNamespace {
-    name: String,
+// the credential, a bearer token or an api key, of the tenant a key is copied to when it isn't the
+// caller's
+const DESTINATION_AUTHORIZATION_HEADER: &str = "x-destination-authorization";
+
+#[derive(Deserialize, Debug)]
+struct CopyKey {
+    // the namespace the key is copied to, the caller's unless X-Destination-Authorization is set
+    namespace: String,
+    // the same key as the source's when not set
+    key: Option<String>,
+    // only copy if the destination key is at this version, 0 for a key that doesn't exist
+    expected_version: Option<u32>,
 }
 
-//#[instrument]
-#[post("/namespaces")]
-async fn create_namespace(
+// Copies the key's value, crc and expiry on the storage node, so the value never passes through the
+// gateway. The copy is a new write to the destination key with its own version history. The caller
+// has to be able to read the key, and the destination's tenant, the caller's own or the one
+// X-Destination-Authorization authenticates as, has to be able to write to the destination.
+//
+// The value can't be checked against the destination's schema without reading it, so copies into a
+// namespace with a schema are only allowed from within that namespace. The node copies from its own
+// partitions, so the two namespaces have to be stored on the same node, otherwise the copy is
+// refused with 409 and the key has to be read and written instead.
+#[instrument(skip(app_data, identity, path, data, req))]
+#[post("/namespaces/{namespace}/keys/{id}/copy")]
+async fn copy_key(
+    req: HttpRequest,
     path: web::Path<(String, String)>,
-    data: web::Json<CreateNamespace>,
+    data: web::Json<CopyKey>,
     app_data: web::Data<AppData>,
-    auth_data: web::Header<common::auth::AuthHeader>,
+    identity: AuthenticatedTenant,
 ) -> Result<impl Responder, KVErrors> {
-    Ok(HttpResponseBuilder::new(StatusCode::NOT_IMPLEMENTED).finish())
-}
-
-#[derive(Serialize, Debug)]
-struct NamespacesResponse {
-    namespaces: Vec<Namespace>,
-}
+    let (source_namespace, source_key) = path.into_inner();
+    let key = data.key.clone().unwrap_or_else(|| source_key.clone());
 
-#[instrument(skip(app_data, auth_data))]
-#[get("/namespaces")]
-async fn list_namespaces(
-    app_data: Data<AppData>,
-    auth_data: web::Header<common::auth::AuthHeader>,
-) -> Result<impl Responder, KVErrors> {
-    let Ok(identity) = app_data.jwts.parse(auth_data.as_ref()) else {
-        error!("failed to verify auth data");
-        return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+    let destination = match req.headers().get(DESTINATION_AUTHORIZATION_HEADER) {
+        Some(value) => {
+            let Ok(value) = value.to_str() else {
+                return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+            };
+            let secret = value.strip_prefix("Bearer ").unwrap_or(value).trim();
+            let source = req.peer_addr().map(|addr| addr.ip());
+            Some(AuthenticatedTenant::from_secret(&app_data, secret, source).await?)
+        }
+        None => None,
     };
+    let writer = destination.as_ref().unwrap_or(&identity);
 
-    let tenant_id = identity.tenant_id();
-
-    info!(tenant_id = tenant_id.to_string(), "fetching namespaces");
+    info!(
+        tenant_id = identity.tenant_id().to_string(),
+        destination_tenant_id = writer.tenant_id().to_string(),
+        key = source_key,
+        "copying key"
+    );
 
-    let namespaces = match app_data.namespaces.list(tenant_id).await {
-        Ok(namespaces) => namespaces,
+    let source_namespace = match app_data
+        .namespaces
+        .get(identity.tenant_id(), &source_namespace)
+        .await
+    {
+        Ok(namespace) => namespace,
         Err(err) => {
-            error!(err = err.to_string());
-            return Ok(HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish());
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+    let namespace = match app_data
+        .namespaces
+        .get(writer.tenant_id(), &data.namespace)
+        .await
+    {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get destination namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
         }
     };
 
-    Ok(HttpResponseBuilder::new(StatusCode::OK).json(NamespacesResponse { namespaces }))
-}
-
-#[derive(Serialize, Debug)]
-struct ListKeyMetadata {
-    name: String,
-    version: u32,
-    crc: u32,
-    creation_time: Option<u64>,
-}
-
-#[derive(Serialize, Debug)]
-struct ListKeysResponse {
-    keys: Vec<ListKeyMetadata>,
-}
+    if !identity.allows(Scope::Read, source_namespace.id)
+        || !writer.allows(Scope::Write, namespace.id)
+    {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
 
-#[instrument(skip(app_data, auth_data))]
-#[get("/namespaces/{namespace}/keys")]
-async fn list_keys(
-    path: web::Path<String>,
-    app_data: Data<AppData>,
-    auth_data: web::Header<common::auth::AuthHeader>,
-) -> Result<impl Responder, KVErrors> {
-    let namespace = path.into_inner();
-    let Ok(identity) = app_data.jwts.parse(auth_data.as_ref()) else {
-        error!("failed to verify auth data");
-        return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
-    };
+    if let Some(reason) = namespace.key_policy.violation(key.as_bytes()) {
+        info!(key = key, "key breaks the namespace's key policy");
+        return Err(KVErrors::InvalidKey(reason));
+    }
 
-    let tenant_id = identity.tenant_id();
+    if namespace.id != source_namespace.id {
+        let schema = app_data
+            .schemas
+            .validator(namespace.id)
+            .await
+            .map_err(|err| {
+                error!(err = err.to_string(), "failed to get namespace schema");
+                KVErrors::from(err)
+            })?;
+        if schema.is_some() {
+            info!("the destination namespace has a schema the copy can't be checked against");
+            return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+        }
+    }
 
-    info!(tenant_id = tenant_id.to_string(), "fetching keys");
+    let connection_manager = &app_data.connection_manager;
+    if connection_manager.endpoint(source_namespace.storage_node.as_deref())
+        != connection_manager.endpoint(namespace.storage_node.as_deref())
+    {
+        info!("the namespaces are stored on different nodes");
+        return Ok(HttpResponseBuilder::new(StatusCode::CONFLICT).finish());
+    }
 
-    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
-        Ok(namespace) => namespace,
+    // the value's size isn't known here, the storage node checks the namespace's quota
+    let overwrite = data.expected_version.is_some_and(|expected| expected > 0);
+    match app_data
+        .usage
+        .check_put(writer.tenant_id(), overwrite, 0)
+        .await
+    {
+        Ok(None) => {}
+        Ok(Some(exceeded)) => {
+            info!(limit = exceeded.limit, "tenant is at its limit");
+            return Err(KVErrors::TenantLimitExceeded(exceeded));
+        }
         Err(err) => {
-            error!(err = err.to_string(), "failed to get namespace");
-            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+            error!(err = err.to_string(), "failed to check the tenant's limits");
+            return Err(err.into());
         }
-    };
-
-    let mut client = app_data.connection_manager.get_conn(0).unwrap().clone(); // this clone is needed because the client needs a mutable reference, the tonic docs claim this is a cheap clone
+    }
 
-    let metadata = auth_data.into_inner().into();
+    // the node writes as the destination's tenant and reads as the caller
+    let mut metadata = service_metadata(&app_data, writer)?;
+    if writer.tenant_id() != identity.tenant_id() {
+        let source = service_metadata(&app_data, &identity)?;
+        if let Some(token) = source.get(header::AUTHORIZATION.as_str()) {
+            metadata.insert(SOURCE_AUTHORIZATION_METADATA, token.clone());
+        }
+    }
 
     let request = tonic::Request::from_parts(
         metadata,
         Extensions::default(),
-        common::storage::ListKeysRequest {
+        CopyKeyRequest {
+            source_namespace_id: source_namespace.id.to_string(),
+            source_key: source_key.into_bytes(),
             namespace_id: namespace.id.to_string(),
-            limit: None,
-            start_key: None,
+            key: key.clone().into_bytes(),
+            expected_version: data.expected_version,
         },
     );
-    let key_span = span!(Level::INFO, "listing keys");
-    let response = match client.list_keys(request).instrument(key_span).await {
+
+    let copy = app_data
+        .connection_manager
+        .call(
+            Rpc::CopyKey,
+            namespace.storage_node.as_deref(),
+            |mut client| async move { client.copy_key(request).await },
+        )
+        .await;
+    app_data
+        .response_cache
+        .invalidate_key(writer.tenant_id(), namespace.id, key.as_bytes())
+        .await;
+    let copied = match copy {
         Ok(response) => response.into_inner(),
         Err(err) => {
-            error!(err = err.to_string(), "failed to list keys");
-            return Err(KVErrors::InternalServerError);
+            error!(err = err.to_string(), "failed to copy key");
+            return Err(err.into());
         }
     };
 
-    let mut result = Vec::new();
+    // the copy went through, so failing to count it is only logged
+    if let Err(err) = app_data
+        .usage
+        .record_put(writer.tenant_id(), copied.version == 1, copied.size)
+        .await
+    {
+        error!(err = err.to_string(), "failed to record the tenant's usage");
+    }
 
-    for item in response.keys {
-        let metadata = item.metadata.as_ref().unwrap();
+    app_data.webhooks.notify(Event::new(
+        EventKind::Put,
+        namespace.id,
+        &namespace.name,
+        &key,
+        Some(copied.version),
+    ));
 
-        result.push(ListKeyMetadata {
-            name: String::from_utf8(item.key).map_err(|err| {
-                error!(err = err.to_string(), "failed to map key");
-                return KVErrors::InternalServerError;
-            })?,
-            version: metadata.version,
-            crc: metadata.crc,
-            creation_time: None,
-        })
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(PutResp {
+        version: copied.version,
+        crc: copied.crc,
+        creation_time: copied
+            .creation_time
+            .map_or(String::from(""), |timestamp| timestamp.to_string()),
+    }))
+}
+
+// None when the value isn't in the encoding
+fn decode_value(value: &str, encoding: Option<&str>) -> Option<Vec<u8>> {
+    match encoding {
+        None => Some(value.as_bytes().to_vec()),
+        Some("base64") => general_purpose::STANDARD
+            .decode(value)
+            .inspect_err(|err| error!(err = err.to_string(), "invalid base64 value"))
+            .ok(),
+        Some(encoding) => {
+            error!(encoding = encoding, "unknown value encoding");
+            None
+        }
     }
+}
 
-    let response = ListKeysResponse { keys: result };
+// Applies every op or none of them, whichever partitions the keys are in. Each put is checked like
+// a single put, and a version conflict on any op fails the whole transaction with a 409.
+#[instrument(skip(app_data, identity, data))]
+#[post("/namespaces/{namespace}/transact")]
+async fn transact(
+    path: web::Path<String>,
+    data: web::Json<Transact>,
+    app_data: web::Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = path.into_inner();
+    let metadata = service_metadata(&app_data, &identity)?;
 
-    Ok(HttpResponseBuilder::new(StatusCode::OK).json(response))
+    let tenant_id = identity.tenant_id();
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Write, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let schema = app_data
+        .schemas
+        .validator(namespace.id)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to get namespace schema");
+            KVErrors::from(err)
+        })?;
+
+    let mut ops = Vec::with_capacity(data.ops.len());
+    // each put's length, counted towards the tenant's usage once the transaction is applied
+    let mut put_lens = vec![0; data.ops.len()];
+    let mut overwrite = true;
+    for (op, put_len) in data.ops.iter().zip(put_lens.iter_mut()) {
+        if op.delete {
+            ops.push(TransactWriteOp {
+                key: op.key.clone().into_bytes(),
+                value: None,
+                crc: None,
+                expected_version: op.expected_version,
+                ttl_secs: None,
+            });
+            continue;
+        }
+
+        if let Some(reason) = namespace.key_policy.violation(op.key.as_bytes()) {
+            info!(key = op.key, "key breaks the namespace's key policy");
+            return Err(KVErrors::InvalidKey(reason));
+        }
+
+        let Some(value) = decode_value(&op.value, op.encoding.as_deref()) else {
+            return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+        };
+
+        if let Some(schema) = &schema {
+            schema::validate(schema, &value).map_err(|violations| {
+                info!(key = op.key, "value does not match the namespace's schema");
+                KVErrors::SchemaViolation(violations)
+            })?;
+        }
+
+        let ttl_secs = op.ttl_secs.or(namespace.settings.default_ttl_secs);
+        if ttl_secs.is_some_and(|ttl| ttl == 0 || ttl > i64::MAX as u64) {
+            return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+        }
+
+        let crc = op.crc.unwrap_or_else(|| {
+            let mut hasher = Hasher::new();
+            hasher.update(op.key.as_bytes());
+            hasher.update(&value);
+            hasher.finalize()
+        });
+
+        overwrite &= op.expected_version.is_some_and(|expected| expected > 0);
+        *put_len = value.len() as u64;
+        ops.push(TransactWriteOp {
+            key: op.key.clone().into_bytes(),
+            value: Some(value),
+            crc: Some(crc),
+            expected_version: op.expected_version,
+            ttl_secs,
+        });
+    }
+
+    // the transaction's puts are checked together, as if they were one value
+    match app_data
+        .usage
+        .check_put(tenant_id, overwrite, put_lens.iter().sum())
+        .await
+    {
+        Ok(None) => {}
+        Ok(Some(exceeded)) => {
+            info!(limit = exceeded.limit, "tenant is at its limit");
+            return Err(KVErrors::TenantLimitExceeded(exceeded));
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to check the tenant's limits");
+            return Err(err.into());
+        }
+    }
+
+    info!(ops = ops.len(), "transacting writes");
+
+    let request = tonic::Request::from_parts(
+        metadata,
+        Extensions::default(),
+        TransactWriteRequest {
+            namespace_id: namespace.id.to_string(),
+            ops,
+        },
+    );
+
+    let transacted = app_data
+        .connection_manager
+        .call(
+            Rpc::TransactWrite,
+            namespace.storage_node.as_deref(),
+            |mut client| async move { client.transact_write(request).await },
+        )
+        .await;
+    for op in &data.ops {
+        app_data
+            .response_cache
+            .invalidate_key(tenant_id, namespace.id, op.key.as_bytes())
+            .await;
+    }
+    let versions = match transacted {
+        Ok(response) => response.into_inner().versions,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to transact writes");
+            return Err(err.into());
+        }
+    };
+
+    for ((op, new_version), put_len) in data.ops.iter().zip(&versions).zip(put_lens) {
+        if op.delete {
+            app_data.webhooks.notify(Event::new(
+                EventKind::Delete,
+                namespace.id,
+                &namespace.name,
+                &op.key,
+                None,
+            ));
+            continue;
+        }
+        // the writes went through, so failing to count them is only logged
+        if let Err(err) = app_data
+            .usage
+            .record_put(tenant_id, *new_version == 1, put_len)
+            .await
+        {
+            error!(err = err.to_string(), "failed to record the tenant's usage");
+        }
+        app_data.webhooks.notify(Event::new(
+            EventKind::Put,
+            namespace.id,
+            &namespace.name,
+            &op.key,
+            Some(*new_version),
+        ));
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(TransactResp { versions }))
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct CreateNamespace {
+    name: String,
+    #[serde(default)]
+    quota: Quota,
+    #[serde(flatten)]
+    settings: NamespaceSettings,
+    #[serde(default)]
+    key_policy: KeyPolicy,
+    // how large the namespace is expected to grow, the storage nodes size its partitions from it
+    size_hint_bytes: Option<u64>,
+}
+
+// Quotas are stored as signed integers, and a limit of 0 would make the namespace unusable
+fn valid_quota(quota: &Quota) -> bool {
+    [quota.max_keys, quota.max_bytes]
+        .into_iter()
+        .flatten()
+        .all(|limit| limit > 0 && limit <= i64::MAX as u64)
+}
+
+// The first failure of a namespace change sent to every storage node, each failure is logged
+fn node_failure(
+    change: &str,
+    results: Vec<(String, Result<tonic::Response<()>, tonic::Status>)>,
+) -> Option<tonic::Status> {
+    let mut failure = None;
+    for (node, result) in results {
+        if let Err(status) = result {
+            error!(
+                node = node,
+                change = change,
+                err = status.message(),
+                "failed to send namespace change"
+            );
+            failure.get_or_insert(status);
+        }
+    }
+    failure
+}
+
+// Namespaces can only be created and deleted by tokens with the admin scope that aren't limited to
+// a set of namespaces
+#[instrument(skip(app_data, identity))]
+#[post("/namespaces")]
+async fn create_namespace(
+    data: web::Json<CreateNamespace>,
+    app_data: web::Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let tenant_id = identity.tenant_id();
+
+    info!(tenant_id = tenant_id.to_string(), "creating namespace");
+
+    if !identity.has_scope(Scope::Admin) || identity.namespaces().is_some() {
+        error!("token does not allow creating namespaces");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    if data.name.is_empty()
+        || !valid_quota(&data.quota)
+        || !data.settings.is_valid()
+        || !data.key_policy.is_valid()
+    {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    }
+
+    match app_data.usage.check_namespace(tenant_id).await {
+        Ok(None) => {}
+        Ok(Some(exceeded)) => {
+            error!(limit = exceeded.limit, "tenant is at its namespace limit");
+            return Err(KVErrors::TenantLimitExceeded(exceeded));
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to check the tenant's limits");
+            return Err(err.into());
+        }
+    }
+
+    // every node has the namespace, its keys are stored on the one it's placed on
+    let Some(storage_node) = app_data.connection_manager.place() else {
+        error!("no storage node to place the namespace on");
+        return Err(tonic::Status::unavailable("no storage node to place the namespace on").into());
+    };
+    let namespace = match app_data
+        .namespaces
+        .create(
+            tenant_id,
+            &data.name,
+            &data.quota,
+            &data.settings,
+            &data.key_policy,
+            Some(&storage_node),
+        )
+        .await
+    {
+        Ok(namespace) => namespace,
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            return Ok(HttpResponseBuilder::new(StatusCode::CONFLICT).finish());
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to create namespace");
+            return Err(err.into());
+        }
+    };
+
+    // the storage nodes enforce the quota and key policy, a namespace they didn't all accept isn't
+    // kept
+    let metadata = service_metadata(&app_data, &identity)?;
+    let request = CreateNamespaceRequest {
+        name: namespace.name.clone(),
+        namespace_id: namespace.id.to_string(),
+        quota: Some(data.quota.into()),
+        key_policy: Some((&data.key_policy).into()),
+        size_hint_bytes: data.size_hint_bytes,
+        partitions: None,
+    };
+    let results = app_data
+        .connection_manager
+        .call_all(Rpc::CreateNamespace, |mut client| {
+            let request = tonic::Request::from_parts(
+                metadata.clone(),
+                Extensions::default(),
+                request.clone(),
+            );
+            async move { client.create_namespace(request).await }
+        })
+        .await;
+    if let Some(status) = node_failure("namespace", results) {
+        if let Err(err) = app_data.namespaces.delete(namespace.id).await {
+            error!(
+                err = err.to_string(),
+                "failed to remove namespace the storage nodes rejected"
+            );
+        }
+        return Err(status.into());
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(namespace))
+}
+
+#[derive(Deserialize, Debug)]
+struct CloneNamespace {
+    name: String,
+}
+
+// Creates a namespace holding a copy of the namespace's keys as they were at one point in time,
+// e.g. a staging copy of production data. The storage nodes copy the partitions themselves, so
+// nothing passes through the gateway. The copy starts with the namespace's quota, settings, key
+// policy, schema, and transforms, and none of its webhooks or retention.
+//
+// Like creating a namespace, the copy isn't kept unless every node cloned it. Partitions a node
+// cloned before another failed are left on that node until they're cleaned up.
+#[instrument(skip(app_data, identity))]
+#[post("/namespaces/{namespace}/clone")]
+async fn clone_namespace(
+    path: web::Path<String>,
+    data: web::Json<CloneNamespace>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let source = match managed_namespace(&app_data, &identity, &path.into_inner()).await {
+        Ok(namespace) => namespace,
+        Err(response) => return Ok(response),
+    };
+    let tenant_id = identity.tenant_id();
+
+    if data.name.is_empty() {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    }
+
+    match app_data.usage.check_namespace(tenant_id).await {
+        Ok(None) => {}
+        Ok(Some(exceeded)) => {
+            error!(limit = exceeded.limit, "tenant is at its namespace limit");
+            return Err(KVErrors::TenantLimitExceeded(exceeded));
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to check the tenant's limits");
+            return Err(err.into());
+        }
+    }
+
+    info!(source = source.name, name = data.name, "cloning namespace");
+    let quota = app_data.namespaces.quota(source.id).await.map_err(|err| {
+        error!(err = err.to_string(), "failed to get namespace quota");
+        KVErrors::from(err)
+    })?;
+    let schema = app_data.schemas.get(source.id).await.map_err(|err| {
+        error!(err = err.to_string(), "failed to get namespace schema");
+        KVErrors::from(err)
+    })?;
+    // each node clones its own partitions, so the copy's keys are on the source's node
+    let namespace = match app_data
+        .namespaces
+        .create(
+            tenant_id,
+            &data.name,
+            &quota,
+            &source.settings,
+            &source.key_policy,
+            source.storage_node.as_deref(),
+        )
+        .await
+    {
+        Ok(namespace) => namespace,
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            return Ok(HttpResponseBuilder::new(StatusCode::CONFLICT).finish());
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to create namespace");
+            return Err(err.into());
+        }
+    };
+
+    let metadata = service_metadata(&app_data, &identity)?;
+    let create = CreateNamespaceRequest {
+        name: namespace.name.clone(),
+        namespace_id: namespace.id.to_string(),
+        quota: Some(quota.into()),
+        key_policy: Some((&source.key_policy).into()),
+        // the clone brings the source's partitions
+        size_hint_bytes: None,
+        partitions: Some(0),
+    };
+    let created = app_data
+        .connection_manager
+        .call_all(Rpc::CreateNamespace, |mut client| {
+            let request = tonic::Request::from_parts(
+                metadata.clone(),
+                Extensions::default(),
+                create.clone(),
+            );
+            async move { client.create_namespace(request).await }
+        })
+        .await;
+    let mut failure = node_failure("namespace", created);
+    if failure.is_none() {
+        let clone = CloneNamespaceRequest {
+            source_namespace_id: source.id.to_string(),
+            namespace_id: namespace.id.to_string(),
+        };
+        let cloned = app_data
+            .connection_manager
+            .call_all(Rpc::CloneNamespace, |mut client| {
+                let request = tonic::Request::from_parts(
+                    metadata.clone(),
+                    Extensions::default(),
+                    clone.clone(),
+                );
+                async move {
+                    let response = client.clone_namespace(request).await?;
+                    Ok(response.map(|_| ()))
+                }
+            })
+            .await;
+        failure = node_failure("clone", cloned);
+    }
+    if let Some(status) = failure {
+        if let Err(err) = app_data.namespaces.delete(namespace.id).await {
+            error!(
+                err = err.to_string(),
+                "failed to remove namespace the storage nodes didn't clone"
+            );
+        }
+        return Err(status.into());
+    }
+
+    if let Some(schema) = schema {
+        app_data
+            .schemas
+            .set(namespace.id, Some(&schema))
+            .await
+            .map_err(|err| {
+                error!(err = err.to_string(), "failed to copy namespace schema");
+                KVErrors::from(err)
+            })?;
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(namespace))
+}
+
+#[instrument(skip(app_data, identity))]
+#[get("/namespaces/{namespace}/quota")]
+async fn get_namespace_quota(
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = path.into_inner();
+
+    let tenant_id = identity.tenant_id();
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Read, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let quota = app_data
+        .namespaces
+        .quota(namespace.id)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to get namespace quota");
+            KVErrors::from(err)
+        })?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(quota))
+}
+
+// Replaces the namespace's quota, unset limits are removed. The quota is saved before it's sent to
+// the storage nodes, so after a node failure the same request is repeated until every node has it.
+#[instrument(skip(app_data, identity))]
+#[put("/namespaces/{namespace}/quota")]
+async fn set_namespace_quota(
+    path: web::Path<String>,
+    data: web::Json<Quota>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = path.into_inner();
+
+    let tenant_id = identity.tenant_id();
+
+    info!(tenant_id = tenant_id.to_string(), "setting namespace quota");
+
+    if !identity.has_scope(Scope::Admin) || identity.namespaces().is_some() {
+        error!("token does not allow setting namespace quotas");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let quota = data.into_inner();
+    if !valid_quota(&quota) {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    }
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    app_data
+        .namespaces
+        .set_quota(namespace.id, &quota)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to save namespace quota");
+            KVErrors::from(err)
+        })?;
+
+    let metadata = service_metadata(&app_data, &identity)?;
+    let request = SetNamespaceQuotaRequest {
+        namespace_id: namespace.id.to_string(),
+        quota: Some(quota.into()),
+    };
+    let results = app_data
+        .connection_manager
+        .call_all(Rpc::SetNamespaceQuota, |mut client| {
+            let request = tonic::Request::from_parts(
+                metadata.clone(),
+                Extensions::default(),
+                request.clone(),
+            );
+            async move { client.set_namespace_quota(request).await }
+        })
+        .await;
+    if let Some(status) = node_failure("quota", results) {
+        return Err(status.into());
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(quota))
+}
+
+#[instrument(skip(app_data, identity))]
+#[get("/namespaces/{namespace}/key-policy")]
+async fn get_namespace_key_policy(
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = match app_data
+        .namespaces
+        .get(identity.tenant_id(), &path.into_inner())
+        .await
+    {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Read, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(namespace.key_policy))
+}
+
+// Replaces the namespace's key policy, an empty policy allows every key. Like the quota, the policy
+// is saved before it's sent to the storage nodes, and keys written before it are kept.
+#[instrument(skip(app_data, identity))]
+#[put("/namespaces/{namespace}/key-policy")]
+async fn set_namespace_key_policy(
+    path: web::Path<String>,
+    data: web::Json<KeyPolicy>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = match managed_namespace(&app_data, &identity, &path.into_inner()).await {
+        Ok(namespace) => namespace,
+        Err(response) => return Ok(response),
+    };
+
+    let key_policy = data.into_inner();
+    if !key_policy.is_valid() {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    }
+
+    info!(namespace = namespace.name, "setting namespace key policy");
+    app_data
+        .namespaces
+        .set_key_policy(namespace.id, &key_policy)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to save namespace key policy");
+            KVErrors::from(err)
+        })?;
+
+    let metadata = service_metadata(&app_data, &identity)?;
+    let request = SetNamespaceKeyPolicyRequest {
+        namespace_id: namespace.id.to_string(),
+        key_policy: Some((&key_policy).into()),
+    };
+    let results = app_data
+        .connection_manager
+        .call_all(Rpc::SetNamespaceKeyPolicy, |mut client| {
+            let request = tonic::Request::from_parts(
+                metadata.clone(),
+                Extensions::default(),
+                request.clone(),
+            );
+            async move { client.set_namespace_key_policy(request).await }
+        })
+        .await;
+    if let Some(status) = node_failure("key policy", results) {
+        return Err(status.into());
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(key_policy))
+}
+
+#[instrument(skip(app_data, identity))]
+#[get("/namespaces/{namespace}/retention")]
+async fn get_namespace_retention(
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = match app_data
+        .namespaces
+        .get(identity.tenant_id(), &path.into_inner())
+        .await
+    {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Read, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let retention = app_data
+        .namespaces
+        .retention(namespace.id)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to get namespace retention");
+            KVErrors::from(err)
+        })?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(retention))
+}
+
+// Replaces how long the storage nodes keep the namespace's deleted and replaced values, unset
+// fields keep nothing. Values already kept are purged once the new retention no longer covers
+// them. Like the quota, the retention is saved before it's sent to the storage nodes.
+#[instrument(skip(app_data, identity))]
+#[put("/namespaces/{namespace}/retention")]
+async fn set_namespace_retention(
+    path: web::Path<String>,
+    data: web::Json<Retention>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = match managed_namespace(&app_data, &identity, &path.into_inner()).await {
+        Ok(namespace) => namespace,
+        Err(response) => return Ok(response),
+    };
+
+    let retention = data.into_inner();
+    let valid = [retention.deleted_secs, retention.versions_secs]
+        .into_iter()
+        .flatten()
+        .all(|secs| secs > 0 && secs <= i64::MAX as u64);
+    if !valid {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    }
+
+    info!(namespace = namespace.name, "setting namespace retention");
+    app_data
+        .namespaces
+        .set_retention(namespace.id, &retention)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to save namespace retention");
+            KVErrors::from(err)
+        })?;
+
+    let metadata = service_metadata(&app_data, &identity)?;
+    let request = SetNamespaceRetentionRequest {
+        namespace_id: namespace.id.to_string(),
+        retention: Some(retention.into()),
+    };
+    let results = app_data
+        .connection_manager
+        .call_all(Rpc::SetNamespaceRetention, |mut client| {
+            let request = tonic::Request::from_parts(
+                metadata.clone(),
+                Extensions::default(),
+                request.clone(),
+            );
+            async move { client.set_namespace_retention(request).await }
+        })
+        .await;
+    if let Some(status) = node_failure("retention", results) {
+        return Err(status.into());
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(retention))
+}
+
+#[derive(Serialize, Debug)]
+struct RetainedVersion {
+    version: u32,
+    // unix seconds
+    retained_at: i64,
+    deleted: bool,
+    size: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct ListVersionsResp {
+    versions: Vec<RetainedVersion>,
+}
+
+// The key's versions the namespace retains, oldest first. Each can be read with ?version= on the
+// key, including after the key was deleted.
+#[instrument(skip(app_data, identity, path))]
+#[get("/namespaces/{namespace}/keys/{id}/versions")]
+async fn list_versions(
+    path: web::Path<(String, String)>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let (namespace, id) = path.into_inner();
+    let metadata = service_metadata(&app_data, &identity)?;
+
+    let namespace = match app_data.namespaces.get(identity.tenant_id(), &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Read, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let request = ListVersionsRequest {
+        namespace_id: namespace.id.to_string(),
+        key: id.into_bytes(),
+    };
+    let response = app_data
+        .connection_manager
+        .call(
+            Rpc::ListVersions,
+            namespace.storage_node.as_deref(),
+            |mut client| {
+                let request = tonic::Request::from_parts(
+                    metadata.clone(),
+                    Extensions::default(),
+                    request.clone(),
+                );
+                async move { client.list_versions(request).await }
+            },
+        )
+        .await
+        .inspect_err(|err| error!(err = err.to_string(), "failed to list versions"))?;
+
+    let versions = response
+        .into_inner()
+        .versions
+        .into_iter()
+        .map(|retained| RetainedVersion {
+            version: retained.version,
+            retained_at: retained.retained_at.map_or(0, |at| at.seconds),
+            deleted: retained.deleted,
+            size: retained.size,
+        })
+        .collect();
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(ListVersionsResp { versions }))
+}
+
+// base64 encoded wasm modules, a module that isn't given is removed
+#[derive(Deserialize, Debug)]
+struct NamespaceTransforms {
+    read: Option<String>,
+    write: Option<String>,
+}
+
+// two modules of the storage nodes' largest size once base64 encoded
+const MAX_TRANSFORMS_BODY: usize = 3 * 1024 * 1024;
+
+// Replaces the namespace's wasm transforms on every storage node. The nodes keep the modules, a
+// node added later doesn't have them until they're set again.
+#[instrument(skip(app_data, identity, payload))]
+#[put("/namespaces/{namespace}/transforms")]
+async fn set_namespace_transforms(
+    path: web::Path<String>,
+    mut payload: web::Payload,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = path.into_inner();
+
+    let tenant_id = identity.tenant_id();
+
+    info!(
+        tenant_id = tenant_id.to_string(),
+        "setting namespace transforms"
+    );
+
+    if !identity.has_scope(Scope::Admin) || identity.namespaces().is_some() {
+        error!("token does not allow setting namespace transforms");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    // modules are larger than the json bodies the other endpoints accept
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let Ok(chunk) = chunk else {
+            return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+        };
+        let limit = MAX_TRANSFORMS_BODY.min(app_data.max_request_body);
+        if body.len() + chunk.len() > limit {
+            return Err(KVErrors::PayloadTooLarge(limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let Ok(transforms) = serde_json::from_slice::<NamespaceTransforms>(&body) else {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    };
+    let decode =
+        |module: Option<String>| module.map(|module| general_purpose::STANDARD.decode(module));
+    let (Ok(read_module), Ok(write_module)) = (
+        decode(transforms.read).transpose(),
+        decode(transforms.write).transpose(),
+    ) else {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    };
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    let metadata = service_metadata(&app_data, &identity)?;
+    let request = SetNamespaceTransformsRequest {
+        namespace_id: namespace.id.to_string(),
+        read_module,
+        write_module,
+    };
+    let results = app_data
+        .connection_manager
+        .call_all(Rpc::SetNamespaceTransforms, |mut client| {
+            let request = tonic::Request::from_parts(
+                metadata.clone(),
+                Extensions::default(),
+                request.clone(),
+            );
+            async move { client.set_namespace_transforms(request).await }
+        })
+        .await;
+    // values are read through the new transforms, even when some nodes didn't take them
+    app_data
+        .response_cache
+        .invalidate_namespace(tenant_id, namespace.id);
+    if let Some(status) = node_failure("transforms", results) {
+        return Err(status.into());
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
+}
+
+#[instrument(skip(app_data, identity))]
+#[get("/namespaces/{namespace}/settings")]
+async fn get_namespace_settings(
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = path.into_inner();
+
+    let tenant_id = identity.tenant_id();
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Read, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(namespace.settings))
+}
+
+// Replaces the namespace's settings, unset ones go back to their defaults
+#[instrument(skip(app_data, identity))]
+#[put("/namespaces/{namespace}/settings")]
+async fn set_namespace_settings(
+    path: web::Path<String>,
+    data: web::Json<NamespaceSettings>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = path.into_inner();
+
+    let tenant_id = identity.tenant_id();
+
+    info!(
+        tenant_id = tenant_id.to_string(),
+        "setting namespace settings"
+    );
+
+    if !identity.has_scope(Scope::Admin) || identity.namespaces().is_some() {
+        error!("token does not allow changing namespace settings");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let settings = data.into_inner();
+    if !settings.is_valid() {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    }
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    app_data
+        .namespaces
+        .set_settings(namespace.id, &settings)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to save namespace settings");
+            KVErrors::from(err)
+        })?;
+    // gets cached with the old cache ttl could otherwise outlive the new one
+    app_data
+        .response_cache
+        .invalidate_namespace(tenant_id, namespace.id);
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(settings))
+}
+
+// The JSON Schema the namespace's values are validated against, 404 when they aren't validated
+#[instrument(skip(app_data, identity))]
+#[get("/namespaces/{namespace}/schema")]
+async fn get_namespace_schema(
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = match app_data
+        .namespaces
+        .get(identity.tenant_id(), &path.into_inner())
+        .await
+    {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Read, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    match app_data.schemas.get(namespace.id).await {
+        Ok(Some(schema)) => Ok(HttpResponseBuilder::new(StatusCode::OK).json(schema)),
+        Ok(None) => Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace schema");
+            Err(err.into())
+        }
+    }
+}
+
+// Validates the namespace's puts against the schema from now on, values already stored aren't
+// checked. Schemas are managed like the namespace's settings.
+#[instrument(skip(app_data, identity, data))]
+#[put("/namespaces/{namespace}/schema")]
+async fn set_namespace_schema(
+    path: web::Path<String>,
+    data: web::Json<serde_json::Value>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<HttpResponse, KVErrors> {
+    let namespace = match managed_namespace(&app_data, &identity, &path.into_inner()).await {
+        Ok(namespace) => namespace,
+        Err(response) => return Ok(response),
+    };
+    let schema = data.into_inner();
+    if schema.to_string().len() > schema::MAX_SCHEMA_LEN {
+        return Err(KVErrors::PayloadTooLarge(schema::MAX_SCHEMA_LEN));
+    }
+    if let Err(err) = schema::compile(&schema) {
+        return Err(KVErrors::BadRequest(Box::new(
+            tonic::Status::invalid_argument(format!("invalid schema: {}", err)),
+        )));
+    }
+    info!(namespace = namespace.name, "setting namespace schema");
+    app_data
+        .schemas
+        .set(namespace.id, Some(&schema))
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to save namespace schema");
+            KVErrors::from(err)
+        })?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(schema))
+}
+
+#[instrument(skip(app_data, identity))]
+#[delete("/namespaces/{namespace}/schema")]
+async fn delete_namespace_schema(
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<HttpResponse, KVErrors> {
+    let namespace = match managed_namespace(&app_data, &identity, &path.into_inner()).await {
+        Ok(namespace) => namespace,
+        Err(response) => return Ok(response),
+    };
+    info!(namespace = namespace.name, "removing namespace schema");
+    app_data
+        .schemas
+        .set(namespace.id, None)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to remove namespace schema");
+            KVErrors::from(err)
+        })?;
+    Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
+}
+
+#[derive(Deserialize, Debug)]
+struct CreateWebhook {
+    url: String,
+}
+
+// Webhooks, schemas, and key policies are managed by tokens with the admin scope that aren't
+// limited to a set of namespaces, like the namespace's settings. Fails with the response to send
+// when the token can't.
+async fn managed_namespace(
+    app_data: &AppData,
+    identity: &AuthenticatedTenant,
+    namespace: &str,
+) -> Result<Namespace, HttpResponse> {
+    if !identity.has_scope(Scope::Admin) || identity.namespaces().is_some() {
+        error!("token does not allow managing the namespace");
+        return Err(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+    app_data
+        .namespaces
+        .get(identity.tenant_id(), namespace)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to get namespace");
+            HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+        })
+}
+
+#[instrument(skip(app_data, identity))]
+#[get("/namespaces/{namespace}/webhooks")]
+async fn list_webhooks(
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<HttpResponse, KVErrors> {
+    let namespace = match managed_namespace(&app_data, &identity, &path.into_inner()).await {
+        Ok(namespace) => namespace,
+        Err(response) => return Ok(response),
+    };
+    let webhooks = app_data.webhooks.list(namespace.id).await.map_err(|err| {
+        error!(err = err.to_string(), "failed to list webhooks");
+        KVErrors::from(err)
+    })?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(webhooks))
+}
+
+// The response has the secret events are signed with, it isn't shown again
+#[instrument(skip(app_data, identity))]
+#[post("/namespaces/{namespace}/webhooks")]
+async fn create_webhook(
+    path: web::Path<String>,
+    data: web::Json<CreateWebhook>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<HttpResponse, KVErrors> {
+    let namespace = match managed_namespace(&app_data, &identity, &path.into_inner()).await {
+        Ok(namespace) => namespace,
+        Err(response) => return Ok(response),
+    };
+    if !webhook::valid_url(&data.url) {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    }
+    info!(namespace = namespace.name, "creating webhook");
+    let webhook = app_data
+        .webhooks
+        .create(namespace.id, &data.url)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to create webhook");
+            KVErrors::from(err)
+        })?;
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(webhook))
+}
+
+#[instrument(skip(app_data, identity))]
+#[delete("/namespaces/{namespace}/webhooks/{id}")]
+async fn delete_webhook(
+    path: web::Path<(String, Uuid)>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<HttpResponse, KVErrors> {
+    let (namespace, id) = path.into_inner();
+    let namespace = match managed_namespace(&app_data, &identity, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(response) => return Ok(response),
+    };
+    info!(namespace = namespace.name, "deleting webhook");
+    match app_data.webhooks.delete(namespace.id, id).await {
+        Ok(true) => Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish()),
+        Ok(false) => Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to delete webhook");
+            Err(err.into())
+        }
+    }
+}
+
+// The namespace's most recent events that couldn't be delivered after every retry
+#[instrument(skip(app_data, identity))]
+#[get("/namespaces/{namespace}/webhooks/dead-letters")]
+async fn webhook_dead_letters(
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<HttpResponse, KVErrors> {
+    let namespace = match managed_namespace(&app_data, &identity, &path.into_inner()).await {
+        Ok(namespace) => namespace,
+        Err(response) => return Ok(response),
+    };
+    let dead_letters = app_data
+        .webhooks
+        .dead_letters(namespace.id)
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to list webhook dead letters");
+            KVErrors::from(err)
+        })?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(dead_letters))
+}
+
+// Only empty namespaces can be deleted, the keys have to be removed first with a prefix delete
+#[instrument(skip(app_data, identity))]
+#[delete("/namespaces/{namespace}")]
+async fn delete_namespace(
+    path: web::Path<String>,
+    app_data: web::Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = path.into_inner();
+
+    let tenant_id = identity.tenant_id();
+
+    info!(tenant_id = tenant_id.to_string(), "deleting namespace");
+
+    if !identity.has_scope(Scope::Admin) || identity.namespaces().is_some() {
+        error!("token does not allow deleting namespaces");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    let metadata = service_metadata(&app_data, &identity)?;
+
+    // every node counts its keys exactly, an estimate can miss keys that were just written
+    let request = CountKeysRequest {
+        namespace_id: namespace.id.to_string(),
+        prefix: None,
+        exact: true,
+    };
+    let results = app_data
+        .connection_manager
+        .call_all(Rpc::CountKeys, |mut client| {
+            let request = tonic::Request::from_parts(
+                metadata.clone(),
+                Extensions::default(),
+                request.clone(),
+            );
+            async move { client.count_keys(request).await }
+        })
+        .await;
+    let mut keys = 0;
+    for (node, result) in results {
+        match result {
+            Ok(response) => keys += response.into_inner().count,
+            Err(status) => {
+                error!(
+                    node = node,
+                    err = status.message(),
+                    "failed to count namespace keys"
+                );
+                return Err(status.into());
+            }
+        }
+    }
+
+    if keys > 0 {
+        error!(keys = keys, "namespace is not empty");
+        return Ok(HttpResponseBuilder::new(StatusCode::CONFLICT).finish());
+    }
+
+    // the nodes drop the namespace's partitions before its metadata goes, so a namespace whose
+    // partitions a node kept can still be deleted again. A node refuses when a key was written
+    // since it was counted.
+    let request = DeleteNamespaceRequest {
+        name: namespace.name.clone(),
+        namespace_id: namespace.id.to_string(),
+    };
+    let results = app_data
+        .connection_manager
+        .call_all(Rpc::DeleteNamespace, |mut client| {
+            let request = tonic::Request::from_parts(
+                metadata.clone(),
+                Extensions::default(),
+                request.clone(),
+            );
+            async move { client.delete_namespace(request).await }
+        })
+        .await;
+    if let Some(status) = node_failure("namespace", results) {
+        if status.code() == tonic::Code::FailedPrecondition {
+            return Ok(HttpResponseBuilder::new(StatusCode::CONFLICT).finish());
+        }
+        return Err(status.into());
+    }
+
+    match app_data.namespaces.delete(namespace.id).await {
+        Ok(()) => {
+            app_data
+                .response_cache
+                .invalidate_namespace(tenant_id, namespace.id);
+            Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to delete namespace");
+            Err(err.into())
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct NamespacesResponse {
+    namespaces: Vec<Namespace>,
+}
+
+#[instrument(skip(app_data, identity))]
+#[get("/namespaces")]
+async fn list_namespaces(
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let tenant_id = identity.tenant_id();
+
+    info!(tenant_id = tenant_id.to_string(), "fetching namespaces");
+
+    if !identity.has_scope(Scope::Read) {
+        error!("token does not have the read scope");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let namespaces = match app_data.namespaces.list(tenant_id).await {
+        // only show the namespaces the token is limited to
+        Ok(namespaces) => namespaces
+            .into_iter()
+            .filter(|namespace| identity.can_access_namespace(namespace.id))
+            .collect(),
+        Err(err) => {
+            error!(err = err.to_string());
+            return Ok(HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).finish());
+        }
+    };
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(NamespacesResponse { namespaces }))
+}
+
+#[derive(Serialize, Debug)]
+struct ListKeyMetadata {
+    name: String,
+    version: u32,
+    crc: u32,
+    // unix seconds, missing for a key that doesn't expire
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+    // only when values are listed and this one is small enough, base64 encoded when it isn't utf-8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+}
+
+#[derive(Serialize, Debug)]
+struct ListKeysResponse {
+    keys: Vec<ListKeyMetadata>,
+    // passed as start_after to get the next page, missing on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+    // passed along with next so the next page is listed as of the same point in time as this one,
+    // it expires after a minute without a page being read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot: Option<String>,
+}
+
+// None for a key that isn't utf-8, which can't be listed over http and is left out of the listing
+fn list_key_metadata(item: KeyMetadata) -> Option<ListKeyMetadata> {
+    let metadata = item.metadata.as_ref().unwrap();
+    let (value, encoding) = match item.value.map(String::from_utf8) {
+        Some(Ok(value)) => (Some(value), None),
+        Some(Err(err)) => (
+            Some(general_purpose::STANDARD.encode(err.as_bytes())),
+            Some("base64"),
+        ),
+        None => (None, None),
+    };
+
+    Some(ListKeyMetadata {
+        name: String::from_utf8(item.key)
+            .inspect_err(|err| warn!(err = err.to_string(), "skipping key that isn't utf-8"))
+            .ok()?,
+        version: metadata.version,
+        crc: metadata.crc,
+        expires_at: metadata
+            .expires_at
+            .as_ref()
+            .map(|expires_at| expires_at.seconds),
+        value,
+        encoding,
+    })
+}
+
+#[derive(Deserialize, Debug)]
+struct ListKeysQuery {
+    limit: Option<u32>,
+    start_after: Option<String>,
+    snapshot: Option<String>,
+    // list each key's value along with it, values over max_value_size bytes are left out
+    #[serde(default)]
+    include_values: bool,
+    max_value_size: Option<u32>,
+}
+
+#[instrument(skip(app_data, identity))]
+#[get("/namespaces/{namespace}/keys")]
+async fn list_keys(
+    path: web::Path<String>,
+    query: web::Query<ListKeysQuery>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = path.into_inner();
+
+    let tenant_id = identity.tenant_id();
+
+    info!(tenant_id = tenant_id.to_string(), "fetching keys");
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Read, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let metadata = service_metadata(&app_data, &identity)?;
+
+    // clamped like storage does, so a full page is recognized when more was asked for
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    let request = common::storage::ListKeysRequest {
+        namespace_id: namespace.id.to_string(),
+        limit: Some(limit),
+        start_key: query.start_after.clone().map(String::into_bytes),
+        snapshot_id: query.snapshot.clone(),
+        include_values: query.include_values,
+        max_value_size: query.max_value_size,
+    };
+    let key_span = span!(Level::INFO, "listing keys");
+    let response = match app_data
+        .connection_manager
+        .call_idempotent(
+            Rpc::ListKeys,
+            namespace.storage_node.as_deref(),
+            |mut client| {
+                let request = tonic::Request::from_parts(
+                    metadata.clone(),
+                    Extensions::default(),
+                    request.clone(),
+                );
+                async move { client.list_keys(request).await }
+            },
+        )
+        .instrument(key_span)
+        .await
+    {
+        Ok(response) => response.into_inner(),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to list keys");
+            return Err(err.into());
+        }
+    };
+
+    let snapshot = response.snapshot_id;
+    // a full page means there may be more keys after the last one
+    let full = response.keys.len() >= limit as usize;
+    let result: Vec<_> = response
+        .keys
+        .into_iter()
+        .filter_map(list_key_metadata)
+        .collect();
+
+    let next = match result.last() {
+        Some(last) if full => Some(last.name.clone()),
+        _ => None,
+    };
+
+    let snapshot = snapshot.filter(|_| next.is_some());
+    let response = ListKeysResponse {
+        keys: result,
+        next,
+        snapshot,
+    };
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(response))
+}
+
+#[derive(Deserialize, Debug)]
+struct ScanQuery {
+    // the values as stored, without the namespace's read transform
+    #[serde(default)]
+    raw: bool,
+}
+
+// One line of a scan
+#[derive(Serialize, Debug)]
+struct ScannedKey {
+    key: String,
+    // base64 encoded
+    value: String,
+    version: u32,
+    crc: u32,
+    // unix seconds, missing for a key that doesn't expire
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+}
+
+// Streams every key of the namespace with its value as json lines, all as of when the scan started.
+// The storage node reads ahead only as far as the client has read. A scan that fails partway cuts
+// the response off, so a client can't mistake it for a complete one.
+#[instrument(skip(app_data, identity))]
+#[get("/namespaces/{namespace}/scan")]
+async fn scan(
+    path: web::Path<String>,
+    query: web::Query<ScanQuery>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = path.into_inner();
+
+    let tenant_id = identity.tenant_id();
+
+    info!(tenant_id = tenant_id.to_string(), "scanning namespace");
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Read, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let metadata = service_metadata(&app_data, &identity)?;
+
+    let request = ScanRequest {
+        namespace_id: namespace.id.to_string(),
+        raw: query.raw,
+    };
+    let records = match app_data
+        .connection_manager
+        .call_idempotent(
+            Rpc::Scan,
+            namespace.storage_node.as_deref(),
+            |mut client| {
+                let request = tonic::Request::from_parts(
+                    metadata.clone(),
+                    Extensions::default(),
+                    request.clone(),
+                );
+                async move { client.scan(request).await }
+            },
+        )
+        .await
+    {
+        Ok(response) => response.into_inner(),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to scan namespace");
+            return Err(err.into());
+        }
+    };
+
+    let lines = records.map(|record| match record {
+        Ok(record) => scanned_line(record).ok_or_else(|| KVErrors::InternalServerError.into()),
+        Err(err) => {
+            error!(err = err.to_string(), "scan failed");
+            Err(actix_web::Error::from(KVErrors::from(err)))
+        }
+    });
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .content_type("application/x-ndjson")
+        .streaming(lines))
+}
+
+// None for a key that isn't utf-8, which can't be scanned over http
+fn scanned_line(record: ScanRecord) -> Option<web::Bytes> {
+    let metadata = record.metadata.unwrap_or_default();
+    let scanned = ScannedKey {
+        key: String::from_utf8(record.key)
+            .inspect_err(|err| error!(err = err.to_string(), "failed to map key"))
+            .ok()?,
+        value: general_purpose::STANDARD.encode(&record.value),
+        version: metadata.version,
+        crc: metadata.crc,
+        expires_at: metadata.expires_at.map(|expires_at| expires_at.seconds),
+    };
+    let mut line = serde_json::to_vec(&scanned).ok()?;
+    line.push(b'\n');
+    Some(line.into())
+}
+
+#[derive(Deserialize, Debug)]
+struct SampleQuery {
+    count: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+struct SampleResponse {
+    keys: Vec<ListKeyMetadata>,
+}
+
+// A handful of keys picked at random, for spot checking a namespace without listing all of it
+#[instrument(skip(app_data, identity))]
+#[get("/namespaces/{namespace}/sample")]
+async fn sample_keys(
+    path: web::Path<String>,
+    query: web::Query<SampleQuery>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = path.into_inner();
+
+    let tenant_id = identity.tenant_id();
+
+    info!(tenant_id = tenant_id.to_string(), "sampling keys");
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Read, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let metadata = service_metadata(&app_data, &identity)?;
+
+    let request = SampleKeysRequest {
+        namespace_id: namespace.id.to_string(),
+        count: query.count,
+    };
+
+    let response = match app_data
+        .connection_manager
+        .call_idempotent(
+            Rpc::SampleKeys,
+            namespace.storage_node.as_deref(),
+            |mut client| {
+                let request = tonic::Request::from_parts(
+                    metadata.clone(),
+                    Extensions::default(),
+                    request.clone(),
+                );
+                async move { client.sample_keys(request).await }
+            },
+        )
+        .await
+    {
+        Ok(response) => response.into_inner(),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to sample keys");
+            return Err(err.into());
+        }
+    };
+
+    let keys = response
+        .keys
+        .into_iter()
+        .filter_map(list_key_metadata)
+        .collect();
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(SampleResponse { keys }))
+}
+
+// The version in an If-Match header, the etag get returns, e.g. If-Match: "3". None when the
+// header is anything else, a list of etags or * included.
+fn if_match_version(value: &header::HeaderValue) -> Option<u32> {
+    value.to_str().ok()?.trim().trim_matches('"').parse().ok()
+}
+
+#[derive(Deserialize, Debug)]
+struct DeleteKeyQuery {
+    // the key's value isn't retained, and the versions the namespace retained are purged with it
+    #[serde(default)]
+    forget: bool,
+}
+
+// With If-Match the key is only deleted if it's still at the version it was read at, a 412
+// otherwise
+#[instrument(skip(app_data, identity, path, req))]
+#[delete("/namespaces/{namespace}/keys/{id}")]
+async fn delete_key(
+    req: HttpRequest,
+    query: web::Query<DeleteKeyQuery>,
+    path: web::Path<(String, String)>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let (namespace, id) = path.into_inner();
+
+    let expected_version = match req.headers().get(header::IF_MATCH) {
+        Some(value) => match if_match_version(value) {
+            Some(expected) => Some(expected),
+            None => return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish()),
+        },
+        None => None,
+    };
+
+    let tenant_id = identity.tenant_id();
+
+    info!(tenant_id = tenant_id.to_string(), "deleting key");
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Write, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let metadata = service_metadata(&app_data, &identity)?;
+
+    let request = tonic::Request::from_parts(
+        metadata,
+        Extensions::default(),
+        DeleteKeyRequest {
+            namespace_id: namespace.id.to_string(),
+            key: id.clone().into_bytes(),
+            expected_version,
+            forget: query.forget,
+        },
+    );
+
+    let deleted = app_data
+        .connection_manager
+        .call(
+            Rpc::Delete,
+            namespace.storage_node.as_deref(),
+            |mut client| async move { client.delete(request).await },
+        )
+        .await;
+    app_data
+        .response_cache
+        .invalidate_key(tenant_id, namespace.id, id.as_bytes())
+        .await;
+    match deleted {
+        Ok(_) => {
+            app_data.webhooks.notify(Event::new(
+                EventKind::Delete,
+                namespace.id,
+                &namespace.name,
+                &id,
+                None,
+            ));
+            Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
+        }
+        Err(err) if err.code() == tonic::Code::FailedPrecondition => {
+            info!(key = id, "key isn't at the expected version");
+            Err(KVErrors::PreconditionFailed(Box::new(err)))
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to delete key");
+            Err(err.into())
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Touch {
+    // the key expires this long from now, the namespace's default_ttl_secs when unset and never
+    // when neither is set
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct TouchResp {
+    version: u32,
+    crc: u32,
+    // unix seconds, missing for a key that doesn't expire
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+}
+
+// Moves when the key expires without rewriting its value, its version stays the same
+#[instrument(skip(app_data, identity, data))]
+#[post("/namespaces/{namespace}/keys/{id}/touch")]
+async fn touch(
+    path: web::Path<(String, String)>,
+    data: web::Json<Touch>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let (namespace, id) = path.into_inner();
+
+    let tenant_id = identity.tenant_id();
+
+    info!(tenant_id = tenant_id.to_string(), "touching key");
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Write, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let ttl_secs = data.ttl_secs.or(namespace.settings.default_ttl_secs);
+    if ttl_secs.is_some_and(|ttl| ttl == 0 || ttl > i64::MAX as u64) {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    }
+
+    let metadata = service_metadata(&app_data, &identity)?;
+
+    let request = TouchRequest {
+        namespace_id: namespace.id.to_string(),
+        key: id.clone().into_bytes(),
+        ttl_secs,
+    };
+
+    let touched = app_data
+        .connection_manager
+        .call_idempotent(
+            Rpc::Touch,
+            namespace.storage_node.as_deref(),
+            |mut client| {
+                let request = tonic::Request::from_parts(
+                    metadata.clone(),
+                    Extensions::default(),
+                    request.clone(),
+                );
+                async move { client.touch(request).await }
+            },
+        )
+        .await;
+    // the cached response has the key's old expiry
+    app_data
+        .response_cache
+        .invalidate_key(tenant_id, namespace.id, id.as_bytes())
+        .await;
+    match touched {
+        Ok(response) => {
+            let touched = response.into_inner();
+            Ok(HttpResponseBuilder::new(StatusCode::OK).json(TouchResp {
+                version: touched.version,
+                crc: touched.crc,
+                expires_at: touched.expires_at.map(|expires_at| expires_at.seconds),
+            }))
+        }
+        Err(err) if err.code() == tonic::Code::NotFound => {
+            info!(key = id, "no key to touch");
+            Err(err.into())
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to touch key");
+            Err(err.into())
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct DeleteKeysQuery {
+    prefix: String,
+    confirm: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct DeleteKeysResponse {
+    deleted: u64,
+    dry_run: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confirmation: Option<String>,
+}
+
+#[instrument(skip(app_data, identity))]
+#[delete("/namespaces/{namespace}/keys")]
+async fn delete_keys(
+    path: web::Path<String>,
+    query: web::Query<DeleteKeysQuery>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = path.into_inner();
+
+    if query.prefix.is_empty() {
+        return Ok(HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish());
+    }
+
+    let tenant_id = identity.tenant_id();
+
+    // a delete can't happen without first previewing how many keys it will remove
+    let dry_run = match &query.confirm {
+        Some(confirm)
+            if app_data
+                .confirmations
+                .verify(confirm, tenant_id, &namespace, &query.prefix) =>
+        {
+            false
+        }
+        Some(_) => {
+            error!("bulk delete confirmation is invalid or expired");
+            return Ok(HttpResponseBuilder::new(StatusCode::PRECONDITION_FAILED).finish());
+        }
+        None => true,
+    };
+
+    info!(
+        tenant_id = tenant_id.to_string(),
+        dry_run = dry_run,
+        "deleting keys by prefix"
+    );
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Write, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let metadata = service_metadata(&app_data, &identity)?;
+
+    let request = tonic::Request::from_parts(
+        metadata,
+        Extensions::default(),
+        DeleteRangeRequest {
+            namespace_id: namespace.id.to_string(),
+            prefix: query.prefix.clone().into_bytes(),
+            dry_run,
+        },
+    );
+
+    let deleted = app_data
+        .connection_manager
+        .call(
+            Rpc::DeleteRange,
+            namespace.storage_node.as_deref(),
+            |mut client| async move { client.delete_range(request).await },
+        )
+        .await;
+    if !dry_run {
+        app_data
+            .response_cache
+            .invalidate_namespace(tenant_id, namespace.id);
+    }
+    let response = match deleted {
+        Ok(response) => response.into_inner(),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to delete keys");
+            return Err(err.into());
+        }
+    };
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(DeleteKeysResponse {
+        deleted: response.deleted,
+        dry_run,
+        confirmation: dry_run.then(|| {
+            app_data
+                .confirmations
+                .issue(tenant_id, &namespace.name, &query.prefix)
+        }),
+    }))
+}
+
+#[derive(Serialize, Debug)]
+struct PartitionUsage {
+    id: String,
+    key_count: u64,
+    total_bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct NamespaceStatsResponse {
+    key_count: u64,
+    total_bytes: u64,
+    partitions: Vec<PartitionUsage>,
+}
+
+#[instrument(skip(app_data, identity))]
+#[get("/namespaces/{namespace}/stats")]
+async fn namespace_stats(
+    path: web::Path<String>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = path.into_inner();
+
+    let tenant_id = identity.tenant_id();
+
+    info!(tenant_id = tenant_id.to_string(), "fetching namespace stats");
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Read, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let metadata = service_metadata(&app_data, &identity)?;
+
+    let request = NamespaceStatsRequest {
+        namespace_id: namespace.id.to_string(),
+    };
+
+    let response = match app_data
+        .connection_manager
+        .call_idempotent(
+            Rpc::NamespaceStats,
+            namespace.storage_node.as_deref(),
+            |mut client| {
+                let request = tonic::Request::from_parts(
+                    metadata.clone(),
+                    Extensions::default(),
+                    request.clone(),
+                );
+                async move { client.namespace_stats(request).await }
+            },
+        )
+        .await
+    {
+        Ok(response) => response.into_inner(),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace stats");
+            return Err(err.into());
+        }
+    };
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(NamespaceStatsResponse {
+        key_count: response.key_count,
+        total_bytes: response.total_bytes,
+        partitions: response
+            .partitions
+            .into_iter()
+            .map(|partition| PartitionUsage {
+                id: partition.partition_id,
+                key_count: partition.key_count,
+                total_bytes: partition.total_bytes,
+            })
+            .collect(),
+    }))
+}
+
+#[derive(Deserialize, Debug)]
+struct CountQuery {
+    prefix: Option<String>,
+    // count every key rather than use the storage nodes' estimate
+    #[serde(default)]
+    exact: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct CountResponse {
+    count: u64,
+    // a count under a prefix is always exact
+    exact: bool,
+}
+
+// Approximate counts are cheap but include expired keys and can be off by recent overwrites and
+// deletes, exact counts read every key under the prefix
+#[instrument(skip(app_data, identity))]
+#[get("/namespaces/{namespace}/count")]
+async fn count_keys(
+    path: web::Path<String>,
+    query: web::Query<CountQuery>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    let namespace = path.into_inner();
+
+    let tenant_id = identity.tenant_id();
+
+    info!(tenant_id = tenant_id.to_string(), "counting keys");
+
+    let namespace = match app_data.namespaces.get(tenant_id, &namespace).await {
+        Ok(namespace) => namespace,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get namespace");
+            return Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish());
+        }
+    };
+
+    if !identity.allows(Scope::Read, namespace.id) {
+        error!("token does not allow the operation on the namespace");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let metadata = service_metadata(&app_data, &identity)?;
+
+    let request = CountKeysRequest {
+        namespace_id: namespace.id.to_string(),
+        prefix: query.prefix.clone().map(String::into_bytes),
+        exact: query.exact,
+    };
+
+    let response = match app_data
+        .connection_manager
+        .call_idempotent(
+            Rpc::CountKeys,
+            namespace.storage_node.as_deref(),
+            |mut client| {
+                let request = tonic::Request::from_parts(
+                    metadata.clone(),
+                    Extensions::default(),
+                    request.clone(),
+                );
+                async move { client.count_keys(request).await }
+            },
+        )
+        .await
+    {
+        Ok(response) => response.into_inner(),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to count keys");
+            return Err(err.into());
+        }
+    };
+
+    Ok(
+        HttpResponseBuilder::new(StatusCode::OK).json(CountResponse {
+            count: response.count,
+            exact: response.exact,
+        }),
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct CreateApiKeyRequest {
+    name: String,
+}
+
+#[derive(Serialize, Debug)]
+struct CreateApiKeyResponse {
+    #[serde(flatten)]
+    info: ApiKeyInfo,
+    key: ApiKey,
+}
+
+#[derive(Serialize, Debug)]
+struct ApiKeysResponse {
+    api_keys: Vec<ApiKeyInfo>,
+}
+
+// Api keys can only be managed with a bearer token so a leaked key or certificate can't be used to mint more keys.
+// A key reaches every namespace with every scope, so only admin tokens that aren't limited to a
+// set of namespaces can manage them.
+#[instrument(skip(app_data, identity))]
+#[post("/api-keys")]
+async fn create_api_key(
+    app_data: Data<AppData>,
+    data: web::Json<CreateApiKeyRequest>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    if !identity.credential().is_bearer() {
+        error!("api keys can only be managed with a bearer token");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    if !identity.has_scope(Scope::Admin) || identity.namespaces().is_some() {
+        error!("token does not allow managing api keys");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let tenant_id = identity.tenant_id();
+
+    info!(tenant_id = tenant_id.to_string(), "creating api key");
+
+    match app_data.api_keys.create(tenant_id, &data.name).await {
+        Ok((info, key)) => {
+            Ok(HttpResponseBuilder::new(StatusCode::CREATED)
+                .json(CreateApiKeyResponse { info, key }))
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            error!("tenant not found");
+            Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish())
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to create api key");
+            Err(err.into())
+        }
+    }
+}
+
+#[instrument(skip(app_data, identity))]
+#[get("/api-keys")]
+async fn list_api_keys(
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    if !identity.credential().is_bearer() {
+        error!("api keys can only be managed with a bearer token");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    if !identity.has_scope(Scope::Admin) || identity.namespaces().is_some() {
+        error!("token does not allow managing api keys");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    match app_data.api_keys.list(identity.tenant_id()).await {
+        Ok(api_keys) => {
+            Ok(HttpResponseBuilder::new(StatusCode::OK).json(ApiKeysResponse { api_keys }))
+        }
+        Err(err) => {
+            error!(err = err.to_string(), "failed to list api keys");
+            Err(err.into())
+        }
+    }
+}
+
+#[instrument(skip(req, app_data, identity))]
+#[delete("/api-keys/{id}")]
+async fn revoke_api_key(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+) -> Result<impl Responder, KVErrors> {
+    if !identity.credential().is_bearer() {
+        error!("api keys can only be managed with a bearer token");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    if !identity.has_scope(Scope::Admin) || identity.namespaces().is_some() {
+        error!("token does not allow managing api keys");
+        return Ok(HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let tenant_id = identity.tenant_id();
+
+    info!(tenant_id = tenant_id.to_string(), "revoking api key");
+
+    let id = path.into_inner();
+    match app_data.api_keys.revoke(tenant_id, id).await {
+        Ok(true) => {
+            app_data
+                .audit
+                .record(
+                    "api_key_revoked",
+                    Some(tenant_id),
+                    req.peer_addr().map(|addr| addr.ip()),
+                    Outcome::Success,
+                    Some(&id.to_string()),
+                )
+                .await;
+            Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
+        }
+        Ok(false) => Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()),
+        Err(err) => {
+            error!(err = err.to_string(), "failed to revoke api key");
+            Err(err.into())
+        }
+    }
 }
@@ -0,0 +1,449 @@
+use crate::adapter::{self, read_line, AdapterError, Put};
+use crate::auth::AuthenticatedTenant;
+use crate::config::MemcachedConfig;
+use crate::namespace::Namespace;
+use crate::AppData;
+use actix_web::rt::net::{TcpListener, TcpStream};
+use actix_web::web::Data;
+use common::auth::Scope;
+use std::io;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tracing::{info, warn};
+
+// memcached's own limits on a command line and a key
+const MAX_LINE: u64 = 2048;
+const MAX_KEY: usize = 250;
+// exptimes past 30 days are unix timestamps rather than seconds from now
+const RELATIVE_EXPTIME: i64 = 30 * 24 * 60 * 60;
+// an incr or decr racing other writes to the key is retried this many times
+const INCR_ATTEMPTS: usize = 5;
+
+// Serves memcached's text protocol so legacy cache clients can be pointed at the gateway: get,
+// gets, set, add, cas, delete, incr, decr, touch, version and quit. Flags aren't stored, they're
+// accepted and always read back as 0. The cas unique is the key's version. Every command is
+// checked and forwarded to the storage nodes like its http counterpart.
+//
+// The listener is bound before this returns, connections are accepted in the background until the
+// gateway stops.
+pub async fn memcached_endpoint(
+    bind_address: &str,
+    config: MemcachedConfig,
+    app_data: Data<AppData>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind((bind_address, config.port)).await?;
+    info!(
+        port = config.port,
+        namespace = config.namespace,
+        "serving the memcached protocol"
+    );
+    actix_web::rt::spawn(accept(listener, config, app_data));
+    Ok(())
+}
+
+async fn accept(listener: TcpListener, config: MemcachedConfig, app_data: Data<AppData>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!(
+                    err = err.to_string(),
+                    "failed to accept memcached connection"
+                );
+                continue;
+            }
+        };
+        let app_data = app_data.clone();
+        let config = config.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(err) = serve(stream, peer.ip(), &config, app_data).await {
+                info!(err = err.to_string(), "memcached connection closed");
+            }
+        });
+    }
+}
+
+// The api key is checked for every connection, so a revoked key or a suspended tenant is locked
+// out of new connections
+async fn serve(
+    stream: TcpStream,
+    source: IpAddr,
+    config: &MemcachedConfig,
+    app_data: Data<AppData>,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let identity =
+        match AuthenticatedTenant::from_secret(&app_data, &config.api_key, Some(source)).await {
+            Ok(identity) => identity,
+            Err(err) => {
+                writer
+                    .write_all(format!("SERVER_ERROR {}\r\n", err).as_bytes())
+                    .await?;
+                return Ok(());
+            }
+        };
+    let session = Session {
+        app_data,
+        identity,
+        namespace: &config.namespace,
+    };
+    let mut reader = BufReader::new(reader);
+    loop {
+        let Some(line) = read_line(&mut reader, MAX_LINE).await? else {
+            return Ok(());
+        };
+        let args: Vec<&[u8]> = line
+            .split(|byte| *byte == b' ')
+            .filter(|word| !word.is_empty())
+            .collect();
+        let Some((name, args)) = args.split_first() else {
+            writer.write_all(b"ERROR\r\n").await?;
+            continue;
+        };
+        let reply = match *name {
+            b"quit" => return Ok(()),
+            b"set" | b"add" | b"cas" => {
+                let Some(storage) = storage_command(name, args) else {
+                    writer
+                        .write_all(b"CLIENT_ERROR bad command line format\r\n")
+                        .await?;
+                    continue;
+                };
+                // there's no telling where the next command starts without reading the block, so
+                // the connection is closed
+                if storage.len > session.app_data.max_request_body {
+                    writer
+                        .write_all(b"SERVER_ERROR object too large for cache\r\n")
+                        .await?;
+                    return Ok(());
+                }
+                let value = read_block(&mut reader, storage.len).await?;
+                let reply = match value {
+                    Some(value) => session.store(storage, value).await,
+                    None => b"CLIENT_ERROR bad data chunk".to_vec(),
+                };
+                (!storage.noreply).then_some(reply)
+            }
+            _ => session.execute(name, args).await,
+        };
+        if let Some(reply) = reply {
+            writer.write_all(&reply).await?;
+            writer.write_all(b"\r\n").await?;
+        }
+    }
+}
+
+// A data block of len bytes and its \r\n, None when the block isn't terminated where it should be
+async fn read_block<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    len: usize,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut block = vec![0; len + 2];
+    reader.read_exact(&mut block).await?;
+    if !block.ends_with(b"\r\n") {
+        return Ok(None);
+    }
+    block.truncate(len);
+    Ok(Some(block))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StorageCommand {
+    Set,
+    Add,
+    Cas(u32),
+}
+
+// set, add and cas: <key> <flags> <exptime> <bytes> [cas unique] [noreply]
+#[derive(Debug, Clone, Copy)]
+struct Storage<'a> {
+    command: StorageCommand,
+    key: &'a [u8],
+    exptime: i64,
+    len: usize,
+    noreply: bool,
+}
+
+fn storage_command<'a>(name: &[u8], args: &[&'a [u8]]) -> Option<Storage<'a>> {
+    let (fixed, rest) = match name {
+        b"cas" => args.split_at_checked(5)?,
+        _ => args.split_at_checked(4)?,
+    };
+    let noreply = match rest {
+        [] => false,
+        [b"noreply"] => true,
+        _ => return None,
+    };
+    // flags have to be a number even though they aren't kept
+    parse::<u32>(fixed[1])?;
+    let command = match name {
+        b"set" => StorageCommand::Set,
+        b"add" => StorageCommand::Add,
+        // versions start at 1, so a cas unique of 0 never matches
+        _ => StorageCommand::Cas(parse::<u32>(fixed[4]).filter(|cas| *cas > 0)?),
+    };
+    Some(Storage {
+        command,
+        key: fixed[0],
+        exptime: parse(fixed[2])?,
+        len: parse(fixed[3])?,
+        noreply,
+    })
+}
+
+fn parse<T: std::str::FromStr>(value: &[u8]) -> Option<T> {
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+// The ttl an exptime stands for, None for 0 which never expires. An exptime that has already passed
+// can't be stored.
+fn ttl_secs(exptime: i64) -> Result<Option<u64>, Vec<u8>> {
+    let ttl = match exptime {
+        0 => return Ok(None),
+        ..0 => None,
+        1..=RELATIVE_EXPTIME => Some(exptime),
+        _ => Some(exptime - now()).filter(|ttl| *ttl > 0),
+    };
+    ttl.map(|ttl| Some(ttl as u64))
+        .ok_or_else(|| b"CLIENT_ERROR exptime has already passed".to_vec())
+}
+
+fn valid_key(key: &[u8]) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY && !key.iter().any(u8::is_ascii_control)
+}
+
+fn noreply(args: &[&[u8]], expected: usize) -> Option<bool> {
+    match &args[expected.min(args.len())..] {
+        _ if args.len() < expected => None,
+        [] => Some(false),
+        [b"noreply"] => Some(true),
+        _ => None,
+    }
+}
+
+struct Session<'a> {
+    app_data: Data<AppData>,
+    identity: AuthenticatedTenant,
+    namespace: &'a str,
+}
+
+impl Session<'_> {
+    // The reply without its \r\n, None when the client asked for no reply
+    async fn execute(&self, name: &[u8], args: &[&[u8]]) -> Option<Vec<u8>> {
+        let reply = match name {
+            b"get" | b"gets" if !args.is_empty() => {
+                return Some(self.get(args, name == b"gets").await);
+            }
+            b"delete" => {
+                let noreply = noreply(args, 1);
+                let reply = match noreply {
+                    Some(_) => self.delete(args[0]).await,
+                    None => b"CLIENT_ERROR bad command line format".to_vec(),
+                };
+                return (noreply != Some(true)).then_some(reply);
+            }
+            b"incr" | b"decr" => {
+                let noreply = noreply(args, 2);
+                let reply = match (noreply, args.get(1).and_then(|delta| parse::<u64>(delta))) {
+                    (Some(_), Some(delta)) => self.incr(args[0], delta, name == b"incr").await,
+                    _ => b"CLIENT_ERROR invalid numeric delta argument".to_vec(),
+                };
+                return (noreply != Some(true)).then_some(reply);
+            }
+            b"touch" => {
+                let noreply = noreply(args, 2);
+                let reply = match (noreply, args.get(1).and_then(|exptime| parse(exptime))) {
+                    (Some(_), Some(exptime)) => self.touch(args[0], exptime).await,
+                    _ => b"CLIENT_ERROR bad command line format".to_vec(),
+                };
+                return (noreply != Some(true)).then_some(reply);
+            }
+            b"version" => format!("VERSION {}", crate::VERSION).into_bytes(),
+            _ => b"ERROR".to_vec(),
+        };
+        Some(reply)
+    }
+
+    async fn namespace(&self, scope: Scope) -> Result<Namespace, Vec<u8>> {
+        adapter::namespace(&self.app_data, &self.identity, self.namespace, scope)
+            .await
+            .map_err(server_error)
+    }
+
+    // Keys that don't exist are left out, as memcached does
+    async fn get(&self, keys: &[&[u8]], with_cas: bool) -> Vec<u8> {
+        if !keys.iter().all(|key| valid_key(key)) {
+            return b"CLIENT_ERROR bad command line format".to_vec();
+        }
+        let namespace = match self.namespace(Scope::Read).await {
+            Ok(namespace) => namespace,
+            Err(reply) => return reply,
+        };
+        let mut reply = Vec::new();
+        for key in keys {
+            let found = match adapter::get(&self.app_data, &self.identity, &namespace, key).await {
+                Ok(found) => found,
+                Err(err) => return server_error(err),
+            };
+            let Some(found) = found else {
+                continue;
+            };
+            let version = found.metadata.map_or(0, |metadata| metadata.version);
+            reply.extend_from_slice(b"VALUE ");
+            reply.extend_from_slice(key);
+            match with_cas {
+                true => reply.extend_from_slice(
+                    format!(" 0 {} {}\r\n", found.value.len(), version).as_bytes(),
+                ),
+                false => {
+                    reply.extend_from_slice(format!(" 0 {}\r\n", found.value.len()).as_bytes())
+                }
+            }
+            reply.extend_from_slice(&found.value);
+            reply.extend_from_slice(b"\r\n");
+        }
+        reply.extend_from_slice(b"END");
+        reply
+    }
+
+    async fn store(&self, storage: Storage<'_>, value: Vec<u8>) -> Vec<u8> {
+        if !valid_key(storage.key) {
+            return b"CLIENT_ERROR bad command line format".to_vec();
+        }
+        let ttl_secs = match ttl_secs(storage.exptime) {
+            Ok(ttl_secs) => ttl_secs,
+            Err(reply) => return reply,
+        };
+        let namespace = match self.namespace(Scope::Write).await {
+            Ok(namespace) => namespace,
+            Err(reply) => return reply,
+        };
+        let put = Put {
+            key: storage.key.to_vec(),
+            value,
+            ttl_secs,
+            expected_version: match storage.command {
+                StorageCommand::Cas(version) => Some(version),
+                _ => None,
+            },
+            if_absent: storage.command == StorageCommand::Add,
+        };
+        match adapter::put(&self.app_data, &self.identity, &namespace, put).await {
+            Ok(_) => b"STORED".to_vec(),
+            Err(AdapterError::Exists) => b"NOT_STORED".to_vec(),
+            // a cas on a key that's gone is NOT_FOUND rather than EXISTS
+            Err(AdapterError::VersionMismatch) => {
+                match adapter::get(&self.app_data, &self.identity, &namespace, storage.key).await {
+                    Ok(Some(_)) => b"EXISTS".to_vec(),
+                    Ok(None) => b"NOT_FOUND".to_vec(),
+                    Err(err) => server_error(err),
+                }
+            }
+            Err(err) => server_error(err),
+        }
+    }
+
+    async fn delete(&self, key: &[u8]) -> Vec<u8> {
+        if !valid_key(key) {
+            return b"CLIENT_ERROR bad command line format".to_vec();
+        }
+        let namespace = match self.namespace(Scope::Write).await {
+            Ok(namespace) => namespace,
+            Err(reply) => return reply,
+        };
+        match adapter::get(&self.app_data, &self.identity, &namespace, key).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return b"NOT_FOUND".to_vec(),
+            Err(err) => return server_error(err),
+        }
+        match adapter::delete(&self.app_data, &self.identity, &namespace, key, None).await {
+            Ok(()) => b"DELETED".to_vec(),
+            Err(err) => server_error(err),
+        }
+    }
+
+    // The value has to be a decimal number. It's rewritten at the version it was read at, keeping
+    // its expiry, and read again when another write got there first. incr wraps around at 2^64
+    // and decr stops at 0, as memcached's do.
+    async fn incr(&self, key: &[u8], delta: u64, incr: bool) -> Vec<u8> {
+        if !valid_key(key) {
+            return b"CLIENT_ERROR bad command line format".to_vec();
+        }
+        let namespace = match self.namespace(Scope::Write).await {
+            Ok(namespace) => namespace,
+            Err(reply) => return reply,
+        };
+        for _ in 0..INCR_ATTEMPTS {
+            let found = match adapter::get(&self.app_data, &self.identity, &namespace, key).await {
+                Ok(Some(found)) => found,
+                Ok(None) => return b"NOT_FOUND".to_vec(),
+                Err(err) => return server_error(err),
+            };
+            let Some(current) = parse::<u64>(&found.value) else {
+                return b"CLIENT_ERROR cannot increment or decrement non-numeric value".to_vec();
+            };
+            let value = match incr {
+                true => current.wrapping_add(delta),
+                false => current.saturating_sub(delta),
+            };
+            let metadata = found.metadata.unwrap_or_default();
+            let put = Put {
+                key: key.to_vec(),
+                value: value.to_string().into_bytes(),
+                ttl_secs: metadata
+                    .expires_at
+                    .map(|expires_at| (expires_at.seconds - now()).max(1) as u64),
+                expected_version: Some(metadata.version),
+                if_absent: false,
+            };
+            match adapter::put(&self.app_data, &self.identity, &namespace, put).await {
+                Ok(_) => return value.to_string().into_bytes(),
+                Err(AdapterError::VersionMismatch) => continue,
+                Err(err) => return server_error(err),
+            }
+        }
+        b"SERVER_ERROR the key is changing too often to increment".to_vec()
+    }
+
+    async fn touch(&self, key: &[u8], exptime: i64) -> Vec<u8> {
+        if !valid_key(key) {
+            return b"CLIENT_ERROR bad command line format".to_vec();
+        }
+        let ttl_secs = match ttl_secs(exptime) {
+            Ok(ttl_secs) => ttl_secs,
+            Err(reply) => return reply,
+        };
+        let namespace = match self.namespace(Scope::Write).await {
+            Ok(namespace) => namespace,
+            Err(reply) => return reply,
+        };
+        match adapter::touch(&self.app_data, &self.identity, &namespace, key, ttl_secs).await {
+            Ok(()) => b"TOUCHED".to_vec(),
+            Err(AdapterError::NotFound) => b"NOT_FOUND".to_vec(),
+            Err(err) => server_error(err),
+        }
+    }
+}
+
+// Client errors are ones the client can fix by changing the command
+fn server_error(err: AdapterError) -> Vec<u8> {
+    match err {
+        // memcached's own reply when it can't make room for a value
+        AdapterError::LimitExceeded(exceeded) if exceeded.is_storage() => {
+            b"SERVER_ERROR out of memory storing object".to_vec()
+        }
+        AdapterError::InvalidKey(_)
+        | AdapterError::SchemaViolation
+        | AdapterError::InvalidTtl
+        | AdapterError::LimitExceeded(_) => format!("CLIENT_ERROR {}", err).into_bytes(),
+        err => format!("SERVER_ERROR {}", err).into_bytes(),
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
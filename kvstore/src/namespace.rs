@@ -1,48 +1,317 @@
+use crate::db::{optional, DbPool};
+use crate::response_cache;
+use common::key_policy::KeyPolicy;
+use common::storage::{NamespaceQuota, NamespaceRetention};
 use derive_more::Display;
-use serde::Serialize;
-use sqlx::sqlite::SqliteRow;
-use sqlx::{query, Pool, Result, Row, Sqlite};
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+use sqlx::any::AnyRow;
+use sqlx::{query, Result, Row};
+use std::sync::{PoisonError, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tracing::{error, info};
 use tracing_attributes::instrument;
 use uuid::Uuid;
 
+// Selected by every query that returns namespaces, see Namespace's From<AnyRow>
+const NAMESPACE_COLUMNS: &str = "ns.name, ns.uuid, ns.created_at, ns.description, ns.default_ttl_secs, ns.compression, ns.durability, ns.key_policy, ns.cache_ttl_secs, ns.storage_node";
+
+pub const MAX_DESCRIPTION_LEN: usize = 1024;
+pub const DEFAULT_CACHE_CAPACITY: u64 = 10_000;
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(30);
+
 #[derive(Serialize, Clone, Debug)]
 pub struct Namespace {
     pub name: String,
     pub id: Uuid,
+    // unix timestamp, 0 for namespaces created before it was recorded
+    pub created_at: i64,
+    #[serde(flatten)]
+    pub settings: NamespaceSettings,
+    #[serde(skip_serializing_if = "KeyPolicy::is_default")]
+    pub key_policy: KeyPolicy,
+    // the storage node holding the namespace's keys, None for namespaces created before nodes were
+    // recorded, which are on the first node, see ConnectionManager::call
+    #[serde(skip)]
+    pub storage_node: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    #[default]
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Snappy => "snappy",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    fn parse(compression: &str) -> Compression {
+        match compression {
+            "none" => Compression::None,
+            "lz4" => Compression::Lz4,
+            "zstd" => Compression::Zstd,
+            _ => Compression::Snappy,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    // acknowledged once the write is in the storage node's write ahead log
+    #[default]
+    Buffered,
+    // acknowledged once the write ahead log is synced to disk
+    Sync,
+}
+
+impl Durability {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Durability::Buffered => "buffered",
+            Durability::Sync => "sync",
+        }
+    }
+
+    fn parse(durability: &str) -> Durability {
+        match durability {
+            "sync" => Durability::Sync,
+            _ => Durability::Buffered,
+        }
+    }
+}
+
+// What a namespace is for and how it's stored, e.g.
+// {"description": "session cache", "default_ttl_secs": 3600, "compression": "zstd"}
+// Compression and durability are recorded with the namespace, the storage nodes don't apply them
+// yet.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NamespaceSettings {
+    pub description: Option<String>,
+    // applied to keys written without their own ttl, keys are kept until deleted when unset
+    pub default_ttl_secs: Option<u64>,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub durability: Durability,
+    // gets are cached by the gateway for this long when set, see response_cache
+    pub cache_ttl_secs: Option<u64>,
+}
+
+impl NamespaceSettings {
+    // Ttls are stored as signed integers, and a ttl of 0 would expire every key as it's written
+    pub fn is_valid(&self) -> bool {
+        self.description
+            .as_ref()
+            .is_none_or(|description| description.len() <= MAX_DESCRIPTION_LEN)
+            && self
+                .default_ttl_secs
+                .is_none_or(|ttl| ttl > 0 && ttl <= i64::MAX as u64)
+            && self
+                .cache_ttl_secs
+                .is_none_or(|ttl| ttl > 0 && ttl <= response_cache::MAX_TTL_SECS)
+    }
+
+    // Bound as an empty string or -1 when unset and stored as null, see db::DbPool
+    fn bind_values(&self) -> (&str, i64, &'static str, &'static str, i64) {
+        (
+            self.description.as_deref().unwrap_or_default(),
+            self.default_ttl_secs.map_or(-1, |ttl| ttl as i64),
+            self.compression.as_str(),
+            self.durability.as_str(),
+            self.cache_ttl_secs.map_or(-1, |ttl| ttl as i64),
+        )
+    }
+}
+
+// The limits the storage nodes enforce on a namespace, unset limits are unlimited
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_keys: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl From<Quota> for NamespaceQuota {
+    fn from(value: Quota) -> Self {
+        NamespaceQuota {
+            max_keys: value.max_keys,
+            max_bytes: value.max_bytes,
+        }
+    }
+}
+
+impl From<AnyRow> for Quota {
+    fn from(row: AnyRow) -> Self {
+        Quota {
+            max_keys: optional::<i64>(&row, 0).map(|max_keys| max_keys as u64),
+            max_bytes: optional::<i64>(&row, 1).map(|max_bytes| max_bytes as u64),
+        }
+    }
+}
+
+impl Quota {
+    // Bound as -1 when unlimited and stored as null, see db::DbPool
+    fn bind_values(&self) -> (i64, i64) {
+        (
+            self.max_keys.map_or(-1, |max_keys| max_keys as i64),
+            self.max_bytes.map_or(-1, |max_bytes| max_bytes as i64),
+        )
+    }
+}
+
+// How long the storage nodes keep a namespace's deleted and replaced values before purging them,
+// unset keeps nothing
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub deleted_secs: Option<u64>,
+    pub versions_secs: Option<u64>,
+}
+
+impl From<Retention> for NamespaceRetention {
+    fn from(value: Retention) -> Self {
+        NamespaceRetention {
+            deleted_secs: value.deleted_secs,
+            versions_secs: value.versions_secs,
+        }
+    }
+}
+
+impl From<AnyRow> for Retention {
+    fn from(row: AnyRow) -> Self {
+        Retention {
+            deleted_secs: optional::<i64>(&row, 0).map(|secs| secs as u64),
+            versions_secs: optional::<i64>(&row, 1).map(|secs| secs as u64),
+        }
+    }
+}
+
+impl Retention {
+    // Bound like the quota's limits
+    fn bind_values(&self) -> (i64, i64) {
+        (
+            self.deleted_secs.map_or(-1, |secs| secs as i64),
+            self.versions_secs.map_or(-1, |secs| secs as i64),
+        )
+    }
+}
+
+// Stored as null when every key follows it, see db::DbPool
+fn key_policy_json(key_policy: &KeyPolicy) -> String {
+    if key_policy.is_default() {
+        return String::new();
+    }
+    serde_json::to_string(key_policy).unwrap_or_default()
 }
 
 impl std::fmt::Display for Namespace {
-fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{{ name: {}, id: {} }}", self.name, self.id)
     }
 }
 
-impl From<SqliteRow> for Namespace {
-    fn from(row: SqliteRow) -> Self {
+impl From<AnyRow> for Namespace {
+    fn from(row: AnyRow) -> Self {
         Namespace {
             name: row.get(0),
             id: Uuid::parse_str(row.get(1)).unwrap(),
+            created_at: row.get(2),
+            settings: NamespaceSettings {
+                description: optional(&row, 3),
+                default_ttl_secs: optional::<i64>(&row, 4).map(|ttl| ttl as u64),
+                compression: Compression::parse(row.get(5)),
+                durability: Durability::parse(row.get(6)),
+                cache_ttl_secs: optional::<i64>(&row, 8).map(|ttl| ttl as u64),
+            },
+            key_policy: optional::<String>(&row, 7)
+                .and_then(|policy| serde_json::from_str(&policy).ok())
+                .unwrap_or_default(),
+            storage_node: optional(&row, 9),
         }
     }
 }
 
+// Namespaces looked up by tenant and name, which every key operation does. Changes made through
+// this gateway invalidate the entries they affect, changes made through another gateway are seen
+// once the entry expires.
+type NamespaceCache = Cache<(Uuid, String), Namespace>;
+
+fn namespace_cache(capacity: u64, ttl: Duration) -> NamespaceCache {
+    Cache::builder()
+        .max_capacity(capacity)
+        .time_to_live(ttl)
+        .support_invalidation_closures()
+        .build()
+}
+
 pub struct NamespaceRepo {
-    db_pool: Pool<Sqlite>,
+    db_pool: DbPool,
+    // replaced when the configuration is reloaded with a new size
+    cache: RwLock<NamespaceCache>,
 }
 
 impl NamespaceRepo {
-    pub fn new(db_pool: Pool<Sqlite>) -> NamespaceRepo {
-        NamespaceRepo { db_pool }
+    pub fn new(db_pool: DbPool) -> NamespaceRepo {
+        NamespaceRepo {
+            db_pool,
+            cache: RwLock::new(namespace_cache(DEFAULT_CACHE_CAPACITY, DEFAULT_CACHE_TTL)),
+        }
+    }
+
+    // A capacity of 0 turns the cache off
+    pub fn with_cache(self, capacity: u64, ttl: Duration) -> NamespaceRepo {
+        self.set_cache(capacity, ttl);
+        self
     }
+
+    // Starts over with an empty cache of the new size
+    pub fn set_cache(&self, capacity: u64, ttl: Duration) {
+        if let Ok(mut cache) = self.cache.write() {
+            *cache = namespace_cache(capacity, ttl);
+        }
+    }
+
+    fn cache(&self) -> NamespaceCache {
+        self.cache
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn invalidate(
+        &self,
+        matches: impl Fn(&(Uuid, String), &Namespace) -> bool + Send + Sync + 'static,
+    ) {
+        if let Err(err) = self.cache().invalidate_entries_if(matches) {
+            error!(
+                err = err.to_string(),
+                "failed to invalidate cached namespaces"
+            );
+        }
+    }
+
+    // Drops the tenant's namespaces from the cache, e.g. once the tenant is purged
+    pub fn invalidate_tenant(&self, tenant_id: Uuid) {
+        self.invalidate(move |(tenant, _), _| *tenant == tenant_id);
+    }
+
     pub async fn exists(&self, tenant: Uuid, namespace: &str) -> bool {
-        match query("select exists(select * from namespaces left join tenants on namespaces.tenant_id = tenants.id where tenants.uuid = ? and namespaces.name = ?)")
+        match query("select 1 from namespaces left join tenants on namespaces.tenant_id = tenants.id where tenants.uuid = $1 and namespaces.name = $2")
             .bind(tenant.to_string())
             .bind(&namespace)
-            .map(|sqlite_row: SqliteRow| sqlite_row.get(0))
-            .fetch_one(&self.db_pool)
+            .fetch_optional(&self.db_pool)
             .await {
-            Ok(exists) => exists,
+            Ok(namespace) => namespace.is_some(),
             Err(err) => {
                 error!(err = err.to_string(), "failed to determine if namespace exists");
                 false
@@ -50,20 +319,171 @@ impl NamespaceRepo {
         }
     }
 
+    // Only namespaces that exist are cached, so a newly created namespace is found right away
     #[instrument(skip(self))]
     pub async fn get(&self, tenant_id: Uuid, namespace: &str) -> Result<Namespace> {
+        let key = (tenant_id, namespace.to_string());
+        let cache = self.cache();
+        if let Some(cached) = cache.get(&key).await {
+            return Ok(cached);
+        }
         info!("getting namespace");
-        query("select ns.name, ns.uuid from namespaces as ns join tenants on ns.tenant_id = tenants.id where tenants.uuid = ? and ns.name = ?")
+        let found: Namespace = query(&format!("select {} from namespaces as ns join tenants on ns.tenant_id = tenants.id where tenants.uuid = $1 and ns.name = $2", NAMESPACE_COLUMNS))
             .bind(tenant_id.to_string())
             .bind(namespace)
-            .map(|row: SqliteRow| row.into())
-            .fetch_one(&self.db_pool).await
+            .map(|row: AnyRow| row.into())
+            .fetch_one(&self.db_pool).await?;
+        cache.insert(key, found.clone()).await;
+        Ok(found)
+    }
+
+    // Lists the namespaces of every tenant along with the owning tenant's name
+    pub async fn list_all(&self) -> Result<Vec<(String, Namespace)>> {
+        query(&format!("select {}, tenants.name from namespaces as ns inner join tenants on ns.tenant_id = tenants.id order by tenants.name, ns.name", NAMESPACE_COLUMNS))
+            .map(|row: AnyRow| (row.get(10), row.into()))
+            .fetch_all(&self.db_pool).await
+    }
+
+    // Fails with a unique constraint violation when the tenant already has a namespace with the name
+    pub async fn create(
+        &self,
+        tenant_id: Uuid,
+        name: &str,
+        quota: &Quota,
+        settings: &NamespaceSettings,
+        key_policy: &KeyPolicy,
+        storage_node: Option<&str>,
+    ) -> Result<Namespace> {
+        let id = Uuid::new_v4();
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0);
+        let (max_keys, max_bytes) = quota.bind_values();
+        let (description, default_ttl_secs, compression, durability, cache_ttl_secs) =
+            settings.bind_values();
+        let result = query("insert into namespaces (name, uuid, tenant_id, max_keys, max_bytes, created_at, description, default_ttl_secs, compression, durability, key_policy, cache_ttl_secs, storage_node) select $1, $2, id, nullif($4, -1), nullif($5, -1), $6, nullif($7, ''), nullif($8, -1), $9, $10, nullif($11, ''), nullif($12, -1), nullif($13, '') from tenants where uuid = $3")
+            .bind(name)
+            .bind(id.to_string())
+            .bind(tenant_id.to_string())
+            .bind(max_keys)
+            .bind(max_bytes)
+            .bind(created_at)
+            .bind(description)
+            .bind(default_ttl_secs)
+            .bind(compression)
+            .bind(durability)
+            .bind(key_policy_json(key_policy))
+            .bind(cache_ttl_secs)
+            .bind(storage_node.unwrap_or_default())
+            .execute(&self.db_pool)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(Namespace {
+            name: name.to_string(),
+            id,
+            created_at,
+            settings: settings.clone(),
+            key_policy: key_policy.clone(),
+            storage_node: storage_node.map(str::to_string),
+        })
+    }
+
+    pub async fn set_key_policy(&self, namespace_id: Uuid, key_policy: &KeyPolicy) -> Result<()> {
+        query("update namespaces set key_policy = nullif($1, '') where uuid = $2")
+            .bind(key_policy_json(key_policy))
+            .bind(namespace_id.to_string())
+            .execute(&self.db_pool)
+            .await?;
+        self.invalidate(move |_, namespace| namespace.id == namespace_id);
+        Ok(())
+    }
+
+    pub async fn set_settings(
+        &self,
+        namespace_id: Uuid,
+        settings: &NamespaceSettings,
+    ) -> Result<()> {
+        let (description, default_ttl_secs, compression, durability, cache_ttl_secs) =
+            settings.bind_values();
+        query("update namespaces set description = nullif($1, ''), default_ttl_secs = nullif($2, -1), compression = $3, durability = $4, cache_ttl_secs = nullif($5, -1) where uuid = $6")
+            .bind(description)
+            .bind(default_ttl_secs)
+            .bind(compression)
+            .bind(durability)
+            .bind(cache_ttl_secs)
+            .bind(namespace_id.to_string())
+            .execute(&self.db_pool)
+            .await?;
+        self.invalidate(move |_, namespace| namespace.id == namespace_id);
+        Ok(())
+    }
+
+    pub async fn quota(&self, namespace_id: Uuid) -> Result<Quota> {
+        query("select max_keys, max_bytes from namespaces where uuid = $1")
+            .bind(namespace_id.to_string())
+            .map(|row: AnyRow| row.into())
+            .fetch_one(&self.db_pool)
+            .await
+    }
+
+    pub async fn set_quota(&self, namespace_id: Uuid, quota: &Quota) -> Result<()> {
+        let (max_keys, max_bytes) = quota.bind_values();
+        query("update namespaces set max_keys = nullif($1, -1), max_bytes = nullif($2, -1) where uuid = $3")
+            .bind(max_keys)
+            .bind(max_bytes)
+            .bind(namespace_id.to_string())
+            .execute(&self.db_pool)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn retention(&self, namespace_id: Uuid) -> Result<Retention> {
+        query("select retain_deleted_secs, retain_versions_secs from namespaces where uuid = $1")
+            .bind(namespace_id.to_string())
+            .map(|row: AnyRow| row.into())
+            .fetch_one(&self.db_pool)
+            .await
+    }
+
+    pub async fn set_retention(&self, namespace_id: Uuid, retention: &Retention) -> Result<()> {
+        let (deleted_secs, versions_secs) = retention.bind_values();
+        query("update namespaces set retain_deleted_secs = nullif($1, -1), retain_versions_secs = nullif($2, -1) where uuid = $3")
+            .bind(deleted_secs)
+            .bind(versions_secs)
+            .bind(namespace_id.to_string())
+            .execute(&self.db_pool)
+            .await
+            .map(|_| ())
+    }
+
+    // Also deletes the namespace's webhooks and their dead letters
+    pub async fn delete(&self, namespace_id: Uuid) -> Result<()> {
+        let mut tx = self.db_pool.begin().await?;
+        let webhooks = "(select w.id from webhooks as w inner join namespaces as ns on w.namespace_id = ns.id where ns.uuid = $1)";
+        query(&format!("delete from webhook_dead_letters where webhook_id in {}", webhooks))
+            .bind(namespace_id.to_string())
+            .execute(&mut *tx)
+            .await?;
+        query("delete from webhooks where namespace_id = (select id from namespaces where uuid = $1)")
+            .bind(namespace_id.to_string())
+            .execute(&mut *tx)
+            .await?;
+        query("delete from namespaces where uuid = $1")
+            .bind(namespace_id.to_string())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        self.invalidate(move |_, namespace| namespace.id == namespace_id);
+        Ok(())
     }
 
     pub async fn list(&self, tenant_id: Uuid) -> Result<Vec<Namespace>> {
-        query("select ns.name, ns.uuid from namespaces as ns inner join tenants on ns.tenant_id = tenants.id where tenants.uuid = ?")
+        query(&format!("select {} from namespaces as ns inner join tenants on ns.tenant_id = tenants.id where tenants.uuid = $1 order by ns.name", NAMESPACE_COLUMNS))
             .bind(tenant_id.to_string())
-            .map(|row: SqliteRow| row.into())
+            .map(|row: AnyRow| row.into())
             .fetch_all(&self.db_pool).await
     }
 }
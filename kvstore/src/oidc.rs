@@ -0,0 +1,194 @@
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+pub const DEFAULT_TENANT_CLAIM: &str = "tenant";
+
+// an unknown kid triggers a jwks refresh, but not more often than this so forged tokens can't hammer the provider
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// only asymmetric algorithms, a provider's public keys must never be usable as an hmac secret
+const ALLOWED_ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to fetch oidc metadata: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("discovery document is for another issuer: {0}")]
+    IssuerMismatch(String),
+}
+
+#[derive(Deserialize, Debug)]
+struct ProviderMetadata {
+    issuer: String,
+    jwks_uri: String,
+}
+
+// Validates tokens issued by an external OpenID Connect provider. The provider's signing keys are
+// found through discovery, and the tenant is read from a configurable claim holding either the
+// tenant's name or uuid.
+#[derive(Debug)]
+pub struct OidcValidator {
+    issuer: String,
+    audience: String,
+    tenant_claim: String,
+    jwks_uri: String,
+    client: reqwest::Client,
+    jwks: RwLock<JwkSet>,
+    last_refresh: Mutex<Instant>,
+}
+
+// The tenant a federated token maps to
+#[derive(Debug, PartialEq, Eq)]
+pub enum TenantClaim {
+    Name(String),
+    Uuid(uuid::Uuid),
+}
+
+impl OidcValidator {
+    pub async fn discover(
+        issuer: impl Into<String>,
+        audience: impl Into<String>,
+        tenant_claim: impl Into<String>,
+    ) -> Result<OidcValidator, Error> {
+        let issuer = issuer.into();
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        let metadata: ProviderMetadata = client
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                issuer.trim_end_matches('/')
+            ))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if metadata.issuer != issuer {
+            return Err(Error::IssuerMismatch(metadata.issuer));
+        }
+
+        let jwks = fetch_jwks(&client, &metadata.jwks_uri).await?;
+        info!(
+            issuer = issuer,
+            keys = jwks.keys.len(),
+            "loaded oidc provider keys"
+        );
+
+        Ok(OidcValidator {
+            issuer,
+            audience: audience.into(),
+            tenant_claim: tenant_claim.into(),
+            jwks_uri: metadata.jwks_uri,
+            client,
+            jwks: RwLock::new(jwks),
+            last_refresh: Mutex::new(Instant::now()),
+        })
+    }
+
+    // Returns the tenant the token was issued for, or None if the token isn't valid
+    pub async fn validate(&self, token: &str) -> Option<TenantClaim> {
+        let header = decode_header(token).ok()?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            warn!(alg = ?header.alg, "rejecting oidc token with disallowed algorithm");
+            return None;
+        }
+        let kid = header.kid?;
+
+        let key = match self.decoding_key(&kid) {
+            Some(key) => key,
+            None => {
+                // the provider may have rotated its keys
+                self.refresh().await;
+                self.decoding_key(&kid)?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[self.issuer.as_str()]);
+        validation.set_audience(&[self.audience.as_str()]);
+        validation.required_spec_claims =
+            HashSet::from(["exp".to_string(), "iss".to_string(), "aud".to_string()]);
+
+        let claims = match decode::<HashMap<String, Value>>(token, &key, &validation) {
+            Ok(token) => token.claims,
+            Err(err) => {
+                warn!(err = err.to_string(), "invalid oidc token");
+                return None;
+            }
+        };
+
+        let Some(Value::String(tenant)) = claims.get(&self.tenant_claim) else {
+            warn!(claim = self.tenant_claim, "oidc token has no tenant claim");
+            return None;
+        };
+        Some(match uuid::Uuid::parse_str(tenant) {
+            Ok(uuid) => TenantClaim::Uuid(uuid),
+            Err(_) => TenantClaim::Name(tenant.clone()),
+        })
+    }
+
+    fn decoding_key(&self, kid: &str) -> Option<DecodingKey> {
+        let jwks = self.jwks.read().ok()?;
+        let jwk = jwks.find(kid)?;
+        if let AlgorithmParameters::OctetKey(_) = jwk.algorithm {
+            return None;
+        }
+        DecodingKey::from_jwk(jwk).ok()
+    }
+
+    async fn refresh(&self) {
+        {
+            let Ok(mut last_refresh) = self.last_refresh.lock() else {
+                return;
+            };
+            if last_refresh.elapsed() < MIN_REFRESH_INTERVAL {
+                return;
+            }
+            *last_refresh = Instant::now();
+        }
+
+        match fetch_jwks(&self.client, &self.jwks_uri).await {
+            Ok(jwks) => {
+                info!(keys = jwks.keys.len(), "refreshed oidc provider keys");
+                if let Ok(mut current) = self.jwks.write() {
+                    *current = jwks;
+                }
+            }
+            Err(err) => error!(
+                err = err.to_string(),
+                "failed to refresh oidc provider keys"
+            ),
+        }
+    }
+}
+
+async fn fetch_jwks(client: &reqwest::Client, jwks_uri: &str) -> Result<JwkSet, Error> {
+    Ok(client
+        .get(jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
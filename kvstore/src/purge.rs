@@ -0,0 +1,117 @@
+use crate::tenant::Tenant;
+use crate::AppData;
+use actix_web::web::Data;
+use common::auth::AuthHeader;
+use common::storage::DeleteTenantRequest;
+use serde::Serialize;
+use std::time::Duration;
+use thiserror::Error;
+use tonic::metadata::MetadataMap;
+use tonic::Extensions;
+use tracing::{error, info};
+
+// Deleting a tenant's partitions includes removing their files from disk
+const DELETE_TENANT_TIMEOUT: Duration = Duration::from_secs(60);
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Debug)]
+pub struct NodeFailure {
+    pub node: String,
+    pub error: String,
+}
+
+#[derive(Error, Debug)]
+pub enum PurgeError {
+    #[error("failed to issue service token")]
+    Token(#[source] jsonwebtoken::errors::Error),
+
+    #[error("failed to delete the tenant's partitions on {} nodes", .0.len())]
+    Nodes(Vec<NodeFailure>),
+
+    #[error("failed to delete the tenant")]
+    Database(#[source] sqlx::Error),
+}
+
+// Deletes the tenant's partitions from every storage node and then the tenant and its namespaces,
+// api keys, and certificate mappings. If a node can't be reached nothing is removed from the
+// database and the purge can be retried. Returns the number of partitions deleted.
+pub async fn purge(app_data: &AppData, tenant: &Tenant) -> Result<u32, PurgeError> {
+    let metadata: MetadataMap = match app_data.jwts.new_operator_identity() {
+        Ok(identity) => AuthHeader::from(identity.token()).into(),
+        Err(err) => return Err(PurgeError::Token(err)),
+    };
+    let request = DeleteTenantRequest {
+        tenant_id: tenant.uuid.to_string(),
+    };
+    let results = app_data
+        .connection_manager
+        .call_admin_all(DELETE_TENANT_TIMEOUT, |mut client| {
+            let request = tonic::Request::from_parts(
+                metadata.clone(),
+                Extensions::default(),
+                request.clone(),
+            );
+            async move { client.delete_tenant(request).await }
+        })
+        .await;
+
+    let mut partitions_deleted = 0;
+    let mut failed = Vec::new();
+    for (node, result) in results {
+        match result {
+            Ok(response) => partitions_deleted += response.into_inner().partitions_deleted,
+            Err(status) => {
+                error!(
+                    node = node,
+                    err = status.message(),
+                    "failed to delete tenant partitions"
+                );
+                failed.push(NodeFailure {
+                    node,
+                    error: status.message().to_string(),
+                });
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(PurgeError::Nodes(failed));
+    }
+
+    app_data
+        .tenants
+        .delete(&tenant.name)
+        .await
+        .map_err(PurgeError::Database)?;
+    app_data.namespaces.invalidate_tenant(tenant.uuid);
+    Ok(partitions_deleted)
+}
+
+// Purges deleted tenants once their grace period has passed. A failed purge is retried on the next
+// pass. Every gateway runs this, a tenant purged by two at once is deleted twice, which is harmless.
+pub async fn purge_deleted(app_data: Data<AppData>) {
+    let mut ticker = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let tenants = match app_data.tenants.pending_purge().await {
+            Ok(tenants) => tenants,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to list tenants to purge");
+                continue;
+            }
+        };
+        for tenant in tenants {
+            match purge(&app_data, &tenant).await {
+                Ok(partitions_deleted) => info!(
+                    tenant = tenant.name.as_ref(),
+                    partitions_deleted = partitions_deleted,
+                    "purged deleted tenant"
+                ),
+                Err(err) => error!(
+                    tenant = tenant.name.as_ref(),
+                    err = err.to_string(),
+                    "failed to purge deleted tenant"
+                ),
+            }
+        }
+    }
+}
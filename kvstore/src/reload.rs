@@ -0,0 +1,257 @@
+use crate::audit::Outcome;
+use crate::config::GatewayConfig;
+use crate::retry::Rpc;
+use crate::tls::ServerCert;
+use crate::AppData;
+use actix_web::web::Data;
+use common::config::{self, Changes, Config};
+use common::logging::LogLevel;
+use std::sync::{Arc, Mutex};
+use tracing::{error, info, warn};
+
+// Rereads the configuration on SIGHUP and applies the settings that can change while the gateway is
+// running: the log level, login throttling, the namespace and response cache sizes, storage
+// timeouts and retry policies, and the https certificate. Changes to anything else are logged as
+// needing a restart.
+pub struct Reloader {
+    app_data: Data<AppData>,
+    log_level: LogLevel,
+    // only set when the https listener is running
+    server_cert: Option<Arc<ServerCert>>,
+    running: Mutex<GatewayConfig>,
+}
+
+impl Reloader {
+    pub fn new(
+        app_data: Data<AppData>,
+        config: GatewayConfig,
+        log_level: LogLevel,
+        server_cert: Option<Arc<ServerCert>>,
+    ) -> Reloader {
+        Reloader {
+            app_data,
+            log_level,
+            server_cert,
+            running: Mutex::new(config),
+        }
+    }
+
+    pub async fn watch(self) {
+        let reloader = Arc::new(self);
+        config::reload_on_hangup(move || {
+            let reloader = reloader.clone();
+            async move { reloader.reload().await }
+        })
+        .await
+    }
+
+    async fn reload(&self) {
+        let config = match Config::load("KVSTORE").and_then(|config| GatewayConfig::load(&config)) {
+            Ok(config) => config,
+            Err(err) => {
+                error!(
+                    err = err.to_string(),
+                    "invalid configuration, keeping the current settings"
+                );
+                self.app_data
+                    .audit
+                    .record(
+                        "config_reloaded",
+                        None,
+                        None,
+                        Outcome::Failure,
+                        Some(&err.to_string()),
+                    )
+                    .await;
+                return;
+            }
+        };
+
+        let changes = self.apply(config);
+        if !changes.requires_restart().is_empty() {
+            warn!(
+                settings = changes.requires_restart().join(", "),
+                "changed settings only take effect after a restart"
+            );
+        }
+        let detail = changes.applied().join("; ");
+        info!(changes = detail, "reloaded configuration");
+        self.app_data
+            .audit
+            .record(
+                "config_reloaded",
+                None,
+                None,
+                Outcome::Success,
+                Some(detail.as_str()).filter(|detail| !detail.is_empty()),
+            )
+            .await;
+    }
+
+    fn apply(&self, config: GatewayConfig) -> Changes {
+        let mut changes = Changes::default();
+        let Ok(mut running) = self.running.lock() else {
+            return changes;
+        };
+
+        if changes.apply("log_level", &running.log_level, &config.log_level) {
+            if let Err(err) = self.log_level.set(config.log_level) {
+                error!(err = err.to_string(), "failed to change the log level");
+            }
+            running.log_level = config.log_level;
+        }
+
+        if changes.apply("login", &running.login, &config.login) {
+            self.app_data.login_throttle.set_limits(config.login);
+            running.login = config.login;
+        }
+
+        // a resized cache starts out empty, both settings are recorded when both change
+        let capacity_changed = changes.apply(
+            "namespace_cache_capacity",
+            &running.namespace_cache_capacity,
+            &config.namespace_cache_capacity,
+        );
+        let ttl_changed = changes.apply(
+            "namespace_cache_ttl_secs",
+            &running.namespace_cache_ttl,
+            &config.namespace_cache_ttl,
+        );
+        if capacity_changed || ttl_changed {
+            self.app_data
+                .namespaces
+                .set_cache(config.namespace_cache_capacity, config.namespace_cache_ttl);
+            running.namespace_cache_capacity = config.namespace_cache_capacity;
+            running.namespace_cache_ttl = config.namespace_cache_ttl;
+        }
+
+        if changes.apply(
+            "response_cache_mb",
+            &running.response_cache_capacity,
+            &config.response_cache_capacity,
+        ) {
+            self.app_data
+                .response_cache
+                .set_capacity(config.response_cache_capacity);
+            running.response_cache_capacity = config.response_cache_capacity;
+        }
+
+        let connection_manager = &self.app_data.connection_manager;
+        for rpc in Rpc::all() {
+            let key = format!("timeout_{}_ms", rpc.env_name().to_lowercase());
+            let timeout = config.storage.timeouts.get(&rpc);
+            if changes.apply(&key, &running.storage.timeouts.get(&rpc), &timeout) {
+                if let Some(timeout) = timeout {
+                    connection_manager.set_timeout(rpc, *timeout);
+                }
+            }
+        }
+        for rpc in Rpc::idempotent() {
+            let key = format!("retry_{}", rpc.env_name().to_lowercase());
+            let policy = config.storage.retry_policies.get(&rpc);
+            if changes.apply(&key, &running.storage.retry_policies.get(&rpc), &policy) {
+                if let Some(policy) = policy {
+                    connection_manager.set_retry_policy(rpc, *policy);
+                }
+            }
+        }
+        running.storage.timeouts = config.storage.timeouts.clone();
+        running.storage.retry_policies = config.storage.retry_policies.clone();
+
+        // the certificate is reread even when its path is unchanged since renewals usually replace the file
+        if let (Some(server_cert), Some(tls)) = (&self.server_cert, &config.tls) {
+            match server_cert.reload(&tls.cert, &tls.key) {
+                Ok(()) => changes.note(format!("tls_cert: reloaded {}", tls.cert)),
+                Err(err) => error!(
+                    err = err.to_string(),
+                    cert = tls.cert,
+                    "failed to reload the tls certificate, keeping the current one"
+                ),
+            }
+        }
+
+        let tls_enabled = |config: &GatewayConfig| config.tls.is_some();
+        let client_ca =
+            |config: &GatewayConfig| config.tls.as_ref().map(|tls| tls.client_ca.clone());
+        changes.restart("tls_cert", &tls_enabled(&running), &tls_enabled(&config));
+        changes.restart("tls_client_ca", &client_ca(&running), &client_ca(&config));
+        changes.restart("bind_address", &running.bind_address, &config.bind_address);
+        changes.restart("http_port", &running.http_port, &config.http_port);
+        changes.restart("health_port", &running.health_port, &config.health_port);
+        changes.restart("admin_port", &running.admin_port, &config.admin_port);
+        changes.restart("tls_port", &running.tls_port, &config.tls_port);
+        changes.restart("resp_port", &running.resp_port, &config.resp_port);
+        changes.restart("memcached", &running.memcached, &config.memcached);
+        changes.restart("database", &running.database, &config.database);
+        changes.restart("admin_token", &running.admin_token, &config.admin_token);
+        changes.restart(
+            "dev_tenant_password",
+            &running.dev_tenant_password,
+            &config.dev_tenant_password,
+        );
+        changes.restart("jwt", &running.jwt, &config.jwt);
+        changes.restart("password", &running.password, &config.password);
+        changes.restart("oidc", &running.oidc, &config.oidc);
+        changes.restart(
+            "tenant_deletion_grace_secs",
+            &running.deletion_grace,
+            &config.deletion_grace,
+        );
+        changes.restart(
+            "default_consistency",
+            &running.default_consistency,
+            &config.default_consistency,
+        );
+        changes.restart(
+            "max_request_body_bytes",
+            &running.max_request_body,
+            &config.max_request_body,
+        );
+        changes.restart(
+            "storage_warning_percent",
+            &running.storage_warning_percent,
+            &config.storage_warning_percent,
+        );
+        changes.restart(
+            "storage_nodes",
+            &running.storage.nodes,
+            &config.storage.nodes,
+        );
+        changes.restart(
+            "storage_tls_config",
+            &running.storage.tls_config,
+            &config.storage.tls_config,
+        );
+        changes.restart(
+            "storage_admin_port",
+            &running.storage.admin_port,
+            &config.storage.admin_port,
+        );
+        changes.restart(
+            "storage_discovery_secs",
+            &running.storage.discovery_interval,
+            &config.storage.discovery_interval,
+        );
+        changes.restart(
+            "storage_health_interval_secs",
+            &running.storage.health_interval,
+            &config.storage.health_interval,
+        );
+        changes.restart(
+            "breaker_failure_threshold",
+            &running.storage.breaker_failure_threshold,
+            &config.storage.breaker_failure_threshold,
+        );
+        changes.restart(
+            "breaker_open_secs",
+            &running.storage.breaker_open_duration,
+            &config.storage.breaker_open_duration,
+        );
+        changes.restart(
+            "retry_budget_ratio",
+            &running.storage.retry_budget_ratio,
+            &config.storage.retry_budget_ratio,
+        );
+        changes
+    }
+}
@@ -0,0 +1,71 @@
+use crate::db::DbPool;
+use sqlx::query;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::error;
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+// A sqlite gateway keeps a copy of its database at sqlite_replica_path, e.g. on a network volume or
+// a disk that outlives the host, so losing the host loses at most one interval of metadata changes.
+// A gateway started without a database restores it from the replica. Postgres is replicated by the
+// database server instead, e.g. with streaming replication to a standby.
+
+// The database file of a sqlite url, None for an in memory database
+pub fn database_path(url: &str) -> Option<PathBuf> {
+    let path = url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))?;
+    let path = path.split('?').next().unwrap_or_default();
+    match path {
+        "" | ":memory:" => None,
+        path => Some(PathBuf::from(path)),
+    }
+}
+
+// Copies the replica into place when the database doesn't exist yet. Returns whether it did.
+pub fn restore(database: &Path, replica: &Path) -> io::Result<bool> {
+    if database.exists() || !replica.exists() {
+        return Ok(false);
+    }
+    if let Some(parent) = database.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(replica, database)?;
+    Ok(true)
+}
+
+// Writes a consistent copy of the database next to the replica and then renames it over the
+// replica, so the replica is never partially written.
+async fn snapshot(pool: &DbPool, replica: &Path) -> Result<(), sqlx::Error> {
+    let partial = replica.with_extension("partial");
+    if let Err(err) = fs::remove_file(&partial) {
+        if err.kind() != io::ErrorKind::NotFound {
+            return Err(err.into());
+        }
+    }
+    query("vacuum into $1")
+        .bind(partial.to_string_lossy().into_owned())
+        .execute(pool)
+        .await?;
+    fs::rename(&partial, replica)?;
+    Ok(())
+}
+
+// Snapshots the database every interval, starting right away. A failed snapshot is retried on the
+// next pass and the previous replica is kept.
+pub async fn replicate(pool: DbPool, replica: PathBuf, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = snapshot(&pool, &replica).await {
+            error!(
+                err = err.to_string(),
+                replica = replica.to_str(),
+                "failed to replicate the database"
+            );
+        }
+    }
+}
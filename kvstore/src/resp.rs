@@ -0,0 +1,624 @@
+use crate::adapter::{self, read_line, AdapterError, Put};
+use crate::auth::AuthenticatedTenant;
+use crate::error::KVErrors;
+use crate::namespace::Namespace;
+use crate::retry::Rpc;
+use crate::{service_metadata, AppData};
+use actix_web::rt::net::{TcpListener, TcpStream};
+use actix_web::web::Data;
+use common::auth::Scope;
+use common::storage::{GetResponse, ListKeysRequest};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tonic::Extensions;
+use tracing::{error, info, warn};
+
+// Lines are the command's header and each argument's length, arguments are read as bulk strings
+const MAX_LINE: u64 = 64 * 1024;
+const MAX_ARGS: usize = 1024;
+const DEFAULT_SCAN_COUNT: u32 = 10;
+const MAX_SCAN_COUNT: u32 = 1000;
+// a connection's oldest cursors are dropped past this many
+const MAX_CURSORS: usize = 16;
+
+// Serves a subset of the redis protocol (RESP2) so redis clients can be pointed at the gateway:
+// AUTH, PING, SELECT 0, QUIT, GET, SET with EX, PX and NX, DEL, EXISTS, TTL and SCAN. Keys are
+// "<namespace>:<key>", split at the first colon, so namespaces with a colon in their name can't be
+// reached. A connection authenticates with AUTH and a token or api key, the username is ignored.
+// Every command is checked and forwarded to the storage nodes like its http counterpart.
+// The listener is bound before this returns, connections are accepted in the background until the
+// gateway stops.
+pub async fn resp_endpoint(
+    bind_address: &str,
+    port: u16,
+    app_data: Data<AppData>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind((bind_address, port)).await?;
+    info!(port = port, "serving the redis protocol");
+    actix_web::rt::spawn(accept(listener, app_data));
+    Ok(())
+}
+
+async fn accept(listener: TcpListener, app_data: Data<AppData>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!(err = err.to_string(), "failed to accept redis connection");
+                continue;
+            }
+        };
+        let session = Session::new(app_data.clone(), peer.ip());
+        actix_web::rt::spawn(async move {
+            if let Err(err) = session.serve(stream).await {
+                info!(err = err.to_string(), "redis connection closed");
+            }
+        });
+    }
+}
+
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn error(message: impl Into<String>) -> Reply {
+        Reply::Error(message.into())
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(message) => out.extend_from_slice(format!("+{}\r\n", message).as_bytes()),
+            // errors are one line, the first word is the error's kind, e.g. ERR or NOAUTH
+            Reply::Error(message) => out.extend_from_slice(
+                format!("-{}\r\n", message.replace(['\r', '\n'], " ")).as_bytes(),
+            ),
+            Reply::Integer(value) => out.extend_from_slice(format!(":{}\r\n", value).as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(value)) => {
+                out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                out.extend_from_slice(value);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Protocol error: {}", message),
+    )
+}
+
+fn parse_len(header: &[u8], prefix: u8) -> io::Result<i64> {
+    match header.split_first() {
+        Some((first, len)) if *first == prefix => std::str::from_utf8(len)
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| protocol_error("invalid length")),
+        _ => Err(protocol_error(&format!("expected '{}'", prefix as char))),
+    }
+}
+
+// A command is an array of bulk strings, or an inline command of space separated words as typed
+// into a telnet session. Bulk strings larger than max_bulk are rejected like http bodies are.
+async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_bulk: usize,
+) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader, MAX_LINE).await? else {
+        return Ok(None);
+    };
+    if !line.starts_with(b"*") {
+        return Ok(Some(
+            line.split(|byte| byte.is_ascii_whitespace())
+                .filter(|word| !word.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        ));
+    }
+    let count = parse_len(&line, b'*')?;
+    if count < 0 || count as usize > MAX_ARGS {
+        return Err(protocol_error("invalid multibulk length"));
+    }
+    let mut args = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let header = read_line(reader, MAX_LINE)
+            .await?
+            .ok_or_else(|| protocol_error("unexpected end of command"))?;
+        let len = parse_len(&header, b'$')?;
+        if len < 0 || len as usize > max_bulk {
+            return Err(protocol_error("invalid bulk length"));
+        }
+        let mut arg = vec![0; len as usize + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string isn't terminated"));
+        }
+        arg.truncate(len as usize);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// Where a SCAN left off, the cursor handed to the client is the key of this in the connection's
+// cursors
+struct ScanCursor {
+    namespace: Namespace,
+    prefix: Vec<u8>,
+    start_after: Option<Vec<u8>>,
+    snapshot: Option<String>,
+}
+
+struct Session {
+    app_data: Data<AppData>,
+    source: IpAddr,
+    identity: Option<AuthenticatedTenant>,
+    cursors: BTreeMap<u64, ScanCursor>,
+    next_cursor: u64,
+}
+
+impl Session {
+    fn new(app_data: Data<AppData>, source: IpAddr) -> Session {
+        Session {
+            app_data,
+            source,
+            identity: None,
+            cursors: BTreeMap::new(),
+            next_cursor: 1,
+        }
+    }
+
+    // Replies to commands in the order they're sent until the client quits or breaks the protocol
+    async fn serve(mut self, stream: TcpStream) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        loop {
+            let args = match read_command(&mut reader, self.app_data.max_request_body).await {
+                Ok(Some(args)) => args,
+                Ok(None) => return Ok(()),
+                Err(err) => {
+                    let mut out = Vec::new();
+                    Reply::error(format!("ERR {}", err)).encode(&mut out);
+                    writer.write_all(&out).await?;
+                    return Err(err);
+                }
+            };
+            let Some(name) = args.first().map(|name| name.to_ascii_uppercase()) else {
+                continue;
+            };
+            let quit = name == b"QUIT";
+            let reply = match quit {
+                true => Reply::Simple("OK"),
+                false => self.execute(&name, &args[1..]).await,
+            };
+            let mut out = Vec::new();
+            reply.encode(&mut out);
+            writer.write_all(&out).await?;
+            if quit {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn execute(&mut self, name: &[u8], args: &[Vec<u8>]) -> Reply {
+        match name {
+            b"PING" => match args {
+                [] => Reply::Simple("PONG"),
+                [message] => Reply::Bulk(Some(message.clone())),
+                _ => wrong_arity("ping"),
+            },
+            b"AUTH" => match args {
+                [secret] | [_, secret] => self.auth(secret).await,
+                _ => wrong_arity("auth"),
+            },
+            b"SELECT" => match args {
+                [db] if db.as_slice() == b"0" => Reply::Simple("OK"),
+                [_] => Reply::error("ERR DB index is out of range"),
+                _ => wrong_arity("select"),
+            },
+            _ if self.identity.is_none() => Reply::error("NOAUTH Authentication required."),
+            b"GET" => match args {
+                [key] => self.get(key).await,
+                _ => wrong_arity("get"),
+            },
+            b"SET" => match args {
+                [key, value, options @ ..] => self.set(key, value, options).await,
+                _ => wrong_arity("set"),
+            },
+            b"DEL" if !args.is_empty() => self.del(args).await,
+            b"DEL" => wrong_arity("del"),
+            b"EXISTS" if !args.is_empty() => self.exists(args).await,
+            b"EXISTS" => wrong_arity("exists"),
+            b"TTL" => match args {
+                [key] => self.ttl(key).await,
+                _ => wrong_arity("ttl"),
+            },
+            b"SCAN" => match args {
+                [cursor, options @ ..] => self.scan(cursor, options).await,
+                _ => wrong_arity("scan"),
+            },
+            _ => Reply::error(format!(
+                "ERR unknown command '{}'",
+                String::from_utf8_lossy(name)
+            )),
+        }
+    }
+
+    async fn auth(&mut self, secret: &[u8]) -> Reply {
+        let Ok(secret) = std::str::from_utf8(secret) else {
+            return Reply::error("WRONGPASS invalid token or api key");
+        };
+        match AuthenticatedTenant::from_secret(&self.app_data, secret, Some(self.source)).await {
+            Ok(identity) => {
+                info!(
+                    tenant_id = identity.tenant_id().to_string(),
+                    "redis connection authenticated"
+                );
+                self.identity = Some(identity);
+                self.cursors.clear();
+                Reply::Simple("OK")
+            }
+            Err(KVErrors::Unauthorized | KVErrors::Forbidden) => {
+                Reply::error("WRONGPASS invalid token or api key")
+            }
+            Err(err) => rpc_error(err),
+        }
+    }
+
+    fn identity(&self) -> &AuthenticatedTenant {
+        self.identity
+            .as_ref()
+            .expect("commands run once authenticated")
+    }
+
+    // Splits the key into its namespace and the key within it, and checks the caller can use the
+    // namespace
+    async fn resolve(&self, key: &[u8], scope: Scope) -> Result<(Namespace, Vec<u8>), Reply> {
+        let Some(split) = key.iter().position(|byte| *byte == b':') else {
+            return Err(Reply::error("ERR keys are <namespace>:<key>"));
+        };
+        let Ok(name) = std::str::from_utf8(&key[..split]) else {
+            return Err(Reply::error("ERR no such namespace"));
+        };
+        let namespace = adapter::namespace(&self.app_data, self.identity(), name, scope)
+            .await
+            .map_err(adapter_error)?;
+        Ok((namespace, key[split + 1..].to_vec()))
+    }
+
+    // None when the key doesn't exist, or has expired
+    async fn fetch(&self, namespace: &Namespace, key: &[u8]) -> Result<Option<GetResponse>, Reply> {
+        adapter::get(&self.app_data, self.identity(), namespace, key)
+            .await
+            .map_err(adapter_error)
+    }
+
+    async fn get(&self, key: &[u8]) -> Reply {
+        let (namespace, key) = match self.resolve(key, Scope::Read).await {
+            Ok(resolved) => resolved,
+            Err(reply) => return reply,
+        };
+        match self.fetch(&namespace, &key).await {
+            Ok(found) => Reply::Bulk(found.map(|response| response.value)),
+            Err(reply) => reply,
+        }
+    }
+
+    async fn set(&self, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> Reply {
+        let (ttl_secs, if_absent) = match set_options(options) {
+            Ok(options) => options,
+            Err(reply) => return reply,
+        };
+        let (namespace, key) = match self.resolve(key, Scope::Write).await {
+            Ok(resolved) => resolved,
+            Err(reply) => return reply,
+        };
+        let put = Put {
+            key,
+            value: value.to_vec(),
+            ttl_secs,
+            expected_version: None,
+            if_absent,
+        };
+        match adapter::put(&self.app_data, self.identity(), &namespace, put).await {
+            Ok(_) => Reply::Simple("OK"),
+            // NX replies nil when the key is already there
+            Err(AdapterError::Exists) => Reply::Bulk(None),
+            Err(err) => adapter_error(err),
+        }
+    }
+
+    // Each key is deleted at the version it was read at, so only the deletes that removed a key
+    // are counted
+    async fn del(&self, keys: &[Vec<u8>]) -> Reply {
+        let mut deleted = 0;
+        for key in keys {
+            let (namespace, key) = match self.resolve(key, Scope::Write).await {
+                Ok(resolved) => resolved,
+                Err(reply) => return reply,
+            };
+            let found = match self.fetch(&namespace, &key).await {
+                Ok(found) => found,
+                Err(reply) => return reply,
+            };
+            let Some(version) = found.and_then(|found| found.metadata).map(|m| m.version) else {
+                continue;
+            };
+            match adapter::delete(
+                &self.app_data,
+                self.identity(),
+                &namespace,
+                &key,
+                Some(version),
+            )
+            .await
+            {
+                Ok(()) => deleted += 1,
+                // written or deleted by someone else since it was read
+                Err(AdapterError::VersionMismatch | AdapterError::NotFound) => {}
+                Err(err) => return adapter_error(err),
+            }
+        }
+        Reply::Integer(deleted)
+    }
+
+    // A key given more than once is counted each time, as redis does
+    async fn exists(&self, keys: &[Vec<u8>]) -> Reply {
+        let mut found = 0;
+        for key in keys {
+            let (namespace, key) = match self.resolve(key, Scope::Read).await {
+                Ok(resolved) => resolved,
+                Err(reply) => return reply,
+            };
+            match self.fetch(&namespace, &key).await {
+                Ok(Some(_)) => found += 1,
+                Ok(None) => {}
+                Err(reply) => return reply,
+            }
+        }
+        Reply::Integer(found)
+    }
+
+    // -2 for a key that doesn't exist and -1 for one that doesn't expire
+    async fn ttl(&self, key: &[u8]) -> Reply {
+        let (namespace, key) = match self.resolve(key, Scope::Read).await {
+            Ok(resolved) => resolved,
+            Err(reply) => return reply,
+        };
+        let found = match self.fetch(&namespace, &key).await {
+            Ok(found) => found,
+            Err(reply) => return reply,
+        };
+        let Some(metadata) = found.and_then(|found| found.metadata) else {
+            return Reply::Integer(-2);
+        };
+        match metadata.expires_at {
+            Some(expires_at) => Reply::Integer((expires_at.seconds - now()).max(0)),
+            None => Reply::Integer(-1),
+        }
+    }
+
+    // Lists one namespace's keys with a prefix, MATCH is required and has to be
+    // <namespace>:<prefix>*. A cursor lists the keys as of when its first page was read, see
+    // ListKeysRequest.snapshot_id.
+    async fn scan(&mut self, cursor: &[u8], options: &[Vec<u8>]) -> Reply {
+        let (pattern, count) = match scan_options(options) {
+            Ok(options) => options,
+            Err(reply) => return reply,
+        };
+        let cursor = match std::str::from_utf8(cursor)
+            .ok()
+            .and_then(|c| c.parse().ok())
+        {
+            Some(0) => None,
+            Some(cursor) => match self.cursors.remove(&cursor) {
+                Some(cursor) => Some(cursor),
+                None => return Reply::error("ERR invalid cursor"),
+            },
+            None => return Reply::error("ERR invalid cursor"),
+        };
+        let cursor = match cursor {
+            Some(cursor) => cursor,
+            None => {
+                let Some(pattern) = pattern else {
+                    return Reply::error("ERR SCAN needs MATCH <namespace>:<prefix>*");
+                };
+                let Some(prefix) = pattern.strip_suffix(b"*").filter(|p| !p.contains(&b'*')) else {
+                    return Reply::error("ERR only MATCH <namespace>:<prefix>* is supported");
+                };
+                let (namespace, prefix) = match self.resolve(prefix, Scope::Read).await {
+                    Ok(resolved) => resolved,
+                    Err(reply) => return reply,
+                };
+                let start_after = before(&prefix);
+                ScanCursor {
+                    namespace,
+                    prefix,
+                    start_after,
+                    snapshot: None,
+                }
+            }
+        };
+
+        let metadata = match service_metadata(&self.app_data, self.identity()) {
+            Ok(metadata) => metadata,
+            Err(err) => return rpc_error(err),
+        };
+        let request = ListKeysRequest {
+            namespace_id: cursor.namespace.id.to_string(),
+            limit: Some(count),
+            start_key: cursor.start_after.clone(),
+            snapshot_id: cursor.snapshot.clone(),
+            include_values: false,
+            max_value_size: None,
+        };
+        let response = match self
+            .app_data
+            .connection_manager
+            .call_idempotent(
+                Rpc::ListKeys,
+                cursor.namespace.storage_node.as_deref(),
+                |mut client| {
+                    let request = tonic::Request::from_parts(
+                        metadata.clone(),
+                        Extensions::default(),
+                        request.clone(),
+                    );
+                    async move { client.list_keys(request).await }
+                },
+            )
+            .await
+        {
+            Ok(response) => response.into_inner(),
+            Err(err) => {
+                error!(err = err.to_string(), "failed to list keys");
+                return rpc_error(err);
+            }
+        };
+
+        // keys are listed in order, so the first one past the prefix ends the scan
+        let full = response.keys.len() >= count as usize;
+        let last = response.keys.last().map(|item| item.key.clone());
+        let mut done = !full;
+        let mut keys = Vec::new();
+        for item in response.keys {
+            if item.key.starts_with(&cursor.prefix) {
+                let mut key = format!("{}:", cursor.namespace.name).into_bytes();
+                key.extend_from_slice(&item.key);
+                keys.push(Reply::Bulk(Some(key)));
+            } else if item.key.as_slice() > cursor.prefix.as_slice() {
+                done = true;
+                break;
+            }
+        }
+
+        let next = match (done, last) {
+            (false, Some(last)) => {
+                let id = self.next_cursor;
+                self.next_cursor += 1;
+                self.cursors.insert(
+                    id,
+                    ScanCursor {
+                        start_after: Some(last),
+                        snapshot: response.snapshot_id,
+                        ..cursor
+                    },
+                );
+                if self.cursors.len() > MAX_CURSORS {
+                    self.cursors.pop_first();
+                }
+                id
+            }
+            _ => 0,
+        };
+        Reply::Array(vec![
+            Reply::Bulk(Some(next.to_string().into_bytes())),
+            Reply::Array(keys),
+        ])
+    }
+}
+
+fn wrong_arity(command: &str) -> Reply {
+    Reply::error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        command
+    ))
+}
+
+// The client sees the same message an http caller would
+fn rpc_error(err: impl Into<KVErrors>) -> Reply {
+    Reply::error(format!("ERR {}", err.into()))
+}
+
+fn adapter_error(err: AdapterError) -> Reply {
+    match err {
+        AdapterError::Forbidden => Reply::error(format!("NOPERM {}", err)),
+        // redis's own reply when it's out of memory for a write
+        AdapterError::LimitExceeded(exceeded) if exceeded.is_storage() => {
+            Reply::error(format!("OOM {}", err))
+        }
+        err => Reply::error(format!("ERR {}", err)),
+    }
+}
+
+// The ttl in seconds from EX or PX, milliseconds are rounded up, and whether NX was given
+fn set_options(options: &[Vec<u8>]) -> Result<(Option<u64>, bool), Reply> {
+    let syntax = || Reply::error("ERR syntax error");
+    let (mut ttl_secs, mut if_absent) = (None, false);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" => if_absent = true,
+            unit @ (b"EX" | b"PX") if ttl_secs.is_none() => {
+                let value: u64 = options
+                    .next()
+                    .and_then(|value| std::str::from_utf8(value).ok())
+                    .and_then(|value| value.parse().ok())
+                    .ok_or_else(syntax)?;
+                ttl_secs = Some(match unit {
+                    b"PX" => value.div_ceil(1000),
+                    _ => value,
+                });
+            }
+            _ => return Err(syntax()),
+        }
+    }
+    Ok((ttl_secs, if_absent))
+}
+
+// The MATCH pattern and the COUNT, bounded like a page of keys over http
+fn scan_options(options: &[Vec<u8>]) -> Result<(Option<Vec<u8>>, u32), Reply> {
+    let syntax = || Reply::error("ERR syntax error");
+    let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or_else(syntax)?;
+        match option.to_ascii_uppercase().as_slice() {
+            b"MATCH" => pattern = Some(value.clone()),
+            b"COUNT" => {
+                count = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.parse::<u32>().ok())
+                    .filter(|count| *count > 0)
+                    .ok_or_else(syntax)?
+                    .min(MAX_SCAN_COUNT);
+            }
+            _ => return Err(syntax()),
+        }
+    }
+    Ok((pattern, count))
+}
+
+// A key that sorts before every key with the prefix, listing starts after it since a listing can't
+// start at a key. None for an empty prefix, which every key has.
+fn before(prefix: &[u8]) -> Option<Vec<u8>> {
+    let (last, rest) = prefix.split_last()?;
+    let mut start = rest.to_vec();
+    if *last > 0 {
+        start.push(last - 1);
+    }
+    (!start.is_empty()).then_some(start)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,159 @@
+use common::metrics::register;
+use common::storage::GetResponse;
+use moka::future::Cache;
+use moka::Expiry;
+use prometheus::{IntCounterVec, Opts};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::error;
+use uuid::Uuid;
+
+pub const DEFAULT_CAPACITY_MB: u64 = 64;
+
+// a namespace's gets can't be cached for longer than this, the cache absorbs read storms on hot
+// keys rather than serving reads that are out of date
+pub const MAX_TTL_SECS: u64 = 60;
+
+// A get as it's cached. A key's latest version and each version read by number are cached apart,
+// and so are raw reads and reads through the namespace's read transform.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CachedGet {
+    pub tenant_id: Uuid,
+    pub namespace_id: Uuid,
+    pub key: Vec<u8>,
+    pub version: Option<u32>,
+    pub raw: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    response: Arc<GetResponse>,
+    ttl: Duration,
+}
+
+// Each entry expires after its own ttl, see ResponseCache::insert
+struct EntryTtl;
+
+impl Expiry<CachedGet, Entry> for EntryTtl {
+    fn expire_after_create(&self, _: &CachedGet, entry: &Entry, _: Instant) -> Option<Duration> {
+        Some(entry.ttl)
+    }
+}
+
+// Responses to gets of the namespaces with a cache_ttl_secs, so a read storm on a hot key is
+// served by the gateway rather than the storage nodes. Writes through this gateway drop the key's
+// cached latest version. Writes through another gateway are seen once the entry expires, and so is
+// a write that lands while a get of the key is in flight, since the get's response can be cached
+// after the write dropped the key. Versions read by number don't change, they're only dropped when
+// they expire or their namespace goes away.
+pub struct ResponseCache {
+    // replaced when the configuration is reloaded with a new capacity
+    cache: RwLock<Cache<CachedGet, Entry>>,
+    requests: IntCounterVec,
+}
+
+impl ResponseCache {
+    // Holds at most capacity bytes of keys and values, a capacity of 0 turns the cache off
+    pub fn new(capacity: u64) -> ResponseCache {
+        ResponseCache {
+            cache: RwLock::new(cache(capacity)),
+            requests: register(
+                IntCounterVec::new(
+                    Opts::new(
+                        "kvstore_response_cache_requests_total",
+                        "Gets of namespaces with a cache ttl by whether they were served from cache",
+                    ),
+                    &["result"],
+                )
+                .unwrap(),
+            ),
+        }
+    }
+
+    // Starts over with an empty cache of the new capacity
+    pub fn set_capacity(&self, capacity: u64) {
+        if let Ok(mut current) = self.cache.write() {
+            *current = cache(capacity);
+        }
+    }
+
+    fn cache(&self) -> Cache<CachedGet, Entry> {
+        self.cache
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub async fn get(&self, get: &CachedGet) -> Option<Arc<GetResponse>> {
+        let cached = self.cache().get(get).await.map(|entry| entry.response);
+        let result = match cached {
+            Some(_) => "hit",
+            None => "miss",
+        };
+        self.requests.with_label_values(&[result]).inc();
+        cached
+    }
+
+    // Cached for the namespace's ttl, or until the key expires when that's sooner
+    pub async fn insert(&self, get: CachedGet, response: Arc<GetResponse>, ttl: Duration) {
+        let expires_at = response
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.expires_at.as_ref());
+        let ttl = match expires_at {
+            Some(expires_at) => {
+                let expires_at = u64::try_from(expires_at.seconds).unwrap_or(0);
+                ttl.min(Duration::from_secs(expires_at.saturating_sub(unix_now())))
+            }
+            None => ttl,
+        };
+        if !ttl.is_zero() {
+            self.cache().insert(get, Entry { response, ttl }).await;
+        }
+    }
+
+    // Drops the key's cached latest version once the key is written
+    pub async fn invalidate_key(&self, tenant_id: Uuid, namespace_id: Uuid, key: &[u8]) {
+        let cache = self.cache();
+        for raw in [false, true] {
+            let get = CachedGet {
+                tenant_id,
+                namespace_id,
+                key: key.to_vec(),
+                version: None,
+                raw,
+            };
+            cache.invalidate(&get).await;
+        }
+    }
+
+    // Drops everything cached for the namespace, e.g. once it's deleted or its transforms change
+    pub fn invalidate_namespace(&self, tenant_id: Uuid, namespace_id: Uuid) {
+        let invalidated = self.cache().invalidate_entries_if(move |get, _| {
+            get.tenant_id == tenant_id && get.namespace_id == namespace_id
+        });
+        if let Err(err) = invalidated {
+            error!(
+                err = err.to_string(),
+                "failed to invalidate cached responses"
+            );
+        }
+    }
+}
+
+fn cache(capacity: u64) -> Cache<CachedGet, Entry> {
+    Cache::builder()
+        .max_capacity(capacity)
+        .weigher(|get: &CachedGet, entry: &Entry| {
+            u32::try_from(get.key.len() + entry.response.value.len()).unwrap_or(u32::MAX)
+        })
+        .expire_after(EntryTtl)
+        .support_invalidation_closures()
+        .build()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
@@ -0,0 +1,214 @@
+use rand::Rng;
+use std::sync::Mutex;
+use std::time::Duration;
+use tonic::{Code, Status};
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+pub const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(50);
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(1);
+pub const DEFAULT_BUDGET_RATIO: f64 = 0.2;
+
+// the budget starts full so a restart right after startup can still be retried
+const BUDGET_CAPACITY: f64 = 10.0;
+
+// The storage rpcs the gateway sends, timeouts are set per rpc and retry policies per idempotent rpc
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rpc {
+    Get,
+    Put,
+    Delete,
+    Touch,
+    DeleteRange,
+    TransactWrite,
+    ListKeys,
+    Scan,
+    NamespaceStats,
+    CountKeys,
+    SampleKeys,
+    ListNamespaces,
+    CreateNamespace,
+    DeleteNamespace,
+    SetNamespaceQuota,
+    SetNamespaceTransforms,
+    SetNamespaceKeyPolicy,
+    SetNamespaceRetention,
+    ListVersions,
+    CloneNamespace,
+    CopyKey,
+}
+
+impl Rpc {
+    pub fn all() -> [Rpc; 21] {
+        [
+            Rpc::Get,
+            Rpc::Put,
+            Rpc::Delete,
+            Rpc::Touch,
+            Rpc::DeleteRange,
+            Rpc::TransactWrite,
+            Rpc::ListKeys,
+            Rpc::Scan,
+            Rpc::NamespaceStats,
+            Rpc::CountKeys,
+            Rpc::SampleKeys,
+            Rpc::ListNamespaces,
+            Rpc::CreateNamespace,
+            Rpc::DeleteNamespace,
+            Rpc::SetNamespaceQuota,
+            Rpc::SetNamespaceTransforms,
+            Rpc::SetNamespaceKeyPolicy,
+            Rpc::SetNamespaceRetention,
+            Rpc::ListVersions,
+            Rpc::CloneNamespace,
+            Rpc::CopyKey,
+        ]
+    }
+
+    // The rpcs that are safe to send more than once, touching a key twice only moves its expiry
+    // along by the time between the attempts
+    pub fn idempotent() -> [Rpc; 9] {
+        [
+            Rpc::Get,
+            Rpc::Touch,
+            Rpc::ListKeys,
+            Rpc::Scan,
+            Rpc::NamespaceStats,
+            Rpc::CountKeys,
+            Rpc::SampleKeys,
+            Rpc::ListNamespaces,
+            Rpc::ListVersions,
+        ]
+    }
+
+    // Used to name the rpc's environment variables, e.g. KVSTORE_RETRY_LIST_KEYS_ATTEMPTS
+    pub fn env_name(&self) -> &'static str {
+        match self {
+            Rpc::Get => "GET",
+            Rpc::Put => "PUT",
+            Rpc::Delete => "DELETE",
+            Rpc::Touch => "TOUCH",
+            Rpc::DeleteRange => "DELETE_RANGE",
+            Rpc::TransactWrite => "TRANSACT_WRITE",
+            Rpc::ListKeys => "LIST_KEYS",
+            Rpc::Scan => "SCAN",
+            Rpc::NamespaceStats => "NAMESPACE_STATS",
+            Rpc::CountKeys => "COUNT_KEYS",
+            Rpc::SampleKeys => "SAMPLE_KEYS",
+            Rpc::ListNamespaces => "LIST_NAMESPACES",
+            Rpc::CreateNamespace => "CREATE_NAMESPACE",
+            Rpc::DeleteNamespace => "DELETE_NAMESPACE",
+            Rpc::SetNamespaceQuota => "SET_NAMESPACE_QUOTA",
+            Rpc::SetNamespaceTransforms => "SET_NAMESPACE_TRANSFORMS",
+            Rpc::SetNamespaceKeyPolicy => "SET_NAMESPACE_KEY_POLICY",
+            Rpc::SetNamespaceRetention => "SET_NAMESPACE_RETENTION",
+            Rpc::ListVersions => "LIST_VERSIONS",
+            Rpc::CloneNamespace => "CLONE_NAMESPACE",
+            Rpc::CopyKey => "COPY_KEY",
+        }
+    }
+
+    // Single key reads should be quick, rpcs that walk a namespace get longer
+    pub fn default_timeout(&self) -> Duration {
+        match self {
+            Rpc::Get => Duration::from_secs(2),
+            Rpc::Put
+            | Rpc::CopyKey
+            | Rpc::Delete
+            | Rpc::Touch
+            | Rpc::ListNamespaces
+            | Rpc::CreateNamespace
+            | Rpc::SetNamespaceQuota
+            | Rpc::SetNamespaceKeyPolicy
+            | Rpc::SetNamespaceRetention
+            | Rpc::ListVersions => Duration::from_secs(5),
+            // nodes compile the modules before answering
+            Rpc::SetNamespaceTransforms => Duration::from_secs(15),
+            // checkpoints hard link the partitions' files, writes are paused while they're taken
+            Rpc::CloneNamespace => Duration::from_secs(60),
+            Rpc::ListKeys | Rpc::NamespaceStats | Rpc::SampleKeys | Rpc::TransactWrite => {
+                Duration::from_secs(15)
+            }
+            // an exact count reads every key of the namespace, nodes count the keys of a namespace
+            // before they drop it
+            Rpc::DeleteRange | Rpc::CountKeys | Rpc::DeleteNamespace => Duration::from_secs(60),
+            // the timeout covers the whole stream, a scan has to finish within it
+            Rpc::Scan => Duration::from_secs(3600),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // includes the first attempt, 1 disables retries
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Full jitter, a random delay up to the exponential backoff for the attempt so retries from
+    // many requests don't arrive at a restarted node all at once
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        rand::thread_rng().gen_range(Duration::ZERO..=backoff)
+    }
+}
+
+// Only failures where the node never got to handle the request are retried
+pub fn is_retryable(status: &Status) -> bool {
+    matches!(status.code(), Code::Unavailable | Code::DeadlineExceeded)
+}
+
+// Limits retries to a fraction of the requests, so a storage outage doesn't get multiplied into
+// max_attempts times the load. Every request adds ratio to the budget and every retry spends one.
+#[derive(Debug)]
+pub struct RetryBudget {
+    ratio: f64,
+    balance: Mutex<f64>,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget::new(DEFAULT_BUDGET_RATIO)
+    }
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f64) -> RetryBudget {
+        RetryBudget {
+            ratio: ratio.max(0.0),
+            balance: Mutex::new(BUDGET_CAPACITY),
+        }
+    }
+
+    pub fn deposit(&self) {
+        if let Ok(mut balance) = self.balance.lock() {
+            *balance = (*balance + self.ratio).min(BUDGET_CAPACITY);
+        }
+    }
+
+    // Returns false when the budget is spent and the request shouldn't be retried
+    pub fn withdraw(&self) -> bool {
+        let Ok(mut balance) = self.balance.lock() else {
+            return false;
+        };
+        if *balance < 1.0 {
+            return false;
+        }
+        *balance -= 1.0;
+        true
+    }
+}
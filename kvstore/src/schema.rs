@@ -0,0 +1,111 @@
+use crate::db::{optional, DbPool};
+use jsonschema::JSONSchema;
+use moka::future::Cache;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::any::AnyRow;
+use sqlx::{query, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::error;
+use uuid::Uuid;
+
+// Schemas are kept in the namespaces table, the largest one a namespace can register
+pub const MAX_SCHEMA_LEN: usize = 64 * 1024;
+
+// Where a value doesn't match its schema, the pointer is the JSON pointer of the offending part of
+// the value, e.g. "/address/zip", and "" for the value itself
+#[derive(Serialize, Debug, Clone)]
+pub struct SchemaViolation {
+    pub pointer: String,
+    pub message: String,
+}
+
+// Fails with why the schema can't be used, e.g. an unknown type or a $ref outside the schema, which
+// is never fetched
+pub fn compile(schema: &Value) -> std::result::Result<JSONSchema, String> {
+    JSONSchema::compile(schema).map_err(|err| format!("{}: {}", err.schema_path, err))
+}
+
+// Checks a value against the schema, values that aren't JSON documents violate every schema
+pub fn validate(
+    schema: &JSONSchema,
+    value: &[u8],
+) -> std::result::Result<(), Vec<SchemaViolation>> {
+    let document: Value = serde_json::from_slice(value).map_err(|err| {
+        vec![SchemaViolation {
+            pointer: String::new(),
+            message: format!("the value is not a JSON document: {}", err),
+        }]
+    })?;
+    schema.validate(&document).map_err(|errors| {
+        errors
+            .map(|err| SchemaViolation {
+                pointer: err.instance_path.to_string(),
+                message: err.to_string(),
+            })
+            .collect()
+    })
+}
+
+// The JSON Schemas namespaces validate their values against. A namespace's compiled schema is
+// cached for as long as its namespace would be, so gateways sharing a postgres database pick up a
+// changed schema within the cache's ttl.
+pub struct Schemas {
+    db_pool: DbPool,
+    compiled: Cache<Uuid, Option<Arc<JSONSchema>>>,
+}
+
+impl Schemas {
+    pub fn new(db_pool: DbPool, capacity: u64, ttl: Duration) -> Schemas {
+        Schemas {
+            db_pool,
+            compiled: Cache::builder()
+                .max_capacity(capacity)
+                .time_to_live(ttl)
+                .build(),
+        }
+    }
+
+    pub async fn get(&self, namespace_id: Uuid) -> Result<Option<Value>> {
+        let schema: Option<String> = query("select json_schema from namespaces where uuid = $1")
+            .bind(namespace_id.to_string())
+            .map(|row: AnyRow| optional(&row, 0))
+            .fetch_one(&self.db_pool)
+            .await?;
+        Ok(schema.and_then(|schema| serde_json::from_str(&schema).ok()))
+    }
+
+    // Replaces the namespace's schema, None stops validating its values. The schema is expected to
+    // compile, see compile.
+    pub async fn set(&self, namespace_id: Uuid, schema: Option<&Value>) -> Result<()> {
+        query("update namespaces set json_schema = nullif($1, '') where uuid = $2")
+            .bind(schema.map(Value::to_string).unwrap_or_default())
+            .bind(namespace_id.to_string())
+            .execute(&self.db_pool)
+            .await?;
+        self.compiled.invalidate(&namespace_id).await;
+        Ok(())
+    }
+
+    // The schema the namespace's values are validated against, None when they aren't
+    pub async fn validator(&self, namespace_id: Uuid) -> Result<Option<Arc<JSONSchema>>> {
+        if let Some(cached) = self.compiled.get(&namespace_id).await {
+            return Ok(cached);
+        }
+        let validator = self.get(namespace_id).await?.and_then(|schema| {
+            compile(&schema)
+                .inspect_err(|err| {
+                    error!(
+                        namespace_id = namespace_id.to_string(),
+                        err = err,
+                        "failed to compile stored schema, values are not validated"
+                    )
+                })
+                .ok()
+                .map(Arc::new)
+        });
+        self.compiled.insert(namespace_id, validator.clone()).await;
+        Ok(validator)
+    }
+}
@@ -0,0 +1,156 @@
+use crate::db::{optional, DbPool};
+use serde::{Deserialize, Serialize};
+use sqlx::any::AnyRow;
+use sqlx::{query, Result, Row};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::transport::Uri;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetStatus {
+    // routed to alongside the configured storage nodes
+    #[default]
+    Active,
+    // kept registered but no longer sent requests, e.g. while its data is moved off
+    Draining,
+    // marked down by an operator, not routed to until it's made active again
+    Down,
+}
+
+impl TargetStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TargetStatus::Active => "active",
+            TargetStatus::Draining => "draining",
+            TargetStatus::Down => "down",
+        }
+    }
+
+    fn parse(status: &str) -> TargetStatus {
+        match status {
+            "active" => TargetStatus::Active,
+            "draining" => TargetStatus::Draining,
+            _ => TargetStatus::Down,
+        }
+    }
+}
+
+// A storage node registered at runtime, e.g.
+// {"endpoint": "http://storage-3:50051", "capacity_bytes": 107374182400}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StorageTarget {
+    #[serde(skip_deserializing)]
+    pub id: Uuid,
+    pub endpoint: String,
+    #[serde(default)]
+    pub status: TargetStatus,
+    // an operator annotation, the gateway doesn't enforce it
+    pub capacity_bytes: Option<u64>,
+    #[serde(skip_deserializing)]
+    pub created_at: i64,
+}
+
+impl From<AnyRow> for StorageTarget {
+    fn from(row: AnyRow) -> Self {
+        StorageTarget {
+            id: Uuid::parse_str(row.get(0)).unwrap(),
+            endpoint: row.get(1),
+            status: TargetStatus::parse(row.get(2)),
+            capacity_bytes: optional::<i64>(&row, 3).map(|capacity| capacity as u64),
+            created_at: row.get(4),
+        }
+    }
+}
+
+impl StorageTarget {
+    // Same form as a static storage node source, an http or https uri with a host
+    pub fn is_valid(&self) -> bool {
+        self.endpoint.parse::<Uri>().is_ok_and(|uri| {
+            matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some()
+        })
+    }
+
+    // Bound as -1 when unknown and stored as null, see db::DbPool
+    fn capacity_value(&self) -> i64 {
+        self.capacity_bytes.map_or(-1, |capacity| capacity as i64)
+    }
+}
+
+pub struct StorageTargetRepo {
+    db_pool: DbPool,
+}
+
+impl StorageTargetRepo {
+    pub fn new(db_pool: DbPool) -> StorageTargetRepo {
+        StorageTargetRepo { db_pool }
+    }
+
+    // Rows without a uuid predate runtime registration and are ignored
+    pub async fn list(&self) -> Result<Vec<StorageTarget>> {
+        query("select uuid, endpoint, status, capacity_bytes, created_at from storage_targets where uuid is not null order by created_at, id")
+            .map(|row: AnyRow| row.into())
+            .fetch_all(&self.db_pool)
+            .await
+    }
+
+    pub async fn create(&self, target: &StorageTarget) -> Result<StorageTarget> {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0);
+        let id = Uuid::new_v4();
+        query("insert into storage_targets (uuid, endpoint, status, capacity_bytes, created_at) values ($1, $2, $3, nullif($4, -1), $5)")
+            .bind(id.to_string())
+            .bind(&target.endpoint)
+            .bind(target.status.as_str())
+            .bind(target.capacity_value())
+            .bind(created_at)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(StorageTarget {
+            id,
+            created_at,
+            ..target.clone()
+        })
+    }
+
+    // Replaces the endpoint, status, and capacity. Returns None if there is no target with the id
+    pub async fn update(&self, id: Uuid, target: &StorageTarget) -> Result<Option<StorageTarget>> {
+        // not an update returning the row, sqlite doesn't finish that statement until the row is
+        // consumed and other connections would still read the old status
+        let result = query("update storage_targets set endpoint = $1, status = $2, capacity_bytes = nullif($3, -1) where uuid = $4")
+            .bind(&target.endpoint)
+            .bind(target.status.as_str())
+            .bind(target.capacity_value())
+            .bind(id.to_string())
+            .execute(&self.db_pool)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        query("select uuid, endpoint, status, capacity_bytes, created_at from storage_targets where uuid = $1")
+            .bind(id.to_string())
+            .map(|row: AnyRow| row.into())
+            .fetch_optional(&self.db_pool)
+            .await
+    }
+
+    // Returns false if there is no target with the id
+    pub async fn delete(&self, id: Uuid) -> Result<bool> {
+        let result = query("delete from storage_targets where uuid = $1")
+            .bind(id.to_string())
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // The endpoints requests are routed to, in addition to the discovered storage nodes
+    pub async fn routed_endpoints(&self) -> Result<Vec<String>> {
+        query("select endpoint from storage_targets where uuid is not null and status = $1 order by endpoint")
+            .bind(TargetStatus::Active.as_str())
+            .map(|row: AnyRow| row.get(0))
+            .fetch_all(&self.db_pool)
+            .await
+    }
+}
@@ -1,29 +1,265 @@
-use sqlx::sqlite::SqliteRow;
-use sqlx::{query, Pool, Result, Row, Sqlite};
+use crate::db::{optional, DbPool};
+use serde::{Deserialize, Serialize};
+use sqlx::any::AnyRow;
+use sqlx::{query, Result, Row};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
-#[derive(Debug)]
+pub const DEFAULT_DELETION_GRACE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TenantStatus {
+    Active,
+    // can't log in or use its data until resumed, nothing is removed
+    Suspended,
+    // waiting out the deletion grace period, it can still be restored until it's purged
+    Deleted,
+}
+
+impl TenantStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TenantStatus::Active => "active",
+            TenantStatus::Suspended => "suspended",
+            TenantStatus::Deleted => "deleted",
+        }
+    }
+
+    fn parse(status: &str) -> TenantStatus {
+        match status {
+            "active" => TenantStatus::Active,
+            "suspended" => TenantStatus::Suspended,
+            _ => TenantStatus::Deleted,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct Tenant {
     pub name: Box<str>,
     pub uuid: Uuid,
+    pub status: TenantStatus,
+    // unix timestamp the tenant is purged at, only set once it's deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purge_after: Option<i64>,
+}
+
+impl From<AnyRow> for Tenant {
+    fn from(row: AnyRow) -> Self {
+        Tenant {
+            name: Box::from(row.get::<String, usize>(0)),
+            uuid: Uuid::parse_str(row.get(1)).unwrap(),
+            status: TenantStatus::parse(row.get(2)),
+            purge_after: optional(&row, 3),
+        }
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
 }
 
 pub struct TenantRepo {
-    db_pool: Pool<Sqlite>,
+    db_pool: DbPool,
+    deletion_grace: Duration,
 }
 
 impl TenantRepo {
-    pub fn new(db_pool: Pool<Sqlite>) -> TenantRepo {
-        TenantRepo { db_pool }
+    pub fn new(db_pool: DbPool) -> TenantRepo {
+        TenantRepo {
+            db_pool,
+            deletion_grace: DEFAULT_DELETION_GRACE,
+        }
+    }
+
+    // How long a deleted tenant can still be restored before it's purged
+    pub fn with_deletion_grace(mut self, deletion_grace: Duration) -> TenantRepo {
+        self.deletion_grace = deletion_grace;
+        self
     }
+
     pub async fn get(&self, name: impl Into<String>) -> Result<Tenant> {
-        query("select name, uuid from tenants where name = ?")
+        query("select name, uuid, status, purge_after from tenants where name = $1")
             .bind(name.into())
-            .map(|row: SqliteRow| Tenant {
-                name: Box::from(row.get::<String, usize>(0)),
-                uuid: Uuid::parse_str(row.get(1)).unwrap(),
-            })
+            .map(|row: AnyRow| row.into())
             .fetch_one(&self.db_pool)
             .await
     }
+
+    pub async fn create(&self, name: &str, password_hash: &str) -> Result<Tenant> {
+        query("insert into tenants (name, uuid, password_hash) values ($1, $2, $3) returning name, uuid, status, purge_after")
+            .bind(name)
+            .bind(Uuid::new_v4().to_string())
+            .bind(password_hash)
+            .map(|row: AnyRow| row.into())
+            .fetch_one(&self.db_pool)
+            .await
+    }
+
+    // Tenants created before passwords were stored have no hash
+    pub async fn password_hash(&self, name: &str) -> Result<Option<String>> {
+        query("select password_hash from tenants where name = $1")
+            .bind(name)
+            .map(|row: AnyRow| optional(&row, 0))
+            .fetch_one(&self.db_pool)
+            .await
+    }
+
+    // Returns false if there is no tenant with the given name
+    pub async fn set_password_hash(&self, name: &str, password_hash: &str) -> Result<bool> {
+        let result = query("update tenants set password_hash = $1 where name = $2")
+            .bind(password_hash)
+            .bind(name)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn list(&self) -> Result<Vec<Tenant>> {
+        query("select name, uuid, status, purge_after from tenants order by name")
+            .map(|row: AnyRow| row.into())
+            .fetch_all(&self.db_pool)
+            .await
+    }
+
+    // Suspends or resumes the tenant. Returns false if there is no tenant with the given name, or
+    // it's deleted and has to be restored instead
+    pub async fn set_suspended(&self, name: &str, suspended: bool) -> Result<bool> {
+        let status = match suspended {
+            true => TenantStatus::Suspended,
+            false => TenantStatus::Active,
+        };
+        let result = query("update tenants set status = $1 where name = $2 and status != $3")
+            .bind(status.as_str())
+            .bind(name)
+            .bind(TenantStatus::Deleted.as_str())
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Marks the tenant deleted and revokes its tokens, it's purged once the grace period passes. A
+    // tenant that's already deleted keeps its purge time. Returns the purge time, or None if there
+    // is no tenant with the given name
+    pub async fn schedule_deletion(&self, name: &str) -> Result<Option<i64>> {
+        let now = now();
+        let purge_after = now + self.deletion_grace.as_secs() as i64;
+        query("update tenants set status = $1, purge_after = $2, tokens_valid_after = $3 where name = $4 and status != $1")
+            .bind(TenantStatus::Deleted.as_str())
+            .bind(purge_after)
+            .bind(now)
+            .bind(name)
+            .execute(&self.db_pool)
+            .await?;
+        query("select purge_after from tenants where name = $1")
+            .bind(name)
+            .map(|row: AnyRow| optional(&row, 0).unwrap_or(purge_after))
+            .fetch_optional(&self.db_pool)
+            .await
+    }
+
+    // Cancels a pending deletion, the tenant is active again but has to get new tokens. Returns false
+    // if there is no deleted tenant with the given name
+    pub async fn restore(&self, name: &str) -> Result<bool> {
+        let result = query(
+            "update tenants set status = $1, purge_after = null where name = $2 and status = $3",
+        )
+        .bind(TenantStatus::Active.as_str())
+        .bind(name)
+        .bind(TenantStatus::Deleted.as_str())
+        .execute(&self.db_pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Deleted tenants whose grace period has passed
+    pub async fn pending_purge(&self) -> Result<Vec<Tenant>> {
+        query("select name, uuid, status, purge_after from tenants where status = $1 and purge_after <= $2 order by purge_after")
+            .bind(TenantStatus::Deleted.as_str())
+            .bind(now())
+            .map(|row: AnyRow| row.into())
+            .fetch_all(&self.db_pool)
+            .await
+    }
+
+    // Purges the tenant along with its namespaces, api keys, client certificate mappings, and
+    // usage. The tenant's data on the storage nodes has to be deleted first. Returns false if there
+    // is no tenant with the given name
+    pub async fn delete(&self, name: &str) -> Result<bool> {
+        let mut tx = self.db_pool.begin().await?;
+        let tenant_id = "(select id from tenants where name = $1)";
+        let namespaces = format!(
+            "(select id from namespaces where tenant_id = {})",
+            tenant_id
+        );
+        query(&format!(
+            "delete from storage_targets where namespace_id in {}",
+            namespaces
+        ))
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+        query(&format!("delete from webhook_dead_letters where webhook_id in (select id from webhooks where namespace_id in {})", namespaces))
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        query(&format!(
+            "delete from webhooks where namespace_id in {}",
+            namespaces
+        ))
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+        for table in [
+            "namespaces",
+            "api_keys",
+            "client_certificates",
+            "tenant_usage",
+            "tenant_usage_hourly",
+        ] {
+            query(&format!(
+                "delete from {} where tenant_id = {}",
+                table, tenant_id
+            ))
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        }
+        let result = query("delete from tenants where name = $1")
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Invalidates every token issued to the tenant before the current second, see is_active.
+    // Returns false if there is no tenant with the given name
+    pub async fn revoke_tokens(&self, name: &str) -> Result<bool> {
+        let result = query("update tenants set tokens_valid_after = $1 where name = $2")
+            .bind(now())
+            .bind(name)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // A tenant is active if it exists, is neither suspended nor deleted, and the token was issued
+    // no earlier than the second of the last revocation, a token issued in that second is kept
+    // rather than rejecting one minted right after it
+    pub async fn is_active(&self, tenant_id: Uuid, issued_at: u64) -> Result<bool> {
+        let tenant = query(
+            "select 1 from tenants where uuid = $1 and status = $2 and tokens_valid_after <= $3",
+        )
+        .bind(tenant_id.to_string())
+        .bind(TenantStatus::Active.as_str())
+        .bind(issued_at as i64)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        Ok(tenant.is_some())
+    }
 }
@@ -0,0 +1,168 @@
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+pub const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(500);
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
+pub const DEFAULT_LOCKOUT_THRESHOLD: u32 = 10;
+pub const DEFAULT_LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+
+// a single source ip gets more attempts than a single tenant from that ip since it may be a shared nat
+const SOURCE_THRESHOLD_MULTIPLIER: u32 = 5;
+// a tenant gets as many across every source, enough that one source can't lock it out, while
+// guessing its password from many sources is still throttled
+const ACCOUNT_THRESHOLD_MULTIPLIER: u32 = 5;
+// entries are pruned once the map grows past this size
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Tenant(String, IpAddr),
+    Source(IpAddr),
+    Account(String),
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    retry_at: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThrottleLimits {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub lockout_threshold: u32,
+    pub lockout_duration: Duration,
+}
+
+impl Default for ThrottleLimits {
+    fn default() -> Self {
+        ThrottleLimits {
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            lockout_threshold: DEFAULT_LOCKOUT_THRESHOLD,
+            lockout_duration: DEFAULT_LOCKOUT_DURATION,
+        }
+    }
+}
+
+impl ThrottleLimits {
+    fn expiry(&self) -> Duration {
+        self.lockout_duration.max(self.max_delay)
+    }
+}
+
+// Tracks failed logins per tenant from each source ip, per source ip, and per tenant across sources,
+// so guessing a tenant's password from many ips is throttled too. Each failure doubles the time
+// before the next attempt is allowed, and hitting the threshold locks the caller out for the lockout
+// duration.
+#[derive(Debug, Default)]
+pub struct LoginThrottle {
+    failures: DashMap<Key, Failures>,
+    // can be changed at runtime, failures already recorded keep their backoff
+    limits: RwLock<ThrottleLimits>,
+}
+
+impl LoginThrottle {
+    pub fn with_limits(self, limits: ThrottleLimits) -> LoginThrottle {
+        self.set_limits(limits);
+        self
+    }
+
+    pub fn limits(&self) -> ThrottleLimits {
+        self.limits.read().map(|limits| *limits).unwrap_or_default()
+    }
+
+    pub fn set_limits(&self, limits: ThrottleLimits) {
+        if let Ok(mut current) = self.limits.write() {
+            *current = limits;
+        }
+    }
+
+    fn keys(tenant: &str, source: IpAddr) -> [Key; 3] {
+        [
+            Key::Tenant(tenant.to_owned(), source),
+            Key::Source(source),
+            Key::Account(tenant.to_owned()),
+        ]
+    }
+
+    // Returns how long the caller has to wait if it isn't allowed to attempt a login yet
+    pub fn check(&self, tenant: &str, source: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        Self::keys(tenant, source)
+            .iter()
+            .filter_map(|key| self.failures.get(key))
+            .filter(|failures| failures.retry_at > now)
+            .map(|failures| failures.retry_at - now)
+            .max()
+    }
+
+    pub fn record_failure(&self, tenant: &str, source: IpAddr) {
+        let now = Instant::now();
+        let limits = self.limits();
+        for key in Self::keys(tenant, source) {
+            let threshold = match key {
+                Key::Tenant(..) => limits.lockout_threshold,
+                Key::Source(_) => limits
+                    .lockout_threshold
+                    .saturating_mul(SOURCE_THRESHOLD_MULTIPLIER),
+                Key::Account(_) => limits
+                    .lockout_threshold
+                    .saturating_mul(ACCOUNT_THRESHOLD_MULTIPLIER),
+            };
+            let mut failures = self.failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                retry_at: now,
+            });
+            // old failures are forgotten once they no longer affect the backoff, and a lockout that
+            // has passed starts the count over rather than locking out again on the next failure
+            let locked_out = failures.count >= threshold;
+            if failures.retry_at + limits.expiry() <= now
+                || (locked_out && failures.retry_at <= now)
+            {
+                failures.count = 0;
+            }
+            failures.count += 1;
+
+            let delay = if failures.count >= threshold {
+                warn!(
+                    target: "audit",
+                    event = "login_lockout",
+                    key = ?key,
+                    failures = failures.count,
+                    "locking out after repeated failed logins"
+                );
+                limits.lockout_duration
+            } else {
+                limits
+                    .base_delay
+                    .saturating_mul(2u32.saturating_pow(failures.count - 1))
+                    .min(limits.max_delay)
+            };
+            failures.retry_at = now + delay;
+        }
+
+        if self.failures.len() > PRUNE_THRESHOLD {
+            self.prune();
+        }
+    }
+
+    // A successful login clears the failures of the tenant from that source, the source's own count
+    // and the tenant's across sources are kept so one valid login can't be used to reset them
+    pub fn record_success(&self, tenant: &str, source: IpAddr) {
+        self.failures
+            .remove(&Key::Tenant(tenant.to_owned(), source));
+    }
+
+    // Drops entries whose backoff has passed long enough ago that they no longer matter
+    fn prune(&self) {
+        let now = Instant::now();
+        let expiry = self.limits().expiry();
+        self.failures
+            .retain(|_, failures| failures.retry_at + expiry > now);
+    }
+}
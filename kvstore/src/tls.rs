@@ -0,0 +1,355 @@
+use actix_tls::accept::rustls_0_21::TlsStream;
+use actix_web::dev::{Extensions, Payload};
+use actix_web::rt::net::TcpStream;
+use actix_web::{FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tonic::transport::ClientTlsConfig;
+use tracing::{error, info, warn};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+// How often certificate and key files are checked for changes, see common::config::watch_files
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+// Builds the config for the https listener. When a client ca is given, clients may present a
+// certificate signed by it, clients without a certificate can still use bearer tokens or api keys.
+pub fn server_config(
+    cert: Arc<ServerCert>,
+    client_ca_path: Option<impl AsRef<Path>>,
+) -> io::Result<ServerConfig> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(client_ca_path)? {
+                roots.add(&cert).map_err(|err| {
+                    error!(err = err.to_string(), "invalid client ca certificate");
+                    ErrorKind::InvalidData
+                })?;
+            }
+            builder.with_client_cert_verifier(
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(builder.with_cert_resolver(cert))
+}
+
+// The https listener's certificate, it can be replaced while the listener is running so renewed
+// certificates are picked up without a restart
+pub struct ServerCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ServerCert {
+    pub fn load(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> io::Result<ServerCert> {
+        Ok(ServerCert {
+            current: RwLock::new(read_certified_key(cert_path, key_path)?),
+        })
+    }
+
+    // The current certificate is kept when the new one can't be read
+    pub fn reload(
+        &self,
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        let certified_key = read_certified_key(cert_path, key_path)?;
+        if let Ok(mut current) = self.current.write() {
+            *current = certified_key;
+        }
+        Ok(())
+    }
+
+    // Reloads the certificate whenever the cert or key file changes, so short lived certificates
+    // can be renewed in place. Connections already established keep the certificate they started with.
+    pub async fn watch(self: Arc<Self>, cert_path: String, key_path: String) {
+        let files = || vec![PathBuf::from(&cert_path), PathBuf::from(&key_path)];
+        common::config::watch_files(files, WATCH_INTERVAL, || {
+            match self.reload(&cert_path, &key_path) {
+                Ok(()) => info!(cert = cert_path, "reloaded the tls certificate"),
+                Err(err) => error!(
+                    err = err.to_string(),
+                    cert = cert_path,
+                    "failed to reload the tls certificate, keeping the current one"
+                ),
+            }
+            async {}
+        })
+        .await
+    }
+}
+
+impl ResolvesServerCert for ServerCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|current| current.clone())
+    }
+}
+
+fn read_certified_key(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+) -> io::Result<Arc<CertifiedKey>> {
+    let certs = read_certs(cert_path)?;
+    let key = sign::any_supported_type(&read_key(key_path)?).map_err(|err| {
+        error!(err = err.to_string(), "invalid tls key");
+        io::Error::from(ErrorKind::InvalidData)
+    })?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+fn read_certs(path: impl AsRef<Path>) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect())
+}
+
+fn read_key(path: impl AsRef<Path>) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => continue,
+        }
+    }
+    error!("no private key found");
+    Err(ErrorKind::InvalidData.into())
+}
+
+// The verified certificate a client presented during the tls handshake
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    fingerprint: String,
+    sans: Vec<String>,
+}
+
+impl ClientCertificate {
+    fn from_der(der: &[u8]) -> ClientCertificate {
+        let fingerprint = Sha256::digest(der)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        let mut sans = Vec::new();
+        match X509Certificate::from_der(der) {
+            Ok((_, cert)) => {
+                if let Ok(Some(san)) = cert.subject_alternative_name() {
+                    for name in san.value.general_names.iter() {
+                        match name {
+                            GeneralName::DNSName(dns) => sans.push(format!("dns:{}", dns)),
+                            GeneralName::URI(uri) => sans.push(format!("uri:{}", uri)),
+                            GeneralName::RFC822Name(email) => sans.push(format!("email:{}", email)),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            Err(err) => warn!(err = err.to_string(), "failed to parse client certificate"),
+        }
+
+        ClientCertificate { fingerprint, sans }
+    }
+
+    // Hex encoded sha256 of the der encoded certificate
+    pub fn fingerprint(&self) -> &str {
+        self.fingerprint.as_str()
+    }
+
+    // Subject alternative names prefixed with their type, e.g. dns:host.example.com or uri:spiffe://cluster/job
+    pub fn sans(&self) -> &[String] {
+        self.sans.as_slice()
+    }
+}
+
+// Registered with HttpServer::on_connect so the client certificate is available to every request on the connection
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    if let Some(cert) = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+    {
+        data.insert(ClientCertificate::from_der(cert.as_ref()));
+    }
+}
+
+impl FromRequest for ClientCertificate {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.conn_data::<ClientCertificate>()
+                .cloned()
+                .ok_or_else(|| actix_web::error::ErrorUnauthorized("no client certificate")),
+        )
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+struct ChannelTlsSettings {
+    // pem bundle of the cas storage node certificates are verified against, the system roots are
+    // used when unset
+    ca: Option<PathBuf>,
+    // client certificate and key presented to storage nodes
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    // the name verified against the storage node's certificate, for when the endpoint is an ip
+    // address or a name the certificate doesn't cover
+    domain: Option<String>,
+}
+
+impl ChannelTlsSettings {
+    // Settings for an endpoint take precedence over the defaults field by field
+    fn or(self, defaults: &ChannelTlsSettings) -> ChannelTlsSettings {
+        ChannelTlsSettings {
+            ca: self.ca.or_else(|| defaults.ca.clone()),
+            cert: self.cert.or_else(|| defaults.cert.clone()),
+            key: self.key.or_else(|| defaults.key.clone()),
+            domain: self.domain.or_else(|| defaults.domain.clone()),
+        }
+    }
+
+    fn client_config(&self) -> io::Result<ClientTlsConfig> {
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca) = &self.ca {
+            config =
+                config.ca_certificate(tonic::transport::Certificate::from_pem(std::fs::read(ca)?));
+        }
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                config = config.identity(tonic::transport::Identity::from_pem(
+                    std::fs::read(cert)?,
+                    std::fs::read(key)?,
+                ));
+            }
+            (None, None) => {}
+            _ => {
+                error!("storage channel client cert and key must be set together");
+                return Err(ErrorKind::InvalidInput.into());
+            }
+        }
+        if let Some(domain) = &self.domain {
+            config = config.domain_name(domain);
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ChannelTlsFile {
+    #[serde(default)]
+    default: ChannelTlsSettings,
+    #[serde(default)]
+    endpoints: HashMap<String, ChannelTlsSettings>,
+}
+
+// Tls settings for the gateway's channels to storage nodes, loaded from a json file like
+// {"default": {"ca": "ca.pem"}, "endpoints": {"https://10.0.0.5:50051": {"domain": "storage-0"}}}
+// Only https endpoints use tls. The settings can be reloaded, channels created afterwards use the
+// new ones.
+#[derive(Debug)]
+pub struct ChannelTls {
+    path: PathBuf,
+    current: RwLock<ChannelTlsConfigs>,
+}
+
+#[derive(Debug)]
+struct ChannelTlsConfigs {
+    default: ClientTlsConfig,
+    endpoints: HashMap<String, ClientTlsConfig>,
+    // the config file and every ca, certificate, and key it names
+    files: Vec<PathBuf>,
+}
+
+impl ChannelTls {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<ChannelTls> {
+        Ok(ChannelTls {
+            path: path.as_ref().to_path_buf(),
+            current: RwLock::new(read_channel_tls(path)?),
+        })
+    }
+
+    // The current settings are kept when the file or anything it names can't be read
+    pub fn reload(&self) -> io::Result<()> {
+        let configs = read_channel_tls(&self.path)?;
+        if let Ok(mut current) = self.current.write() {
+            *current = configs;
+        }
+        Ok(())
+    }
+
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.current
+            .read()
+            .map(|current| current.files.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn client_config(&self, endpoint: &str) -> Option<ClientTlsConfig> {
+        if !endpoint.starts_with("https://") {
+            return None;
+        }
+        let current = self.current.read().ok()?;
+        Some(
+            current
+                .endpoints
+                .get(endpoint)
+                .unwrap_or(&current.default)
+                .clone(),
+        )
+    }
+}
+
+fn read_channel_tls(path: impl AsRef<Path>) -> io::Result<ChannelTlsConfigs> {
+    let path = path.as_ref();
+    let file: ChannelTlsFile =
+        serde_json::from_reader(BufReader::new(File::open(path)?)).map_err(|err| {
+            error!(err = err.to_string(), "invalid storage channel tls config");
+            io::Error::from(ErrorKind::InvalidData)
+        })?;
+
+    let mut files = vec![path.to_path_buf()];
+    for settings in std::iter::once(&file.default).chain(file.endpoints.values()) {
+        files.extend(
+            [&settings.ca, &settings.cert, &settings.key]
+                .into_iter()
+                .flatten()
+                .filter(|file| !files.contains(file))
+                .cloned()
+                .collect::<Vec<PathBuf>>(),
+        );
+    }
+
+    let mut endpoints = HashMap::with_capacity(file.endpoints.len());
+    for (endpoint, settings) in file.endpoints {
+        endpoints.insert(endpoint, settings.or(&file.default).client_config()?);
+    }
+    Ok(ChannelTlsConfigs {
+        default: file.default.client_config()?,
+        endpoints,
+        files,
+    })
+}
@@ -0,0 +1,386 @@
+use crate::audit::Outcome;
+use crate::db::{optional, DbPool};
+use crate::retry::Rpc;
+use crate::AppData;
+use actix_web::web::Data;
+use common::auth::{AuthHeader, JwtIssuer, Scope};
+use common::storage::NamespaceStatsRequest;
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+use sqlx::any::AnyRow;
+use sqlx::{query, Result, Row};
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::metadata::MetadataMap;
+use tonic::Extensions;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_STORAGE_WARNING_PERCENT: u8 = 80;
+// how stale the limits and usage puts are checked against can be
+const CACHE_TTL: Duration = Duration::from_secs(5);
+const CACHE_CAPACITY: u64 = 10_000;
+
+// Limits on a tenant's usage across all its namespaces, unset limits are unlimited. They keep one
+// tenant from filling a cluster it shares with others, a namespace's own quota is enforced by the
+// storage nodes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TenantLimits {
+    pub max_namespaces: Option<u64>,
+    pub max_keys: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl From<AnyRow> for TenantLimits {
+    fn from(row: AnyRow) -> Self {
+        TenantLimits {
+            max_namespaces: optional::<i64>(&row, 0).map(|limit| limit as u64),
+            max_keys: optional::<i64>(&row, 1).map(|limit| limit as u64),
+            max_bytes: optional::<i64>(&row, 2).map(|limit| limit as u64),
+        }
+    }
+}
+
+impl TenantLimits {
+    // Limits are stored as signed integers, and a limit of 0 would make the tenant unusable
+    pub fn is_valid(&self) -> bool {
+        [self.max_namespaces, self.max_keys, self.max_bytes]
+            .into_iter()
+            .flatten()
+            .all(|limit| limit > 0 && limit <= i64::MAX as u64)
+    }
+
+    // Keys and bytes are only tracked for tenants limited by them
+    fn limits_data(&self) -> bool {
+        self.max_keys.is_some() || self.max_bytes.is_some()
+    }
+
+    // Bound as -1 when unlimited and stored as null, see db::DbPool
+    fn bind_values(&self) -> (i64, i64, i64) {
+        let bind = |limit: Option<u64>| limit.map_or(-1, |limit| limit as i64);
+        (
+            bind(self.max_namespaces),
+            bind(self.max_keys),
+            bind(self.max_bytes),
+        )
+    }
+}
+
+// The tenant's keys and bytes are the storage nodes' estimates as of updated_at plus what was put
+// through the gateways since, so they run ahead of the real usage until the next refresh. The
+// namespaces are counted as they are.
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct TenantUsage {
+    pub namespaces: u64,
+    pub key_count: u64,
+    pub total_bytes: u64,
+    // unix timestamp of the last refresh, 0 before the first
+    pub updated_at: i64,
+}
+
+impl From<AnyRow> for TenantUsage {
+    fn from(row: AnyRow) -> Self {
+        TenantUsage {
+            namespaces: row.get::<i64, usize>(0) as u64,
+            key_count: optional::<i64>(&row, 1).unwrap_or_default() as u64,
+            total_bytes: optional::<i64>(&row, 2).unwrap_or_default() as u64,
+            updated_at: optional(&row, 3).unwrap_or_default(),
+        }
+    }
+}
+
+// The limit a tenant is at and the limit's value
+#[derive(Debug, Clone, Copy)]
+pub struct LimitExceeded {
+    pub limit: &'static str,
+    pub max: u64,
+}
+
+impl LimitExceeded {
+    // max_bytes is the storage provisioned for the tenant, running out of it is reported as the
+    // storage being exhausted rather than as the tenant being throttled
+    pub fn is_storage(&self) -> bool {
+        self.limit == "max_bytes"
+    }
+}
+
+pub struct UsageRepo {
+    db_pool: DbPool,
+    // checked on every put, so a tenant can go over a limit by what it puts within the ttl
+    cache: Cache<Uuid, (TenantLimits, TenantUsage)>,
+}
+
+impl UsageRepo {
+    pub fn new(db_pool: DbPool) -> UsageRepo {
+        UsageRepo {
+            db_pool,
+            cache: Cache::builder()
+                .max_capacity(CACHE_CAPACITY)
+                .time_to_live(CACHE_TTL)
+                .build(),
+        }
+    }
+
+    pub async fn limits(&self, tenant_id: Uuid) -> Result<TenantLimits> {
+        query("select max_namespaces, max_keys, max_bytes from tenants where uuid = $1")
+            .bind(tenant_id.to_string())
+            .map(|row: AnyRow| row.into())
+            .fetch_one(&self.db_pool)
+            .await
+    }
+
+    // Returns false if there is no tenant with the given name
+    pub async fn set_limits(&self, name: &str, limits: &TenantLimits) -> Result<bool> {
+        let (max_namespaces, max_keys, max_bytes) = limits.bind_values();
+        let result = query("update tenants set max_namespaces = nullif($1, -1), max_keys = nullif($2, -1), max_bytes = nullif($3, -1) where name = $4")
+            .bind(max_namespaces)
+            .bind(max_keys)
+            .bind(max_bytes)
+            .bind(name)
+            .execute(&self.db_pool)
+            .await?;
+        self.cache.invalidate_all();
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn usage(&self, tenant_id: Uuid) -> Result<TenantUsage> {
+        query("select (select count(*) from namespaces where tenant_id = t.id), u.key_count, u.total_bytes, u.updated_at from tenants as t left join tenant_usage as u on u.tenant_id = t.id where t.uuid = $1")
+            .bind(tenant_id.to_string())
+            .map(|row: AnyRow| row.into())
+            .fetch_one(&self.db_pool)
+            .await
+    }
+
+    async fn cached(&self, tenant_id: Uuid) -> Result<(TenantLimits, TenantUsage)> {
+        if let Some(cached) = self.cache.get(&tenant_id).await {
+            return Ok(cached);
+        }
+        let found = (self.limits(tenant_id).await?, self.usage(tenant_id).await?);
+        self.cache.insert(tenant_id, found).await;
+        Ok(found)
+    }
+
+    // Whether the tenant can create another namespace
+    pub async fn check_namespace(&self, tenant_id: Uuid) -> Result<Option<LimitExceeded>> {
+        let limits = self.limits(tenant_id).await?;
+        let Some(max) = limits.max_namespaces else {
+            return Ok(None);
+        };
+        let usage = self.usage(tenant_id).await?;
+        Ok((usage.namespaces >= max).then_some(LimitExceeded {
+            limit: "max_namespaces",
+            max,
+        }))
+    }
+
+    // Whether a put of value_len bytes fits. The gateway can't tell whether a put adds a key, so at
+    // the key limit only puts that expect the key to exist already are let through.
+    pub async fn check_put(
+        &self,
+        tenant_id: Uuid,
+        overwrite: bool,
+        value_len: u64,
+    ) -> Result<Option<LimitExceeded>> {
+        let (limits, usage) = self.cached(tenant_id).await?;
+        if let Some(max) = limits.max_keys {
+            if !overwrite && usage.key_count >= max {
+                return Ok(Some(LimitExceeded {
+                    limit: "max_keys",
+                    max,
+                }));
+            }
+        }
+        if let Some(max) = limits.max_bytes {
+            if usage.total_bytes + value_len > max {
+                return Ok(Some(LimitExceeded {
+                    limit: "max_bytes",
+                    max,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    // Counts a put towards the tenant's usage until the next refresh, only tenants limited by keys
+    // or bytes are tracked
+    pub async fn record_put(&self, tenant_id: Uuid, new_key: bool, value_len: u64) -> Result<()> {
+        let (limits, _) = self.cached(tenant_id).await?;
+        if !limits.limits_data() {
+            return Ok(());
+        }
+        query("insert into tenant_usage (tenant_id, key_count, total_bytes) select id, $2, $3 from tenants where uuid = $1 on conflict (tenant_id) do update set key_count = tenant_usage.key_count + excluded.key_count, total_bytes = tenant_usage.total_bytes + excluded.total_bytes")
+            .bind(tenant_id.to_string())
+            .bind(new_key as i64)
+            .bind(value_len as i64)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_usage(&self, tenant_id: Uuid, key_count: u64, total_bytes: u64) -> Result<()> {
+        query("insert into tenant_usage (tenant_id, key_count, total_bytes, updated_at) select id, $2, $3, $4 from tenants where uuid = $1 on conflict (tenant_id) do update set key_count = excluded.key_count, total_bytes = excluded.total_bytes, updated_at = excluded.updated_at")
+            .bind(tenant_id.to_string())
+            .bind(key_count as i64)
+            .bind(total_bytes as i64)
+            .bind(now())
+            .execute(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    // The tenants whose keys and bytes are tracked
+    async fn tracked(&self) -> Result<Vec<Uuid>> {
+        query("select uuid from tenants where max_keys is not null or max_bytes is not null")
+            .map(|row: AnyRow| Uuid::parse_str(row.get(0)).unwrap())
+            .fetch_all(&self.db_pool)
+            .await
+    }
+}
+
+// Sums the stats of every namespace of the tenant, fails if any namespace's stats couldn't be had
+pub async fn measure(
+    app_data: &AppData,
+    tenant_id: Uuid,
+) -> std::result::Result<(u64, u64), String> {
+    let metadata: MetadataMap = app_data
+        .jwts
+        .new_scoped_identity(tenant_id, vec![Scope::Read], None)
+        .and_then(|identity| app_data.jwts.new_service_identity(&identity))
+        .map(|identity| AuthHeader::from(identity.token()).into())
+        .map_err(|err| err.to_string())?;
+    let namespaces = app_data
+        .namespaces
+        .list(tenant_id)
+        .await
+        .map_err(|err| err.to_string())?;
+    let (mut key_count, mut total_bytes) = (0, 0);
+    for namespace in namespaces {
+        let request = NamespaceStatsRequest {
+            namespace_id: namespace.id.to_string(),
+        };
+        let stats = app_data
+            .connection_manager
+            .call_idempotent(
+                Rpc::NamespaceStats,
+                namespace.storage_node.as_deref(),
+                |mut client| {
+                    let request = tonic::Request::from_parts(
+                        metadata.clone(),
+                        Extensions::default(),
+                        request.clone(),
+                    );
+                    async move { client.namespace_stats(request).await }
+                },
+            )
+            .await
+            .map_err(|status| format!("{}: {}", namespace.name, status.message()))?
+            .into_inner();
+        key_count += stats.key_count;
+        total_bytes += stats.total_bytes;
+    }
+    Ok((key_count, total_bytes))
+}
+
+// Replaces the tracked tenants' usage with the storage nodes' stats every interval. A tenant whose
+// stats can't all be gathered keeps its usage until the next pass.
+//
+// A tenant whose bytes reach warning_percent of its max_bytes is recorded in the audit log as a
+// storage_warning, once each time it crosses the threshold. Its writes are only rejected once it's
+// out of bytes, see check_put. Each gateway records the crossings it measures.
+pub async fn refresh(app_data: Data<AppData>, interval: Duration, warning_percent: u8) {
+    let mut ticker = tokio::time::interval(interval);
+    // tenants over the warning threshold as of their last refresh
+    let mut warned = HashSet::new();
+    loop {
+        ticker.tick().await;
+        let tenants = match app_data.usage.tracked().await {
+            Ok(tenants) => tenants,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to list tenants to measure");
+                continue;
+            }
+        };
+        for tenant_id in tenants {
+            let result = match measure(&app_data, tenant_id).await {
+                Ok((key_count, total_bytes)) => app_data
+                    .usage
+                    .set_usage(tenant_id, key_count, total_bytes)
+                    .await
+                    .map(|()| total_bytes)
+                    .map_err(|err| err.to_string()),
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(total_bytes) => {
+                    info!(tenant_id = tenant_id.to_string(), "refreshed tenant usage");
+                    warn_storage(
+                        &app_data,
+                        tenant_id,
+                        total_bytes,
+                        warning_percent,
+                        &mut warned,
+                    )
+                    .await;
+                }
+                Err(err) => error!(
+                    tenant_id = tenant_id.to_string(),
+                    err = err,
+                    "failed to refresh tenant usage"
+                ),
+            }
+        }
+    }
+}
+
+async fn warn_storage(
+    app_data: &AppData,
+    tenant_id: Uuid,
+    total_bytes: u64,
+    warning_percent: u8,
+    warned: &mut HashSet<Uuid>,
+) {
+    let max_bytes = match app_data.usage.limits(tenant_id).await {
+        Ok(limits) => limits.max_bytes,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to get tenant limits");
+            return;
+        }
+    };
+    let over = max_bytes.is_some_and(|max_bytes| {
+        total_bytes as u128 * 100 >= max_bytes as u128 * warning_percent as u128
+    });
+    if !over {
+        warned.remove(&tenant_id);
+        return;
+    }
+    if !warned.insert(tenant_id) {
+        return;
+    }
+    let detail = format!(
+        "using {} of {} bytes",
+        total_bytes,
+        max_bytes.unwrap_or_default()
+    );
+    warn!(
+        tenant_id = tenant_id.to_string(),
+        detail, "tenant is near its storage limit"
+    );
+    app_data
+        .audit
+        .record(
+            "storage_warning",
+            Some(tenant_id),
+            None,
+            Outcome::Success,
+            Some(&detail),
+        )
+        .await;
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
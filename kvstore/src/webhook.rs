@@ -0,0 +1,348 @@
+use crate::db::DbPool;
+use crate::AppData;
+use actix_web::web::Data;
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde::Serialize;
+use sha2::Sha256;
+use sqlx::any::AnyRow;
+use sqlx::{query, Result, Row};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+// events waiting for delivery, the gateway drops events once this many are queued
+const QUEUE_SIZE: usize = 10_000;
+// deliveries in flight at once across every webhook
+const MAX_CONCURRENT_DELIVERIES: usize = 64;
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const SECRET_PREFIX: &str = "whsec_";
+const MAX_DEAD_LETTERS: i64 = 100;
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Put,
+    Delete,
+}
+
+// A key change POSTed to the namespace's webhooks, e.g.
+// {"id": "...", "type": "put", "namespace": "users", "key": "alice", "version": 3, "timestamp": 1}
+// Events are delivered at least once and not necessarily in order, the version orders a key's puts.
+#[derive(Serialize, Debug, Clone)]
+pub struct Event {
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    #[serde(skip)]
+    pub namespace_id: Uuid,
+    pub namespace: String,
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    pub timestamp: i64,
+}
+
+impl Event {
+    pub fn new(
+        kind: EventKind,
+        namespace_id: Uuid,
+        namespace: &str,
+        key: &str,
+        version: Option<u32>,
+    ) -> Event {
+        Event {
+            id: Uuid::new_v4(),
+            kind,
+            namespace_id,
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            version,
+            timestamp: now(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub url: String,
+    pub created_at: i64,
+    // only returned when the webhook is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl From<AnyRow> for Webhook {
+    fn from(row: AnyRow) -> Self {
+        Webhook {
+            id: Uuid::parse_str(row.get(0)).unwrap(),
+            url: row.get(1),
+            created_at: row.get(2),
+            secret: None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct DeadLetter {
+    pub webhook_id: Uuid,
+    pub event: serde_json::Value,
+    pub error: String,
+    pub attempts: i64,
+    pub failed_at: i64,
+}
+
+impl From<AnyRow> for DeadLetter {
+    fn from(row: AnyRow) -> Self {
+        DeadLetter {
+            webhook_id: Uuid::parse_str(row.get(0)).unwrap(),
+            event: serde_json::from_str(row.get(1)).unwrap_or_default(),
+            error: row.get(2),
+            attempts: row.get(3),
+            failed_at: row.get(4),
+        }
+    }
+}
+
+// Webhooks are only http and https urls
+pub fn valid_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+}
+
+// The urls a namespace's key changes are POSTed to. Each event is signed with the webhook's secret,
+// the x-kvstore-signature header is "sha256=" and the hex hmac-sha256 of the x-kvstore-timestamp
+// header, a ".", and the body. Failed deliveries are retried with backoff, events that still can't
+// be delivered are kept in the namespace's dead letters.
+pub struct Webhooks {
+    db_pool: DbPool,
+    events: mpsc::Sender<Event>,
+}
+
+impl Webhooks {
+    // The receiver is handed to deliver
+    pub fn new(db_pool: DbPool) -> (Webhooks, mpsc::Receiver<Event>) {
+        let (events, receiver) = mpsc::channel(QUEUE_SIZE);
+        (Webhooks { db_pool, events }, receiver)
+    }
+
+    // Creates a webhook with a new secret, the secret is only returned here
+    pub async fn create(&self, namespace_id: Uuid, url: &str) -> Result<Webhook> {
+        let secret = format!("{}{}", SECRET_PREFIX, Uuid::new_v4().simple());
+        let webhook = Webhook {
+            id: Uuid::new_v4(),
+            url: url.to_string(),
+            created_at: now(),
+            secret: Some(secret.clone()),
+        };
+        query("insert into webhooks (uuid, namespace_id, url, secret, created_at) select $1, id, $2, $3, $4 from namespaces where uuid = $5")
+            .bind(webhook.id.to_string())
+            .bind(&webhook.url)
+            .bind(secret)
+            .bind(webhook.created_at)
+            .bind(namespace_id.to_string())
+            .execute(&self.db_pool)
+            .await?;
+        Ok(webhook)
+    }
+
+    pub async fn list(&self, namespace_id: Uuid) -> Result<Vec<Webhook>> {
+        query("select w.uuid, w.url, w.created_at from webhooks as w inner join namespaces as ns on w.namespace_id = ns.id where ns.uuid = $1 order by w.created_at")
+            .bind(namespace_id.to_string())
+            .map(|row: AnyRow| row.into())
+            .fetch_all(&self.db_pool)
+            .await
+    }
+
+    // Deletes the webhook and its dead letters. Returns false if the namespace has no such webhook.
+    pub async fn delete(&self, namespace_id: Uuid, id: Uuid) -> Result<bool> {
+        let mut tx = self.db_pool.begin().await?;
+        let webhook = "(select w.id from webhooks as w inner join namespaces as ns on w.namespace_id = ns.id where w.uuid = $1 and ns.uuid = $2)";
+        query(&format!(
+            "delete from webhook_dead_letters where webhook_id in {}",
+            webhook
+        ))
+        .bind(id.to_string())
+        .bind(namespace_id.to_string())
+        .execute(&mut *tx)
+        .await?;
+        let result = query(&format!("delete from webhooks where id in {}", webhook))
+            .bind(id.to_string())
+            .bind(namespace_id.to_string())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // The namespace's most recent events that couldn't be delivered
+    pub async fn dead_letters(&self, namespace_id: Uuid) -> Result<Vec<DeadLetter>> {
+        query("select w.uuid, d.event, d.error, d.attempts, d.failed_at from webhook_dead_letters as d inner join webhooks as w on d.webhook_id = w.id inner join namespaces as ns on w.namespace_id = ns.id where ns.uuid = $1 order by d.id desc limit $2")
+            .bind(namespace_id.to_string())
+            .bind(MAX_DEAD_LETTERS)
+            .map(|row: AnyRow| row.into())
+            .fetch_all(&self.db_pool)
+            .await
+    }
+
+    // The webhooks an event is sent to, with their secrets
+    async fn targets(&self, namespace_id: Uuid) -> Result<Vec<(Uuid, String, String)>> {
+        query("select w.uuid, w.url, w.secret from webhooks as w inner join namespaces as ns on w.namespace_id = ns.id where ns.uuid = $1")
+            .bind(namespace_id.to_string())
+            .map(|row: AnyRow| (Uuid::parse_str(row.get(0)).unwrap(), row.get(1), row.get(2)))
+            .fetch_all(&self.db_pool)
+            .await
+    }
+
+    async fn dead_letter(
+        &self,
+        webhook_id: Uuid,
+        event: &str,
+        error: &str,
+        attempts: u32,
+    ) -> Result<()> {
+        query("insert into webhook_dead_letters (webhook_id, event, error, attempts, failed_at) select id, $2, $3, $4, $5 from webhooks where uuid = $1")
+            .bind(webhook_id.to_string())
+            .bind(event)
+            .bind(error)
+            .bind(attempts as i64)
+            .bind(now())
+            .execute(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    // Queues the event for delivery without waiting for it, a full queue drops the event
+    pub fn notify(&self, event: Event) {
+        if let Err(err) = self.events.try_send(event) {
+            warn!(
+                err = err.to_string(),
+                "webhook queue is full, dropping event"
+            );
+        }
+    }
+}
+
+// Delivers queued events to their namespace's webhooks until the gateway stops
+pub async fn deliver(app_data: Data<AppData>, mut events: mpsc::Receiver<Event>) {
+    let client = match reqwest::Client::builder().timeout(DELIVERY_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => {
+            error!(err = err.to_string(), "failed to create the webhook client");
+            return;
+        }
+    };
+    let deliveries = Arc::new(Semaphore::new(MAX_CONCURRENT_DELIVERIES));
+    while let Some(event) = events.recv().await {
+        let targets = match app_data.webhooks.targets(event.namespace_id).await {
+            Ok(targets) => targets,
+            Err(err) => {
+                error!(
+                    err = err.to_string(),
+                    "failed to look up webhooks, dropping event"
+                );
+                continue;
+            }
+        };
+        if targets.is_empty() {
+            continue;
+        }
+        let body = match serde_json::to_string(&event) {
+            Ok(body) => body,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to serialize webhook event");
+                continue;
+            }
+        };
+        for (webhook_id, url, secret) in targets {
+            // waiting here holds events in the queue while every delivery slot is busy
+            let Ok(permit) = deliveries.clone().acquire_owned().await else {
+                return;
+            };
+            let (app_data, client, body) = (app_data.clone(), client.clone(), body.clone());
+            let event_id = event.id;
+            actix_web::rt::spawn(async move {
+                let _permit = permit;
+                let Err((attempts, err)) = send(&client, &url, &secret, event_id, &body).await
+                else {
+                    return;
+                };
+                warn!(
+                    webhook_id = webhook_id.to_string(),
+                    event_id = event_id.to_string(),
+                    err = err,
+                    "failed to deliver webhook event"
+                );
+                if let Err(err) = app_data
+                    .webhooks
+                    .dead_letter(webhook_id, &body, &err, attempts)
+                    .await
+                {
+                    error!(
+                        err = err.to_string(),
+                        "failed to record webhook dead letter"
+                    );
+                }
+            });
+        }
+    }
+    info!("webhook delivery stopped");
+}
+
+// Posts the event until the webhook answers with a success status, on failure returns the attempts
+// made and the last error
+async fn send(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &str,
+    event_id: Uuid,
+    body: &str,
+) -> std::result::Result<(), (u32, String)> {
+    let mut last_error = String::new();
+    for attempt in 1..=MAX_ATTEMPTS {
+        if attempt > 1 {
+            tokio::time::sleep(RETRY_BASE_DELAY * 2u32.pow(attempt - 2)).await;
+        }
+        let timestamp = now().to_string();
+        let result = client
+            .post(url)
+            .header("content-type", "application/json")
+            .header("x-kvstore-event-id", event_id.to_string())
+            .header("x-kvstore-timestamp", &timestamp)
+            .header("x-kvstore-signature", signature(secret, &timestamp, body))
+            .body(body.to_string())
+            .send()
+            .await;
+        match result {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => last_error = format!("webhook responded with {}", response.status()),
+            Err(err) => last_error = err.to_string(),
+        }
+    }
+    Err((MAX_ATTEMPTS, last_error))
+}
+
+fn signature(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,737 @@
+use crate::auth::ClientCertificate;
+use crate::backup::Backups;
+use crate::compaction::{self, CompactionScheduler};
+use crate::error::Error;
+use crate::format::EntryMetadata;
+use crate::fsck;
+use crate::lookup::PartitionLookup;
+use crate::merkle;
+use crate::orphans::Orphans;
+use crate::partition::{Partition, RawEntry};
+use crate::replication::{self, CheckpointDir, FollowerStatus, Followers};
+use crate::transform::Transforms;
+use common::auth::{Identity, Scope};
+use common::logging::{self, FilterStatus, LogLevel};
+use common::storage::{
+    node_admin_server::NodeAdmin, AddPartitionRequest, BackupRequest, BackupResponse,
+    CheckpointChunk, CompactionStatus, DeleteTenantRequest, DeleteTenantResponse,
+    DumpPartitionRequest, DumpPartitionResponse, DumpedKey, FollowPartitionRequest,
+    ListOrphanedPartitionsResponse, ListPartitionsRequest, ListPartitionsResponse, LogFilter,
+    MerkleTreeResponse, OrphanedPartition, PartitionInfo, PartitionRequest,
+    ReadReplicationLogRequest, ReadReplicationLogResponse, RepairLeavesRequest,
+    RepairLeavesResponse, ReplicationStatus, ReplicationStatusResponse,
+    SetCompactionsPausedRequest, SetLogFilterRequest, VersionInfo,
+};
+use common::version::BuildInfo;
+use futures::channel::mpsc;
+use futures::Stream;
+use prost_types::Timestamp;
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tonic::{Code, Request, Response, Status};
+use tracing::{error, info, warn};
+use url::Url;
+use uuid::Uuid;
+
+// checkpoint chunks read ahead of the follower taking them
+const CHECKPOINT_BUFFER: usize = 4;
+
+type CheckpointChunks = Pin<Box<dyn Stream<Item = Result<CheckpointChunk, Status>> + Send>>;
+
+// Operational rpcs for the storage node, called with an admin scoped service token
+#[derive(Clone)]
+pub struct NodeAdminService {
+    log_level: LogLevel,
+    build_info: BuildInfo,
+    partition_lookup: Arc<PartitionLookup>,
+    transforms: Arc<Transforms>,
+    backups: Arc<Backups>,
+    compactions: Arc<CompactionScheduler>,
+    orphans: Arc<Orphans>,
+    // set when the node can follow leaders, see replication
+    followers: Option<Arc<Followers>>,
+}
+
+// The node's maintenance the admin service runs and reports on besides the partitions themselves
+pub struct Maintenance {
+    pub backups: Backups,
+    pub compactions: Arc<CompactionScheduler>,
+    pub orphans: Arc<Orphans>,
+    // set when the node can follow leaders, see replication
+    pub followers: Option<Arc<Followers>>,
+}
+
+impl NodeAdminService {
+    pub fn new(
+        log_level: LogLevel,
+        build_info: BuildInfo,
+        partition_lookup: Arc<PartitionLookup>,
+        transforms: Arc<Transforms>,
+        maintenance: Maintenance,
+    ) -> NodeAdminService {
+        NodeAdminService {
+            log_level,
+            build_info,
+            partition_lookup,
+            transforms,
+            backups: Arc::new(maintenance.backups),
+            compactions: maintenance.compactions,
+            orphans: maintenance.orphans,
+            followers: maintenance.followers,
+        }
+    }
+
+    // The partitions of a namespace, of a tenant, or all of them
+    fn select(
+        &self,
+        tenant_id: &Option<String>,
+        namespace_id: &Option<String>,
+    ) -> Result<Vec<Partition>, Error> {
+        let tenant_id = match tenant_id {
+            Some(id) => Some(parse_id("tenant_id", id)?),
+            None => None,
+        };
+        let namespace_id = match namespace_id {
+            Some(id) => Some(parse_id("namespace_id", id)?),
+            None => None,
+        };
+        Ok(self
+            .partition_lookup
+            .all_partitions()
+            .into_iter()
+            .filter(|partition| {
+                tenant_id.is_none_or(|id| id == partition.tenant_id)
+                    && namespace_id.is_none_or(|id| id == partition.namespace_id)
+            })
+            .collect())
+    }
+
+    fn partition(&self, id: &str) -> Result<Partition, Error> {
+        self.partition_lookup
+            .find_partition(parse_id("partition_id", id)?)
+            .ok_or(Error::PartitionNotFound)
+    }
+
+    // A follower's lag is as of its last read of the leader's log
+    fn replication_status(&self, partition: &Partition) -> ReplicationStatus {
+        let leader = partition.leader();
+        let follower = match (&leader, &self.followers) {
+            (Some(_), Some(followers)) => followers.status(partition.id),
+            _ => FollowerStatus::default(),
+        };
+        ReplicationStatus {
+            partition_id: partition.id.to_string(),
+            leader,
+            next_sequence: partition.log_next().unwrap_or_default(),
+            leader_next_sequence: follower.leader_next,
+            lag_entries: follower.lag_entries,
+            lag_seconds: follower.lag_seconds,
+            last_error: follower.last_error,
+            bootstrapping: follower.bootstrapping,
+        }
+    }
+
+    // A partition's leader doesn't change while it's copied from one
+    fn check_not_bootstrapping(&self, id: Uuid) -> Result<(), Error> {
+        match &self.followers {
+            Some(followers) if followers.status(id).bootstrapping => Err(Error::Bootstrapping),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn parse_id(field: &'static str, id: &str) -> Result<Uuid, Error> {
+    Uuid::parse_str(id).map_err(|source| Error::InvalidId { field, source })
+}
+
+fn partition_info(partition: &Partition) -> Result<PartitionInfo, Error> {
+    let stats = partition.stats()?;
+    Ok(PartitionInfo {
+        tenant_id: partition.tenant_id.to_string(),
+        namespace_id: partition.namespace_id.to_string(),
+        partition_id: partition.id.to_string(),
+        key_count: stats.key_count,
+        total_bytes: stats.total_bytes,
+    })
+}
+
+fn compaction_status(status: compaction::Status) -> CompactionStatus {
+    CompactionStatus {
+        schedule: status.schedule,
+        paused: status.paused,
+        in_window: status.in_window,
+        compacting: status.compacting.map(|id| id.to_string()),
+    }
+}
+
+fn is_admin<T>(request: &Request<T>) -> bool {
+    // callers with a client certificate are only let through on an admin listener using mtls
+    let admin = request.extensions().get::<ClientCertificate>().is_some()
+        || request
+            .extensions()
+            .get::<Identity>()
+            .is_some_and(|identity| identity.has_scope(Scope::Admin));
+    if !admin {
+        error!("caller does not have the admin scope");
+    }
+    admin
+}
+
+fn log_filter(status: FilterStatus) -> LogFilter {
+    LogFilter {
+        level: status.level,
+        directives: status.directives,
+        expires_at: status
+            .expires_at
+            .map(|expires_at| Timestamp::from(UNIX_EPOCH + Duration::from_secs(expires_at))),
+    }
+}
+
+#[tonic::async_trait]
+impl NodeAdmin for NodeAdminService {
+    async fn get_log_filter(&self, request: Request<()>) -> Result<Response<LogFilter>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        Ok(Response::new(log_filter(self.log_level.current())))
+    }
+
+    // an empty directives string clears the override
+    async fn set_log_filter(
+        &self,
+        request: Request<SetLogFilterRequest>,
+    ) -> Result<Response<LogFilter>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let request = request.into_inner();
+        let result = match request.directives.is_empty() {
+            true => self.log_level.clear(),
+            false => self.log_level.override_with(
+                &request.directives,
+                request.duration_secs.map(Duration::from_secs),
+            ),
+        };
+        match result {
+            Ok(()) => Ok(Response::new(log_filter(self.log_level.current()))),
+            Err(logging::Error::InvalidFilter(err)) => {
+                Err(Status::new(Code::InvalidArgument, err.to_string()))
+            }
+            Err(err) => {
+                error!(err = err.to_string(), "failed to set log filter");
+                Err(Status::new(Code::Internal, "internal error"))
+            }
+        }
+    }
+
+    async fn version(&self, request: Request<()>) -> Result<Response<VersionInfo>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let build_info = &self.build_info;
+        Ok(Response::new(VersionInfo {
+            service: build_info.service.to_string(),
+            version: build_info.version.to_string(),
+            git_hash: build_info.git_hash.to_string(),
+            build_time: Some(Timestamp::from(
+                UNIX_EPOCH + Duration::from_secs(build_info.build_timestamp),
+            )),
+            profile: build_info.profile.to_string(),
+            features: build_info
+                .features
+                .iter()
+                .map(|feature| feature.to_string())
+                .collect(),
+            protocol_version: build_info.protocol_version,
+        }))
+    }
+
+    async fn list_partitions(
+        &self,
+        request: Request<ListPartitionsRequest>,
+    ) -> Result<Response<ListPartitionsResponse>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let request = request.into_inner();
+        let mut partitions = Vec::new();
+        for partition in self.select(&request.tenant_id, &request.namespace_id)? {
+            partitions.push(partition_info(&partition).inspect_err(|err| {
+                error!(err = err.to_string(), "failed to get partition stats");
+            })?);
+        }
+        Ok(Response::new(ListPartitionsResponse { partitions }))
+    }
+
+    async fn compact_partition(
+        &self,
+        request: Request<PartitionRequest>,
+    ) -> Result<Response<()>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let partition = self.partition(&request.get_ref().partition_id)?;
+        info!(
+            partition_id = partition.id.to_string(),
+            "compacting partition"
+        );
+        // a full compaction can take a while, it runs off the async workers
+        tokio::task::spawn_blocking(move || partition.compact())
+            .await
+            .map_err(|err| {
+                error!(err = err.to_string(), "compaction failed");
+                Status::new(Code::Internal, "internal error")
+            })?;
+        Ok(Response::new(()))
+    }
+
+    async fn dump_partition(
+        &self,
+        request: Request<DumpPartitionRequest>,
+    ) -> Result<Response<DumpPartitionResponse>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let request = request.into_inner();
+        let partition = self.partition(&request.partition_id)?;
+        let limit = Some(request.limit)
+            .filter(|limit| *limit > 0)
+            .unwrap_or(crate::DEFAULT_LIST_LIMIT)
+            .clamp(1, crate::MAX_LIST_LIMIT) as usize;
+        let (entries, next) = partition.dump(
+            request.start_at.as_deref(),
+            request.end_before.as_deref(),
+            request.sample_every.max(1) as usize,
+            limit,
+        )?;
+        let keys = entries
+            .into_iter()
+            .map(|entry| dumped_key(entry, request.include_values))
+            .collect();
+        Ok(Response::new(DumpPartitionResponse { keys, next }))
+    }
+
+    async fn get_compaction_status(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<CompactionStatus>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        Ok(Response::new(compaction_status(self.compactions.status())))
+    }
+
+    async fn set_compactions_paused(
+        &self,
+        request: Request<SetCompactionsPausedRequest>,
+    ) -> Result<Response<CompactionStatus>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        self.compactions.set_paused(request.get_ref().paused);
+        Ok(Response::new(compaction_status(self.compactions.status())))
+    }
+
+    async fn add_partition(
+        &self,
+        request: Request<AddPartitionRequest>,
+    ) -> Result<Response<PartitionInfo>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let request = request.into_inner();
+        let tenant_id = parse_id("tenant_id", &request.tenant_id)?;
+        let namespace_id = parse_id("namespace_id", &request.namespace_id)?;
+        let id = match &request.partition_id {
+            Some(id) => parse_id("partition_id", id)?,
+            None => Uuid::new_v4(),
+        };
+        if self.partition_lookup.find_partition(id).is_some() {
+            return Err(Status::new(Code::AlreadyExists, "partition already exists"));
+        }
+
+        let partition = self
+            .partition_lookup
+            .open_partition(id, tenant_id, namespace_id)
+            .inspect_err(|err| {
+                error!(err = err.to_string(), "failed to open partition");
+            })?;
+        let info = partition_info(&partition)?;
+        self.partition_lookup
+            .add_partition(partition)
+            .map_err(|err| {
+                error!(err = err.to_string(), "failed to save partitions");
+                Status::new(Code::Internal, "internal error")
+            })?;
+        Ok(Response::new(info))
+    }
+
+    async fn remove_partition(
+        &self,
+        request: Request<PartitionRequest>,
+    ) -> Result<Response<()>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let id = parse_id("partition_id", &request.get_ref().partition_id)?;
+        match self.partition_lookup.remove_partition(id) {
+            Ok(Some(_)) => Ok(Response::new(())),
+            Ok(None) => Err(Error::PartitionNotFound.into()),
+            Err(err) => {
+                error!(err = err.to_string(), "failed to save partitions");
+                Err(Status::new(Code::Internal, "internal error"))
+            }
+        }
+    }
+
+    async fn delete_tenant(
+        &self,
+        request: Request<DeleteTenantRequest>,
+    ) -> Result<Response<DeleteTenantResponse>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let tenant_id = parse_id("tenant_id", &request.get_ref().tenant_id)?;
+        info!(tenant_id = tenant_id.to_string(), "deleting tenant");
+
+        let partitions = self
+            .partition_lookup
+            .remove_tenant(tenant_id)
+            .map_err(|err| {
+                error!(err = err.to_string(), "failed to save partitions");
+                Status::new(Code::Internal, "internal error")
+            })?;
+        let partitions_deleted = partitions.len() as u32;
+
+        if let Err(err) = self.transforms.remove_tenant(tenant_id) {
+            warn!(
+                err = err.to_string(),
+                "failed to delete the tenant's transforms"
+            );
+        }
+
+        // the partitions are no longer routed to, so a file that can't be deleted is only wasted disk
+        let partition_lookup = self.partition_lookup.clone();
+        tokio::task::spawn_blocking(move || {
+            for partition in partitions {
+                let id = partition.id;
+                // release this handle on the database before its files go away
+                drop(partition);
+                if let Err(err) = partition_lookup.delete_partition_files(id) {
+                    warn!(
+                        partition_id = id.to_string(),
+                        err = err.to_string(),
+                        "failed to delete partition files, they're left on disk"
+                    );
+                }
+            }
+        })
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to delete partition files");
+            Status::new(Code::Internal, "internal error")
+        })?;
+
+        Ok(Response::new(DeleteTenantResponse { partitions_deleted }))
+    }
+
+    async fn backup(
+        &self,
+        request: Request<BackupRequest>,
+    ) -> Result<Response<BackupResponse>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let request = request.into_inner();
+        let upload_to = request
+            .upload_to
+            .as_deref()
+            .map(Url::parse)
+            .transpose()
+            .map_err(Error::InvalidUploadUrl)?;
+        let partitions = self.select(&request.tenant_id, &request.namespace_id)?;
+        if partitions.is_empty() {
+            return Err(Error::PartitionNotFound.into());
+        }
+        info!(partitions = partitions.len(), "taking backup");
+
+        // checkpoints flush memtables and checksumming reads every file, so it runs off the async workers
+        let backups = self.backups.clone();
+        let partition_lookup = self.partition_lookup.clone();
+        let build_info = self.build_info.clone();
+        let manifest = tokio::task::spawn_blocking(move || {
+            backups.create(&partition_lookup, &partitions, &build_info)
+        })
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "backup failed");
+            Status::new(Code::Internal, "internal error")
+        })?
+        .inspect_err(|err| error!(err = err.to_string(), "backup failed"))?;
+
+        let uploaded_to = self
+            .backups
+            .upload(&manifest, upload_to.as_ref())
+            .await
+            .inspect_err(|err| error!(err = err.to_string(), "failed to upload backup"))?;
+
+        Ok(Response::new(BackupResponse {
+            backup_id: manifest.backup_id.clone(),
+            partitions: manifest.partitions.len() as u32,
+            total_bytes: manifest.total_bytes(),
+            uploaded_to,
+        }))
+    }
+
+    async fn list_orphaned_partitions(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<ListOrphanedPartitionsResponse>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let orphans = self.orphans.clone();
+        let partition_lookup = self.partition_lookup.clone();
+        let report = tokio::task::spawn_blocking(move || orphans.find(&partition_lookup))
+            .await
+            .map_err(|err| {
+                error!(err = err.to_string(), "failed to find orphaned partitions");
+                Status::new(Code::Internal, "internal error")
+            })?
+            .map_err(|err| {
+                error!(err = err.to_string(), "failed to find orphaned partitions");
+                Status::new(Code::Internal, "internal error")
+            })?;
+        Ok(Response::new(ListOrphanedPartitionsResponse {
+            orphaned: report
+                .orphaned
+                .into_iter()
+                .map(|orphan| OrphanedPartition {
+                    partition_id: orphan.id.to_string(),
+                    bytes: orphan.bytes,
+                    first_seen: Some(Timestamp::from(orphan.first_seen)),
+                    quarantined: orphan.quarantined,
+                })
+                .collect(),
+            missing: report.missing.iter().map(Uuid::to_string).collect(),
+        }))
+    }
+
+    async fn read_replication_log(
+        &self,
+        request: Request<ReadReplicationLogRequest>,
+    ) -> Result<Response<ReadReplicationLogResponse>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let request = request.into_inner();
+        let partition = self.partition(&request.partition_id)?;
+        let limit = Some(request.limit)
+            .filter(|limit| *limit > 0)
+            .unwrap_or(replication::DEFAULT_READ_LIMIT)
+            .min(replication::MAX_READ_LIMIT) as usize;
+        let from = request.from_sequence;
+        let (entries, next_sequence) =
+            tokio::task::spawn_blocking(move || partition.read_log(from, limit))
+                .await
+                .map_err(|err| {
+                    error!(err = err.to_string(), "failed to read replication log");
+                    Status::new(Code::Internal, "internal error")
+                })?
+                .inspect_err(|err| {
+                    warn!(err = err.to_string(), "failed to read replication log")
+                })?;
+        Ok(Response::new(ReadReplicationLogResponse {
+            entries: entries
+                .iter()
+                .map(|(sequence, entry)| entry.to_proto(*sequence))
+                .collect(),
+            next_sequence,
+        }))
+    }
+
+    // The partition keeps the keys it has and applies the leader's log from its own log's end, so
+    // it has to start out new or as a copy of the leader's partition as of that point. One whose
+    // log ends before the leader's starts is bootstrapped from a checkpoint of the leader's.
+    async fn follow_partition(
+        &self,
+        request: Request<FollowPartitionRequest>,
+    ) -> Result<Response<ReplicationStatus>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let Some(followers) = &self.followers else {
+            return Err(Error::ReplicationDisabled.into());
+        };
+        let request = request.into_inner();
+        let id = parse_id("partition_id", &request.partition_id)?;
+        replication::leader_channel(&request.leader)?;
+        self.check_not_bootstrapping(id)?;
+        let partition = self
+            .partition_lookup
+            .set_leader(id, Some(request.leader))
+            .map_err(|err| {
+                error!(err = err.to_string(), "failed to save partitions");
+                Status::new(Code::Internal, "internal error")
+            })?
+            .ok_or(Error::PartitionNotFound)?;
+        followers.remove(id);
+        Ok(Response::new(self.replication_status(&partition)))
+    }
+
+    async fn promote_partition(
+        &self,
+        request: Request<PartitionRequest>,
+    ) -> Result<Response<ReplicationStatus>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let id = parse_id("partition_id", &request.get_ref().partition_id)?;
+        self.check_not_bootstrapping(id)?;
+        let partition = self
+            .partition_lookup
+            .set_leader(id, None)
+            .map_err(|err| {
+                error!(err = err.to_string(), "failed to save partitions");
+                Status::new(Code::Internal, "internal error")
+            })?
+            .ok_or(Error::PartitionNotFound)?;
+        if let Some(followers) = &self.followers {
+            followers.remove(id);
+        }
+        Ok(Response::new(self.replication_status(&partition)))
+    }
+
+    // Every partition that logs its writes or follows a leader
+    async fn get_replication_status(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<ReplicationStatusResponse>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let partitions = self
+            .partition_lookup
+            .all_partitions()
+            .iter()
+            .filter(|partition| partition.log_next().is_some() || partition.leader().is_some())
+            .map(|partition| self.replication_status(partition))
+            .collect();
+        Ok(Response::new(ReplicationStatusResponse { partitions }))
+    }
+
+    // The log's end is read before the tree is built, so the tree has every entry before it
+    async fn get_merkle_tree(
+        &self,
+        request: Request<PartitionRequest>,
+    ) -> Result<Response<MerkleTreeResponse>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let partition = self.partition(&request.get_ref().partition_id)?;
+        let next_sequence = partition.log_next().ok_or(Error::ReplicationDisabled)?;
+        let tree = tokio::task::spawn_blocking(move || partition.merkle_tree())
+            .await
+            .map_err(|err| {
+                error!(err = err.to_string(), "failed to build merkle tree");
+                Status::new(Code::Internal, "internal error")
+            })?
+            .inspect_err(|err| error!(err = err.to_string(), "failed to build merkle tree"))?;
+        Ok(Response::new(MerkleTreeResponse {
+            leaves: tree.leaves().iter().map(|leaf| leaf.to_vec()).collect(),
+            next_sequence,
+        }))
+    }
+
+    async fn repair_leaves(
+        &self,
+        request: Request<RepairLeavesRequest>,
+    ) -> Result<Response<RepairLeavesResponse>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let request = request.into_inner();
+        let partition = self.partition(&request.partition_id)?;
+        if let Some(leaf) = request
+            .leaves
+            .iter()
+            .find(|leaf| **leaf as usize >= merkle::LEAVES)
+        {
+            return Err(Error::InvalidLeaf(*leaf).into());
+        }
+        let leaves: HashSet<u32> = request.leaves.into_iter().collect();
+        let entries = tokio::task::spawn_blocking(move || partition.leaf_entries(&leaves, true))
+            .await
+            .map_err(|err| {
+                error!(err = err.to_string(), "failed to read merkle tree leaves");
+                Status::new(Code::Internal, "internal error")
+            })?
+            .inspect_err(|err| {
+                error!(err = err.to_string(), "failed to read merkle tree leaves")
+            })?;
+        Ok(Response::new(replication::repairs(entries, request.keys)))
+    }
+
+    type StreamCheckpointStream = CheckpointChunks;
+
+    // Writes are paused while the partition is checkpointed, so the checkpoint's log has every
+    // entry before its end. The checkpoint is deleted once it's sent or the follower goes away.
+    async fn stream_checkpoint(
+        &self,
+        request: Request<PartitionRequest>,
+    ) -> Result<Response<CheckpointChunks>, Status> {
+        if !is_admin(&request) {
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+        let partition = self.partition(&request.get_ref().partition_id)?;
+        if partition.log_next().is_none() {
+            return Err(Error::ReplicationDisabled.into());
+        }
+        info!(
+            partition_id = partition.id.to_string(),
+            "streaming partition checkpoint"
+        );
+        let lookup = self.partition_lookup.clone();
+        let dir = tokio::task::spawn_blocking(move || {
+            let dir = CheckpointDir::new(lookup.data_dir(), Uuid::new_v4())?;
+            let _paused = lookup.pause_writes();
+            partition.checkpoint(dir.path())?;
+            Ok::<_, Error>(dir)
+        })
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "failed to checkpoint partition");
+            Status::new(Code::Internal, "internal error")
+        })?
+        .inspect_err(|err| error!(err = err.to_string(), "failed to checkpoint partition"))?;
+
+        let (sender, receiver) = mpsc::channel(CHECKPOINT_BUFFER);
+        tokio::task::spawn_blocking(move || replication::send_checkpoint(dir, sender));
+        Ok(Response::new(Box::pin(receiver)))
+    }
+}
+
+// Decodes a key's metadata when it's in the encoding this build writes and checks it against
+// the value
+fn dumped_key(entry: RawEntry, include_value: bool) -> DumpedKey {
+    let metadata = EntryMetadata::decode(&entry.metadata);
+    let (crc, version) = (
+        metadata.map(|metadata| metadata.crc),
+        metadata.map(|metadata| metadata.version),
+    );
+    let crc_matches = match (&entry.value, crc) {
+        (Some(value), Some(crc)) => fsck::crc(&entry.key, value) == crc,
+        _ => false,
+    };
+    DumpedKey {
+        value_len: entry.value.as_ref().map(|value| value.len() as u64),
+        value: entry.value.filter(|_| include_value),
+        key: entry.key.into_vec(),
+        raw_metadata: entry.metadata.into_vec(),
+        crc,
+        version,
+        crc_matches,
+    }
+}
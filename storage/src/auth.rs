@@ -1,15 +1,21 @@
-use common::auth::{JwtValidator, RsaJwtValidator};
+use crate::metrics::RpcTenant;
+use common::auth::{
+    AuthHeader, Identity, JwtValidator, KeyJwtValidator, Scope, SOURCE_AUTHORIZATION_METADATA,
+};
+use common::read_file_bytes;
 use tonic::service::Interceptor;
+use tonic::transport::{Certificate, ServerTlsConfig};
 use tonic::{Code, Request, Status};
 use tracing::{error, info};
+use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct AuthInterceptor {
-    jwt_validator: RsaJwtValidator,
+    jwt_validator: KeyJwtValidator,
 }
 
 impl AuthInterceptor {
-    pub fn new(jwt_validator: RsaJwtValidator) -> AuthInterceptor {
+    pub fn new(jwt_validator: KeyJwtValidator) -> AuthInterceptor {
         AuthInterceptor { jwt_validator }
     }
 }
@@ -26,11 +32,113 @@ impl Interceptor for AuthInterceptor {
             return Err(Status::new(Code::NotFound, "not found"));
         };
 
+        // tenant tokens are never valid here, the gateway has to mint a service token for the call
+        if !identity.is_service() {
+            error!("not a service token");
+            return Err(Status::new(Code::Unauthenticated, "service token required"));
+        }
+
+        if identity.scopes().is_empty() {
+            error!("token has no scopes");
+            return Err(Status::new(Code::PermissionDenied, "permission denied"));
+        }
+
         info!(
             tenant_id = identity.tenant_id().to_string(),
+            service = identity.actor(),
             "authenticated as tenant"
         );
+        if let Some(tenant) = request.extensions().get::<RpcTenant>() {
+            tenant.set(identity.tenant_id());
+        }
+
+        // a call reading from another tenant's namespace carries a service token for that tenant
+        if request
+            .metadata()
+            .contains_key(SOURCE_AUTHORIZATION_METADATA)
+        {
+            let source =
+                AuthHeader::from_metadata(request.metadata(), SOURCE_AUTHORIZATION_METADATA)
+                    .ok()
+                    .and_then(|header| self.jwt_validator.parse(header).ok())
+                    .filter(|source| source.is_service() && !source.scopes().is_empty());
+            let Some(source) = source else {
+                error!("invalid source auth header");
+                return Err(Status::new(Code::PermissionDenied, "permission denied"));
+            };
+            info!(
+                tenant_id = source.tenant_id().to_string(),
+                "reading as source tenant"
+            );
+            request.extensions_mut().insert(SourceIdentity(source));
+        }
+
         request.extensions_mut().insert(identity);
         Ok(request)
     }
 }
+
+// The identity a call reads from another tenant's namespace as, handlers use the caller's identity
+// when there isn't one
+pub struct SourceIdentity(pub Identity);
+
+// Marks a request on the admin listener made with a client certificate the tls handshake verified
+#[derive(Debug, Clone, Copy)]
+pub struct ClientCertificate;
+
+// Authenticates callers of the admin listener as admin_auth is configured
+#[derive(Debug, Clone)]
+pub enum AdminInterceptor {
+    Token(AuthInterceptor),
+    ClientCertificate,
+}
+
+impl Interceptor for AdminInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        match self {
+            AdminInterceptor::Token(interceptor) => interceptor.call(request),
+            AdminInterceptor::ClientCertificate => {
+                if request.peer_certs().is_none_or(|certs| certs.is_empty()) {
+                    error!("no client certificate");
+                    return Err(Status::new(
+                        Code::Unauthenticated,
+                        "client certificate required",
+                    ));
+                }
+                info!("authenticated with a client certificate");
+                request.extensions_mut().insert(ClientCertificate);
+                Ok(request)
+            }
+        }
+    }
+}
+
+// The admin listener's tls settings, with a client ca every client has to present a certificate
+// signed by it
+pub fn admin_tls_config(
+    cert: &str,
+    key: &str,
+    client_ca: Option<&str>,
+) -> Result<ServerTlsConfig, common::error::Error> {
+    let identity =
+        tonic::transport::Identity::from_pem(read_file_bytes(cert)?, read_file_bytes(key)?);
+    let mut tls = ServerTlsConfig::new().identity(identity);
+    if let Some(client_ca) = client_ca {
+        tls = tls.client_ca_root(Certificate::from_pem(read_file_bytes(client_ca)?));
+    }
+    Ok(tls)
+}
+
+// The interceptor can't tell which rpc is being called, so handlers check the token's scopes and
+// namespace restrictions once they know the namespace being accessed
+pub fn authorized(identity: &Identity, scope: Scope, namespace_id: Uuid) -> bool {
+    if identity.allows(scope, namespace_id) {
+        return true;
+    }
+    error!(
+        tenant_id = identity.tenant_id().to_string(),
+        scope = ?scope,
+        "token does not allow the operation on the namespace"
+    );
+    false
+}
@@ -0,0 +1,329 @@
+use crate::error::Error;
+use crate::lookup::PartitionLookup;
+use crate::partition::Partition;
+use common::version::BuildInfo;
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, WriteMultipart};
+use rocksdb::IteratorMode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+use tracing::info;
+use url::Url;
+use uuid::Uuid;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+// files are uploaded in parts of this size, with up to UPLOAD_CONCURRENCY parts in flight
+const UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+const UPLOAD_CONCURRENCY: usize = 4;
+
+// Describes a backup, written next to the partitions' checkpoints
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub backup_id: String,
+    pub created_at: u64,
+    pub version: String,
+    pub git_hash: String,
+    pub protocol_version: u32,
+    pub partitions: Vec<PartitionManifest>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PartitionManifest {
+    pub tenant_id: Uuid,
+    pub namespace_id: Uuid,
+    pub partition_id: Uuid,
+    // the on disk format of the checkpoint
+    pub format: u32,
+    // the last write included in the checkpoint
+    pub sequence_number: u64,
+    // not recorded by older builds
+    #[serde(default)]
+    pub keys: Option<u64>,
+    pub files: Vec<FileManifest>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileManifest {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Manifest {
+    pub fn total_bytes(&self) -> u64 {
+        self.partitions
+            .iter()
+            .flat_map(|partition| &partition.files)
+            .map(|file| file.size)
+            .sum()
+    }
+}
+
+// Credentials and settings for the object store backups are uploaded to, and tiered values are
+// moved to, any left unset fall back to the usual AWS_* environment variables
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UploadOptions {
+    // for s3 compatible stores, e.g. http://minio:9000
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+}
+
+impl UploadOptions {
+    pub fn store_options(&self) -> Vec<(String, String)> {
+        let mut options: Vec<(String, String)> = std::env::vars()
+            .filter(|(key, _)| key.starts_with("AWS_"))
+            .map(|(key, value)| (key.to_ascii_lowercase(), value))
+            .collect();
+        // later options take precedence over the environment
+        for (key, value) in [
+            ("aws_endpoint", &self.endpoint),
+            ("aws_region", &self.region),
+            ("aws_access_key_id", &self.access_key_id),
+            ("aws_secret_access_key", &self.secret_access_key),
+        ] {
+            if let Some(value) = value {
+                options.push((key.to_string(), value.clone()));
+            }
+        }
+        if self
+            .endpoint
+            .as_ref()
+            .is_some_and(|endpoint| endpoint.starts_with("http://"))
+        {
+            options.push(("aws_allow_http".to_string(), "true".to_string()));
+        }
+        // s3 verifies every part against its sha256 and rejects the upload on a mismatch
+        options.push(("aws_checksum_algorithm".to_string(), "sha256".to_string()));
+        options
+    }
+}
+
+// Where backups are uploaded after they're taken
+struct Destination {
+    url: String,
+    store: Box<dyn ObjectStore>,
+    prefix: ObjectPath,
+}
+
+// Takes backups of partitions into a local directory, and uploads them to an object store if one is
+// configured or asked for
+pub struct Backups {
+    dir: PathBuf,
+    destination: Option<Destination>,
+    options: UploadOptions,
+}
+
+impl Backups {
+    pub fn new(dir: impl Into<PathBuf>) -> Backups {
+        Backups {
+            dir: dir.into(),
+            destination: None,
+            options: UploadOptions::default(),
+        }
+    }
+
+    pub fn with_upload_options(mut self, options: UploadOptions) -> Backups {
+        self.options = options;
+        self
+    }
+
+    // Uploads every backup under the url, e.g. s3://bucket/backups or file:///mnt/backups
+    pub fn with_destination(mut self, url: &Url) -> Result<Backups, Error> {
+        self.destination = Some(self.destination(url)?);
+        Ok(self)
+    }
+
+    fn destination(&self, url: &Url) -> Result<Destination, Error> {
+        let (store, prefix) = object_store::parse_url_opts(url, self.options.store_options())?;
+        Ok(Destination {
+            url: url.to_string(),
+            store,
+            prefix,
+        })
+    }
+
+    // Checkpoints the partitions with writes paused, so the backup is a single point in time across
+    // all of them. Blocks until the checkpoints and the manifest are written.
+    pub fn create(
+        &self,
+        partition_lookup: &PartitionLookup,
+        partitions: &[Partition],
+        build_info: &BuildInfo,
+    ) -> Result<Manifest, Error> {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let backup_id = format!("{}-{}", created_at, Uuid::new_v4().simple());
+        let backup_dir = self.dir.join(&backup_id);
+        std::fs::create_dir_all(&backup_dir)?;
+
+        let mut checkpoints = Vec::with_capacity(partitions.len());
+        {
+            let _paused = partition_lookup.pause_writes();
+            for partition in partitions {
+                let dir = backup_dir.join(partition.id.to_string());
+                checkpoints.push((partition, partition.checkpoint(&dir)?, dir));
+            }
+        }
+
+        let mut manifest = Manifest {
+            backup_id,
+            created_at,
+            version: build_info.version.to_string(),
+            git_hash: build_info.git_hash.to_string(),
+            protocol_version: build_info.protocol_version,
+            partitions: Vec::with_capacity(checkpoints.len()),
+        };
+        for (partition, sequence_number, dir) in checkpoints {
+            manifest.partitions.push(PartitionManifest {
+                tenant_id: partition.tenant_id,
+                namespace_id: partition.namespace_id,
+                partition_id: partition.id,
+                format: crate::format::version(&dir)?,
+                sequence_number,
+                keys: Some(count_keys(&dir)?),
+                files: checksum_files(&dir)?,
+            });
+        }
+
+        let manifest_file = File::create(backup_dir.join(MANIFEST_FILE))?;
+        serde_json::to_writer_pretty(manifest_file, &manifest).map_err(std::io::Error::from)?;
+        info!(
+            backup_id = manifest.backup_id,
+            partitions = manifest.partitions.len(),
+            "backup taken"
+        );
+        Ok(manifest)
+    }
+
+    // Uploads the backup, the manifest last so a backup with a manifest in the object store is
+    // complete. It goes to `to` when given, otherwise to the configured destination if there is
+    // one. Returns where it was uploaded to.
+    pub async fn upload(
+        &self,
+        manifest: &Manifest,
+        to: Option<&Url>,
+    ) -> Result<Option<String>, Error> {
+        let requested;
+        let destination = match (to, &self.destination) {
+            (Some(url), _) => {
+                requested = self.destination(url)?;
+                &requested
+            }
+            (None, Some(destination)) => destination,
+            (None, None) => return Ok(None),
+        };
+        let backup_dir = self.dir.join(&manifest.backup_id);
+        let prefix = destination.prefix.child(manifest.backup_id.as_str());
+        for partition in &manifest.partitions {
+            let partition_id = partition.partition_id.to_string();
+            for file in &partition.files {
+                upload_file(
+                    destination.store.as_ref(),
+                    &backup_dir.join(&partition_id).join(&file.name),
+                    &prefix
+                        .child(partition_id.as_str())
+                        .child(file.name.as_str()),
+                )
+                .await?;
+            }
+        }
+        upload_file(
+            destination.store.as_ref(),
+            &backup_dir.join(MANIFEST_FILE),
+            &prefix.child(MANIFEST_FILE),
+        )
+        .await?;
+
+        let location = format!(
+            "{}/{}",
+            destination.url.trim_end_matches('/'),
+            manifest.backup_id
+        );
+        info!(
+            backup_id = manifest.backup_id,
+            location = location,
+            "backup uploaded"
+        );
+        Ok(Some(location))
+    }
+}
+
+fn checksum_files(dir: &Path) -> Result<Vec<FileManifest>, Error> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let (size, sha256) = checksum(&entry.path())?;
+        files.push(FileManifest {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            size,
+            sha256,
+        });
+    }
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files)
+}
+
+// A file's size and hex encoded sha256
+pub fn checksum(path: &Path) -> std::io::Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+pub fn count_keys(dir: &Path) -> Result<u64, Error> {
+    let db = crate::fsck::open_read_only(dir)?;
+    let metadata_handle = db.cf_handle("metadata").unwrap();
+    let mut keys = 0;
+    for item in db.iterator_cf(&metadata_handle, IteratorMode::Start) {
+        item?;
+        keys += 1;
+    }
+    Ok(keys)
+}
+
+async fn upload_file(
+    store: &dyn ObjectStore,
+    path: &Path,
+    location: &ObjectPath,
+) -> Result<(), Error> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut upload = WriteMultipart::new_with_chunk_size(
+        store.put_multipart(location).await?,
+        UPLOAD_CHUNK_SIZE,
+    );
+    let mut buf = vec![0; UPLOAD_CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        upload.wait_for_capacity(UPLOAD_CONCURRENCY).await?;
+        upload.write(&buf[..read]);
+        size += read as u64;
+    }
+    upload.finish().await?;
+
+    // the parts' checksums were verified as they were uploaded, this catches a truncated object
+    let uploaded = store.head(location).await?;
+    if uploaded.size != size {
+        return Err(Error::UploadMismatch {
+            location: location.to_string(),
+            expected: size,
+            found: uploaded.size,
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,345 @@
+use crate::error::Error;
+use crate::lookup::PartitionLookup;
+use crate::metrics;
+use crate::partition::Partition;
+use rskafka::chrono::{TimeZone, Utc};
+use rskafka::client::partition::{Compression, PartitionClient, UnknownTopicHandling};
+use rskafka::client::{Client, ClientBuilder};
+use rskafka::record::Record;
+use rskafka::BackoffConfig;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+use uuid::Uuid;
+
+// how often partitions are checked for changes to publish
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// changes read from a partition at once, they're cleared once all of them are published
+const PUBLISH_BATCH: usize = 1000;
+
+// a request to the brokers fails after retrying this long, and is tried again on the next poll
+const BROKER_DEADLINE: Duration = Duration::from_secs(30);
+
+const KIND_PUT: u8 = 0;
+const KIND_DELETE: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Put,
+    Delete,
+}
+
+// A write to a key as the partition records it for publishing. A put's version is the one it wrote,
+// a delete's is the version it removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub key: Vec<u8>,
+    pub version: u32,
+    // unix milliseconds
+    pub timestamp: u64,
+}
+
+impl Change {
+    pub fn put(key: &[u8], version: u32) -> Change {
+        Change::new(ChangeKind::Put, key, version)
+    }
+
+    pub fn delete(key: &[u8], version: u32) -> Change {
+        Change::new(ChangeKind::Delete, key, version)
+    }
+
+    fn new(kind: ChangeKind, key: &[u8], version: u32) -> Change {
+        Change {
+            kind,
+            key: key.to_vec(),
+            version,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_millis() as u64),
+        }
+    }
+
+    // The kind, the version and the timestamp, all big endian, followed by the key
+    pub fn encode(&self) -> Vec<u8> {
+        let kind = match self.kind {
+            ChangeKind::Put => KIND_PUT,
+            ChangeKind::Delete => KIND_DELETE,
+        };
+        [
+            [kind].as_slice(),
+            self.version.to_be_bytes().as_slice(),
+            self.timestamp.to_be_bytes().as_slice(),
+            &self.key,
+        ]
+        .concat()
+    }
+
+    // None when the change isn't in a known encoding
+    pub fn decode(change: &[u8]) -> Option<Change> {
+        let (kind, rest) = change.split_first()?;
+        let (version, rest) = rest.split_first_chunk::<4>()?;
+        let (timestamp, key) = rest.split_first_chunk::<8>()?;
+        Some(Change {
+            kind: match *kind {
+                KIND_PUT => ChangeKind::Put,
+                KIND_DELETE => ChangeKind::Delete,
+                _ => return None,
+            },
+            key: key.to_vec(),
+            version: u32::from_be_bytes(*version),
+            timestamp: u64::from_be_bytes(*timestamp),
+        })
+    }
+}
+
+// Hands out the sequence numbers a partition's changes are recorded under, and tracks the ones
+// whose writes are still in flight. Writes to different keys can land out of sequence order, so
+// changes are only published below the first sequence number still in flight.
+#[derive(Debug)]
+pub struct Sequencer {
+    state: Mutex<SequencerState>,
+}
+
+#[derive(Debug)]
+struct SequencerState {
+    next: u64,
+    // the first sequence number of every reservation not yet dropped
+    in_flight: BTreeSet<u64>,
+}
+
+impl Sequencer {
+    pub fn starting_at(next: u64) -> Sequencer {
+        Sequencer {
+            state: Mutex::new(SequencerState {
+                next,
+                in_flight: BTreeSet::new(),
+            }),
+        }
+    }
+
+    // Reserves count sequence numbers, in flight until the reservation is dropped
+    pub fn reserve(&self, count: usize) -> Reservation<'_> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let first = state.next;
+        state.next += count as u64;
+        state.in_flight.insert(first);
+        Reservation {
+            sequencer: self,
+            first,
+        }
+    }
+
+    // Every change below it has been written, or its write failed
+    pub fn settled_before(&self) -> u64 {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.in_flight.first().copied().unwrap_or(state.next)
+    }
+
+    // Moves past sequence numbers recorded without a reservation, e.g. a follower's copy of its
+    // leader's log. Never moves back.
+    pub fn advance_to(&self, next: u64) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.next = state.next.max(next);
+    }
+}
+
+// Held until the batch recording the reserved changes is written
+pub struct Reservation<'a> {
+    sequencer: &'a Sequencer,
+    pub first: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.sequencer
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .in_flight
+            .remove(&self.first);
+    }
+}
+
+// The brokers changes are published to, and the topic each namespace's changes go to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CdcSettings {
+    pub brokers: Vec<String>,
+    // {tenant_id} and {namespace_id} are replaced with the namespace's, e.g. kvstore.{namespace_id}
+    pub topic: String,
+}
+
+impl CdcSettings {
+    fn topic(&self, partition: &Partition) -> String {
+        self.topic
+            .replace("{tenant_id}", &partition.tenant_id.to_string())
+            .replace("{namespace_id}", &partition.namespace_id.to_string())
+    }
+}
+
+// A change as it's published, keyed by the key's raw bytes so a key's changes stay in order on one
+// of the topic's partitions, e.g.
+// {"type": "put", "tenant_id": "...", "namespace_id": "...", "key": "alice", "version": 3,
+// "timestamp": 1700000000000}
+#[derive(Serialize, Debug)]
+struct Message<'a> {
+    #[serde(rename = "type")]
+    kind: ChangeKind,
+    tenant_id: Uuid,
+    namespace_id: Uuid,
+    key: &'a str,
+    version: u32,
+    timestamp: u64,
+}
+
+// Publishes every partition's changes to its namespace's topic in the order they were written.
+// Changes are recorded in the same write batch as the keys they change and only cleared once the
+// brokers have acknowledged them, so each is delivered at least once, in order for its key. A
+// publish that fails is retried from the first change that wasn't cleared, which can deliver some
+// changes again.
+//
+// Keys that expire aren't reported, only puts and deletes are.
+pub struct ChangePublisher {
+    settings: CdcSettings,
+    client: Option<Client>,
+    // each topic's partitions, read when the topic is first published to
+    topics: HashMap<String, Vec<PartitionClient>>,
+}
+
+impl ChangePublisher {
+    pub fn new(settings: CdcSettings) -> ChangePublisher {
+        ChangePublisher {
+            settings,
+            client: None,
+            topics: HashMap::new(),
+        }
+    }
+
+    pub async fn run(mut self, partition_lookup: Arc<PartitionLookup>) {
+        info!(
+            brokers = self.settings.brokers.join(","),
+            "publishing changes"
+        );
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            ticker.tick().await;
+            for partition in partition_lookup.all_partitions() {
+                // a partition is published until it has no changes left, or its publish fails
+                loop {
+                    match self.publish(&partition).await {
+                        Ok(0) => break,
+                        Ok(published) => metrics::record_changes_published(published),
+                        Err(err) => {
+                            error!(
+                                partition_id = partition.id.to_string(),
+                                err = err.to_string(),
+                                "failed to publish partition's changes"
+                            );
+                            // reconnects on the next poll, in case the brokers moved
+                            self.client = None;
+                            self.topics.clear();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Publishes the partition's oldest changes and clears them, returns how many there were
+    async fn publish(&mut self, partition: &Partition) -> Result<usize, Error> {
+        let changes = {
+            let partition = partition.clone();
+            tokio::task::spawn_blocking(move || partition.pending_changes(PUBLISH_BATCH))
+                .await
+                .map_err(io::Error::other)??
+        };
+        let Some((last, _)) = changes.last() else {
+            return Ok(0);
+        };
+        let last = *last;
+
+        let topic = self.settings.topic(partition);
+        let partitions = self.partitions(&topic).await?;
+        // a key's changes all go to the same partition of the topic, in the order they were made
+        let mut records: BTreeMap<usize, Vec<Record>> = BTreeMap::new();
+        for (_, change) in &changes {
+            let index = crc32fast::hash(&change.key) as usize % partitions.len();
+            records
+                .entry(index)
+                .or_default()
+                .push(record(partition, change)?);
+        }
+        for (index, records) in records {
+            partitions[index]
+                .produce(records, Compression::NoCompression)
+                .await?;
+        }
+
+        let cleared = partition.clone();
+        tokio::task::spawn_blocking(move || cleared.clear_changes(last))
+            .await
+            .map_err(io::Error::other)??;
+        Ok(changes.len())
+    }
+
+    async fn partitions(&mut self, topic: &str) -> Result<&[PartitionClient], Error> {
+        if self.client.is_none() {
+            let client = ClientBuilder::new(self.settings.brokers.clone())
+                .client_id("kvstore-storage")
+                .backoff_config(BackoffConfig {
+                    deadline: Some(BROKER_DEADLINE),
+                    ..BackoffConfig::default()
+                })
+                .build()
+                .await?;
+            self.client = Some(client);
+        }
+        let client = self.client.as_ref().unwrap();
+        if !self.topics.contains_key(topic) {
+            let Some(found) = client
+                .list_topics()
+                .await?
+                .into_iter()
+                .find(|found| found.name == topic && !found.partitions.is_empty())
+            else {
+                return Err(Error::TopicNotFound(topic.to_string()));
+            };
+            let mut partitions = Vec::with_capacity(found.partitions.len());
+            for index in found.partitions {
+                partitions.push(
+                    client
+                        .partition_client(topic, index, UnknownTopicHandling::Error)
+                        .await?,
+                );
+            }
+            self.topics.insert(topic.to_string(), partitions);
+        }
+        Ok(&self.topics[topic])
+    }
+}
+
+fn record(partition: &Partition, change: &Change) -> Result<Record, Error> {
+    let message = Message {
+        kind: change.kind,
+        tenant_id: partition.tenant_id,
+        namespace_id: partition.namespace_id,
+        key: &String::from_utf8_lossy(&change.key),
+        version: change.version,
+        timestamp: change.timestamp,
+    };
+    Ok(Record {
+        key: Some(change.key.clone()),
+        value: Some(serde_json::to_vec(&message).map_err(io::Error::other)?),
+        headers: BTreeMap::new(),
+        timestamp: Utc
+            .timestamp_millis_opt(change.timestamp as i64)
+            .single()
+            .unwrap_or_else(Utc::now),
+    })
+}
@@ -0,0 +1,260 @@
+use crate::lookup::PartitionLookup;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+use uuid::Uuid;
+
+// how often the scheduler checks whether a window has opened
+const TICK: Duration = Duration::from_secs(60);
+
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+// A daily time range, in UTC, during which compactions run, e.g. "sat,sun 01:00-05:00" or
+// "* 02:00-03:30". A window that ends before it starts runs past midnight into the next day.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    // indexed by day of the week, sunday first, for the day the window starts on
+    days: [bool; 7],
+    start: u64,
+    end: u64,
+}
+
+impl Window {
+    // Identifies the occurrence of the window that `minutes` since the epoch falls in, if any
+    fn occurrence(&self, minutes: u64) -> Option<u64> {
+        let day = minutes / MINUTES_PER_DAY;
+        let minute = minutes % MINUTES_PER_DAY;
+        let runs_on = |day: u64| self.days[((day + 4) % 7) as usize]; // the epoch was a thursday
+        if self.start < self.end {
+            (runs_on(day) && minute >= self.start && minute < self.end)
+                .then_some(day * MINUTES_PER_DAY + self.start)
+        } else if minute >= self.start && runs_on(day) {
+            Some(day * MINUTES_PER_DAY + self.start)
+        } else if minute < self.end && day > 0 && runs_on(day - 1) {
+            Some((day - 1) * MINUTES_PER_DAY + self.start)
+        } else {
+            None
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Option<u64> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes): (u64, u64) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+fn format_time(minutes: u64) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid compaction window {}, expected e.g. mon,tue 01:00-04:00",
+                s
+            )
+        };
+        let (days, times) = s.trim().split_once(' ').ok_or_else(invalid)?;
+        let mut window = Window {
+            days: [false; 7],
+            start: 0,
+            end: 0,
+        };
+        if days == "*" {
+            window.days = [true; 7];
+        } else {
+            for day in days.split(',') {
+                let index = DAYS
+                    .iter()
+                    .position(|name| name.eq_ignore_ascii_case(day))
+                    .ok_or_else(invalid)?;
+                window.days[index] = true;
+            }
+        }
+        let (start, end) = times.trim().split_once('-').ok_or_else(invalid)?;
+        window.start = parse_time(start).ok_or_else(invalid)?;
+        window.end = parse_time(end).ok_or_else(invalid)?;
+        if window.start == window.end {
+            return Err(invalid());
+        }
+        Ok(window)
+    }
+}
+
+impl Display for Window {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.days.iter().all(|day| *day) {
+            write!(f, "*")?;
+        } else {
+            let days: Vec<&str> = DAYS
+                .iter()
+                .zip(self.days)
+                .filter(|(_, runs)| *runs)
+                .map(|(name, _)| *name)
+                .collect();
+            write!(f, "{}", days.join(","))?;
+        }
+        write!(f, " {}-{}", format_time(self.start), format_time(self.end))
+    }
+}
+
+// The windows compactions run in, separated by semicolons, e.g.
+// "mon,tue,wed,thu,fri 02:00-04:00; sat,sun 00:00-06:00"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule(Vec<Window>);
+
+impl Schedule {
+    fn occurrence(&self, minutes: u64) -> Option<u64> {
+        self.0.iter().find_map(|window| window.occurrence(minutes))
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(';')
+            .filter(|window| !window.trim().is_empty())
+            .map(Window::from_str)
+            .collect::<Result<Vec<Window>, String>>()
+            .map(Schedule)
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let windows: Vec<String> = self.0.iter().map(Window::to_string).collect();
+        write!(f, "{}", windows.join("; "))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    pub schedule: Option<String>,
+    pub paused: bool,
+    pub in_window: bool,
+    pub compacting: Option<Uuid>,
+}
+
+// Compacts partitions during the scheduled windows, one at a time, each at most once per window.
+// Partitions with less than min_pending_bytes of estimated compaction debt are skipped, with a
+// minimum of zero every partition gets a full compaction.
+pub struct CompactionScheduler {
+    schedule: Option<Schedule>,
+    min_pending_bytes: u64,
+    partition_lookup: Arc<PartitionLookup>,
+    paused: AtomicBool,
+    compacting: Mutex<Option<Uuid>>,
+}
+
+impl CompactionScheduler {
+    pub fn new(
+        schedule: Option<Schedule>,
+        min_pending_bytes: u64,
+        partition_lookup: Arc<PartitionLookup>,
+    ) -> CompactionScheduler {
+        CompactionScheduler {
+            schedule,
+            min_pending_bytes,
+            partition_lookup,
+            paused: AtomicBool::new(false),
+            compacting: Mutex::new(None),
+        }
+    }
+
+    // A paused scheduler finishes the compaction it's running and then starts no more until resumed
+    pub fn set_paused(&self, paused: bool) {
+        info!(paused = paused, "compaction scheduler paused or resumed");
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            schedule: self.schedule.as_ref().map(Schedule::to_string),
+            paused: self.paused.load(Ordering::SeqCst),
+            in_window: self.current_window().is_some(),
+            compacting: *self
+                .compacting
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        }
+    }
+
+    fn current_window(&self) -> Option<u64> {
+        let minutes = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() / 60)
+            .unwrap_or(0);
+        self.schedule.as_ref()?.occurrence(minutes)
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        if self.schedule.is_none() {
+            return;
+        }
+        let mut ticker = tokio::time::interval(TICK);
+        let mut occurrence = None;
+        let mut compacted: HashSet<Uuid> = HashSet::new();
+        loop {
+            ticker.tick().await;
+            // the window is checked again between partitions so compactions stop when it closes
+            while let Some(current) = self.current_window() {
+                if self.paused.load(Ordering::SeqCst) {
+                    break;
+                }
+                if occurrence != Some(current) {
+                    occurrence = Some(current);
+                    compacted.clear();
+                    info!("compaction window opened");
+                }
+                let Some(partition) = self
+                    .partition_lookup
+                    .all_partitions()
+                    .into_iter()
+                    .find(|partition| !compacted.contains(&partition.id))
+                else {
+                    break;
+                };
+                compacted.insert(partition.id);
+
+                match partition.pending_compaction_bytes() {
+                    Ok(pending) if pending < self.min_pending_bytes => continue,
+                    Ok(_) => {}
+                    Err(err) => {
+                        error!(
+                            partition_id = partition.id.to_string(),
+                            err = err.to_string(),
+                            "failed to get pending compaction bytes"
+                        );
+                        continue;
+                    }
+                }
+
+                *self
+                    .compacting
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = Some(partition.id);
+                info!(
+                    partition_id = partition.id.to_string(),
+                    "running scheduled compaction"
+                );
+                if let Err(err) = tokio::task::spawn_blocking(move || partition.compact()).await {
+                    error!(err = err.to_string(), "scheduled compaction failed");
+                }
+                *self
+                    .compacting
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = None;
+            }
+        }
+    }
+}
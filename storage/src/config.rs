@@ -0,0 +1,525 @@
+use crate::backup::UploadOptions;
+use crate::cdc::CdcSettings;
+use crate::compaction::Schedule;
+use crate::grpc_web::GrpcWebOrigins;
+use crate::orphans::{OrphanAction, OrphanSettings};
+use crate::partition::BackgroundLimits;
+use crate::provision::{ProvisionSettings, MAX_PARTITIONS};
+use crate::reaper::ReaperSettings;
+use crate::replication::ReplicationSettings;
+use crate::tier::TierSettings;
+use crate::transform::{TransformLimits, DEFAULT_FUEL, DEFAULT_MEMORY_LIMIT};
+use common::auth::{KeyAlgorithm, DEFAULT_CLOCK_SKEW, DEFAULT_ISSUER, DEFAULT_SERVICE_AUDIENCE};
+use common::config::{self, Changes, Config, Error};
+use common::logging::LogLevel;
+use std::future::ready;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, warn};
+use url::Url;
+
+pub const DEFAULT_LISTEN_ADDRESS: &str = "[::1]:50051";
+pub const DEFAULT_DATA_DIR: &str = "namespaces";
+pub const DEFAULT_HEALTH_PORT: u16 = 50052;
+pub const DEFAULT_MIN_FREE_DISK_MB: u64 = 1024;
+pub const DEFAULT_BACKUP_DIR: &str = "backups";
+pub const DEFAULT_TIER_AFTER_HOURS: u64 = 7 * 24;
+pub const DEFAULT_TIER_MIN_VALUE_KB: usize = 4;
+pub const DEFAULT_CDC_TOPIC: &str = "kvstore.{namespace_id}";
+pub const DEFAULT_REAPER_INTERVAL_SECS: u64 = 5 * 60;
+pub const DEFAULT_REAPER_BATCH_SIZE: usize = 1000;
+pub const DEFAULT_REAPER_MAX_DELETES_PER_SEC: u64 = 5000;
+pub const DEFAULT_ORPHAN_GRACE_HOURS: u64 = 24;
+pub const DEFAULT_ANTI_ENTROPY_SECS: u64 = 60 * 60;
+pub const DEFAULT_NAMESPACE_PARTITIONS: u64 = 4;
+pub const DEFAULT_PARTITION_TARGET_SIZE_GB: u64 = 16;
+
+// How callers on the admin listener authenticate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AdminAuth {
+    // a service token with the admin scope, the same as the storage listener
+    #[default]
+    Token,
+    // a client certificate signed by admin_tls_client_ca, no token is needed
+    Mtls,
+}
+
+impl FromStr for AdminAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "token" => Ok(AdminAuth::Token),
+            "mtls" => Ok(AdminAuth::Mtls),
+            _ => Err(format!("unknown admin auth {}, expected token or mtls", s)),
+        }
+    }
+}
+
+// Everything a storage node can be configured with, see common::config for where settings are read from
+#[derive(Debug, Clone)]
+pub struct StorageConfig {
+    pub listen_address: SocketAddr,
+    // serves /healthz, /readyz, and /metrics over http
+    pub health_port: u16,
+    // the admin rpcs are served here instead of on listen_address when set, so they can be
+    // firewalled away from application traffic
+    pub admin_listen_address: Option<SocketAddr>,
+    pub admin_auth: AdminAuth,
+    // the admin listener serves tls when both are set
+    pub admin_tls_cert: Option<String>,
+    pub admin_tls_key: Option<String>,
+    // admin clients must present a certificate signed by this ca when set
+    pub admin_tls_client_ca: Option<String>,
+    // browsers from these origins can call the services on listen_address over grpc-web, it's off
+    // when unset
+    pub grpc_web_origins: Option<GrpcWebOrigins>,
+    // holds partitions.json and the partitions' rocksdb directories
+    pub data_dir: String,
+    // the node stops reporting ready when the data directory's disk has less space than this
+    pub min_free_disk: u64,
+    // backups are taken into this directory, it should be on the same filesystem as data_dir so
+    // checkpoints can hard link the partitions' files
+    pub backup_dir: String,
+    // backups are also uploaded here when set, e.g. s3://bucket/backups
+    pub backup_upload_url: Option<Url>,
+    // credentials for backup_upload_url, for backups uploaded elsewhere on request, and for
+    // tier_url
+    pub backup_upload: UploadOptions,
+    // cold values are moved to tier_url when it's set, see tier
+    pub tiering: Option<TierSettings>,
+    // every put and delete is published to kafka when cdc_brokers is set, see cdc
+    pub cdc: Option<CdcSettings>,
+    // writes are logged for followers on other nodes when replication_log_entries is set, see
+    // replication
+    pub replication: Option<ReplicationSettings>,
+    // expired keys are deleted in the background unless reaper_interval_secs is 0, see reaper
+    pub reaper: Option<ReaperSettings>,
+    // what's done with partition directories the partition map doesn't have, see orphans
+    pub orphans: OrphanSettings,
+    // how many partitions a namespace is created with, see provision
+    pub provisioning: ProvisionSettings,
+    // when compactions are scheduled to run, in UTC, e.g. "sat,sun 01:00-05:00; * 03:00-04:00"
+    pub compaction_windows: Option<Schedule>,
+    // scheduled compactions skip partitions with less estimated compaction debt, 0 compacts every
+    // partition in full
+    pub compaction_min_pending: u64,
+    // caps on the partitions' flushes and compactions, see partition::BackgroundLimits
+    pub background: BackgroundLimits,
+    // limits on each call into a namespace's transform modules
+    pub transform_limits: TransformLimits,
+    pub jwt_algorithm: KeyAlgorithm,
+    // only used with HS256, the other algorithms verify with the gateway's public key
+    pub jwt_secret: Option<String>,
+    pub jwt_public_key: String,
+    pub jwt_issuer: String,
+    pub jwt_audience: String,
+    pub clock_skew: Duration,
+    pub log_level: LevelFilter,
+}
+
+impl StorageConfig {
+    pub fn load(config: &Config) -> Result<StorageConfig, Error> {
+        let jwt_algorithm = config.get_or("jwt_algorithm", KeyAlgorithm::default())?;
+        let jwt_secret = match jwt_algorithm {
+            KeyAlgorithm::Hs256 => Some(config.require("jwt_secret")?),
+            _ => None,
+        };
+        let storage = StorageConfig {
+            listen_address: config
+                .get_or("listen_address", DEFAULT_LISTEN_ADDRESS.parse().unwrap())?,
+            health_port: config.get_or("health_port", DEFAULT_HEALTH_PORT)?,
+            admin_listen_address: config.get("admin_listen_address")?,
+            admin_auth: config.get_or("admin_auth", AdminAuth::default())?,
+            admin_tls_cert: config.get("admin_tls_cert")?,
+            admin_tls_key: config.get("admin_tls_key")?,
+            admin_tls_client_ca: config.get("admin_tls_client_ca")?,
+            grpc_web_origins: config.get("grpc_web_origins")?,
+            data_dir: config.get_or("data_dir", DEFAULT_DATA_DIR.to_string())?,
+            min_free_disk: config.get_or("min_free_disk_mb", DEFAULT_MIN_FREE_DISK_MB)?
+                * 1024
+                * 1024,
+            backup_dir: config.get_or("backup_dir", DEFAULT_BACKUP_DIR.to_string())?,
+            backup_upload_url: config.get("backup_upload_url")?,
+            backup_upload: UploadOptions {
+                endpoint: config.get("backup_upload_endpoint")?,
+                region: config.get("backup_upload_region")?,
+                access_key_id: config.get("backup_upload_access_key_id")?,
+                secret_access_key: config.get("backup_upload_secret_access_key")?,
+            },
+            tiering: tiering(config)?,
+            cdc: cdc(config)?,
+            replication: replication(config)?,
+            reaper: reaper(config)?,
+            orphans: OrphanSettings {
+                action: config.get_or("orphan_action", OrphanAction::default())?,
+                grace: Duration::from_secs(
+                    config.get_or("orphan_grace_hours", DEFAULT_ORPHAN_GRACE_HOURS)? * 60 * 60,
+                ),
+            },
+            provisioning: ProvisionSettings {
+                default_partitions: config
+                    .get_or("namespace_partitions", DEFAULT_NAMESPACE_PARTITIONS)?,
+                target_partition_size: config
+                    .get_or("partition_target_size_gb", DEFAULT_PARTITION_TARGET_SIZE_GB)?
+                    * 1024
+                    * 1024
+                    * 1024,
+            },
+            compaction_windows: config.get("compaction_windows")?,
+            compaction_min_pending: config.get_or("compaction_min_pending_mb", 0)? * 1024 * 1024,
+            background: BackgroundLimits {
+                rate_limit: config
+                    .get::<u64>("compaction_rate_limit_mb")?
+                    .map(|limit| limit * 1024 * 1024),
+                max_jobs: config.get("max_background_jobs")?,
+                compaction_threads: config.get("compaction_threads")?,
+                flush_threads: config.get("flush_threads")?,
+            },
+            transform_limits: TransformLimits {
+                fuel: config.get_or("transform_fuel", DEFAULT_FUEL)?,
+                memory: config.get_or("transform_memory_mb", DEFAULT_MEMORY_LIMIT / 1024 / 1024)?
+                    * 1024
+                    * 1024,
+            },
+            jwt_algorithm,
+            jwt_secret,
+            jwt_public_key: config.get_or("jwt_public_key", "key.pub".to_string())?,
+            jwt_issuer: config.get_or("jwt_issuer", DEFAULT_ISSUER.to_string())?,
+            jwt_audience: config.get_or("jwt_audience", DEFAULT_SERVICE_AUDIENCE.to_string())?,
+            clock_skew: config.secs_or("clock_skew_secs", DEFAULT_CLOCK_SKEW)?,
+            log_level: config.get_or("log_level", LevelFilter::INFO)?,
+        };
+        if storage.health_port == storage.listen_address.port() {
+            return Err(config.invalid("health_port", "port is already used by listen_address"));
+        }
+        if let Some(admin_listen_address) = storage.admin_listen_address {
+            if admin_listen_address.port() == storage.listen_address.port()
+                || admin_listen_address.port() == storage.health_port
+            {
+                return Err(config.invalid(
+                    "admin_listen_address",
+                    "port is already used by listen_address or health_port",
+                ));
+            }
+        }
+        match (&storage.admin_tls_cert, &storage.admin_tls_key) {
+            (Some(_), None) => {
+                return Err(config.invalid("admin_tls_cert", "admin_tls_key must also be set"))
+            }
+            (None, Some(_)) => {
+                return Err(config.invalid("admin_tls_key", "admin_tls_cert must also be set"))
+            }
+            _ => {}
+        }
+        if storage.admin_listen_address.is_none()
+            && (storage.admin_tls_cert.is_some() || storage.admin_tls_client_ca.is_some())
+        {
+            return Err(config.invalid(
+                "admin_listen_address",
+                "must be set for the admin tls settings to apply",
+            ));
+        }
+        if storage.admin_auth == AdminAuth::Mtls
+            && (storage.admin_tls_cert.is_none() || storage.admin_tls_client_ca.is_none())
+        {
+            return Err(config.invalid(
+                "admin_auth",
+                "mtls requires admin_tls_cert, admin_tls_key, and admin_tls_client_ca",
+            ));
+        }
+        if storage.transform_limits.fuel == 0 {
+            return Err(config.invalid("transform_fuel", "must be greater than 0"));
+        }
+        if storage.transform_limits.memory == 0 {
+            return Err(config.invalid("transform_memory_mb", "must be greater than 0"));
+        }
+        if storage.background.rate_limit == Some(0) {
+            return Err(config.invalid("compaction_rate_limit_mb", "must be greater than 0"));
+        }
+        for (key, value) in [
+            ("max_background_jobs", storage.background.max_jobs),
+            ("compaction_threads", storage.background.compaction_threads),
+            ("flush_threads", storage.background.flush_threads),
+        ] {
+            if value.is_some_and(|value| value == 0 || value > i32::MAX as u32) {
+                return Err(config.invalid(key, "must be greater than 0"));
+            }
+        }
+        if storage.orphans.grace.is_zero() {
+            return Err(config.invalid("orphan_grace_hours", "must be greater than 0"));
+        }
+        if storage.provisioning.default_partitions == 0
+            || storage.provisioning.default_partitions > MAX_PARTITIONS
+        {
+            return Err(config.invalid(
+                "namespace_partitions",
+                format!("must be between 1 and {MAX_PARTITIONS}"),
+            ));
+        }
+        if storage.provisioning.target_partition_size == 0 {
+            return Err(config.invalid("partition_target_size_gb", "must be greater than 0"));
+        }
+        if storage.data_dir.is_empty() {
+            return Err(config.invalid("data_dir", "must not be empty"));
+        }
+        config.check_unknown()?;
+        Ok(storage)
+    }
+
+    // Rereads the configuration on SIGHUP, the log level is the only setting that can change while
+    // the node is running
+    pub async fn watch(mut self, log_level: LogLevel) {
+        config::reload_on_hangup(move || {
+            self.reload(&log_level);
+            ready(())
+        })
+        .await
+    }
+
+    fn reload(&mut self, log_level: &LogLevel) {
+        let config = match Config::load("STORAGE").and_then(|config| StorageConfig::load(&config)) {
+            Ok(config) => config,
+            Err(err) => {
+                error!(
+                    err = err.to_string(),
+                    "invalid configuration, keeping the current settings"
+                );
+                warn!(
+                    target: "audit",
+                    event = "config_reloaded",
+                    outcome = "failure",
+                    detail = err.to_string(),
+                );
+                return;
+            }
+        };
+
+        let mut changes = Changes::default();
+        if changes.apply("log_level", &self.log_level, &config.log_level) {
+            if let Err(err) = log_level.set(config.log_level) {
+                error!(err = err.to_string(), "failed to change the log level");
+            }
+            self.log_level = config.log_level;
+        }
+        changes.restart(
+            "listen_address",
+            &self.listen_address,
+            &config.listen_address,
+        );
+        changes.restart("health_port", &self.health_port, &config.health_port);
+        changes.restart(
+            "admin_listen_address",
+            &self.admin_listen_address,
+            &config.admin_listen_address,
+        );
+        changes.restart("admin_auth", &self.admin_auth, &config.admin_auth);
+        changes.restart(
+            "admin_tls_cert",
+            &self.admin_tls_cert,
+            &config.admin_tls_cert,
+        );
+        changes.restart("admin_tls_key", &self.admin_tls_key, &config.admin_tls_key);
+        changes.restart(
+            "admin_tls_client_ca",
+            &self.admin_tls_client_ca,
+            &config.admin_tls_client_ca,
+        );
+        changes.restart(
+            "grpc_web_origins",
+            &self.grpc_web_origins,
+            &config.grpc_web_origins,
+        );
+        changes.restart(
+            "min_free_disk_mb",
+            &self.min_free_disk,
+            &config.min_free_disk,
+        );
+        changes.restart("data_dir", &self.data_dir, &config.data_dir);
+        changes.restart("backup_dir", &self.backup_dir, &config.backup_dir);
+        changes.restart(
+            "backup_upload_url",
+            &self.backup_upload_url,
+            &config.backup_upload_url,
+        );
+        changes.restart(
+            "backup_upload_endpoint",
+            &self.backup_upload.endpoint,
+            &config.backup_upload.endpoint,
+        );
+        changes.restart(
+            "backup_upload_region",
+            &self.backup_upload.region,
+            &config.backup_upload.region,
+        );
+        changes.restart(
+            "backup_upload_access_key_id",
+            &self.backup_upload.access_key_id,
+            &config.backup_upload.access_key_id,
+        );
+        changes.restart(
+            "backup_upload_secret_access_key",
+            &self.backup_upload.secret_access_key,
+            &config.backup_upload.secret_access_key,
+        );
+        changes.restart("tiering", &self.tiering, &config.tiering);
+        changes.restart("cdc", &self.cdc, &config.cdc);
+        changes.restart("replication", &self.replication, &config.replication);
+        changes.restart("reaper", &self.reaper, &config.reaper);
+        changes.restart("orphans", &self.orphans, &config.orphans);
+        changes.restart("provisioning", &self.provisioning, &config.provisioning);
+        changes.restart(
+            "compaction_windows",
+            &self.compaction_windows,
+            &config.compaction_windows,
+        );
+        changes.restart(
+            "compaction_min_pending_mb",
+            &self.compaction_min_pending,
+            &config.compaction_min_pending,
+        );
+        changes.restart(
+            "compaction_rate_limit_mb",
+            &self.background.rate_limit,
+            &config.background.rate_limit,
+        );
+        changes.restart(
+            "max_background_jobs",
+            &self.background.max_jobs,
+            &config.background.max_jobs,
+        );
+        changes.restart(
+            "compaction_threads",
+            &self.background.compaction_threads,
+            &config.background.compaction_threads,
+        );
+        changes.restart(
+            "flush_threads",
+            &self.background.flush_threads,
+            &config.background.flush_threads,
+        );
+        changes.restart(
+            "transform_fuel",
+            &self.transform_limits.fuel,
+            &config.transform_limits.fuel,
+        );
+        changes.restart(
+            "transform_memory_mb",
+            &self.transform_limits.memory,
+            &config.transform_limits.memory,
+        );
+        changes.restart("jwt_algorithm", &self.jwt_algorithm, &config.jwt_algorithm);
+        changes.restart("jwt_secret", &self.jwt_secret, &config.jwt_secret);
+        changes.restart(
+            "jwt_public_key",
+            &self.jwt_public_key,
+            &config.jwt_public_key,
+        );
+        changes.restart("jwt_issuer", &self.jwt_issuer, &config.jwt_issuer);
+        changes.restart("jwt_audience", &self.jwt_audience, &config.jwt_audience);
+        changes.restart("clock_skew_secs", &self.clock_skew, &config.clock_skew);
+        if !changes.requires_restart().is_empty() {
+            warn!(
+                settings = changes.requires_restart().join(", "),
+                "changed settings only take effect after a restart"
+            );
+        }
+
+        let detail = changes.applied().join("; ");
+        info!(changes = detail, "reloaded configuration");
+        warn!(
+            target: "audit",
+            event = "config_reloaded",
+            outcome = "success",
+            detail = detail,
+        );
+    }
+}
+
+// The tier settings are read whether or not tier_url is set, so none of them are reported unknown
+fn tiering(config: &Config) -> Result<Option<TierSettings>, Error> {
+    let url: Option<Url> = config.get("tier_url")?;
+    let after_hours = config.get_or("tier_after_hours", DEFAULT_TIER_AFTER_HOURS)?;
+    let min_value_kb = config.get_or("tier_min_value_kb", DEFAULT_TIER_MIN_VALUE_KB)?;
+    let promote = config.get_or("tier_promote", false)?;
+    if after_hours == 0 {
+        return Err(config.invalid("tier_after_hours", "must be greater than 0"));
+    }
+    Ok(url.map(|url| TierSettings {
+        url,
+        after: Duration::from_secs(after_hours * 60 * 60),
+        min_value_size: min_value_kb * 1024,
+        promote,
+    }))
+}
+
+// cdc_brokers is a comma separated list of host:port
+fn cdc(config: &Config) -> Result<Option<CdcSettings>, Error> {
+    let brokers: Option<String> = config.get("cdc_brokers")?;
+    let topic = config.get_or("cdc_topic", DEFAULT_CDC_TOPIC.to_string())?;
+    if !topic.contains("{namespace_id}") {
+        return Err(config.invalid(
+            "cdc_topic",
+            "must contain {namespace_id}, each namespace has its own topic",
+        ));
+    }
+    let Some(brokers) = brokers else {
+        return Ok(None);
+    };
+    let brokers: Vec<String> = brokers
+        .split(',')
+        .map(str::trim)
+        .filter(|broker| !broker.is_empty())
+        .map(String::from)
+        .collect();
+    if brokers.is_empty() {
+        return Err(config.invalid("cdc_brokers", "must list at least one broker"));
+    }
+    Ok(Some(CdcSettings { brokers, topic }))
+}
+
+// A node logs writes without a token, it only needs one to follow leaders on other nodes
+fn replication(config: &Config) -> Result<Option<ReplicationSettings>, Error> {
+    let log_entries: Option<u64> = config.get("replication_log_entries")?;
+    let token: Option<String> = config.get("replication_token")?;
+    // 0 turns anti entropy off, it only runs on nodes with a replication token
+    let anti_entropy_secs =
+        config.get_or("replication_anti_entropy_secs", DEFAULT_ANTI_ENTROPY_SECS)?;
+    match log_entries {
+        Some(0) => Err(config.invalid("replication_log_entries", "must be greater than 0")),
+        Some(log_entries) => Ok(Some(ReplicationSettings {
+            log_entries,
+            token,
+            anti_entropy_interval: (anti_entropy_secs > 0)
+                .then(|| Duration::from_secs(anti_entropy_secs)),
+        })),
+        None if token.is_some() => Err(config.invalid(
+            "replication_token",
+            "replication_log_entries must also be set",
+        )),
+        None => Ok(None),
+    }
+}
+
+// Like the tier settings, the reaper's are read whether or not it's enabled
+fn reaper(config: &Config) -> Result<Option<ReaperSettings>, Error> {
+    let interval_secs = config.get_or("reaper_interval_secs", DEFAULT_REAPER_INTERVAL_SECS)?;
+    let batch_size = config.get_or("reaper_batch_size", DEFAULT_REAPER_BATCH_SIZE)?;
+    let max_deletes_per_sec = config.get_or(
+        "reaper_max_deletes_per_sec",
+        DEFAULT_REAPER_MAX_DELETES_PER_SEC,
+    )?;
+    if batch_size == 0 {
+        return Err(config.invalid("reaper_batch_size", "must be greater than 0"));
+    }
+    if max_deletes_per_sec == 0 {
+        return Err(config.invalid("reaper_max_deletes_per_sec", "must be greater than 0"));
+    }
+    Ok((interval_secs > 0).then(|| ReaperSettings {
+        interval: Duration::from_secs(interval_secs),
+        batch_size,
+        max_deletes_per_sec,
+    }))
+}
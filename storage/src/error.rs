@@ -0,0 +1,365 @@
+use crate::merkle;
+use std::collections::HashMap;
+use std::time::Duration;
+use thiserror::Error;
+use tonic::{Code, Status};
+use tonic_types::{ErrorDetails, StatusExt};
+
+// The google.rpc.ErrorInfo domain of every error the storage service returns
+pub const ERROR_DOMAIN: &str = "storage.kvstore";
+
+// Errors from partitions and the storage service, converted into a grpc status at the service boundary
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("rocksdb error: {0}")]
+    RocksDB(#[from] rocksdb::Error),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("object store error: {0}")]
+    ObjectStore(#[from] object_store::Error),
+
+    #[error("uploaded {location} is {found} bytes, expected {expected}")]
+    UploadMismatch {
+        location: String,
+        expected: u64,
+        found: u64,
+    },
+
+    #[error("kafka error: {0}")]
+    Kafka(#[from] rskafka::client::error::Error),
+
+    // changes aren't published until the namespace's topic is created
+    #[error("topic {0} doesn't exist")]
+    TopicNotFound(String),
+
+    #[error("invalid upload url")]
+    InvalidUploadUrl(#[source] url::ParseError),
+
+    #[error("partition is in format {found}, this build reads format {supported}")]
+    UnsupportedFormat { found: u32, supported: u32 },
+
+    #[error("key metadata is in an unknown encoding")]
+    UnknownEncoding,
+
+    #[error("key not found")]
+    NotFound,
+
+    #[error("partition not found")]
+    PartitionNotFound,
+
+    // a namespace is only cloned into one that has no partitions on the node
+    #[error("the namespace already has partitions")]
+    NamespaceExists,
+
+    // a namespace's partitions are only dropped once its keys are deleted
+    #[error("the namespace still has {0} keys")]
+    NamespaceNotEmpty(u64),
+
+    #[error("invalid namespace id")]
+    InvalidNamespace(#[source] uuid::Error),
+
+    #[error("invalid {field}")]
+    InvalidId {
+        field: &'static str,
+        #[source]
+        source: uuid::Error,
+    },
+
+    #[error("crc {expected} does not match the computed crc {computed}")]
+    CrcMismatch { expected: u32, computed: u32 },
+
+    #[error("expected version {expected}, the key is at version {current}")]
+    VersionConflict { expected: u32, current: u32 },
+
+    #[error("the key already exists at version {version}")]
+    KeyExists {
+        version: u32,
+        crc: u32,
+        // unix seconds, 0 for a key that doesn't expire
+        expires_at: u64,
+    },
+
+    #[error("permission denied")]
+    PermissionDenied,
+
+    #[error("namespace {limit} quota of {quota} exceeded")]
+    QuotaExceeded { limit: &'static str, quota: u64 },
+
+    #[error("invalid transform: {0}")]
+    InvalidTransform(String),
+
+    #[error("transform failed: {0}")]
+    TransformFailed(String),
+
+    #[error("value rejected by the namespace's write transform with code {0}")]
+    ValueRejected(i32),
+
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+
+    #[error("invalid key policy: {0}")]
+    InvalidKeyPolicy(String),
+
+    #[error("unknown consistency {0}")]
+    InvalidConsistency(i32),
+
+    #[error("the listing's snapshot expired, start the listing over")]
+    SnapshotExpired,
+
+    #[error("invalid transaction: {0}")]
+    InvalidTransaction(String),
+
+    // a key's value was moved to an object store, but the node was started without tier_url
+    #[error("the value is tiered but tiering isn't configured")]
+    TieringDisabled,
+
+    #[error("writes are {condition} while the partition catches up on flushes and compactions")]
+    WriteStalled {
+        condition: &'static str,
+        retry_after: Duration,
+    },
+
+    // writes to a follower partition only come from its leader's replication log
+    #[error("the partition follows {leader}, write to the leader")]
+    FollowerPartition { leader: String },
+
+    #[error("replication isn't enabled on this node")]
+    ReplicationDisabled,
+
+    #[error("invalid leader {0}")]
+    InvalidLeader(String),
+
+    #[error("invalid merkle tree leaf {0}")]
+    InvalidLeaf(u32),
+
+    #[error("the replication log starts at {first}, entries from {from} were trimmed")]
+    LogTrimmed { from: u64, first: u64 },
+
+    // the follower has entries the leader doesn't, e.g. it was a leader that took writes after the
+    // new leader was promoted
+    #[error("the follower is at {from}, past the end of the leader's log at {next}")]
+    AheadOfLeader { from: u64, next: u64 },
+
+    // the follower is being copied from a checkpoint of its leader's partition
+    #[error("the partition is bootstrapping from its leader, try again once it's done")]
+    Bootstrapping,
+
+    #[error("expected replication log entry {expected}, got {found}")]
+    LogGap { expected: u64, found: u64 },
+
+    #[error("leader returned {}: {}", .0.code(), .0.message())]
+    Leader(Box<Status>),
+
+    // a file of the leader's checkpoint is named for somewhere outside the directory it's copied to
+    #[error("invalid checkpoint file {0}")]
+    InvalidCheckpointFile(String),
+}
+
+impl From<&rocksdb::Error> for Error {
+    fn from(value: &rocksdb::Error) -> Self {
+        Error::RocksDB(value.clone())
+    }
+}
+
+impl Error {
+    pub fn code(&self) -> Code {
+        match self {
+            Error::NotFound | Error::PartitionNotFound => Code::NotFound,
+            // a tenant's module failing or rejecting a value is the tenant's to fix
+            Error::InvalidNamespace(_)
+            | Error::InvalidId { .. }
+            | Error::InvalidUploadUrl(_)
+            | Error::InvalidTransform(_)
+            | Error::TransformFailed(_)
+            | Error::ValueRejected(_)
+            | Error::InvalidKey(_)
+            | Error::InvalidKeyPolicy(_)
+            | Error::InvalidConsistency(_)
+            | Error::InvalidLeader(_)
+            | Error::InvalidLeaf(_)
+            | Error::InvalidTransaction(_) => Code::InvalidArgument,
+            // the value arrived corrupted, distinct from a request that's malformed
+            Error::CrcMismatch { .. } => Code::DataLoss,
+            Error::VersionConflict { .. }
+            | Error::SnapshotExpired
+            | Error::NamespaceNotEmpty(_) => Code::FailedPrecondition,
+            Error::KeyExists { .. } | Error::NamespaceExists => Code::AlreadyExists,
+            Error::PermissionDenied => Code::PermissionDenied,
+            Error::QuotaExceeded { .. } | Error::WriteStalled { .. } => Code::ResourceExhausted,
+            Error::UnsupportedFormat { .. } => Code::FailedPrecondition,
+            Error::FollowerPartition { .. }
+            | Error::ReplicationDisabled
+            | Error::LogTrimmed { .. }
+            | Error::AheadOfLeader { .. }
+            | Error::Bootstrapping => Code::FailedPrecondition,
+            Error::RocksDB(_)
+            | Error::Io(_)
+            | Error::ObjectStore(_)
+            | Error::Kafka(_)
+            | Error::TopicNotFound(_)
+            | Error::UploadMismatch { .. }
+            | Error::UnknownEncoding
+            | Error::TieringDisabled
+            | Error::LogGap { .. }
+            | Error::Leader(_)
+            | Error::InvalidCheckpointFile(_) => Code::Internal,
+        }
+    }
+
+    // Identifies the error to clients more precisely than the grpc code, e.g. a missing key and a
+    // missing partition are both NotFound
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::RocksDB(_)
+            | Error::Io(_)
+            | Error::ObjectStore(_)
+            | Error::Kafka(_)
+            | Error::TopicNotFound(_)
+            | Error::UploadMismatch { .. }
+            | Error::Leader(_)
+            | Error::InvalidCheckpointFile(_) => "INTERNAL",
+            Error::UnsupportedFormat { .. } => "UNSUPPORTED_FORMAT",
+            Error::UnknownEncoding => "UNKNOWN_ENCODING",
+            Error::TieringDisabled => "TIERING_DISABLED",
+            Error::NotFound => "KEY_NOT_FOUND",
+            Error::PartitionNotFound => "PARTITION_NOT_FOUND",
+            Error::NamespaceExists => "NAMESPACE_EXISTS",
+            Error::NamespaceNotEmpty(_) => "NAMESPACE_NOT_EMPTY",
+            Error::InvalidNamespace(_) => "INVALID_NAMESPACE",
+            Error::InvalidId { .. } => "INVALID_ID",
+            Error::CrcMismatch { .. } => "CRC_MISMATCH",
+            Error::VersionConflict { .. } => "VERSION_CONFLICT",
+            Error::KeyExists { .. } => "KEY_EXISTS",
+            Error::InvalidUploadUrl(_) => "INVALID_UPLOAD_URL",
+            Error::PermissionDenied => "PERMISSION_DENIED",
+            Error::QuotaExceeded { .. } => "QUOTA_EXCEEDED",
+            Error::InvalidTransform(_) => "INVALID_TRANSFORM",
+            Error::TransformFailed(_) => "TRANSFORM_FAILED",
+            Error::ValueRejected(_) => "VALUE_REJECTED",
+            Error::InvalidKey(_) => "INVALID_KEY",
+            Error::InvalidKeyPolicy(_) => "INVALID_KEY_POLICY",
+            Error::InvalidConsistency(_) => "INVALID_CONSISTENCY",
+            Error::InvalidTransaction(_) => "INVALID_TRANSACTION",
+            Error::SnapshotExpired => "SNAPSHOT_EXPIRED",
+            Error::WriteStalled { .. } => "WRITE_STALLED",
+            Error::FollowerPartition { .. } => "FOLLOWER_PARTITION",
+            Error::ReplicationDisabled => "REPLICATION_DISABLED",
+            Error::InvalidLeader(_) => "INVALID_LEADER",
+            Error::InvalidLeaf(_) => "INVALID_LEAF",
+            Error::LogTrimmed { .. } => "LOG_TRIMMED",
+            Error::AheadOfLeader { .. } => "AHEAD_OF_LEADER",
+            Error::Bootstrapping => "BOOTSTRAPPING",
+            Error::LogGap { .. } => "LOG_GAP",
+        }
+    }
+
+    // google.rpc error details sent along with the status
+    pub fn details(&self) -> ErrorDetails {
+        let metadata = match self {
+            Error::CrcMismatch { expected, computed } => HashMap::from([
+                ("expected_crc".to_string(), expected.to_string()),
+                ("computed_crc".to_string(), computed.to_string()),
+            ]),
+            Error::ValueRejected(code) => {
+                HashMap::from([("rejection_code".to_string(), code.to_string())])
+            }
+            Error::VersionConflict { expected, current } => HashMap::from([
+                ("expected_version".to_string(), expected.to_string()),
+                ("current_version".to_string(), current.to_string()),
+            ]),
+            // the existing key's metadata, so the caller doesn't need another read to see who won
+            Error::KeyExists {
+                version,
+                crc,
+                expires_at,
+            } => {
+                let mut metadata = HashMap::from([
+                    ("version".to_string(), version.to_string()),
+                    ("crc".to_string(), crc.to_string()),
+                ]);
+                if *expires_at > 0 {
+                    metadata.insert("expires_at".to_string(), expires_at.to_string());
+                }
+                metadata
+            }
+            Error::WriteStalled { condition, .. } => {
+                HashMap::from([("condition".to_string(), condition.to_string())])
+            }
+            Error::FollowerPartition { leader } => {
+                HashMap::from([("leader".to_string(), leader.clone())])
+            }
+            _ => HashMap::new(),
+        };
+        let mut details = ErrorDetails::with_error_info(self.reason(), ERROR_DOMAIN, metadata);
+        match self {
+            Error::InvalidNamespace(err) => {
+                details.add_bad_request_violation("namespace_id", err.to_string());
+            }
+            Error::InvalidId { field, source } => {
+                details.add_bad_request_violation(*field, source.to_string());
+            }
+            Error::InvalidUploadUrl(err) => {
+                details.add_bad_request_violation("upload_to", err.to_string());
+            }
+            Error::InvalidKey(reason) => {
+                details.add_bad_request_violation("key", reason);
+            }
+            Error::InvalidKeyPolicy(reason) => {
+                details.add_bad_request_violation("key_policy", reason);
+            }
+            Error::InvalidTransaction(reason) => {
+                details.add_bad_request_violation("ops", reason);
+            }
+            Error::InvalidLeader(_) => {
+                details.add_bad_request_violation("leader", "must be an http or https url");
+            }
+            Error::InvalidLeaf(_) => {
+                details.add_bad_request_violation(
+                    "leaves",
+                    format!("must be less than {}", merkle::LEAVES),
+                );
+            }
+            Error::CrcMismatch { .. } => {
+                details.add_bad_request_violation(
+                    "crc",
+                    "does not match the crc of the key and value",
+                );
+            }
+            Error::VersionConflict { .. } => {
+                details.add_precondition_failure_violation("VERSION", "key", self.to_string());
+            }
+            Error::SnapshotExpired => {
+                details.add_precondition_failure_violation(
+                    "SNAPSHOT",
+                    "snapshot_id",
+                    self.to_string(),
+                );
+            }
+            Error::QuotaExceeded { limit, quota } => {
+                details.add_quota_failure_violation(
+                    *limit,
+                    format!("the namespace is limited to {}", quota),
+                );
+            }
+            // the write can be retried as is once rocksdb has caught up
+            Error::WriteStalled { retry_after, .. } => {
+                details.set_retry_info(Some(*retry_after));
+            }
+            _ => {}
+        }
+        details
+    }
+}
+
+// Internal errors are reported without their details, those are logged where they happen
+impl From<Error> for Status {
+    fn from(err: Error) -> Self {
+        let message = match err.code() {
+            Code::Internal => "internal error".to_string(),
+            _ => err.to_string(),
+        };
+        Status::with_error_details(err.code(), message, err.details())
+    }
+}
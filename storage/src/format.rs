@@ -0,0 +1,223 @@
+use crate::error::Error;
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{properties, IteratorMode, Options, WriteBatch, DB, DEFAULT_COLUMN_FAMILY_NAME};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The on disk format partitions are written in. Bump it with a migration below whenever the
+// metadata encoding or the partition's layout changes.
+pub const CURRENT_FORMAT: u32 = 2;
+
+// partitions created before the format was recorded are in the first format
+const FIRST_FORMAT: u32 = 1;
+const FORMAT_FILE: &str = "FORMAT";
+
+// keys rewritten per write batch, progress is reported after every batch
+const BATCH_SIZE: usize = 10_000;
+
+// Rewrites the metadata of every key from one format to the next
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    pub rewrite: fn(&[u8]) -> Vec<u8>,
+}
+
+// in order, the migration from format n to n + 1 is the one with from = n
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    description: "add an expiry to each key's metadata",
+    rewrite: add_expiry,
+}];
+
+// keys written before expiries never expire
+fn add_expiry(metadata: &[u8]) -> Vec<u8> {
+    [metadata, NEVER_EXPIRES.to_be_bytes().as_slice()].concat()
+}
+
+const NEVER_EXPIRES: u64 = 0;
+pub const METADATA_LEN: usize = 16;
+
+// A key's entry in the metadata column family, the value's crc, the key's version, and when the
+// key expires in unix seconds or 0 if it doesn't, all big endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryMetadata {
+    pub crc: u32,
+    pub version: u32,
+    pub expires_at: u64,
+}
+
+impl EntryMetadata {
+    // None when the entry isn't in the current format's encoding
+    pub fn decode(metadata: &[u8]) -> Option<EntryMetadata> {
+        if metadata.len() != METADATA_LEN {
+            return None;
+        }
+        Some(EntryMetadata {
+            crc: u32::from_be_bytes(metadata[..4].try_into().unwrap()),
+            version: u32::from_be_bytes(metadata[4..8].try_into().unwrap()),
+            expires_at: u64::from_be_bytes(metadata[8..].try_into().unwrap()),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        [
+            self.crc.to_be_bytes().as_slice(),
+            self.version.to_be_bytes().as_slice(),
+            self.expires_at.to_be_bytes().as_slice(),
+        ]
+        .concat()
+    }
+
+    // When the key expires, None if it doesn't
+    pub fn expiry(&self) -> Option<SystemTime> {
+        (self.expires_at != NEVER_EXPIRES)
+            .then(|| UNIX_EPOCH + Duration::from_secs(self.expires_at))
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expiry().is_some_and(|expiry| expiry <= now)
+    }
+}
+
+// The format of the partition in the directory
+pub fn version(partition_dir: &Path) -> io::Result<u32> {
+    match std::fs::read_to_string(partition_dir.join(FORMAT_FILE)) {
+        Ok(version) => version
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid partition format")),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(FIRST_FORMAT),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn set_version(partition_dir: &Path, version: u32) -> io::Result<()> {
+    std::fs::write(partition_dir.join(FORMAT_FILE), format!("{}\n", version))
+}
+
+// Fails if the partition in the directory isn't in the format this build reads
+pub fn check_version(partition_dir: &Path) -> Result<(), Error> {
+    match version(partition_dir)? {
+        CURRENT_FORMAT => Ok(()),
+        found => Err(Error::UnsupportedFormat {
+            found,
+            supported: CURRENT_FORMAT,
+        }),
+    }
+}
+
+// where the partition is checkpointed before it's migrated
+fn checkpoint_dir(partition_dir: &Path) -> PathBuf {
+    let mut dir = partition_dir.as_os_str().to_owned();
+    dir.push(".pre-migration");
+    PathBuf::from(dir)
+}
+
+// Brings a partition that's out of service up to the current format. A checkpoint of the partition
+// is taken first, next to it, so a failed or unwanted migration can be rolled back.
+pub fn migrate(partition_dir: &Path) -> Result<(), Error> {
+    let from = version(partition_dir)?;
+    if from == CURRENT_FORMAT {
+        println!("partition is already in format {}", CURRENT_FORMAT);
+        return Ok(());
+    }
+    if from > CURRENT_FORMAT {
+        return Err(Error::UnsupportedFormat {
+            found: from,
+            supported: CURRENT_FORMAT,
+        });
+    }
+
+    let checkpoint_dir = checkpoint_dir(partition_dir);
+    if checkpoint_dir.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} is left from an earlier migration, roll back or delete it first",
+                checkpoint_dir.display()
+            ),
+        )
+        .into());
+    }
+
+    let db = DB::open_cf(
+        &Options::default(),
+        partition_dir,
+        [DEFAULT_COLUMN_FAMILY_NAME, "metadata"],
+    )?;
+    Checkpoint::new(&db)?.create_checkpoint(&checkpoint_dir)?;
+    set_version(&checkpoint_dir, from)?;
+    println!(
+        "checkpointed format {} to {}, roll back with storage --migrate-rollback {}",
+        from,
+        checkpoint_dir.display(),
+        partition_dir.display()
+    );
+
+    let handle = db.cf_handle("metadata").unwrap();
+    let total = db
+        .property_int_value_cf(&handle, properties::ESTIMATE_NUM_KEYS)?
+        .unwrap_or(0);
+    for version in from..CURRENT_FORMAT {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or(Error::UnsupportedFormat {
+                found: version,
+                supported: CURRENT_FORMAT,
+            })?;
+        println!(
+            "migrating format {} to {}: {}",
+            version,
+            version + 1,
+            migration.description
+        );
+
+        let mut batch = WriteBatch::default();
+        let mut migrated = 0;
+        for item in db.iterator_cf(&handle, IteratorMode::Start) {
+            let (key, metadata) = item?;
+            batch.put_cf(&handle, &key, (migration.rewrite)(&metadata));
+            migrated += 1;
+            if batch.len() >= BATCH_SIZE {
+                db.write(std::mem::take(&mut batch))?;
+                println!("  {} of about {} keys", migrated, total);
+            }
+        }
+        db.write(batch)?;
+        db.flush_cf(&handle)?;
+        set_version(partition_dir, version + 1)?;
+        println!("  {} keys migrated", migrated);
+    }
+
+    println!(
+        "partition is now in format {}, delete {} once the partition has been checked",
+        CURRENT_FORMAT,
+        checkpoint_dir.display()
+    );
+    Ok(())
+}
+
+// Puts the checkpoint taken by migrate back in place of the partition. The migrated partition is
+// kept next to it rather than deleted.
+pub fn rollback(partition_dir: &Path) -> Result<(), Error> {
+    let checkpoint_dir = checkpoint_dir(partition_dir);
+    if !checkpoint_dir.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no checkpoint at {}", checkpoint_dir.display()),
+        )
+        .into());
+    }
+    let mut migrated_dir = partition_dir.as_os_str().to_owned();
+    migrated_dir.push(".migrated");
+    std::fs::rename(partition_dir, &migrated_dir)?;
+    std::fs::rename(&checkpoint_dir, partition_dir)?;
+    println!(
+        "rolled back to format {}, the migrated partition was moved to {}",
+        version(partition_dir)?,
+        PathBuf::from(migrated_dir).display()
+    );
+    Ok(())
+}
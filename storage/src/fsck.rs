@@ -0,0 +1,197 @@
+use crate::error::Error;
+use crate::format::{self, EntryMetadata};
+use crate::partition::TIER_CF;
+use crate::tier::TierEntry;
+use crc32fast::Hasher;
+use rocksdb::{IteratorMode, Options, DB, DEFAULT_COLUMN_FAMILY_NAME};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+// only the first issues are listed in full, the rest are just counted
+const MAX_LISTED_ISSUES: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IssueKind {
+    MetadataWithoutValue,
+    ValueWithoutMetadata,
+    UnknownEncoding,
+    CrcMismatch,
+}
+
+impl IssueKind {
+    fn name(&self) -> &'static str {
+        match self {
+            IssueKind::MetadataWithoutValue => "metadata without a value",
+            IssueKind::ValueWithoutMetadata => "value without metadata",
+            IssueKind::UnknownEncoding => "unknown metadata encoding",
+            IssueKind::CrcMismatch => "crc mismatch",
+        }
+    }
+
+    fn repair(&self) -> &'static str {
+        match self {
+            IssueKind::MetadataWithoutValue => {
+                "the key reads as not found, delete it so it stops being listed"
+            }
+            IssueKind::ValueWithoutMetadata => {
+                "the key reads as not found, rewrite it to recompute its metadata or delete it"
+            }
+            IssueKind::UnknownEncoding => {
+                "written by a newer or broken build, check the node's version before touching it"
+            }
+            IssueKind::CrcMismatch => {
+                "the value is corrupt, restore the key from a backup or delete it"
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub key: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub path: PathBuf,
+    pub keys_checked: u64,
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn add(&mut self, kind: IssueKind, key: &[u8]) {
+        self.issues.push(Issue {
+            kind,
+            key: key.to_vec(),
+        });
+    }
+
+    // a key whose value was tiered has metadata alone
+    fn add_unless_tiered(&mut self, key: &[u8], tiered: bool) {
+        match tiered {
+            true => self.keys_checked += 1,
+            false => self.add(IssueKind::MetadataWithoutValue, key),
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "partition {}", self.path.display())?;
+        writeln!(f, "{} keys checked", self.keys_checked)?;
+        if self.is_clean() {
+            return writeln!(f, "no issues found");
+        }
+
+        let mut counts: BTreeMap<IssueKind, u64> = BTreeMap::new();
+        for issue in &self.issues {
+            *counts.entry(issue.kind).or_default() += 1;
+        }
+        writeln!(f, "{} issues found", self.issues.len())?;
+        for (kind, count) in &counts {
+            writeln!(f, "  {}: {}, {}", kind.name(), count, kind.repair())?;
+        }
+
+        writeln!(f)?;
+        for issue in self.issues.iter().take(MAX_LISTED_ISSUES) {
+            writeln!(f, "{}: {}", issue.kind.name(), issue.key.escape_ascii())?;
+        }
+        if self.issues.len() > MAX_LISTED_ISSUES {
+            writeln!(f, "... {} more", self.issues.len() - MAX_LISTED_ISSUES)?;
+        }
+        Ok(())
+    }
+}
+
+// Checks a partition that isn't being served. The partition is opened read only, and every key's
+// metadata and value are read together to verify each has the other, the metadata's encoding is
+// known, and the value matches its crc. A tiered key has no value in the partition, its value's crc
+// is checked whenever it's fetched.
+pub fn check(path: &Path) -> Result<Report, Error> {
+    // keys can only be decoded in the format this build writes, older partitions are migrated first
+    format::check_version(path)?;
+    let db = open_read_only(path)?;
+    let metadata_handle = db.cf_handle("metadata").unwrap();
+    let default_handle = db.cf_handle(DEFAULT_COLUMN_FAMILY_NAME).unwrap();
+    let tier_handle = db.cf_handle(TIER_CF);
+    let tiered = |key: &[u8]| -> Result<bool, Error> {
+        let Some(tier_handle) = &tier_handle else {
+            return Ok(false);
+        };
+        Ok(db
+            .get_pinned_cf(tier_handle, key)?
+            .and_then(|entry| TierEntry::decode(&entry))
+            .is_some_and(|entry| entry.object.is_some()))
+    };
+
+    let mut report = Report {
+        path: path.to_path_buf(),
+        ..Report::default()
+    };
+
+    // both column families are in key order, so they're walked side by side
+    let mut metadata = db.iterator_cf(&metadata_handle, IteratorMode::Start);
+    let mut values = db.iterator_cf(&default_handle, IteratorMode::Start);
+    let mut next_metadata = metadata.next().transpose()?;
+    let mut next_value = values.next().transpose()?;
+    loop {
+        match (&next_metadata, &next_value) {
+            (None, None) => break,
+            (Some((key, _)), None) => {
+                report.add_unless_tiered(key, tiered(key)?);
+                next_metadata = metadata.next().transpose()?;
+            }
+            (None, Some((key, _))) => {
+                report.add(IssueKind::ValueWithoutMetadata, key);
+                next_value = values.next().transpose()?;
+            }
+            (Some((metadata_key, _)), Some((value_key, _))) if metadata_key < value_key => {
+                report.add_unless_tiered(metadata_key, tiered(metadata_key)?);
+                next_metadata = metadata.next().transpose()?;
+            }
+            (Some((metadata_key, _)), Some((value_key, _))) if metadata_key > value_key => {
+                report.add(IssueKind::ValueWithoutMetadata, value_key);
+                next_value = values.next().transpose()?;
+            }
+            (Some((key, key_metadata)), Some((_, value))) => {
+                report.keys_checked += 1;
+                match EntryMetadata::decode(key_metadata) {
+                    None => report.add(IssueKind::UnknownEncoding, key),
+                    Some(entry) if entry.crc != crc(key, value) => {
+                        report.add(IssueKind::CrcMismatch, key)
+                    }
+                    Some(_) => {}
+                }
+                next_metadata = metadata.next().transpose()?;
+                next_value = values.next().transpose()?;
+            }
+        }
+    }
+    Ok(report)
+}
+
+// Opens a partition, or a checkpoint of one, without writing to it. Every column family it has is
+// opened, the ones besides default and metadata are only there once they're used.
+pub fn open_read_only(path: &Path) -> Result<DB, Error> {
+    let column_families = DB::list_cf(&Options::default(), path)?;
+    Ok(DB::open_cf_for_read_only(
+        &Options::default(),
+        path,
+        column_families,
+        false,
+    )?)
+}
+
+// the crc the node computes on put, over the key followed by the value
+pub fn crc(key: &[u8], value: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize()
+}
@@ -0,0 +1,84 @@
+use hyper::header::{HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use hyper::Method;
+use std::str::FromStr;
+use std::time::Duration;
+use tonic_web::GrpcWebLayer;
+use tower::layer::util::Stack;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+// How long browsers can cache a preflight's answer
+const PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+// The headers grpc-web clients send besides the token and content type, and the ones they read the
+// rpc's status from when it isn't in trailers
+const ALLOW_HEADERS: [&str; 5] = [
+    "x-grpc-web",
+    "x-user-agent",
+    "grpc-timeout",
+    "traceparent",
+    "tracestate",
+];
+const EXPOSE_HEADERS: [&str; 3] = ["grpc-status", "grpc-message", "grpc-status-details-bin"];
+
+// The browser origins allowed to call the node over grpc-web, either * for any origin or a comma
+// separated list, e.g. "https://app.example.com,https://admin.example.com"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GrpcWebOrigins {
+    Any,
+    List(Vec<HeaderValue>),
+}
+
+impl FromStr for GrpcWebOrigins {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim() == "*" {
+            return Ok(GrpcWebOrigins::Any);
+        }
+        let origins = s
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(parse_origin)
+            .collect::<Result<Vec<_>, _>>()?;
+        match origins.is_empty() {
+            true => Err("expected * or a comma separated list of origins".to_string()),
+            false => Ok(GrpcWebOrigins::List(origins)),
+        }
+    }
+}
+
+// An origin is a scheme and host, with the port when it isn't the scheme's default
+fn parse_origin(origin: &str) -> Result<HeaderValue, String> {
+    if !origin.starts_with("http://") && !origin.starts_with("https://") {
+        return Err(format!(
+            "origin {} must start with http:// or https://",
+            origin
+        ));
+    }
+    HeaderValue::from_str(origin.trim_end_matches('/'))
+        .map_err(|_| format!("invalid origin {}", origin))
+}
+
+// Translates grpc-web requests to grpc for the services behind it and answers the browsers' cors
+// preflights. Tokens are sent in the authorization header rather than cookies, so credentials
+// aren't allowed.
+pub fn layer(origins: &GrpcWebOrigins) -> Stack<GrpcWebLayer, CorsLayer> {
+    let allow_origin = match origins {
+        GrpcWebOrigins::Any => AllowOrigin::any(),
+        GrpcWebOrigins::List(origins) => AllowOrigin::list(origins.iter().cloned()),
+    };
+    let cors = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::POST])
+        .allow_headers(
+            ALLOW_HEADERS
+                .into_iter()
+                .map(HeaderName::from_static)
+                .chain([AUTHORIZATION, CONTENT_TYPE])
+                .collect::<Vec<_>>(),
+        )
+        .expose_headers(EXPOSE_HEADERS.map(HeaderName::from_static))
+        .max_age(PREFLIGHT_MAX_AGE);
+    Stack::new(GrpcWebLayer::new(), cors)
+}
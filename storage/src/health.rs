@@ -0,0 +1,57 @@
+use crate::lookup::PartitionLookup;
+use common::healthcheck::{DependencyStatus, HealthCheck};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// Every partition's database is open and hasn't hit a background error
+pub struct PartitionCheck(pub Arc<PartitionLookup>);
+
+#[tonic::async_trait]
+impl HealthCheck for PartitionCheck {
+    async fn check(&self) -> Vec<DependencyStatus> {
+        self.0
+            .all_partitions()
+            .iter()
+            .map(|partition| {
+                let name = format!("partition {}", partition.id);
+                match partition.background_errors() {
+                    Ok(0) => DependencyStatus::healthy(name),
+                    Ok(errors) => {
+                        DependencyStatus::unhealthy(name, format!("{} background errors", errors))
+                    }
+                    Err(err) => DependencyStatus::unhealthy(name, err.to_string()),
+                }
+            })
+            .collect()
+    }
+}
+
+// The data directory's disk has room for more writes
+pub struct DiskCheck {
+    path: PathBuf,
+    min_free: u64,
+}
+
+impl DiskCheck {
+    pub fn new(path: impl Into<PathBuf>, min_free: u64) -> DiskCheck {
+        DiskCheck {
+            path: path.into(),
+            min_free,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl HealthCheck for DiskCheck {
+    async fn check(&self) -> Vec<DependencyStatus> {
+        let name = format!("disk {}", self.path.display());
+        vec![match fs2::available_space(&self.path) {
+            Ok(free) if free >= self.min_free => DependencyStatus::healthy(name),
+            Ok(free) => DependencyStatus::unhealthy(
+                name,
+                format!("{} bytes free, at least {} required", free, self.min_free),
+            ),
+            Err(err) => DependencyStatus::unhealthy(name, err.to_string()),
+        }]
+    }
+}
@@ -3,14 +3,18 @@ use std::error::Error;
 use std::fmt::Formatter;
 use std::fs::File;
 use std::path::{Path, PathBuf};
-use crate::partition::{Key, Partition, Error as PError};
+use crate::error::Error as PError;
+use crate::partition::{BackgroundLimits, Key, Partition, Stats};
+use crate::quota::Quota;
+use crate::retention::Retention;
+use common::key_policy::KeyPolicy;
 use dashmap::DashMap;
 use jumphash::{CustomJumpHasher, JumpHasher};
 use tracing::instrument;
-use std::sync::Arc;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::Visitor;
-use tracing::info;
+use tracing::{error, info};
 use uuid::Uuid;
 use common::crc64hasher::Crc64Hasher;
 
@@ -19,13 +23,39 @@ const PARTITION_CONFIG: &str = "partitions.json";
 #[derive(Debug, Clone)]
 pub struct PartitionLookup {
     partitions: DashMap<(Uuid, Uuid), Arc<[Partition]>>,
+    // keyed like the partitions, namespaces without a quota aren't in it
+    quotas: DashMap<(Uuid, Uuid), Quota>,
+    // like the quotas, namespaces that allow every key aren't in it
+    key_policies: DashMap<(Uuid, Uuid), KeyPolicy>,
+    // like the quotas, namespaces that retain nothing aren't in it
+    retentions: DashMap<(Uuid, Uuid), Retention>,
     config_dir: String,
     hasher: CustomJumpHasher<Crc64Hasher>,
+    // writes hold it shared so a backup can hold it exclusively while it checkpoints partitions
+    write_gate: Arc<RwLock<()>>,
+    // every partition is opened with them
+    background: BackgroundLimits,
+    // whether partitions record their changes for publishing, see cdc
+    capture_changes: bool,
+    // whether partitions log their writes for followers, see replication
+    log_writes: bool,
+    // the leader each follower partition follows, by partition id
+    leaders: DashMap<Uuid, String>,
+    // partitions in the partition map whose directory was gone when the node started, see orphans
+    missing_at_load: Vec<Uuid>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct PersistedState {
     partitions: HashMap<PersistedID, Vec<PersistedPartition>>,
+    #[serde(default)]
+    quotas: HashMap<PersistedID, Quota>,
+    #[serde(default)]
+    key_policies: HashMap<PersistedID, KeyPolicy>,
+    #[serde(default)]
+    retentions: HashMap<PersistedID, Retention>,
+    #[serde(default)]
+    leaders: HashMap<Uuid, String>,
 }
 
 #[derive(Debug, Clone, Hash, Ord, PartialOrd, Eq, PartialEq)]
@@ -93,30 +123,98 @@ struct PersistedPartition {
 }
 
 impl PersistedState {
-    fn to_partition_lookup(&self, config_dir: impl AsRef<Path>) -> Result<PartitionLookup, PError> {
+    fn to_partition_lookup(
+        &self,
+        config_dir: impl AsRef<Path>,
+        background: BackgroundLimits,
+        capture_changes: bool,
+        log_writes: bool,
+    ) -> Result<PartitionLookup, PError> {
         let config_dir = config_dir.as_ref();
+        // a partition whose directory is gone is opened empty, it's reported rather than failing
+        // the node since its namespace's other partitions can still be served
+        let missing_at_load: Vec<Uuid> = self
+            .partitions
+            .values()
+            .flatten()
+            .map(|partition| partition.id)
+            .filter(|id| !config_dir.join(id.to_string()).exists())
+            .collect();
+        for id in &missing_at_load {
+            error!(
+                partition_id = id.to_string(),
+                "partition directory is missing, opening it empty"
+            );
+        }
         let mut partitions: DashMap<(Uuid, Uuid), Arc<[Partition]>> = DashMap::new();
         for (key, value) in self.partitions.iter() {
-            let value: Vec<Partition> = value.iter().map(|partition| partition.to_partition(config_dir)).collect::<Result<Vec<Partition>, PError>>()?;
+            let value: Vec<Partition> = value.iter().map(|partition| partition.to_partition(config_dir, &background, capture_changes, log_writes)).collect::<Result<Vec<Partition>, PError>>()?;
 
             partitions.insert(key.into(), value.into());
         }
+        let quotas = self
+            .quotas
+            .iter()
+            .map(|(key, quota)| (key.into(), *quota))
+            .collect();
+        let key_policies = self
+            .key_policies
+            .iter()
+            .map(|(key, policy)| (key.into(), policy.clone()))
+            .collect();
+        let retentions: DashMap<(Uuid, Uuid), Retention> = self
+            .retentions
+            .iter()
+            .map(|(key, retention)| (key.into(), *retention))
+            .collect();
+        for item in retentions.iter() {
+            let Some(partitions) = partitions.get(item.key()) else {
+                continue;
+            };
+            for partition in partitions.iter() {
+                partition.set_retention(*item.value())?;
+            }
+        }
+        let leaders: DashMap<Uuid, String> = self.leaders.clone().into_iter().collect();
+        for partitions in partitions.iter() {
+            for partition in partitions.iter() {
+                partition.set_leader(leaders.get(&partition.id).map(|leader| leader.clone()));
+            }
+        }
 
         Ok(PartitionLookup {
             partitions,
+            quotas,
+            key_policies,
+            retentions,
             hasher: CustomJumpHasher::new(Crc64Hasher::new()),
             config_dir: config_dir.to_str().unwrap().to_string(),
+            write_gate: Arc::default(),
+            background,
+            capture_changes,
+            log_writes,
+            leaders,
+            missing_at_load,
         })
     }
 }
 
 impl PersistedPartition {
-    fn to_partition(&self, base_path: impl AsRef<Path>) -> Result<Partition, PError> {
+    fn to_partition(
+        &self,
+        base_path: impl AsRef<Path>,
+        background: &BackgroundLimits,
+        capture_changes: bool,
+        log_writes: bool,
+    ) -> Result<Partition, PError> {
         Partition::new(
             self.id,
             self.namespace_id,
             self.tenant_id,
             &base_path,
+            background,
+            capture_changes,
+            log_writes,
         )
     }
 }
@@ -142,12 +240,41 @@ impl From<&PartitionLookup> for PersistedState {
             partitions.insert(item.key().into(), value);
         }
 
-        PersistedState { partitions }
+        let quotas = value
+            .quotas
+            .iter()
+            .map(|item| (item.key().into(), *item.value()))
+            .collect();
+
+        let key_policies = value
+            .key_policies
+            .iter()
+            .map(|item| (item.key().into(), item.value().clone()))
+            .collect();
+
+        let retentions = value
+            .retentions
+            .iter()
+            .map(|item| (item.key().into(), *item.value()))
+            .collect();
+
+        let leaders = value
+            .leaders
+            .iter()
+            .map(|item| (*item.key(), item.value().clone()))
+            .collect();
+
+        PersistedState { partitions, quotas, key_policies, retentions, leaders }
     }
 }
 
 impl PartitionLookup {
-    pub fn load(config: impl AsRef<Path>) -> Result<PartitionLookup, Box<dyn Error>> {
+    pub fn load(
+        config: impl AsRef<Path>,
+        background: BackgroundLimits,
+        capture_changes: bool,
+        log_writes: bool,
+    ) -> Result<PartitionLookup, Box<dyn Error>> {
 
         let config = config.as_ref();
 
@@ -159,8 +286,17 @@ impl PartitionLookup {
             info!("creating empty partition lookup");
             return Ok(PartitionLookup{
                 partitions: DashMap::new(),
+                quotas: DashMap::new(),
+                key_policies: DashMap::new(),
+                retentions: DashMap::new(),
                 config_dir: config.to_str().unwrap().to_string(),
                 hasher: CustomJumpHasher::new(Crc64Hasher::new()),
+                write_gate: Arc::default(),
+                background,
+                capture_changes,
+                log_writes,
+                leaders: DashMap::new(),
+                missing_at_load: Vec::new(),
             })
         }
 
@@ -168,7 +304,7 @@ impl PartitionLookup {
         let config_file = File::options().read(true).write(false).open(config_file)?;
         let mut persisted_state: PersistedState = serde_json::from_reader(config_file)?;
 
-        let mut lookup: PartitionLookup = persisted_state.to_partition_lookup(config)?;
+        let mut lookup: PartitionLookup = persisted_state.to_partition_lookup(config, background, capture_changes, log_writes)?;
         lookup.config_dir = config.to_str().unwrap().to_string();
 
         Ok(lookup)
@@ -188,6 +324,20 @@ impl PartitionLookup {
         Ok(())
     }
 
+    // Held while writing to a partition
+    pub fn write_permit(&self) -> RwLockReadGuard<'_, ()> {
+        self.write_gate
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Blocks writes to every partition until the guard is dropped, once the writes in progress finish
+    pub fn pause_writes(&self) -> RwLockWriteGuard<'_, ()> {
+        self.write_gate
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
     // Returns the partition that the key routes to using the consistent jump algorithm
     #[instrument(skip(self, key))]
     pub fn get_partition_for_key(
@@ -211,10 +361,332 @@ impl PartitionLookup {
         }
     }
 
-    pub fn add_partition(&self, partition: Partition) -> std::io::Result<()> {
+    pub fn quota(&self, tenant_id: Uuid, namespace_id: Uuid) -> Option<Quota> {
+        self.quotas
+            .get(&(tenant_id, namespace_id))
+            .map(|quota| *quota)
+    }
+
+    // Replaces the namespace's quota, an unlimited quota removes it
+    pub fn set_quota(
+        &self,
+        tenant_id: Uuid,
+        namespace_id: Uuid,
+        quota: Quota,
+    ) -> std::io::Result<()> {
+        if quota.is_unlimited() {
+            self.quotas.remove(&(tenant_id, namespace_id));
+        } else {
+            self.quotas.insert((tenant_id, namespace_id), quota);
+        }
+        info!(namespace_id = namespace_id.to_string(), quota = ?quota, "set namespace quota");
+        self.save()
+    }
+
+    pub fn key_policy(&self, tenant_id: Uuid, namespace_id: Uuid) -> Option<KeyPolicy> {
+        self.key_policies
+            .get(&(tenant_id, namespace_id))
+            .map(|policy| policy.clone())
+    }
+
+    // Replaces the namespace's key policy, a policy every key follows removes it
+    pub fn set_key_policy(
+        &self,
+        tenant_id: Uuid,
+        namespace_id: Uuid,
+        policy: KeyPolicy,
+    ) -> std::io::Result<()> {
+        info!(namespace_id = namespace_id.to_string(), policy = ?policy, "set key policy");
+        if policy.is_default() {
+            self.key_policies.remove(&(tenant_id, namespace_id));
+        } else {
+            self.key_policies.insert((tenant_id, namespace_id), policy);
+        }
+        self.save()
+    }
+
+    pub fn retention(&self, tenant_id: Uuid, namespace_id: Uuid) -> Retention {
+        self.retentions
+            .get(&(tenant_id, namespace_id))
+            .map(|retention| *retention)
+            .unwrap_or_default()
+    }
+
+    // Replaces the namespace's retention and applies it to the namespace's partitions, one that
+    // retains nothing removes it
+    pub fn set_retention(
+        &self,
+        tenant_id: Uuid,
+        namespace_id: Uuid,
+        retention: Retention,
+    ) -> Result<(), PError> {
+        info!(namespace_id = namespace_id.to_string(), retention = ?retention, "set retention");
+        if retention.is_none() {
+            self.retentions.remove(&(tenant_id, namespace_id));
+        } else {
+            self.retentions.insert((tenant_id, namespace_id), retention);
+        }
+        for partition in self
+            .partitions(tenant_id, namespace_id)
+            .iter()
+            .flat_map(|partitions| partitions.iter())
+        {
+            partition.set_retention(retention)?;
+        }
+        Ok(self.save()?)
+    }
+
+    // Makes the partition follow the leader's copy of it, or with no leader makes it a leader that
+    // takes writes. None when the partition isn't on the node.
+    pub fn set_leader(&self, id: Uuid, leader: Option<String>) -> std::io::Result<Option<Partition>> {
+        let Some(partition) = self.find_partition(id) else {
+            return Ok(None);
+        };
+        info!(partition_id = id.to_string(), leader = ?leader, "set partition leader");
+        match &leader {
+            Some(leader) => self.leaders.insert(id, leader.clone()),
+            None => self.leaders.remove(&id).map(|(_, leader)| leader),
+        };
+        partition.set_leader(leader);
+        self.save()?;
+        Ok(Some(partition))
+    }
+
+    // The namespace's estimated usage summed over its partitions on this node
+    pub fn usage(&self, tenant_id: Uuid, namespace_id: Uuid) -> Result<Stats, PError> {
+        let mut usage = Stats::default();
+        for partition in self
+            .partitions(tenant_id, namespace_id)
+            .iter()
+            .flat_map(|partitions| partitions.iter())
+        {
+            let stats = partition.stats()?;
+            usage.key_count += stats.key_count;
+            usage.total_bytes += stats.total_bytes;
+        }
+        Ok(usage)
+    }
+
+    // The tenant's namespaces with partitions on this node and how many each has, by namespace id
+    pub fn namespaces(&self, tenant_id: Uuid) -> Vec<(Uuid, usize)> {
+        let mut namespaces: Vec<(Uuid, usize)> = self
+            .partitions
+            .iter()
+            .filter(|entry| entry.key().0 == tenant_id)
+            .map(|entry| (entry.key().1, entry.value().len()))
+            .collect();
+        namespaces.sort();
+        namespaces
+    }
+
+    // Copies the source namespace's partitions into the namespace, as rocksdb checkpoints in the
+    // data directory so their files are hard linked rather than copied. Writes are paused while
+    // they're checkpointed, so the copy is a single point in time across the partitions. The
+    // copies keep the source's order, so every key routes to the copy of its partition.
+    //
+    // Changes the source hadn't published yet aren't copied, and neither are its retained values
+    // since the namespace starts out retaining nothing. A tiered value stays in the source
+    // partition's object, and can't be read from the copy once the source no longer points at it.
+    pub fn clone_namespace(
+        &self,
+        tenant_id: Uuid,
+        source_namespace_id: Uuid,
+        namespace_id: Uuid,
+    ) -> Result<Vec<Partition>, PError> {
+        if self.partitions.contains_key(&(tenant_id, namespace_id)) {
+            return Err(PError::NamespaceExists);
+        }
+        let Some(sources) = self.partitions(tenant_id, source_namespace_id) else {
+            return Ok(Vec::new());
+        };
+
+        let mut ids = Vec::with_capacity(sources.len());
+        {
+            let _paused = self.pause_writes();
+            for source in sources.iter() {
+                let id = Uuid::new_v4();
+                source.checkpoint(&PathBuf::from(&self.config_dir).join(id.to_string()))?;
+                ids.push(id);
+            }
+        }
+
+        let mut partitions = Vec::with_capacity(ids.len());
+        for id in ids {
+            let partition = self.open_partition(id, tenant_id, namespace_id)?;
+            partition.discard_changes()?;
+            partitions.push(partition);
+        }
+        // the namespace gains every partition at once, a key never routes to a partial set
+        self.partitions.insert((tenant_id, namespace_id), partitions.clone().into());
+        info!(
+            source_namespace_id = source_namespace_id.to_string(),
+            namespace_id = namespace_id.to_string(),
+            partitions = partitions.len(),
+            "cloned namespace"
+        );
+        self.save()?;
+        Ok(partitions)
+    }
+
+    // Creates the namespace's partitions, which take its retention. A namespace that already has
+    // partitions keeps them and none are created, so creating a namespace again is harmless.
+    pub fn provision_namespace(
+        &self,
+        tenant_id: Uuid,
+        namespace_id: Uuid,
+        count: u64,
+    ) -> Result<Vec<Partition>, PError> {
+        if count == 0 || self.partitions.contains_key(&(tenant_id, namespace_id)) {
+            return Ok(Vec::new());
+        }
+
+        let retention = self.retention(tenant_id, namespace_id);
+        let mut partitions = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let partition = self.open_partition(Uuid::new_v4(), tenant_id, namespace_id)?;
+            partition.set_retention(retention)?;
+            partitions.push(partition);
+        }
+        // like a clone, the namespace gains every partition at once
+        self.partitions.insert((tenant_id, namespace_id), partitions.clone().into());
+        info!(
+            namespace_id = namespace_id.to_string(),
+            partitions = partitions.len(),
+            "provisioned namespace"
+        );
+        self.save()?;
+        Ok(partitions)
+    }
+
+    // Every partition on this node, across all tenants and namespaces
+    pub fn all_partitions(&self) -> Vec<Partition> {
+        self.partitions
+            .iter()
+            .flat_map(|entry| entry.value().to_vec())
+            .collect()
+    }
+
+    pub fn missing_at_load(&self) -> &[Uuid] {
+        &self.missing_at_load
+    }
+
+    // The directory every partition's directory is in
+    pub fn data_dir(&self) -> &Path {
+        Path::new(&self.config_dir)
+    }
+
+    // The directory the partition's rocksdb files are in
+    pub fn partition_dir(&self, id: Uuid) -> PathBuf {
+        PathBuf::from(&self.config_dir).join(id.to_string())
+    }
+
+    pub fn find_partition(&self, id: Uuid) -> Option<Partition> {
+        self.all_partitions().into_iter().find(|partition| partition.id == id)
+    }
+
+    // Opens, or creates, a partition in the config directory
+    pub fn open_partition(&self, id: Uuid, tenant_id: Uuid, namespace_id: Uuid) -> Result<Partition, PError> {
+        Partition::new(
+            id,
+            namespace_id,
+            tenant_id,
+            &self.config_dir,
+            &self.background,
+            self.capture_changes,
+            self.log_writes,
+        )
+    }
+
+    // The partition takes its namespace's retention
+    pub fn add_partition(&self, partition: Partition) -> Result<(), PError> {
+        partition.set_retention(self.retention(partition.tenant_id, partition.namespace_id))?;
         self.add_partition_internal(partition);
         info!("adding new partition");
-        self.save()
+        Ok(self.save()?)
+    }
+
+    // Stops routing keys to the partition. Its rocksdb directory is left on disk, and the database
+    // stays open until requests still using it finish.
+    pub fn remove_partition(&self, id: Uuid) -> std::io::Result<Option<Partition>> {
+        let Some(partition) = self.find_partition(id) else {
+            return Ok(None);
+        };
+        let key = (partition.tenant_id, partition.namespace_id);
+        let remaining: Vec<Partition> = self
+            .partitions(partition.tenant_id, partition.namespace_id)
+            .map(|partitions| partitions.iter().filter(|other| other.id != id).cloned().collect())
+            .unwrap_or_default();
+        if remaining.is_empty() {
+            self.partitions.remove(&key);
+        } else {
+            self.partitions.insert(key, remaining.into());
+        }
+        self.leaders.remove(&id);
+        info!(partition_id = id.to_string(), "removed partition");
+        self.save()?;
+        Ok(Some(partition))
+    }
+
+    // Stops routing keys to the namespace's partitions and forgets its quota, key policy and
+    // retention
+    pub fn remove_namespace(
+        &self,
+        tenant_id: Uuid,
+        namespace_id: Uuid,
+    ) -> std::io::Result<Vec<Partition>> {
+        let key = (tenant_id, namespace_id);
+        let removed = self
+            .partitions
+            .remove(&key)
+            .map(|(_, partitions)| partitions.to_vec())
+            .unwrap_or_default();
+        self.quotas.remove(&key);
+        self.key_policies.remove(&key);
+        self.retentions.remove(&key);
+        for partition in &removed {
+            self.leaders.remove(&partition.id);
+        }
+        info!(
+            namespace_id = namespace_id.to_string(),
+            partitions = removed.len(),
+            "removed namespace partitions"
+        );
+        self.save()?;
+        Ok(removed)
+    }
+
+    // Stops routing keys to every partition of the tenant, across all its namespaces
+    pub fn remove_tenant(&self, tenant_id: Uuid) -> std::io::Result<Vec<Partition>> {
+        let mut removed = Vec::new();
+        self.partitions
+            .retain(|(partition_tenant_id, _), partitions| {
+                if *partition_tenant_id != tenant_id {
+                    return true;
+                }
+                removed.extend(partitions.iter().cloned());
+                false
+            });
+        self.quotas
+            .retain(|(quota_tenant_id, _), _| *quota_tenant_id != tenant_id);
+        self.key_policies
+            .retain(|(policy_tenant_id, _), _| *policy_tenant_id != tenant_id);
+        self.retentions
+            .retain(|(retention_tenant_id, _), _| *retention_tenant_id != tenant_id);
+        for partition in &removed {
+            self.leaders.remove(&partition.id);
+        }
+        info!(
+            tenant_id = tenant_id.to_string(),
+            partitions = removed.len(),
+            "removed tenant partitions"
+        );
+        self.save()?;
+        Ok(removed)
+    }
+
+    // Deletes a partition's rocksdb directory, the partition has to have been removed from the lookup first
+    pub fn delete_partition_files(&self, id: Uuid) -> std::io::Result<()> {
+        std::fs::remove_dir_all(PathBuf::from(&self.config_dir).join(id.to_string()))
     }
 
     fn add_partition_internal(&self, partition: Partition) {
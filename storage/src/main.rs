@@ -1,59 +1,156 @@
+mod admin;
 mod auth;
+mod backup;
+mod cdc;
+mod compaction;
+mod config;
+mod error;
+mod format;
+mod fsck;
+mod grpc_web;
+mod health;
 mod lookup;
+mod merkle;
+mod metrics;
+mod orphans;
 mod partition;
+mod provision;
+mod quota;
+mod reaper;
+mod replication;
+mod restore;
+mod retention;
+mod snapshot;
+mod tier;
+mod transact;
+mod transform;
 
-use std::error::Error;
-use std::path::Path;
-use auth::AuthInterceptor;
-use common::auth::{Identity, JwtValidator, RsaJwtValidator};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use admin::{Maintenance, NodeAdminService};
+use auth::{authorized, AdminInterceptor, AuthInterceptor, SourceIdentity};
+use backup::Backups;
+use cdc::ChangePublisher;
+use compaction::CompactionScheduler;
+use common::auth::{Identity, KeyJwtValidator, Scope};
+use common::healthcheck::HealthChecks;
 use common::read_file_bytes;
+use common::trace_context;
+use config::{AdminAuth, StorageConfig};
 use common::storage::{
-    storage_server::Storage, storage_server::StorageServer, CreateNamespaceRequest,
-    DeleteKeyRequest, DeleteNamespaceRequest, GetRequest, GetResponse, KeyMetadata,
-    ListKeysRequest, ListKeysResponse, MigrateToNewNodeRequest, PutRequest, PutResponse,
+    node_admin_server::NodeAdminServer, storage_server::Storage, storage_server::StorageServer,
+    CloneNamespaceRequest, CloneNamespaceResponse, CopyKeyRequest, CopyKeyResponse,
+    Consistency, CountKeysRequest, CountKeysResponse, CreateNamespaceRequest, DeleteKeyRequest,
+    DeleteNamespaceRequest, DeleteRangeRequest, DeleteRangeResponse, GetRequest, GetResponse,
+    HostedNamespace, KeyMetadata, ListKeysRequest, ListKeysResponse, ListNamespacesRequest,
+    ListNamespacesResponse, ListVersionsRequest, ListVersionsResponse, MigrateToNewNodeRequest,
+    NamespaceQuota, NamespaceStatsRequest, NamespaceStatsResponse,
+    KeyPolicy as NamespaceKeyPolicy, NamespaceRetention, PartitionStats, PutRequest, PutResponse,
+    RetainedVersion, SampleKeysRequest, SampleKeysResponse, ScanRecord, ScanRequest,
+    SetNamespaceKeyPolicyRequest, SetNamespaceQuotaRequest, SetNamespaceRetentionRequest,
+    SetNamespaceTransformsRequest, TouchRequest, TransactWriteRequest, TransactWriteResponse,
 };
+use common::key_policy::KeyPolicy;
 use crc32fast::Hasher;
+use health::{DiskCheck, PartitionCheck};
 use lookup::PartitionLookup;
+use metrics::GrpcMetrics;
+use orphans::Orphans;
 use partition::ListOptions;
-use partition::{Key, PutValue, Error as PError};
+use error::Error;
+use partition::{BackgroundLimits, Key, Partition, PutValue, ScanValue};
+use provision::{ProvisionSettings, MAX_PARTITIONS};
+use quota::Quota;
+use replication::{AntiEntropy, Followers, LogFollower};
+use retention::Retention;
 use prost_types::Timestamp;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
 use rayon::prelude::*;
-use std::time::SystemTime;
+use snapshot::ListSnapshots;
+use tier::Tiering;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tonic::service::Interceptor;
-use tonic::{transport::Server, Code, Request, Response, Status};
-use tracing::{error, info, warn, Level};
+use tower::util::option_layer;
+use transact::{IntentLog, TransactOp, MAX_TRANSACT_OPS};
+use transform::{TransformLimits, Transforms};
+use tonic::{transport::Server, Request, Response, Status};
+use tracing::{error, info, warn};
 use tracing_attributes::instrument;
 use uuid::Uuid;
+use futures::channel::mpsc;
+use futures::executor::block_on;
 use futures::future::join_all;
 use futures::{FutureExt, TryFutureExt};
-use tracing_subscriber::fmt::format::FmtSpan;
+use futures::{SinkExt, Stream};
+use std::pin::Pin;
+
+// page size of key listings when the caller doesn't ask for one, and the most it can ask for
+const DEFAULT_LIST_LIMIT: u32 = 50;
+const MAX_LIST_LIMIT: u32 = 1000;
+// a full page of values this size still fits in a grpc message
+const DEFAULT_LIST_VALUE_SIZE: u32 = 1024;
+const MAX_LIST_VALUE_SIZE: u32 = 2048;
+// how many records a scan reads ahead of the client
+const SCAN_BUFFER: usize = 64;
+
+type ScanRecords = Pin<Box<dyn Stream<Item = Result<ScanRecord, Status>> + Send>>;
+const DEFAULT_SAMPLE_COUNT: u32 = 10;
+const MAX_SAMPLE_COUNT: u32 = 1000;
+
+// The directory given with a flag that runs the node as an offline tool instead of serving
+fn offline_mode(flag: &str) -> Option<PathBuf> {
+    let mut args = std::env::args().skip_while(|arg| arg != flag).skip(1);
+    args.next().map(PathBuf::from)
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    if cfg!(debug_assertions) {
-    tracing_subscriber::fmt()
-        .with_max_level(Level::INFO)
-        .with_target(true)
-        .with_span_events(FmtSpan::CLOSE)
-        .with_thread_names(true)
-        .with_file(true)
-        .init();
-        } else {
-        tracing_subscriber::fmt()
-            .json()
-            .with_max_level(Level::INFO)
-            .with_target(true)
-            .with_span_events(FmtSpan::CLOSE)
-            .with_thread_names(true)
-            .with_file(true)
-            .init();
+    // `storage --fsck <partition-dir>` checks a partition that's out of service and exits, with a
+    // failure status if there were issues
+    if let Some(partition_dir) = offline_mode("--fsck") {
+        let report = fsck::check(&partition_dir)?;
+        print!("{}", report);
+        std::process::exit(if report.is_clean() { 0 } else { 1 });
+    }
+    // `storage --migrate <partition-dir>` rewrites a partition in an older on disk format to the
+    // current one, `--migrate-rollback` restores the checkpoint taken before it did
+    if let Some(partition_dir) = offline_mode("--migrate") {
+        return Ok(format::migrate(&partition_dir)?);
+    }
+    if let Some(partition_dir) = offline_mode("--migrate-rollback") {
+        return Ok(format::rollback(&partition_dir)?);
+    }
+
+    // `storage --verify-backup <backup-dir> [--against <data-dir>]` checks a backup's files and key
+    // counts against its manifest, and with the node's data directory reports what restoring each
+    // partition would change. Nothing is written to either.
+    if let Some(backup_dir) = offline_mode("--verify-backup") {
+        let report = restore::verify(&backup_dir, offline_mode("--against").as_deref())?;
+        print!("{}", report);
+        std::process::exit(if report.is_clean() { 0 } else { 1 });
     }
 
-    let addr = "[::1]:50051".parse()?;
+    let log_level = common::logging::init("storage");
+    common::panics::install_hook("storage");
+
+    // settings come from defaults, the file given with --config or STORAGE_CONFIG, STORAGE_*
+    // environment variables, and --flags, in that order
+    let config =
+        common::config::Config::load("STORAGE").and_then(|config| StorageConfig::load(&config))?;
+    log_level.set(config.log_level)?;
+    // the log level can be changed without a restart by editing the config and sending a SIGHUP
+    tokio::spawn(config.clone().watch(log_level.clone()));
 
-    let private_key = read_file_bytes("key.pub")?;
+    let key = match &config.jwt_secret {
+        Some(secret) => secret.clone().into_bytes(),
+        None => read_file_bytes(&config.jwt_public_key)?,
+    };
 
-    let validator = RsaJwtValidator::new(private_key.as_slice())?;
+    let validator = KeyJwtValidator::new(config.jwt_algorithm, key.as_slice())?
+        .with_clock_skew(config.clock_skew)
+        .with_issuer(config.jwt_issuer.clone())
+        .with_audience(config.jwt_audience.clone());
 
     let interceptor = AuthInterceptor::new(validator);
 
@@ -74,44 +171,460 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     )?;
      */
 
-    let server = NodeStorageServer::new(Path::new("namespaces"))?;
+    // the thread pools are sized before the partitions open, they're shared by all of them
+    config.background.size_thread_pools()?;
+    let tiering = match &config.tiering {
+        Some(settings) => Some(Arc::new(Tiering::new(
+            settings.clone(),
+            &config.backup_upload,
+        )?)),
+        None => None,
+    };
+    let server = NodeStorageServer::new(
+        Path::new(&config.data_dir),
+        config.transform_limits,
+        config.background,
+        config.cdc.is_some(),
+        config.replication.is_some(),
+        tiering.clone(),
+        config.provisioning,
+    )?;
     //server.partition_lookup.add_partition(partition)?;
     //server.partition_lookup.add_partition(partition2)?;
 
-    Server::builder()
+    // a panic may have left a partition half written, so once partitions are open the node flushes
+    // what it can and stops rather than keep serving
+    let partition_lookup = server.partition_lookup.clone();
+    common::panics::install_fatal_hook("storage", move || {
+        for partition in partition_lookup.all_partitions() {
+            if let Err(err) = partition.flush() {
+                error!(
+                    err = err.to_string(),
+                    partition_id = partition.id.to_string(),
+                    "failed to flush partition"
+                );
+            }
+        }
+    });
+
+    let backups =
+        Backups::new(&config.backup_dir).with_upload_options(config.backup_upload.clone());
+    let backups = match &config.backup_upload_url {
+        Some(url) => backups.with_destination(url)?,
+        None => backups,
+    };
+
+    let compactions = Arc::new(CompactionScheduler::new(
+        config.compaction_windows.clone(),
+        config.compaction_min_pending,
+        server.partition_lookup.clone(),
+    ));
+    tokio::spawn(compactions.clone().run());
+    if let Some(tiering) = tiering {
+        tokio::spawn(tiering.run(server.partition_lookup.clone()));
+    }
+    if let Some(cdc) = &config.cdc {
+        let publisher = ChangePublisher::new(cdc.clone());
+        tokio::spawn(publisher.run(server.partition_lookup.clone()));
+    }
+    tokio::spawn(retention::purge(server.partition_lookup.clone()));
+    if let Some(reaper) = config.reaper {
+        tokio::spawn(reaper::run(reaper, server.partition_lookup.clone()));
+    }
+    let orphans = Arc::new(Orphans::new(&config.data_dir, config.orphans));
+    tokio::spawn(orphans::run(orphans.clone(), server.partition_lookup.clone()));
+    // partitions only follow leaders on nodes with a replication token
+    let mut followers = None;
+    if let Some(replication) = &config.replication {
+        replication::clear_checkpoints(Path::new(&config.data_dir))?;
+        tokio::spawn(replication::trim(
+            replication.log_entries,
+            server.partition_lookup.clone(),
+        ));
+        if let Some(token) = &replication.token {
+            let statuses = Arc::new(Followers::default());
+            let follower = LogFollower::new(token, statuses.clone())?;
+            tokio::spawn(follower.run(server.partition_lookup.clone()));
+            if let Some(interval) = replication.anti_entropy_interval {
+                let anti_entropy = AntiEntropy::new(token, statuses.clone(), interval)?;
+                tokio::spawn(anti_entropy.run(server.partition_lookup.clone()));
+            }
+            followers = Some(statuses);
+        }
+    }
+
+    // the http health endpoint also serves /metrics
+    let health_checks = HealthChecks::default()
+        .with_readiness("partitions", PartitionCheck(server.partition_lookup.clone()))
+        .with_readiness("disk", DiskCheck::new(&config.data_dir, config.min_free_disk));
+    // served on the address the storage rpcs listen on
+    common::healthcheck::spawn_healthcheck_endpoint(
+        config.listen_address.ip().to_string(),
+        config.health_port,
+        health_checks,
+    )?;
+
+    // rpcs on both listeners are recorded in the same metrics, served on the health port
+    let grpc_metrics = GrpcMetrics::default();
+
+    // the health service is registered without the auth interceptor so the gateway can probe readiness without a token
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter
+        .set_serving::<StorageServer<NodeStorageServer>>()
+        .await;
+
+    let admin = NodeAdminServer::with_interceptor(
+        NodeAdminService::new(
+            log_level,
+            common::build_info!("storage"),
+            server.partition_lookup.clone(),
+            server.transforms.clone(),
+            Maintenance {
+                backups,
+                compactions,
+                orphans,
+                followers,
+            },
+        ),
+        match config.admin_auth {
+            AdminAuth::Token => AdminInterceptor::Token(interceptor.clone()),
+            AdminAuth::Mtls => AdminInterceptor::ClientCertificate,
+        },
+    );
+    // with an admin listener the admin rpcs are only served there, not next to the storage service
+    let admin = match config.admin_listen_address {
+        Some(admin_listen_address) => {
+            let mut admin_server = Server::builder()
+                .trace_fn(trace_context::rpc_span)
+                .layer(grpc_metrics.clone());
+            if let (Some(cert), Some(key)) = (&config.admin_tls_cert, &config.admin_tls_key) {
+                admin_server = admin_server.tls_config(auth::admin_tls_config(
+                    cert,
+                    key,
+                    config.admin_tls_client_ca.as_deref(),
+                )?)?;
+            }
+            let admin_server = admin_server.add_service(admin).serve(admin_listen_address);
+            info!(
+                address = admin_listen_address.to_string(),
+                "serving admin rpcs"
+            );
+            tokio::spawn(async move {
+                if let Err(err) = admin_server.await {
+                    error!(err = err.to_string(), "admin listener failed");
+                }
+            });
+            None
+        }
+        None => Some(admin),
+    };
+
+    // browsers call over grpc-web, which needs http/1.1, only when their origins are configured
+    let grpc_web = config.grpc_web_origins.as_ref().map(grpc_web::layer);
+    if let Some(origins) = &config.grpc_web_origins {
+        info!(origins = ?origins, "serving grpc-web");
+    }
+
+    // each rpc is served in a span joining the trace of the gateway request that made it
+    let result = Server::builder()
+        .accept_http1(grpc_web.is_some())
+        .trace_fn(trace_context::rpc_span)
+        .layer(grpc_metrics)
+        .layer(option_layer(grpc_web))
+        .add_service(health_service)
+        .add_optional_service(admin)
         .add_service(StorageServer::with_interceptor(server, interceptor))
-        .serve(addr)
-        .await?;
+        .serve(config.listen_address)
+        .await;
+    common::logging::shutdown().await;
+    result?;
     Ok(())
 }
 
 #[derive(Debug)]
 struct NodeStorageServer {
-    partition_lookup: PartitionLookup,
+    partition_lookup: Arc<PartitionLookup>,
+    transforms: Arc<Transforms>,
+    intents: Arc<IntentLog>,
+    snapshots: Arc<ListSnapshots>,
+    tiering: Option<Arc<Tiering>>,
+    provisioning: ProvisionSettings,
 }
 
 impl NodeStorageServer {
-    fn new(config: impl AsRef<Path>) -> Result<NodeStorageServer, Box<dyn Error>> {
-        let partition_lookup = PartitionLookup::load(config)?; // should move this out
-        Ok(NodeStorageServer { partition_lookup })
+    fn new(
+        config: impl AsRef<Path>,
+        transform_limits: TransformLimits,
+        background: BackgroundLimits,
+        capture_changes: bool,
+        log_writes: bool,
+        tiering: Option<Arc<Tiering>>,
+        provisioning: ProvisionSettings,
+    ) -> Result<NodeStorageServer, Box<dyn std::error::Error>> {
+        // should move this out
+        let partition_lookup =
+            PartitionLookup::load(&config, background, capture_changes, log_writes)?;
+        let transforms = Transforms::load(&config, transform_limits)?;
+        // transactions a crash interrupted are finished before the node serves any request
+        let intents = IntentLog::open(&config)?;
+        let recovered = intents.recover(&partition_lookup)?;
+        if recovered > 0 {
+            info!(transactions = recovered, "replayed interrupted transactions");
+        }
+        Ok(NodeStorageServer {
+            partition_lookup: Arc::new(partition_lookup),
+            transforms: Arc::new(transforms),
+            intents: Arc::new(intents),
+            snapshots: Arc::default(),
+            tiering,
+            provisioning,
+        })
+    }
+
+    // Like quotas, transforms are set by tokens with the admin scope for the namespace
+    fn set_transforms(
+        &self,
+        identity: &Identity,
+        request: &SetNamespaceTransformsRequest,
+    ) -> Result<(), Error> {
+        let namespace_id = Uuid::parse_str(&request.namespace_id).map_err(|err| {
+            error!(err = err.to_string(), "failed to parse uuid");
+            Error::InvalidNamespace(err)
+        })?;
+
+        if !authorized(identity, Scope::Admin, namespace_id) {
+            return Err(Error::PermissionDenied);
+        }
+
+        self.transforms
+            .set(
+                identity.tenant_id(),
+                namespace_id,
+                request.read_module.as_deref(),
+                request.write_module.as_deref(),
+            )
+            .inspect_err(|err| error!(err = err.to_string(), "failed to set namespace transforms"))
+    }
+
+    // Quotas are set by tokens with the admin scope for the namespace, a missing quota is unlimited
+    fn set_quota(
+        &self,
+        identity: &Identity,
+        namespace_id: &str,
+        quota: Option<&NamespaceQuota>,
+    ) -> Result<(), Error> {
+        let namespace_id = Uuid::parse_str(namespace_id).map_err(|err| {
+            error!(err = err.to_string(), "failed to parse uuid");
+            Error::InvalidNamespace(err)
+        })?;
+
+        if !authorized(identity, Scope::Admin, namespace_id) {
+            return Err(Error::PermissionDenied);
+        }
+
+        let quota = quota.map(Quota::from).unwrap_or_default();
+        self.partition_lookup
+            .set_quota(identity.tenant_id(), namespace_id, quota)
+            .inspect_err(|err| error!(err = err.to_string(), "failed to save namespace quota"))?;
+        Ok(())
+    }
+
+    // Like quotas, key policies are set by tokens with the admin scope for the namespace, a missing
+    // policy allows every key
+    fn set_key_policy(
+        &self,
+        identity: &Identity,
+        namespace_id: &str,
+        policy: Option<&NamespaceKeyPolicy>,
+    ) -> Result<(), Error> {
+        let namespace_id = Uuid::parse_str(namespace_id).map_err(|err| {
+            error!(err = err.to_string(), "failed to parse uuid");
+            Error::InvalidNamespace(err)
+        })?;
+
+        if !authorized(identity, Scope::Admin, namespace_id) {
+            return Err(Error::PermissionDenied);
+        }
+
+        let policy = match policy {
+            Some(policy) => KeyPolicy::try_from(policy).map_err(|charset| {
+                Error::InvalidKeyPolicy(format!("unknown charset {}", charset))
+            })?,
+            None => KeyPolicy::default(),
+        };
+        if !policy.is_valid() {
+            return Err(Error::InvalidKeyPolicy(
+                "max_length must not be 0 and reserved prefixes must not be empty".to_string(),
+            ));
+        }
+        self.partition_lookup
+            .set_key_policy(identity.tenant_id(), namespace_id, policy)
+            .inspect_err(|err| error!(err = err.to_string(), "failed to save key policy"))?;
+        Ok(())
+    }
+
+    // Like quotas, retention is set by tokens with the admin scope for the namespace, a missing
+    // retention keeps nothing
+    fn set_retention(
+        &self,
+        identity: &Identity,
+        namespace_id: &str,
+        retention: Option<&NamespaceRetention>,
+    ) -> Result<(), Error> {
+        let namespace_id = Uuid::parse_str(namespace_id).map_err(|err| {
+            error!(err = err.to_string(), "failed to parse uuid");
+            Error::InvalidNamespace(err)
+        })?;
+
+        if !authorized(identity, Scope::Admin, namespace_id) {
+            return Err(Error::PermissionDenied);
+        }
+
+        let retention = retention.map(Retention::from).unwrap_or_default();
+        self.partition_lookup
+            .set_retention(identity.tenant_id(), namespace_id, retention)
+            .inspect_err(|err| error!(err = err.to_string(), "failed to save retention"))?;
+        Ok(())
+    }
+
+    // Only a put that adds a key counts against max keys, so the key is looked up when the
+    // namespace is at its limit
+    fn check_quota(
+        &self,
+        quota: &Quota,
+        tenant_id: Uuid,
+        namespace_id: Uuid,
+        partition: &Partition,
+        key: &Key,
+        value_len: usize,
+    ) -> Result<(), Error> {
+        let usage = self
+            .partition_lookup
+            .usage(tenant_id, namespace_id)
+            .inspect_err(|err| error!(err = err.to_string(), "failed to get namespace usage"))?;
+        let at_key_limit = quota
+            .max_keys
+            .is_some_and(|max_keys| usage.key_count >= max_keys);
+        let new_key = at_key_limit
+            && !partition
+                .exists(key.clone())
+                .inspect_err(|err| error!(err = err.to_string(), "failed to look up key"))?;
+        quota
+            .check(&usage, new_key, value_len as u64)
+            .inspect_err(|err| warn!(err = err.to_string(), "namespace quota exceeded"))
     }
 }
 
 #[tonic::async_trait]
 impl Storage for NodeStorageServer {
-    #[instrument]
+    // Namespaces exist on a node once partitions are added for them, creating one records its quota
+    // and key policy
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
     async fn create_namespace(
         &self,
         request: Request<CreateNamespaceRequest>,
     ) -> Result<Response<()>, Status> {
-        todo!()
+        let identity = request.extensions().get::<Identity>().unwrap();
+        let request = request.get_ref();
+        self.set_quota(identity, &request.namespace_id, request.quota.as_ref())?;
+        self.set_key_policy(identity, &request.namespace_id, request.key_policy.as_ref())?;
+
+        // every node the namespace is created on holds all of its partitions, since the gateway
+        // sends a key's requests to any node
+        let namespace_id = Uuid::parse_str(&request.namespace_id).map_err(|err| {
+            error!(err = err.to_string(), "failed to parse uuid");
+            Error::InvalidNamespace(err)
+        })?;
+        let count = match request.partitions {
+            Some(partitions) => (partitions as u64).min(MAX_PARTITIONS),
+            None => self.provisioning.partitions(request.size_hint_bytes),
+        };
+        let tenant_id = identity.tenant_id();
+        let partition_lookup = self.partition_lookup.clone();
+        tokio::task::spawn_blocking(move || {
+            partition_lookup.provision_namespace(tenant_id, namespace_id, count)
+        })
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "provisioning namespace failed");
+            Status::internal("internal error")
+        })?
+        .inspect_err(|err| error!(err = err.to_string(), "failed to provision namespace"))?;
+        Ok(Response::new(()))
     }
 
+    // The partitions' keys are counted exactly before they're dropped, a namespace that still has
+    // keys is left as it is
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
     async fn delete_namespace(
         &self,
         request: Request<DeleteNamespaceRequest>,
     ) -> Result<Response<()>, Status> {
-        todo!()
+        let identity = request.extensions().get::<Identity>().unwrap();
+        let request = request.get_ref();
+
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            "got request to delete namespace"
+        );
+
+        let namespace_id = Uuid::parse_str(&request.namespace_id).map_err(|err| {
+            error!(err = err.to_string(), "failed to parse uuid");
+            Error::InvalidNamespace(err)
+        })?;
+
+        if !authorized(identity, Scope::Admin, namespace_id) {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        let tenant_id = identity.tenant_id();
+        let partition_lookup = self.partition_lookup.clone();
+        tokio::task::spawn_blocking(move || {
+            let keys = partition_lookup
+                .partitions(tenant_id, namespace_id)
+                .map(|partitions| {
+                    partitions
+                        .iter()
+                        .map(|partition| partition.count_keys(&[]))
+                        .sum::<Result<u64, Error>>()
+                })
+                .transpose()?
+                .unwrap_or_default();
+            if keys > 0 {
+                return Err(Error::NamespaceNotEmpty(keys));
+            }
+            let partitions = partition_lookup.remove_namespace(tenant_id, namespace_id)?;
+            // the partitions are no longer routed to, so a file that can't be deleted is only
+            // wasted disk
+            for partition in partitions {
+                let id = partition.id;
+                // release this handle on the database before its files go away
+                drop(partition);
+                if let Err(err) = partition_lookup.delete_partition_files(id) {
+                    warn!(
+                        partition_id = id.to_string(),
+                        err = err.to_string(),
+                        "failed to delete partition files, they're left on disk"
+                    );
+                }
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "deleting namespace failed");
+            Status::internal("internal error")
+        })?
+        .inspect_err(|err| error!(err = err.to_string(), "failed to delete namespace"))?;
+
+        if let Err(err) = self.transforms.set(tenant_id, namespace_id, None, None) {
+            warn!(
+                err = err.to_string(),
+                "failed to delete the namespace's transforms"
+            );
+        }
+
+        Ok(Response::new(()))
     }
 
     #[instrument(skip(request) fields(namespace_id = %request.get_ref().namespace_id))]
@@ -129,10 +642,14 @@ impl Storage for NodeStorageServer {
             Ok(id) => id,
             Err(err) => {
                 error!(err = err.to_string(), "failed to parse uuid");
-                return Err(Status::new(Code::InvalidArgument, "invalid uuid"));
+                return Err(Error::InvalidNamespace(err).into());
             }
         };
 
+        if !authorized(identity, Scope::Write, namespace_id) {
+            return Err(Error::PermissionDenied.into());
+        }
+
         let mut crc_hasher = Hasher::new();
         crc_hasher.update(request.key.as_slice());
         crc_hasher.update(request.value.as_slice());
@@ -142,7 +659,11 @@ impl Storage for NodeStorageServer {
             Some(crc) => {
                 if crc != calculated_crc {
                     error!("crc mismatch");
-                    return Err(Status::new(Code::InvalidArgument, "crc mismatch"));
+                    return Err(Error::CrcMismatch {
+                        expected: crc,
+                        computed: calculated_crc,
+                    }
+                    .into());
                 }
             }
             None => {
@@ -150,24 +671,61 @@ impl Storage for NodeStorageServer {
             }
         };
 
+        self.transforms
+            .validate(identity.tenant_id(), namespace_id, &request.value)
+            .inspect_err(|err| warn!(err = err.to_string(), "value failed validation"))?;
+
+        if let Some(violation) = self
+            .partition_lookup
+            .key_policy(identity.tenant_id(), namespace_id)
+            .and_then(|policy| policy.violation(&request.key))
+        {
+            warn!(reason = violation, "key breaks the namespace's key policy");
+            return Err(Error::InvalidKey(violation).into());
+        }
+
         let key: Key = (&request.key).into();
 
         let partition = self
             .partition_lookup
             .get_partition_for_key(identity.tenant_id(), namespace_id, &key)
-            .ok_or(Status::new(Code::NotFound, "partition not found"))?;
-
-        match partition.put(
-            key,
-            &PutValue {
-                crc: calculated_crc,
-                version: 1, // todo calculate the version given the current version
-                value: request.value.as_slice(),
-            },
-        ) {
+            .ok_or(Error::PartitionNotFound)?;
+
+        if let Some(quota) = self
+            .partition_lookup
+            .quota(identity.tenant_id(), namespace_id)
+        {
+            self.check_quota(
+                &quota,
+                identity.tenant_id(),
+                namespace_id,
+                &partition,
+                &key,
+                request.value.len(),
+            )?;
+        }
+
+        let expires_at = expires_at(request.ttl_secs);
+
+        let value = PutValue {
+            crc: calculated_crc,
+            value: request.value.as_slice(),
+            expires_at,
+        };
+        let _writes = self.partition_lookup.write_permit();
+        let result = if request.if_absent {
+            partition.put_if_absent(key, &value)
+        } else {
+            partition.put(key, &value, request.expected_version)
+        };
+        match result {
+            Err(err @ (Error::VersionConflict { .. } | Error::KeyExists { .. })) => {
+                warn!(err = err.to_string(), "version conflict");
+                Err(err.into())
+            }
             Err(err) => {
-                error!("failed to put value");
-                Err(Status::new(Code::Internal, "internal error"))
+                error!(err = err.to_string(), "failed to put value");
+                Err(err.into())
             }
             Ok(metadata) => Ok(Response::new(PutResponse {
                 version: metadata.version,
@@ -192,32 +750,61 @@ impl Storage for NodeStorageServer {
             Ok(id) => id,
             Err(err) => {
                 error!(err = err.to_string(), "failed to parse uuid");
-                return Err(Status::new(Code::InvalidArgument, "invalid uuid"));
+                return Err(Error::InvalidNamespace(err).into());
             }
         };
 
+        if !authorized(identity, Scope::Read, namespace_id) {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        // the gateway routed the read by its consistency, the node only checks it's one it knows
+        if Consistency::try_from(request.consistency).is_err() {
+            return Err(Error::InvalidConsistency(request.consistency).into());
+        }
+
         let key: Key = (&request.key).into();
 
         let partition = self
             .partition_lookup
             .get_partition_for_key(identity.tenant_id(), namespace_id, &key)
-            .ok_or(Status::new(Code::NotFound, "partition not found"))?;
-
-        match partition.get(&key) {
-            Ok(value) => Ok(Response::new(GetResponse {
-                key: key.into(),
-                value: value.value,
-                metadata: Some(common::storage::Metadata {
-                    version: value.version,
-                    crc: value.crc,
-                    creation_time: Some(Timestamp::from(SystemTime::now())),
-                }),
-            })),
-            Err(err) => {
-                error!(err = err.to_string(), "failed to get value");
-                Err(Status::new(Code::NotFound, "not found"))
+            .ok_or(Error::PartitionNotFound)?;
+
+        let current = tier::get(self.tiering.as_deref(), &partition, &key).await;
+        // a version other than the current one, or one of a deleted key, can only be read from the
+        // versions the namespace retains
+        let mut value = match (request.version, current) {
+            (Some(version), Ok(value)) if value.version != version => {
+                partition.get_version(&key, version)
             }
+            (Some(version), Err(Error::NotFound)) => partition.get_version(&key, version),
+            (_, current) => current,
+        }
+        .inspect_err(|err| {
+            error!(err = err.to_string(), "failed to get value");
+        })?;
+
+        if !request.raw {
+            (value.value, value.crc) = read_value(
+                &self.transforms,
+                identity.tenant_id(),
+                namespace_id,
+                &request.key,
+                value.value,
+                value.crc,
+            )?;
         }
+
+        Ok(Response::new(GetResponse {
+            key: key.into(),
+            value: value.value,
+            metadata: Some(common::storage::Metadata {
+                version: value.version,
+                crc: value.crc,
+                creation_time: Some(Timestamp::from(SystemTime::now())),
+                expires_at: value.expiry.map(Timestamp::from),
+            }),
+        }))
     }
 
     async fn get_metadata(
@@ -241,55 +828,1039 @@ impl Storage for NodeStorageServer {
             "listing keys in namespace"
         );
 
-        let Some(partitions) = self.partition_lookup.partitions(
-            identity.tenant_id(),
-            Uuid::parse_str(&request.namespace_id).unwrap(),
-        ) else {
+        let namespace_id = match Uuid::parse_str(&request.namespace_id) {
+            Ok(id) => id,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to parse uuid");
+                return Err(Error::InvalidNamespace(err).into());
+            }
+        };
+
+        if !authorized(identity, Scope::Read, namespace_id) {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        let Some(partitions) = self
+            .partition_lookup
+            .partitions(identity.tenant_id(), namespace_id)
+        else {
             return Ok(Response::new(ListKeysResponse::default())); // if there are no partitions return an empty list
         };
         // todo see if we can use rayon here, I ran into some issues with not being able to map the data in inner iterator and then return that back
 
+        // keys are listed in order starting after start_key, every partition can hold any of them so
+        // each one is asked for a full page and the pages are merged
+        let limit = request
+            .limit
+            .unwrap_or(DEFAULT_LIST_LIMIT)
+            .clamp(1, MAX_LIST_LIMIT) as usize;
+        let start_key = request.start_key.as_deref();
+        let max_value_size = request
+            .max_value_size
+            .unwrap_or(DEFAULT_LIST_VALUE_SIZE)
+            .clamp(1, MAX_LIST_VALUE_SIZE) as usize;
+
+        // every page of a listing reads from the snapshots taken for its first page
+        let (snapshot_id, snapshots) = match &request.snapshot_id {
+            Some(snapshot_id) => {
+                let snapshot_id = Uuid::parse_str(snapshot_id).map_err(|source| Error::InvalidId {
+                    field: "snapshot_id",
+                    source,
+                })?;
+                let snapshots = self
+                    .snapshots
+                    .get(snapshot_id, identity.tenant_id(), namespace_id)
+                    .ok_or(Error::SnapshotExpired)?;
+                (snapshot_id, snapshots)
+            }
+            None => self
+                .snapshots
+                .pin(identity.tenant_id(), namespace_id, &partitions),
+        };
+        let snapshots = &snapshots;
+
         let futures = partitions.iter().map(|partition| async move {
-            let result_set = partition.list_keys(ListOptions::default())?;
+            let mut opts = ListOptions::default();
+            // the start key itself is skipped, so one extra key is needed to fill the page
+            opts.with_limit(limit + 1);
+            if let Some(start_key) = start_key {
+                opts.with_start_at(start_key);
+            }
+            // a partition added since the first page is listed as it is now
+            if let Some(snapshot) = snapshots.get(&partition.id) {
+                opts.with_snapshot(snapshot);
+            }
+            if request.include_values {
+                opts.with_values(max_value_size);
+            }
+            let result_set = partition.list_keys(opts)?;
             let mut keys = Vec::new();
             for metadata in result_set.as_ref() {
                 let key_metadata = metadata.metadata.as_ref().unwrap();
+                let mut crc = key_metadata.crc;
+                // a value that's over the size once transformed is left out too
+                let value = match metadata.value.clone() {
+                    Some(value) => {
+                        let (value, value_crc) = read_value(
+                            &self.transforms,
+                            identity.tenant_id(),
+                            namespace_id,
+                            &metadata.key,
+                            value,
+                            crc,
+                        )?;
+                        (value.len() <= max_value_size).then(|| {
+                            crc = value_crc;
+                            value
+                        })
+                    }
+                    None => None,
+                };
                 keys.push(KeyMetadata {
                     key: metadata.key.clone(),
                     metadata: Some(common::storage::Metadata {
                         version: key_metadata.version,
-                        crc: key_metadata.crc,
+                        crc,
                         creation_time: Some(Timestamp::from(SystemTime::now())),
+                        expires_at: key_metadata.expires_at.clone(),
                     }),
+                    value,
                 });
             }
 
-            Ok::<Vec<KeyMetadata>, PError>(keys)
+            Ok::<Vec<KeyMetadata>, Error>(keys)
         });
 
         let mut keys = Vec::new();
 
-        for result_set in join_all(futures).await.iter() {
+        for result_set in join_all(futures).await {
             match result_set {
-                Ok(result_set) => keys.extend_from_slice(result_set.as_slice()),
+                Ok(result_set) => keys.extend(result_set),
                 Err(err) => {
-                    error!(err = format!("err: {}", err), "failed to list keys");
-                    return Err(Status::new(Code::Internal, "internal error"));
+                    error!(err = err.to_string(), "failed to list keys");
+                    return Err(err.into());
                 }
             }
         }
 
-        Ok(Response::new(ListKeysResponse { keys }))
+        if let Some(start_key) = start_key {
+            keys.retain(|item| item.key.as_slice() > start_key);
+        }
+        keys.sort_by(|a, b| a.key.cmp(&b.key));
+        keys.truncate(limit);
+
+        // a page that isn't full is the last one
+        let snapshot_id = if keys.len() < limit {
+            self.snapshots.release(snapshot_id);
+            None
+        } else {
+            Some(snapshot_id.to_string())
+        };
+
+        Ok(Response::new(ListKeysResponse { keys, snapshot_id }))
+    }
+
+    type ScanStream = ScanRecords;
+
+    // Every partition is snapshotted with writes paused, so the scan sees the namespace as it was
+    // at one point in time, transactions included. The records are read as the client takes them,
+    // and a client that goes away stops the scan.
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn scan(&self, request: Request<ScanRequest>) -> Result<Response<ScanRecords>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+
+        let request = request.get_ref();
+
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            "scanning namespace"
+        );
+
+        let namespace_id = match Uuid::parse_str(&request.namespace_id) {
+            Ok(id) => id,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to parse uuid");
+                return Err(Error::InvalidNamespace(err).into());
+            }
+        };
+
+        if !authorized(identity, Scope::Read, namespace_id) {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        let tenant_id = identity.tenant_id();
+        let Some(partitions) = self.partition_lookup.partitions(tenant_id, namespace_id) else {
+            return Ok(Response::new(Box::pin(futures::stream::empty())));
+        };
+
+        let raw = request.raw;
+        let lookup = self.partition_lookup.clone();
+        let transforms = self.transforms.clone();
+        let tiering = self.tiering.clone();
+        // tiered values are fetched from the blocking scan on the runtime's handle
+        let runtime = tokio::runtime::Handle::current();
+        let (mut sender, receiver) = mpsc::channel(SCAN_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let snapshots: Vec<_> = {
+                let _paused = lookup.pause_writes();
+                partitions.iter().map(Partition::pin_snapshot).collect()
+            };
+            for (partition, snapshot) in partitions.iter().zip(&snapshots) {
+                let result = partition.scan(snapshot, |key, metadata, value| {
+                    let value = match value {
+                        ScanValue::Local(value) => value.to_vec(),
+                        ScanValue::Tiered(object) => {
+                            let tiering = tiering.as_deref().ok_or(Error::TieringDisabled)?;
+                            runtime.block_on(tiering.fetch(key, &object, metadata.crc))?
+                        }
+                    };
+                    let (value, crc) = match raw {
+                        true => (value, metadata.crc),
+                        false => read_value(
+                            &transforms,
+                            tenant_id,
+                            namespace_id,
+                            key,
+                            value,
+                            metadata.crc,
+                        )?,
+                    };
+                    let record = ScanRecord {
+                        key: key.to_vec(),
+                        metadata: Some(common::storage::Metadata {
+                            version: metadata.version,
+                            crc,
+                            creation_time: None,
+                            expires_at: metadata.expiry().map(Timestamp::from),
+                        }),
+                        value,
+                    };
+                    Ok(block_on(sender.send(Ok(record))).is_ok())
+                });
+                if let Err(err) = result {
+                    error!(err = err.to_string(), "failed to scan partition");
+                    let _ = block_on(sender.send(Err(err.into())));
+                    return;
+                }
+                if sender.is_closed() {
+                    info!("scan stopped, the client went away");
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(receiver)))
     }
 
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
     async fn delete(&self, request: Request<DeleteKeyRequest>) -> Result<Response<()>, Status> {
-        todo!()
+        let identity = request.extensions().get::<Identity>().unwrap();
+
+        let request = request.get_ref();
+
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            "got request to delete key"
+        );
+
+        let namespace_id = match Uuid::parse_str(&request.namespace_id) {
+            Ok(id) => id,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to parse uuid");
+                return Err(Error::InvalidNamespace(err).into());
+            }
+        };
+
+        if !authorized(identity, Scope::Write, namespace_id) {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        let key: Key = (&request.key).into();
+
+        let partition = self
+            .partition_lookup
+            .get_partition_for_key(identity.tenant_id(), namespace_id, &key)
+            .ok_or(Error::PartitionNotFound)?;
+
+        let _writes = self.partition_lookup.write_permit();
+        match partition.delete(key, request.expected_version, request.forget) {
+            Ok(()) => Ok(Response::new(())),
+            Err(err @ Error::VersionConflict { .. }) => {
+                warn!(err = err.to_string(), "version conflict");
+                Err(err.into())
+            }
+            Err(err) => {
+                error!(err = err.to_string(), "failed to delete key");
+                Err(err.into())
+            }
+        }
     }
 
-    async fn migrate_to_new_node(
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn touch(
         &self,
-        request: Request<MigrateToNewNodeRequest>,
-    ) -> Result<Response<()>, Status> {
-        todo!()
+        request: Request<TouchRequest>,
+    ) -> Result<Response<common::storage::Metadata>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+
+        let request = request.get_ref();
+
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            "got request to touch key"
+        );
+
+        let namespace_id = match Uuid::parse_str(&request.namespace_id) {
+            Ok(id) => id,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to parse uuid");
+                return Err(Error::InvalidNamespace(err).into());
+            }
+        };
+
+        if !authorized(identity, Scope::Write, namespace_id) {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        let key: Key = (&request.key).into();
+
+        let partition = self
+            .partition_lookup
+            .get_partition_for_key(identity.tenant_id(), namespace_id, &key)
+            .ok_or(Error::PartitionNotFound)?;
+
+        let _writes = self.partition_lookup.write_permit();
+        match partition.touch(key, expires_at(request.ttl_secs)) {
+            Ok(metadata) => Ok(Response::new(common::storage::Metadata {
+                creation_time: None,
+                version: metadata.version,
+                crc: metadata.crc,
+                expires_at: metadata.expiry().map(Timestamp::from),
+            })),
+            Err(err @ Error::NotFound) => Err(err.into()),
+            Err(err) => {
+                error!(err = err.to_string(), "failed to touch key");
+                Err(err.into())
+            }
+        }
     }
+
+    // Every put is checked like a single put would be before any op is applied
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn transact_write(
+        &self,
+        request: Request<TransactWriteRequest>,
+    ) -> Result<Response<TransactWriteResponse>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+
+        let request = request.get_ref();
+
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            ops = request.ops.len(),
+            "got request to transact writes"
+        );
+
+        let namespace_id = match Uuid::parse_str(&request.namespace_id) {
+            Ok(id) => id,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to parse uuid");
+                return Err(Error::InvalidNamespace(err).into());
+            }
+        };
+
+        if !authorized(identity, Scope::Write, namespace_id) {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        if request.ops.is_empty() || request.ops.len() > MAX_TRANSACT_OPS {
+            return Err(Error::InvalidTransaction(format!(
+                "a transaction has between 1 and {} ops",
+                MAX_TRANSACT_OPS
+            ))
+            .into());
+        }
+
+        let key_policy = self
+            .partition_lookup
+            .key_policy(identity.tenant_id(), namespace_id);
+        let quota = self
+            .partition_lookup
+            .quota(identity.tenant_id(), namespace_id);
+
+        let mut ops = Vec::with_capacity(request.ops.len());
+        for op in &request.ops {
+            let key: Key = (&op.key).into();
+            let Some(value) = &op.value else {
+                ops.push(TransactOp {
+                    key,
+                    value: None,
+                    expected_version: op.expected_version,
+                });
+                continue;
+            };
+
+            let mut crc_hasher = Hasher::new();
+            crc_hasher.update(op.key.as_slice());
+            crc_hasher.update(value.as_slice());
+            let calculated_crc = crc_hasher.finalize();
+            if let Some(crc) = op.crc.filter(|crc| *crc != calculated_crc) {
+                error!("crc mismatch");
+                return Err(Error::CrcMismatch {
+                    expected: crc,
+                    computed: calculated_crc,
+                }
+                .into());
+            }
+
+            self.transforms
+                .validate(identity.tenant_id(), namespace_id, value)
+                .inspect_err(|err| warn!(err = err.to_string(), "value failed validation"))?;
+
+            if let Some(violation) = key_policy
+                .as_ref()
+                .and_then(|policy| policy.violation(&op.key))
+            {
+                warn!(reason = violation, "key breaks the namespace's key policy");
+                return Err(Error::InvalidKey(violation).into());
+            }
+
+            if let Some(quota) = &quota {
+                let partition = self
+                    .partition_lookup
+                    .get_partition_for_key(identity.tenant_id(), namespace_id, &key)
+                    .ok_or(Error::PartitionNotFound)?;
+                self.check_quota(
+                    quota,
+                    identity.tenant_id(),
+                    namespace_id,
+                    &partition,
+                    &key,
+                    value.len(),
+                )?;
+            }
+
+            ops.push(TransactOp {
+                key,
+                value: Some(PutValue {
+                    crc: calculated_crc,
+                    value: value.as_slice(),
+                    expires_at: expires_at(op.ttl_secs),
+                }),
+                expected_version: op.expected_version,
+            });
+        }
+
+        let _writes = self.partition_lookup.write_permit();
+        match transact::transact(
+            &self.partition_lookup,
+            &self.intents,
+            identity.tenant_id(),
+            namespace_id,
+            &ops,
+        ) {
+            Ok(versions) => Ok(Response::new(TransactWriteResponse { versions })),
+            Err(err @ (Error::VersionConflict { .. } | Error::InvalidTransaction(_))) => {
+                warn!(err = err.to_string(), "transaction rejected");
+                Err(err.into())
+            }
+            Err(err) => {
+                error!(err = err.to_string(), "failed to transact writes");
+                Err(err.into())
+            }
+        }
+    }
+
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn delete_range(
+        &self,
+        request: Request<DeleteRangeRequest>,
+    ) -> Result<Response<DeleteRangeResponse>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+
+        let request = request.get_ref();
+
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            dry_run = request.dry_run,
+            "deleting keys by prefix"
+        );
+
+        let namespace_id = match Uuid::parse_str(&request.namespace_id) {
+            Ok(id) => id,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to parse uuid");
+                return Err(Error::InvalidNamespace(err).into());
+            }
+        };
+
+        if !authorized(identity, Scope::Write, namespace_id) {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        let Some(partitions) = self
+            .partition_lookup
+            .partitions(identity.tenant_id(), namespace_id)
+        else {
+            return Ok(Response::new(DeleteRangeResponse::default())); // nothing to delete if there are no partitions
+        };
+
+        // every partition can hold keys with the prefix since keys are routed by hash, and a
+        // partition's keys are scanned and deleted off the async workers
+        let partition_lookup = self.partition_lookup.clone();
+        let prefix = request.prefix.clone();
+        let dry_run = request.dry_run;
+        let deleted = tokio::task::spawn_blocking(move || {
+            let _writes = partition_lookup.write_permit();
+            partitions
+                .iter()
+                .map(|partition| partition.delete_prefix(&prefix, dry_run))
+                .sum::<Result<u64, Error>>()
+        })
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "deleting keys by prefix failed");
+            Status::internal("internal error")
+        })?
+        .inspect_err(|err| error!(err = err.to_string(), "failed to delete keys by prefix"))?;
+
+        Ok(Response::new(DeleteRangeResponse { deleted }))
+    }
+
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn namespace_stats(
+        &self,
+        request: Request<NamespaceStatsRequest>,
+    ) -> Result<Response<NamespaceStatsResponse>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+
+        let request = request.get_ref();
+
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            "getting namespace stats"
+        );
+
+        let namespace_id = match Uuid::parse_str(&request.namespace_id) {
+            Ok(id) => id,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to parse uuid");
+                return Err(Error::InvalidNamespace(err).into());
+            }
+        };
+
+        if !authorized(identity, Scope::Read, namespace_id) {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        let Some(partitions) = self
+            .partition_lookup
+            .partitions(identity.tenant_id(), namespace_id)
+        else {
+            return Ok(Response::new(NamespaceStatsResponse::default()));
+        };
+
+        let mut response = NamespaceStatsResponse::default();
+        for partition in partitions.iter() {
+            let stats = partition.stats().map_err(|err| {
+                error!(err = err.to_string(), "failed to get partition stats");
+                Status::from(err)
+            })?;
+
+            response.key_count += stats.key_count;
+            response.total_bytes += stats.total_bytes;
+            response.partitions.push(PartitionStats {
+                partition_id: partition.id.to_string(),
+                key_count: stats.key_count,
+                total_bytes: stats.total_bytes,
+            });
+        }
+
+        Ok(Response::new(response))
+    }
+
+    // Approximate counts add up rocksdb's estimates, which count expired keys and can be off by
+    // overwrites and deletes not yet compacted away
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn count_keys(
+        &self,
+        request: Request<CountKeysRequest>,
+    ) -> Result<Response<CountKeysResponse>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+
+        let request = request.get_ref();
+
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            exact = request.exact,
+            "counting keys in namespace"
+        );
+
+        let namespace_id = match Uuid::parse_str(&request.namespace_id) {
+            Ok(id) => id,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to parse uuid");
+                return Err(Error::InvalidNamespace(err).into());
+            }
+        };
+
+        if !authorized(identity, Scope::Read, namespace_id) {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        let prefix = request.prefix.as_deref().unwrap_or_default();
+        let exact = request.exact || !prefix.is_empty();
+
+        let Some(partitions) = self
+            .partition_lookup
+            .partitions(identity.tenant_id(), namespace_id)
+        else {
+            return Ok(Response::new(CountKeysResponse { count: 0, exact }));
+        };
+
+        // an exact count reads every key, it runs off the async workers
+        let prefix = prefix.to_vec();
+        let counted = tokio::task::spawn_blocking(move || {
+            partitions
+                .par_iter()
+                .map(|partition| match exact {
+                    true => partition.count_keys(&prefix),
+                    false => partition.stats().map(|stats| stats.key_count),
+                })
+                .sum::<Result<u64, Error>>()
+        })
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "counting keys failed");
+            Status::internal("internal error")
+        })?;
+        let count = counted.map_err(|err| {
+            error!(err = err.to_string(), "failed to count keys");
+            Status::from(err)
+        })?;
+
+        Ok(Response::new(CountKeysResponse { count, exact }))
+    }
+
+    // The sample is spread over the partitions by their estimated key counts, so a key in a small
+    // partition is about as likely to be picked as one in a large partition
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn sample_keys(
+        &self,
+        request: Request<SampleKeysRequest>,
+    ) -> Result<Response<SampleKeysResponse>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+
+        let request = request.get_ref();
+
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            "sampling keys in namespace"
+        );
+
+        let namespace_id = match Uuid::parse_str(&request.namespace_id) {
+            Ok(id) => id,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to parse uuid");
+                return Err(Error::InvalidNamespace(err).into());
+            }
+        };
+
+        if !authorized(identity, Scope::Read, namespace_id) {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        let count = request
+            .count
+            .unwrap_or(DEFAULT_SAMPLE_COUNT)
+            .clamp(1, MAX_SAMPLE_COUNT) as usize;
+
+        let Some(partitions) = self
+            .partition_lookup
+            .partitions(identity.tenant_id(), namespace_id)
+        else {
+            return Ok(Response::new(SampleKeysResponse::default()));
+        };
+
+        let sampled = tokio::task::spawn_blocking(move || {
+            let mut weights = Vec::with_capacity(partitions.len());
+            for partition in partitions.iter() {
+                weights.push(partition.stats()?.key_count);
+            }
+            let mut rng = rand::thread_rng();
+            let mut allotted = vec![0; partitions.len()];
+            match WeightedIndex::new(&weights) {
+                Ok(index) => {
+                    for _ in 0..count {
+                        allotted[index.sample(&mut rng)] += 1;
+                    }
+                }
+                // every estimate is 0, the partitions may just not have been flushed yet
+                Err(_) => allotted.iter_mut().for_each(|allotted| *allotted = count),
+            }
+            let mut keys = Vec::with_capacity(count);
+            for (partition, allotted) in partitions.iter().zip(allotted) {
+                if allotted > 0 {
+                    keys.extend(partition.sample_keys(allotted)?);
+                }
+            }
+            // more than count are only sampled when every partition was asked for count keys
+            if keys.len() > count {
+                keys.shuffle(&mut rng);
+                keys.truncate(count);
+            }
+            Ok::<Vec<KeyMetadata>, Error>(keys)
+        })
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "sampling keys failed");
+            Status::internal("internal error")
+        })?;
+
+        match sampled {
+            Ok(keys) => Ok(Response::new(SampleKeysResponse { keys })),
+            Err(err) => {
+                error!(err = err.to_string(), "failed to sample keys");
+                Err(err.into())
+            }
+        }
+    }
+
+    async fn list_namespaces(
+        &self,
+        request: Request<ListNamespacesRequest>,
+    ) -> Result<Response<ListNamespacesResponse>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            "listing hosted namespaces"
+        );
+
+        if !identity.has_scope(Scope::Read) {
+            error!("token does not allow listing namespaces");
+            return Err(Error::PermissionDenied.into());
+        }
+
+        let namespaces = self
+            .partition_lookup
+            .namespaces(identity.tenant_id())
+            .into_iter()
+            .filter(|(namespace_id, _)| identity.allows(Scope::Read, *namespace_id))
+            .map(|(namespace_id, partitions)| HostedNamespace {
+                namespace_id: namespace_id.to_string(),
+                partitions: partitions as u32,
+            })
+            .collect();
+
+        Ok(Response::new(ListNamespacesResponse { namespaces }))
+    }
+
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn set_namespace_quota(
+        &self,
+        request: Request<SetNamespaceQuotaRequest>,
+    ) -> Result<Response<()>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+        let request = request.get_ref();
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            "setting namespace quota"
+        );
+        self.set_quota(identity, &request.namespace_id, request.quota.as_ref())?;
+        Ok(Response::new(()))
+    }
+
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn set_namespace_transforms(
+        &self,
+        request: Request<SetNamespaceTransformsRequest>,
+    ) -> Result<Response<()>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            "setting namespace transforms"
+        );
+        self.set_transforms(identity, request.get_ref())?;
+        Ok(Response::new(()))
+    }
+
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn set_namespace_key_policy(
+        &self,
+        request: Request<SetNamespaceKeyPolicyRequest>,
+    ) -> Result<Response<()>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+        let request = request.get_ref();
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            "setting namespace key policy"
+        );
+        self.set_key_policy(identity, &request.namespace_id, request.key_policy.as_ref())?;
+        Ok(Response::new(()))
+    }
+
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn set_namespace_retention(
+        &self,
+        request: Request<SetNamespaceRetentionRequest>,
+    ) -> Result<Response<()>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+        let request = request.get_ref();
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            "setting namespace retention"
+        );
+        self.set_retention(identity, &request.namespace_id, request.retention.as_ref())?;
+        Ok(Response::new(()))
+    }
+
+    // The key's versions the namespace retains, whether it still exists or was deleted
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn list_versions(
+        &self,
+        request: Request<ListVersionsRequest>,
+    ) -> Result<Response<ListVersionsResponse>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+        let request = request.get_ref();
+
+        let namespace_id = match Uuid::parse_str(&request.namespace_id) {
+            Ok(id) => id,
+            Err(err) => {
+                error!(err = err.to_string(), "failed to parse uuid");
+                return Err(Error::InvalidNamespace(err).into());
+            }
+        };
+
+        if !authorized(identity, Scope::Read, namespace_id) {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        let key: Key = (&request.key).into();
+
+        let partition = self
+            .partition_lookup
+            .get_partition_for_key(identity.tenant_id(), namespace_id, &key)
+            .ok_or(Error::PartitionNotFound)?;
+
+        let versions = partition
+            .versions(&key)
+            .inspect_err(|err| error!(err = err.to_string(), "failed to list versions"))?
+            .into_iter()
+            .map(|retained| RetainedVersion {
+                version: retained.metadata.version,
+                retained_at: Some(Timestamp {
+                    seconds: retained.retained_at as i64,
+                    nanos: 0,
+                }),
+                deleted: retained.deleted,
+                size: retained.value.len() as u64,
+            })
+            .collect();
+
+        Ok(Response::new(ListVersionsResponse { versions }))
+    }
+
+    // Cloning reads every key of the source and writes every key of the namespace, so it takes the
+    // admin scope for both
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn clone_namespace(
+        &self,
+        request: Request<CloneNamespaceRequest>,
+    ) -> Result<Response<CloneNamespaceResponse>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+        let request = request.get_ref();
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            source_namespace_id = request.source_namespace_id,
+            "cloning namespace"
+        );
+
+        let source_namespace_id = Uuid::parse_str(&request.source_namespace_id).map_err(|err| {
+            error!(err = err.to_string(), "failed to parse uuid");
+            Error::InvalidNamespace(err)
+        })?;
+        let namespace_id = Uuid::parse_str(&request.namespace_id).map_err(|err| {
+            error!(err = err.to_string(), "failed to parse uuid");
+            Error::InvalidNamespace(err)
+        })?;
+
+        if !authorized(identity, Scope::Admin, source_namespace_id)
+            || !authorized(identity, Scope::Admin, namespace_id)
+        {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        let tenant_id = identity.tenant_id();
+        let partition_lookup = self.partition_lookup.clone();
+        let transforms = self.transforms.clone();
+        let partitions = tokio::task::spawn_blocking(move || {
+            let partitions =
+                partition_lookup.clone_namespace(tenant_id, source_namespace_id, namespace_id)?;
+            // the values were written through the source's transforms, so they're read through them
+            transforms.copy(tenant_id, source_namespace_id, namespace_id)?;
+            Ok::<_, Error>(partitions)
+        })
+        .await
+        .map_err(|err| {
+            error!(err = err.to_string(), "cloning namespace failed");
+            Status::internal("internal error")
+        })?
+        .inspect_err(|err| error!(err = err.to_string(), "failed to clone namespace"))?;
+
+        Ok(Response::new(CloneNamespaceResponse {
+            partitions: partitions.len() as u32,
+        }))
+    }
+
+    // The value is copied as it's stored, so it's read through the destination's read transform
+    // rather than the source's. Both keys' partitions have to be on this node.
+    #[instrument(skip(self, request) fields(namespace_id = %request.get_ref().namespace_id))]
+    async fn copy_key(
+        &self,
+        request: Request<CopyKeyRequest>,
+    ) -> Result<Response<CopyKeyResponse>, Status> {
+        let identity = request.extensions().get::<Identity>().unwrap();
+        let source_identity = request
+            .extensions()
+            .get::<SourceIdentity>()
+            .map_or(identity, |source| &source.0);
+        let request = request.get_ref();
+        info!(
+            uuid = identity.tenant_id().to_string(),
+            source_uuid = source_identity.tenant_id().to_string(),
+            source_namespace_id = request.source_namespace_id,
+            "copying key"
+        );
+
+        let source_namespace_id = Uuid::parse_str(&request.source_namespace_id).map_err(|err| {
+            error!(err = err.to_string(), "failed to parse uuid");
+            Error::InvalidNamespace(err)
+        })?;
+        let namespace_id = Uuid::parse_str(&request.namespace_id).map_err(|err| {
+            error!(err = err.to_string(), "failed to parse uuid");
+            Error::InvalidNamespace(err)
+        })?;
+
+        if !authorized(source_identity, Scope::Read, source_namespace_id)
+            || !authorized(identity, Scope::Write, namespace_id)
+        {
+            return Err(Error::PermissionDenied.into());
+        }
+
+        if let Some(violation) = self
+            .partition_lookup
+            .key_policy(identity.tenant_id(), namespace_id)
+            .and_then(|policy| policy.violation(&request.key))
+        {
+            warn!(reason = violation, "key breaks the namespace's key policy");
+            return Err(Error::InvalidKey(violation).into());
+        }
+
+        let source_key: Key = (&request.source_key).into();
+        let key: Key = (&request.key).into();
+        let source_partition = self
+            .partition_lookup
+            .get_partition_for_key(source_identity.tenant_id(), source_namespace_id, &source_key)
+            .ok_or(Error::PartitionNotFound)?;
+        let partition = self
+            .partition_lookup
+            .get_partition_for_key(identity.tenant_id(), namespace_id, &key)
+            .ok_or(Error::PartitionNotFound)?;
+
+        let source = tier::get(self.tiering.as_deref(), &source_partition, &source_key)
+            .await
+            .inspect_err(|err| error!(err = err.to_string(), "failed to get source value"))?;
+
+        self.transforms
+            .validate(identity.tenant_id(), namespace_id, &source.value)
+            .inspect_err(|err| warn!(err = err.to_string(), "value failed validation"))?;
+
+        if let Some(quota) = self
+            .partition_lookup
+            .quota(identity.tenant_id(), namespace_id)
+        {
+            self.check_quota(
+                &quota,
+                identity.tenant_id(),
+                namespace_id,
+                &partition,
+                &key,
+                source.value.len(),
+            )?;
+        }
+
+        // the crc covers the key as well as the value
+        let mut crc_hasher = Hasher::new();
+        crc_hasher.update(request.key.as_slice());
+        crc_hasher.update(&source.value);
+        let value = PutValue {
+            crc: crc_hasher.finalize(),
+            value: &source.value,
+            expires_at: source
+                .expiry
+                .and_then(|expiry| expiry.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |expiry| expiry.as_secs()),
+        };
+        let _writes = self.partition_lookup.write_permit();
+        match partition.put(key, &value, request.expected_version) {
+            Err(err @ Error::VersionConflict { .. }) => {
+                warn!(err = err.to_string(), "version conflict");
+                Err(err.into())
+            }
+            Err(err) => {
+                error!(err = err.to_string(), "failed to copy value");
+                Err(err.into())
+            }
+            Ok(metadata) => Ok(Response::new(CopyKeyResponse {
+                version: metadata.version,
+                crc: metadata.crc,
+                size: source.value.len() as u64,
+                creation_time: Some(Timestamp::from(SystemTime::now())),
+            })),
+        }
+    }
+
+    async fn migrate_to_new_node(
+        &self,
+        request: Request<MigrateToNewNodeRequest>,
+    ) -> Result<Response<()>, Status> {
+        todo!()
+    }
+}
+
+// The unix time a key written with the ttl expires at, 0 is stored for a key that doesn't expire
+fn expires_at(ttl_secs: Option<u64>) -> u64 {
+    match ttl_secs {
+        Some(ttl_secs) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .saturating_add(ttl_secs.max(1)),
+        None => 0,
+    }
+}
+
+// The value and its crc as they're read, after the namespace's read transform. A transformed
+// value's crc is computed over what's returned, the stored crc covers the value as it was written.
+fn read_value(
+    transforms: &Transforms,
+    tenant_id: Uuid,
+    namespace_id: Uuid,
+    key: &[u8],
+    value: Vec<u8>,
+    crc: u32,
+) -> Result<(Vec<u8>, u32), Error> {
+    let transformed = transforms
+        .read(tenant_id, namespace_id, &value)
+        .inspect_err(|err| warn!(err = err.to_string(), "read transform failed"))?;
+    Ok(match transformed {
+        Some(transformed) => {
+            let mut crc_hasher = Hasher::new();
+            crc_hasher.update(key);
+            crc_hasher.update(transformed.as_slice());
+            (transformed, crc_hasher.finalize())
+        }
+        None => (value, crc),
+    })
 }
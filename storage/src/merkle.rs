@@ -0,0 +1,102 @@
+use sha2::{Digest, Sha256};
+
+// leaves of every partition's tree, a power of two so every level halves the one below it
+pub const LEAVES: usize = 1024;
+
+pub type Hash = [u8; 32];
+
+// The leaf a key is hashed into, the same on every node
+pub fn leaf(key: &[u8]) -> u32 {
+    crc32fast::hash(key) % LEAVES as u32
+}
+
+// A key's hash over its length, the key and its metadata as it's stored, which has its version,
+// its value's crc and its expiry
+pub fn digest(key: &[u8], metadata: &[u8]) -> Hash {
+    Sha256::new()
+        .chain_update((key.len() as u32).to_be_bytes())
+        .chain_update(key)
+        .chain_update(metadata)
+        .finalize()
+        .into()
+}
+
+// A hash tree over a partition's keys. Each leaf hashes the digests of the keys in it in key order,
+// and each node above hashes its two children. Two replicas with the same keys at the same
+// versions have the same root, and the subtrees whose hashes differ lead to the leaves they
+// diverge in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    // the root is at 1, a node's children are at 2i and 2i + 1, and the leaves are the last LEAVES
+    nodes: Vec<Hash>,
+}
+
+impl MerkleTree {
+    // None unless there are LEAVES leaves
+    pub fn from_leaves(leaves: Vec<Hash>) -> Option<MerkleTree> {
+        if leaves.len() != LEAVES {
+            return None;
+        }
+        let mut nodes = vec![Hash::default(); LEAVES];
+        nodes.extend(leaves);
+        for index in (1..LEAVES).rev() {
+            nodes[index] = Sha256::new()
+                .chain_update(nodes[2 * index])
+                .chain_update(nodes[2 * index + 1])
+                .finalize()
+                .into();
+        }
+        Some(MerkleTree { nodes })
+    }
+
+    pub fn leaves(&self) -> &[Hash] {
+        &self.nodes[LEAVES..]
+    }
+
+    // The leaves whose hashes differ from other's, in order. Only the subtrees whose hashes differ
+    // are descended into.
+    pub fn diff(&self, other: &MerkleTree) -> Vec<u32> {
+        let mut leaves = Vec::new();
+        let mut pending = vec![1];
+        while let Some(index) = pending.pop() {
+            if self.nodes[index] == other.nodes[index] {
+                continue;
+            }
+            if index >= LEAVES {
+                leaves.push((index - LEAVES) as u32);
+            } else {
+                pending.push(2 * index + 1);
+                pending.push(2 * index);
+            }
+        }
+        leaves
+    }
+}
+
+// Builds a partition's tree from its keys, which have to be added in order
+pub struct MerkleBuilder {
+    leaves: Vec<Sha256>,
+}
+
+impl Default for MerkleBuilder {
+    fn default() -> Self {
+        MerkleBuilder {
+            leaves: vec![Sha256::new(); LEAVES],
+        }
+    }
+}
+
+impl MerkleBuilder {
+    pub fn add(&mut self, key: &[u8], metadata: &[u8]) {
+        self.leaves[leaf(key) as usize].update(digest(key, metadata));
+    }
+
+    pub fn finish(self) -> MerkleTree {
+        let leaves = self
+            .leaves
+            .into_iter()
+            .map(|leaf| leaf.finalize().into())
+            .collect();
+        MerkleTree::from_leaves(leaves).unwrap()
+    }
+}
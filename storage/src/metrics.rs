@@ -0,0 +1,442 @@
+use common::metrics::register;
+use futures::StreamExt;
+use prometheus::{
+    exponential_buckets, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGaugeVec, Opts,
+};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tonic::codegen::http::{HeaderMap, Request, Response};
+use tonic::codegen::{Body, BoxFuture, Bytes, Service};
+use tonic::{Code, Status};
+use tower::Layer;
+use uuid::Uuid;
+
+// The tenant an rpc is made for, the layer can't read the token itself so the auth interceptor
+// fills it in once the token is validated. Rpcs without a token, e.g. health checks, have none.
+#[derive(Debug, Clone, Default)]
+pub struct RpcTenant(Arc<OnceLock<Uuid>>);
+
+impl RpcTenant {
+    pub fn set(&self, tenant_id: Uuid) {
+        let _ = self.0.set(tenant_id);
+    }
+
+    fn label(&self) -> String {
+        self.0.get().map_or_else(String::new, Uuid::to_string)
+    }
+}
+
+// Counts writes rejected because rocksdb stalled them, by whether writes were stopped or delayed
+pub fn record_write_stall(condition: &str) {
+    static WRITE_STALLS: OnceLock<IntCounterVec> = OnceLock::new();
+    WRITE_STALLS
+        .get_or_init(|| {
+            register(
+                IntCounterVec::new(
+                    Opts::new(
+                        "storage_write_stalls_total",
+                        "Writes rejected because rocksdb stalled them",
+                    ),
+                    &["condition"],
+                )
+                .unwrap(),
+            )
+        })
+        .with_label_values(&[condition])
+        .inc();
+}
+
+// Counts values moved to the tier's object store, fetched or promoted back from it, and objects
+// collected once no key points at them
+pub fn record_tiering(event: &str) {
+    static TIERING: OnceLock<IntCounterVec> = OnceLock::new();
+    TIERING
+        .get_or_init(|| {
+            register(
+                IntCounterVec::new(
+                    Opts::new(
+                        "storage_tiering_total",
+                        "Values tiered, fetched, and promoted, and objects collected",
+                    ),
+                    &["event"],
+                )
+                .unwrap(),
+            )
+        })
+        .with_label_values(&[event])
+        .inc();
+}
+
+// Counts changes published to the brokers, a change published again after a failure counts twice
+pub fn record_changes_published(count: usize) {
+    static PUBLISHED: OnceLock<IntCounter> = OnceLock::new();
+    PUBLISHED
+        .get_or_init(|| {
+            register(
+                IntCounter::new(
+                    "storage_changes_published_total",
+                    "Changes published to the change data capture topics",
+                )
+                .unwrap(),
+            )
+        })
+        .inc_by(count as u64);
+}
+
+// Counts retained values purged once their namespace's retention passed, see retention
+pub fn record_retained_purged(count: usize) {
+    static PURGED: OnceLock<IntCounter> = OnceLock::new();
+    PURGED
+        .get_or_init(|| {
+            register(
+                IntCounter::new(
+                    "storage_retained_purged_total",
+                    "Deleted and replaced values purged after their retention",
+                )
+                .unwrap(),
+            )
+        })
+        .inc_by(count as u64);
+}
+
+// Counts expired keys the reaper deleted and the bytes they took up, see reaper
+pub fn record_expired_reaped(keys: u64, bytes: u64) {
+    static REAPED: OnceLock<(IntCounter, IntCounter)> = OnceLock::new();
+    let (reaped_keys, reaped_bytes) = REAPED.get_or_init(|| {
+        (
+            register(
+                IntCounter::new("storage_expired_keys_reaped_total", "Expired keys deleted")
+                    .unwrap(),
+            ),
+            register(
+                IntCounter::new(
+                    "storage_expired_bytes_reaped_total",
+                    "Bytes of keys, metadata and values freed by deleting expired keys",
+                )
+                .unwrap(),
+            ),
+        )
+    });
+    reaped_keys.inc_by(keys);
+    reaped_bytes.inc_by(bytes);
+}
+
+// Counts replication log entries follower partitions applied from their leaders
+pub fn record_replicated(count: usize) {
+    static REPLICATED: OnceLock<IntCounter> = OnceLock::new();
+    REPLICATED
+        .get_or_init(|| {
+            register(
+                IntCounter::new(
+                    "storage_replication_entries_applied_total",
+                    "Replication log entries followers applied from their leaders",
+                )
+                .unwrap(),
+            )
+        })
+        .inc_by(count as u64);
+}
+
+// Counts follower partitions copied from a checkpoint of their leader's partition, by whether the
+// copy succeeded or failed
+pub fn record_bootstrap(result: &str) {
+    static BOOTSTRAPS: OnceLock<IntCounterVec> = OnceLock::new();
+    BOOTSTRAPS
+        .get_or_init(|| {
+            register(
+                IntCounterVec::new(
+                    Opts::new(
+                        "storage_replication_bootstraps_total",
+                        "Follower partitions copied from a checkpoint of their leader's partition",
+                    ),
+                    &["result"],
+                )
+                .unwrap(),
+            )
+        })
+        .with_label_values(&[result])
+        .inc();
+}
+
+// Counts the leaves follower partitions' trees differed from their leaders' in, and the keys anti
+// entropy repaired in them
+pub fn record_anti_entropy(leaves: usize, keys: usize) {
+    static REPAIRS: OnceLock<(IntCounter, IntCounter)> = OnceLock::new();
+    let (divergent_leaves, repaired_keys) = REPAIRS.get_or_init(|| {
+        (
+            register(
+                IntCounter::new(
+                    "storage_anti_entropy_divergent_leaves_total",
+                    "Merkle tree leaves follower partitions differed from their leaders in",
+                )
+                .unwrap(),
+            ),
+            register(
+                IntCounter::new(
+                    "storage_anti_entropy_keys_repaired_total",
+                    "Keys of follower partitions anti entropy changed to match their leaders",
+                )
+                .unwrap(),
+            ),
+        )
+    });
+    divergent_leaves.inc_by(leaves as u64);
+    repaired_keys.inc_by(keys as u64);
+}
+
+fn replication_lag() -> &'static (IntGaugeVec, GaugeVec) {
+    static LAG: OnceLock<(IntGaugeVec, GaugeVec)> = OnceLock::new();
+    LAG.get_or_init(|| {
+        (
+            register(
+                IntGaugeVec::new(
+                    Opts::new(
+                        "storage_replication_lag_entries",
+                        "Leader log entries a follower partition hasn't applied",
+                    ),
+                    &["partition_id"],
+                )
+                .unwrap(),
+            ),
+            register(
+                GaugeVec::new(
+                    Opts::new(
+                        "storage_replication_lag_seconds",
+                        "Age of the last entry a follower partition applied, 0 once it's caught up",
+                    ),
+                    &["partition_id"],
+                )
+                .unwrap(),
+            ),
+        )
+    })
+}
+
+// A follower partition's lag as of its last read of its leader's log
+pub fn set_replication_lag(partition_id: Uuid, entries: u64, seconds: f64) {
+    let (lag_entries, lag_seconds) = replication_lag();
+    let partition_id = partition_id.to_string();
+    lag_entries
+        .with_label_values(&[&partition_id])
+        .set(entries as i64);
+    lag_seconds.with_label_values(&[&partition_id]).set(seconds);
+}
+
+// Drops a partition's lag once it's no longer a follower
+pub fn clear_replication_lag(partition_id: Uuid) {
+    let (lag_entries, lag_seconds) = replication_lag();
+    let partition_id = partition_id.to_string();
+    let _ = lag_entries.remove_label_values(&[&partition_id]);
+    let _ = lag_seconds.remove_label_values(&[&partition_id]);
+}
+
+// Counts, latency, payload sizes, and status codes of every rpc the node serves, labeled by method
+// and tenant. An rpc is recorded once its response ends, a streaming rpc's latency covers the whole
+// stream, and one the client abandons is recorded as cancelled.
+#[derive(Debug, Clone)]
+pub struct GrpcMetrics {
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    request_bytes: HistogramVec,
+    response_bytes: HistogramVec,
+}
+
+impl Default for GrpcMetrics {
+    fn default() -> Self {
+        // 64 bytes to 64 MiB
+        let size_buckets = exponential_buckets(64.0, 4.0, 11).unwrap();
+        GrpcMetrics {
+            requests: register(
+                IntCounterVec::new(
+                    Opts::new("storage_grpc_requests_total", "Handled rpcs by status code"),
+                    &["method", "tenant", "code"],
+                )
+                .unwrap(),
+            ),
+            duration: register(
+                HistogramVec::new(
+                    HistogramOpts::new("storage_grpc_request_duration_seconds", "Latency of rpcs"),
+                    &["method", "tenant"],
+                )
+                .unwrap(),
+            ),
+            request_bytes: register(
+                HistogramVec::new(
+                    HistogramOpts::new("storage_grpc_request_bytes", "Size of rpc requests")
+                        .buckets(size_buckets.clone()),
+                    &["method", "tenant"],
+                )
+                .unwrap(),
+            ),
+            response_bytes: register(
+                HistogramVec::new(
+                    HistogramOpts::new("storage_grpc_response_bytes", "Size of rpc responses")
+                        .buckets(size_buckets),
+                    &["method", "tenant"],
+                )
+                .unwrap(),
+            ),
+        }
+    }
+}
+
+impl<S> Layer<S> for GrpcMetrics {
+    type Service = MeteredService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MeteredService {
+            metrics: self.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MeteredService<S> {
+    metrics: GrpcMetrics,
+    inner: S,
+}
+
+impl<S, ResBody> Service<Request<hyper::Body>> for MeteredService<S>
+where
+    S: Service<Request<hyper::Body>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<MeteredBody<ResBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<hyper::Body>) -> Self::Future {
+        // the ready service handles this request, a clone takes its place for the next one
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let tenant = RpcTenant::default();
+        let request_bytes = Arc::new(AtomicU64::new(0));
+        let mut rpc = Rpc {
+            metrics: self.metrics.clone(),
+            method: request.uri().path().to_string(),
+            tenant: tenant.clone(),
+            request_bytes: request_bytes.clone(),
+            response_bytes: 0,
+            code: None,
+            start: Instant::now(),
+        };
+
+        let (mut parts, body) = request.into_parts();
+        parts.extensions.insert(tenant);
+        let body = hyper::Body::wrap_stream(body.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                request_bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+        }));
+
+        Box::pin(async move {
+            let response = inner
+                .call(Request::from_parts(parts, body))
+                .await
+                .inspect_err(|_| rpc.code = Some(Code::Internal))?;
+            // an rpc that fails before sending a message returns its status in the headers
+            rpc.code = Status::from_header_map(response.headers()).map(|status| status.code());
+            Ok(response.map(|body| MeteredBody {
+                inner: body,
+                rpc: Some(rpc),
+            }))
+        })
+    }
+}
+
+// An rpc being served, recorded when it's dropped
+struct Rpc {
+    metrics: GrpcMetrics,
+    method: String,
+    tenant: RpcTenant,
+    request_bytes: Arc<AtomicU64>,
+    response_bytes: u64,
+    code: Option<Code>,
+    start: Instant,
+}
+
+impl Drop for Rpc {
+    fn drop(&mut self) {
+        let code = self.code.unwrap_or(Code::Cancelled);
+        // paths that aren't rpcs the node serves aren't labeled by their path, which anyone can pick
+        let method = match code {
+            Code::Unimplemented => "unknown",
+            _ => self.method.as_str(),
+        };
+        let tenant = self.tenant.label();
+        let labels = [method, tenant.as_str()];
+        self.metrics
+            .requests
+            .with_label_values(&[method, tenant.as_str(), &format!("{:?}", code)])
+            .inc();
+        self.metrics
+            .duration
+            .with_label_values(&labels)
+            .observe(self.start.elapsed().as_secs_f64());
+        self.metrics
+            .request_bytes
+            .with_label_values(&labels)
+            .observe(self.request_bytes.load(Ordering::Relaxed) as f64);
+        self.metrics
+            .response_bytes
+            .with_label_values(&labels)
+            .observe(self.response_bytes as f64);
+    }
+}
+
+// A response body counting the bytes sent and reading the rpc's status from the trailers
+pub struct MeteredBody<B> {
+    inner: B,
+    rpc: Option<Rpc>,
+}
+
+impl<B> Body for MeteredBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let data = Pin::new(&mut self.inner).poll_data(cx);
+        if let (Poll::Ready(Some(Ok(data))), Some(rpc)) = (&data, &mut self.rpc) {
+            rpc.response_bytes += data.len() as u64;
+        }
+        data
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let trailers = Pin::new(&mut self.inner).poll_trailers(cx);
+        if let Poll::Ready(result) = &trailers {
+            if let Some(mut rpc) = self.rpc.take() {
+                let trailers = result.as_ref().ok().and_then(Option::as_ref);
+                if let Some(status) = trailers.and_then(Status::from_header_map) {
+                    rpc.code = Some(status.code());
+                } else if result.is_err() {
+                    rpc.code = Some(Code::Internal);
+                }
+            }
+        }
+        trailers
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+}
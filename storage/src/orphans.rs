@@ -0,0 +1,257 @@
+use crate::lookup::PartitionLookup;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+// how often the data directory is checked for orphaned partitions
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// quarantined partitions are moved here, under the data directory
+const QUARANTINE_DIR: &str = "quarantine";
+
+// What's done with a partition directory nothing uses once its grace period is over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OrphanAction {
+    // it's only logged and listed by the admin rpc
+    #[default]
+    Report,
+    // it's moved to data_dir/quarantine, where an operator can inspect it or move it back
+    Quarantine,
+    Delete,
+}
+
+impl FromStr for OrphanAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "report" => Ok(OrphanAction::Report),
+            "quarantine" => Ok(OrphanAction::Quarantine),
+            "delete" => Ok(OrphanAction::Delete),
+            _ => Err(format!(
+                "unknown orphan action {}, expected report, quarantine or delete",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrphanSettings {
+    pub action: OrphanAction,
+    // an orphan is only acted on once it's been seen for this long, so a partition whose directory
+    // is created before it's added to the partition map, e.g. by a clone, isn't collected
+    pub grace: Duration,
+}
+
+// A directory in the data directory named for a partition the partition map doesn't have, e.g. one
+// removed with RemovePartition, or left behind by a clone that failed part way
+#[derive(Debug, Clone)]
+pub struct Orphan {
+    pub id: Uuid,
+    pub bytes: u64,
+    pub first_seen: SystemTime,
+    pub quarantined: bool,
+}
+
+// Partitions the partition map and the data directory disagree about
+#[derive(Debug, Clone, Default)]
+pub struct OrphanReport {
+    pub orphaned: Vec<Orphan>,
+    // partitions in the map whose directory was missing when the node started, or is missing now
+    pub missing: Vec<Uuid>,
+}
+
+// Finds the partition directories nothing uses, and the partitions without a directory. When an
+// orphan was first seen is only kept in memory, its grace period starts over when the node restarts.
+pub struct Orphans {
+    data_dir: PathBuf,
+    settings: OrphanSettings,
+    first_seen: Mutex<HashMap<Uuid, SystemTime>>,
+}
+
+impl Orphans {
+    pub fn new(data_dir: impl AsRef<Path>, settings: OrphanSettings) -> Orphans {
+        Orphans {
+            data_dir: data_dir.as_ref().to_path_buf(),
+            settings,
+            first_seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn find(&self, partition_lookup: &PartitionLookup) -> io::Result<OrphanReport> {
+        let known: HashSet<Uuid> = partition_lookup
+            .all_partitions()
+            .iter()
+            .map(|partition| partition.id)
+            .collect();
+        let mut found = partition_dirs(&self.data_dir)?
+            .into_iter()
+            .filter(|(id, _)| !known.contains(id))
+            .map(|(id, path)| (id, path, false))
+            .collect::<Vec<_>>();
+        let quarantine = self.data_dir.join(QUARANTINE_DIR);
+        if quarantine.is_dir() {
+            found.extend(
+                partition_dirs(&quarantine)?
+                    .into_iter()
+                    .map(|(id, path)| (id, path, true)),
+            );
+        }
+
+        let now = SystemTime::now();
+        let mut first_seen = self
+            .first_seen
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // an orphan that's gone, or was added back, starts over if it turns up again
+        first_seen.retain(|id, _| found.iter().any(|(found, _, _)| found == id));
+        let orphaned = found
+            .into_iter()
+            .map(|(id, path, quarantined)| Orphan {
+                id,
+                bytes: dir_size(&path),
+                first_seen: *first_seen.entry(id).or_insert(now),
+                quarantined,
+            })
+            .collect();
+
+        let mut missing: Vec<Uuid> = known
+            .into_iter()
+            .filter(|id| !partition_lookup.partition_dir(*id).exists())
+            .chain(partition_lookup.missing_at_load().iter().copied())
+            .collect();
+        missing.sort();
+        missing.dedup();
+        Ok(OrphanReport { orphaned, missing })
+    }
+
+    // Reports the orphans and missing partitions, and quarantines or deletes the orphans past their
+    // grace period
+    pub fn sweep(&self, partition_lookup: &PartitionLookup) -> io::Result<()> {
+        let report = self.find(partition_lookup)?;
+        for id in &report.missing {
+            error!(
+                partition_id = id.to_string(),
+                "partition directory is missing, the partition was opened empty"
+            );
+        }
+        let now = SystemTime::now();
+        for orphan in report.orphaned {
+            let due = now
+                .duration_since(orphan.first_seen)
+                .is_ok_and(|seen_for| seen_for >= self.settings.grace);
+            // the partition may have been added since the directory was listed
+            let collect = due && partition_lookup.find_partition(orphan.id).is_none();
+            let result = match (self.settings.action, collect) {
+                (OrphanAction::Quarantine, true) if !orphan.quarantined => {
+                    self.quarantine(orphan.id)
+                }
+                (OrphanAction::Delete, true) => self.delete(&orphan),
+                _ => {
+                    warn!(
+                        partition_id = orphan.id.to_string(),
+                        bytes = orphan.bytes,
+                        quarantined = orphan.quarantined,
+                        "found orphaned partition directory"
+                    );
+                    Ok(())
+                }
+            };
+            if let Err(err) = result {
+                error!(
+                    partition_id = orphan.id.to_string(),
+                    err = err.to_string(),
+                    "failed to collect orphaned partition directory"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn quarantine(&self, id: Uuid) -> io::Result<()> {
+        let quarantine = self.data_dir.join(QUARANTINE_DIR);
+        fs::create_dir_all(&quarantine)?;
+        fs::rename(
+            self.data_dir.join(id.to_string()),
+            quarantine.join(id.to_string()),
+        )?;
+        info!(
+            partition_id = id.to_string(),
+            "quarantined orphaned partition directory"
+        );
+        Ok(())
+    }
+
+    fn delete(&self, orphan: &Orphan) -> io::Result<()> {
+        let dir = match orphan.quarantined {
+            true => self.data_dir.join(QUARANTINE_DIR),
+            false => self.data_dir.clone(),
+        };
+        fs::remove_dir_all(dir.join(orphan.id.to_string()))?;
+        info!(
+            partition_id = orphan.id.to_string(),
+            bytes = orphan.bytes,
+            "deleted orphaned partition directory"
+        );
+        Ok(())
+    }
+}
+
+// Checks the data directory when the node starts and every SWEEP_INTERVAL after
+pub async fn run(orphans: Arc<Orphans>, partition_lookup: Arc<PartitionLookup>) {
+    let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let (orphans, partition_lookup) = (orphans.clone(), partition_lookup.clone());
+        let swept = tokio::task::spawn_blocking(move || orphans.sweep(&partition_lookup))
+            .await
+            .map_err(io::Error::other);
+        if let Err(err) | Ok(Err(err)) = swept {
+            error!(
+                err = err.to_string(),
+                "failed to check for orphaned partitions"
+            );
+        }
+    }
+}
+
+// The directories in dir named for a partition
+fn partition_dirs(dir: &Path) -> io::Result<Vec<(Uuid, PathBuf)>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let Some(id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| Uuid::parse_str(name).ok())
+        else {
+            continue;
+        };
+        dirs.push((id, entry.path()));
+    }
+    Ok(dirs)
+}
+
+// The bytes of the files in the directory, partitions don't have subdirectories
+fn dir_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter_map(|entry| entry.metadata().ok())
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len())
+                .sum()
+        })
+        .unwrap_or(0)
+}
@@ -1,54 +1,32 @@
 use common::storage::KeyMetadata;
 use common::storage::Metadata;
+use prost_types::Timestamp;
+use rocksdb::checkpoint::Checkpoint;
 use rocksdb::{
-    IteratorMode, Options, WriteBatch, DB, DEFAULT_COLUMN_FAMILY_NAME,
+    properties, Env, ErrorKind, IteratorMode, Options, Snapshot, WriteBatch, WriteOptions, DB,
+    DEFAULT_COLUMN_FAMILY_NAME,
 };
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
 use std::path::Path;
-use std::sync::Arc;
-use tracing::{error, info};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
 use tracing_attributes::instrument;
 use uuid::Uuid;
-use std::fmt::Display;
-use crate::partition::Error::RocksDBError;
-use std::error::Error as StdError;
-
-#[derive(Debug, Clone)]
-pub enum Error {
-    RocksDBError(rocksdb::Error),
-    General(String)
-}
-
-impl Display for Error {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            RocksDBError(err) => f.write_str(err.to_string().as_str()),
-            Error::General(err) => f.write_str(err.as_str())
-        }
-    }
-}
-
-impl StdError for Error {
-    fn source(&self) -> Option<&(dyn StdError + 'static)> {
-        match self {
-            RocksDBError(err) => Some(err),
-            Error::General(_) => None
-        }
-    }
-}
-
-impl From<rocksdb::Error> for Error {
-    fn from(value: rocksdb::Error) -> Self {
-        RocksDBError(value)
-    }
-}
-
-impl From<&rocksdb::Error> for Error {
-    fn from(value: &rocksdb::Error) -> Self {
-        RocksDBError(value.clone())
-    }
-}
+use crate::cdc::{Change, Reservation, Sequencer};
+use crate::error::Error;
+use crate::format::{self, EntryMetadata, METADATA_LEN};
+use crate::merkle::{self, MerkleBuilder, MerkleTree};
+use crate::metrics;
+use crate::replication::{LogEntry, LogOp};
+use crate::retention::{self, RetainedValue, Retention};
+use crate::tier::TierEntry;
+use crate::transact::IntentWrite;
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct Key(Arc<[u8]>);
@@ -83,9 +61,101 @@ impl AsRef<[u8]> for Key {
     }
 }
 
+// Writes to the same key are serialized so reading the current version and writing the next one
+// can't interleave. Keys share a lock by their hash, this many locks per partition.
+const WRITE_LOCK_STRIPES: usize = 256;
+
+// Keys' access times and the objects tiered values were moved to, see tier. It's only created once
+// tiering first sweeps the partition.
+pub const TIER_CF: &str = "tier";
+
+// Changes waiting to be published, keyed by their big endian sequence number, see cdc. It's only
+// there while changes are captured.
+const CHANGES_CF: &str = "changes";
+
+// Values kept after they were deleted or replaced, under the key followed by the big endian
+// version, see retention. It's only created once the namespace retains values.
+const HISTORY_CF: &str = "history";
+
+// The writes followers on other nodes apply, keyed by their big endian sequence number, see
+// replication. It's only there while writes are logged.
+const REPLICATION_CF: &str = "replication";
+
+// Where the replication log starts once it's trimmed, under the empty key which sorts before every
+// entry. A log without it starts at 0.
+const LOG_START_KEY: &[u8] = b"";
+
+// A prefix delete that has to record every key deletes them in batches of this many keys, so it
+// only holds a batch's keys and their stripes at a time
+const DELETE_PREFIX_CHUNK: usize = 1000;
+
+// A follower copying its leader's checkpoint writes it in batches of about this many bytes
+const INSTALL_BATCH_BYTES: usize = 4 << 20;
+
+// How long a client is told to wait before retrying a write rocksdb stalled. Stopped writes wait
+// for a flush or compaction to finish, delayed ones only for the write rate to catch up.
+const STOPPED_RETRY_AFTER: Duration = Duration::from_secs(5);
+const DELAYED_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+// The rate limiter refills every 100ms, and flushes are favored over compactions 10 to 1 by it
+const RATE_LIMIT_REFILL_MICROS: i64 = 100_000;
+const RATE_LIMIT_FAIRNESS: i32 = 10;
+
+// Caps on the flushes and compactions partitions run in the background, so a compaction storm
+// can't take the disk from foreground reads. Unset caps are left to rocksdb.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BackgroundLimits {
+    // bytes per second each partition's flushes and compactions write, every partition has its own
+    // limiter
+    pub rate_limit: Option<u64>,
+    // flushes and compactions each partition runs at once
+    pub max_jobs: Option<u32>,
+    // the node's compaction and flush threads, shared by every partition
+    pub compaction_threads: Option<u32>,
+    pub flush_threads: Option<u32>,
+}
+
+impl BackgroundLimits {
+    // Sizes the thread pools every partition's flushes and compactions share, before any partition
+    // is opened
+    pub fn size_thread_pools(&self) -> Result<(), Error> {
+        let mut env = Env::new()?;
+        if let Some(threads) = self.compaction_threads {
+            env.set_low_priority_background_threads(threads as i32);
+        }
+        if let Some(threads) = self.flush_threads {
+            env.set_high_priority_background_threads(threads as i32);
+        }
+        Ok(())
+    }
+
+    fn apply(&self, options: &mut Options) {
+        if let Some(rate_limit) = self.rate_limit {
+            options.set_ratelimiter(
+                rate_limit as i64,
+                RATE_LIMIT_REFILL_MICROS,
+                RATE_LIMIT_FAIRNESS,
+            );
+        }
+        if let Some(max_jobs) = self.max_jobs {
+            options.set_max_background_jobs(max_jobs as i32);
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Partition {
     db: Arc<DB>,
+    path: Arc<Path>,
+    write_locks: Arc<[Mutex<()>]>,
+    // set when the partition's changes are captured for publishing
+    changes: Option<Arc<Sequencer>>,
+    // set when the partition's writes are logged for followers
+    log: Option<Arc<Sequencer>>,
+    // the admin endpoint of the node whose partition this one follows, set by the partition lookup
+    leader: Arc<Mutex<Option<String>>>,
+    // the namespace's, set by the partition lookup
+    retention: Arc<Mutex<Retention>>,
     pub namespace_id: Uuid,
     pub tenant_id: Uuid,
     pub id: Uuid,
@@ -101,22 +171,22 @@ impl Debug for Partition {
     }
 }
 
+// The version is assigned by Partition::put, one past the key's current version
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PutValue<'a> {
     pub crc: u32,
-    pub version: u32, // need to check to make sure the current version at least one above the current version, and if it is not, return a cas error
     pub value: &'a [u8],
+    // unix seconds, 0 for a key that doesn't expire
+    pub expires_at: u64,
 }
 
 impl PutValue<'_> {
-    // Might want to consider passing in the buffer that is stack allocated to fill instead of allocating a vec on the heap for this
-    fn metadata_as_bytes(&self) -> Vec<u8> {
-        return vec![
-            self.crc.to_be_bytes().as_slice(),
-            self.version.to_be_bytes().as_slice(),
-        ]
-        .concat()
-        .to_vec();
+    pub fn metadata(&self, version: u32) -> EntryMetadata {
+        EntryMetadata {
+            crc: self.crc,
+            version,
+            expires_at: self.expires_at,
+        }
     }
 }
 
@@ -128,13 +198,71 @@ pub struct ValueMetadata {
 pub struct GetValue {
     pub crc: u32,
     pub version: u32, // need to check to make sure the current version at least one above the current version, and if it is not, return a cas error
+    pub expiry: Option<SystemTime>,
     pub value: Vec<u8>,
+    // the object the value was moved to by tiering, value is empty when it's set
+    pub tiered: Option<String>,
+}
+
+// A key's value as a scan reads it, see Partition::scan
+pub enum ScanValue<'a> {
+    Local(&'a [u8]),
+    // the object the value was moved to by tiering
+    Tiered(String),
+}
+
+// A value the tiering sweep can move out of the partition, see Partition::cold_values
+pub struct ColdValue {
+    pub key: Key,
+    pub version: u32,
+    pub value: Vec<u8>,
+}
+
+// What one batch of the reaper deleted, see Partition::reap_expired
+#[derive(Debug, Default)]
+pub struct Reaped {
+    pub keys: u64,
+    pub bytes: u64,
+    // where the next batch starts, None once the batch reached the partition's last key
+    pub next: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub key_count: u64,
+    pub total_bytes: u64,
+}
+
+// A key as it's stored, see Partition::dump
+pub struct RawEntry {
+    pub key: Box<[u8]>,
+    pub metadata: Box<[u8]>,
+    pub value: Option<Vec<u8>>,
+}
+
+// A point in time view of a partition that can be kept across requests, it holds on to the db so
+// the db outlives it
+pub struct PinnedSnapshot {
+    // declared first so it's dropped before the db it was taken from
+    snapshot: Snapshot<'static>,
+    _db: Arc<DB>,
+    pub partition_id: Uuid,
+}
+
+impl Debug for PinnedSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PinnedSnapshot")
+            .field("partition_id", &self.partition_id)
+            .finish()
+    }
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct ListOptions<'a> {
     limit: Option<usize>,
-    start_at: Option<&'a str>,
+    start_at: Option<&'a [u8]>,
+    snapshot: Option<&'a PinnedSnapshot>,
+    max_value_size: Option<usize>,
 }
 
 impl<'a> ListOptions<'a> {
@@ -143,10 +271,23 @@ impl<'a> ListOptions<'a> {
         self
     }
 
-    pub fn with_start_at(&mut self, start_at: &'a str) -> &mut Self {
+    pub fn with_start_at(&mut self, start_at: &'a [u8]) -> &mut Self {
         self.start_at = Some(start_at);
         self
     }
+
+    // Lists the keys as of the snapshot rather than as they are now
+    pub fn with_snapshot(&mut self, snapshot: &'a PinnedSnapshot) -> &mut Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    // Includes the value of every key whose value is at most max_value_size bytes, tiered values
+    // are left out
+    pub fn with_values(&mut self, max_value_size: usize) -> &mut Self {
+        self.max_value_size = Some(max_value_size);
+        self
+    }
 }
 
 impl Partition {
@@ -155,6 +296,9 @@ impl Partition {
         namespace_id: Uuid,
         tenant_id: Uuid,
         path: I,
+        background: &BackgroundLimits,
+        capture_changes: bool,
+        log_writes: bool,
     ) -> Result<Partition, Error>
     where
         I: AsRef<Path>,
@@ -165,17 +309,82 @@ impl Partition {
         options.set_use_direct_io_for_flush_and_compaction(true);
         options.set_use_direct_reads(true);
         options.create_missing_column_families(true);
+        background.apply(&mut options);
 
         let path = path.as_ref().join(id.to_string());
 
-        let db = DB::open_cf(
-            &options,
-            path.as_path(),
-            vec![DEFAULT_COLUMN_FAMILY_NAME, "metadata"],
-        )?;
+        // a new partition is marked with the current format, an existing one has to already be in it
+        let created = !path.exists();
+        if !created {
+            format::check_version(&path)?;
+        }
+
+        // every column family a partition has must be opened, tier is only there once it's swept
+        let existing = match created {
+            true => Vec::new(),
+            false => DB::list_cf(&options, &path)?,
+        };
+        let mut column_families = vec![DEFAULT_COLUMN_FAMILY_NAME, "metadata"];
+        for cf_name in [TIER_CF, CHANGES_CF, HISTORY_CF, REPLICATION_CF] {
+            if existing.iter().any(|name| name == cf_name) {
+                column_families.push(cf_name);
+            }
+        }
+        if capture_changes && !column_families.contains(&CHANGES_CF) {
+            column_families.push(CHANGES_CF);
+        }
+        let log_started = column_families.contains(&REPLICATION_CF);
+        if log_writes && !log_started {
+            column_families.push(REPLICATION_CF);
+        }
+        let db = DB::open_cf(&options, path.as_path(), column_families)?;
+        if created {
+            format::set_version(&path, format::CURRENT_FORMAT)?;
+        }
+
+        let changes = match capture_changes {
+            true => Some(Arc::new(Sequencer::starting_at(next_sequence(&db, CHANGES_CF)?))),
+            false => {
+                // changes stop being captured, the ones not published yet are dropped with them
+                if db.cf_handle(CHANGES_CF).is_some() {
+                    warn!(partition_id = id.to_string(), "dropping unpublished changes");
+                    db.drop_cf(CHANGES_CF)?;
+                }
+                None
+            }
+        };
+
+        let log = match log_writes {
+            true => {
+                // keys written before the log started aren't in it, so a follower can't start
+                // from the beginning of the log of a partition that already had keys
+                let metadata_handle = db.cf_handle("metadata").unwrap();
+                let had_keys = db.iterator_cf(&metadata_handle, IteratorMode::Start).next().is_some();
+                if !log_started && had_keys {
+                    let cf_handle = db.cf_handle(REPLICATION_CF).unwrap();
+                    db.put_cf(&cf_handle, LOG_START_KEY, 1u64.to_be_bytes())?;
+                }
+                let next = next_sequence(&db, REPLICATION_CF)?.max(log_start(&db)?);
+                Some(Arc::new(Sequencer::starting_at(next)))
+            }
+            false => {
+                // like changes, the log is dropped once writes stop being logged
+                if db.cf_handle(REPLICATION_CF).is_some() {
+                    warn!(partition_id = id.to_string(), "dropping replication log");
+                    db.drop_cf(REPLICATION_CF)?;
+                }
+                None
+            }
+        };
 
         let db = Arc::new(db);
         Ok(Partition {
+            path: path.into(),
+            write_locks: (0..WRITE_LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+            changes,
+            log,
+            leader: Arc::default(),
+            retention: Arc::default(),
             id,
             namespace_id,
             tenant_id,
@@ -188,73 +397,1457 @@ impl Partition {
         let metadata_handle = self.db.cf_handle("metadata").unwrap();
         let default_handle = self.db.cf_handle(DEFAULT_COLUMN_FAMILY_NAME).unwrap();
 
-        let mut get_parts = self
-            .db
-            .multi_get_cf(vec![(&default_handle, key), (&metadata_handle, key)]);
+        let tier_handle = self.db.cf_handle(TIER_CF);
 
-        let (crc, version) = match get_parts.remove(1) {
-            Ok(Some(value)) => {
-                let (crc, version) = value.split_at(4);
-                (
-                    u32::from_be_bytes(crc.try_into().unwrap()),
-                    u32::from_be_bytes(version.try_into().unwrap()),
-                )
-            }
+        let mut lookups = vec![(&default_handle, key), (&metadata_handle, key)];
+        if let Some(tier_handle) = &tier_handle {
+            lookups.push((tier_handle, key));
+        }
+        let mut get_parts = self.db.multi_get_cf(lookups);
+
+        let tier = match get_parts.len() > 2 {
+            true => match get_parts.remove(2)? {
+                Some(entry) => Some(TierEntry::decode(&entry).ok_or(Error::UnknownEncoding)?),
+                None => None,
+            },
+            false => None,
+        };
+
+        // an expired key reads as missing until it's removed
+        let metadata = match get_parts.remove(1) {
+            Ok(Some(value)) => match EntryMetadata::decode(&value) {
+                Some(metadata) if metadata.is_expired(SystemTime::now()) => {
+                    return Err(Error::NotFound)
+                }
+                Some(metadata) => metadata,
+                None => return Err(Error::UnknownEncoding),
+            },
             Err(err) => {
                 error!({info = err.to_string()}, "failed to get value: {}", err);
                 return Err(err.into());
             }
-            _ => return Err(Error::General("could not find value".to_string())),
+            _ => return Err(Error::NotFound),
          };
 
 
-        let value: Vec<u8> = match get_parts.remove(0) {
-            Ok(Some(value)) => value,
+        let (value, tiered) = match get_parts.remove(0) {
+            Ok(Some(value)) => (value, None),
 
             Err(err) => {
                 error!({info = err.to_string()}, "failed to get value: {}", err);
                 return Err(err.into());
             }
 
-            _ => return Err(Error::General("could not find value".to_string())),
+            // a tiered key's value is in the object store instead
+            _ => match tier.as_ref().and_then(|tier| tier.object.clone()) {
+                Some(object) => (Vec::new(), Some(object)),
+                None => return Err(Error::NotFound),
+            },
         };
 
+        // the read keeps the key from going cold, a failure to record it doesn't fail the read
+        if tier_handle.is_some() && tier.as_ref().is_none_or(TierEntry::is_stale) {
+            if let Err(err) = self.record_access(key) {
+                warn!(err = err.to_string(), "failed to record key access");
+            }
+        }
+
         Ok(GetValue {
-            crc,
-            version,
+            crc: metadata.crc,
+            version: metadata.version,
+            expiry: metadata.expiry(),
             value,
+            tiered,
         })
     }
 
-    pub fn put(&self, key: Key, value: &PutValue) -> Result<ValueMetadata, rocksdb::ErrorKind> {
-        // todo get the metadata first to get the latest version and crc information, then update if no invariants are violated, like making sure the version we're going to put is larger than the current version
+    // Only called once the tier column family exists
+    fn record_access(&self, key: &Key) -> Result<(), Error> {
+        let tier_handle = self.db.cf_handle(TIER_CF).unwrap();
+        let _lock = self.write_lock(key);
+        // the key may have been deleted since it was read
+        if self.current_metadata(key)?.is_none() {
+            return Ok(());
+        }
+        let object = self.tier_entry(key.as_ref())?.and_then(|entry| entry.object);
+        let mut batch = WriteBatch::default();
+        batch.put_cf(&tier_handle, key, TierEntry::accessed_now(object).encode());
+        self.write(batch)
+    }
+
+    // None when the key has no entry, or the tier column family doesn't exist
+    fn tier_entry(&self, key: &[u8]) -> Result<Option<TierEntry>, Error> {
+        let Some(tier_handle) = self.db.cf_handle(TIER_CF) else {
+            return Ok(None);
+        };
+        match self.db.get_pinned_cf(&tier_handle, key)? {
+            Some(entry) => Ok(Some(TierEntry::decode(&entry).ok_or(Error::UnknownEncoding)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Creates the tier column family so keys' accesses are recorded, see tier
+    pub fn enable_tiering(&self) -> Result<(), Error> {
+        if self.db.cf_handle(TIER_CF).is_none() {
+            info!(partition_id = self.id.to_string(), "creating tier column family");
+            self.db.create_cf(TIER_CF, &Options::default())?;
+        }
+        Ok(())
+    }
+
+    // Up to limit values, and max_bytes in all, of at least min_size bytes whose keys weren't read
+    // or written since cutoff, in unix seconds. Keys without an access time yet are given one.
+    pub fn cold_values(
+        &self,
+        cutoff: u64,
+        min_size: usize,
+        limit: usize,
+        max_bytes: usize,
+    ) -> Result<Vec<ColdValue>, Error> {
+        let metadata_handle = self.db.cf_handle("metadata").unwrap();
+        let tier_handle = self.db.cf_handle(TIER_CF).unwrap();
+        let mut accessed = WriteBatch::default();
+        let mut cold = Vec::new();
+        let mut bytes = 0;
+        let now = SystemTime::now();
+        for item in self.db.iterator_cf(&metadata_handle, IteratorMode::Start) {
+            let (key, metadata) = item?;
+            let metadata = EntryMetadata::decode(&metadata).ok_or(Error::UnknownEncoding)?;
+            if metadata.is_expired(now) {
+                continue;
+            }
+            let entry = match self.tier_entry(&key)? {
+                Some(entry) => entry,
+                None => {
+                    accessed.put_cf(&tier_handle, &key, TierEntry::accessed_now(None).encode());
+                    continue;
+                }
+            };
+            if entry.object.is_some() || entry.accessed_at > cutoff {
+                continue;
+            }
+            let Some(value) = self.db.get_pinned(&key)? else {
+                continue;
+            };
+            if value.len() < min_size || bytes + value.len() > max_bytes {
+                continue;
+            }
+            bytes += value.len();
+            cold.push(ColdValue {
+                key: key.as_ref().into(),
+                version: metadata.version,
+                value: value.to_vec(),
+            });
+            if cold.len() == limit {
+                break;
+            }
+        }
+        // a key deleted meanwhile is left with an entry, tiered_objects removes it
+        self.db.write(accessed)?;
+        Ok(cold)
+    }
+
+    // Points the key at the object its value was uploaded to and drops the value, unless the key
+    // was written since the value was read at version
+    pub fn tier(&self, key: &Key, version: u32, object: &str) -> Result<bool, Error> {
+        let tier_handle = self.db.cf_handle(TIER_CF).unwrap();
+        let _lock = self.write_lock(key);
+        if self.current_version(key)? != version {
+            return Ok(false);
+        }
+        let entry = self.tier_entry(key.as_ref())?;
+        let Some(mut entry) = entry.filter(|entry| entry.object.is_none()) else {
+            return Ok(false);
+        };
+        entry.object = Some(object.to_string());
+        let mut batch = WriteBatch::default();
+        batch.put_cf(&tier_handle, key, entry.encode());
+        batch.delete(key);
+        self.write(batch)?;
+        Ok(true)
+    }
+
+    // Writes a tiered value back into the partition, unless the key was written since it was read
+    // at version. The object is left for tiering to collect.
+    pub fn promote(
+        &self,
+        key: &Key,
+        version: u32,
+        object: &str,
+        value: &[u8],
+    ) -> Result<bool, Error> {
+        let tier_handle = self.db.cf_handle(TIER_CF).unwrap();
+        let _lock = self.write_lock(key);
+        let tiered_to = self.tier_entry(key.as_ref())?.and_then(|entry| entry.object);
+        if self.current_version(key)? != version || tiered_to.as_deref() != Some(object) {
+            return Ok(false);
+        }
+        let mut batch = WriteBatch::default();
+        batch.put_cf(&tier_handle, key, TierEntry::accessed_now(None).encode());
+        batch.put(key, value);
+        self.write(batch)?;
+        Ok(true)
+    }
+
+    // The objects the partition's keys point at. Entries left for keys that no longer exist are
+    // removed along the way.
+    pub fn tiered_objects(&self) -> Result<HashSet<String>, Error> {
+        let Some(tier_handle) = self.db.cf_handle(TIER_CF) else {
+            return Ok(HashSet::new());
+        };
+        let metadata_handle = self.db.cf_handle("metadata").unwrap();
+        let mut objects = HashSet::new();
+        for item in self.db.iterator_cf(&tier_handle, IteratorMode::Start) {
+            let (key, entry) = item?;
+            if self.db.get_pinned_cf(&metadata_handle, &key)?.is_none() {
+                let key: Key = key.as_ref().into();
+                let _lock = self.write_lock(&key);
+                // checked again with the lock held, a put writes both together
+                if self.db.get_pinned_cf(&metadata_handle, &key)?.is_none() {
+                    self.db.delete_cf(&tier_handle, &key)?;
+                }
+                continue;
+            }
+            if let Some(object) = TierEntry::decode(&entry)
+                .ok_or(Error::UnknownEncoding)?
+                .object
+            {
+                objects.insert(object);
+            }
+        }
+        Ok(objects)
+    }
+
+    // The write lock the key shares with the other keys hashing to it
+    pub fn write_stripe(&self, key: &Key) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % self.write_locks.len()
+    }
+
+    // Held while a key is written. A single key write only ever holds one, a transaction takes the
+    // stripes of all its keys in order, see transact.
+    pub fn lock_stripe(&self, stripe: usize) -> MutexGuard<'_, ()> {
+        // a writer that panicked left nothing half written, the batch is applied atomically
+        self.write_locks[stripe]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write_lock(&self, key: &Key) -> MutexGuard<'_, ()> {
+        self.lock_stripe(self.write_stripe(key))
+    }
+
+    // The key's version, 0 when it doesn't exist or has expired. Only stable while the key's write
+    // lock is held.
+    pub fn current_version(&self, key: &Key) -> Result<u32, Error> {
+        Ok(self
+            .current_metadata(key)?
+            .map_or(0, |metadata| metadata.version))
+    }
+
+    // The key's metadata, None when it doesn't exist or has expired
+    fn current_metadata(&self, key: &Key) -> Result<Option<EntryMetadata>, Error> {
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        match self.db.get_pinned_cf(&cf_handle, key)? {
+            Some(metadata) => match EntryMetadata::decode(&metadata) {
+                Some(metadata) if metadata.is_expired(SystemTime::now()) => Ok(None),
+                Some(metadata) => Ok(Some(metadata)),
+                None => Err(Error::UnknownEncoding),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // Writes the value as the key's next version, 1 for a new key. With an expected version the
+    // write only happens if the key is still at it, 0 expecting the key not to exist. An expired
+    // key doesn't exist, it's written again from version 1.
+    pub fn put(
+        &self,
+        key: Key,
+        value: &PutValue,
+        expected_version: Option<u32>,
+    ) -> Result<ValueMetadata, Error> {
+        self.check_writable()?;
+        let _lock = self.write_lock(&key);
+        let current = self.current_version(&key)?;
+        if let Some(expected) = expected_version.filter(|expected| *expected != current) {
+            return Err(Error::VersionConflict { expected, current });
+        }
+        self.put_version(&key, value, current.wrapping_add(1))
+    }
+
+    // Writes the value as version 1 unless the key exists, which fails with the existing key's
+    // metadata. Like put, an expired key doesn't exist.
+    pub fn put_if_absent(&self, key: Key, value: &PutValue) -> Result<ValueMetadata, Error> {
+        self.check_writable()?;
+        let _lock = self.write_lock(&key);
+        if let Some(existing) = self.current_metadata(&key)? {
+            return Err(Error::KeyExists {
+                version: existing.version,
+                crc: existing.crc,
+                expires_at: existing.expires_at,
+            });
+        }
+        self.put_version(&key, value, 1)
+    }
+
+    // Only called with the key's write lock held
+    fn put_version(
+        &self,
+        key: &Key,
+        value: &PutValue,
+        version: u32,
+    ) -> Result<ValueMetadata, Error> {
         let cf_handle = self.db.cf_handle("metadata").unwrap();
         let mut batch = WriteBatch::default();
-        batch.put_cf(&cf_handle, &key, value.metadata_as_bytes());
-        batch.put(&key, value.value);
+        self.retain(&mut batch, key.as_ref(), version.wrapping_sub(1), false)?;
+        batch.put_cf(&cf_handle, key, value.metadata(version).encode());
+        batch.put(key, value.value);
+        // a value tiered before is replaced, its object is collected
+        if let Some(tier_handle) = self.db.cf_handle(TIER_CF) {
+            batch.put_cf(&tier_handle, key, TierEntry::accessed_now(None).encode());
+        }
+        let _reservation = self.capture(&mut batch, &[Change::put(key.as_ref(), version)]);
+        let entry = LogEntry::put(key.as_ref(), value.metadata(version), value.value);
+        let _logged = self.log(&mut batch, &[entry]);
 
-        self.db.write(batch).map_err(|err| {
+        self.write(batch).inspect_err(|err| {
             error! {err = err.to_string(), "failed to write value"};
-            err.kind()
         })?;
 
         Ok(ValueMetadata {
             crc: value.crc,
-            version: value.version,
+            version,
         })
     }
 
+    // Writes the batch unless rocksdb would stall it. A write that would wait for memtables to be
+    // flushed or for compactions to catch up fails right away with WriteStalled instead of holding
+    // the key's write lock and the rpc until rocksdb accepts writes again.
+    fn write(&self, batch: WriteBatch) -> Result<(), Error> {
+        let mut options = WriteOptions::default();
+        options.set_no_slowdown(true);
+        match self.db.write_opt(batch, &options) {
+            Err(err) if err.kind() == ErrorKind::Incomplete => {
+                let stopped = self
+                    .db
+                    .property_int_value(properties::IS_WRITE_STOPPED)
+                    .ok()
+                    .flatten()
+                    .is_some_and(|stopped| stopped > 0);
+                let condition = if stopped { "stopped" } else { "delayed" };
+                warn!(
+                    partition_id = self.id.to_string(),
+                    condition = condition,
+                    "rocksdb stalled a write"
+                );
+                metrics::record_write_stall(condition);
+                Err(Error::WriteStalled {
+                    condition,
+                    retry_after: if stopped {
+                        STOPPED_RETRY_AFTER
+                    } else {
+                        DELAYED_RETRY_AFTER
+                    },
+                })
+            }
+            result => result.map_err(Error::from),
+        }
+    }
+
+    // Applies a transaction's writes to this partition's keys in one batch, synced to disk before
+    // it returns since the transaction's intent is cleared after. The writes are applied as given,
+    // applying them again leaves the keys the same.
+    pub fn apply(&self, writes: &[&IntentWrite]) -> Result<(), Error> {
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        let tier_handle = self.db.cf_handle(TIER_CF);
+        let mut batch = WriteBatch::default();
+        let mut changes = Vec::new();
+        let mut logged = Vec::new();
+        for write in writes {
+            match &write.entry {
+                Some((metadata, value)) => {
+                    let entry = EntryMetadata::decode(metadata).ok_or(Error::UnknownEncoding)?;
+                    self.retain(&mut batch, &write.key, write.previous_version, false)?;
+                    changes.push(Change::put(&write.key, entry.version));
+                    if self.log.is_some() {
+                        logged.push(LogEntry::put(&write.key, entry, value));
+                    }
+                    batch.put_cf(&cf_handle, &write.key, metadata);
+                    batch.put(&write.key, value);
+                    if let Some(tier_handle) = &tier_handle {
+                        let entry = TierEntry::accessed_now(None);
+                        batch.put_cf(tier_handle, &write.key, entry.encode());
+                    }
+                }
+                None => {
+                    if write.previous_version > 0 {
+                        changes.push(Change::delete(&write.key, write.previous_version));
+                        if self.log.is_some() {
+                            logged.push(LogEntry::delete(&write.key));
+                        }
+                    }
+                    self.retain(&mut batch, &write.key, write.previous_version, true)?;
+                    batch.delete_cf(&cf_handle, &write.key);
+                    batch.delete(&write.key);
+                    if let Some(tier_handle) = &tier_handle {
+                        batch.delete_cf(tier_handle, &write.key);
+                    }
+                }
+            }
+        }
+        let _reservation = self.capture(&mut batch, &changes);
+        let _logged = self.log(&mut batch, &logged);
+        let mut options = WriteOptions::default();
+        options.set_sync(true);
+        Ok(self.db.write_opt(batch, &options)?)
+    }
+
     pub fn exists(&self, key: Key) -> Result<bool, Error> {
         Ok(self.db.get(&key).map(|v| v.is_some())?)
     }
 
-    pub fn delete(&self, key: Key) -> Result<(), Error> {
+    // Rewrites only the key's metadata with the new expiry, leaving the value as it is. An expired
+    // key can't be touched back to life.
+    pub fn touch(&self, key: Key, expires_at: u64) -> Result<EntryMetadata, Error> {
+        self.check_writable()?;
         let cf_handle = self.db.cf_handle("metadata").unwrap();
+        let _lock = self.write_lock(&key);
+        let mut metadata = self.current_metadata(&key)?.ok_or(Error::NotFound)?;
+        metadata.expires_at = expires_at;
         let mut batch = WriteBatch::default();
+        batch.put_cf(&cf_handle, &key, metadata.encode());
+        let _logged = self.log(&mut batch, &[LogEntry::touch(key.as_ref(), metadata)]);
+
+        self.write(batch)?;
+        Ok(metadata)
+    }
+
+    // With an expected version the key is only deleted if it's still at it, like put. A key that's
+    // forgotten isn't retained, and every version retained before is purged with it.
+    pub fn delete(
+        &self,
+        key: Key,
+        expected_version: Option<u32>,
+        forget: bool,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        let _lock = self.write_lock(&key);
+        let current = self.current_version(&key)?;
+        if let Some(expected) = expected_version.filter(|expected| *expected != current) {
+            return Err(Error::VersionConflict { expected, current });
+        }
+        let mut batch = WriteBatch::default();
+        match forget {
+            true => self.forget(&mut batch, key.as_ref())?,
+            false => self.retain(&mut batch, key.as_ref(), current, true)?,
+        }
         batch.delete_cf(&cf_handle, &key);
         batch.delete(&key);
+        if let Some(tier_handle) = self.db.cf_handle(TIER_CF) {
+            batch.delete_cf(&tier_handle, &key);
+        }
+        // deleting a key that doesn't exist changes nothing
+        let (deleted, logged) = match current {
+            0 => (Vec::new(), Vec::new()),
+            _ => (
+                vec![Change::delete(key.as_ref(), current)],
+                vec![LogEntry::delete(key.as_ref())],
+            ),
+        };
+        let _reservation = self.capture(&mut batch, &deleted);
+        let _logged = self.log(&mut batch, &logged);
+
+        self.write(batch)
+    }
+
+    // Deletes every key that starts with prefix and returns how many keys were removed. When the
+    // partition publishes changes, logs writes for followers or retains deleted values, every
+    // deleted key has to be recorded at the version it's deleted at, so the keys are deleted one by
+    // one with their stripes held, DELETE_PREFIX_CHUNK keys to a batch, and a key written after it
+    // was scanned is left alone. Otherwise the keys are range deleted, so the count is only
+    // approximate if there are concurrent writes.
+    #[instrument(skip(self, prefix), fields(namespace_id = %self.namespace_id, tenant_id = %self.tenant_id, partition_id = %self.id))]
+    pub fn delete_prefix(&self, prefix: &[u8], dry_run: bool) -> Result<u64, Error> {
+        if !dry_run {
+            self.check_writable()?;
+        }
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        let default_handle = self.db.cf_handle(DEFAULT_COLUMN_FAMILY_NAME).unwrap();
+        let tier_handle = self.db.cf_handle(TIER_CF);
+        let upper_bound = prefix_upper_bound(prefix);
+        let retain_deleted = self.retention().deleted_secs.is_some();
+        let recorded = self.changes.is_some() || self.log.is_some() || retain_deleted;
+        // without an upper bound a range delete can't be expressed, so keys are deleted one by one
+        let by_key = !dry_run && (recorded || upper_bound.is_none());
+
+        if by_key {
+            let mut start = prefix.to_vec();
+            let mut count = 0;
+            loop {
+                let keys = self.prefix_keys(prefix, &start, DELETE_PREFIX_CHUNK)?;
+                count += self.delete_keys(&keys, retain_deleted)?;
+                let Some(last) = keys.last() else {
+                    break;
+                };
+                if keys.len() < DELETE_PREFIX_CHUNK {
+                    break;
+                }
+                // the next chunk starts right after the last key scanned
+                start = last.as_ref().to_vec();
+                start.push(0);
+            }
+            info!(count = count, "deleted keys by prefix");
+            return Ok(count);
+        }
+
+        let mut matched = 0;
+        for item in self.db.iterator_cf(
+            &cf_handle,
+            IteratorMode::From(prefix, rocksdb::Direction::Forward),
+        ) {
+            let (key, _) = item?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            matched += 1;
+        }
+
+        info!(
+            count = matched,
+            dry_run = dry_run,
+            "matched keys for prefix delete"
+        );
+
+        if dry_run || matched == 0 {
+            return Ok(matched);
+        }
+
+        let mut batch = WriteBatch::default();
+        if let Some(upper_bound) = &upper_bound {
+            batch.delete_range_cf(&cf_handle, prefix, upper_bound.as_slice());
+            batch.delete_range_cf(&default_handle, prefix, upper_bound.as_slice());
+            if let Some(tier_handle) = &tier_handle {
+                batch.delete_range_cf(tier_handle, prefix, upper_bound.as_slice());
+            }
+            self.write(batch)?;
+        }
+        Ok(matched)
+    }
+
+    // Returns up to limit keys that start with prefix, from start on
+    fn prefix_keys(&self, prefix: &[u8], start: &[u8], limit: usize) -> Result<Vec<Key>, Error> {
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        let mut keys = Vec::new();
+        for item in self.db.iterator_cf(
+            &cf_handle,
+            IteratorMode::From(start, rocksdb::Direction::Forward),
+        ) {
+            let (key, _) = item?;
+            if !key.starts_with(prefix) || keys.len() == limit {
+                break;
+            }
+            keys.push(Key::from(key.as_ref()));
+        }
+        Ok(keys)
+    }
+
+    // Deletes the keys in one batch with their stripes held, recording each delete at the version
+    // it's deleted at. A key deleted since it was scanned is skipped, the count is of the keys
+    // removed.
+    fn delete_keys(&self, keys: &[Key], retain_deleted: bool) -> Result<u64, Error> {
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        let default_handle = self.db.cf_handle(DEFAULT_COLUMN_FAMILY_NAME).unwrap();
+        let tier_handle = self.db.cf_handle(TIER_CF);
+
+        // the keys' stripes are taken in order, as a transaction takes them
+        let stripes: BTreeSet<usize> = keys.iter().map(|key| self.write_stripe(key)).collect();
+        let _locks: Vec<_> = stripes
+            .into_iter()
+            .map(|stripe| self.lock_stripe(stripe))
+            .collect();
+        let mut batch = WriteBatch::default();
+        let mut changes = Vec::new();
+        let mut logged = Vec::new();
+        let mut count = 0;
+        for key in keys {
+            let Some(metadata) = self.db.get_pinned_cf(&cf_handle, key)? else {
+                continue;
+            };
+            let metadata = EntryMetadata::decode(&metadata).ok_or(Error::UnknownEncoding)?;
+            if self.changes.is_some() {
+                changes.push(Change::delete(key.as_ref(), metadata.version));
+            }
+            if self.log.is_some() {
+                logged.push(LogEntry::delete(key.as_ref()));
+            }
+            if retain_deleted {
+                self.retain(&mut batch, key.as_ref(), metadata.version, true)?;
+            }
+            batch.delete_cf(&cf_handle, key);
+            batch.delete_cf(&default_handle, key);
+            if let Some(tier_handle) = &tier_handle {
+                batch.delete_cf(tier_handle, key);
+            }
+            count += 1;
+        }
+        if count == 0 {
+            return Ok(0);
+        }
+        let _reservation = self.capture(&mut batch, &changes);
+        let _logged = self.log(&mut batch, &logged);
 
-        self.db.write(batch).map_err(|err| Error::RocksDBError(err))
+        self.write(batch)?;
+        Ok(count)
+    }
+
+    // Deletes the expired keys among the next scan_limit keys from start on, in one batch. A key
+    // rewritten since it was scanned is left as it is. A key's bytes are its key, metadata and
+    // value, a tiered value's object is collected by the tier sweep. The deletes are captured and
+    // logged like delete's, a follower's expired keys are deleted by its leader's log.
+    pub fn reap_expired(
+        &self,
+        start: &[u8],
+        scan_limit: usize,
+        now: SystemTime,
+    ) -> Result<Reaped, Error> {
+        self.check_writable()?;
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        let mut reaped = Reaped::default();
+        let mut expired = Vec::new();
+        let iterator = self.db.iterator_cf(
+            &cf_handle,
+            IteratorMode::From(start, rocksdb::Direction::Forward),
+        );
+        for (scanned, item) in iterator.enumerate() {
+            let (key, metadata) = item?;
+            if scanned == scan_limit {
+                reaped.next = Some(key.to_vec());
+                break;
+            }
+            let metadata = EntryMetadata::decode(&metadata).ok_or(Error::UnknownEncoding)?;
+            if metadata.is_expired(now) {
+                expired.push(Key::from(key.as_ref()));
+            }
+        }
+        if expired.is_empty() {
+            return Ok(reaped);
+        }
+
+        // the keys' stripes are taken in order, as a transaction takes them
+        let stripes: BTreeSet<usize> = expired.iter().map(|key| self.write_stripe(key)).collect();
+        let _locks: Vec<_> = stripes
+            .into_iter()
+            .map(|stripe| self.lock_stripe(stripe))
+            .collect();
+        let tier_handle = self.db.cf_handle(TIER_CF);
+        let mut batch = WriteBatch::default();
+        let mut changes = Vec::new();
+        let mut logged = Vec::new();
+        for key in &expired {
+            let Some(metadata) = self.db.get_pinned_cf(&cf_handle, key)? else {
+                continue;
+            };
+            let metadata = EntryMetadata::decode(&metadata).ok_or(Error::UnknownEncoding)?;
+            if !metadata.is_expired(now) {
+                continue;
+            }
+            if self.changes.is_some() {
+                changes.push(Change::delete(key.as_ref(), metadata.version));
+            }
+            if self.log.is_some() {
+                logged.push(LogEntry::delete(key.as_ref()));
+            }
+            let value_len = self.db.get_pinned(key)?.map_or(0, |value| value.len());
+            batch.delete_cf(&cf_handle, key);
+            batch.delete(key);
+            if let Some(tier_handle) = &tier_handle {
+                batch.delete_cf(tier_handle, key);
+            }
+            reaped.keys += 1;
+            reaped.bytes += (key.as_ref().len() + METADATA_LEN + value_len) as u64;
+        }
+        if reaped.keys == 0 {
+            return Ok(reaped);
+        }
+        let _reservation = self.capture(&mut batch, &changes);
+        let _logged = self.log(&mut batch, &logged);
+
+        self.write(batch)?;
+        Ok(reaped)
+    }
+
+    pub fn retention(&self) -> Retention {
+        *self.retention.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Replaces the namespace's retention, creating the history column family the first time it
+    // retains values. Values already retained are purged by the next pass once the new retention
+    // no longer keeps them.
+    pub fn set_retention(&self, retention: Retention) -> Result<(), Error> {
+        if !retention.is_none() && self.db.cf_handle(HISTORY_CF).is_none() {
+            info!(partition_id = self.id.to_string(), "creating history column family");
+            self.db.create_cf(HISTORY_CF, &Options::default())?;
+        }
+        *self.retention.lock().unwrap_or_else(PoisonError::into_inner) = retention;
+        Ok(())
+    }
+
+    // Keeps the key's value at version in the batch's history when the namespace retains deleted
+    // or replaced values. Nothing is kept when the key isn't at version, or its value is tiered.
+    // Only called with the key's write lock held.
+    fn retain(
+        &self,
+        batch: &mut WriteBatch,
+        key: &[u8],
+        version: u32,
+        deleted: bool,
+    ) -> Result<(), Error> {
+        if self.retention().keeps(deleted).is_none() {
+            return Ok(());
+        }
+        let Some(history_handle) = self.db.cf_handle(HISTORY_CF) else {
+            return Ok(());
+        };
+        let Some(metadata) = self.current_metadata(&Key::from(key))? else {
+            return Ok(());
+        };
+        if metadata.version != version {
+            return Ok(());
+        }
+        let Some(value) = self.db.get_pinned(key)? else {
+            return Ok(());
+        };
+        let retained = RetainedValue {
+            retained_at: retention::unix_now(),
+            deleted,
+            metadata,
+            value: value.to_vec(),
+        };
+        batch.put_cf(
+            &history_handle,
+            retention::history_key(key, version),
+            retained.encode(),
+        );
+        Ok(())
+    }
+
+    // Removes every version of the key retained so far in the batch
+    fn forget(&self, batch: &mut WriteBatch, key: &[u8]) -> Result<(), Error> {
+        let Some(history_handle) = self.db.cf_handle(HISTORY_CF) else {
+            return Ok(());
+        };
+        for retained in self.history(key)? {
+            let history_key = retention::history_key(key, retained.metadata.version);
+            batch.delete_cf(&history_handle, history_key);
+        }
+        Ok(())
+    }
+
+    // The key's retained versions, oldest first, including ones past the retention that haven't
+    // been purged yet
+    fn history(&self, key: &[u8]) -> Result<Vec<RetainedValue>, Error> {
+        let Some(history_handle) = self.db.cf_handle(HISTORY_CF) else {
+            return Ok(Vec::new());
+        };
+        let mut history = Vec::new();
+        for item in self.db.iterator_cf(
+            &history_handle,
+            IteratorMode::From(key, rocksdb::Direction::Forward),
+        ) {
+            let (history_key, retained) = item?;
+            if !history_key.starts_with(key) {
+                break;
+            }
+            // a longer key sharing the prefix
+            if history_key.len() != key.len() + 4 {
+                continue;
+            }
+            history.push(RetainedValue::decode(&retained).ok_or(Error::UnknownEncoding)?);
+        }
+        Ok(history)
+    }
+
+    // The key's versions the namespace still retains, oldest first
+    pub fn versions(&self, key: &Key) -> Result<Vec<RetainedValue>, Error> {
+        let retention = self.retention();
+        let now = retention::unix_now();
+        Ok(self
+            .history(key.as_ref())?
+            .into_iter()
+            .filter(|retained| !retained.is_expired(&retention, now))
+            .collect())
+    }
+
+    // Reads a version of the key the namespace retains, NotFound when it isn't retained
+    pub fn get_version(&self, key: &Key, version: u32) -> Result<GetValue, Error> {
+        let Some(history_handle) = self.db.cf_handle(HISTORY_CF) else {
+            return Err(Error::NotFound);
+        };
+        let history_key = retention::history_key(key.as_ref(), version);
+        let Some(retained) = self.db.get_pinned_cf(&history_handle, history_key)? else {
+            return Err(Error::NotFound);
+        };
+        let retained = RetainedValue::decode(&retained).ok_or(Error::UnknownEncoding)?;
+        if retained.is_expired(&self.retention(), retention::unix_now()) {
+            return Err(Error::NotFound);
+        }
+        Ok(GetValue {
+            crc: retained.metadata.crc,
+            version: retained.metadata.version,
+            expiry: retained.metadata.expiry(),
+            value: retained.value,
+            tiered: None,
+        })
+    }
+
+    // Purges the retained values the namespace's retention no longer keeps as of now, in unix
+    // seconds, and returns how many there were
+    pub fn purge_retained(&self, now: u64) -> Result<usize, Error> {
+        let Some(history_handle) = self.db.cf_handle(HISTORY_CF) else {
+            return Ok(0);
+        };
+        let retention = self.retention();
+        let mut batch = WriteBatch::default();
+        let mut purged = 0;
+        for item in self.db.iterator_cf(&history_handle, IteratorMode::Start) {
+            let (history_key, retained) = item?;
+            let retained = RetainedValue::decode(&retained).ok_or(Error::UnknownEncoding)?;
+            if retained.is_expired(&retention, now) {
+                batch.delete_cf(&history_handle, history_key);
+                purged += 1;
+            }
+        }
+        if purged > 0 {
+            self.db.write(batch)?;
+        }
+        Ok(purged)
+    }
+
+    fn column_families(&self) -> Vec<&'static str> {
+        let mut column_families = vec![DEFAULT_COLUMN_FAMILY_NAME, "metadata"];
+        for cf_name in [TIER_CF, CHANGES_CF, HISTORY_CF, REPLICATION_CF] {
+            if self.db.cf_handle(cf_name).is_some() {
+                column_families.push(cf_name);
+            }
+        }
+        column_families
+    }
+
+    // Records the changes in the batch when they're captured. The sequence numbers they're recorded
+    // under stay in flight until the returned reservation is dropped, after the batch is written.
+    fn capture(&self, batch: &mut WriteBatch, changes: &[Change]) -> Option<Reservation<'_>> {
+        let sequencer = self.changes.as_ref().filter(|_| !changes.is_empty())?;
+        let cf_handle = self.db.cf_handle(CHANGES_CF).unwrap();
+        let reservation = sequencer.reserve(changes.len());
+        for (sequence, change) in (reservation.first..).zip(changes) {
+            batch.put_cf(&cf_handle, sequence.to_be_bytes(), change.encode());
+        }
+        Some(reservation)
+    }
+
+    // Drops the changes recorded but not yet published, for a partition copied from another whose
+    // changes they are
+    pub fn discard_changes(&self) -> Result<(), Error> {
+        let Some(cf_handle) = self.db.cf_handle(CHANGES_CF) else {
+            return Ok(());
+        };
+        let mut batch = WriteBatch::default();
+        batch.delete_range_cf(&cf_handle, 0u64.to_be_bytes(), u64::MAX.to_be_bytes());
+        Ok(self.db.write(batch)?)
+    }
+
+    // Up to limit of the oldest changes whose writes have all settled, with their sequence numbers
+    pub fn pending_changes(&self, limit: usize) -> Result<Vec<(u64, Change)>, Error> {
+        let Some(sequencer) = &self.changes else {
+            return Ok(Vec::new());
+        };
+        // read before the iterator is created, so every change below it is already written
+        let settled_before = sequencer.settled_before();
+        let cf_handle = self.db.cf_handle(CHANGES_CF).unwrap();
+        let mut changes = Vec::new();
+        for item in self.db.iterator_cf(&cf_handle, IteratorMode::Start) {
+            let (sequence, change) = item?;
+            let sequence = decode_sequence(&sequence)?;
+            if sequence >= settled_before || changes.len() == limit {
+                break;
+            }
+            changes.push((
+                sequence,
+                Change::decode(&change).ok_or(Error::UnknownEncoding)?,
+            ));
+        }
+        Ok(changes)
+    }
+
+    // Clears the changes up to and including through once they're published
+    pub fn clear_changes(&self, through: u64) -> Result<(), Error> {
+        let cf_handle = self.db.cf_handle(CHANGES_CF).unwrap();
+        let mut batch = WriteBatch::default();
+        batch.delete_range_cf(&cf_handle, 0u64.to_be_bytes(), (through + 1).to_be_bytes());
+        Ok(self.db.write(batch)?)
+    }
+
+    // The admin endpoint of the leader the partition follows, None for a leader
+    pub fn leader(&self) -> Option<String> {
+        self.leader
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn set_leader(&self, leader: Option<String>) {
+        *self.leader.lock().unwrap_or_else(PoisonError::into_inner) = leader;
+    }
+
+    // Writes to a follower only come from its leader's log
+    pub fn check_writable(&self) -> Result<(), Error> {
+        match self.leader() {
+            Some(leader) => Err(Error::FollowerPartition { leader }),
+            None => Ok(()),
+        }
+    }
+
+    // Records the writes in the batch for followers when writes are logged, like capture
+    fn log(&self, batch: &mut WriteBatch, entries: &[LogEntry]) -> Option<Reservation<'_>> {
+        let sequencer = self.log.as_ref().filter(|_| !entries.is_empty())?;
+        let cf_handle = self.db.cf_handle(REPLICATION_CF).unwrap();
+        let reservation = sequencer.reserve(entries.len());
+        for (sequence, entry) in (reservation.first..).zip(entries) {
+            batch.put_cf(&cf_handle, sequence.to_be_bytes(), entry.encode());
+        }
+        Some(reservation)
+    }
+
+    // One past the last entry of the replication log whose write has settled, None when writes
+    // aren't logged
+    pub fn log_next(&self) -> Option<u64> {
+        self.log.as_ref().map(|sequencer| sequencer.settled_before())
+    }
+
+    // Up to limit of the log's settled entries from the sequence number from on, and one past the
+    // last settled entry. A write that failed leaves a gap in the sequence numbers, so the entries
+    // may skip some.
+    pub fn read_log(&self, from: u64, limit: usize) -> Result<(Vec<(u64, LogEntry)>, u64), Error> {
+        let sequencer = self.log.as_ref().ok_or(Error::ReplicationDisabled)?;
+        // read before the iterator is created, like pending_changes
+        let next = sequencer.settled_before();
+        if from > next {
+            return Err(Error::AheadOfLeader { from, next });
+        }
+        let first = log_start(&self.db)?;
+        if from < first {
+            return Err(Error::LogTrimmed { from, first });
+        }
+        let cf_handle = self.db.cf_handle(REPLICATION_CF).unwrap();
+        let mut entries = Vec::new();
+        for item in self.db.iterator_cf(
+            &cf_handle,
+            IteratorMode::From(&from.to_be_bytes(), rocksdb::Direction::Forward),
+        ) {
+            let (sequence, entry) = item?;
+            let sequence = decode_sequence(&sequence)?;
+            if sequence >= next || entries.len() == limit {
+                break;
+            }
+            entries.push((sequence, LogEntry::decode(&entry).ok_or(Error::UnknownEncoding)?));
+        }
+        Ok((entries, next))
+    }
+
+    // Applies entries read from the leader's log in one batch and records them in this partition's
+    // log under the same sequence numbers. They have to come after the entries already applied.
+    // Versions a batch replaces within itself aren't retained, and the changes aren't captured
+    // since the leader publishes them.
+    pub fn apply_log(&self, entries: &[(u64, LogEntry)]) -> Result<(), Error> {
+        let sequencer = self.log.as_ref().ok_or(Error::ReplicationDisabled)?;
+        // the keys' stripes are taken in order, as a transaction takes them
+        let stripes: BTreeSet<usize> = entries
+            .iter()
+            .map(|(_, entry)| self.write_stripe(&Key::from(entry.key.as_slice())))
+            .collect();
+        let _locks: Vec<_> = stripes
+            .into_iter()
+            .map(|stripe| self.lock_stripe(stripe))
+            .collect();
+
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        let log_handle = self.db.cf_handle(REPLICATION_CF).unwrap();
+        let tier_handle = self.db.cf_handle(TIER_CF);
+        let mut batch = WriteBatch::default();
+        // whether each key the batch writes exists once the batch is written
+        let mut written: HashMap<&[u8], bool> = HashMap::new();
+        let mut next = sequencer.settled_before();
+        for (sequence, entry) in entries {
+            if *sequence < next {
+                return Err(Error::LogGap {
+                    expected: next,
+                    found: *sequence,
+                });
+            }
+            next = sequence + 1;
+            let key = entry.key.as_slice();
+            match &entry.op {
+                LogOp::Put { metadata, value } => {
+                    self.retain(&mut batch, key, metadata.version.wrapping_sub(1), false)?;
+                    batch.put_cf(&cf_handle, key, metadata.encode());
+                    batch.put(key, value);
+                    if let Some(tier_handle) = &tier_handle {
+                        batch.put_cf(tier_handle, key, TierEntry::accessed_now(None).encode());
+                    }
+                    written.insert(key, true);
+                }
+                // a key this node has already reaped stays deleted
+                LogOp::Touch(metadata) => {
+                    let exists = match written.get(key) {
+                        Some(exists) => *exists,
+                        None => self.db.get_pinned_cf(&cf_handle, key)?.is_some(),
+                    };
+                    if exists {
+                        batch.put_cf(&cf_handle, key, metadata.encode());
+                    }
+                }
+                LogOp::Delete => {
+                    let current = self.current_version(&Key::from(key))?;
+                    self.retain(&mut batch, key, current, true)?;
+                    batch.delete_cf(&cf_handle, key);
+                    batch.delete(key);
+                    if let Some(tier_handle) = &tier_handle {
+                        batch.delete_cf(tier_handle, key);
+                    }
+                    written.insert(key, false);
+                }
+            }
+            batch.put_cf(&log_handle, sequence.to_be_bytes(), entry.encode());
+        }
+        self.write(batch)?;
+        sequencer.advance_to(next);
+        Ok(())
+    }
+
+    // Drops the log's entries before its latest keep, a follower that hasn't read them has to be
+    // copied from the leader again
+    pub fn trim_log(&self, keep: u64) -> Result<(), Error> {
+        let Some(next) = self.log_next() else {
+            return Ok(());
+        };
+        let start = next.saturating_sub(keep);
+        if start <= log_start(&self.db)? {
+            return Ok(());
+        }
+        let cf_handle = self.db.cf_handle(REPLICATION_CF).unwrap();
+        let mut batch = WriteBatch::default();
+        batch.delete_range_cf(&cf_handle, 0u64.to_be_bytes(), start.to_be_bytes());
+        batch.put_cf(&cf_handle, LOG_START_KEY, start.to_be_bytes());
+        Ok(self.db.write(batch)?)
+    }
+
+    // Replaces the partition's keys, retained values, tiering state and replication log with a
+    // checkpoint of its leader's partition, the leader's log is then applied from the end of the
+    // checkpoint's. The checkpoint is copied in batches, so reads see a mix of the partition's old
+    // keys and the leader's until it's done. Like a cloned namespace's, a tiered value stays in the
+    // leader's object.
+    pub fn install_checkpoint(&self, checkpoint: &DB) -> Result<(), Error> {
+        let sequencer = self.log.as_ref().ok_or(Error::ReplicationDisabled)?;
+        // nothing else writes to the partition while it's replaced
+        let _locks: Vec<_> = (0..self.write_locks.len())
+            .map(|stripe| self.lock_stripe(stripe))
+            .collect();
+        let column_families = [
+            DEFAULT_COLUMN_FAMILY_NAME,
+            "metadata",
+            TIER_CF,
+            HISTORY_CF,
+            REPLICATION_CF,
+        ];
+        for cf_name in column_families {
+            self.replace_cf(checkpoint, cf_name)?;
+        }
+        let next = next_sequence(checkpoint, REPLICATION_CF)?.max(log_start(checkpoint)?);
+        sequencer.advance_to(next);
+        info!(
+            partition_id = self.id.to_string(),
+            next_sequence = next,
+            "installed leader's checkpoint"
+        );
+        Ok(())
+    }
+
+    // Replaces the column family's entries with the checkpoint's, it ends up empty when the
+    // checkpoint doesn't have it
+    fn replace_cf(&self, checkpoint: &DB, cf_name: &str) -> Result<(), Error> {
+        let source = checkpoint.cf_handle(cf_name);
+        if self.db.cf_handle(cf_name).is_none() {
+            if source.is_none() {
+                return Ok(());
+            }
+            self.db.create_cf(cf_name, &Options::default())?;
+        }
+        let cf_handle = self.db.cf_handle(cf_name).unwrap();
+        let mut batch = WriteBatch::default();
+        // every key sorts at or after the empty key, and before the last key with a 0 appended
+        let last = self.db.iterator_cf(&cf_handle, IteratorMode::End).next().transpose()?;
+        if let Some((last, _)) = last {
+            batch.delete_range_cf(&cf_handle, Vec::new(), [last.as_ref(), &[0]].concat());
+        }
+        let entries = source
+            .iter()
+            .flat_map(|source| checkpoint.iterator_cf(source, IteratorMode::Start));
+        for item in entries {
+            let (key, value) = item?;
+            batch.put_cf(&cf_handle, key, value);
+            if batch.size_in_bytes() >= INSTALL_BATCH_BYTES {
+                self.db.write(std::mem::take(&mut batch))?;
+            }
+        }
+        Ok(self.db.write(batch)?)
+    }
+
+    // A hash tree over the partition's keys and their metadata, see merkle. Expired keys are in it
+    // until they're reaped.
+    pub fn merkle_tree(&self) -> Result<MerkleTree, Error> {
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        let mut builder = MerkleBuilder::default();
+        for item in self.db.iterator_cf(&cf_handle, IteratorMode::Start) {
+            let (key, metadata) = item?;
+            builder.add(&key, &metadata);
+        }
+        Ok(builder.finish())
+    }
+
+    // The keys in the tree's leaves with their metadata as it's stored, and with_values their
+    // values unless they're tiered
+    pub fn leaf_entries(
+        &self,
+        leaves: &HashSet<u32>,
+        with_values: bool,
+    ) -> Result<Vec<RawEntry>, Error> {
+        let metadata_handle = self.db.cf_handle("metadata").unwrap();
+        let default_handle = self.db.cf_handle(DEFAULT_COLUMN_FAMILY_NAME).unwrap();
+        let mut entries = Vec::new();
+        for item in self.db.iterator_cf(&metadata_handle, IteratorMode::Start) {
+            let (key, metadata) = item?;
+            if !leaves.contains(&merkle::leaf(&key)) {
+                continue;
+            }
+            let value = match with_values {
+                true => self.db.get_cf(&default_handle, &key)?,
+                false => None,
+            };
+            entries.push(RawEntry {
+                key,
+                metadata,
+                value,
+            });
+        }
+        Ok(entries)
+    }
+
+    // Puts the leader's keys as they're stored on it and deletes the keys it doesn't have, returns
+    // how many were changed. A key is only changed while its digest is still the one the follower
+    // sent the leader, one its log has changed since is left to the next repair. Repairs aren't
+    // logged, the follower's log only has its leader's entries, and the values they replace
+    // aren't retained.
+    pub fn repair(
+        &self,
+        puts: &[RawEntry],
+        deletes: &[Vec<u8>],
+        sent: &HashMap<Vec<u8>, merkle::Hash>,
+    ) -> Result<usize, Error> {
+        // the keys' stripes are taken in order, like apply_log
+        let stripes: BTreeSet<usize> = puts
+            .iter()
+            .map(|entry| entry.key.as_ref())
+            .chain(deletes.iter().map(Vec::as_slice))
+            .map(|key| self.write_stripe(&Key::from(key)))
+            .collect();
+        let _locks: Vec<_> = stripes
+            .into_iter()
+            .map(|stripe| self.lock_stripe(stripe))
+            .collect();
+
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        let tier_handle = self.db.cf_handle(TIER_CF);
+        let unchanged = |key: &[u8]| -> Result<bool, Error> {
+            let current = self.db.get_pinned_cf(&cf_handle, key)?;
+            Ok(current.map(|metadata| merkle::digest(key, &metadata)).as_ref() == sent.get(key))
+        };
+        let mut batch = WriteBatch::default();
+        let mut repaired = 0;
+        for entry in puts {
+            let key = entry.key.as_ref();
+            let Some(value) = &entry.value else {
+                continue;
+            };
+            if !unchanged(key)? {
+                continue;
+            }
+            EntryMetadata::decode(&entry.metadata).ok_or(Error::UnknownEncoding)?;
+            batch.put_cf(&cf_handle, key, &entry.metadata);
+            batch.put(key, value);
+            if let Some(tier_handle) = &tier_handle {
+                batch.put_cf(tier_handle, key, TierEntry::accessed_now(None).encode());
+            }
+            repaired += 1;
+        }
+        for key in deletes {
+            if !unchanged(key)? {
+                continue;
+            }
+            batch.delete_cf(&cf_handle, key);
+            batch.delete(key);
+            if let Some(tier_handle) = &tier_handle {
+                batch.delete_cf(tier_handle, key);
+            }
+            repaired += 1;
+        }
+        self.write(batch)?;
+        Ok(repaired)
+    }
+
+    // Returns rocksdb's estimates of the number of keys and the bytes used by the partition
+    #[instrument(skip(self), fields(namespace_id = %self.namespace_id, tenant_id = %self.tenant_id, partition_id = %self.id))]
+    pub fn stats(&self) -> Result<Stats, Error> {
+        let mut stats = Stats::default();
+        for cf_name in self.column_families() {
+            let cf_handle = self.db.cf_handle(cf_name).unwrap();
+            stats.total_bytes += self
+                .db
+                .property_int_value_cf(&cf_handle, properties::ESTIMATE_LIVE_DATA_SIZE)?
+                .unwrap_or(0);
+            stats.total_bytes += self
+                .db
+                .property_int_value_cf(&cf_handle, properties::CUR_SIZE_ALL_MEM_TABLES)?
+                .unwrap_or(0);
+        }
+
+        // every key has exactly one metadata entry so that column family gives the key count
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        stats.key_count = self
+            .db
+            .property_int_value_cf(&cf_handle, properties::ESTIMATE_NUM_KEYS)?
+            .unwrap_or(0);
+
+        Ok(stats)
+    }
+
+    // Counts the keys starting with prefix, all of them when it's empty, leaving out expired keys
+    #[instrument(skip(self, prefix), fields(namespace_id = %self.namespace_id, tenant_id = %self.tenant_id, partition_id = %self.id))]
+    pub fn count_keys(&self, prefix: &[u8]) -> Result<u64, Error> {
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        let now = SystemTime::now();
+        let mut count = 0;
+        for item in self.db.iterator_cf(
+            &cf_handle,
+            IteratorMode::From(prefix, rocksdb::Direction::Forward),
+        ) {
+            let (key, metadata) = item?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            let metadata = EntryMetadata::decode(&metadata).ok_or(Error::UnknownEncoding)?;
+            if !metadata.is_expired(now) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // Up to count distinct keys, each found by seeking to a random point between the partition's
+    // first and last keys. A key that follows a large gap in the key space is more likely to be
+    // picked, so the sample is only roughly uniform, but it never reads more than count keys' worth
+    // of seeks.
+    #[instrument(skip(self), fields(namespace_id = %self.namespace_id, tenant_id = %self.tenant_id, partition_id = %self.id))]
+    pub fn sample_keys(&self, count: usize) -> Result<Vec<KeyMetadata>, Error> {
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        let first = self.db.iterator_cf(&cf_handle, IteratorMode::Start).next().transpose()?;
+        let last = self.db.iterator_cf(&cf_handle, IteratorMode::End).next().transpose()?;
+        let (Some((first, _)), Some((last, _))) = (first, last) else {
+            return Ok(Vec::new());
+        };
+
+        let mut rng = rand::thread_rng();
+        let now = SystemTime::now();
+        let mut sampled = BTreeMap::new();
+        // repeats and expired keys are skipped, so more seeks than keys are allowed
+        for _ in 0..count * 4 {
+            if sampled.len() == count {
+                break;
+            }
+            let target = random_key_between(&first, &last, &mut rng);
+            let mut iter = self.db.iterator_cf(
+                &cf_handle,
+                IteratorMode::From(&target, rocksdb::Direction::Forward),
+            );
+            // past the last key wraps around to the first
+            let (key, metadata) = match iter.next().transpose()? {
+                Some(entry) => entry,
+                None => match self.db.iterator_cf(&cf_handle, IteratorMode::Start).next() {
+                    Some(entry) => entry?,
+                    None => break,
+                },
+            };
+            let metadata = EntryMetadata::decode(&metadata).ok_or(Error::UnknownEncoding)?;
+            if metadata.is_expired(now) {
+                continue;
+            }
+            sampled
+                .entry(key.to_vec())
+                .or_insert_with(|| key_metadata(&key, &metadata));
+        }
+
+        Ok(sampled.into_values().collect())
+    }
+
+    // rocksdb's estimate of the bytes compactions would rewrite to bring the partition's levels back
+    // under their target sizes
+    pub fn pending_compaction_bytes(&self) -> Result<u64, Error> {
+        let mut pending = 0;
+        for cf_name in self.column_families() {
+            let cf_handle = self.db.cf_handle(cf_name).unwrap();
+            pending += self
+                .db
+                .property_int_value_cf(&cf_handle, properties::ESTIMATE_PENDING_COMPACTION_BYTES)?
+                .unwrap_or(0);
+        }
+        Ok(pending)
+    }
+
+    // Errors from flushes and compactions, rocksdb stops accepting writes after one
+    pub fn background_errors(&self) -> Result<u64, Error> {
+        Ok(self
+            .db
+            .property_int_value(properties::BACKGROUND_ERRORS)?
+            .unwrap_or(0))
+    }
+
+    // Compacts every column family over their whole key range, blocking until it's done
+    #[instrument(skip(self), fields(namespace_id = %self.namespace_id, tenant_id = %self.tenant_id, partition_id = %self.id))]
+    pub fn compact(&self) {
+        for cf_name in self.column_families() {
+            let cf_handle = self.db.cf_handle(cf_name).unwrap();
+            self.db
+                .compact_range_cf(&cf_handle, None::<&[u8]>, None::<&[u8]>);
+        }
+    }
+
+    // Writes the write ahead log and the memtables out to disk
+    pub fn flush(&self) -> Result<(), Error> {
+        self.db.flush_wal(true)?;
+        for cf_name in self.column_families() {
+            let cf_handle = self.db.cf_handle(cf_name).unwrap();
+            self.db.flush_cf(&cf_handle)?;
+        }
+        Ok(())
+    }
+
+    // Reads keys with their metadata and values as they're stored, for debugging. Every
+    // sample_every-th key from start_at up to end_before is returned, up to limit of them, along
+    // with the key the next page starts at.
+    #[instrument(skip(self, start_at, end_before), fields(namespace_id = %self.namespace_id, tenant_id = %self.tenant_id, partition_id = %self.id))]
+    pub fn dump(
+        &self,
+        start_at: Option<&[u8]>,
+        end_before: Option<&[u8]>,
+        sample_every: usize,
+        limit: usize,
+    ) -> Result<(Vec<RawEntry>, Option<Vec<u8>>), Error> {
+        let metadata_handle = self.db.cf_handle("metadata").unwrap();
+        let default_handle = self.db.cf_handle(DEFAULT_COLUMN_FAMILY_NAME).unwrap();
+        let mode = match start_at {
+            Some(start_at) => IteratorMode::From(start_at, rocksdb::Direction::Forward),
+            None => IteratorMode::Start,
+        };
+
+        let mut entries = Vec::new();
+        for (index, item) in self.db.iterator_cf(&metadata_handle, mode).enumerate() {
+            let (key, metadata) = item?;
+            if end_before.is_some_and(|end_before| key.as_ref() >= end_before) {
+                break;
+            }
+            if index % sample_every != 0 {
+                continue;
+            }
+            if entries.len() == limit {
+                return Ok((entries, Some(key.to_vec())));
+            }
+            let value = self.db.get_cf(&default_handle, &key)?;
+            entries.push(RawEntry {
+                key,
+                metadata,
+                value,
+            });
+        }
+        Ok((entries, None))
+    }
+
+    // Takes a rocksdb checkpoint of the partition, hard linking its files where it can, into a
+    // directory that must not exist yet. Returns the sequence number the checkpoint is at.
+    #[instrument(skip(self), fields(namespace_id = %self.namespace_id, tenant_id = %self.tenant_id, partition_id = %self.id))]
+    pub fn checkpoint(&self, dir: &Path) -> Result<u64, Error> {
+        let sequence_number = self.db.latest_sequence_number();
+        Checkpoint::new(&self.db)?.create_checkpoint(dir)?;
+        format::set_version(dir, format::version(&self.path)?)?;
+        Ok(sequence_number)
+    }
+
+    // Calls f with every key in the snapshot of this partition, in order, along with its metadata
+    // and value. Expired keys are left out. The scan stops early when f returns false.
+    pub fn scan<F>(&self, pinned: &PinnedSnapshot, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&[u8], &EntryMetadata, ScanValue) -> Result<bool, Error>,
+    {
+        let cf_handle = self.db.cf_handle("metadata").unwrap();
+        let tier_handle = self.db.cf_handle(TIER_CF);
+        let now = SystemTime::now();
+        for item in pinned.snapshot.iterator_cf(&cf_handle, IteratorMode::Start) {
+            let (key, metadata) = item?;
+            let metadata = EntryMetadata::decode(&metadata).ok_or(Error::UnknownEncoding)?;
+            if metadata.is_expired(now) {
+                continue;
+            }
+            // the metadata and value are written together, a key only has one without the other
+            // once its value is tiered
+            let keep_going = match pinned.snapshot.get_pinned(&key)? {
+                Some(value) => f(&key, &metadata, ScanValue::Local(&value))?,
+                None => {
+                    let entry = match &tier_handle {
+                        Some(tier_handle) => pinned.snapshot.get_pinned_cf(tier_handle, &key)?,
+                        None => None,
+                    };
+                    let object = entry
+                        .map(|entry| TierEntry::decode(&entry).ok_or(Error::UnknownEncoding))
+                        .transpose()?
+                        .and_then(|entry| entry.object);
+                    match object {
+                        Some(object) => f(&key, &metadata, ScanValue::Tiered(object))?,
+                        None => true,
+                    }
+                }
+            };
+            if !keep_going {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // The partition as it is now, kept until the returned snapshot is dropped. Rocksdb keeps every
+    // version of a key a snapshot can see, so snapshots shouldn't be held for long.
+    pub fn pin_snapshot(&self) -> PinnedSnapshot {
+        let db = self.db.clone();
+        // the snapshot borrows the db, which lives as long as the Arc held alongside it and is
+        // only dropped after the snapshot
+        let snapshot = unsafe {
+            std::mem::transmute::<Snapshot<'_>, Snapshot<'static>>(db.snapshot())
+        };
+        PinnedSnapshot {
+            snapshot,
+            _db: db,
+            partition_id: self.id,
+        }
     }
 
     #[instrument(skip(self, opts), fields(namespace_id = %self.namespace_id, tenant_id = %self.tenant_id, partition_id = %self.id))]
@@ -262,26 +1855,43 @@ impl Partition {
         info!("listing keys");
         let cf_handle = self.db.cf_handle("metadata").unwrap();
 
-        let iter = match opts.start_at {
-            Some(start_at) => self.db.iterator_cf(
-                &cf_handle,
-                IteratorMode::From(start_at.as_bytes(), rocksdb::Direction::Forward),
-            ),
-            None => self.db.iterator_cf(&cf_handle, IteratorMode::Start),
+        let mode = match opts.start_at {
+            Some(start_at) => IteratorMode::From(start_at, rocksdb::Direction::Forward),
+            None => IteratorMode::Start,
+        };
+        let snapshot = opts
+            .snapshot
+            .filter(|pinned| pinned.partition_id == self.id);
+        let iter = match snapshot {
+            Some(pinned) => pinned.snapshot.iterator_cf(&cf_handle, mode),
+            None => self.db.iterator_cf(&cf_handle, mode),
         };
 
         let mut results = Vec::new();
+        let limit = opts.limit.unwrap_or(50);
+        let now = SystemTime::now();
 
-        for item in iter.take(opts.limit.unwrap_or(50)) {
+        for item in iter {
+            if results.len() == limit {
+                break;
+            }
             let (key, metadata) = item?;
-            results.push(KeyMetadata {
-                key: key.to_vec(),
-                metadata: Some(Metadata {
-                    crc: u32::from_be_bytes(metadata[..4].try_into().unwrap()),
-                    version: u32::from_be_bytes(metadata[4..].try_into().unwrap()),
-                    creation_time: None,
-                }),
-            });
+            let metadata = EntryMetadata::decode(&metadata).ok_or(Error::UnknownEncoding)?;
+            if metadata.is_expired(now) {
+                continue;
+            }
+            let mut item = key_metadata(&key, &metadata);
+            if let Some(max_value_size) = opts.max_value_size {
+                // the value is read from the same point in time as its metadata
+                let value = match snapshot {
+                    Some(pinned) => pinned.snapshot.get_pinned(&key)?,
+                    None => self.db.get_pinned(&key)?,
+                };
+                item.value = value
+                    .filter(|value| value.len() <= max_value_size)
+                    .map(|value| value.to_vec());
+            }
+            results.push(item);
         }
 
         info!(result_size = results.len(), "finished listing keys");
@@ -289,3 +1899,68 @@ impl Partition {
         Ok(results.as_slice().into())
     }
 }
+
+// The sequence number the column family's next entry is recorded under, one past the last one left
+fn next_sequence(db: &DB, cf_name: &str) -> Result<u64, Error> {
+    let cf_handle = db.cf_handle(cf_name).unwrap();
+    match db.iterator_cf(&cf_handle, IteratorMode::End).next().transpose()? {
+        Some((sequence, _)) if sequence.as_ref() != LOG_START_KEY => {
+            Ok(decode_sequence(&sequence)? + 1)
+        }
+        _ => Ok(0),
+    }
+}
+
+// The first sequence number the replication log still has, or had before the entries after it
+// were written
+fn log_start(db: &DB) -> Result<u64, Error> {
+    let cf_handle = db.cf_handle(REPLICATION_CF).unwrap();
+    match db.get_pinned_cf(&cf_handle, LOG_START_KEY)? {
+        Some(start) => decode_sequence(&start),
+        None => Ok(0),
+    }
+}
+
+fn decode_sequence(sequence: &[u8]) -> Result<u64, Error> {
+    Ok(u64::from_be_bytes(
+        sequence.try_into().map_err(|_| Error::UnknownEncoding)?,
+    ))
+}
+
+fn key_metadata(key: &[u8], metadata: &EntryMetadata) -> KeyMetadata {
+    KeyMetadata {
+        key: key.to_vec(),
+        metadata: Some(Metadata {
+            crc: metadata.crc,
+            version: metadata.version,
+            creation_time: None,
+            expires_at: metadata.expiry().map(Timestamp::from),
+        }),
+        value: None,
+    }
+}
+
+// A key at a random point between first and last, which are in order. The random bytes after the
+// first byte the keys differ in make it land between keys that only differ further along.
+fn random_key_between(first: &[u8], last: &[u8], rng: &mut impl Rng) -> Vec<u8> {
+    let shared = first.iter().zip(last).take_while(|(a, b)| a == b).count();
+    let mut key = first[..shared].to_vec();
+    let low = first.get(shared).copied().unwrap_or(0);
+    let high = last.get(shared).copied().unwrap_or(u8::MAX);
+    key.push(rng.gen_range(low..=high));
+    key.extend((0..8).map(|_| rng.gen::<u8>()));
+    key
+}
+
+// Returns the smallest key that is greater than every key starting with prefix, or None if there
+// isn't one (the prefix is empty or all 0xff bytes)
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper_bound = prefix.to_vec();
+    while let Some(last) = upper_bound.pop() {
+        if last < u8::MAX {
+            upper_bound.push(last + 1);
+            return Some(upper_bound);
+        }
+    }
+    None
+}
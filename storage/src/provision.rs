@@ -0,0 +1,26 @@
+// a namespace is never created with more partitions than this, however large its size hint
+pub const MAX_PARTITIONS: u64 = 256;
+
+// How many partitions a new namespace starts with on this node. Keys are routed to a partition by
+// hashing them across the namespace's partitions, so the count is fixed once the namespace has
+// keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProvisionSettings {
+    // created when the namespace doesn't say how large it's expected to grow
+    pub default_partitions: u64,
+    // a namespace with a size hint gets a partition for every this many bytes of it
+    pub target_partition_size: u64,
+}
+
+impl ProvisionSettings {
+    // The partitions to create for a namespace expected to hold size_hint bytes, at least one and
+    // at most MAX_PARTITIONS
+    pub fn partitions(&self, size_hint: Option<u64>) -> u64 {
+        match size_hint {
+            Some(bytes) => bytes
+                .div_ceil(self.target_partition_size)
+                .clamp(1, MAX_PARTITIONS),
+            None => self.default_partitions,
+        }
+    }
+}
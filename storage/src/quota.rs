@@ -0,0 +1,48 @@
+use crate::error::Error;
+use crate::partition::Stats;
+use common::storage::NamespaceQuota;
+use serde::{Deserialize, Serialize};
+
+// A namespace's limits on this node, unset limits are unlimited. They're checked against rocksdb's
+// estimate of the namespace's usage, so a namespace can end up slightly over its quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_keys: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl From<&NamespaceQuota> for Quota {
+    fn from(value: &NamespaceQuota) -> Self {
+        Quota {
+            max_keys: value.max_keys,
+            max_bytes: value.max_bytes,
+        }
+    }
+}
+
+impl Quota {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_keys.is_none() && self.max_bytes.is_none()
+    }
+
+    // Whether a put of value_len bytes fits, new_key is false when the put overwrites a key
+    pub fn check(&self, usage: &Stats, new_key: bool, value_len: u64) -> Result<(), Error> {
+        if let Some(max_keys) = self.max_keys {
+            if new_key && usage.key_count >= max_keys {
+                return Err(Error::QuotaExceeded {
+                    limit: "max_keys",
+                    quota: max_keys,
+                });
+            }
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            if usage.total_bytes + value_len > max_bytes {
+                return Err(Error::QuotaExceeded {
+                    limit: "max_bytes",
+                    quota: max_bytes,
+                });
+            }
+        }
+        Ok(())
+    }
+}
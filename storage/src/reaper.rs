@@ -0,0 +1,90 @@
+use crate::error::Error;
+use crate::lookup::PartitionLookup;
+use crate::metrics;
+use crate::partition::Partition;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReaperSettings {
+    // how often every partition is swept for expired keys
+    pub interval: Duration,
+    // keys scanned by each batch, a batch's expired keys are deleted in one write
+    pub batch_size: usize,
+    // deletes are spread out so a sweep doesn't compete with the node's traffic
+    pub max_deletes_per_sec: u64,
+}
+
+// Deletes expired keys in the background. Expired keys already read as missing, but without the
+// reaper they'd keep their space until they're overwritten. Every partition's metadata is scanned in
+// batches, a pause after each batch keeps the deletes under max_deletes_per_sec.
+//
+// Expiring isn't a delete, so reaped keys aren't retained by the namespace's retention, but they're
+// published and logged for followers as deletes. Only leaders are swept, a follower's expired keys
+// go when it applies its leader's deletes.
+pub async fn run(settings: ReaperSettings, partition_lookup: Arc<PartitionLookup>) {
+    info!(
+        interval_secs = settings.interval.as_secs(),
+        "reaping expired keys"
+    );
+    let mut ticker = tokio::time::interval(settings.interval);
+    loop {
+        ticker.tick().await;
+        for partition in partition_lookup.all_partitions() {
+            if partition.leader().is_some() {
+                continue;
+            }
+            let id = partition.id;
+            match reap(&settings, &partition_lookup, partition).await {
+                Ok((0, _)) => {}
+                Ok((keys, bytes)) => info!(
+                    partition_id = id.to_string(),
+                    keys = keys,
+                    bytes = bytes,
+                    "reaped expired keys"
+                ),
+                Err(err) => error!(
+                    partition_id = id.to_string(),
+                    err = err.to_string(),
+                    "failed to reap expired keys"
+                ),
+            }
+        }
+    }
+}
+
+// Sweeps the partition from its first key to its last, returns the keys and bytes reaped
+async fn reap(
+    settings: &ReaperSettings,
+    partition_lookup: &Arc<PartitionLookup>,
+    partition: Partition,
+) -> Result<(u64, u64), Error> {
+    let mut start = Vec::new();
+    let (mut keys, mut bytes) = (0, 0);
+    loop {
+        let batch = {
+            let partition_lookup = partition_lookup.clone();
+            let partition = partition.clone();
+            let batch_size = settings.batch_size;
+            tokio::task::spawn_blocking(move || {
+                let _writes = partition_lookup.write_permit();
+                partition.reap_expired(&start, batch_size, SystemTime::now())
+            })
+            .await
+            .map_err(io::Error::other)??
+        };
+        if batch.keys > 0 {
+            metrics::record_expired_reaped(batch.keys, batch.bytes);
+            keys += batch.keys;
+            bytes += batch.bytes;
+        }
+        let Some(next) = batch.next else {
+            return Ok((keys, bytes));
+        };
+        start = next;
+        let pause = batch.keys as f64 / settings.max_deletes_per_sec as f64;
+        tokio::time::sleep(Duration::from_secs_f64(pause)).await;
+    }
+}
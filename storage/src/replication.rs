@@ -0,0 +1,822 @@
+use crate::error::Error;
+use crate::format::{self, EntryMetadata, METADATA_LEN};
+use crate::fsck;
+use crate::lookup::PartitionLookup;
+use crate::merkle::{self, MerkleTree};
+use crate::metrics;
+use crate::partition::{Partition, RawEntry};
+use common::storage::node_admin_client::NodeAdminClient;
+use common::storage::{
+    CheckpointChunk, KeyDigest, PartitionRequest, ReadReplicationLogRequest, RepairLeavesRequest,
+    RepairLeavesResponse, RepairedKey, ReplicationEntry, ReplicationOp,
+};
+use dashmap::DashMap;
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::SinkExt;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tonic::metadata::errors::InvalidMetadataValue;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Status};
+use tonic_types::StatusExt;
+use tracing::{error, info};
+use uuid::Uuid;
+
+// how often followers read their leaders' logs
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// how often every partition's log is trimmed down to log_entries
+const TRIM_INTERVAL: Duration = Duration::from_secs(60);
+
+// entries a follower reads at once, and the most a read returns
+pub const DEFAULT_READ_LIMIT: u32 = 1000;
+pub const MAX_READ_LIMIT: u32 = 10_000;
+
+// a follower whose bootstrap failed copies the leader's checkpoint again no sooner than this
+const BOOTSTRAP_RETRY: Duration = Duration::from_secs(60);
+
+// divergent leaves a follower repairs at once, each repair reads the partition on both nodes
+const REPAIR_LEAVES: usize = 64;
+
+// where checkpoints are kept while they're sent to or copied from another node, next to the
+// partition directories
+const CHECKPOINT_DIR: &str = "replication-checkpoints";
+
+// a checkpoint's files are streamed in chunks of this many bytes
+const CHECKPOINT_CHUNK: usize = 1 << 20;
+
+const OP_PUT: u8 = 0;
+const OP_TOUCH: u8 = 1;
+const OP_DELETE: u8 = 2;
+
+// Writes are logged for followers on other nodes when log_entries is set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicationSettings {
+    // each partition keeps this many of its latest entries, a follower further behind can't catch up
+    pub log_entries: u64,
+    // a service token with the admin scope, sent to leaders to read their logs. The node can only
+    // follow when it's set.
+    pub token: Option<String>,
+    // how often followers compare their keys with their leaders', None to never compare them
+    pub anti_entropy_interval: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogOp {
+    Put {
+        metadata: EntryMetadata,
+        value: Vec<u8>,
+    },
+    // only the key's metadata changed, e.g. its expiry
+    Touch(EntryMetadata),
+    Delete,
+}
+
+// A write to a key as a partition's replication log records it. The log is kept in the same write
+// batch as the keys it changes, under big endian sequence numbers. A follower applies its leader's
+// entries and records them under the same sequence numbers, so once it's promoted the leader's
+// other followers can read the rest of the log from it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub key: Vec<u8>,
+    pub op: LogOp,
+    // unix milliseconds
+    pub timestamp: u64,
+}
+
+impl LogEntry {
+    pub fn put(key: &[u8], metadata: EntryMetadata, value: &[u8]) -> LogEntry {
+        LogEntry::new(
+            key,
+            LogOp::Put {
+                metadata,
+                value: value.to_vec(),
+            },
+        )
+    }
+
+    pub fn touch(key: &[u8], metadata: EntryMetadata) -> LogEntry {
+        LogEntry::new(key, LogOp::Touch(metadata))
+    }
+
+    pub fn delete(key: &[u8]) -> LogEntry {
+        LogEntry::new(key, LogOp::Delete)
+    }
+
+    fn new(key: &[u8], op: LogOp) -> LogEntry {
+        LogEntry {
+            key: key.to_vec(),
+            op,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_millis() as u64),
+        }
+    }
+
+    // The op, the timestamp and the key's length, all big endian, the key, then the metadata of a
+    // put or touch and a put's value
+    pub fn encode(&self) -> Vec<u8> {
+        let (op, metadata, value) = match &self.op {
+            LogOp::Put { metadata, value } => (OP_PUT, metadata.encode(), value.as_slice()),
+            LogOp::Touch(metadata) => (OP_TOUCH, metadata.encode(), [].as_slice()),
+            LogOp::Delete => (OP_DELETE, Vec::new(), [].as_slice()),
+        };
+        [
+            [op].as_slice(),
+            self.timestamp.to_be_bytes().as_slice(),
+            (self.key.len() as u32).to_be_bytes().as_slice(),
+            &self.key,
+            &metadata,
+            value,
+        ]
+        .concat()
+    }
+
+    // None when the entry isn't in a known encoding
+    pub fn decode(entry: &[u8]) -> Option<LogEntry> {
+        let (op, rest) = entry.split_first()?;
+        let (timestamp, rest) = rest.split_first_chunk::<8>()?;
+        let (key_len, rest) = rest.split_first_chunk::<4>()?;
+        let key_len = u32::from_be_bytes(*key_len) as usize;
+        if rest.len() < key_len {
+            return None;
+        }
+        let (key, rest) = rest.split_at(key_len);
+        let op = match *op {
+            OP_PUT | OP_TOUCH if rest.len() < METADATA_LEN => return None,
+            OP_PUT => LogOp::Put {
+                metadata: EntryMetadata::decode(&rest[..METADATA_LEN])?,
+                value: rest[METADATA_LEN..].to_vec(),
+            },
+            OP_TOUCH => LogOp::Touch(EntryMetadata::decode(rest)?),
+            OP_DELETE => LogOp::Delete,
+            _ => return None,
+        };
+        Some(LogEntry {
+            key: key.to_vec(),
+            op,
+            timestamp: u64::from_be_bytes(*timestamp),
+        })
+    }
+
+    pub fn to_proto(&self, sequence: u64) -> ReplicationEntry {
+        let (op, metadata, value) = match &self.op {
+            LogOp::Put { metadata, value } => {
+                (ReplicationOp::Put, metadata.encode(), value.clone())
+            }
+            LogOp::Touch(metadata) => (ReplicationOp::Touch, metadata.encode(), Vec::new()),
+            LogOp::Delete => (ReplicationOp::Delete, Vec::new(), Vec::new()),
+        };
+        ReplicationEntry {
+            sequence,
+            op: op.into(),
+            key: self.key.clone(),
+            metadata,
+            value,
+            timestamp: self.timestamp,
+        }
+    }
+
+    // None when the leader sent an op or metadata this build doesn't know
+    pub fn from_proto(entry: ReplicationEntry) -> Option<(u64, LogEntry)> {
+        let op = match ReplicationOp::try_from(entry.op).ok()? {
+            ReplicationOp::Put => LogOp::Put {
+                metadata: EntryMetadata::decode(&entry.metadata)?,
+                value: entry.value,
+            },
+            ReplicationOp::Touch => LogOp::Touch(EntryMetadata::decode(&entry.metadata)?),
+            ReplicationOp::Delete => LogOp::Delete,
+        };
+        Some((
+            entry.sequence,
+            LogEntry {
+                key: entry.key,
+                op,
+                timestamp: entry.timestamp,
+            },
+        ))
+    }
+}
+
+// What a follower partition last saw of its leader's log
+#[derive(Debug, Clone, Default)]
+pub struct FollowerStatus {
+    pub leader_next: Option<u64>,
+    pub lag_entries: u64,
+    pub lag_seconds: f64,
+    pub last_error: Option<String>,
+    // set while the follower is copied from a checkpoint of the leader's partition
+    pub bootstrapping: bool,
+    // a failed bootstrap isn't retried before then
+    retry_bootstrap_at: Option<Instant>,
+}
+
+// Each follower partition's status by partition id, shared by the log follower and the admin rpcs
+#[derive(Debug, Default)]
+pub struct Followers(DashMap<Uuid, FollowerStatus>);
+
+impl Followers {
+    pub fn status(&self, partition_id: Uuid) -> FollowerStatus {
+        self.0
+            .get(&partition_id)
+            .map(|status| status.clone())
+            .unwrap_or_default()
+    }
+
+    // Forgets the partition once it stops following
+    pub fn remove(&self, partition_id: Uuid) {
+        self.0.remove(&partition_id);
+        metrics::clear_replication_lag(partition_id);
+    }
+
+    fn update(&self, partition_id: Uuid, f: impl FnOnce(&mut FollowerStatus)) {
+        f(&mut self.0.entry(partition_id).or_default());
+    }
+}
+
+// Sends the replication token with every read of a leader's log
+#[derive(Clone)]
+pub struct BearerToken(MetadataValue<Ascii>);
+
+impl Interceptor for BearerToken {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        request
+            .metadata_mut()
+            .insert("authorization", self.0.clone());
+        Ok(request)
+    }
+}
+
+type LeaderClient = NodeAdminClient<InterceptedService<Channel, BearerToken>>;
+
+// Clients of the leaders' admin endpoints that send the replication token, by endpoint. A leader's
+// is dropped when an rpc to it fails, so the next one reconnects.
+pub struct LeaderClients {
+    token: BearerToken,
+    clients: HashMap<String, LeaderClient>,
+}
+
+impl LeaderClients {
+    pub fn new(token: &str) -> Result<LeaderClients, InvalidMetadataValue> {
+        Ok(LeaderClients {
+            token: BearerToken(format!("Bearer {}", token).parse()?),
+            clients: HashMap::new(),
+        })
+    }
+
+    fn get(&mut self, leader: &str) -> Result<LeaderClient, Error> {
+        if !self.clients.contains_key(leader) {
+            let client =
+                NodeAdminClient::with_interceptor(leader_channel(leader)?, self.token.clone());
+            self.clients.insert(leader.to_string(), client);
+        }
+        Ok(self.clients[leader].clone())
+    }
+
+    fn remove(&mut self, leader: &str) {
+        self.clients.remove(leader);
+    }
+}
+
+// Connects to the leader's admin endpoint, the connection is made on the first read
+pub fn leader_channel(leader: &str) -> Result<Channel, Error> {
+    Ok(Endpoint::from_shared(leader.to_string())
+        .map_err(|_| Error::InvalidLeader(leader.to_string()))?
+        .connect_lazy())
+}
+
+// Keeps every follower partition on this node applying its leader's log. Each poll a follower reads
+// from the first entry it doesn't have until it's caught up, and applies each read in one batch.
+// A follower whose leader has trimmed the entries it needs, e.g. a new one or one that fell too
+// far behind, is bootstrapped from a checkpoint of the leader's partition and applies the log
+// from there. One that has entries the leader doesn't stops applying and reports why in its
+// status.
+pub struct LogFollower {
+    clients: LeaderClients,
+    followers: Arc<Followers>,
+}
+
+impl LogFollower {
+    pub fn new(
+        token: &str,
+        followers: Arc<Followers>,
+    ) -> Result<LogFollower, InvalidMetadataValue> {
+        Ok(LogFollower {
+            clients: LeaderClients::new(token)?,
+            followers,
+        })
+    }
+
+    pub async fn run(mut self, partition_lookup: Arc<PartitionLookup>) {
+        info!("following leader partitions");
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            ticker.tick().await;
+            for partition in partition_lookup.all_partitions() {
+                let Some(leader) = partition.leader() else {
+                    continue;
+                };
+                // it's followed again once it's installed
+                if self.followers.status(partition.id).bootstrapping {
+                    continue;
+                }
+                loop {
+                    match self.follow(&partition, &leader).await {
+                        Ok(0) => break,
+                        Ok(applied) => metrics::record_replicated(applied),
+                        Err(err) if log_trimmed(&err) => {
+                            self.bootstrap(&partition, &leader, partition_lookup.data_dir());
+                            break;
+                        }
+                        Err(err) => {
+                            error!(
+                                partition_id = partition.id.to_string(),
+                                leader = leader,
+                                err = err.to_string(),
+                                "failed to apply leader's replication log"
+                            );
+                            self.followers.update(partition.id, |status| {
+                                status.last_error = Some(err.to_string());
+                            });
+                            self.clients.remove(&leader);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Applies the next entries of the leader's log, returns how many there were
+    async fn follow(&mut self, partition: &Partition, leader: &str) -> Result<usize, Error> {
+        let from = partition.log_next().ok_or(Error::ReplicationDisabled)?;
+        let request = ReadReplicationLogRequest {
+            partition_id: partition.id.to_string(),
+            from_sequence: from,
+            limit: DEFAULT_READ_LIMIT,
+        };
+        let response = self
+            .clients
+            .get(leader)?
+            .read_replication_log(request)
+            .await
+            .map_err(|status| Error::Leader(Box::new(status)))?
+            .into_inner();
+        let entries = response
+            .entries
+            .into_iter()
+            .map(LogEntry::from_proto)
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Error::UnknownEncoding)?;
+        let applied = entries.len();
+        let last_timestamp = entries.last().map(|(_, entry)| entry.timestamp);
+
+        if !entries.is_empty() {
+            let partition = partition.clone();
+            tokio::task::spawn_blocking(move || partition.apply_log(&entries))
+                .await
+                .map_err(io::Error::other)??;
+        }
+
+        let lag_entries = response.next_sequence.saturating_sub(from + applied as u64);
+        // how long ago the last entry applied was written, while there are more to apply
+        let lag_seconds = match (lag_entries, last_timestamp) {
+            (0, _) | (_, None) => 0.0,
+            (_, Some(timestamp)) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |now| now.as_millis() as u64);
+                now.saturating_sub(timestamp) as f64 / 1000.0
+            }
+        };
+        self.followers.update(partition.id, |status| {
+            status.leader_next = Some(response.next_sequence);
+            status.lag_entries = lag_entries;
+            status.lag_seconds = lag_seconds;
+            status.last_error = None;
+        });
+        metrics::set_replication_lag(partition.id, lag_entries, lag_seconds);
+        Ok(applied)
+    }
+
+    // Copies a checkpoint of the leader's partition in the background, unless the last copy failed
+    // less than BOOTSTRAP_RETRY ago
+    fn bootstrap(&mut self, partition: &Partition, leader: &str, data_dir: &Path) {
+        let status = self.followers.status(partition.id);
+        if status
+            .retry_bootstrap_at
+            .is_some_and(|retry_at| retry_at > Instant::now())
+        {
+            return;
+        }
+        let client = match self.clients.get(leader) {
+            Ok(client) => client,
+            Err(err) => {
+                self.followers.update(partition.id, |status| {
+                    status.last_error = Some(err.to_string());
+                });
+                return;
+            }
+        };
+        info!(
+            partition_id = partition.id.to_string(),
+            leader = leader,
+            "bootstrapping follower from the leader's checkpoint"
+        );
+        self.followers.update(partition.id, |status| {
+            status.bootstrapping = true;
+            status.last_error = None;
+        });
+        let followers = self.followers.clone();
+        let partition = partition.clone();
+        let data_dir = data_dir.to_path_buf();
+        tokio::spawn(async move {
+            let result = bootstrap(client, &partition, &data_dir).await;
+            match &result {
+                Ok(bytes) => {
+                    metrics::record_bootstrap("succeeded");
+                    info!(
+                        partition_id = partition.id.to_string(),
+                        bytes = bytes,
+                        "bootstrapped follower"
+                    );
+                }
+                Err(err) => {
+                    metrics::record_bootstrap("failed");
+                    error!(
+                        partition_id = partition.id.to_string(),
+                        err = err.to_string(),
+                        "failed to bootstrap follower"
+                    );
+                }
+            }
+            followers.update(partition.id, |status| {
+                status.bootstrapping = false;
+                status.last_error = result.as_ref().err().map(ToString::to_string);
+                status.retry_bootstrap_at =
+                    result.is_err().then(|| Instant::now() + BOOTSTRAP_RETRY);
+            });
+        });
+    }
+}
+
+// Periodically compares each follower partition's merkle tree with its leader's and repairs the
+// keys in the leaves they differ in, so a follower converges with its leader even when it missed
+// or misapplied entries of the log, or the two reaped expired keys at different times. Only
+// followers that have caught up are compared, one still applying the log differs by the entries
+// it hasn't applied.
+pub struct AntiEntropy {
+    clients: LeaderClients,
+    followers: Arc<Followers>,
+    interval: Duration,
+}
+
+impl AntiEntropy {
+    pub fn new(
+        token: &str,
+        followers: Arc<Followers>,
+        interval: Duration,
+    ) -> Result<AntiEntropy, InvalidMetadataValue> {
+        Ok(AntiEntropy {
+            clients: LeaderClients::new(token)?,
+            followers,
+            interval,
+        })
+    }
+
+    pub async fn run(mut self, partition_lookup: Arc<PartitionLookup>) {
+        info!(
+            interval_secs = self.interval.as_secs(),
+            "comparing followers with their leaders"
+        );
+        let mut ticker = tokio::time::interval(self.interval);
+        loop {
+            ticker.tick().await;
+            for partition in partition_lookup.all_partitions() {
+                let Some(leader) = partition.leader() else {
+                    continue;
+                };
+                let status = self.followers.status(partition.id);
+                if status.bootstrapping || status.leader_next.is_none() || status.lag_entries > 0 {
+                    continue;
+                }
+                if let Err(err) = self.repair(&partition, &leader).await {
+                    error!(
+                        partition_id = partition.id.to_string(),
+                        leader = leader,
+                        err = err.to_string(),
+                        "failed to compare follower with its leader"
+                    );
+                    self.clients.remove(&leader);
+                }
+            }
+        }
+    }
+
+    async fn repair(&mut self, partition: &Partition, leader: &str) -> Result<(), Error> {
+        let mut client = self.clients.get(leader)?;
+        let request = PartitionRequest {
+            partition_id: partition.id.to_string(),
+        };
+        let response = client
+            .get_merkle_tree(request)
+            .await
+            .map_err(|status| Error::Leader(Box::new(status)))?
+            .into_inner();
+        // the follower's tree has to have every write the leader's has, or it differs by the
+        // writes it hasn't applied yet
+        if partition.log_next().unwrap_or_default() < response.next_sequence {
+            return Ok(());
+        }
+        let leaves = response
+            .leaves
+            .iter()
+            .map(|leaf| merkle::Hash::try_from(leaf.as_slice()).ok())
+            .collect::<Option<Vec<_>>>()
+            .and_then(MerkleTree::from_leaves)
+            .ok_or(Error::UnknownEncoding)?;
+        let tree = {
+            let partition = partition.clone();
+            tokio::task::spawn_blocking(move || partition.merkle_tree())
+                .await
+                .map_err(io::Error::other)??
+        };
+        let divergent = tree.diff(&leaves);
+        if divergent.is_empty() {
+            return Ok(());
+        }
+
+        let mut repaired = 0;
+        for batch in divergent.chunks(REPAIR_LEAVES) {
+            let leaves: HashSet<u32> = batch.iter().copied().collect();
+            let held = {
+                let partition = partition.clone();
+                tokio::task::spawn_blocking(move || partition.leaf_entries(&leaves, false))
+                    .await
+                    .map_err(io::Error::other)??
+            };
+            let sent: HashMap<Vec<u8>, merkle::Hash> = held
+                .iter()
+                .map(|entry| {
+                    (
+                        entry.key.to_vec(),
+                        merkle::digest(&entry.key, &entry.metadata),
+                    )
+                })
+                .collect();
+            let request = RepairLeavesRequest {
+                partition_id: partition.id.to_string(),
+                leaves: batch.to_vec(),
+                keys: sent
+                    .iter()
+                    .map(|(key, digest)| KeyDigest {
+                        key: key.clone(),
+                        digest: digest.to_vec(),
+                    })
+                    .collect(),
+            };
+            let response = client
+                .repair_leaves(request)
+                .await
+                .map_err(|status| Error::Leader(Box::new(status)))?
+                .into_inner();
+            let puts: Vec<RawEntry> = response
+                .puts
+                .into_iter()
+                .map(|put| RawEntry {
+                    key: put.key.into(),
+                    metadata: put.metadata.into(),
+                    value: Some(put.value),
+                })
+                .collect();
+            let partition = partition.clone();
+            repaired += tokio::task::spawn_blocking(move || {
+                partition.repair(&puts, &response.deletes, &sent)
+            })
+            .await
+            .map_err(io::Error::other)??;
+        }
+        metrics::record_anti_entropy(divergent.len(), repaired);
+        info!(
+            partition_id = partition.id.to_string(),
+            leaves = divergent.len(),
+            keys = repaired,
+            "repaired follower's divergent leaves"
+        );
+        Ok(())
+    }
+}
+
+// What a follower has to change to match the leader's keys in the leaves, given the leader's
+// entries in them and the digests of the follower's keys. Keys whose values are tiered on the
+// leader are left out, see Partition::leaf_entries.
+pub fn repairs(entries: Vec<RawEntry>, keys: Vec<KeyDigest>) -> RepairLeavesResponse {
+    let mut held: HashMap<Vec<u8>, Vec<u8>> =
+        keys.into_iter().map(|key| (key.key, key.digest)).collect();
+    let mut puts = Vec::new();
+    for entry in entries {
+        let digest = merkle::digest(&entry.key, &entry.metadata);
+        let same = held
+            .remove(entry.key.as_ref())
+            .is_some_and(|held| held == digest);
+        if same {
+            continue;
+        }
+        if let Some(value) = entry.value {
+            puts.push(RepairedKey {
+                key: entry.key.into(),
+                metadata: entry.metadata.into(),
+                value,
+            });
+        }
+    }
+    RepairLeavesResponse {
+        puts,
+        deletes: held.into_keys().collect(),
+    }
+}
+
+// Trims every partition's log down to its latest log_entries entries
+pub async fn trim(log_entries: u64, partition_lookup: Arc<PartitionLookup>) {
+    let mut ticker = tokio::time::interval(TRIM_INTERVAL);
+    loop {
+        ticker.tick().await;
+        for partition in partition_lookup.all_partitions() {
+            let id = partition.id;
+            let trimmed = tokio::task::spawn_blocking(move || partition.trim_log(log_entries))
+                .await
+                .map_err(io::Error::other);
+            let err = match trimmed {
+                Ok(Ok(())) => continue,
+                Ok(Err(err)) => err.to_string(),
+                Err(err) => err.to_string(),
+            };
+            error!(
+                partition_id = id.to_string(),
+                err = err,
+                "failed to trim replication log"
+            );
+        }
+    }
+}
+
+// Whether the leader refused a read of its log because it's trimmed the entries the follower needs
+fn log_trimmed(err: &Error) -> bool {
+    let Error::Leader(status) = err else {
+        return false;
+    };
+    status
+        .get_error_details()
+        .error_info()
+        .is_some_and(|info| info.reason == "LOG_TRIMMED")
+}
+
+// Streams the leader's checkpoint of the partition into a checkpoint directory and installs it in
+// place of the partition's keys and log, returns the checkpoint's bytes
+async fn bootstrap(
+    mut client: LeaderClient,
+    partition: &Partition,
+    data_dir: &Path,
+) -> Result<usize, Error> {
+    let dir = CheckpointDir::new(data_dir, partition.id)?;
+    tokio::fs::create_dir(dir.path()).await?;
+    let request = PartitionRequest {
+        partition_id: partition.id.to_string(),
+    };
+    let mut chunks = client
+        .stream_checkpoint(request)
+        .await
+        .map_err(|status| Error::Leader(Box::new(status)))?
+        .into_inner();
+    let mut file: Option<(String, tokio::fs::File)> = None;
+    let mut bytes = 0;
+    while let Some(chunk) = chunks
+        .message()
+        .await
+        .map_err(|status| Error::Leader(Box::new(status)))?
+    {
+        if file.as_ref().map(|(name, _)| name) != Some(&chunk.file) {
+            // a checkpoint has no subdirectories
+            if Path::new(&chunk.file).file_name() != Some(OsStr::new(&chunk.file)) {
+                return Err(Error::InvalidCheckpointFile(chunk.file));
+            }
+            if let Some((_, mut writer)) = file.take() {
+                writer.flush().await?;
+            }
+            let writer = tokio::fs::File::create(dir.path().join(&chunk.file)).await?;
+            file = Some((chunk.file.clone(), writer));
+        }
+        if let Some((_, writer)) = file.as_mut() {
+            writer.write_all(&chunk.data).await?;
+        }
+        bytes += chunk.data.len();
+    }
+    if let Some((_, mut writer)) = file {
+        writer.flush().await?;
+    }
+
+    let partition = partition.clone();
+    tokio::task::spawn_blocking(move || {
+        format::check_version(dir.path())?;
+        let checkpoint = fsck::open_read_only(dir.path())?;
+        partition.install_checkpoint(&checkpoint)
+    })
+    .await
+    .map_err(io::Error::other)??;
+    Ok(bytes)
+}
+
+// A checkpoint's directory, deleted along with everything in it once it's dropped
+pub struct CheckpointDir(PathBuf);
+
+impl CheckpointDir {
+    // The directory itself isn't created, one left over under the same name is deleted
+    pub fn new(data_dir: &Path, name: Uuid) -> io::Result<CheckpointDir> {
+        let checkpoints = data_dir.join(CHECKPOINT_DIR);
+        fs::create_dir_all(&checkpoints)?;
+        let dir = checkpoints.join(name.to_string());
+        remove_dir(&dir)?;
+        Ok(CheckpointDir(dir))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for CheckpointDir {
+    fn drop(&mut self) {
+        if let Err(err) = remove_dir(&self.0) {
+            error!(
+                dir = self.0.display().to_string(),
+                err = err.to_string(),
+                "failed to delete checkpoint"
+            );
+        }
+    }
+}
+
+// Deletes the checkpoints a node left behind when it stopped while sending or copying them
+pub fn clear_checkpoints(data_dir: &Path) -> io::Result<()> {
+    remove_dir(&data_dir.join(CHECKPOINT_DIR))
+}
+
+fn remove_dir(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+// Sends the checkpoint's files in chunks until they're all sent or the follower goes away, then
+// deletes the checkpoint
+pub fn send_checkpoint(
+    dir: CheckpointDir,
+    mut sender: mpsc::Sender<Result<CheckpointChunk, Status>>,
+) {
+    if let Err(err) = send_files(dir.path(), &mut sender) {
+        error!(err = err.to_string(), "failed to send checkpoint");
+        let _ = block_on(sender.send(Err(err.into())));
+    }
+}
+
+fn send_files(
+    dir: &Path,
+    sender: &mut mpsc::Sender<Result<CheckpointChunk, Status>>,
+) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let mut reader = File::open(entry.path())?;
+        // an empty file is still sent, as one empty chunk
+        loop {
+            let mut data = Vec::with_capacity(CHECKPOINT_CHUNK);
+            (&mut reader)
+                .take(CHECKPOINT_CHUNK as u64)
+                .read_to_end(&mut data)?;
+            let last = data.len() < CHECKPOINT_CHUNK;
+            let chunk = CheckpointChunk {
+                file: name.clone(),
+                data,
+            };
+            if block_on(sender.send(Ok(chunk))).is_err() {
+                info!("checkpoint stopped, the follower went away");
+                return Ok(());
+            }
+            if last {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
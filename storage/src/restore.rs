@@ -0,0 +1,217 @@
+use crate::backup::{self, Manifest, PartitionManifest, MANIFEST_FILE};
+use crate::error::Error;
+use crate::format;
+use crate::fsck::open_read_only;
+use rocksdb::{IteratorMode, DB};
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::path::Path;
+
+// What restoring a partition from a backup would do to the partition of the same id that's live
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub added: u64,
+    pub overwritten: u64,
+    // keys written since the backup was taken, lost by restoring it
+    pub removed: u64,
+    pub unchanged: u64,
+}
+
+#[derive(Debug)]
+pub struct PartitionReport {
+    pub partition: PartitionManifest,
+    pub files_verified: usize,
+    pub keys: Option<u64>,
+    // only when compared against a data directory
+    pub changes: Option<Changes>,
+    pub issues: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub backup_id: String,
+    pub created_at: u64,
+    pub version: String,
+    pub git_hash: String,
+    pub partitions: Vec<PartitionReport>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.partitions
+            .iter()
+            .all(|partition| partition.issues.is_empty())
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "backup {}, taken at {} by {} ({})",
+            self.backup_id, self.created_at, self.version, self.git_hash
+        )?;
+        let mut issues = 0;
+        for report in &self.partitions {
+            let partition = &report.partition;
+            writeln!(
+                f,
+                "partition {} (tenant {}, namespace {})",
+                partition.partition_id, partition.tenant_id, partition.namespace_id
+            )?;
+            write!(f, "  {} files verified", report.files_verified)?;
+            match report.keys {
+                Some(keys) => writeln!(f, ", {} keys", keys)?,
+                None => writeln!(f)?,
+            }
+            if let Some(changes) = &report.changes {
+                writeln!(
+                    f,
+                    "  restoring would add {}, overwrite {}, and remove {} keys, {} unchanged",
+                    changes.added, changes.overwritten, changes.removed, changes.unchanged
+                )?;
+            }
+            for issue in &report.issues {
+                writeln!(f, "  {}", issue)?;
+            }
+            issues += report.issues.len();
+        }
+        match issues {
+            0 => writeln!(f, "no issues found"),
+            _ => writeln!(f, "{} issues found", issues),
+        }
+    }
+}
+
+// Verifies a backup without touching live data. Every file is checked against the manifest's size
+// and checksum, and every checkpoint's keys are counted. With a data directory each partition is
+// also compared with the live partition of the same id to report what restoring it would change.
+pub fn verify(backup_dir: &Path, data_dir: Option<&Path>) -> Result<Report, Error> {
+    let manifest: Manifest = serde_json::from_reader(File::open(backup_dir.join(MANIFEST_FILE))?)
+        .map_err(std::io::Error::from)?;
+    let mut partitions = Vec::with_capacity(manifest.partitions.len());
+    for partition in manifest.partitions {
+        let partition_id = partition.partition_id.to_string();
+        let live_dir = data_dir
+            .map(|data_dir| data_dir.join(&partition_id))
+            .filter(|live_dir| live_dir.exists());
+        partitions.push(verify_partition(
+            &backup_dir.join(&partition_id),
+            live_dir.as_deref(),
+            partition,
+        )?);
+    }
+    Ok(Report {
+        backup_id: manifest.backup_id,
+        created_at: manifest.created_at,
+        version: manifest.version,
+        git_hash: manifest.git_hash,
+        partitions,
+    })
+}
+
+fn verify_partition(
+    dir: &Path,
+    live_dir: Option<&Path>,
+    partition: PartitionManifest,
+) -> Result<PartitionReport, Error> {
+    let mut report = PartitionReport {
+        files_verified: 0,
+        keys: None,
+        changes: None,
+        issues: Vec::new(),
+        partition,
+    };
+    for file in &report.partition.files {
+        match backup::checksum(&dir.join(&file.name)) {
+            Ok((size, _)) if size != file.size => report.issues.push(format!(
+                "{}: {} bytes, the manifest has {}",
+                file.name, size, file.size
+            )),
+            Ok((_, sha256)) if sha256 != file.sha256 => report.issues.push(format!(
+                "{}: checksum does not match the manifest",
+                file.name
+            )),
+            Ok(_) => report.files_verified += 1,
+            Err(err) => report.issues.push(format!("{}: {}", file.name, err)),
+        }
+    }
+    // a damaged checkpoint isn't opened, its keys can't be trusted
+    if !report.issues.is_empty() {
+        return Ok(report);
+    }
+    if let Err(err) = format::check_version(dir) {
+        report.issues.push(err.to_string());
+        return Ok(report);
+    }
+
+    // a live partition in another format can't be compared key by key, it'd be migrated first
+    let live_dir = match live_dir.map(|live_dir| (live_dir, format::check_version(live_dir))) {
+        Some((_, Err(err))) => {
+            report.issues.push(format!("live partition: {}", err));
+            None
+        }
+        Some((live_dir, Ok(()))) => Some(live_dir),
+        None => None,
+    };
+    let backup = open_read_only(dir)?;
+    let changes = match live_dir {
+        Some(live_dir) => compare(&backup, Some(&open_read_only(live_dir)?))?,
+        None => compare(&backup, None)?,
+    };
+    let keys = changes.added + changes.overwritten + changes.unchanged;
+    if let Some(expected) = report.partition.keys.filter(|expected| *expected != keys) {
+        report
+            .issues
+            .push(format!("{} keys, the manifest has {}", keys, expected));
+    }
+    report.keys = Some(keys);
+    report.changes = live_dir.map(|_| changes);
+    Ok(report)
+}
+
+// Walks the backup's and the live partition's metadata side by side, both are in key order. Without
+// a live partition every key counts as added.
+fn compare(backup: &DB, live: Option<&DB>) -> Result<Changes, Error> {
+    let mut changes = Changes::default();
+    let backup_handle = backup.cf_handle("metadata").unwrap();
+    let mut backup_keys = backup.iterator_cf(&backup_handle, IteratorMode::Start);
+    let mut live_keys = match live {
+        Some(live) => {
+            Some(live.iterator_cf(&live.cf_handle("metadata").unwrap(), IteratorMode::Start))
+        }
+        None => None,
+    };
+    let mut next_backup = backup_keys.next().transpose()?;
+    let mut next_live = match &mut live_keys {
+        Some(live_keys) => live_keys.next().transpose()?,
+        None => None,
+    };
+    loop {
+        let (ordering, same_metadata) = match (&next_backup, &next_live) {
+            (None, None) => break,
+            (Some(_), None) => (Ordering::Less, false),
+            (None, Some(_)) => (Ordering::Greater, false),
+            (Some((backup_key, backup_metadata)), Some((live_key, live_metadata))) => {
+                (backup_key.cmp(live_key), backup_metadata == live_metadata)
+            }
+        };
+        // the metadata holds the value's crc and version, so it differs when the value does
+        match ordering {
+            Ordering::Less => changes.added += 1,
+            Ordering::Greater => changes.removed += 1,
+            Ordering::Equal if same_metadata => changes.unchanged += 1,
+            Ordering::Equal => changes.overwritten += 1,
+        }
+        if ordering != Ordering::Greater {
+            next_backup = backup_keys.next().transpose()?;
+        }
+        if ordering != Ordering::Less {
+            if let Some(live_keys) = &mut live_keys {
+                next_live = live_keys.next().transpose()?;
+            }
+        }
+    }
+    Ok(changes)
+}
@@ -0,0 +1,140 @@
+use crate::format::{EntryMetadata, METADATA_LEN};
+use crate::lookup::PartitionLookup;
+use crate::metrics;
+use common::storage::NamespaceRetention;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+// how often partitions are checked for retained values past their namespace's retention
+const PURGE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// A namespace's retention on this node, unset fields retain nothing. Retained values count towards
+// the namespace's usage until they're purged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retention {
+    // a deleted key's last value is kept this long, so the delete can be undone
+    pub deleted_secs: Option<u64>,
+    // a value replaced by a put is kept this long
+    pub versions_secs: Option<u64>,
+}
+
+impl From<&NamespaceRetention> for Retention {
+    fn from(value: &NamespaceRetention) -> Self {
+        Retention {
+            deleted_secs: value.deleted_secs,
+            versions_secs: value.versions_secs,
+        }
+    }
+}
+
+impl Retention {
+    pub fn is_none(&self) -> bool {
+        self.deleted_secs.is_none() && self.versions_secs.is_none()
+    }
+
+    // How long a value that's deleted, or replaced otherwise, is kept, None when it isn't
+    pub fn keeps(&self, deleted: bool) -> Option<u64> {
+        match deleted {
+            true => self.deleted_secs,
+            false => self.versions_secs,
+        }
+    }
+}
+
+// A value kept after it was deleted or replaced, in the partition's history column family under
+// the key followed by the value's big endian version. Encoded as when it was retained in unix
+// seconds, whether it was deleted, and the key's metadata, followed by the value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetainedValue {
+    pub retained_at: u64,
+    pub deleted: bool,
+    pub metadata: EntryMetadata,
+    pub value: Vec<u8>,
+}
+
+impl RetainedValue {
+    pub fn encode(&self) -> Vec<u8> {
+        [
+            self.retained_at.to_be_bytes().as_slice(),
+            [self.deleted as u8].as_slice(),
+            &self.metadata.encode(),
+            &self.value,
+        ]
+        .concat()
+    }
+
+    // None when the value isn't in a known encoding
+    pub fn decode(retained: &[u8]) -> Option<RetainedValue> {
+        let (retained_at, rest) = retained.split_first_chunk::<8>()?;
+        let (deleted, rest) = rest.split_first()?;
+        if rest.len() < METADATA_LEN {
+            return None;
+        }
+        let (metadata, value) = rest.split_at(METADATA_LEN);
+        Some(RetainedValue {
+            retained_at: u64::from_be_bytes(*retained_at),
+            deleted: *deleted != 0,
+            metadata: EntryMetadata::decode(metadata)?,
+            value: value.to_vec(),
+        })
+    }
+
+    // Whether the namespace's retention no longer keeps the value, at now in unix seconds
+    pub fn is_expired(&self, retention: &Retention, now: u64) -> bool {
+        retention
+            .keeps(self.deleted)
+            .is_none_or(|secs| self.retained_at.saturating_add(secs) <= now)
+    }
+}
+
+// Where a key's version is retained. Keys of the same length can't collide, and a longer key can't
+// end up at a shorter key's version since the version is always the last 4 bytes.
+pub fn history_key(key: &[u8], version: u32) -> Vec<u8> {
+    [key, version.to_be_bytes().as_slice()].concat()
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+// Purges every partition's retained values once their namespace's retention has passed, so a
+// value is gone at most PURGE_INTERVAL after its retention ends. Values a namespace no longer
+// retains, because its retention was shortened or removed, are purged on the next pass.
+pub async fn purge(partition_lookup: Arc<PartitionLookup>) {
+    let mut ticker = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        for partition in partition_lookup.all_partitions() {
+            let id = partition.id;
+            let purged = tokio::task::spawn_blocking(move || partition.purge_retained(unix_now()))
+                .await
+                .map_err(io::Error::other);
+            match purged {
+                Ok(Ok(0)) => {}
+                Ok(Ok(purged)) => {
+                    info!(
+                        partition_id = id.to_string(),
+                        values = purged,
+                        "purged retained values"
+                    );
+                    metrics::record_retained_purged(purged);
+                }
+                Ok(Err(err)) => error!(
+                    partition_id = id.to_string(),
+                    err = err.to_string(),
+                    "failed to purge retained values"
+                ),
+                Err(err) => error!(
+                    partition_id = id.to_string(),
+                    err = err.to_string(),
+                    "failed to purge retained values"
+                ),
+            }
+        }
+    }
+}
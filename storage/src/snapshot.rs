@@ -0,0 +1,101 @@
+use crate::partition::{Partition, PinnedSnapshot};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// How long a listing's snapshots are kept after the last page was read
+pub const SNAPSHOT_TTL: Duration = Duration::from_secs(60);
+// Past this the listing idle the longest loses its snapshots
+const MAX_SNAPSHOTS: usize = 1024;
+
+struct ListSnapshot {
+    tenant_id: Uuid,
+    namespace_id: Uuid,
+    partitions: Arc<HashMap<Uuid, PinnedSnapshot>>,
+    expires_at: Instant,
+}
+
+// The snapshots paginated listings read from, one per partition of the namespace, so every page of
+// a listing sees the keys as they were when its first page was read. A listing's snapshots are
+// kept until its last page or until they've gone unused for SNAPSHOT_TTL.
+#[derive(Default)]
+pub struct ListSnapshots {
+    listings: Mutex<HashMap<Uuid, ListSnapshot>>,
+}
+
+impl std::fmt::Debug for ListSnapshots {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let listings = self
+            .listings
+            .lock()
+            .map(|listings| listings.len())
+            .unwrap_or(0);
+        f.debug_struct("ListSnapshots")
+            .field("listings", &listings)
+            .finish()
+    }
+}
+
+impl ListSnapshots {
+    // Snapshots the partitions for a new listing, returns its id
+    pub fn pin(
+        &self,
+        tenant_id: Uuid,
+        namespace_id: Uuid,
+        partitions: &[Partition],
+    ) -> (Uuid, Arc<HashMap<Uuid, PinnedSnapshot>>) {
+        let snapshots: Arc<HashMap<Uuid, PinnedSnapshot>> = Arc::new(
+            partitions
+                .iter()
+                .map(|partition| (partition.id, partition.pin_snapshot()))
+                .collect(),
+        );
+        let id = Uuid::new_v4();
+        let now = Instant::now();
+        let mut listings = self.listings.lock().unwrap();
+        listings.retain(|_, listing| listing.expires_at > now);
+        if listings.len() >= MAX_SNAPSHOTS {
+            if let Some(oldest) = listings
+                .iter()
+                .min_by_key(|(_, listing)| listing.expires_at)
+                .map(|(id, _)| *id)
+            {
+                listings.remove(&oldest);
+            }
+        }
+        listings.insert(
+            id,
+            ListSnapshot {
+                tenant_id,
+                namespace_id,
+                partitions: snapshots.clone(),
+                expires_at: now + SNAPSHOT_TTL,
+            },
+        );
+        (id, snapshots)
+    }
+
+    // The listing's snapshots, None once they've expired or if the listing is of another namespace
+    pub fn get(
+        &self,
+        id: Uuid,
+        tenant_id: Uuid,
+        namespace_id: Uuid,
+    ) -> Option<Arc<HashMap<Uuid, PinnedSnapshot>>> {
+        let now = Instant::now();
+        let mut listings = self.listings.lock().unwrap();
+        let listing = listings.get_mut(&id).filter(|listing| {
+            listing.expires_at > now
+                && listing.tenant_id == tenant_id
+                && listing.namespace_id == namespace_id
+        })?;
+        listing.expires_at = now + SNAPSHOT_TTL;
+        Some(listing.partitions.clone())
+    }
+
+    // Drops the listing's snapshots once its last page is read
+    pub fn release(&self, id: Uuid) {
+        self.listings.lock().unwrap().remove(&id);
+    }
+}
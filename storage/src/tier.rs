@@ -0,0 +1,265 @@
+use crate::backup::UploadOptions;
+use crate::error::Error;
+use crate::fsck;
+use crate::lookup::PartitionLookup;
+use crate::metrics;
+use crate::partition::{GetValue, Key, Partition};
+use futures::TryStreamExt;
+use object_store::path::Path as ObjectPath;
+use object_store::ObjectStore;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+use url::Url;
+use uuid::Uuid;
+
+// how often partitions are swept for cold values and unreferenced objects
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// caps on the values one sweep of a partition uploads, the rest wait for the next sweep
+const MAX_SWEEP_VALUES: usize = 1000;
+const MAX_SWEEP_BYTES: usize = 256 * 1024 * 1024;
+
+// an object nothing references is only deleted once it's this old, so a value uploaded but not
+// yet pointed at by its key isn't collected out from under the sweep
+const COLLECT_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Access times are only recorded this often per key, so reads don't turn into writes
+const ACCESS_RESOLUTION_SECS: u64 = 60 * 60;
+
+// A key's entry in the tier column family, when the key was last read or written in unix seconds,
+// and the object its value was moved to if it has been. The object's path follows the time as utf8.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TierEntry {
+    pub accessed_at: u64,
+    pub object: Option<String>,
+}
+
+impl TierEntry {
+    pub fn accessed_now(object: Option<String>) -> TierEntry {
+        TierEntry {
+            accessed_at: unix_now(),
+            object,
+        }
+    }
+
+    // None when the entry isn't in a known encoding
+    pub fn decode(entry: &[u8]) -> Option<TierEntry> {
+        let (accessed_at, object) = entry.split_first_chunk::<8>()?;
+        Some(TierEntry {
+            accessed_at: u64::from_be_bytes(*accessed_at),
+            object: match object.is_empty() {
+                true => None,
+                false => Some(String::from_utf8(object.to_vec()).ok()?),
+            },
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        [
+            self.accessed_at.to_be_bytes().as_slice(),
+            self.object.as_deref().unwrap_or_default().as_bytes(),
+        ]
+        .concat()
+    }
+
+    // Whether the access time is old enough to be recorded again
+    pub fn is_stale(&self) -> bool {
+        self.accessed_at + ACCESS_RESOLUTION_SECS <= unix_now()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TierSettings {
+    // where cold values are moved to, e.g. s3://bucket/tier
+    pub url: Url,
+    // values neither read nor written for this long are moved
+    pub after: Duration,
+    // smaller values stay in rocksdb, their pointer would save little
+    pub min_value_size: usize,
+    // a tiered value that's read is written back to rocksdb
+    pub promote: bool,
+}
+
+// Moves values that haven't been read or written for a while out of the partitions into an object
+// store, leaving each key's metadata behind along with a pointer to its value's object. Reads fetch
+// tiered values back transparently. Objects are named by partition, under <url>/<partition id>/,
+// and ones no key points at any more are deleted by the sweep.
+//
+// Backups and dumps of a partition hold the pointers rather than the tiered values, a restored key
+// can only be read while its object hasn't been collected.
+#[derive(Debug)]
+pub struct Tiering {
+    store: Box<dyn ObjectStore>,
+    prefix: ObjectPath,
+    settings: TierSettings,
+}
+
+impl Tiering {
+    pub fn new(settings: TierSettings, options: &UploadOptions) -> Result<Tiering, Error> {
+        let (store, prefix) = object_store::parse_url_opts(&settings.url, options.store_options())?;
+        Ok(Tiering {
+            store,
+            prefix,
+            settings,
+        })
+    }
+
+    // Fetches a tiered value, checking it against the crc in the key's metadata
+    pub async fn fetch(&self, key: &[u8], object: &str, crc: u32) -> Result<Vec<u8>, Error> {
+        let value = self
+            .store
+            .get(&ObjectPath::from(object))
+            .await?
+            .bytes()
+            .await?
+            .to_vec();
+        let computed = fsck::crc(key, &value);
+        if computed != crc {
+            error!(object = object, "tiered value doesn't match its crc");
+            return Err(Error::CrcMismatch {
+                expected: crc,
+                computed,
+            });
+        }
+        metrics::record_tiering("fetched");
+        Ok(value)
+    }
+
+    // Sweeps every partition in turn, forever
+    pub async fn run(self: Arc<Self>, partition_lookup: Arc<PartitionLookup>) {
+        info!(url = self.settings.url.to_string(), "tiering cold values");
+        let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            for partition in partition_lookup.all_partitions() {
+                if let Err(err) = self.sweep(&partition).await {
+                    error!(
+                        partition_id = partition.id.to_string(),
+                        err = err.to_string(),
+                        "failed to tier partition's cold values"
+                    );
+                }
+                if let Err(err) = self.collect(&partition).await {
+                    error!(
+                        partition_id = partition.id.to_string(),
+                        err = err.to_string(),
+                        "failed to collect partition's unreferenced objects"
+                    );
+                }
+            }
+        }
+    }
+
+    // Uploads the partition's cold values and points their keys at the objects. A key written
+    // while its value was uploaded keeps its new value, and the upload is deleted.
+    async fn sweep(&self, partition: &Partition) -> Result<(), Error> {
+        let cutoff = unix_now().saturating_sub(self.settings.after.as_secs());
+        let min_value_size = self.settings.min_value_size;
+        let cold = {
+            let partition = partition.clone();
+            tokio::task::spawn_blocking(move || {
+                partition.enable_tiering()?;
+                partition.cold_values(cutoff, min_value_size, MAX_SWEEP_VALUES, MAX_SWEEP_BYTES)
+            })
+            .await
+            .map_err(io::Error::other)??
+        };
+
+        let mut tiered = 0;
+        for value in cold {
+            let object = self
+                .prefix
+                .child(partition.id.to_string())
+                .child(Uuid::new_v4().to_string());
+            self.store.put(&object, value.value.into()).await?;
+            if partition.tier(&value.key, value.version, object.as_ref())? {
+                metrics::record_tiering("tiered");
+                tiered += 1;
+            } else if let Err(err) = self.store.delete(&object).await {
+                // left for collect to delete
+                warn!(err = err.to_string(), "failed to delete unused object");
+            }
+        }
+        if tiered > 0 {
+            info!(
+                partition_id = partition.id.to_string(),
+                values = tiered,
+                "tiered cold values"
+            );
+        }
+        Ok(())
+    }
+
+    // Deletes the partition's objects that no key points at any more
+    async fn collect(&self, partition: &Partition) -> Result<(), Error> {
+        // the referenced objects are read before listing, an object tiered after is too new to go
+        let referenced = {
+            let partition = partition.clone();
+            tokio::task::spawn_blocking(move || partition.tiered_objects())
+                .await
+                .map_err(io::Error::other)??
+        };
+        let collect_before = unix_now().saturating_sub(COLLECT_AFTER.as_secs()) as i64;
+        let prefix = self.prefix.child(partition.id.to_string());
+        let mut objects = self.store.list(Some(&prefix));
+        while let Some(object) = objects.try_next().await? {
+            if referenced.contains(object.location.as_ref())
+                || object.last_modified.timestamp() > collect_before
+            {
+                continue;
+            }
+            self.store.delete(&object.location).await?;
+            metrics::record_tiering("collected");
+        }
+        Ok(())
+    }
+}
+
+// Reads the key like Partition::get, fetching its value from the object store when it's tiered and
+// writing it back to the partition when promote is set
+pub async fn get(
+    tiering: Option<&Tiering>,
+    partition: &Partition,
+    key: &Key,
+) -> Result<GetValue, Error> {
+    // a key rewritten between being read and its object being fetched can have had the object
+    // collected, it's read again once
+    let mut retried = false;
+    loop {
+        let mut value = partition.get(key)?;
+        let Some(object) = value.tiered.take() else {
+            return Ok(value);
+        };
+        let Some(tiering) = tiering else {
+            error!(
+                object = object,
+                "the value is tiered but tiering isn't configured"
+            );
+            return Err(Error::TieringDisabled);
+        };
+        value.value = match tiering.fetch(key.as_ref(), &object, value.crc).await {
+            Ok(fetched) => fetched,
+            Err(Error::ObjectStore(object_store::Error::NotFound { .. })) if !retried => {
+                retried = true;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if tiering.settings.promote {
+            match partition.promote(key, value.version, &object, &value.value) {
+                Ok(true) => metrics::record_tiering("promoted"),
+                Ok(false) => {}
+                Err(err) => error!(err = err.to_string(), "failed to promote tiered value"),
+            }
+        }
+        return Ok(value);
+    }
+}
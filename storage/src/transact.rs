@@ -0,0 +1,235 @@
+use crate::error::Error;
+use crate::lookup::PartitionLookup;
+use crate::partition::{Key, Partition, PutValue};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+// The most writes one transaction can make
+pub const MAX_TRANSACT_OPS: usize = 100;
+
+const INTENT_DIR: &str = "intents";
+
+// One key of a transaction as it will be written, the key's encoded metadata and value, or neither
+// for a delete
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IntentWrite {
+    pub partition_id: Uuid,
+    pub key: Vec<u8>,
+    pub entry: Option<(Vec<u8>, Vec<u8>)>,
+    // the key's version when the transaction was decided, a replayed write is only applied to a
+    // key still at it
+    pub previous_version: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Intent {
+    id: Uuid,
+    writes: Vec<IntentWrite>,
+}
+
+// A write a transaction makes, a put when there's a value and a delete otherwise
+pub struct TransactOp<'a> {
+    pub key: Key,
+    pub value: Option<PutValue<'a>>,
+    // 0 expects the key not to exist
+    pub expected_version: Option<u32>,
+}
+
+// Transactions that were decided but may not be applied to every partition yet, one file each in
+// the data directory's intents directory. An intent is recorded before any of its partitions are
+// written and removed once all of them are, so after a crash the intents left are replayed.
+#[derive(Debug)]
+pub struct IntentLog {
+    dir: PathBuf,
+}
+
+impl IntentLog {
+    pub fn open(data_dir: impl AsRef<Path>) -> io::Result<IntentLog> {
+        let dir = data_dir.as_ref().join(INTENT_DIR);
+        fs::create_dir_all(&dir)?;
+        Ok(IntentLog { dir })
+    }
+
+    fn path(&self, id: Uuid) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    // The intent is on disk once this returns, it's written aside and renamed into place so a
+    // crash never leaves half an intent
+    fn record(&self, intent: &Intent) -> io::Result<()> {
+        let path = self.path(intent.id);
+        let staged = path.with_extension("tmp");
+        let mut file = File::create(&staged)?;
+        file.write_all(&serde_json::to_vec(intent)?)?;
+        file.sync_all()?;
+        fs::rename(&staged, &path)?;
+        File::open(&self.dir)?.sync_all()
+    }
+
+    fn clear(&self, id: Uuid) -> io::Result<()> {
+        fs::remove_file(self.path(id))
+    }
+
+    // Applies the intents a crash left behind, returns how many there were. Writes to partitions
+    // that have since been removed from the node are dropped.
+    pub fn recover(&self, lookup: &PartitionLookup) -> Result<usize, Error> {
+        let mut recovered = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            // an intent that was never renamed into place was never applied either
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let intent: Intent = serde_json::from_slice(&fs::read(&path)?)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            info!(
+                transaction_id = intent.id.to_string(),
+                writes = intent.writes.len(),
+                "replaying transaction"
+            );
+            for (partition_id, writes) in by_partition(&intent.writes) {
+                match lookup.find_partition(partition_id) {
+                    Some(partition) => replay(&partition, writes)?,
+                    None => warn!(
+                        transaction_id = intent.id.to_string(),
+                        partition_id = partition_id.to_string(),
+                        "partition is gone, dropping its writes"
+                    ),
+                }
+            }
+            self.clear(intent.id)?;
+            recovered += 1;
+        }
+        Ok(recovered)
+    }
+}
+
+// Writes to keys that were already written, by this transaction or after it, are skipped
+fn replay(partition: &Partition, writes: Vec<&IntentWrite>) -> Result<(), Error> {
+    let mut pending = Vec::with_capacity(writes.len());
+    for write in writes {
+        let key: Key = (&write.key).into();
+        if partition.current_version(&key)? == write.previous_version {
+            pending.push(write);
+        }
+    }
+    partition.apply(&pending)
+}
+
+fn by_partition(writes: &[IntentWrite]) -> BTreeMap<Uuid, Vec<&IntentWrite>> {
+    let mut partitions: BTreeMap<Uuid, Vec<&IntentWrite>> = BTreeMap::new();
+    for write in writes {
+        partitions
+            .entry(write.partition_id)
+            .or_default()
+            .push(write);
+    }
+    partitions
+}
+
+// Applies the writes to the namespace's keys all together or not at all, whichever partitions they
+// route to. The keys' write locks are taken in order and held throughout. Every expected version is
+// checked first and the writes are recorded as an intent, then each partition's writes are applied
+// and the intent is cleared. A partition that fails to apply its writes leaves the intent to be
+// replayed when the node restarts. Returns each op's new version, 0 for a delete.
+//
+// Readers aren't isolated from a transaction being applied, a get can see one partition's writes
+// before another's.
+pub fn transact(
+    lookup: &PartitionLookup,
+    log: &IntentLog,
+    tenant_id: Uuid,
+    namespace_id: Uuid,
+    ops: &[TransactOp],
+) -> Result<Vec<u32>, Error> {
+    let mut keys = HashSet::new();
+    let mut routed: Vec<Partition> = Vec::with_capacity(ops.len());
+    for op in ops {
+        if !keys.insert(&op.key) {
+            return Err(Error::InvalidTransaction(
+                "a key can only be written once per transaction".to_string(),
+            ));
+        }
+        let partition = lookup
+            .get_partition_for_key(tenant_id, namespace_id, &op.key)
+            .ok_or(Error::PartitionNotFound)?;
+        // a follower is refused before the intent is recorded, a recorded intent is always applied
+        partition.check_writable()?;
+        routed.push(partition);
+    }
+
+    // locked in partition then stripe order, so transactions sharing keys can't deadlock
+    let stripes: BTreeMap<(Uuid, usize), &Partition> = ops
+        .iter()
+        .zip(&routed)
+        .map(|(op, partition)| ((partition.id, partition.write_stripe(&op.key)), partition))
+        .collect();
+    let _locks: Vec<_> = stripes
+        .iter()
+        .map(|((_, stripe), partition)| partition.lock_stripe(*stripe))
+        .collect();
+
+    let mut versions = Vec::with_capacity(ops.len());
+    let mut writes = Vec::with_capacity(ops.len());
+    for (op, partition) in ops.iter().zip(&routed) {
+        let current = partition.current_version(&op.key)?;
+        if let Some(expected) = op.expected_version.filter(|expected| *expected != current) {
+            return Err(Error::VersionConflict { expected, current });
+        }
+        let (version, entry) = match &op.value {
+            Some(value) => {
+                let version = current.wrapping_add(1);
+                let metadata = value.metadata(version).encode().to_vec();
+                (version, Some((metadata, value.value.to_vec())))
+            }
+            None => (0, None),
+        };
+        versions.push(version);
+        writes.push(IntentWrite {
+            partition_id: partition.id,
+            key: op.key.clone().into(),
+            entry,
+            previous_version: current,
+        });
+    }
+
+    let intent = Intent {
+        id: Uuid::new_v4(),
+        writes,
+    };
+    log.record(&intent)
+        .inspect_err(|err| error!(err = err.to_string(), "failed to record transaction"))?;
+
+    let partitions: BTreeMap<Uuid, &Partition> = routed
+        .iter()
+        .map(|partition| (partition.id, partition))
+        .collect();
+    for (partition_id, writes) in by_partition(&intent.writes) {
+        partitions[&partition_id]
+            .apply(&writes)
+            .inspect_err(|err| {
+                error!(
+                    err = err.to_string(),
+                    transaction_id = intent.id.to_string(),
+                    partition_id = partition_id.to_string(),
+                    "failed to apply transaction, it's replayed when the node restarts"
+                )
+            })?;
+    }
+    // every write is applied, an intent left behind is skipped when it's replayed
+    if let Err(err) = log.clear(intent.id) {
+        warn!(
+            err = err.to_string(),
+            transaction_id = intent.id.to_string(),
+            "failed to clear applied transaction"
+        );
+    }
+
+    Ok(versions)
+}
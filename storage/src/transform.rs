@@ -0,0 +1,339 @@
+use crate::error::Error;
+use dashmap::DashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{error, info};
+use uuid::Uuid;
+use wasmi::{Config, Engine, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+pub const DEFAULT_FUEL: u64 = 10_000_000;
+pub const DEFAULT_MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+pub const MAX_MODULE_SIZE: usize = 1024 * 1024;
+
+const TRANSFORM_DIR: &str = "transforms";
+
+// Tenant supplied wasm modules a namespace's values pass through on the node, a read module
+// transforms values as they're read, e.g. to extract a field of a large document, and a write
+// module validates values before they're stored. Modules can't import anything, and every call
+// runs in a fresh instance with a fuel and memory limit.
+//
+// A module exports its memory as "memory" and "alloc(len: i32) -> i32", which returns where the
+// node writes the value. A read module exports "transform(ptr: i32, len: i32) -> i64" returning the
+// transformed value's pointer in the upper 32 bits and its length in the lower 32. A write module
+// exports "validate(ptr: i32, len: i32) -> i32" returning 0 to accept the value.
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    Read,
+    Write,
+}
+
+impl Stage {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Read => "read",
+            Stage::Write => "write",
+        }
+    }
+
+    fn export(&self) -> &'static str {
+        match self {
+            Stage::Read => "transform",
+            Stage::Write => "validate",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransformLimits {
+    // instructions a call may run, roughly
+    pub fuel: u64,
+    pub memory: usize,
+}
+
+impl Default for TransformLimits {
+    fn default() -> Self {
+        TransformLimits {
+            fuel: DEFAULT_FUEL,
+            memory: DEFAULT_MEMORY_LIMIT,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct NamespaceModules {
+    read: Option<Arc<Module>>,
+    write: Option<Arc<Module>>,
+}
+
+impl NamespaceModules {
+    fn get(&self, stage: Stage) -> Option<Arc<Module>> {
+        match stage {
+            Stage::Read => self.read.clone(),
+            Stage::Write => self.write.clone(),
+        }
+    }
+}
+
+// The modules of every namespace on the node, kept in data_dir/transforms so they survive a restart
+#[derive(Debug)]
+pub struct Transforms {
+    engine: Engine,
+    limits: TransformLimits,
+    dir: PathBuf,
+    // keyed by namespace and tenant like the partitions
+    modules: DashMap<(Uuid, Uuid), NamespaceModules>,
+}
+
+impl Transforms {
+    pub fn load(data_dir: impl AsRef<Path>, limits: TransformLimits) -> Result<Transforms, Error> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let transforms = Transforms {
+            engine: Engine::new(&config),
+            limits,
+            dir: data_dir.as_ref().join(TRANSFORM_DIR),
+            modules: DashMap::new(),
+        };
+        fs::create_dir_all(&transforms.dir)?;
+        for entry in fs::read_dir(&transforms.dir)? {
+            let path = entry?.path();
+            let Some((namespace_id, tenant_id, stage)) = parse_file_name(&path) else {
+                continue;
+            };
+            let module = transforms
+                .compile(stage, &fs::read(&path)?)
+                .inspect_err(|err| {
+                    error!(
+                        err = err.to_string(),
+                        path = path.to_str(),
+                        "failed to load transform"
+                    )
+                })?;
+            let mut modules = transforms
+                .modules
+                .entry((namespace_id, tenant_id))
+                .or_default();
+            match stage {
+                Stage::Read => modules.read = Some(module),
+                Stage::Write => modules.write = Some(module),
+            }
+        }
+        info!(namespaces = transforms.modules.len(), "loaded transforms");
+        Ok(transforms)
+    }
+
+    fn compile(&self, stage: Stage, wasm: &[u8]) -> Result<Arc<Module>, Error> {
+        if wasm.len() > MAX_MODULE_SIZE {
+            return Err(Error::InvalidTransform(format!(
+                "the {} module is larger than {} bytes",
+                stage.as_str(),
+                MAX_MODULE_SIZE
+            )));
+        }
+        let module = Module::new(&self.engine, wasm)
+            .map_err(|err| Error::InvalidTransform(format!("{}: {}", stage.as_str(), err)))?;
+        for export in ["memory", "alloc", stage.export()] {
+            if !module.exports().any(|item| item.name() == export) {
+                return Err(Error::InvalidTransform(format!(
+                    "the {} module doesn't export {}",
+                    stage.as_str(),
+                    export
+                )));
+            }
+        }
+        if module.imports().len() > 0 {
+            return Err(Error::InvalidTransform(format!(
+                "the {} module imports functions, modules can't import anything",
+                stage.as_str()
+            )));
+        }
+        Ok(Arc::new(module))
+    }
+
+    fn file(&self, namespace_id: Uuid, tenant_id: Uuid, stage: Stage) -> PathBuf {
+        self.dir.join(format!(
+            "{}.{}.{}.wasm",
+            namespace_id,
+            tenant_id,
+            stage.as_str()
+        ))
+    }
+
+    // Replaces the namespace's modules, None removes one. Both are compiled before either is saved.
+    pub fn set(
+        &self,
+        tenant_id: Uuid,
+        namespace_id: Uuid,
+        read: Option<&[u8]>,
+        write: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        let modules = NamespaceModules {
+            read: read
+                .map(|wasm| self.compile(Stage::Read, wasm))
+                .transpose()?,
+            write: write
+                .map(|wasm| self.compile(Stage::Write, wasm))
+                .transpose()?,
+        };
+        for (stage, wasm) in [(Stage::Read, read), (Stage::Write, write)] {
+            let file = self.file(namespace_id, tenant_id, stage);
+            match wasm {
+                Some(wasm) => fs::write(&file, wasm)?,
+                None => match fs::remove_file(&file) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                    _ => {}
+                },
+            }
+        }
+        if modules.read.is_none() && modules.write.is_none() {
+            self.modules.remove(&(namespace_id, tenant_id));
+        } else {
+            self.modules.insert((namespace_id, tenant_id), modules);
+        }
+        Ok(())
+    }
+
+    // Gives the namespace the same modules as the source namespace, removing any it had
+    pub fn copy(
+        &self,
+        tenant_id: Uuid,
+        source_namespace_id: Uuid,
+        namespace_id: Uuid,
+    ) -> Result<(), Error> {
+        let mut modules = Vec::with_capacity(2);
+        for stage in [Stage::Read, Stage::Write] {
+            match fs::read(self.file(source_namespace_id, tenant_id, stage)) {
+                Ok(wasm) => modules.push(Some(wasm)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => modules.push(None),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.set(
+            tenant_id,
+            namespace_id,
+            modules[0].as_deref(),
+            modules[1].as_deref(),
+        )
+    }
+
+    // Drops the modules of every namespace of a deleted tenant
+    pub fn remove_tenant(&self, tenant_id: Uuid) -> Result<(), Error> {
+        self.modules.retain(|(_, tenant), _| *tenant != tenant_id);
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if parse_file_name(&path).is_some_and(|(_, tenant, _)| tenant == tenant_id) {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn module(&self, tenant_id: Uuid, namespace_id: Uuid, stage: Stage) -> Option<Arc<Module>> {
+        self.modules
+            .get(&(namespace_id, tenant_id))
+            .and_then(|modules| modules.get(stage))
+    }
+
+    // The value as the namespace's read module transforms it, None when the namespace has none
+    pub fn read(
+        &self,
+        tenant_id: Uuid,
+        namespace_id: Uuid,
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let Some(module) = self.module(tenant_id, namespace_id, Stage::Read) else {
+            return Ok(None);
+        };
+        let (mut store, instance) = self.instantiate(&module)?;
+        let (ptr, len) = pass_value(&mut store, &instance, value)?;
+        let packed = instance
+            .get_typed_func::<(i32, i32), i64>(&store, Stage::Read.export())
+            .map_err(failed)?
+            .call(&mut store, (ptr, len))
+            .map_err(failed)?;
+        let (ptr, len) = ((packed >> 32) as u32 as usize, packed as u32 as usize);
+        let mut transformed = vec![0; len];
+        memory(&store, &instance)?
+            .read(&store, ptr, &mut transformed)
+            .map_err(failed)?;
+        Ok(Some(transformed))
+    }
+
+    // Fails with ValueRejected when the namespace's write module doesn't accept the value
+    pub fn validate(&self, tenant_id: Uuid, namespace_id: Uuid, value: &[u8]) -> Result<(), Error> {
+        let Some(module) = self.module(tenant_id, namespace_id, Stage::Write) else {
+            return Ok(());
+        };
+        let (mut store, instance) = self.instantiate(&module)?;
+        let (ptr, len) = pass_value(&mut store, &instance, value)?;
+        let code = instance
+            .get_typed_func::<(i32, i32), i32>(&store, Stage::Write.export())
+            .map_err(failed)?
+            .call(&mut store, (ptr, len))
+            .map_err(failed)?;
+        match code {
+            0 => Ok(()),
+            code => Err(Error::ValueRejected(code)),
+        }
+    }
+
+    fn instantiate(&self, module: &Module) -> Result<(Store<StoreLimits>, Instance), Error> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.limits.memory)
+            .instances(1)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.add_fuel(self.limits.fuel).map_err(failed)?;
+        let instance = Linker::new(&self.engine)
+            .instantiate(&mut store, module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(failed)?;
+        Ok((store, instance))
+    }
+}
+
+// A module's failure, e.g. running out of fuel or memory, is the tenant's to fix
+fn failed(err: impl ToString) -> Error {
+    Error::TransformFailed(err.to_string())
+}
+
+fn memory(store: &Store<StoreLimits>, instance: &Instance) -> Result<wasmi::Memory, Error> {
+    instance
+        .get_memory(store, "memory")
+        .ok_or_else(|| failed("the module doesn't export its memory"))
+}
+
+// Copies the value into memory the module allocated for it
+fn pass_value(
+    store: &mut Store<StoreLimits>,
+    instance: &Instance,
+    value: &[u8],
+) -> Result<(i32, i32), Error> {
+    let len = i32::try_from(value.len()).map_err(failed)?;
+    let ptr = instance
+        .get_typed_func::<i32, i32>(&*store, "alloc")
+        .map_err(failed)?
+        .call(&mut *store, len)
+        .map_err(failed)?;
+    memory(store, instance)?
+        .write(&mut *store, ptr as u32 as usize, value)
+        .map_err(failed)?;
+    Ok((ptr, len))
+}
+
+// <namespace id>.<tenant id>.<read|write>.wasm
+fn parse_file_name(path: &Path) -> Option<(Uuid, Uuid, Stage)> {
+    let name = path.file_name()?.to_str()?.strip_suffix(".wasm")?;
+    let mut parts = name.split('.');
+    let namespace_id = Uuid::parse_str(parts.next()?).ok()?;
+    let tenant_id = Uuid::parse_str(parts.next()?).ok()?;
+    let stage = match parts.next()? {
+        "read" => Stage::Read,
+        "write" => Stage::Write,
+        _ => return None,
+    };
+    Some((namespace_id, tenant_id, stage))
+}